@@ -0,0 +1,6 @@
+CREATE TABLE IF NOT EXISTS idempotency (
+    idempotency_key TEXT PRIMARY KEY,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    status_code INTEGER NOT NULL,
+    response JSONB NOT NULL
+);
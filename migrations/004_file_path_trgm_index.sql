@@ -0,0 +1,4 @@
+CREATE EXTENSION IF NOT EXISTS pg_trgm;
+
+CREATE INDEX IF NOT EXISTS filehash_file_path_trgm_idx
+    ON filehash USING GIN (file_path gin_trgm_ops);
@@ -0,0 +1 @@
+ALTER TABLE filehash ADD COLUMN IF NOT EXISTS content_type TEXT;
@@ -0,0 +1,12 @@
+CREATE TABLE IF NOT EXISTS file_versions (
+    id SERIAL PRIMARY KEY,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    file_path TEXT NOT NULL,
+    version INTEGER NOT NULL,
+    file_hash TEXT,
+    file_size BIGINT NOT NULL,
+    modified_time BIGINT NOT NULL,
+    content_type TEXT,
+    s3_key TEXT NOT NULL,
+    UNIQUE (file_path, version)
+);
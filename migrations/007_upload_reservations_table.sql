@@ -0,0 +1,8 @@
+CREATE TABLE IF NOT EXISTS upload_reservations (
+    id SERIAL PRIMARY KEY,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    expires_at TIMESTAMP NOT NULL,
+    system_path TEXT NOT NULL UNIQUE,
+    file_size BIGINT NOT NULL,
+    content_type TEXT NOT NULL
+);
@@ -0,0 +1,2 @@
+ALTER TABLE filehash ADD COLUMN IF NOT EXISTS etag TEXT;
+ALTER TABLE file_versions ADD COLUMN IF NOT EXISTS etag TEXT;
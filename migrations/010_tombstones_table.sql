@@ -0,0 +1,7 @@
+CREATE TABLE IF NOT EXISTS tombstones (
+    file_path TEXT PRIMARY KEY,
+    deleted_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS filehash_updated_at_idx ON filehash (updated_at);
+CREATE INDEX IF NOT EXISTS tombstones_deleted_at_idx ON tombstones (deleted_at);
@@ -0,0 +1,22 @@
+-- Files synced before namespaces existed are handed to the `admin` user.
+INSERT INTO users (username, is_admin) VALUES ('admin', TRUE) ON CONFLICT (username) DO NOTHING;
+
+ALTER TABLE filehash ADD COLUMN IF NOT EXISTS user_id INTEGER REFERENCES users(id) ON DELETE CASCADE;
+UPDATE filehash SET user_id = (SELECT id FROM users WHERE username = 'admin') WHERE user_id IS NULL;
+ALTER TABLE filehash ALTER COLUMN user_id SET NOT NULL;
+ALTER TABLE filehash DROP CONSTRAINT IF EXISTS filehash_file_path_key;
+ALTER TABLE filehash ADD CONSTRAINT filehash_user_file_path_key UNIQUE (user_id, file_path);
+
+ALTER TABLE file_versions ADD COLUMN IF NOT EXISTS user_id INTEGER REFERENCES users(id) ON DELETE CASCADE;
+UPDATE file_versions SET user_id = (SELECT id FROM users WHERE username = 'admin') WHERE user_id IS NULL;
+ALTER TABLE file_versions ALTER COLUMN user_id SET NOT NULL;
+ALTER TABLE file_versions DROP CONSTRAINT IF EXISTS file_versions_file_path_version_key;
+ALTER TABLE file_versions ADD CONSTRAINT file_versions_user_file_path_version_key UNIQUE (user_id, file_path, version);
+
+ALTER TABLE tombstones ADD COLUMN IF NOT EXISTS user_id INTEGER REFERENCES users(id) ON DELETE CASCADE;
+UPDATE tombstones SET user_id = (SELECT id FROM users WHERE username = 'admin') WHERE user_id IS NULL;
+ALTER TABLE tombstones ALTER COLUMN user_id SET NOT NULL;
+ALTER TABLE tombstones DROP CONSTRAINT IF EXISTS tombstones_pkey;
+ALTER TABLE tombstones ADD PRIMARY KEY (user_id, file_path);
+
+ALTER TABLE jobs ADD COLUMN IF NOT EXISTS user_id INTEGER REFERENCES users(id) ON DELETE CASCADE;
@@ -0,0 +1,16 @@
+CREATE TABLE IF NOT EXISTS upload_sessions (
+    id TEXT PRIMARY KEY,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    expires_at TIMESTAMP NOT NULL,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    file_path TEXT NOT NULL,
+    file_hash TEXT,
+    file_size BIGINT NOT NULL,
+    modified_time BIGINT NOT NULL,
+    content_type TEXT NOT NULL,
+    system_path TEXT NOT NULL UNIQUE,
+    storage_upload_id TEXT NOT NULL,
+    upload_offset BIGINT NOT NULL DEFAULT 0,
+    parts JSONB NOT NULL DEFAULT '[]'
+);
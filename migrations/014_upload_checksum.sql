@@ -0,0 +1 @@
+ALTER TABLE upload_reservations ADD COLUMN IF NOT EXISTS sha256 TEXT;
@@ -0,0 +1 @@
+CREATE INDEX IF NOT EXISTS file_versions_file_hash_idx ON file_versions (user_id, file_path, file_hash);
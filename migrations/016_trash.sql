@@ -0,0 +1,17 @@
+CREATE TABLE IF NOT EXISTS trash (
+    id SERIAL PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    file_path TEXT NOT NULL,
+    file_hash TEXT,
+    file_size BIGINT NOT NULL,
+    modified_time BIGINT NOT NULL,
+    content_type TEXT,
+    etag TEXT,
+    system_path TEXT NOT NULL,
+    trash_key TEXT NOT NULL UNIQUE,
+    created_at TIMESTAMP,
+    deleted_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS trash_user_deleted_at_idx ON trash (user_id, deleted_at);
+CREATE INDEX IF NOT EXISTS trash_deleted_at_idx ON trash (deleted_at);
@@ -0,0 +1,33 @@
+-- One counter per user, bumped by a row lock held until commit, so each user's
+-- sequence numbers become visible in the order they were handed out.
+CREATE TABLE IF NOT EXISTS change_counters (
+    user_id INTEGER PRIMARY KEY REFERENCES users(id) ON DELETE CASCADE,
+    seq BIGINT NOT NULL
+);
+
+CREATE OR REPLACE FUNCTION assign_change_seq() RETURNS trigger AS $$
+BEGIN
+    INSERT INTO change_counters (user_id, seq) VALUES (NEW.user_id, 1)
+    ON CONFLICT (user_id) DO UPDATE SET seq = change_counters.seq + 1
+    RETURNING seq INTO NEW.change_seq;
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+ALTER TABLE filehash ADD COLUMN IF NOT EXISTS change_seq BIGINT;
+ALTER TABLE tombstones ADD COLUMN IF NOT EXISTS change_seq BIGINT;
+
+DROP TRIGGER IF EXISTS filehash_change_seq ON filehash;
+CREATE TRIGGER filehash_change_seq BEFORE INSERT OR UPDATE ON filehash
+    FOR EACH ROW EXECUTE FUNCTION assign_change_seq();
+
+DROP TRIGGER IF EXISTS tombstones_change_seq ON tombstones;
+CREATE TRIGGER tombstones_change_seq BEFORE INSERT OR UPDATE ON tombstones
+    FOR EACH ROW EXECUTE FUNCTION assign_change_seq();
+
+-- Existing rows get numbered by the triggers.
+UPDATE filehash SET change_seq = NULL WHERE change_seq IS NULL;
+UPDATE tombstones SET change_seq = NULL WHERE change_seq IS NULL;
+
+CREATE INDEX IF NOT EXISTS filehash_user_change_seq_idx ON filehash (user_id, change_seq);
+CREATE INDEX IF NOT EXISTS tombstones_user_change_seq_idx ON tombstones (user_id, change_seq);
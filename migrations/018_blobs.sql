@@ -0,0 +1,15 @@
+CREATE TABLE IF NOT EXISTS blobs (
+    sha256 TEXT PRIMARY KEY,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    size BIGINT NOT NULL,
+    ref_count INTEGER NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS blob_refs (
+    key TEXT PRIMARY KEY,
+    updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    sha256 TEXT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS blob_refs_key_prefix_idx ON blob_refs (key text_pattern_ops);
+CREATE INDEX IF NOT EXISTS blob_refs_sha256_idx ON blob_refs (sha256);
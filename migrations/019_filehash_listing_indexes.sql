@@ -0,0 +1,3 @@
+CREATE INDEX IF NOT EXISTS filehash_user_path_prefix_idx ON filehash (user_id, file_path text_pattern_ops);
+CREATE INDEX IF NOT EXISTS filehash_user_size_idx ON filehash (user_id, file_size);
+CREATE INDEX IF NOT EXISTS filehash_user_modified_time_idx ON filehash (user_id, modified_time);
@@ -0,0 +1,33 @@
+-- NULL falls back to the server-wide default quota.
+ALTER TABLE users ADD COLUMN IF NOT EXISTS quota_bytes BIGINT;
+
+-- Running totals of each user's live files, kept in step with `filehash` by a
+-- trigger so reading usage never has to sum the whole table.
+CREATE TABLE IF NOT EXISTS user_usage (
+    user_id INTEGER PRIMARY KEY REFERENCES users(id) ON DELETE CASCADE,
+    bytes BIGINT NOT NULL DEFAULT 0,
+    files BIGINT NOT NULL DEFAULT 0
+);
+
+CREATE OR REPLACE FUNCTION track_user_usage() RETURNS trigger AS $$
+BEGIN
+    IF TG_OP IN ('UPDATE', 'DELETE') THEN
+        UPDATE user_usage SET bytes = bytes - OLD.file_size, files = files - 1
+        WHERE user_id = OLD.user_id;
+    END IF;
+    IF TG_OP IN ('INSERT', 'UPDATE') THEN
+        INSERT INTO user_usage (user_id, bytes, files) VALUES (NEW.user_id, NEW.file_size, 1)
+        ON CONFLICT (user_id) DO UPDATE
+        SET bytes = user_usage.bytes + EXCLUDED.bytes, files = user_usage.files + 1;
+    END IF;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS filehash_user_usage ON filehash;
+CREATE TRIGGER filehash_user_usage AFTER INSERT OR UPDATE OF file_size, user_id OR DELETE ON filehash
+    FOR EACH ROW EXECUTE FUNCTION track_user_usage();
+
+INSERT INTO user_usage (user_id, bytes, files)
+SELECT user_id, COALESCE(SUM(file_size), 0), COUNT(*) FROM filehash GROUP BY user_id
+ON CONFLICT (user_id) DO UPDATE SET bytes = EXCLUDED.bytes, files = EXCLUDED.files;
@@ -0,0 +1,14 @@
+-- Each of a user's sync clients. `cursor_seq` is the last `change_seq` the device
+-- has acknowledged through `/changes`, compared against `change_counters` to tell
+-- which devices are behind.
+CREATE TABLE IF NOT EXISTS devices (
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    id TEXT NOT NULL,
+    name TEXT NOT NULL,
+    platform TEXT,
+    cursor_seq BIGINT NOT NULL DEFAULT 0,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    last_seen_at TIMESTAMP,
+    last_sync_at TIMESTAMP,
+    PRIMARY KEY (user_id, id)
+);
@@ -0,0 +1,13 @@
+-- Named include/exclude glob sets a device can sync under. Patterns are stored as
+-- given and translated to regexes when queried.
+CREATE TABLE IF NOT EXISTS sync_profiles (
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    name TEXT NOT NULL,
+    include_patterns TEXT[] NOT NULL DEFAULT '{}',
+    exclude_patterns TEXT[] NOT NULL DEFAULT '{}',
+    updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (user_id, name)
+);
+
+-- NULL syncs everything.
+ALTER TABLE devices ADD COLUMN IF NOT EXISTS profile TEXT;
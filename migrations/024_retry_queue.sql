@@ -0,0 +1,18 @@
+-- Storage operations that failed and are retried in the background with backoff.
+-- Rows are deleted once they succeed; `dead` ones ran out of attempts and stay
+-- for inspection through `/admin/jobs`.
+CREATE TABLE IF NOT EXISTS retry_queue (
+    id SERIAL PRIMARY KEY,
+    kind TEXT NOT NULL,
+    object_key TEXT NOT NULL,
+    -- For copies, the object copied to `object_key`.
+    source_key TEXT,
+    status TEXT NOT NULL DEFAULT 'pending',
+    attempts INTEGER NOT NULL DEFAULT 0,
+    last_error TEXT,
+    run_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS retry_queue_due_idx ON retry_queue (run_at) WHERE status = 'pending';
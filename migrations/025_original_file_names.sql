@@ -0,0 +1,19 @@
+-- Storage keys no longer derive from the file's name, so the name a file was
+-- uploaded under is kept beside its key. Existing keys stay valid: they were
+-- unique already, and the name they end with is all there is to backfill.
+ALTER TABLE filehash ADD COLUMN IF NOT EXISTS file_name TEXT;
+-- Not a change any client needs to hear about, so the backfill keeps its change_seq.
+ALTER TABLE filehash DISABLE TRIGGER filehash_change_seq;
+UPDATE filehash SET file_name = regexp_replace(system_path, '^.*/', '') WHERE file_name IS NULL;
+ALTER TABLE filehash ENABLE TRIGGER filehash_change_seq;
+
+ALTER TABLE trash ADD COLUMN IF NOT EXISTS file_name TEXT;
+UPDATE trash SET file_name = regexp_replace(system_path, '^.*/', '') WHERE file_name IS NULL;
+
+ALTER TABLE upload_sessions ADD COLUMN IF NOT EXISTS file_name TEXT;
+UPDATE upload_sessions SET file_name = regexp_replace(system_path, '^.*/', '') WHERE file_name IS NULL;
+
+-- Reservations are looked up by the name the client asked for, within its own namespace.
+ALTER TABLE upload_reservations ADD COLUMN IF NOT EXISTS user_id INTEGER REFERENCES users(id) ON DELETE CASCADE;
+ALTER TABLE upload_reservations ADD COLUMN IF NOT EXISTS file_name TEXT;
+CREATE INDEX IF NOT EXISTS upload_reservations_user_file_name_idx ON upload_reservations (user_id, file_name);
@@ -0,0 +1,7 @@
+-- A key is claimed before its request runs, so a retry that arrives while the first
+-- attempt is still going is turned away instead of running the operations twice.
+-- Claimed keys have no response yet; `request_hash` ties a key to the payload it
+-- was first sent with.
+ALTER TABLE idempotency ALTER COLUMN status_code DROP NOT NULL;
+ALTER TABLE idempotency ALTER COLUMN response DROP NOT NULL;
+ALTER TABLE idempotency ADD COLUMN IF NOT EXISTS request_hash TEXT;
@@ -0,0 +1,10 @@
+-- Data keys for storage encryption, each wrapped by the master key. One per user,
+-- plus a shared one (no user) for objects outside every user's namespace.
+CREATE TABLE IF NOT EXISTS encryption_keys (
+    id SERIAL PRIMARY KEY,
+    user_id INTEGER REFERENCES users(id) ON DELETE CASCADE,
+    wrapped_key BYTEA NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS encryption_keys_owner_idx ON encryption_keys ((COALESCE(user_id, 0)));
@@ -0,0 +1,5 @@
+-- `/search` matches original file names as well as paths, and filters by extension.
+CREATE INDEX IF NOT EXISTS filehash_file_name_trgm_idx
+    ON filehash USING GIN (file_name gin_trgm_ops);
+CREATE INDEX IF NOT EXISTS filehash_user_extension_idx
+    ON filehash (user_id, lower(substring(file_path FROM '\.([^./]+)$')));
@@ -0,0 +1,15 @@
+-- Tags and key-value metadata clients attach to files. Tags belong to the row, so
+-- moves and renames keep them and deletes drop them; the trash keeps a copy of
+-- both for restores.
+CREATE TABLE IF NOT EXISTS file_tags (
+    file_id INTEGER NOT NULL REFERENCES filehash(id) ON DELETE CASCADE,
+    tag TEXT NOT NULL,
+    PRIMARY KEY (file_id, tag)
+);
+
+CREATE INDEX IF NOT EXISTS file_tags_tag_idx ON file_tags (tag, file_id);
+
+ALTER TABLE filehash ADD COLUMN IF NOT EXISTS metadata JSONB NOT NULL DEFAULT '{}';
+
+ALTER TABLE trash ADD COLUMN IF NOT EXISTS tags TEXT[] NOT NULL DEFAULT '{}';
+ALTER TABLE trash ADD COLUMN IF NOT EXISTS metadata JSONB NOT NULL DEFAULT '{}';
@@ -0,0 +1,9 @@
+-- Folders created explicitly, e.g. by a WebDAV MKCOL. Folders are otherwise implied
+-- by the paths of the files inside them, so these rows only matter while a folder
+-- is empty; moves carry them along and deleting the folder drops them.
+CREATE TABLE IF NOT EXISTS folders (
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    path TEXT NOT NULL,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (user_id, path)
+);
@@ -0,0 +1,6 @@
+-- A role replaces the admin flag: `user`, `operator` (can view everything under
+-- `/admin` but change nothing) or `admin`.
+ALTER TABLE users ADD COLUMN IF NOT EXISTS role TEXT NOT NULL DEFAULT 'user'
+    CHECK (role IN ('user', 'operator', 'admin'));
+UPDATE users SET role = 'admin' WHERE is_admin;
+ALTER TABLE users DROP COLUMN is_admin;
@@ -0,0 +1,26 @@
+-- Every insert, update, delete and download of a user's files. Rows outlive the
+-- files and users they describe, so nothing references them, and the trigger keeps
+-- the table append-only.
+CREATE TABLE IF NOT EXISTS audit_log (
+    id BIGSERIAL PRIMARY KEY,
+    user_id INTEGER NOT NULL,
+    -- Who acted: the owner, an admin, or NULL for a share link.
+    username TEXT,
+    device_id TEXT,
+    ip TEXT,
+    operation TEXT NOT NULL CHECK (operation IN ('insert', 'update', 'delete', 'download')),
+    file_path TEXT NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS audit_log_user_path_idx ON audit_log (user_id, file_path, id);
+
+CREATE OR REPLACE FUNCTION audit_log_append_only() RETURNS trigger AS $$
+BEGIN
+    RAISE EXCEPTION 'audit_log is append-only';
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS audit_log_append_only ON audit_log;
+CREATE TRIGGER audit_log_append_only BEFORE UPDATE OR DELETE ON audit_log
+    FOR EACH ROW EXECUTE FUNCTION audit_log_append_only();
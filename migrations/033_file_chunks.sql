@@ -0,0 +1,21 @@
+-- The content-defined chunks of each file's current content, in order, as last
+-- stored through `/sync/blocks`. Replacing the content any other way drops them,
+-- so a manifest never describes bytes the object no longer holds.
+CREATE TABLE IF NOT EXISTS file_chunks (
+    file_id INTEGER NOT NULL REFERENCES filehash(id) ON DELETE CASCADE,
+    seq INTEGER NOT NULL,
+    sha256 TEXT NOT NULL,
+    size BIGINT NOT NULL,
+    PRIMARY KEY (file_id, seq)
+);
+
+CREATE OR REPLACE FUNCTION drop_file_chunks() RETURNS trigger AS $$
+BEGIN
+    DELETE FROM file_chunks WHERE file_id = NEW.id;
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS filehash_drop_chunks ON filehash;
+CREATE TRIGGER filehash_drop_chunks AFTER UPDATE OF file_hash, file_size, system_path, etag ON filehash
+    FOR EACH ROW EXECUTE FUNCTION drop_file_chunks();
@@ -0,0 +1,9 @@
+-- Objects stored compressed, by key, with the codec and the size of the content
+-- before compression. Keys without a row hold their content as uploaded.
+CREATE TABLE IF NOT EXISTS compressed_objects (
+    key TEXT PRIMARY KEY,
+    updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    codec TEXT NOT NULL,
+    original_size BIGINT NOT NULL,
+    stored_size BIGINT NOT NULL
+);
@@ -0,0 +1,12 @@
+-- Lease-based locks on a user's files, each held by one of their devices until
+-- `expires_at`. Past that the lock is ignored, and the next `/lock` takes it over.
+-- While it holds, `/sync` refuses updates of the file from any other device.
+CREATE TABLE IF NOT EXISTS file_locks (
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    file_path TEXT NOT NULL,
+    device_id TEXT NOT NULL,
+    acquired_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    expires_at TIMESTAMP NOT NULL,
+    PRIMARY KEY (user_id, file_path),
+    FOREIGN KEY (user_id, device_id) REFERENCES devices(user_id, id) ON DELETE CASCADE
+);
@@ -0,0 +1,92 @@
+-- Rollups behind `/stats`, kept in step with `filehash` and `trash` by triggers
+-- like `user_usage` is, so the dashboard never has to sum a user's files.
+
+-- The first segment of a path, or '' for a file at the root.
+CREATE OR REPLACE FUNCTION path_top_folder(path TEXT) RETURNS TEXT AS $$
+    SELECT CASE WHEN position('/' IN path) > 0 THEN split_part(path, '/', 1) ELSE '' END
+$$ LANGUAGE sql IMMUTABLE;
+
+-- The lowercased extension of a path's last segment, or '' when it has none.
+CREATE OR REPLACE FUNCTION path_extension(path TEXT) RETURNS TEXT AS $$
+    SELECT COALESCE(lower(substring(path FROM '\.([^./]+)$')), '')
+$$ LANGUAGE sql IMMUTABLE;
+
+CREATE TABLE IF NOT EXISTS folder_usage (
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    folder TEXT NOT NULL,
+    bytes BIGINT NOT NULL DEFAULT 0,
+    files BIGINT NOT NULL DEFAULT 0,
+    PRIMARY KEY (user_id, folder)
+);
+
+CREATE TABLE IF NOT EXISTS extension_usage (
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    extension TEXT NOT NULL,
+    bytes BIGINT NOT NULL DEFAULT 0,
+    files BIGINT NOT NULL DEFAULT 0,
+    PRIMARY KEY (user_id, extension)
+);
+
+CREATE OR REPLACE FUNCTION track_usage_rollups() RETURNS trigger AS $$
+BEGIN
+    IF TG_OP IN ('UPDATE', 'DELETE') THEN
+        UPDATE folder_usage SET bytes = bytes - OLD.file_size, files = files - 1
+        WHERE user_id = OLD.user_id AND folder = path_top_folder(OLD.file_path);
+        DELETE FROM folder_usage
+        WHERE user_id = OLD.user_id AND folder = path_top_folder(OLD.file_path) AND files <= 0;
+        UPDATE extension_usage SET bytes = bytes - OLD.file_size, files = files - 1
+        WHERE user_id = OLD.user_id AND extension = path_extension(OLD.file_path);
+        DELETE FROM extension_usage
+        WHERE user_id = OLD.user_id AND extension = path_extension(OLD.file_path) AND files <= 0;
+    END IF;
+    IF TG_OP IN ('INSERT', 'UPDATE') THEN
+        INSERT INTO folder_usage (user_id, folder, bytes, files)
+        VALUES (NEW.user_id, path_top_folder(NEW.file_path), NEW.file_size, 1)
+        ON CONFLICT (user_id, folder) DO UPDATE
+        SET bytes = folder_usage.bytes + EXCLUDED.bytes, files = folder_usage.files + 1;
+        INSERT INTO extension_usage (user_id, extension, bytes, files)
+        VALUES (NEW.user_id, path_extension(NEW.file_path), NEW.file_size, 1)
+        ON CONFLICT (user_id, extension) DO UPDATE
+        SET bytes = extension_usage.bytes + EXCLUDED.bytes, files = extension_usage.files + 1;
+    END IF;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS filehash_usage_rollups ON filehash;
+CREATE TRIGGER filehash_usage_rollups AFTER INSERT OR UPDATE OF file_size, user_id, file_path OR DELETE ON filehash
+    FOR EACH ROW EXECUTE FUNCTION track_usage_rollups();
+
+-- Trashed files still take up storage until they're purged.
+ALTER TABLE user_usage ADD COLUMN IF NOT EXISTS trash_bytes BIGINT NOT NULL DEFAULT 0;
+ALTER TABLE user_usage ADD COLUMN IF NOT EXISTS trash_files BIGINT NOT NULL DEFAULT 0;
+
+CREATE OR REPLACE FUNCTION track_trash_usage() RETURNS trigger AS $$
+BEGIN
+    IF TG_OP = 'DELETE' THEN
+        UPDATE user_usage SET trash_bytes = trash_bytes - OLD.file_size, trash_files = trash_files - 1
+        WHERE user_id = OLD.user_id;
+    ELSE
+        INSERT INTO user_usage (user_id, trash_bytes, trash_files) VALUES (NEW.user_id, NEW.file_size, 1)
+        ON CONFLICT (user_id) DO UPDATE
+        SET trash_bytes = user_usage.trash_bytes + EXCLUDED.trash_bytes, trash_files = user_usage.trash_files + 1;
+    END IF;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS trash_user_usage ON trash;
+CREATE TRIGGER trash_user_usage AFTER INSERT OR DELETE ON trash
+    FOR EACH ROW EXECUTE FUNCTION track_trash_usage();
+
+INSERT INTO folder_usage (user_id, folder, bytes, files)
+SELECT user_id, path_top_folder(file_path), SUM(file_size), COUNT(*) FROM filehash GROUP BY 1, 2
+ON CONFLICT (user_id, folder) DO UPDATE SET bytes = EXCLUDED.bytes, files = EXCLUDED.files;
+
+INSERT INTO extension_usage (user_id, extension, bytes, files)
+SELECT user_id, path_extension(file_path), SUM(file_size), COUNT(*) FROM filehash GROUP BY 1, 2
+ON CONFLICT (user_id, extension) DO UPDATE SET bytes = EXCLUDED.bytes, files = EXCLUDED.files;
+
+INSERT INTO user_usage (user_id, trash_bytes, trash_files)
+SELECT user_id, SUM(file_size), COUNT(*) FROM trash GROUP BY user_id
+ON CONFLICT (user_id) DO UPDATE SET trash_bytes = EXCLUDED.trash_bytes, trash_files = EXCLUDED.trash_files;
@@ -0,0 +1,23 @@
+-- What each image's EXIF data says about it, recorded by the job that generates
+-- its thumbnails after upload; images without EXIF data get a row of NULLs. The
+-- photo timeline places images without a capture time by their modified time.
+-- Replacing the content drops the row until the job has read the new content.
+CREATE TABLE IF NOT EXISTS media_metadata (
+    file_id INTEGER PRIMARY KEY REFERENCES filehash(id) ON DELETE CASCADE,
+    taken_at TIMESTAMP,
+    camera_make TEXT,
+    camera_model TEXT,
+    latitude DOUBLE PRECISION,
+    longitude DOUBLE PRECISION
+);
+
+CREATE OR REPLACE FUNCTION drop_media_metadata() RETURNS trigger AS $$
+BEGIN
+    DELETE FROM media_metadata WHERE file_id = NEW.id;
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS filehash_drop_media_metadata ON filehash;
+CREATE TRIGGER filehash_drop_media_metadata AFTER UPDATE OF file_hash, file_size, system_path, etag ON filehash
+    FOR EACH ROW EXECUTE FUNCTION drop_media_metadata();
@@ -0,0 +1,14 @@
+-- TOTP two-factor authentication. `totp_secret` is set on enrollment and only
+-- takes effect once a code from it has been confirmed, at `totp_enabled_at`.
+-- `totp_last_step` is the time step of the last code accepted, so no code is
+-- accepted twice.
+ALTER TABLE users ADD COLUMN IF NOT EXISTS totp_secret TEXT;
+ALTER TABLE users ADD COLUMN IF NOT EXISTS totp_enabled_at TIMESTAMP;
+ALTER TABLE users ADD COLUMN IF NOT EXISTS totp_last_step BIGINT;
+
+-- What an API token may do: `full` is everything its user can, `read` only
+-- listing and downloading, and `upload` only adding files. Users label their
+-- own tokens with a name.
+ALTER TABLE api_tokens ADD COLUMN IF NOT EXISTS scope TEXT NOT NULL DEFAULT 'full'
+    CHECK (scope IN ('full', 'read', 'upload'));
+ALTER TABLE api_tokens ADD COLUMN IF NOT EXISTS name TEXT;
@@ -0,0 +1,15 @@
+-- Stored objects the integrity check found missing or not matching their file,
+-- one row per object. A later check that finds the object intact clears it.
+CREATE TABLE IF NOT EXISTS integrity_issues (
+    id SERIAL PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    file_path TEXT NOT NULL,
+    system_path TEXT NOT NULL UNIQUE,
+    -- `missing` or `corrupted`.
+    kind TEXT NOT NULL,
+    detail TEXT NOT NULL,
+    detected_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    checked_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS integrity_issues_user_idx ON integrity_issues (user_id);
@@ -0,0 +1,3 @@
+-- The newest change_seq among each user's pruned tombstones. A cursor from before
+-- it may have missed deletions that are no longer recorded.
+ALTER TABLE change_counters ADD COLUMN IF NOT EXISTS pruned_seq BIGINT NOT NULL DEFAULT 0;
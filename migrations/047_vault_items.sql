@@ -0,0 +1,19 @@
+-- Files their clients encrypt end to end. The server knows each one by an opaque
+-- id, with its size and version vector; its name and metadata are ciphertext.
+CREATE TABLE IF NOT EXISTS vault_items (
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    item_id TEXT NOT NULL,
+    encrypted_name TEXT NOT NULL,
+    encrypted_metadata TEXT,
+    file_size BIGINT NOT NULL,
+    system_path TEXT NOT NULL UNIQUE,
+    version_vector JSONB NOT NULL DEFAULT '{}',
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (user_id, item_id)
+);
+
+-- Vault items count against quotas like any other file.
+DROP TRIGGER IF EXISTS vault_items_user_usage ON vault_items;
+CREATE TRIGGER vault_items_user_usage AFTER INSERT OR UPDATE OF file_size, user_id OR DELETE ON vault_items
+    FOR EACH ROW EXECUTE FUNCTION track_user_usage();
@@ -0,0 +1,7 @@
+-- Moves, restores from the trash or an older version, and new shares are logged
+-- too, for the activity feed. It pages through a user's rows by id.
+ALTER TABLE audit_log DROP CONSTRAINT IF EXISTS audit_log_operation_check;
+ALTER TABLE audit_log ADD CONSTRAINT audit_log_operation_check
+    CHECK (operation IN ('insert', 'update', 'delete', 'download', 'move', 'restore', 'share'));
+
+CREATE INDEX IF NOT EXISTS audit_log_user_idx ON audit_log (user_id, id);
@@ -0,0 +1,8 @@
+-- Upload policies admins give particular users, held to on top of the server's
+-- `upload_policy` setting, as JSON.
+CREATE TABLE IF NOT EXISTS upload_policies (
+    user_id INTEGER PRIMARY KEY REFERENCES users(id) ON DELETE CASCADE,
+    policy TEXT NOT NULL,
+    updated_by INTEGER REFERENCES users(id) ON DELETE SET NULL,
+    updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
@@ -0,0 +1,2 @@
+-- `/sync/precheck` and linked uploads look files up by content.
+CREATE INDEX IF NOT EXISTS filehash_user_hash_idx ON filehash (user_id, file_hash);
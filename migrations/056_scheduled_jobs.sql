@@ -0,0 +1,13 @@
+-- What the scheduler knows of each job it runs on a cron schedule, or was asked
+-- to run: when it's next due, and how its last run went. `schedule` is the
+-- expression `next_run_at` was worked out from, so a changed one starts afresh.
+CREATE TABLE IF NOT EXISTS scheduled_jobs (
+    name TEXT PRIMARY KEY,
+    schedule TEXT,
+    next_run_at TIMESTAMP,
+    last_started_at TIMESTAMP,
+    last_finished_at TIMESTAMP,
+    -- `succeeded` or `failed`.
+    last_status TEXT,
+    last_error TEXT
+);
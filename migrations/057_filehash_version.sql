@@ -0,0 +1,3 @@
+-- A counter per file, bumped by every change to its content or path, which sync
+-- updates and deletes name to say which copy they were made against.
+ALTER TABLE filehash ADD COLUMN IF NOT EXISTS version BIGINT NOT NULL DEFAULT 1;
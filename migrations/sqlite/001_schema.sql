@@ -0,0 +1,331 @@
+-- The schema the Postgres migrations up to 032 build, in SQLite's dialect. Later
+-- migrations mirror their Postgres counterparts, under the same version number.
+-- Timestamps are stored as UTC text, lists as JSON arrays and JSONB as JSON text.
+
+CREATE TABLE users (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    username TEXT NOT NULL UNIQUE,
+    password_hash TEXT,
+    -- NULL falls back to the server-wide default quota.
+    quota_bytes BIGINT,
+    -- `user`, `operator` (can view everything under `/admin` but change nothing) or `admin`.
+    role TEXT NOT NULL DEFAULT 'user' CHECK (role IN ('user', 'operator', 'admin'))
+);
+
+-- Postgres installs always have it, from before namespaces existed.
+INSERT INTO users (username, role) VALUES ('admin', 'admin');
+
+CREATE TABLE api_tokens (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    last_used_at TIMESTAMP,
+    revoked_at TIMESTAMP,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    token_hash TEXT NOT NULL UNIQUE
+);
+
+CREATE TABLE filehash (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    file_path TEXT NOT NULL,
+    file_hash TEXT NOT NULL,
+    file_size BIGINT NOT NULL,
+    modified_time BIGINT NOT NULL,
+    system_path TEXT NOT NULL UNIQUE,
+    content_type TEXT,
+    etag TEXT,
+    change_seq BIGINT,
+    file_name TEXT,
+    metadata TEXT NOT NULL DEFAULT '{}',
+    UNIQUE (user_id, file_path)
+);
+
+CREATE INDEX filehash_updated_at_idx ON filehash (updated_at);
+CREATE INDEX filehash_user_change_seq_idx ON filehash (user_id, change_seq);
+CREATE INDEX filehash_user_size_idx ON filehash (user_id, file_size);
+CREATE INDEX filehash_user_modified_time_idx ON filehash (user_id, modified_time);
+
+CREATE TABLE file_tags (
+    file_id INTEGER NOT NULL REFERENCES filehash(id) ON DELETE CASCADE,
+    tag TEXT NOT NULL,
+    PRIMARY KEY (file_id, tag)
+);
+
+CREATE INDEX file_tags_tag_idx ON file_tags (tag, file_id);
+
+CREATE TABLE folders (
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    path TEXT NOT NULL,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (user_id, path)
+);
+
+CREATE TABLE file_versions (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    file_path TEXT NOT NULL,
+    version INTEGER NOT NULL,
+    file_hash TEXT,
+    file_size BIGINT NOT NULL,
+    modified_time BIGINT NOT NULL,
+    content_type TEXT,
+    etag TEXT,
+    s3_key TEXT NOT NULL,
+    UNIQUE (user_id, file_path, version)
+);
+
+CREATE INDEX file_versions_file_hash_idx ON file_versions (user_id, file_path, file_hash);
+
+CREATE TABLE tombstones (
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    file_path TEXT NOT NULL,
+    deleted_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    change_seq BIGINT,
+    PRIMARY KEY (user_id, file_path)
+);
+
+CREATE INDEX tombstones_deleted_at_idx ON tombstones (deleted_at);
+CREATE INDEX tombstones_user_change_seq_idx ON tombstones (user_id, change_seq);
+
+CREATE TABLE trash (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    file_path TEXT NOT NULL,
+    file_hash TEXT,
+    file_size BIGINT NOT NULL,
+    modified_time BIGINT NOT NULL,
+    content_type TEXT,
+    etag TEXT,
+    system_path TEXT NOT NULL,
+    trash_key TEXT NOT NULL UNIQUE,
+    file_name TEXT,
+    tags TEXT NOT NULL DEFAULT '[]',
+    metadata TEXT NOT NULL DEFAULT '{}',
+    created_at TIMESTAMP,
+    deleted_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX trash_user_deleted_at_idx ON trash (user_id, deleted_at);
+CREATE INDEX trash_deleted_at_idx ON trash (deleted_at);
+
+CREATE TABLE jobs (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    user_id INTEGER REFERENCES users(id) ON DELETE CASCADE,
+    kind TEXT NOT NULL,
+    status TEXT NOT NULL,
+    progress INTEGER NOT NULL DEFAULT 0,
+    total INTEGER NOT NULL DEFAULT 0,
+    result TEXT,
+    error TEXT
+);
+
+CREATE TABLE idempotency (
+    idempotency_key TEXT PRIMARY KEY,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    status_code INTEGER,
+    response TEXT,
+    request_hash TEXT
+);
+
+CREATE TABLE upload_reservations (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    expires_at TIMESTAMP NOT NULL,
+    user_id INTEGER REFERENCES users(id) ON DELETE CASCADE,
+    system_path TEXT NOT NULL UNIQUE,
+    file_name TEXT,
+    file_size BIGINT NOT NULL,
+    content_type TEXT NOT NULL,
+    sha256 TEXT
+);
+
+CREATE INDEX upload_reservations_user_file_name_idx ON upload_reservations (user_id, file_name);
+
+CREATE TABLE upload_sessions (
+    id TEXT PRIMARY KEY,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    expires_at TIMESTAMP NOT NULL,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    file_path TEXT NOT NULL,
+    file_hash TEXT,
+    file_size BIGINT NOT NULL,
+    modified_time BIGINT NOT NULL,
+    content_type TEXT NOT NULL,
+    system_path TEXT NOT NULL UNIQUE,
+    file_name TEXT,
+    storage_upload_id TEXT NOT NULL,
+    upload_offset BIGINT NOT NULL DEFAULT 0,
+    parts TEXT NOT NULL DEFAULT '[]'
+);
+
+CREATE TABLE blobs (
+    sha256 TEXT PRIMARY KEY,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    size BIGINT NOT NULL,
+    ref_count INTEGER NOT NULL
+);
+
+CREATE TABLE blob_refs (
+    key TEXT PRIMARY KEY,
+    updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    sha256 TEXT NOT NULL
+);
+
+CREATE INDEX blob_refs_sha256_idx ON blob_refs (sha256);
+
+CREATE TABLE shares (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    expires_at TIMESTAMP,
+    revoked_at TIMESTAMP,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    file_path TEXT NOT NULL,
+    token_hash TEXT NOT NULL UNIQUE,
+    password_hash TEXT
+);
+
+CREATE INDEX shares_user_file_path_idx ON shares (user_id, file_path);
+
+CREATE TABLE devices (
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    id TEXT NOT NULL,
+    name TEXT NOT NULL,
+    platform TEXT,
+    profile TEXT,
+    cursor_seq BIGINT NOT NULL DEFAULT 0,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    last_seen_at TIMESTAMP,
+    last_sync_at TIMESTAMP,
+    PRIMARY KEY (user_id, id)
+);
+
+CREATE TABLE sync_profiles (
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    name TEXT NOT NULL,
+    include_patterns TEXT NOT NULL DEFAULT '[]',
+    exclude_patterns TEXT NOT NULL DEFAULT '[]',
+    updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (user_id, name)
+);
+
+CREATE TABLE retry_queue (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    kind TEXT NOT NULL,
+    object_key TEXT NOT NULL,
+    source_key TEXT,
+    status TEXT NOT NULL DEFAULT 'pending',
+    attempts INTEGER NOT NULL DEFAULT 0,
+    last_error TEXT,
+    run_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX retry_queue_due_idx ON retry_queue (run_at) WHERE status = 'pending';
+
+CREATE TABLE encryption_keys (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    user_id INTEGER REFERENCES users(id) ON DELETE CASCADE,
+    wrapped_key BLOB NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE UNIQUE INDEX encryption_keys_owner_idx ON encryption_keys (COALESCE(user_id, 0));
+
+CREATE TABLE audit_log (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    user_id INTEGER NOT NULL,
+    username TEXT,
+    device_id TEXT,
+    ip TEXT,
+    operation TEXT NOT NULL CHECK (operation IN ('insert', 'update', 'delete', 'download')),
+    file_path TEXT NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX audit_log_user_path_idx ON audit_log (user_id, file_path, id);
+
+CREATE TABLE change_counters (
+    user_id INTEGER PRIMARY KEY REFERENCES users(id) ON DELETE CASCADE,
+    seq BIGINT NOT NULL
+);
+
+CREATE TABLE user_usage (
+    user_id INTEGER PRIMARY KEY REFERENCES users(id) ON DELETE CASCADE,
+    bytes BIGINT NOT NULL DEFAULT 0,
+    files BIGINT NOT NULL DEFAULT 0
+);
+
+-- SQLite triggers can't assign to NEW, so change numbers are written after the
+-- fact. Writes are serialized, so each user's numbers still become visible in the
+-- order they were handed out. The update triggers name every other column, so the
+-- numbering itself doesn't count as a change.
+CREATE TRIGGER filehash_change_seq_insert AFTER INSERT ON filehash
+BEGIN
+    INSERT INTO change_counters (user_id, seq) VALUES (NEW.user_id, 1)
+    ON CONFLICT (user_id) DO UPDATE SET seq = seq + 1;
+    UPDATE filehash SET change_seq = (SELECT seq FROM change_counters WHERE user_id = NEW.user_id)
+    WHERE id = NEW.id;
+END;
+
+CREATE TRIGGER filehash_change_seq_update
+AFTER UPDATE OF created_at, updated_at, user_id, file_path, file_hash, file_size, modified_time, system_path,
+    content_type, etag, file_name, metadata ON filehash
+BEGIN
+    INSERT INTO change_counters (user_id, seq) VALUES (NEW.user_id, 1)
+    ON CONFLICT (user_id) DO UPDATE SET seq = seq + 1;
+    UPDATE filehash SET change_seq = (SELECT seq FROM change_counters WHERE user_id = NEW.user_id)
+    WHERE id = NEW.id;
+END;
+
+CREATE TRIGGER tombstones_change_seq_insert AFTER INSERT ON tombstones
+BEGIN
+    INSERT INTO change_counters (user_id, seq) VALUES (NEW.user_id, 1)
+    ON CONFLICT (user_id) DO UPDATE SET seq = seq + 1;
+    UPDATE tombstones SET change_seq = (SELECT seq FROM change_counters WHERE user_id = NEW.user_id)
+    WHERE user_id = NEW.user_id AND file_path = NEW.file_path;
+END;
+
+CREATE TRIGGER tombstones_change_seq_update AFTER UPDATE OF user_id, file_path, deleted_at ON tombstones
+BEGIN
+    INSERT INTO change_counters (user_id, seq) VALUES (NEW.user_id, 1)
+    ON CONFLICT (user_id) DO UPDATE SET seq = seq + 1;
+    UPDATE tombstones SET change_seq = (SELECT seq FROM change_counters WHERE user_id = NEW.user_id)
+    WHERE user_id = NEW.user_id AND file_path = NEW.file_path;
+END;
+
+-- Running totals of each user's live files, kept in step with `filehash`.
+CREATE TRIGGER filehash_user_usage_insert AFTER INSERT ON filehash
+BEGIN
+    INSERT INTO user_usage (user_id, bytes, files) VALUES (NEW.user_id, NEW.file_size, 1)
+    ON CONFLICT (user_id) DO UPDATE SET bytes = bytes + excluded.bytes, files = files + 1;
+END;
+
+CREATE TRIGGER filehash_user_usage_update AFTER UPDATE OF file_size, user_id ON filehash
+BEGIN
+    UPDATE user_usage SET bytes = bytes - OLD.file_size, files = files - 1 WHERE user_id = OLD.user_id;
+    INSERT INTO user_usage (user_id, bytes, files) VALUES (NEW.user_id, NEW.file_size, 1)
+    ON CONFLICT (user_id) DO UPDATE SET bytes = bytes + excluded.bytes, files = files + 1;
+END;
+
+CREATE TRIGGER filehash_user_usage_delete AFTER DELETE ON filehash
+BEGIN
+    UPDATE user_usage SET bytes = bytes - OLD.file_size, files = files - 1 WHERE user_id = OLD.user_id;
+END;
+
+CREATE TRIGGER audit_log_no_update BEFORE UPDATE ON audit_log
+BEGIN
+    SELECT RAISE(ABORT, 'audit_log is append-only');
+END;
+
+CREATE TRIGGER audit_log_no_delete BEFORE DELETE ON audit_log
+BEGIN
+    SELECT RAISE(ABORT, 'audit_log is append-only');
+END;
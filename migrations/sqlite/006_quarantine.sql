@@ -0,0 +1,24 @@
+-- Files a content scan found infected, moved out of their owner's tree until an
+-- admin releases or deletes them. Rows mirror `trash`; the content is kept under
+-- `quarantine_key`.
+CREATE TABLE quarantine (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    file_path TEXT NOT NULL,
+    file_hash TEXT,
+    file_size BIGINT NOT NULL,
+    modified_time BIGINT NOT NULL,
+    content_type TEXT,
+    etag TEXT,
+    system_path TEXT NOT NULL,
+    quarantine_key TEXT NOT NULL UNIQUE,
+    file_name TEXT,
+    tags TEXT NOT NULL DEFAULT '[]',
+    metadata TEXT NOT NULL DEFAULT '{}',
+    -- What the scanner reported finding.
+    signature TEXT NOT NULL,
+    created_at TIMESTAMP,
+    quarantined_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX quarantine_user_idx ON quarantine (user_id);
@@ -0,0 +1,2 @@
+-- A folder share links to everything under `file_path` rather than to one file.
+ALTER TABLE shares ADD COLUMN folder BOOLEAN NOT NULL DEFAULT FALSE;
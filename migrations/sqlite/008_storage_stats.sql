@@ -0,0 +1,84 @@
+-- Rollups behind `/stats`, kept in step with `filehash` and `trash` by triggers
+-- like `user_usage` is, so the dashboard never has to sum a user's files. SQLite
+-- has no regular expressions to split paths with, so a path's last segment is
+-- what's left after trimming every character up to its last '/', and likewise
+-- its extension after the last '.'.
+CREATE TABLE folder_usage (
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    folder TEXT NOT NULL,
+    bytes BIGINT NOT NULL DEFAULT 0,
+    files BIGINT NOT NULL DEFAULT 0,
+    PRIMARY KEY (user_id, folder)
+);
+
+CREATE TABLE extension_usage (
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    extension TEXT NOT NULL,
+    bytes BIGINT NOT NULL DEFAULT 0,
+    files BIGINT NOT NULL DEFAULT 0,
+    PRIMARY KEY (user_id, extension)
+);
+
+CREATE TRIGGER filehash_usage_rollups_insert AFTER INSERT ON filehash
+BEGIN
+    INSERT INTO folder_usage (user_id, folder, bytes, files)
+    VALUES (NEW.user_id, CASE WHEN instr(NEW.file_path, '/') > 0 THEN substr(NEW.file_path, 1, instr(NEW.file_path, '/') - 1) ELSE '' END, NEW.file_size, 1)
+    ON CONFLICT (user_id, folder) DO UPDATE SET bytes = bytes + excluded.bytes, files = files + 1;
+    INSERT INTO extension_usage (user_id, extension, bytes, files)
+    VALUES (NEW.user_id, CASE WHEN instr(replace(NEW.file_path, rtrim(NEW.file_path, replace(NEW.file_path, '/', '')), ''), '.') = 0 THEN '' ELSE lower(replace(replace(NEW.file_path, rtrim(NEW.file_path, replace(NEW.file_path, '/', '')), ''), rtrim(replace(NEW.file_path, rtrim(NEW.file_path, replace(NEW.file_path, '/', '')), ''), replace(replace(NEW.file_path, rtrim(NEW.file_path, replace(NEW.file_path, '/', '')), ''), '.', '')), '')) END, NEW.file_size, 1)
+    ON CONFLICT (user_id, extension) DO UPDATE SET bytes = bytes + excluded.bytes, files = files + 1;
+END;
+
+CREATE TRIGGER filehash_usage_rollups_update AFTER UPDATE OF file_size, user_id, file_path ON filehash
+BEGIN
+    UPDATE folder_usage SET bytes = bytes - OLD.file_size, files = files - 1
+    WHERE user_id = OLD.user_id AND folder = CASE WHEN instr(OLD.file_path, '/') > 0 THEN substr(OLD.file_path, 1, instr(OLD.file_path, '/') - 1) ELSE '' END;
+    DELETE FROM folder_usage WHERE user_id = OLD.user_id AND files <= 0;
+    UPDATE extension_usage SET bytes = bytes - OLD.file_size, files = files - 1
+    WHERE user_id = OLD.user_id AND extension = CASE WHEN instr(replace(OLD.file_path, rtrim(OLD.file_path, replace(OLD.file_path, '/', '')), ''), '.') = 0 THEN '' ELSE lower(replace(replace(OLD.file_path, rtrim(OLD.file_path, replace(OLD.file_path, '/', '')), ''), rtrim(replace(OLD.file_path, rtrim(OLD.file_path, replace(OLD.file_path, '/', '')), ''), replace(replace(OLD.file_path, rtrim(OLD.file_path, replace(OLD.file_path, '/', '')), ''), '.', '')), '')) END;
+    DELETE FROM extension_usage WHERE user_id = OLD.user_id AND files <= 0;
+    INSERT INTO folder_usage (user_id, folder, bytes, files)
+    VALUES (NEW.user_id, CASE WHEN instr(NEW.file_path, '/') > 0 THEN substr(NEW.file_path, 1, instr(NEW.file_path, '/') - 1) ELSE '' END, NEW.file_size, 1)
+    ON CONFLICT (user_id, folder) DO UPDATE SET bytes = bytes + excluded.bytes, files = files + 1;
+    INSERT INTO extension_usage (user_id, extension, bytes, files)
+    VALUES (NEW.user_id, CASE WHEN instr(replace(NEW.file_path, rtrim(NEW.file_path, replace(NEW.file_path, '/', '')), ''), '.') = 0 THEN '' ELSE lower(replace(replace(NEW.file_path, rtrim(NEW.file_path, replace(NEW.file_path, '/', '')), ''), rtrim(replace(NEW.file_path, rtrim(NEW.file_path, replace(NEW.file_path, '/', '')), ''), replace(replace(NEW.file_path, rtrim(NEW.file_path, replace(NEW.file_path, '/', '')), ''), '.', '')), '')) END, NEW.file_size, 1)
+    ON CONFLICT (user_id, extension) DO UPDATE SET bytes = bytes + excluded.bytes, files = files + 1;
+END;
+
+CREATE TRIGGER filehash_usage_rollups_delete AFTER DELETE ON filehash
+BEGIN
+    UPDATE folder_usage SET bytes = bytes - OLD.file_size, files = files - 1
+    WHERE user_id = OLD.user_id AND folder = CASE WHEN instr(OLD.file_path, '/') > 0 THEN substr(OLD.file_path, 1, instr(OLD.file_path, '/') - 1) ELSE '' END;
+    DELETE FROM folder_usage WHERE user_id = OLD.user_id AND files <= 0;
+    UPDATE extension_usage SET bytes = bytes - OLD.file_size, files = files - 1
+    WHERE user_id = OLD.user_id AND extension = CASE WHEN instr(replace(OLD.file_path, rtrim(OLD.file_path, replace(OLD.file_path, '/', '')), ''), '.') = 0 THEN '' ELSE lower(replace(replace(OLD.file_path, rtrim(OLD.file_path, replace(OLD.file_path, '/', '')), ''), rtrim(replace(OLD.file_path, rtrim(OLD.file_path, replace(OLD.file_path, '/', '')), ''), replace(replace(OLD.file_path, rtrim(OLD.file_path, replace(OLD.file_path, '/', '')), ''), '.', '')), '')) END;
+    DELETE FROM extension_usage WHERE user_id = OLD.user_id AND files <= 0;
+END;
+
+-- Trashed files still take up storage until they're purged.
+ALTER TABLE user_usage ADD COLUMN trash_bytes BIGINT NOT NULL DEFAULT 0;
+ALTER TABLE user_usage ADD COLUMN trash_files BIGINT NOT NULL DEFAULT 0;
+
+CREATE TRIGGER trash_user_usage_insert AFTER INSERT ON trash
+BEGIN
+    INSERT INTO user_usage (user_id, trash_bytes, trash_files) VALUES (NEW.user_id, NEW.file_size, 1)
+    ON CONFLICT (user_id) DO UPDATE SET trash_bytes = trash_bytes + excluded.trash_bytes, trash_files = trash_files + 1;
+END;
+
+CREATE TRIGGER trash_user_usage_delete AFTER DELETE ON trash
+BEGIN
+    UPDATE user_usage SET trash_bytes = trash_bytes - OLD.file_size, trash_files = trash_files - 1
+    WHERE user_id = OLD.user_id;
+END;
+
+INSERT INTO folder_usage (user_id, folder, bytes, files)
+SELECT user_id, CASE WHEN instr(file_path, '/') > 0 THEN substr(file_path, 1, instr(file_path, '/') - 1) ELSE '' END, SUM(file_size), COUNT(*)
+FROM filehash GROUP BY 1, 2;
+
+INSERT INTO extension_usage (user_id, extension, bytes, files)
+SELECT user_id, CASE WHEN instr(replace(file_path, rtrim(file_path, replace(file_path, '/', '')), ''), '.') = 0 THEN '' ELSE lower(replace(replace(file_path, rtrim(file_path, replace(file_path, '/', '')), ''), rtrim(replace(file_path, rtrim(file_path, replace(file_path, '/', '')), ''), replace(replace(file_path, rtrim(file_path, replace(file_path, '/', '')), ''), '.', '')), '')) END, SUM(file_size), COUNT(*)
+FROM filehash GROUP BY 1, 2;
+
+INSERT INTO user_usage (user_id, trash_bytes, trash_files)
+SELECT user_id, SUM(file_size), COUNT(*) FROM trash WHERE true GROUP BY user_id
+ON CONFLICT (user_id) DO UPDATE SET trash_bytes = excluded.trash_bytes, trash_files = excluded.trash_files;
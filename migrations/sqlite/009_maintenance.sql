@@ -0,0 +1,8 @@
+-- At most one row, present while the server is in maintenance mode and file
+-- writes are refused.
+CREATE TABLE maintenance (
+    id INTEGER PRIMARY KEY CHECK (id = 1),
+    reason TEXT,
+    started_by INTEGER REFERENCES users(id) ON DELETE SET NULL,
+    started_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
@@ -0,0 +1,17 @@
+-- What each image's EXIF data says about it, recorded by the job that generates
+-- its thumbnails after upload; images without EXIF data get a row of NULLs. The
+-- photo timeline places images without a capture time by their modified time.
+-- Replacing the content drops the row until the job has read the new content.
+CREATE TABLE media_metadata (
+    file_id INTEGER PRIMARY KEY REFERENCES filehash(id) ON DELETE CASCADE,
+    taken_at TIMESTAMP,
+    camera_make TEXT,
+    camera_model TEXT,
+    latitude DOUBLE PRECISION,
+    longitude DOUBLE PRECISION
+);
+
+CREATE TRIGGER filehash_drop_media_metadata AFTER UPDATE OF file_hash, file_size, system_path, etag ON filehash
+BEGIN
+    DELETE FROM media_metadata WHERE file_id = NEW.id;
+END;
@@ -0,0 +1,15 @@
+-- Folders of one user's tree opened to another registered user, who reaches them
+-- by passing `owner` to `/get`, `/download` and `/sync`. `folder` has no slashes
+-- at either end, and the grant covers everything below it.
+CREATE TABLE folder_acl (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    owner_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    folder TEXT NOT NULL,
+    grantee_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    -- `read`, or `write` to also sync changes into the folder.
+    permission TEXT NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    UNIQUE (owner_id, folder, grantee_id)
+);
+
+CREATE INDEX folder_acl_grantee_idx ON folder_acl (grantee_id, owner_id);
@@ -0,0 +1,33 @@
+-- Files their clients encrypt end to end. The server knows each one by an opaque
+-- id, with its size and version vector; its name and metadata are ciphertext.
+CREATE TABLE vault_items (
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    item_id TEXT NOT NULL,
+    encrypted_name TEXT NOT NULL,
+    encrypted_metadata TEXT,
+    file_size BIGINT NOT NULL,
+    system_path TEXT NOT NULL UNIQUE,
+    version_vector TEXT NOT NULL DEFAULT '{}',
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (user_id, item_id)
+);
+
+-- Vault items count against quotas like any other file.
+CREATE TRIGGER vault_items_user_usage_insert AFTER INSERT ON vault_items
+BEGIN
+    INSERT INTO user_usage (user_id, bytes, files) VALUES (NEW.user_id, NEW.file_size, 1)
+    ON CONFLICT (user_id) DO UPDATE SET bytes = bytes + excluded.bytes, files = files + 1;
+END;
+
+CREATE TRIGGER vault_items_user_usage_update AFTER UPDATE OF file_size, user_id ON vault_items
+BEGIN
+    UPDATE user_usage SET bytes = bytes - OLD.file_size, files = files - 1 WHERE user_id = OLD.user_id;
+    INSERT INTO user_usage (user_id, bytes, files) VALUES (NEW.user_id, NEW.file_size, 1)
+    ON CONFLICT (user_id) DO UPDATE SET bytes = bytes + excluded.bytes, files = files + 1;
+END;
+
+CREATE TRIGGER vault_items_user_usage_delete AFTER DELETE ON vault_items
+BEGIN
+    UPDATE user_usage SET bytes = bytes - OLD.file_size, files = files - 1 WHERE user_id = OLD.user_id;
+END;
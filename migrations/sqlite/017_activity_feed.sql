@@ -0,0 +1,33 @@
+-- Moves, restores from the trash or an older version, and new shares are logged
+-- too, for the activity feed. SQLite can't change a CHECK in place, so the table
+-- is copied into one with the wider constraint.
+CREATE TABLE audit_log_new (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    user_id INTEGER NOT NULL,
+    username TEXT,
+    device_id TEXT,
+    ip TEXT,
+    operation TEXT NOT NULL
+        CHECK (operation IN ('insert', 'update', 'delete', 'download', 'move', 'restore', 'share')),
+    file_path TEXT NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+INSERT INTO audit_log_new (id, user_id, username, device_id, ip, operation, file_path, created_at)
+SELECT id, user_id, username, device_id, ip, operation, file_path, created_at FROM audit_log;
+
+DROP TABLE audit_log;
+ALTER TABLE audit_log_new RENAME TO audit_log;
+
+CREATE INDEX audit_log_user_path_idx ON audit_log (user_id, file_path, id);
+CREATE INDEX audit_log_user_idx ON audit_log (user_id, id);
+
+CREATE TRIGGER audit_log_no_update BEFORE UPDATE ON audit_log
+BEGIN
+    SELECT RAISE(ABORT, 'audit_log is append-only');
+END;
+
+CREATE TRIGGER audit_log_no_delete BEFORE DELETE ON audit_log
+BEGIN
+    SELECT RAISE(ABORT, 'audit_log is append-only');
+END;
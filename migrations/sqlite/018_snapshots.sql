@@ -0,0 +1,23 @@
+-- Points in time each user's tree can be put back to. `change_seq` is the user's
+-- change counter when it was taken, so a tree that hasn't changed isn't
+-- snapshotted again.
+CREATE TABLE snapshots (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    change_seq BIGINT NOT NULL DEFAULT 0,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX snapshots_user_idx ON snapshots (user_id, id);
+
+CREATE TABLE snapshot_files (
+    snapshot_id INTEGER NOT NULL REFERENCES snapshots(id) ON DELETE CASCADE,
+    file_path TEXT NOT NULL,
+    file_name TEXT,
+    file_hash TEXT NOT NULL,
+    file_size BIGINT NOT NULL,
+    modified_time BIGINT NOT NULL,
+    content_type TEXT,
+    etag TEXT,
+    PRIMARY KEY (snapshot_id, file_path)
+);
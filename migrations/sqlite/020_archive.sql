@@ -0,0 +1,21 @@
+-- Files and folders users keep in a cheaper S3 storage class. A folder's rule
+-- covers everything under it, and the rule closest to a file wins.
+CREATE TABLE archive_rules (
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    path TEXT NOT NULL,
+    storage_class TEXT NOT NULL CHECK (storage_class IN ('STANDARD_IA', 'GLACIER')),
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (user_id, path)
+);
+
+-- The class each archived file's object was moved to, and how far a restore of a
+-- glacial one got. `change_seq` is the file's when it was moved, since rewriting
+-- the object later puts it back in STANDARD.
+CREATE TABLE archived_files (
+    file_id INTEGER PRIMARY KEY REFERENCES filehash(id) ON DELETE CASCADE,
+    storage_class TEXT NOT NULL,
+    change_seq BIGINT,
+    restore_status TEXT CHECK (restore_status IN ('in_progress', 'restored')),
+    restore_expires_at TIMESTAMP,
+    updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
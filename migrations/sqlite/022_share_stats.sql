@@ -0,0 +1,5 @@
+-- How often each share link was used, for `/share/{id}/stats`. Views count the
+-- pages of folder links and previews of file links; downloads count files served.
+ALTER TABLE shares ADD COLUMN view_count BIGINT NOT NULL DEFAULT 0;
+ALTER TABLE shares ADD COLUMN download_count BIGINT NOT NULL DEFAULT 0;
+ALTER TABLE shares ADD COLUMN last_accessed_at TIMESTAMP;
@@ -0,0 +1,17 @@
+-- Where each user wants to be notified, and of which events. `quota_warned` is set
+-- once they were told their quota is nearly used, until usage falls back below.
+CREATE TABLE notification_preferences (
+    user_id INTEGER PRIMARY KEY REFERENCES users(id) ON DELETE CASCADE,
+    email TEXT,
+    ntfy_topic TEXT,
+    push_token TEXT,
+    -- The events notified of; every event when empty.
+    events TEXT NOT NULL DEFAULT '[]',
+    quota_warned BOOLEAN NOT NULL DEFAULT FALSE,
+    updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+);
+
+-- When a device's syncs started failing, cleared by one that doesn't, and whether
+-- its user was told.
+ALTER TABLE devices ADD COLUMN sync_failing_since TIMESTAMP;
+ALTER TABLE devices ADD COLUMN sync_failure_notified BOOLEAN NOT NULL DEFAULT FALSE;
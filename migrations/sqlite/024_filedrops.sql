@@ -0,0 +1,16 @@
+-- File request links: anyone holding one may upload files into `folder` of its
+-- owner until it expires, is revoked, or has taken `max_files` files.
+CREATE TABLE filedrops (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    folder TEXT NOT NULL,
+    token_hash TEXT NOT NULL UNIQUE,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    expires_at TIMESTAMP NOT NULL,
+    revoked_at TIMESTAMP,
+    max_file_bytes BIGINT,
+    max_files INTEGER,
+    file_count INTEGER NOT NULL DEFAULT 0
+);
+
+CREATE INDEX filedrops_user_id_idx ON filedrops (user_id);
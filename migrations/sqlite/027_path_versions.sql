@@ -0,0 +1,28 @@
+-- The highest version each path has reached, kept for paths whose file has been
+-- deleted or moved away, so a file created there later carries on from it rather
+-- than starting over at 1: a version a client holds must never come to name a
+-- different copy than the one it was made against.
+CREATE TABLE path_versions (
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    file_path TEXT NOT NULL,
+    version INTEGER NOT NULL,
+    PRIMARY KEY (user_id, file_path)
+);
+
+-- Skipped for a user being deleted, whose files cascade away after them.
+CREATE TRIGGER filehash_retire_path_version AFTER DELETE ON filehash
+BEGIN
+    INSERT INTO path_versions (user_id, file_path, version)
+    SELECT OLD.user_id, OLD.file_path, OLD.version
+    WHERE EXISTS (SELECT 1 FROM users WHERE id = OLD.user_id)
+    ON CONFLICT (user_id, file_path) DO UPDATE SET version = MAX(version, excluded.version);
+END;
+
+CREATE TRIGGER filehash_retire_moved_path_version AFTER UPDATE OF file_path ON filehash
+WHEN OLD.file_path <> NEW.file_path
+BEGIN
+    INSERT INTO path_versions (user_id, file_path, version)
+    SELECT OLD.user_id, OLD.file_path, OLD.version
+    WHERE EXISTS (SELECT 1 FROM users WHERE id = OLD.user_id)
+    ON CONFLICT (user_id, file_path) DO UPDATE SET version = MAX(version, excluded.version);
+END;
@@ -0,0 +1,308 @@
+use std::fmt;
+
+use reqwest::{
+    multipart::{Form, Part},
+    Response, StatusCode,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    types::{ChangeCursor, ChangesResponse, ContentRef, FileEntry, OnConflict, Operation, PrecheckResponse, SyncReport},
+    SYNC_PAYLOAD_VERSION, UPLOAD_PART_PREFIX,
+};
+
+/// Why a request to the server failed.
+#[derive(Debug)]
+pub enum Error {
+    /// The request didn't get a response, or its body couldn't be read.
+    Http(reqwest::Error),
+    /// The server answered with an error, worded as its `error`.
+    Api { status: StatusCode, message: String },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Http(e) => write!(f, "Request failed: {}", e),
+            Error::Api { status, message } => write!(f, "{}: {}", status, message),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Http(e) => Some(e),
+            Error::Api { .. } => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(e: reqwest::Error) -> Self {
+        Error::Http(e)
+    }
+}
+
+/// One `/sync` request: the payload, the bytes of its inserts and updates, and
+/// the options it's applied with.
+#[derive(Debug, Clone, Default)]
+pub struct SyncRequest {
+    /// Every file and what to do with it, in the order they were added.
+    operations: Vec<(Operation, FileEntry)>,
+    /// `(upload_id, file_path, bytes)` of each part, in payload order.
+    uploads: Vec<(String, String, Vec<u8>)>,
+    conflict_copies: bool,
+    on_conflict: OnConflict,
+    atomic: bool,
+    dry_run: bool,
+}
+
+impl SyncRequest {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds `file` with `bytes` as its content. Its `file_size` is set to match,
+    /// and its `file_hash`, when it has none, to their SHA-256, which the server
+    /// checks them against.
+    pub fn insert(self, file: FileEntry, bytes: Vec<u8>) -> Self {
+        self.upload(Operation::Insert, file, bytes)
+    }
+
+    /// Replaces the content of `file`, sized and hashed as for `insert`. Its
+    /// `base_version` must be the `version` of the file it was made against; the
+    /// update is reported as a conflict if the server copy moved on since.
+    pub fn update(self, file: FileEntry, bytes: Vec<u8>) -> Self {
+        self.upload(Operation::Update, file, bytes)
+    }
+
+    /// Records `file` without any bytes.
+    pub fn insert_metadata_only(mut self, file: FileEntry) -> Self {
+        let file = FileEntry { metadata_only: true, ..file };
+        self.operations.push((Operation::Insert, file));
+        self
+    }
+
+    /// Records `file` with content the server already has, which
+    /// [`PocketClient::precheck`] tells, found by its `file_hash` and `file_size`.
+    pub fn insert_linked(self, file: FileEntry) -> Self {
+        self.link(Operation::Insert, file)
+    }
+
+    /// Replaces the content of `file` with content the server already has, as for
+    /// `insert_linked`. Its `base_version` is required, as for `update`.
+    pub fn update_linked(self, file: FileEntry) -> Self {
+        self.link(Operation::Update, file)
+    }
+
+    /// Moves the file at `file_path` to the trash, unless the server copy has
+    /// moved on from `base_version`, the `version` the client last saw.
+    pub fn delete(mut self, file_path: impl Into<String>, base_version: i64) -> Self {
+        let file = FileEntry { base_version: Some(base_version), ..FileEntry::new(file_path, 0) };
+        self.operations.push((Operation::Delete, file));
+        self
+    }
+
+    /// Moves the file at `from_path` to `to_path`, keeping its content.
+    pub fn rename(mut self, from_path: impl Into<String>, to_path: impl Into<String>) -> Self {
+        let file = FileEntry { from_path: Some(from_path.into()), ..FileEntry::new(to_path, 0) };
+        self.operations.push((Operation::Move, file));
+        self
+    }
+
+    /// Stores an update that conflicts with the server copy beside it, as a
+    /// conflicted copy, instead of rejecting it.
+    pub fn conflict_copies(mut self, enabled: bool) -> Self {
+        self.conflict_copies = enabled;
+        self
+    }
+
+    /// What an insert does when a file already exists at its path.
+    pub fn on_conflict(mut self, on_conflict: OnConflict) -> Self {
+        self.on_conflict = on_conflict;
+        self
+    }
+
+    /// Applies the whole request in one transaction, all or nothing.
+    pub fn atomic(mut self, enabled: bool) -> Self {
+        self.atomic = enabled;
+        self
+    }
+
+    /// Has the server report what would happen without storing anything.
+    pub fn dry_run(mut self, enabled: bool) -> Self {
+        self.dry_run = enabled;
+        self
+    }
+
+    fn link(mut self, operation: Operation, file: FileEntry) -> Self {
+        let file = FileEntry { link: true, ..file };
+        self.operations.push((operation, file));
+        self
+    }
+
+    fn upload(mut self, operation: Operation, file: FileEntry, bytes: Vec<u8>) -> Self {
+        let upload_id = self.uploads.len().to_string();
+        let file = FileEntry {
+            file_hash: file.file_hash.or_else(|| Some(hex::encode(Sha256::digest(&bytes)))),
+            file_size: bytes.len() as i64,
+            upload_id: Some(upload_id.clone()),
+            ..file
+        };
+        self.uploads.push((upload_id, file.file_path.clone(), bytes));
+        self.operations.push((operation, file));
+        self
+    }
+
+    /// The multipart body: the payload first, as the server requires, then one
+    /// `files:<upload_id>` part per upload.
+    fn form(self) -> Form {
+        let operations = self.operations.iter().map(|(op, file)| OperationEntry { op: *op, file }).collect();
+        let payload = Payload { schema_version: SYNC_PAYLOAD_VERSION, conflict_copies: self.conflict_copies, operations };
+        let payload = serde_json::to_string(&payload).expect("payloads serialize");
+
+        let mut form = Form::new().text("payload", payload);
+        for (upload_id, file_path, bytes) in self.uploads {
+            form = form.part(format!("{}{}", UPLOAD_PART_PREFIX, upload_id), Part::bytes(bytes).file_name(file_path));
+        }
+        form
+    }
+
+    fn query(&self) -> Vec<(&'static str, String)> {
+        let mut query = Vec::new();
+        if self.on_conflict == OnConflict::Update {
+            query.push(("on_conflict", "update".to_string()));
+        }
+        if self.atomic {
+            query.push(("atomic", "true".to_string()));
+        }
+        if self.dry_run {
+            query.push(("dry_run", "true".to_string()));
+        }
+        query
+    }
+}
+
+/// The multipart `payload` field.
+#[derive(Serialize)]
+struct Payload<'a> {
+    schema_version: u32,
+    conflict_copies: bool,
+    operations: Vec<OperationEntry<'a>>,
+}
+
+/// A file of the payload, and what to do with it.
+#[derive(Serialize)]
+struct OperationEntry<'a> {
+    op: Operation,
+    #[serde(flatten)]
+    file: &'a FileEntry,
+}
+
+/// A client for one user's files on one server, authenticated by an API token.
+#[derive(Debug, Clone)]
+pub struct PocketClient {
+    http: reqwest::Client,
+    base_url: String,
+    token: String,
+    device_id: Option<String>,
+}
+
+impl PocketClient {
+    /// A client for the server at `base_url`, such as `https://pocket.example.com`.
+    pub fn new(base_url: impl Into<String>, token: impl Into<String>) -> Self {
+        Self {
+            http: reqwest::Client::new(),
+            base_url: base_url.into().trim_end_matches('/').to_string(),
+            token: token.into(),
+            device_id: None,
+        }
+    }
+
+    /// Sends `device_id`, registered through `/devices/register`, with every
+    /// request. `/sync` requires one.
+    pub fn with_device(mut self, device_id: impl Into<String>) -> Self {
+        self.device_id = Some(device_id.into());
+        self
+    }
+
+    /// Sends requests through `http` instead of a client of its own, e.g. to set
+    /// timeouts or a proxy.
+    pub fn with_http_client(mut self, http: reqwest::Client) -> Self {
+        self.http = http;
+        self
+    }
+
+    /// Applies `request`. Files that failed, conflicted or were locked are in the
+    /// report rather than an error.
+    pub async fn sync(&self, request: SyncRequest) -> Result<SyncReport, Error> {
+        let query = request.query();
+        let response = self.post("/sync").query(&query).multipart(request.form()).send().await?;
+        match response.status() {
+            StatusCode::OK | StatusCode::MULTI_STATUS | StatusCode::UNPROCESSABLE_ENTITY => Ok(response.json().await?),
+            _ => Err(api_error(response).await),
+        }
+    }
+
+    /// Which of `files` the server already has, so a sync can link them instead of
+    /// uploading their bytes again.
+    pub async fn precheck(&self, files: &[ContentRef]) -> Result<PrecheckResponse, Error> {
+        let body = serde_json::json!({ "files": files });
+        json(self.post("/sync/precheck").json(&body).send().await?).await
+    }
+
+    /// The changes after `cursor`, or from the beginning without one.
+    pub async fn changes(&self, cursor: Option<ChangeCursor>) -> Result<ChangesResponse, Error> {
+        let mut request = self.get("/changes");
+        if let Some(ChangeCursor(since)) = cursor {
+            request = request.query(&[("since", since)]);
+        }
+        json(request.send().await?).await
+    }
+
+    /// The content of the file at `file_path`, streamed through the server.
+    pub async fn download(&self, file_path: &str) -> Result<Vec<u8>, Error> {
+        let response = self.get("/download/direct").query(&[("file_path", file_path)]).send().await?;
+        if !response.status().is_success() {
+            return Err(api_error(response).await);
+        }
+        Ok(response.bytes().await?.to_vec())
+    }
+
+    fn get(&self, path: &str) -> reqwest::RequestBuilder {
+        self.authenticated(self.http.get(format!("{}{}", self.base_url, path)))
+    }
+
+    fn post(&self, path: &str) -> reqwest::RequestBuilder {
+        self.authenticated(self.http.post(format!("{}{}", self.base_url, path)))
+    }
+
+    fn authenticated(&self, request: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+        let request = request.bearer_auth(&self.token);
+        match &self.device_id {
+            Some(device_id) => request.header("X-Device-Id", device_id),
+            None => request,
+        }
+    }
+}
+
+async fn json<T: DeserializeOwned>(response: Response) -> Result<T, Error> {
+    if !response.status().is_success() {
+        return Err(api_error(response).await);
+    }
+    Ok(response.json().await?)
+}
+
+/// The `error` of a failure response, or its status when it has none.
+async fn api_error(response: Response) -> Error {
+    let status = response.status();
+    let message = response
+        .json::<serde_json::Value>()
+        .await
+        .ok()
+        .and_then(|body| body["error"].as_str().map(str::to_string))
+        .unwrap_or_else(|| status.canonical_reason().unwrap_or_default().to_string());
+    Error::Api { status, message }
+}
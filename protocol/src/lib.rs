@@ -0,0 +1,41 @@
+//! The wire format of pocket-server, and a small async client for it.
+//!
+//! `pocket-server` serves the types here as JSON; apps that sync with it can use
+//! them instead of hand-rolling the `/sync` multipart body. [`SyncRequest`]
+//! builds a payload along with the parts carrying its files' bytes, and
+//! [`PocketClient`] sends it:
+//!
+//! ```no_run
+//! # async fn example() -> Result<(), pocket_protocol::Error> {
+//! use pocket_protocol::{FileEntry, PocketClient, SyncRequest};
+//!
+//! let client = PocketClient::new("https://pocket.example.com", "pk_...").with_device("laptop");
+//! let request = SyncRequest::new().insert(FileEntry::new("notes/todo.txt", 1_700_000_000), b"milk".to_vec());
+//! let report = client.sync(request).await?;
+//! assert_eq!(report.summary.failed, 0);
+//!
+//! let page = client.changes(None).await?;
+//! let next_page = client.changes(page.cursor).await?;
+//! assert!(next_page.cursor >= page.cursor);
+//! # Ok(())
+//! # }
+//! ```
+
+mod client;
+mod types;
+
+pub use client::{Error, PocketClient, SyncRequest};
+pub use types::{
+    Change, ChangeCursor, ChangesResponse, ContentRef, FailureCode, FileConflict, FileEntry, FileFailure, FileLock,
+    FileLocked, OnConflict, Operation, OperationResult, PrecheckResponse, SyncReport, SyncSummary,
+};
+
+/// Newest `/sync` payload format this crate writes. Every file is listed under
+/// `operations` with the `op` to apply to it, every insert and update that sends
+/// bytes names the multipart part they come in by `upload_id`, and every update
+/// and delete gives the `base_version` of the file it was made against.
+pub const SYNC_PAYLOAD_VERSION: u32 = 4;
+
+/// Prefix of the name of a `/sync` part holding the bytes of the entry whose
+/// `upload_id` follows it.
+pub const UPLOAD_PART_PREFIX: &str = "files:";
@@ -0,0 +1,266 @@
+use std::collections::{BTreeMap, HashMap};
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Deserialize, Serialize, Hash, Eq, PartialEq, Debug, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Operation {
+    Insert,
+    Update,
+    Delete,
+    /// Gives a file a new path without sending its content again. The entry's
+    /// `from_path` is where the file is, and `file_path` where it goes.
+    Move,
+}
+
+/// A file as `/sync` takes it and as the server lists it.
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq)]
+pub struct FileEntry {
+    pub file_name: String,
+    pub file_path: String,
+    #[serde(default)]
+    pub file_hash: Option<String>,
+    #[serde(default)]
+    pub file_size: i64,
+    pub modified_time: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub etag: Option<String>,
+    /// Server-side timestamps, set by the database; ignored in payloads.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub updated_at: Option<String>,
+    /// Counts the changes to the file's content and path, starting at 1; set by
+    /// the server and ignored in payloads.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<i64>,
+    /// Set on insert results that matched an existing row and were not re-uploaded.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub skipped: bool,
+    /// For updates: the `modified_time` and hash the client last saw on the server.
+    /// When given and the server copy has moved on, the update is reported as a conflict.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub base_modified_time: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub base_hash: Option<String>,
+    /// For updates and deletes: the `version` the client last saw on the server,
+    /// which the server requires. When the server copy has moved on, the file is
+    /// reported as a conflict with the `version_mismatch` code.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub base_version: Option<i64>,
+    /// Set on insert results that stored a conflicting update as a conflicted copy:
+    /// the path the update was meant for.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub conflict_copy_of: Option<String>,
+    /// For moves: the path the file is moved from.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub from_path: Option<String>,
+    /// Labels attached to the file. On an insert or update a list replaces the
+    /// file's tags, and leaving it out keeps them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tags: Option<Vec<String>>,
+    /// Key-value metadata attached to the file, replaced the same way as `tags`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<BTreeMap<String, String>>,
+    /// For inserts and updates: the id of the part, named `files:<upload_id>`,
+    /// holding the file's bytes. [`SyncRequest`](crate::SyncRequest) assigns it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub upload_id: Option<String>,
+    /// For inserts: the file is recorded without bytes on purpose.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub metadata_only: bool,
+    /// For inserts and updates: the bytes are copied from a file the user already
+    /// has with the same `file_hash` and `file_size`, instead of being sent.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub link: bool,
+}
+
+impl FileEntry {
+    /// An entry for `file_path`, named after its last segment.
+    pub fn new(file_path: impl Into<String>, modified_time: i64) -> Self {
+        let file_path = file_path.into();
+        Self {
+            file_name: file_path.rsplit('/').next().unwrap_or_default().to_string(),
+            file_path,
+            modified_time,
+            ..Self::default()
+        }
+    }
+}
+
+/// What went wrong with a file, for clients to act on. Unlike `error`, which is
+/// worded for people and may change, codes are stable.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum FailureCode {
+    /// The file, or the file a move starts from, doesn't exist.
+    NotFound,
+    /// A file already exists at the path.
+    AlreadyExists,
+    /// The path is listed twice in the payload.
+    DuplicatePath,
+    /// The server copy changed since the update's base.
+    Conflict,
+    /// The server copy is at another version than the `base_version` given.
+    VersionMismatch,
+    /// Another device holds a lock on the file.
+    Locked,
+    /// The upload doesn't match the size or hash the payload gives it.
+    IntegrityFailure,
+    /// No part was sent for the entry's `upload_id`.
+    MissingUpload,
+    /// Storing the files would take the user over their storage quota.
+    QuotaExceeded,
+    /// The entry is malformed, such as a move without `from_path`.
+    InvalidRequest,
+    /// The file was fine, but another file of an atomic sync failed.
+    RolledBack,
+    /// Storage failed transiently; retrying may work.
+    StorageUnavailable,
+    /// The database failed transiently; retrying may work.
+    DatabaseUnavailable,
+    /// The upload policy refuses the file, by its extension, path or size.
+    PolicyViolation,
+    /// The path is spelled differently from another, existing or in the payload,
+    /// that it stands for once normalized.
+    PathCollision,
+    /// Anything else, including codes newer than this crate.
+    #[serde(other)]
+    Internal,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct FileFailure {
+    pub file_path: String,
+    pub code: FailureCode,
+    pub error: String,
+}
+
+/// An update or delete refused because the server copy changed since the client's base.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct FileConflict {
+    pub file_path: String,
+    /// `version_mismatch` when the `base_version` given is out of date, and
+    /// `conflict` when another part of the base is.
+    pub code: FailureCode,
+    pub error: String,
+    /// The `base_version` the client gave, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub base_version: Option<i64>,
+    /// The server's current version of the file.
+    pub server: Box<FileEntry>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct FileLock {
+    pub file_path: String,
+    pub device_id: String,
+    #[serde(default)]
+    pub acquired_at: Option<String>,
+    pub expires_at: String,
+}
+
+/// An update refused because another device holds a lock on the file.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct FileLocked {
+    pub file_path: String,
+    pub code: FailureCode,
+    pub error: String,
+    pub lock: FileLock,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq)]
+pub struct OperationResult {
+    pub success: Vec<FileEntry>,
+    pub failure: Vec<FileFailure>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub conflict: Vec<FileConflict>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub locked: Vec<FileLocked>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SyncSummary {
+    pub total: usize,
+    pub succeeded: usize,
+    pub failed: usize,
+    pub conflicted: usize,
+    pub locked: usize,
+}
+
+/// Body returned by `/sync`: per-operation results plus overall counts.
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq)]
+pub struct SyncReport {
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub dry_run: bool,
+    pub summary: SyncSummary,
+    pub results: HashMap<Operation, OperationResult>,
+}
+
+impl SyncReport {
+    /// The results of one operation, empty when the payload had none.
+    pub fn results(&self, operation: Operation) -> OperationResult {
+        self.results.get(&operation).cloned().unwrap_or_default()
+    }
+}
+
+/// What an insert does when a file already exists at its `file_path`.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum OnConflict {
+    #[default]
+    Fail,
+    Update,
+}
+
+/// A position in a user's change feed, passed back to `/changes` as `since` to
+/// get only what changed after it.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(transparent)]
+pub struct ChangeCursor(pub i64);
+
+/// One entry of the change feed: the file's current row, or a deletion.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct Change {
+    pub change_seq: i64,
+    pub file_path: String,
+    pub deleted: bool,
+    /// When a deletion happened.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deleted_at: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file: Option<FileEntry>,
+}
+
+/// Body returned by `/changes`.
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq)]
+pub struct ChangesResponse {
+    #[serde(default)]
+    pub data: Option<Vec<Change>>,
+    /// Pass back to get only what changed after this page.
+    #[serde(default)]
+    pub cursor: Option<ChangeCursor>,
+    /// More changes are already waiting past `cursor`.
+    #[serde(default)]
+    pub has_more: bool,
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+/// A file's content, by its hex SHA-256 and size.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct ContentRef {
+    pub file_hash: String,
+    pub file_size: i64,
+}
+
+/// Body returned by `/sync/precheck`.
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq)]
+pub struct PrecheckResponse {
+    /// Contents the server has, which a sync can link to instead of uploading.
+    pub available: Vec<ContentRef>,
+    /// Contents that have to be uploaded.
+    pub missing: Vec<ContentRef>,
+}
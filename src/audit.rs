@@ -0,0 +1,106 @@
+//! The append-only `audit_log`: who inserted, updated, moved, deleted, restored,
+//! shared or downloaded each file, from which device and address. Rows are written from background tasks,
+//! like webhook deliveries, so logging never holds up the request it describes.
+
+use std::net::IpAddr;
+
+use tracing::{warn, Instrument};
+
+use crate::{
+    db::{on_db, Array},
+    models::{AuthUser, FileChange, Operation},
+    AppState,
+};
+
+/// Logs the applied changes of one request, as published to the user's devices.
+pub(crate) fn record_changes(state: &AppState, user: &AuthUser, changes: &[FileChange]) {
+    let entries = changes
+        .iter()
+        .map(|change| {
+            let operation = match change.operation {
+                Operation::Insert => "insert",
+                Operation::Update => "update",
+                Operation::Delete => "delete",
+                Operation::Move => "move",
+            };
+            (operation, change.file_path.clone())
+        })
+        .collect();
+    record(state, user.user_id, Some(user), None, entries);
+}
+
+/// Logs files `user` was served, or handed a URL for.
+pub(crate) fn record_downloads(state: &AppState, user: &AuthUser, paths: Vec<String>) {
+    record(state, user.user_id, Some(user), None, paths.into_iter().map(|path| ("download", path)).collect());
+}
+
+/// Logs files `user` brought back from the trash or an older version.
+pub(crate) fn record_restores(state: &AppState, user: &AuthUser, paths: Vec<String>) {
+    record(state, user.user_id, Some(user), None, paths.into_iter().map(|path| ("restore", path)).collect());
+}
+
+/// Logs a file or folder `owner` shared, through a link or with another user.
+pub(crate) fn record_share(state: &AppState, owner: &AuthUser, path: String) {
+    record(state, owner.user_id, Some(owner), None, vec![("share", path)]);
+}
+
+/// Logs a file of `owner`'s downloaded through one of their share links, by whoever
+/// opened it from `ip`.
+pub(crate) fn record_share_download(state: &AppState, owner: i32, file_path: String, ip: IpAddr) {
+    record(state, owner, None, Some(ip), vec![("download", file_path)]);
+}
+
+/// Logs a file of `owner`'s moved into the quarantine by a content scan, as a
+/// delete nobody made.
+pub(crate) fn record_quarantine(state: &AppState, owner: i32, file_path: String) {
+    record(state, owner, None, None, vec![("delete", file_path)]);
+}
+
+/// `actor` is who acted on `owner`'s files, `None` when it was someone anonymous or
+/// the server itself.
+fn record(
+    state: &AppState,
+    owner: i32,
+    actor: Option<&AuthUser>,
+    ip: Option<IpAddr>,
+    entries: Vec<(&'static str, String)>,
+) {
+    if entries.is_empty() {
+        return;
+    }
+
+    let pool = state.pool.clone();
+    let username = actor.map(|user| user.username.clone());
+    let device_id = actor.and_then(|user| user.device_id.clone());
+    let ip = ip.or(actor.and_then(|user| user.ip)).map(|ip| ip.to_string());
+    let (operations, paths): (Vec<&str>, Vec<String>) = entries.into_iter().unzip();
+
+    tokio::spawn(async move {
+        let sql = pool.sql(
+            r#"
+            INSERT INTO audit_log (user_id, username, device_id, ip, operation, file_path)
+            SELECT $1, $2, $3, $4, operation, file_path
+            FROM UNNEST($5::TEXT[], $6::TEXT[]) AS entry(operation, file_path)
+            "#,
+            r#"
+            INSERT INTO audit_log (user_id, username, device_id, ip, operation, file_path)
+            SELECT $1, $2, $3, $4, value, $6 ->> key
+            FROM json_each($5)
+            "#,
+        );
+        let result = on_db!(&pool, pool => sqlx::query(sql)
+            .bind(owner)
+            .bind(username)
+            .bind(device_id)
+            .bind(ip)
+            .bind(Array(&operations))
+            .bind(Array(&paths))
+            .execute(pool)
+            .await
+            .map(|_| ()));
+
+        if let Err(e) = result {
+            warn!("Failed to record {} audit log entries for user {}: {}", paths.len(), owner, e);
+        }
+    }.in_current_span());
+}
@@ -0,0 +1,276 @@
+use std::env;
+
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use axum::http::{header, HeaderMap, Method};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use jsonwebtoken::{DecodingKey, EncodingKey, Header, Validation};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    db::{on_db, DbPool},
+    env_or,
+    models::{AuthUser, Claims, DownloadClaims, Role, TokenScope},
+    AppState, API_TOKEN_PREFIX, DEFAULT_DOWNLOAD_TOKEN_EXPIRY_SECS, DEFAULT_JWT_EXPIRY_SECS,
+};
+
+/// Keys for the HS256 session tokens issued by `/auth/login`, and for the download
+/// tokens issued by `/download/token`. Those are signed with a key derived from the
+/// same secret, so neither kind of token passes for the other.
+pub(crate) struct JwtKeys {
+    encoding: EncodingKey,
+    decoding: DecodingKey,
+    pub(crate) expiry_secs: i64,
+    download_encoding: EncodingKey,
+    download_decoding: DecodingKey,
+    /// The longest a download token may live.
+    pub(crate) download_expiry_secs: i64,
+}
+
+impl JwtKeys {
+    pub(crate) fn from_env() -> Self {
+        // Without a configured secret, issued sessions stop working after a restart.
+        let secret = env::var("JWT_SECRET")
+            .map(String::into_bytes)
+            .unwrap_or_else(|_| rand::random::<[u8; 32]>().to_vec());
+
+        let download_secret = Sha256::new().chain_update(b"download tokens\n").chain_update(&secret).finalize();
+
+        Self {
+            encoding: EncodingKey::from_secret(&secret),
+            decoding: DecodingKey::from_secret(&secret),
+            expiry_secs: env_or("JWT_EXPIRY_SECS", DEFAULT_JWT_EXPIRY_SECS),
+            download_encoding: EncodingKey::from_secret(&download_secret),
+            download_decoding: DecodingKey::from_secret(&download_secret),
+            download_expiry_secs: env_or("DOWNLOAD_TOKEN_EXPIRY_SECS", DEFAULT_DOWNLOAD_TOKEN_EXPIRY_SECS).max(1),
+        }
+    }
+}
+
+pub(crate) fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+pub(crate) fn generate_token() -> String {
+    format!("{}{}", API_TOKEN_PREFIX, hex::encode(rand::random::<[u8; 32]>()))
+}
+
+pub(crate) fn hash_password(password: &str) -> Result<String, String> {
+    Argon2::default()
+        .hash_password(password.as_bytes(), &SaltString::generate(&mut OsRng))
+        .map(|hash| hash.to_string())
+        .map_err(|e| e.to_string())
+}
+
+pub(crate) fn verify_password(password: &str, hash: &str) -> bool {
+    PasswordHash::new(hash)
+        .is_ok_and(|parsed| Argon2::default().verify_password(password.as_bytes(), &parsed).is_ok())
+}
+
+pub(crate) fn issue_jwt(keys: &JwtKeys, user: &AuthUser) -> Result<String, String> {
+    let now = chrono::Utc::now().timestamp();
+    let claims = Claims {
+        sub: user.user_id,
+        username: user.username.clone(),
+        role: user.role,
+        iat: now,
+        exp: now + keys.expiry_secs,
+    };
+    jsonwebtoken::encode(&Header::default(), &claims, &keys.encoding).map_err(|e| e.to_string())
+}
+
+/// A token that lets whoever holds it download `user`'s file at `file_path` for
+/// the next `expires_in` seconds, without any other credentials.
+pub(crate) fn issue_download_token(
+    keys: &JwtKeys,
+    user: &AuthUser,
+    file_path: &str,
+    expires_in: i64,
+) -> Result<String, String> {
+    let claims = DownloadClaims {
+        sub: user.user_id,
+        username: user.username.clone(),
+        role: user.role,
+        path: file_path.to_string(),
+        exp: chrono::Utc::now().timestamp() + expires_in,
+    };
+    jsonwebtoken::encode(&Header::default(), &claims, &keys.download_encoding).map_err(|e| e.to_string())
+}
+
+/// The claims of a download token, or why it isn't valid.
+pub(crate) fn verify_download_token(
+    keys: &JwtKeys,
+    token: &str,
+) -> Result<DownloadClaims, jsonwebtoken::errors::Error> {
+    let mut validation = Validation::default();
+    // The default leeway would keep a five-minute token working for six.
+    validation.leeway = 0;
+    jsonwebtoken::decode::<DownloadClaims>(token, &keys.download_decoding, &validation).map(|data| data.claims)
+}
+
+pub(crate) fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+}
+
+/// Resolves a bearer token, either a session JWT or an API token, to its user.
+/// Expired, revoked and unknown tokens yield `None`.
+pub(crate) async fn authenticate(state: &AppState, headers: &HeaderMap) -> Result<Option<AuthUser>, sqlx::Error> {
+    let Some(token) = bearer_token(headers) else { return Ok(None) };
+
+    if !token.starts_with(API_TOKEN_PREFIX) {
+        let claims = jsonwebtoken::decode::<Claims>(token, &state.jwt.decoding, &Validation::default());
+        return Ok(claims.ok().map(|data| AuthUser {
+            user_id: data.claims.sub,
+            username: data.claims.username,
+            role: data.claims.role,
+            scope: TokenScope::Full,
+            device_id: None,
+            ip: None,
+        }));
+    }
+
+    api_token_user(state, token).await
+}
+
+/// API tokens are checked against the database so revocation takes effect immediately,
+/// which also records when the token was last used.
+async fn api_token_user(state: &AppState, token: &str) -> Result<Option<AuthUser>, sqlx::Error> {
+    let sql = state.pool.sql(
+        r#"
+        UPDATE api_tokens t
+        SET last_used_at = CURRENT_TIMESTAMP
+        FROM users u
+        WHERE t.user_id = u.id AND t.token_hash = $1 AND t.revoked_at IS NULL
+        RETURNING u.id AS user_id, u.username, u.role, t.scope
+        "#,
+        // SQLite's RETURNING only sees the updated table.
+        r#"
+        UPDATE api_tokens
+        SET last_used_at = CURRENT_TIMESTAMP
+        WHERE token_hash = $1 AND revoked_at IS NULL
+        RETURNING user_id, scope,
+            (SELECT username FROM users WHERE id = user_id) AS username,
+            (SELECT role FROM users WHERE id = user_id) AS role
+        "#,
+    );
+    on_db!(&state.pool, pool => sqlx::query_as::<_, AuthUser>(sql)
+        .bind(hash_token(token))
+        .fetch_optional(pool)
+        .await)
+}
+
+/// The username and password of an HTTP Basic `Authorization` header.
+fn basic_credentials(headers: &HeaderMap) -> Option<(String, String)> {
+    let encoded = headers
+        .get(header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Basic ")?;
+    let decoded = String::from_utf8(BASE64.decode(encoded).ok()?).ok()?;
+    let (username, password) = decoded.split_once(':')?;
+    Some((username.to_string(), password.to_string()))
+}
+
+/// Resolves HTTP Basic credentials, for clients such as WebDAV mounts that can't
+/// send a bearer token. The password is the user's own, or one of their API tokens.
+/// Users with two-factor authentication have to use a token, as Basic has nowhere
+/// to put a code.
+pub(crate) async fn authenticate_basic(state: &AppState, headers: &HeaderMap) -> Result<Option<AuthUser>, sqlx::Error> {
+    let Some((username, password)) = basic_credentials(headers) else { return Ok(None) };
+
+    if password.starts_with(API_TOKEN_PREFIX) {
+        let user = api_token_user(state, &password).await?;
+        return Ok(user.filter(|user| user.username == username));
+    }
+
+    let row = on_db!(&state.pool, pool => sqlx::query_as::<_, (i32, Role, Option<String>)>(
+        "SELECT id, role, password_hash FROM users WHERE username = $1 AND totp_enabled_at IS NULL"
+    )
+    .bind(&username)
+    .fetch_optional(pool)
+    .await)?;
+    Ok(row
+        .filter(|(_, _, hash)| hash.as_deref().is_some_and(|hash| verify_password(&password, hash)))
+        .map(|(user_id, role, _)| AuthUser {
+            user_id,
+            username,
+            role,
+            scope: TokenScope::Full,
+            device_id: None,
+            ip: None,
+        }))
+}
+
+/// The `POST` endpoints that only read, which read-only tokens may call too.
+const READ_POSTS: [&str; 4] = ["/download/urls", "/download/batch", "/download/archive", "/download/token"];
+
+/// What upload-only tokens may call: adding files, and what resuming an upload
+/// and registering the device doing it take.
+const UPLOAD_ROUTES: [(Method, &str); 11] = [
+    (Method::POST, "/sync"),
+    (Method::GET, "/sync/blocks"),
+    (Method::POST, "/sync/blocks"),
+    (Method::POST, "/upload-url"),
+    (Method::POST, "/upload-confirm"),
+    (Method::POST, "/upload/init"),
+    (Method::PATCH, "/upload/{id}"),
+    (Method::HEAD, "/upload/{id}"),
+    (Method::GET, "/upload/{id}/status"),
+    (Method::POST, "/upload/{id}/complete"),
+    (Method::POST, "/devices/register"),
+];
+
+/// Whether a credential with `scope` may make a `method` request to the route `path`.
+pub(crate) fn scope_allows(scope: TokenScope, method: &Method, path: &str) -> bool {
+    match scope {
+        TokenScope::Full => true,
+        TokenScope::Read => is_read(method) || (*method == Method::POST && READ_POSTS.contains(&path)),
+        TokenScope::Upload => UPLOAD_ROUTES.iter().any(|(m, p)| m == method && *p == path),
+    }
+}
+
+/// `scope_allows` for WebDAV, whose requests are told apart by method alone.
+pub(crate) fn dav_scope_allows(scope: TokenScope, method: &Method) -> bool {
+    match scope {
+        TokenScope::Full => true,
+        TokenScope::Read => is_read(method) || method.as_str() == "PROPFIND",
+        TokenScope::Upload => matches!(method.as_str(), "PUT" | "MKCOL" | "OPTIONS"),
+    }
+}
+
+fn is_read(method: &Method) -> bool {
+    [Method::GET, Method::HEAD, Method::OPTIONS].contains(method)
+}
+
+/// Installs `token` as a credential for the `admin` user so a fresh deployment
+/// has a way to mint further tokens.
+pub(crate) async fn bootstrap_admin_token(pool: &DbPool, token: &str) -> Result<(), sqlx::Error> {
+    let user_id = on_db!(pool, pool => sqlx::query_scalar::<_, i32>(
+        r#"
+        INSERT INTO users (username, role)
+        VALUES ('admin', 'admin')
+        ON CONFLICT (username) DO UPDATE SET role = 'admin'
+        RETURNING id
+        "#
+    )
+    .fetch_one(pool)
+    .await)?;
+
+    on_db!(pool, pool => sqlx::query(
+        r#"
+        INSERT INTO api_tokens (user_id, token_hash)
+        VALUES ($1, $2)
+        ON CONFLICT (token_hash) DO NOTHING
+        "#
+    )
+    .bind(user_id)
+    .bind(hash_token(token))
+    .execute(pool)
+    .await
+    .map(|_| ()))
+}
@@ -0,0 +1,134 @@
+//! Byte-rate limits on what users and their devices upload and download, so one
+//! device's initial sync can't take a whole connection. Bodies are slowed rather
+//! than refused: once a body has used up its budget the next frame waits until the
+//! budget refills, which backs off the sender through TCP.
+
+use std::{
+    collections::HashMap,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{ready, Context, Poll},
+    time::{Duration, Instant},
+};
+
+use axum::body::{Body, Bytes, HttpBody};
+use http_body::{Frame, SizeHint};
+use tokio::time::Sleep;
+
+use crate::RATE_LIMIT_MAX_TRACKED_KEYS;
+
+struct ByteBucket {
+    /// Bytes that can pass before waiting; negative while a frame larger than the
+    /// budget is paid off.
+    tokens: f64,
+    last_refill: Instant,
+}
+
+/// The byte buckets of every user and device seen lately. Each holds up to a
+/// second's worth of its rate.
+#[derive(Default)]
+pub(crate) struct Bandwidth {
+    buckets: Mutex<HashMap<String, ByteBucket>>,
+}
+
+impl Bandwidth {
+    /// Takes `bytes` from the bucket for `key`, returning how long the body has to
+    /// wait before sending more.
+    fn take(&self, key: &str, bytes_per_sec: u64, bytes: usize) -> Duration {
+        let rate = bytes_per_sec.max(1) as f64;
+        let mut buckets = self.buckets.lock().unwrap();
+        let now = Instant::now();
+
+        if buckets.len() > RATE_LIMIT_MAX_TRACKED_KEYS {
+            buckets.retain(|_, b| now.duration_since(b.last_refill) < Duration::from_secs(3600));
+        }
+
+        let bucket = buckets.entry(key.to_string()).or_insert(ByteBucket { tokens: rate, last_refill: now });
+        let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(rate) - bytes as f64;
+        bucket.last_refill = now;
+
+        if bucket.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-bucket.tokens / rate)
+        }
+    }
+}
+
+/// The buckets one body's bytes count against, with each one's rate.
+#[derive(Clone)]
+pub(crate) struct Throttle {
+    bandwidth: Arc<Bandwidth>,
+    limits: Vec<(String, u64)>,
+}
+
+impl Throttle {
+    pub(crate) fn new(bandwidth: Arc<Bandwidth>) -> Self {
+        Self { bandwidth, limits: Vec::new() }
+    }
+
+    /// Also counts against the bucket for `key`, when there's a rate for it.
+    pub(crate) fn limit(mut self, key: String, bytes_per_sec: Option<u64>) -> Self {
+        if let Some(rate) = bytes_per_sec {
+            self.limits.push((key, rate));
+        }
+        self
+    }
+
+    /// The body, slowed to every rate this throttle holds it to.
+    pub(crate) fn wrap(self, body: Body) -> Body {
+        if self.limits.is_empty() {
+            return body;
+        }
+        Body::new(ThrottledBody { inner: body, throttle: self, delay: None })
+    }
+
+    fn take(&self, bytes: usize) -> Duration {
+        self.limits
+            .iter()
+            .map(|(key, rate)| self.bandwidth.take(key, *rate, bytes))
+            .max()
+            .unwrap_or_default()
+    }
+}
+
+/// A body whose data frames are spaced out to a `Throttle`'s rates. Trailers pass
+/// as they come, so gRPC statuses still arrive.
+struct ThrottledBody {
+    inner: Body,
+    throttle: Throttle,
+    /// What has to pass before the next frame is read.
+    delay: Option<Pin<Box<Sleep>>>,
+}
+
+impl HttpBody for ThrottledBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Frame<Bytes>, axum::Error>>> {
+        if let Some(delay) = self.delay.as_mut() {
+            ready!(delay.as_mut().poll(cx));
+            self.delay = None;
+        }
+
+        let frame = ready!(Pin::new(&mut self.inner).poll_frame(cx));
+        if let Some(Ok(frame)) = &frame
+            && let Some(data) = frame.data_ref()
+        {
+            let wait = self.throttle.take(data.len());
+            if !wait.is_zero() {
+                self.delay = Some(Box::pin(tokio::time::sleep(wait)));
+            }
+        }
+        Poll::Ready(frame)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
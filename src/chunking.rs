@@ -0,0 +1,88 @@
+//! Content-defined chunking for `/sync/blocks`. Boundaries depend only on the
+//! bytes around them, so an edit changes the chunks it touches and leaves the rest
+//! of a file's manifest as it was.
+//!
+//! A chunk's rolling hash starts at zero and takes in each byte `b` as
+//! `hash = (hash << 1) + GEAR[b]`, wrapping. The chunk ends after the byte that
+//! leaves the low `BLOCK_MASK_BITS` bits of the hash zero once it holds at least
+//! `MIN_BLOCK_BYTES`, or after its `MAX_BLOCK_BYTES`th byte. `GEAR[i]` is the
+//! `i + 1`th output of SplitMix64 seeded with zero, so clients can build the same
+//! table and cut the same chunks.
+
+use sha2::{Digest, Sha256};
+
+use crate::{BLOCK_MASK_BITS, MAX_BLOCK_BYTES, MIN_BLOCK_BYTES};
+
+const GEAR: [u64; 256] = gear_table();
+
+const BOUNDARY_MASK: u64 = (1 << BLOCK_MASK_BITS) - 1;
+
+const fn gear_table() -> [u64; 256] {
+    let mut table = [0; 256];
+    let mut state: u64 = 0;
+    let mut i = 0;
+    while i < table.len() {
+        state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+}
+
+/// One chunk of a file: the hex SHA-256 of its bytes and its length.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Chunk {
+    pub(crate) sha256: String,
+    pub(crate) size: i64,
+}
+
+/// Cuts the bytes fed to it into chunks as they pass, without holding on to them.
+#[derive(Default)]
+pub(crate) struct Chunker {
+    hash: u64,
+    len: usize,
+    digest: Sha256,
+    chunks: Vec<Chunk>,
+}
+
+impl Chunker {
+    pub(crate) fn update(&mut self, mut data: &[u8]) {
+        while let Some(end) = self.boundary(data) {
+            self.digest.update(&data[..end]);
+            self.cut();
+            data = &data[end..];
+        }
+        self.digest.update(data);
+    }
+
+    /// The chunks of everything fed in, the last one ending with the data.
+    pub(crate) fn finish(mut self) -> Vec<Chunk> {
+        if self.len > 0 {
+            self.cut();
+        }
+        self.chunks
+    }
+
+    /// Where in `data` the current chunk ends, if it does. Bytes before that
+    /// point are counted into the chunk.
+    fn boundary(&mut self, data: &[u8]) -> Option<usize> {
+        for (i, &byte) in data.iter().enumerate() {
+            self.hash = (self.hash << 1).wrapping_add(GEAR[byte as usize]);
+            self.len += 1;
+            if self.len >= MAX_BLOCK_BYTES || (self.len >= MIN_BLOCK_BYTES && self.hash & BOUNDARY_MASK == 0) {
+                return Some(i + 1);
+            }
+        }
+        None
+    }
+
+    fn cut(&mut self) {
+        let digest = std::mem::take(&mut self.digest);
+        self.chunks.push(Chunk { sha256: hex::encode(digest.finalize()), size: self.len as i64 });
+        self.hash = 0;
+        self.len = 0;
+    }
+}
@@ -0,0 +1,879 @@
+use std::{collections::HashMap, env, path::PathBuf};
+
+use serde::Deserialize;
+
+use crate::{
+    env_or, DEFAULT_MAX_BODY_BYTES, DEFAULT_PRESIGN_EXPIRY_SECS, DEFAULT_SPOOL_THRESHOLD_BYTES, LETS_ENCRYPT_DIRECTORY_URL,
+};
+
+/// Default TOML file read at startup when `POCKET_CONFIG` isn't set.
+const DEFAULT_CONFIG_FILE: &str = "pocket.toml";
+
+/// Server settings that used to be literals. Values come from the TOML file named
+/// by `POCKET_CONFIG` (or `pocket.toml` when present), and environment variables
+/// override whatever the file sets.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct AppConfig {
+    /// `s3`, `local` or `memory`.
+    pub storage_backend: String,
+    pub bucket: String,
+    /// The bucket `POST /admin/migrate` copies every object to, on the same S3
+    /// service as `bucket`.
+    pub migration_bucket: Option<String>,
+    /// Falls back to the AWS SDK's own region resolution when unset.
+    pub region: Option<String>,
+    /// Custom S3 endpoint, e.g. a MinIO server.
+    pub endpoint_url: Option<String>,
+    pub force_path_style: bool,
+    /// Static S3 credentials, for services such as MinIO or Backblaze B2. The AWS
+    /// SDK's own credential chain is used when unset.
+    pub access_key_id: Option<String>,
+    pub secret_access_key: Option<String>,
+    pub credentials: CredentialsConfig,
+    pub local_storage_dir: PathBuf,
+    /// Store identical content once, shared between every key that holds it.
+    pub dedup: bool,
+    /// Treat paths differing only in the case of ASCII letters as one, the way
+    /// Windows and macOS do, refusing a file whose path is an existing one's in
+    /// other case. Paths are otherwise case-sensitive.
+    pub case_insensitive_paths: bool,
+    /// Base64 of the 32-byte key that wraps every data key stored content is
+    /// encrypted with. Content is stored as uploaded when unset.
+    pub encryption_master_key: Option<String>,
+    pub compression: CompressionConfig,
+    pub cache: CacheConfig,
+    pub scanning: ScanConfig,
+    pub notifications: NotificationConfig,
+    pub remote_upload: RemoteUploadConfig,
+    pub routing: RoutingConfig,
+    /// Lifetime of every presigned download and upload URL handed out.
+    pub presign_expiry_secs: u64,
+    pub port: u16,
+    /// Externally reachable address of this server, prefixed to the URLs it hands out.
+    pub public_base_url: String,
+    /// Largest file accepted by any upload path; unlimited when unset.
+    pub max_upload_bytes: Option<u64>,
+    /// Largest `/sync` request body, every file in it included; unlimited when unset.
+    pub max_request_bytes: Option<u64>,
+    /// Largest body accepted by requests that don't upload files, and by the
+    /// `payload` field of a `/sync`.
+    pub max_body_bytes: usize,
+    /// Bytes of an upload to `/stream` held in memory before the rest is spooled
+    /// to a temp file in `spool_dir`.
+    pub spool_threshold_bytes: usize,
+    /// Where spooled uploads are written; the system's temp directory by default.
+    pub spool_dir: PathBuf,
+    /// Bytes of live files each user may store, unless their own quota says otherwise;
+    /// unlimited when unset.
+    pub default_quota_bytes: Option<u64>,
+    /// How long a shutdown waits for background sync jobs before exiting anyway.
+    pub shutdown_timeout_secs: u64,
+    /// Bearer token `/metrics` requires; the endpoint is open when unset.
+    pub metrics_token: Option<String>,
+    pub rate_limits: RateLimitConfig,
+    pub cors: CorsConfig,
+    pub tls: TlsConfig,
+    pub retry: RetryConfig,
+    pub schedule: ScheduleConfig,
+}
+
+/// A token bucket: `burst` requests at once, refilled at `per_min` a minute.
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimitSettings {
+    pub per_min: f64,
+    pub burst: f64,
+}
+
+/// The budgets requests are rate limited against. Callers are identified by their
+/// bearer token, or by IP when they send none.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    /// Per caller and endpoint, for `GET` and `HEAD` requests.
+    pub read: RateLimitSettings,
+    /// Per caller and endpoint, for every other method.
+    pub write: RateLimitSettings,
+    /// Budgets for particular routes, by path as routed (`/sync`, `/upload/{id}`),
+    /// used there instead of `read` or `write`.
+    pub routes: HashMap<String, RateLimitSettings>,
+    /// Shared by all of one user's requests, whichever tokens and endpoints they
+    /// are spread over; unlimited when unset.
+    pub user: Option<RateLimitSettings>,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            read: RateLimitSettings { per_min: 600.0, burst: 60.0 },
+            write: RateLimitSettings { per_min: 60.0, burst: 10.0 },
+            routes: HashMap::new(),
+            user: None,
+        }
+    }
+}
+
+impl RateLimitConfig {
+    /// Overrides from `RATE_LIMIT_{READ,WRITE,SYNC,USER}_{PER_MIN,BURST}`. Setting
+    /// `RATE_LIMIT_SYNC_PER_MIN` gives `/sync` its own budget and `RATE_LIMIT_USER_PER_MIN`
+    /// turns the per-user one on; their burst defaults to a minute's worth.
+    fn apply_env(&mut self) {
+        self.read = env_limit("READ", Some(self.read)).unwrap_or(self.read);
+        self.write = env_limit("WRITE", Some(self.write)).unwrap_or(self.write);
+        if let Some(sync) = env_limit("SYNC", self.routes.get("/sync").copied()) {
+            self.routes.insert("/sync".to_string(), sync);
+        }
+        self.user = env_limit("USER", self.user);
+    }
+}
+
+fn env_limit(name: &str, current: Option<RateLimitSettings>) -> Option<RateLimitSettings> {
+    let per_min = env::var(format!("RATE_LIMIT_{}_PER_MIN", name)).ok().and_then(|v| v.parse().ok());
+    let burst = env::var(format!("RATE_LIMIT_{}_BURST", name)).ok().and_then(|v| v.parse().ok());
+    let per_min: f64 = per_min.or(current.map(|c| c.per_min))?;
+    Some(RateLimitSettings {
+        per_min,
+        burst: burst.or(current.map(|c| c.burst)).unwrap_or(per_min),
+    })
+}
+
+/// Which browser origins may call the API, for web clients served from elsewhere.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct CorsConfig {
+    /// Origins such as `https://drive.example.com`, or `*` for any. Browsers are
+    /// refused cross-origin access when empty.
+    pub allowed_origins: Vec<String>,
+    /// `*` allows any.
+    pub allowed_methods: Vec<String>,
+    /// Request headers browsers may send; `*` allows any.
+    pub allowed_headers: Vec<String>,
+    /// How long browsers may cache a preflight's answer.
+    pub max_age_secs: u64,
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        let list = |items: &[&str]| items.iter().map(|item| item.to_string()).collect();
+        Self {
+            allowed_origins: Vec::new(),
+            allowed_methods: list(&["GET", "HEAD", "POST", "PUT", "PATCH", "DELETE"]),
+            allowed_headers: list(&[
+                "authorization", "content-type", "range", "if-match", "if-none-match",
+                "x-device-id", "x-share-password", "x-request-id", "x-encrypted-name",
+                "x-encrypted-metadata", "x-version-vector",
+            ]),
+            max_age_secs: 3600,
+        }
+    }
+}
+
+impl CorsConfig {
+    /// Overrides from the comma-separated `CORS_ALLOWED_{ORIGINS,METHODS,HEADERS}`
+    /// and `CORS_MAX_AGE_SECS`.
+    fn apply_env(&mut self) {
+        let list = |name: &str| {
+            env::var(name).ok().map(|v| {
+                v.split(',').map(str::trim).filter(|item| !item.is_empty()).map(String::from).collect()
+            })
+        };
+        if let Some(origins) = list("CORS_ALLOWED_ORIGINS") {
+            self.allowed_origins = origins;
+        }
+        if let Some(methods) = list("CORS_ALLOWED_METHODS") {
+            self.allowed_methods = methods;
+        }
+        if let Some(headers) = list("CORS_ALLOWED_HEADERS") {
+            self.allowed_headers = headers;
+        }
+        self.max_age_secs = env_or("CORS_MAX_AGE_SECS", self.max_age_secs);
+    }
+}
+
+/// Compression of stored content with zstd. Content whose extension is skipped,
+/// such as images and archives that are compressed already, is stored as uploaded.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct CompressionConfig {
+    pub enabled: bool,
+    /// zstd level, from 1 (fastest) to 22 (smallest).
+    pub level: i32,
+    /// Extensions, without the dot, of content not worth compressing.
+    pub skip_extensions: Vec<String>,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        let list = |items: &[&str]| items.iter().map(|item| item.to_string()).collect();
+        Self {
+            enabled: false,
+            level: 3,
+            skip_extensions: list(&[
+                "jpg", "jpeg", "png", "gif", "webp", "heic", "avif", "mp3", "m4a", "aac", "ogg", "opus", "flac",
+                "mp4", "m4v", "mov", "mkv", "webm", "avi", "zip", "gz", "tgz", "bz2", "xz", "zst", "7z", "rar",
+                "docx", "xlsx", "pptx", "odt", "epub", "apk", "jar", "pdf",
+            ]),
+        }
+    }
+}
+
+impl CompressionConfig {
+    /// Overrides from `STORAGE_COMPRESSION`, `COMPRESSION_LEVEL` and the
+    /// comma-separated `COMPRESSION_SKIP_EXTENSIONS`.
+    fn apply_env(&mut self) {
+        if let Ok(v) = env::var("STORAGE_COMPRESSION") {
+            self.enabled = v == "true" || v == "1";
+        }
+        self.level = env_or("COMPRESSION_LEVEL", self.level);
+        if let Ok(extensions) = env::var("COMPRESSION_SKIP_EXTENSIONS") {
+            self.skip_extensions = extensions
+                .split(',')
+                .map(|ext| ext.trim().trim_start_matches('.').to_ascii_lowercase())
+                .filter(|ext| !ext.is_empty())
+                .collect();
+        }
+    }
+}
+
+/// A disk cache in front of the storage backend, of objects small enough to be
+/// worth keeping. Recent uploads and downloaded objects are served from it, and
+/// with `write_behind` uploads are acknowledged once on disk and sent on to the
+/// backend in the background. Each server keeps its own; it is empty after a
+/// restart but for uploads still to be written behind.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheConfig {
+    pub enabled: bool,
+    pub dir: PathBuf,
+    /// Bytes the cache may hold before the least recently used objects are evicted.
+    pub max_bytes: u64,
+    /// Larger objects are never cached.
+    pub max_object_bytes: u64,
+    /// Acknowledge uploads before they reach the backend. A server that loses
+    /// its disk loses whatever it hadn't sent yet, and other servers can't read
+    /// an upload until it has been.
+    pub write_behind: bool,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            dir: PathBuf::from("/data/cache"),
+            max_bytes: 1024 * 1024 * 1024,
+            max_object_bytes: 8 * 1024 * 1024,
+            write_behind: false,
+        }
+    }
+}
+
+impl CacheConfig {
+    /// Overrides from `STORAGE_CACHE`, `CACHE_DIR`, `CACHE_MAX_BYTES`,
+    /// `CACHE_MAX_OBJECT_BYTES` and `CACHE_WRITE_BEHIND`.
+    fn apply_env(&mut self) {
+        if let Ok(v) = env::var("STORAGE_CACHE") {
+            self.enabled = v == "true" || v == "1";
+        }
+        if let Ok(dir) = env::var("CACHE_DIR") {
+            self.dir = PathBuf::from(dir);
+        }
+        self.max_bytes = env_or("CACHE_MAX_BYTES", self.max_bytes);
+        self.max_object_bytes = env_or("CACHE_MAX_OBJECT_BYTES", self.max_object_bytes);
+        if let Ok(v) = env::var("CACHE_WRITE_BEHIND") {
+            self.write_behind = v == "true" || v == "1";
+        }
+    }
+}
+
+/// Scanning of uploaded content for malware, by at most one of a clamd, a command
+/// or an HTTP service. Files are scanned in the background once stored, and those
+/// found infected are moved into the quarantine. Off when no scanner is set.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ScanConfig {
+    /// `host:port` of a clamd, sent the content with `INSTREAM`.
+    pub clamav_addr: Option<String>,
+    /// Run through `sh -c` with the content on stdin. Exit status 0 means clean and
+    /// 1 infected, with what it prints naming the finding; anything else is an error.
+    pub command: Option<String>,
+    /// Sent the content in a `POST`, and answers `{"infected": bool, "signature": "..."}`.
+    pub http_url: Option<String>,
+    /// Files larger than this are not scanned. clamd refuses streams over 25 MiB
+    /// unless its `StreamMaxLength` is raised.
+    pub max_bytes: i64,
+    /// Limit on each scan.
+    pub timeout_secs: u64,
+}
+
+impl Default for ScanConfig {
+    fn default() -> Self {
+        Self {
+            clamav_addr: None,
+            command: None,
+            http_url: None,
+            max_bytes: 25 * 1024 * 1024,
+            timeout_secs: 60,
+        }
+    }
+}
+
+impl ScanConfig {
+    /// Overrides from `SCAN_{CLAMAV_ADDR,COMMAND,HTTP_URL,MAX_BYTES,TIMEOUT_SECS}`.
+    fn apply_env(&mut self) {
+        if let Ok(addr) = env::var("SCAN_CLAMAV_ADDR") {
+            self.clamav_addr = Some(addr).filter(|a| !a.is_empty());
+        }
+        if let Ok(command) = env::var("SCAN_COMMAND") {
+            self.command = Some(command).filter(|c| !c.is_empty());
+        }
+        if let Ok(url) = env::var("SCAN_HTTP_URL") {
+            self.http_url = Some(url).filter(|u| !u.is_empty());
+        }
+        self.max_bytes = env_or("SCAN_MAX_BYTES", self.max_bytes);
+        self.timeout_secs = env_or("SCAN_TIMEOUT_SECS", self.timeout_secs).max(1);
+    }
+}
+
+/// How users are told of what happens to their files, by email, ntfy or mobile
+/// push, each used by those who give an address, topic or device token for it in
+/// `/notifications`. A channel is off until its server is set here.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct NotificationConfig {
+    /// SMTP relay mail is handed to.
+    pub smtp_host: Option<String>,
+    pub smtp_port: u16,
+    pub smtp_security: SmtpSecurity,
+    /// Credentials for `AUTH PLAIN`; mail is sent unauthenticated when unset.
+    pub smtp_username: Option<String>,
+    pub smtp_password: Option<String>,
+    /// The `From` of every mail, e.g. `Pocket <pocket@example.com>`.
+    pub smtp_from: String,
+    /// The ntfy server topics are published to, e.g. `https://ntfy.sh`.
+    pub ntfy_url: Option<String>,
+    /// Access token for an ntfy server that requires one.
+    pub ntfy_token: Option<String>,
+    /// Push service sent `{"to", "title", "body", "data"}` for each device token,
+    /// e.g. Expo's `https://exp.host/--/api/v2/push/send`.
+    pub push_url: Option<String>,
+    /// Bearer token for the push service.
+    pub push_token: Option<String>,
+    /// Percent of a quota used at which its user is warned, once until usage
+    /// falls below it again.
+    pub quota_warning_percent: u8,
+    /// How long a device's syncs have to keep failing before its user is told.
+    pub sync_failure_hours: u64,
+    /// How often quotas and failing syncs are looked at.
+    pub sweep_interval_secs: u64,
+    /// Limit on each delivery.
+    pub timeout_secs: u64,
+}
+
+/// How the connection to the SMTP relay is secured.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpSecurity {
+    /// Upgraded with `STARTTLS`, usually on port 587.
+    StartTls,
+    /// TLS from the start, usually on port 465.
+    Tls,
+    /// Plain text, for a relay on the same host or network.
+    None,
+}
+
+impl Default for NotificationConfig {
+    fn default() -> Self {
+        Self {
+            smtp_host: None,
+            smtp_port: 587,
+            smtp_security: SmtpSecurity::StartTls,
+            smtp_username: None,
+            smtp_password: None,
+            smtp_from: "pocket@localhost".to_string(),
+            ntfy_url: None,
+            ntfy_token: None,
+            push_url: None,
+            push_token: None,
+            quota_warning_percent: 90,
+            sync_failure_hours: 24,
+            sweep_interval_secs: 600,
+            timeout_secs: 30,
+        }
+    }
+}
+
+impl NotificationConfig {
+    /// Overrides from `SMTP_{HOST,PORT,SECURITY,USERNAME,PASSWORD,FROM}`,
+    /// `NTFY_{URL,TOKEN}`, `PUSH_{URL,TOKEN}` and
+    /// `NOTIFY_{QUOTA_WARNING_PERCENT,SYNC_FAILURE_HOURS,SWEEP_INTERVAL_SECS,TIMEOUT_SECS}`.
+    fn apply_env(&mut self) {
+        let set = |name: &str, value: &mut Option<String>| {
+            if let Ok(v) = env::var(name) {
+                *value = Some(v).filter(|v| !v.is_empty());
+            }
+        };
+        set("SMTP_HOST", &mut self.smtp_host);
+        set("SMTP_USERNAME", &mut self.smtp_username);
+        set("SMTP_PASSWORD", &mut self.smtp_password);
+        set("NTFY_URL", &mut self.ntfy_url);
+        set("NTFY_TOKEN", &mut self.ntfy_token);
+        set("PUSH_URL", &mut self.push_url);
+        set("PUSH_TOKEN", &mut self.push_token);
+        self.smtp_port = env_or("SMTP_PORT", self.smtp_port);
+        match env::var("SMTP_SECURITY").as_deref() {
+            Ok("starttls") => self.smtp_security = SmtpSecurity::StartTls,
+            Ok("tls") => self.smtp_security = SmtpSecurity::Tls,
+            Ok("none") => self.smtp_security = SmtpSecurity::None,
+            Ok(other) => panic!("SMTP_SECURITY must be starttls, tls or none, not {}", other),
+            Err(_) => {}
+        }
+        if let Ok(from) = env::var("SMTP_FROM") {
+            self.smtp_from = from;
+        }
+        self.quota_warning_percent = env_or("NOTIFY_QUOTA_WARNING_PERCENT", self.quota_warning_percent).clamp(1, 100);
+        self.sync_failure_hours = env_or("NOTIFY_SYNC_FAILURE_HOURS", self.sync_failure_hours).max(1);
+        self.sweep_interval_secs = env_or("NOTIFY_SWEEP_INTERVAL_SECS", self.sweep_interval_secs).max(1);
+        self.timeout_secs = env_or("NOTIFY_TIMEOUT_SECS", self.timeout_secs).max(1);
+    }
+}
+
+/// Files the server downloads itself for `POST /remote-upload`.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct RemoteUploadConfig {
+    /// Larger downloads are abandoned, besides any `max_upload_bytes`.
+    pub max_bytes: u64,
+    /// Limit on each download, connecting and every redirect included.
+    pub timeout_secs: u64,
+    /// Let URLs reach loopback, private and link-local addresses. Off, so users
+    /// can't have the server fetch from the network it runs in.
+    pub allow_private_addresses: bool,
+}
+
+impl Default for RemoteUploadConfig {
+    fn default() -> Self {
+        Self {
+            max_bytes: 5 * 1024 * 1024 * 1024,
+            timeout_secs: 1800,
+            allow_private_addresses: false,
+        }
+    }
+}
+
+impl RemoteUploadConfig {
+    /// Overrides from `REMOTE_UPLOAD_{MAX_BYTES,TIMEOUT_SECS,ALLOW_PRIVATE}`.
+    fn apply_env(&mut self) {
+        self.max_bytes = env_or("REMOTE_UPLOAD_MAX_BYTES", self.max_bytes);
+        self.timeout_secs = env_or("REMOTE_UPLOAD_TIMEOUT_SECS", self.timeout_secs).max(1);
+        if let Ok(v) = env::var("REMOTE_UPLOAD_ALLOW_PRIVATE") {
+            self.allow_private_addresses = v == "true" || v == "1";
+        }
+    }
+}
+
+/// Buckets besides `bucket` that files can be kept in, such as one in the EU for
+/// EU users, and which users and folders go to each. The bucket an object goes to
+/// is settled when its key is made, so changed routes only apply to files written
+/// afterwards until `POST /admin/reroute` moves the rest. Needs S3 storage.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct RoutingConfig {
+    /// By name. Keys of the objects kept in a target carry its name, so a target
+    /// holding any mustn't be renamed, removed or pointed at another bucket.
+    pub targets: HashMap<String, StorageTarget>,
+    pub routes: Vec<StorageRoute>,
+}
+
+/// A bucket on the same S3 service as `bucket`.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct StorageTarget {
+    pub bucket: String,
+    /// The region of `bucket`, when it isn't `region`.
+    pub region: Option<String>,
+}
+
+/// Sends the files of `user_id`, or of every user when unset, under `prefix` to
+/// the target named `target`.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct StorageRoute {
+    pub user_id: Option<i32>,
+    /// A folder; the user's every file when empty. Uploads through `/upload-url`
+    /// aren't at a path yet, so only routes without one apply to them.
+    #[serde(default)]
+    pub prefix: String,
+    pub target: String,
+}
+
+impl RoutingConfig {
+    /// The target `user_id`'s file at `file_path` belongs in, or `None` for
+    /// `bucket`. The route with the longest matching prefix wins, and of those one
+    /// naming the user beats one for everyone.
+    pub(crate) fn target_for(&self, user_id: i32, file_path: &str) -> Option<&str> {
+        self.routes
+            .iter()
+            .filter(|route| route.user_id.is_none_or(|id| id == user_id))
+            .filter(|route| {
+                let prefix = route.prefix.trim_matches('/');
+                prefix.is_empty()
+                    || file_path
+                        .strip_prefix(prefix)
+                        .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+            })
+            .max_by_key(|route| (route.prefix.trim_matches('/').len(), route.user_id.is_some()))
+            .map(|route| route.target.as_str())
+    }
+
+    /// Panics on routes to targets that aren't defined, and on target names that
+    /// can't be part of a key.
+    pub(crate) fn validate(&self) {
+        for name in self.targets.keys() {
+            if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+                panic!("Storage target name {:?} may only hold letters, digits, - and _", name);
+            }
+        }
+        for route in &self.routes {
+            if !self.targets.contains_key(&route.target) {
+                panic!("Storage route to undefined target {}", route.target);
+            }
+        }
+    }
+}
+
+/// Where the S3 client gets credentials from other than static keys. Credentials
+/// that expire, such as those of an assumed role, are renewed by the client before
+/// they do; `POST /admin/storage/reload` picks up keys or settings changed since.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct CredentialsConfig {
+    /// A profile of the shared AWS config and credentials files, instead of `default`.
+    pub profile: Option<String>,
+    /// A role assumed through STS, with the static keys, the profile or the SDK's
+    /// own chain as the credentials that assume it.
+    pub role_arn: Option<String>,
+    pub role_session_name: String,
+    /// Required by the role's trust policy when it's another account's.
+    pub external_id: Option<String>,
+    /// A file holding the OIDC token `role_arn` is assumed with, as on EKS. The
+    /// token is read again on every renewal, so rotating it in place is enough.
+    pub web_identity_token_file: Option<PathBuf>,
+}
+
+impl Default for CredentialsConfig {
+    fn default() -> Self {
+        Self {
+            profile: None,
+            role_arn: None,
+            role_session_name: "pocket-server".to_string(),
+            external_id: None,
+            web_identity_token_file: None,
+        }
+    }
+}
+
+impl CredentialsConfig {
+    /// Overrides from `S3_PROFILE`, `S3_ROLE_ARN`, `S3_ROLE_SESSION_NAME`,
+    /// `S3_EXTERNAL_ID` and `S3_WEB_IDENTITY_TOKEN_FILE`; empty ones unset them.
+    fn apply_env(&mut self) {
+        if let Ok(profile) = env::var("S3_PROFILE") {
+            self.profile = Some(profile).filter(|p| !p.is_empty());
+        }
+        if let Ok(arn) = env::var("S3_ROLE_ARN") {
+            self.role_arn = Some(arn).filter(|a| !a.is_empty());
+        }
+        if let Ok(name) = env::var("S3_ROLE_SESSION_NAME") {
+            self.role_session_name = name;
+        }
+        if let Ok(id) = env::var("S3_EXTERNAL_ID") {
+            self.external_id = Some(id).filter(|i| !i.is_empty());
+        }
+        if let Ok(file) = env::var("S3_WEB_IDENTITY_TOKEN_FILE") {
+            self.web_identity_token_file = Some(PathBuf::from(file)).filter(|f| !f.as_os_str().is_empty());
+        }
+    }
+}
+
+/// How storage calls and the database writes of synced files are retried when
+/// they fail in a way that may pass, such as an S3 500, throttling or a deadlock.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetryConfig {
+    /// Attempts per storage call, the first included; 1 turns retries off.
+    pub storage_attempts: u32,
+    /// Attempts per database write of a synced file, the first included.
+    pub db_attempts: u32,
+    /// Longest wait before the first retry. It doubles with every retry up to
+    /// `max_delay_ms`, and each wait is picked at random below it.
+    pub base_delay_ms: u64,
+    pub max_delay_ms: u64,
+    /// Limit on each storage attempt. Streamed uploads are only bounded by the S3
+    /// client's own timeouts, since their body can't be replayed. `s3_client`
+    /// spells out how the two add up.
+    pub storage_timeout_secs: u64,
+    /// Limit on each database attempt.
+    pub db_timeout_secs: u64,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            storage_attempts: 3,
+            db_attempts: 3,
+            base_delay_ms: 100,
+            max_delay_ms: 2000,
+            storage_timeout_secs: 60,
+            db_timeout_secs: 10,
+        }
+    }
+}
+
+impl RetryConfig {
+    /// Overrides from `{STORAGE,DB}_RETRY_ATTEMPTS`, `RETRY_{BASE,MAX}_DELAY_MS` and
+    /// `{STORAGE,DB}_TIMEOUT_SECS`.
+    fn apply_env(&mut self) {
+        self.storage_attempts = env_or("STORAGE_RETRY_ATTEMPTS", self.storage_attempts).max(1);
+        self.db_attempts = env_or("DB_RETRY_ATTEMPTS", self.db_attempts).max(1);
+        self.base_delay_ms = env_or("RETRY_BASE_DELAY_MS", self.base_delay_ms);
+        self.max_delay_ms = env_or("RETRY_MAX_DELAY_MS", self.max_delay_ms);
+        self.storage_timeout_secs = env_or("STORAGE_TIMEOUT_SECS", self.storage_timeout_secs);
+        self.db_timeout_secs = env_or("DB_TIMEOUT_SECS", self.db_timeout_secs);
+    }
+}
+
+/// Cron expressions of the background jobs run by the clock rather than every
+/// interval, e.g. `trash_purge = "0 3 * * *"`. A job listed here no longer runs
+/// on its `*_INTERVAL_SECS`; see `scheduler` for the jobs there are.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct ScheduleConfig {
+    /// Expressions by job name, in UTC.
+    pub jobs: HashMap<String, String>,
+}
+
+impl ScheduleConfig {
+    /// Overrides from `SCHEDULE_<JOB>`, e.g. `SCHEDULE_TRASH_PURGE`; an empty one
+    /// takes the job off the schedule.
+    fn apply_env(&mut self) {
+        for (name, expression) in env::vars() {
+            let Some(job) = name.strip_prefix("SCHEDULE_") else { continue };
+            let job = job.to_ascii_lowercase();
+            if expression.trim().is_empty() {
+                self.jobs.remove(&job);
+            } else {
+                self.jobs.insert(job, expression);
+            }
+        }
+    }
+}
+
+/// HTTPS served by the server itself, from certificate files or, with the `acme`
+/// feature, certificates it obtains and renews itself. Plain HTTP when neither is set.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    /// PEM certificate chain, leaf first. Reloaded when the file changes, so
+    /// renewals by e.g. certbot are picked up without a restart.
+    pub cert_path: Option<PathBuf>,
+    /// PEM private key of the certificate.
+    pub key_path: Option<PathBuf>,
+    /// Domains to get a certificate for from an ACME CA such as Let's Encrypt;
+    /// used instead of `cert_path` when set. Needs the `acme` feature.
+    pub acme_domains: Vec<String>,
+    /// Contact address the CA sends expiry warnings to.
+    pub acme_email: Option<String>,
+    pub acme_directory_url: String,
+    /// Where the ACME account key and the issued certificate are kept across restarts.
+    pub acme_cache_dir: PathBuf,
+    /// Port answering the CA's HTTP-01 challenges, which it always sends to port 80.
+    /// Other requests to it are redirected to HTTPS.
+    pub acme_http_port: u16,
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        Self {
+            cert_path: None,
+            key_path: None,
+            acme_domains: Vec::new(),
+            acme_email: None,
+            acme_directory_url: LETS_ENCRYPT_DIRECTORY_URL.to_string(),
+            acme_cache_dir: PathBuf::from("/data/acme"),
+            acme_http_port: 80,
+        }
+    }
+}
+
+impl TlsConfig {
+    /// Overrides from `TLS_CERT_PATH`, `TLS_KEY_PATH`, the comma-separated
+    /// `ACME_DOMAINS`, and `ACME_{EMAIL,DIRECTORY_URL,CACHE_DIR,HTTP_PORT}`.
+    fn apply_env(&mut self) {
+        if let Ok(path) = env::var("TLS_CERT_PATH") {
+            self.cert_path = Some(PathBuf::from(path)).filter(|p| !p.as_os_str().is_empty());
+        }
+        if let Ok(path) = env::var("TLS_KEY_PATH") {
+            self.key_path = Some(PathBuf::from(path)).filter(|p| !p.as_os_str().is_empty());
+        }
+        if let Ok(domains) = env::var("ACME_DOMAINS") {
+            self.acme_domains = domains
+                .split(',')
+                .map(str::trim)
+                .filter(|domain| !domain.is_empty())
+                .map(String::from)
+                .collect();
+        }
+        if let Ok(email) = env::var("ACME_EMAIL") {
+            self.acme_email = Some(email).filter(|e| !e.is_empty());
+        }
+        if let Ok(url) = env::var("ACME_DIRECTORY_URL") {
+            self.acme_directory_url = url;
+        }
+        if let Ok(dir) = env::var("ACME_CACHE_DIR") {
+            self.acme_cache_dir = PathBuf::from(dir);
+        }
+        self.acme_http_port = env_or("ACME_HTTP_PORT", self.acme_http_port);
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.cert_path.is_some() || !self.acme_domains.is_empty()
+    }
+}
+
+impl Default for AppConfig {
+    fn default() -> Self {
+        Self {
+            storage_backend: "s3".to_string(),
+            bucket: "pocket-directory".to_string(),
+            migration_bucket: None,
+            region: None,
+            endpoint_url: None,
+            force_path_style: false,
+            access_key_id: None,
+            secret_access_key: None,
+            credentials: CredentialsConfig::default(),
+            local_storage_dir: PathBuf::from("/data"),
+            dedup: false,
+            case_insensitive_paths: false,
+            encryption_master_key: None,
+            compression: CompressionConfig::default(),
+            cache: CacheConfig::default(),
+            scanning: ScanConfig::default(),
+            notifications: NotificationConfig::default(),
+            remote_upload: RemoteUploadConfig::default(),
+            routing: RoutingConfig::default(),
+            presign_expiry_secs: DEFAULT_PRESIGN_EXPIRY_SECS,
+            port: 8000,
+            public_base_url: String::new(),
+            max_upload_bytes: None,
+            max_request_bytes: None,
+            max_body_bytes: DEFAULT_MAX_BODY_BYTES,
+            spool_threshold_bytes: DEFAULT_SPOOL_THRESHOLD_BYTES,
+            spool_dir: env::temp_dir(),
+            default_quota_bytes: None,
+            shutdown_timeout_secs: 30,
+            metrics_token: None,
+            rate_limits: RateLimitConfig::default(),
+            cors: CorsConfig::default(),
+            tls: TlsConfig::default(),
+            retry: RetryConfig::default(),
+            schedule: ScheduleConfig::default(),
+        }
+    }
+}
+
+impl AppConfig {
+    /// Reads the config file, if any, then applies environment overrides. Panics
+    /// on an unreadable or malformed file rather than starting with the wrong settings.
+    pub fn load() -> Self {
+        Self::try_load().unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Like `load`, but with an error instead of a panic, for reading the settings
+    /// again while running.
+    pub(crate) fn try_load() -> Result<Self, String> {
+        let explicit = env::var("POCKET_CONFIG").ok();
+        let path = explicit.clone().unwrap_or_else(|| DEFAULT_CONFIG_FILE.to_string());
+
+        let mut config = match std::fs::read_to_string(&path) {
+            Ok(contents) => toml::from_str(&contents).map_err(|e| format!("Invalid config file {}: {}", path, e))?,
+            Err(e) if explicit.is_some() => return Err(format!("Failed to read config file {}: {}", path, e)),
+            Err(_) => Self::default(),
+        };
+        config.apply_env();
+        Ok(config)
+    }
+
+    fn apply_env(&mut self) {
+        if let Ok(backend) = env::var("STORAGE_BACKEND") {
+            self.storage_backend = backend;
+        }
+        if let Ok(bucket) = env::var("S3_BUCKET") {
+            self.bucket = bucket;
+        }
+        if let Ok(bucket) = env::var("MIGRATION_BUCKET") {
+            self.migration_bucket = Some(bucket).filter(|b| !b.is_empty());
+        }
+        if let Ok(region) = env::var("AWS_REGION") {
+            self.region = Some(region);
+        }
+        if let Ok(endpoint) = env::var("S3_ENDPOINT_URL") {
+            self.endpoint_url = Some(endpoint);
+        }
+        if let Ok(v) = env::var("S3_FORCE_PATH_STYLE") {
+            self.force_path_style = v == "true" || v == "1";
+        }
+        if let Ok(id) = env::var("S3_ACCESS_KEY_ID") {
+            self.access_key_id = Some(id);
+        }
+        if let Ok(secret) = env::var("S3_SECRET_ACCESS_KEY") {
+            self.secret_access_key = Some(secret);
+        }
+        self.credentials.apply_env();
+        if let Ok(dir) = env::var("LOCAL_STORAGE_DIR") {
+            self.local_storage_dir = PathBuf::from(dir);
+        }
+        if let Ok(v) = env::var("STORAGE_DEDUP") {
+            self.dedup = v == "true" || v == "1";
+        }
+        if let Ok(v) = env::var("CASE_INSENSITIVE_PATHS") {
+            self.case_insensitive_paths = v == "true" || v == "1";
+        }
+        if let Ok(key) = env::var("ENCRYPTION_MASTER_KEY") {
+            self.encryption_master_key = Some(key).filter(|k| !k.is_empty());
+        }
+        self.presign_expiry_secs = env_or("PRESIGN_EXPIRY_SECS", self.presign_expiry_secs);
+        self.port = env_or("PORT", self.port);
+        if let Ok(url) = env::var("PUBLIC_BASE_URL") {
+            self.public_base_url = url;
+        }
+        if let Ok(v) = env::var("MAX_UPLOAD_BYTES") {
+            self.max_upload_bytes = v.parse().ok().filter(|&max| max > 0);
+        }
+        if let Ok(v) = env::var("MAX_REQUEST_BYTES") {
+            self.max_request_bytes = v.parse().ok().filter(|&max| max > 0);
+        }
+        self.max_body_bytes = env_or("MAX_BODY_BYTES", self.max_body_bytes).max(1);
+        self.spool_threshold_bytes = env_or("SPOOL_THRESHOLD_BYTES", self.spool_threshold_bytes);
+        if let Ok(dir) = env::var("SPOOL_DIR") {
+            self.spool_dir = PathBuf::from(dir);
+        }
+        if let Ok(v) = env::var("DEFAULT_QUOTA_BYTES") {
+            self.default_quota_bytes = v.parse().ok().filter(|&quota| quota > 0);
+        }
+        self.shutdown_timeout_secs = env_or("SHUTDOWN_TIMEOUT_SECS", self.shutdown_timeout_secs);
+        if let Ok(token) = env::var("METRICS_TOKEN") {
+            self.metrics_token = Some(token).filter(|t| !t.is_empty());
+        }
+        self.rate_limits.apply_env();
+        self.cors.apply_env();
+        self.compression.apply_env();
+        self.cache.apply_env();
+        self.scanning.apply_env();
+        self.notifications.apply_env();
+        self.remote_upload.apply_env();
+        self.tls.apply_env();
+        self.retry.apply_env();
+        self.schedule.apply_env();
+    }
+}
@@ -0,0 +1,410 @@
+use std::collections::{HashMap, HashSet};
+
+use sqlx::{PgExecutor, SqliteExecutor};
+use tracing::warn;
+
+use crate::{
+    db::{on_db, Array, Db, DbConn, DbPool},
+    handlers::sync::conflicts_with_base,
+    models::{FileEntry, FileLock, UploadSession, Usage},
+    AppState,
+};
+
+/// Remembers that `file_path` was deleted so delta listings can report it.
+pub(crate) async fn record_tombstone<'e>(
+    executor: Db<impl PgExecutor<'e>, impl SqliteExecutor<'e>>,
+    user_id: i32,
+    file_path: &str,
+) {
+    let recorded = on_db!(executor, executor => sqlx::query(
+        r#"
+        INSERT INTO tombstones (user_id, file_path)
+        VALUES ($1, $2)
+        ON CONFLICT (user_id, file_path) DO UPDATE SET deleted_at = CURRENT_TIMESTAMP
+        "#
+    )
+    .bind(user_id)
+    .bind(file_path)
+    .execute(executor)
+    .await
+    .map(|_| ()));
+    if let Err(e) = recorded {
+        warn!("Failed to record tombstone for {}: {}", file_path, e);
+    }
+}
+
+/// Forgets a tombstone once a file exists at that path again.
+pub(crate) async fn clear_tombstone<'e>(
+    executor: Db<impl PgExecutor<'e>, impl SqliteExecutor<'e>>,
+    user_id: i32,
+    file_path: &str,
+) {
+    let cleared = on_db!(executor, executor => sqlx::query("DELETE FROM tombstones WHERE user_id = $1 AND file_path = $2")
+        .bind(user_id)
+        .bind(file_path)
+        .execute(executor)
+        .await
+        .map(|_| ()));
+    if let Err(e) = cleared {
+        warn!("Failed to clear tombstone for {}: {}", file_path, e);
+    }
+}
+
+/// Replaces the tags and metadata of the file at `file_path` with those `file`
+/// carries. Only what `file` sets is touched: tags it doesn't list are removed and
+/// new ones added, in one query so neither undoes the other.
+pub(crate) async fn annotate_file<'e>(
+    executor: Db<impl PgExecutor<'e>, impl SqliteExecutor<'e>>,
+    user_id: i32,
+    file_path: &str,
+    file: &FileEntry,
+) -> Result<(), sqlx::Error> {
+    if file.tags.is_none() && file.metadata.is_none() {
+        return Ok(());
+    }
+
+    // SQLite has no data-modifying CTEs, but the statements of one query share
+    // its parameters.
+    let sql = executor.sql(
+        r#"
+        WITH target AS (
+            UPDATE filehash SET metadata = COALESCE($3, metadata)
+            WHERE user_id = $1 AND file_path = $2
+            RETURNING id
+        ), untagged AS (
+            DELETE FROM file_tags
+            WHERE $4::TEXT[] IS NOT NULL AND file_id IN (SELECT id FROM target) AND tag <> ALL($4)
+        )
+        INSERT INTO file_tags (file_id, tag)
+        SELECT target.id, tag FROM target, UNNEST($4::TEXT[]) AS tag
+        ON CONFLICT DO NOTHING
+        "#,
+        r#"
+        UPDATE filehash SET metadata = COALESCE($3, metadata)
+        WHERE user_id = $1 AND file_path = $2;
+        DELETE FROM file_tags
+        WHERE $4 IS NOT NULL AND tag NOT IN (SELECT value FROM json_each($4))
+          AND file_id = (SELECT id FROM filehash WHERE user_id = $1 AND file_path = $2);
+        INSERT INTO file_tags (file_id, tag)
+        SELECT f.id, tag.value FROM filehash f, json_each($4) AS tag
+        WHERE f.user_id = $1 AND f.file_path = $2
+        ON CONFLICT DO NOTHING
+        "#,
+    );
+    on_db!(executor, executor => sqlx::query(sql)
+        .bind(user_id)
+        .bind(file_path)
+        .bind(&file.metadata)
+        .bind(file.tags.as_deref().map(Array))
+        .execute(executor)
+        .await
+        .map(|_| ()))
+}
+
+/// Returns the stored row for `file` if one exists with the same path and hash.
+pub(crate) async fn find_unchanged<'e>(
+    executor: Db<impl PgExecutor<'e>, impl SqliteExecutor<'e>>,
+    user_id: i32,
+    file: &FileEntry,
+) -> Result<Option<FileEntry>, sqlx::Error> {
+    let Some(hash) = &file.file_hash else { return Ok(None) };
+
+    on_db!(executor, executor => sqlx::query_as::<_, FileEntry>(
+        r#"
+        SELECT file_path, file_hash, file_size, modified_time, system_path AS file_name, content_type, etag, created_at, updated_at, version
+        FROM filehash
+        WHERE user_id = $1 AND file_path = $2 AND file_hash = $3
+        "#
+    )
+    .bind(user_id)
+    .bind(&file.file_path)
+    .bind(hash)
+    .fetch_optional(executor)
+    .await)
+}
+
+/// Returns the stored row for `file` if the client gave a base it no longer matches.
+pub(crate) async fn find_update_conflict<'e>(
+    executor: Db<impl PgExecutor<'e>, impl SqliteExecutor<'e>>,
+    user_id: i32,
+    file: &FileEntry,
+) -> Result<Option<FileEntry>, sqlx::Error> {
+    if file.base_modified_time.is_none() && file.base_hash.is_none() && file.base_version.is_none() {
+        return Ok(None);
+    }
+
+    let row = on_db!(executor, executor => sqlx::query_as::<_, FileEntry>(
+        r#"
+        SELECT file_path, file_hash, file_size, modified_time, system_path AS file_name, content_type, etag, created_at, updated_at, version
+        FROM filehash
+        WHERE user_id = $1 AND file_path = $2
+        "#
+    )
+    .bind(user_id)
+    .bind(&file.file_path)
+    .fetch_optional(executor)
+    .await)?;
+
+    Ok(row.filter(|row| conflicts_with_base(file, row)))
+}
+
+/// Reads the row for `file_path` if there is one, locking it until the transaction
+/// ends so nothing else overwrites it in between. SQLite transactions hold the
+/// write lock from the start, so there is nothing more to lock there.
+pub(crate) async fn lock_current(
+    conn: DbConn<'_>,
+    user_id: i32,
+    file_path: &str,
+) -> Result<Option<FileEntry>, sqlx::Error> {
+    let sql = conn.sql(
+        r#"
+        SELECT file_path, file_hash, file_size, modified_time, system_path AS file_name, content_type, etag, created_at, updated_at, version
+        FROM filehash
+        WHERE user_id = $1 AND file_path = $2
+        FOR UPDATE
+        "#,
+        r#"
+        SELECT file_path, file_hash, file_size, modified_time, system_path AS file_name, content_type, etag, created_at, updated_at, version
+        FROM filehash
+        WHERE user_id = $1 AND file_path = $2
+        "#,
+    );
+    on_db!(conn, conn => sqlx::query_as::<_, FileEntry>(sql)
+        .bind(user_id)
+        .bind(file_path)
+        .fetch_optional(conn)
+        .await)
+}
+
+/// The lock in force on `file_path`, if a device other than `device_id` holds it.
+/// Without a device, every lock counts.
+pub(crate) async fn find_foreign_lock<'e>(
+    executor: Db<impl PgExecutor<'e>, impl SqliteExecutor<'e>>,
+    user_id: i32,
+    file_path: &str,
+    device_id: Option<&str>,
+) -> Result<Option<FileLock>, sqlx::Error> {
+    on_db!(executor, executor => sqlx::query_as::<_, FileLock>(
+        r#"
+        SELECT file_path, device_id, acquired_at, expires_at
+        FROM file_locks
+        WHERE user_id = $1 AND file_path = $2 AND expires_at > CURRENT_TIMESTAMP
+          AND COALESCE(device_id <> $3, TRUE)
+        "#
+    )
+    .bind(user_id)
+    .bind(file_path)
+    .bind(device_id)
+    .fetch_optional(executor)
+    .await)
+}
+
+/// `find_unchanged` for many files in one query: the stored rows of those whose
+/// path and hash match, by path.
+pub(crate) async fn find_unchanged_files(
+    pool: &DbPool,
+    user_id: i32,
+    files: &[FileEntry],
+) -> Result<HashMap<String, FileEntry>, sqlx::Error> {
+    let (paths, hashes): (Vec<String>, Vec<String>) = files
+        .iter()
+        .filter_map(|f| f.file_hash.clone().map(|hash| (f.file_path.clone(), hash)))
+        .unzip();
+    if paths.is_empty() {
+        return Ok(HashMap::new());
+    }
+    let sql = pool.sql(
+        r#"
+        SELECT file_path, file_hash, file_size, modified_time, system_path AS file_name, content_type, etag, created_at, updated_at, version
+        FROM filehash
+        WHERE user_id = $1
+          AND (file_path, file_hash) IN (SELECT * FROM UNNEST($2::TEXT[], $3::TEXT[]))
+        "#,
+        r#"
+        SELECT file_path, file_hash, file_size, modified_time, system_path AS file_name, content_type, etag, created_at, updated_at, version
+        FROM filehash
+        WHERE user_id = $1
+          AND (file_path, file_hash) IN (SELECT value, $3 ->> key FROM json_each($2))
+        "#,
+    );
+    let rows = on_db!(pool, pool => sqlx::query_as::<_, FileEntry>(sql)
+        .bind(user_id)
+        .bind(Array(&paths))
+        .bind(Array(&hashes))
+        .fetch_all(pool)
+        .await)?;
+    Ok(rows.into_iter().map(|row| (row.file_path.clone(), row)).collect())
+}
+
+/// `find_update_conflict` for many files in one query: the paths of those whose
+/// base the stored row no longer matches.
+pub(crate) async fn find_update_conflicts(
+    pool: &DbPool,
+    user_id: i32,
+    files: &[&FileEntry],
+) -> Result<HashSet<String>, sqlx::Error> {
+    let based: HashMap<&str, &FileEntry> = files
+        .iter()
+        .filter(|f| f.base_modified_time.is_some() || f.base_hash.is_some() || f.base_version.is_some())
+        .map(|f| (f.file_path.as_str(), *f))
+        .collect();
+    if based.is_empty() {
+        return Ok(HashSet::new());
+    }
+    let paths: Vec<&str> = based.keys().copied().collect();
+    let sql = pool.sql(
+        r#"
+        SELECT file_path, file_hash, file_size, modified_time, system_path AS file_name, content_type, etag, created_at, updated_at, version
+        FROM filehash
+        WHERE user_id = $1 AND file_path = ANY($2)
+        "#,
+        r#"
+        SELECT file_path, file_hash, file_size, modified_time, system_path AS file_name, content_type, etag, created_at, updated_at, version
+        FROM filehash
+        WHERE user_id = $1 AND file_path IN (SELECT value FROM json_each($2))
+        "#,
+    );
+    let rows = on_db!(pool, pool => sqlx::query_as::<_, FileEntry>(sql)
+        .bind(user_id)
+        .bind(Array(&paths))
+        .fetch_all(pool)
+        .await)?;
+    Ok(rows
+        .into_iter()
+        .filter(|row| based.get(row.file_path.as_str()).is_some_and(|file| conflicts_with_base(file, row)))
+        .map(|row| row.file_path)
+        .collect())
+}
+
+/// A readable file of the user's for each content among `hashes` they have, by
+/// hash and size, with its storage key as `file_name`. Glacial files that aren't
+/// restored can't be copied, so they don't count.
+pub(crate) async fn find_stored_contents(
+    pool: &DbPool,
+    user_id: i32,
+    hashes: &[String],
+) -> Result<HashMap<(String, i64), FileEntry>, sqlx::Error> {
+    if hashes.is_empty() {
+        return Ok(HashMap::new());
+    }
+    let sql = pool.sql(
+        r#"
+        SELECT file_path, file_hash, file_size, modified_time, system_path AS file_name, content_type, etag, created_at, updated_at, version
+        FROM filehash f
+        WHERE user_id = $1 AND file_hash = ANY($2)
+          AND NOT EXISTS (
+              SELECT 1 FROM archived_files a
+              WHERE a.file_id = f.id AND a.storage_class = 'GLACIER' AND COALESCE(a.restore_status, '') <> 'restored'
+          )
+        ORDER BY id
+        "#,
+        r#"
+        SELECT file_path, file_hash, file_size, modified_time, system_path AS file_name, content_type, etag, created_at, updated_at, version
+        FROM filehash f
+        WHERE user_id = $1 AND file_hash IN (SELECT value FROM json_each($2))
+          AND NOT EXISTS (
+              SELECT 1 FROM archived_files a
+              WHERE a.file_id = f.id AND a.storage_class = 'GLACIER' AND COALESCE(a.restore_status, '') <> 'restored'
+          )
+        ORDER BY id
+        "#,
+    );
+    let rows = on_db!(pool, pool => sqlx::query_as::<_, FileEntry>(sql)
+        .bind(user_id)
+        .bind(Array(hashes))
+        .fetch_all(pool)
+        .await)?;
+    let mut contents = HashMap::new();
+    for row in rows {
+        let Some(hash) = row.file_hash.clone() else { continue };
+        contents.entry((hash, row.file_size)).or_insert(row);
+    }
+    Ok(contents)
+}
+
+/// `find_foreign_lock` for many paths in one query: those a device other than
+/// `device_id` holds a lock on.
+pub(crate) async fn find_foreign_locks(
+    pool: &DbPool,
+    user_id: i32,
+    paths: &[&str],
+    device_id: Option<&str>,
+) -> Result<HashSet<String>, sqlx::Error> {
+    if paths.is_empty() {
+        return Ok(HashSet::new());
+    }
+    let sql = pool.sql(
+        r#"
+        SELECT file_path FROM file_locks
+        WHERE user_id = $1 AND file_path = ANY($2) AND expires_at > CURRENT_TIMESTAMP
+          AND COALESCE(device_id <> $3, TRUE)
+        "#,
+        r#"
+        SELECT file_path FROM file_locks
+        WHERE user_id = $1 AND file_path IN (SELECT value FROM json_each($2)) AND expires_at > CURRENT_TIMESTAMP
+          AND COALESCE(device_id <> $3, TRUE)
+        "#,
+    );
+    let locked = on_db!(pool, pool => sqlx::query_scalar::<_, String>(sql)
+        .bind(user_id)
+        .bind(Array(paths))
+        .bind(device_id)
+        .fetch_all(pool)
+        .await)?;
+    Ok(locked.into_iter().collect())
+}
+
+/// The user's current usage and effective quota.
+pub(crate) async fn load_usage(state: &AppState, user_id: i32) -> Result<Usage, sqlx::Error> {
+    let (bytes, files, quota_bytes) = on_db!(&state.pool, pool => sqlx::query_as::<_, (i64, i64, Option<i64>)>(
+        r#"
+        SELECT COALESCE(us.bytes, 0), COALESCE(us.files, 0), COALESCE(u.quota_bytes, $2)
+        FROM users u
+        LEFT JOIN user_usage us ON us.user_id = u.id
+        WHERE u.id = $1
+        "#
+    )
+    .bind(user_id)
+    .bind(state.settings.current().default_quota_bytes.map(|quota| quota as i64))
+    .fetch_one(pool)
+    .await)?;
+
+    Ok(Usage {
+        bytes,
+        files,
+        quota_bytes,
+        remaining_bytes: quota_bytes.map(|quota| (quota - bytes).max(0)),
+    })
+}
+
+/// Total size of the user's files currently stored at `paths`.
+pub(crate) async fn stored_bytes(pool: &DbPool, user_id: i32, paths: &[String]) -> Result<i64, sqlx::Error> {
+    let sql = pool.sql(
+        "SELECT COALESCE(SUM(file_size), 0)::BIGINT FROM filehash WHERE user_id = $1 AND file_path = ANY($2)",
+        "SELECT COALESCE(SUM(file_size), 0) FROM filehash WHERE user_id = $1 AND file_path IN (SELECT value FROM json_each($2))",
+    );
+    on_db!(pool, pool => sqlx::query_scalar::<_, i64>(sql)
+        .bind(user_id)
+        .bind(Array(paths))
+        .fetch_one(pool)
+        .await)
+}
+
+pub(crate) async fn find_upload_session(
+    pool: &DbPool,
+    user_id: i32,
+    id: &str,
+) -> Result<Option<UploadSession>, sqlx::Error> {
+    on_db!(pool, pool => sqlx::query_as::<_, UploadSession>(
+        r#"
+        SELECT user_id, file_path, file_hash, file_size, modified_time, content_type,
+               system_path, storage_upload_id, upload_offset, parts, file_name, expires_at, updated_at
+        FROM upload_sessions
+        WHERE id = $1 AND user_id = $2 AND expires_at >= CURRENT_TIMESTAMP
+        "#
+    )
+    .bind(id)
+    .bind(user_id)
+    .fetch_optional(pool)
+    .await)
+}
@@ -0,0 +1,140 @@
+use axum::http::StatusCode;
+use tracing::warn;
+
+use crate::{
+    db::{on_db, DbPool},
+    IDEMPOTENCY_CLAIM_TIMEOUT_SECS, IDEMPOTENCY_TTL_HOURS,
+};
+
+/// What `claim_idempotency_key` found.
+pub(crate) enum KeyClaim {
+    /// The key was free and now belongs to this request.
+    Acquired(HeldKey),
+    /// Another request with the key hasn't finished yet.
+    InProgress,
+    /// The key's request finished with this response. `request_hash` is the hash of
+    /// the payload it was sent with, unknown for keys stored before hashes were kept.
+    Completed {
+        status: i32,
+        response: serde_json::Value,
+        request_hash: Option<String>,
+    },
+}
+
+/// Claims `key` for a request whose payload hashes to `request_hash`, unless another
+/// request holds it or finished with it. Expired keys are purged first, and a claim
+/// left behind by a server that stopped mid-request is taken over once it is old
+/// enough.
+pub(crate) async fn claim_idempotency_key(
+    pool: &DbPool,
+    key: &str,
+    request_hash: &str,
+) -> Result<KeyClaim, sqlx::Error> {
+    let sql = pool.sql(
+        "DELETE FROM idempotency WHERE created_at < NOW() - make_interval(hours => $1)",
+        "DELETE FROM idempotency WHERE created_at < datetime('now', -$1 || ' hours')",
+    );
+    on_db!(pool, pool => sqlx::query(sql).bind(IDEMPOTENCY_TTL_HOURS).execute(pool).await.map(|_| ()))?;
+
+    let sql = pool.sql(
+        r#"
+        INSERT INTO idempotency (idempotency_key, request_hash)
+        VALUES ($1, $2)
+        ON CONFLICT (idempotency_key) DO UPDATE
+        SET request_hash = EXCLUDED.request_hash, created_at = CURRENT_TIMESTAMP
+        WHERE idempotency.response IS NULL
+          AND idempotency.created_at < NOW() - make_interval(secs => $3)
+        "#,
+        r#"
+        INSERT INTO idempotency (idempotency_key, request_hash)
+        VALUES ($1, $2)
+        ON CONFLICT (idempotency_key) DO UPDATE
+        SET request_hash = excluded.request_hash, created_at = CURRENT_TIMESTAMP
+        WHERE idempotency.response IS NULL
+          AND idempotency.created_at < datetime('now', -$3 || ' seconds')
+        "#,
+    );
+    let claimed = on_db!(pool, pool => sqlx::query(sql)
+        .bind(key)
+        .bind(request_hash)
+        .bind(IDEMPOTENCY_CLAIM_TIMEOUT_SECS)
+        .execute(pool)
+        .await
+        .map(|r| r.rows_affected()))?;
+    if claimed == 1 {
+        return Ok(KeyClaim::Acquired(HeldKey {
+            pool: pool.clone(),
+            key: key.to_string(),
+            recorded: false,
+        }));
+    }
+
+    let row = on_db!(pool, pool => sqlx::query_as::<_, (Option<i32>, Option<serde_json::Value>, Option<String>)>(
+        "SELECT status_code, response, request_hash FROM idempotency WHERE idempotency_key = $1"
+    )
+    .bind(key)
+    .fetch_optional(pool)
+    .await)?;
+    Ok(match row {
+        Some((Some(status), Some(response), request_hash)) => {
+            KeyClaim::Completed { status, response, request_hash }
+        }
+        // Released again since the insert; the client's next retry will get it.
+        _ => KeyClaim::InProgress,
+    })
+}
+
+/// An idempotency key held by the request that claimed it. Dropped before its
+/// response was recorded, because the request failed or was cancelled, it frees
+/// the key again so a retry can run.
+pub(crate) struct HeldKey {
+    pool: DbPool,
+    key: String,
+    recorded: bool,
+}
+
+impl HeldKey {
+    /// Stores the response replayed to every later request with this key.
+    pub(crate) async fn record(mut self, status: StatusCode, body: &serde_json::Value) {
+        let stored = on_db!(&self.pool, pool => sqlx::query(
+            r#"
+            UPDATE idempotency
+            SET status_code = $2, response = $3
+            WHERE idempotency_key = $1
+            "#
+        )
+        .bind(&self.key)
+        .bind(status.as_u16() as i32)
+        .bind(body)
+        .execute(pool)
+        .await
+        .map(|_| ()));
+
+        match stored {
+            Ok(_) => self.recorded = true,
+            Err(e) => warn!("Failed to store response for idempotency key {}: {}", self.key, e),
+        }
+    }
+}
+
+impl Drop for HeldKey {
+    fn drop(&mut self) {
+        if self.recorded {
+            return;
+        }
+        let pool = self.pool.clone();
+        let key = std::mem::take(&mut self.key);
+        tokio::spawn(async move {
+            let released = on_db!(&pool, pool => sqlx::query(
+                "DELETE FROM idempotency WHERE idempotency_key = $1 AND response IS NULL"
+            )
+            .bind(&key)
+            .execute(pool)
+            .await
+            .map(|_| ()));
+            if let Err(e) = released {
+                warn!("Failed to release idempotency key {}: {}", key, e);
+            }
+        });
+    }
+}
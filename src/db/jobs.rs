@@ -0,0 +1,92 @@
+use tracing::{info, warn};
+
+use crate::db::{on_db, DbPool};
+
+/// Records a new pending job; `user_id` is `None` for the server's own scheduled jobs.
+pub(crate) async fn create_job(pool: &DbPool, user_id: Option<i32>, kind: &str, total: i32) -> Result<i32, sqlx::Error> {
+    on_db!(pool, pool => sqlx::query_scalar::<_, i32>(
+        r#"
+        INSERT INTO jobs (user_id, kind, status, total)
+        VALUES ($1, $2, 'pending', $3)
+        RETURNING id
+        "#
+    )
+    .bind(user_id)
+    .bind(kind)
+    .bind(total)
+    .fetch_one(pool)
+    .await)
+}
+
+pub(crate) async fn report_progress(pool: &DbPool, job_id: Option<i32>, progress: i32) {
+    let Some(id) = job_id else { return };
+
+    let reported = on_db!(pool, pool => sqlx::query(
+        "UPDATE jobs SET progress = $1, updated_at = CURRENT_TIMESTAMP WHERE id = $2"
+    )
+    .bind(progress)
+    .bind(id)
+    .execute(pool)
+    .await
+    .map(|_| ()));
+    if let Err(e) = reported {
+        warn!("Failed to update progress for job {}: {}", id, e);
+    }
+}
+
+/// Sets the number of items a job works through, once it knows.
+pub(crate) async fn report_total(pool: &DbPool, job_id: i32, total: i32) {
+    let reported = on_db!(pool, pool => sqlx::query(
+        "UPDATE jobs SET total = $1, updated_at = CURRENT_TIMESTAMP WHERE id = $2"
+    )
+    .bind(total)
+    .bind(job_id)
+    .execute(pool)
+    .await
+    .map(|_| ()));
+    if let Err(e) = reported {
+        warn!("Failed to update the total for job {}: {}", job_id, e);
+    }
+}
+
+pub(crate) async fn mark_job_running(pool: &DbPool, job_id: i32) {
+    info!(job_id, "JOB STARTED");
+    let _ = on_db!(pool, pool => sqlx::query(
+        "UPDATE jobs SET status = 'running', updated_at = CURRENT_TIMESTAMP WHERE id = $1"
+    )
+    .bind(job_id)
+    .execute(pool)
+    .await
+    .map(|_| ()));
+}
+
+/// Records a job's final result, or its error, and marks it finished.
+pub(crate) async fn finish_job(pool: &DbPool, job_id: i32, outcome: Result<serde_json::Value, String>) {
+    let finished = on_db!(pool, pool => {
+        let update = match outcome {
+            Ok(result) => sqlx::query(
+                r#"
+                UPDATE jobs
+                SET status = 'completed', result = $1, updated_at = CURRENT_TIMESTAMP
+                WHERE id = $2
+                "#
+            )
+            .bind(result)
+            .bind(job_id),
+            Err(e) => sqlx::query(
+                r#"
+                UPDATE jobs
+                SET status = 'failed', error = $1, updated_at = CURRENT_TIMESTAMP
+                WHERE id = $2
+                "#
+            )
+            .bind(e)
+            .bind(job_id),
+        };
+        update.execute(pool).await.map(|_| ())
+    });
+    if let Err(e) = finished {
+        warn!("Failed to finalize job {}: {}", job_id, e);
+    }
+    info!(job_id, "JOB FINISHED");
+}
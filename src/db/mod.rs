@@ -0,0 +1,322 @@
+use std::{ops::DerefMut, str::FromStr, time::Duration};
+
+use serde::Serialize;
+use sqlx::{
+    encode::IsNull,
+    error::BoxDynError,
+    pool::PoolConnection,
+    postgres::{PgArgumentBuffer, PgConnectOptions, PgHasArrayType, PgPoolOptions, PgTypeInfo, PgValueRef},
+    sqlite::{
+        SqliteArgumentValue, SqliteConnectOptions, SqliteError, SqliteJournalMode, SqlitePoolOptions, SqliteTypeInfo,
+        SqliteValueRef,
+    },
+    Decode, Encode, PgConnection, PgPool, Postgres, Sqlite, SqliteConnection, SqlitePool, Transaction, Type, ValueRef,
+};
+use tracing::{error, warn};
+
+use crate::{env_or, DB_UNAVAILABLE_ERROR};
+
+mod files;
+mod idempotency;
+mod jobs;
+mod retries;
+
+pub(crate) use files::*;
+pub(crate) use idempotency::*;
+pub(crate) use jobs::*;
+pub(crate) use retries::*;
+
+/// The same thing on either database the server can keep its metadata in.
+#[derive(Clone, Debug)]
+pub enum Db<P, S> {
+    Postgres(P),
+    Sqlite(S),
+}
+
+/// The server's database, picked by the scheme of `DATABASE_URL`: Postgres, or a
+/// SQLite file for single-box deployments where running Postgres is too heavy.
+pub type DbPool = Db<PgPool, SqlitePool>;
+
+pub(crate) type DbConnection = Db<PoolConnection<Postgres>, PoolConnection<Sqlite>>;
+
+pub(crate) type DbTransaction = Db<Transaction<'static, Postgres>, Transaction<'static, Sqlite>>;
+
+/// A connection borrowed from a `DbConnection` or `DbTransaction`.
+pub(crate) type DbConn<'c> = Db<&'c mut PgConnection, &'c mut SqliteConnection>;
+
+/// Evaluates `$body` with `$inner` bound to whichever backend's value `$db` holds.
+/// The body is compiled once per backend, so a query both dialects accept as
+/// written is only written once; see `Db::sql` for those they don't.
+macro_rules! on_db {
+    ($db:expr, $inner:ident => $body:expr) => {
+        match $db {
+            $crate::db::Db::Postgres($inner) => $body,
+            $crate::db::Db::Sqlite($inner) => $body,
+        }
+    };
+}
+
+pub(crate) use on_db;
+
+impl<P, S> Db<P, S> {
+    /// Picks the statement written for this backend's dialect.
+    pub(crate) fn sql(&self, postgres: &'static str, sqlite: &'static str) -> &'static str {
+        match self {
+            Db::Postgres(_) => postgres,
+            Db::Sqlite(_) => sqlite,
+        }
+    }
+}
+
+impl<P, S> Db<P, S>
+where
+    P: DerefMut<Target = PgConnection>,
+    S: DerefMut<Target = SqliteConnection>,
+{
+    /// Reborrows the connection, to run a query on it or hand it to a helper.
+    pub(crate) fn as_conn(&mut self) -> DbConn<'_> {
+        match self {
+            Db::Postgres(conn) => Db::Postgres(&mut **conn),
+            Db::Sqlite(conn) => Db::Sqlite(&mut **conn),
+        }
+    }
+}
+
+impl DbPool {
+    /// The pool as an executor, for helpers that also run on a connection.
+    pub(crate) fn executor(&self) -> Db<&PgPool, &SqlitePool> {
+        match self {
+            Db::Postgres(pool) => Db::Postgres(pool),
+            Db::Sqlite(pool) => Db::Sqlite(pool),
+        }
+    }
+
+    pub(crate) async fn acquire(&self) -> Result<DbConnection, sqlx::Error> {
+        match self {
+            Db::Postgres(pool) => pool.acquire().await.map(Db::Postgres),
+            Db::Sqlite(pool) => pool.acquire().await.map(Db::Sqlite),
+        }
+    }
+
+    /// Starts a transaction. SQLite ones take the write lock up front: one that
+    /// reads first and writes later can otherwise fail at once rather than wait
+    /// when another writer got there in between.
+    pub(crate) async fn begin(&self) -> Result<DbTransaction, sqlx::Error> {
+        match self {
+            Db::Postgres(pool) => pool.begin().await.map(Db::Postgres),
+            Db::Sqlite(pool) => pool.begin_with("BEGIN IMMEDIATE").await.map(Db::Sqlite),
+        }
+    }
+
+    pub(crate) fn max_connections(&self) -> u32 {
+        on_db!(self, pool => pool.options().get_max_connections())
+    }
+
+    /// Brings the schema up to date, from the migrations written for this backend.
+    pub(crate) async fn migrate(&self) -> Result<(), sqlx::migrate::MigrateError> {
+        match self {
+            Db::Postgres(pool) => sqlx::migrate!().run(pool).await,
+            Db::Sqlite(pool) => sqlx::migrate!("migrations/sqlite").run(pool).await,
+        }
+    }
+}
+
+impl From<PgPool> for DbPool {
+    fn from(pool: PgPool) -> Self {
+        Db::Postgres(pool)
+    }
+}
+
+impl From<SqlitePool> for DbPool {
+    fn from(pool: SqlitePool) -> Self {
+        Db::Sqlite(pool)
+    }
+}
+
+impl DbTransaction {
+    pub(crate) async fn commit(self) -> Result<(), sqlx::Error> {
+        on_db!(self, tx => tx.commit().await)
+    }
+}
+
+/// A list of strings read from either database: a `TEXT[]` in Postgres, a JSON
+/// array in SQLite, which has no arrays. A NULL column reads as `None`.
+pub(crate) struct TextList(Option<Vec<String>>);
+
+impl Type<Postgres> for TextList {
+    fn type_info() -> PgTypeInfo {
+        <Vec<String> as Type<Postgres>>::type_info()
+    }
+
+    fn compatible(ty: &PgTypeInfo) -> bool {
+        <Vec<String> as Type<Postgres>>::compatible(ty)
+    }
+}
+
+impl<'r> Decode<'r, Postgres> for TextList {
+    fn decode(value: PgValueRef<'r>) -> Result<Self, BoxDynError> {
+        if value.is_null() {
+            return Ok(TextList(None));
+        }
+        Ok(TextList(Some(Vec::<String>::decode(value)?)))
+    }
+}
+
+impl Type<Sqlite> for TextList {
+    fn type_info() -> SqliteTypeInfo {
+        <str as Type<Sqlite>>::type_info()
+    }
+}
+
+impl<'r> Decode<'r, Sqlite> for TextList {
+    fn decode(value: SqliteValueRef<'r>) -> Result<Self, BoxDynError> {
+        if value.is_null() {
+            return Ok(TextList(None));
+        }
+        Ok(TextList(Some(serde_json::from_str(<&str as Decode<Sqlite>>::decode(value)?)?)))
+    }
+}
+
+impl From<TextList> for Vec<String> {
+    fn from(list: TextList) -> Self {
+        list.0.unwrap_or_default()
+    }
+}
+
+impl From<TextList> for Option<Vec<String>> {
+    fn from(list: TextList) -> Self {
+        list.0
+    }
+}
+
+/// A list bound as one parameter: a Postgres array, for `ANY($1)` and `UNNEST`, or
+/// a JSON array in SQLite, for `json_each($1)` and `$1 ->> key`.
+pub(crate) struct Array<'a, T>(pub(crate) &'a [T]);
+
+impl<T: PgHasArrayType> Type<Postgres> for Array<'_, T> {
+    fn type_info() -> PgTypeInfo {
+        T::array_type_info()
+    }
+
+    fn compatible(ty: &PgTypeInfo) -> bool {
+        T::array_compatible(ty)
+    }
+}
+
+impl<'q, T> Encode<'q, Postgres> for Array<'_, T>
+where
+    T: Encode<'q, Postgres> + Type<Postgres>,
+{
+    fn encode_by_ref(&self, buf: &mut PgArgumentBuffer) -> Result<IsNull, BoxDynError> {
+        <&[T] as Encode<Postgres>>::encode_by_ref(&self.0, buf)
+    }
+}
+
+impl<T> Type<Sqlite> for Array<'_, T> {
+    fn type_info() -> SqliteTypeInfo {
+        <str as Type<Sqlite>>::type_info()
+    }
+}
+
+impl<'q, T: Serialize> Encode<'q, Sqlite> for Array<'_, T> {
+    fn encode_by_ref(&self, buf: &mut Vec<SqliteArgumentValue<'q>>) -> Result<IsNull, BoxDynError> {
+        <String as Encode<Sqlite>>::encode(serde_json::to_string(self.0)?, buf)
+    }
+}
+
+/// Builds the pool from `DB_*` env vars, retrying the initial connect with
+/// exponential backoff so the server can start before the database is up. A
+/// `sqlite:` URL opens that file instead, creating it if needed. Exits the process
+/// once the retry budget is spent.
+pub(crate) async fn connect_with_retry(db_url: &str) -> DbPool {
+    let max_attempts: u32 = env_or("DB_CONNECT_RETRIES", 10);
+    let mut backoff = Duration::from_millis(500);
+
+    for attempt in 1..=max_attempts {
+        match connect(db_url).await {
+            Ok(pool) => return pool,
+            Err(e) if attempt < max_attempts => {
+                warn!(
+                    "DB connect attempt {}/{} failed: {}; retrying in {:?}",
+                    attempt, max_attempts, e, backoff
+                );
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(Duration::from_secs(30));
+            }
+            Err(e) => {
+                error!("Failed to connect to DB after {} attempts: {}", attempt, e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    error!("Failed to connect to DB: DB_CONNECT_RETRIES must be at least 1");
+    std::process::exit(1);
+}
+
+async fn connect(db_url: &str) -> Result<DbPool, sqlx::Error> {
+    let max_connections = env_or("DB_MAX_CONNECTIONS", 10);
+    // Kept open even when idle, so bursts don't wait on new connections.
+    let min_connections = env_or("DB_MIN_CONNECTIONS", 0).min(max_connections);
+    let acquire_timeout = Duration::from_secs(env_or("DB_ACQUIRE_TIMEOUT_SECS", 30));
+    let idle_timeout = Duration::from_secs(env_or("DB_IDLE_TIMEOUT_SECS", 600));
+    let max_lifetime = Duration::from_secs(env_or("DB_MAX_LIFETIME_SECS", 1800));
+    // Statements each connection keeps prepared; sync runs the same few over and
+    // over, so they are parsed and planned once per connection.
+    let statement_cache = env_or("DB_STATEMENT_CACHE_CAPACITY", 100);
+
+    if db_url.starts_with("sqlite:") {
+        let options = SqliteConnectOptions::from_str(db_url)?
+            .create_if_missing(true)
+            // Readers then neither block the writer nor wait for it.
+            .journal_mode(SqliteJournalMode::Wal)
+            // Paths are case sensitive, as `LIKE` is in Postgres.
+            .pragma("case_sensitive_like", "ON")
+            // Sync profiles match paths against regexes.
+            .with_regexp()
+            .statement_cache_capacity(statement_cache);
+        return SqlitePoolOptions::new()
+            .max_connections(max_connections)
+            .min_connections(min_connections)
+            .acquire_timeout(acquire_timeout)
+            .idle_timeout(idle_timeout)
+            .max_lifetime(max_lifetime)
+            .connect_with(options)
+            .await
+            .map(Db::Sqlite);
+    }
+
+    let options = PgConnectOptions::from_str(db_url)?.statement_cache_capacity(statement_cache);
+    PgPoolOptions::new()
+        .max_connections(max_connections)
+        .min_connections(min_connections)
+        .acquire_timeout(acquire_timeout)
+        .idle_timeout(idle_timeout)
+        .max_lifetime(max_lifetime)
+        .connect_with(options)
+        .await
+        .map(Db::Postgres)
+}
+
+/// Renders `err`, prefixed with `DB_UNAVAILABLE_ERROR` when the same statement
+/// may well succeed if run again.
+pub(crate) fn describe_error(err: &sqlx::Error) -> String {
+    if is_transient(err) {
+        format!("{}: {}", DB_UNAVAILABLE_ERROR, err)
+    } else {
+        err.to_string()
+    }
+}
+
+fn is_transient(err: &sqlx::Error) -> bool {
+    match err {
+        sqlx::Error::Io(_) | sqlx::Error::PoolTimedOut => true,
+        // SQLITE_BUSY and SQLITE_LOCKED, in any of their extended forms.
+        sqlx::Error::Database(e) if e.try_downcast_ref::<SqliteError>().is_some() => {
+            e.code().and_then(|code| code.parse::<i32>().ok()).is_some_and(|code| matches!(code & 0xff, 5 | 6))
+        }
+        // Serialization failure, deadlock, and the server shutting down or starting up.
+        sqlx::Error::Database(e) => matches!(e.code().as_deref(), Some("40001" | "40P01" | "57P01" | "57P03")),
+        _ => false,
+    }
+}
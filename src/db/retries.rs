@@ -0,0 +1,127 @@
+use tracing::error;
+
+use crate::{
+    db::{on_db, DbPool},
+    models::QueuedRetry, RETRY_BASE_DELAY_SECS, RETRY_LEASE_SECS, RETRY_MAX_DELAY_SECS,
+};
+
+/// Retry kind that deletes `object_key`.
+pub(crate) const RETRY_DELETE: &str = "delete";
+/// Retry kind that copies `source_key` to `object_key`.
+pub(crate) const RETRY_COPY: &str = "copy";
+/// Retry kind that aborts the multipart upload `source_key` of `object_key`.
+pub(crate) const RETRY_ABORT: &str = "abort";
+
+/// Wait before the attempt after `attempts` failed ones, doubling from
+/// `RETRY_BASE_DELAY_SECS` up to `RETRY_MAX_DELAY_SECS`.
+pub(crate) fn retry_delay(attempts: i32) -> f64 {
+    let doublings = attempts.clamp(1, 20) as u32 - 1;
+    RETRY_BASE_DELAY_SECS.saturating_mul(1 << doublings).min(RETRY_MAX_DELAY_SECS) as f64
+}
+
+/// Queues a storage operation that just failed with `error` for a background retry.
+/// Only logs when even that fails, since the caller has nothing left to try.
+pub(crate) async fn enqueue_retry(pool: &DbPool, kind: &str, key: &str, source: Option<&str>, error: &str) {
+    let sql = pool.sql(
+        r#"
+        INSERT INTO retry_queue (kind, object_key, source_key, attempts, last_error, run_at)
+        VALUES ($1, $2, $3, 1, $4, CURRENT_TIMESTAMP + make_interval(secs => $5))
+        "#,
+        r#"
+        INSERT INTO retry_queue (kind, object_key, source_key, attempts, last_error, run_at)
+        VALUES ($1, $2, $3, 1, $4, datetime('now', $5 || ' seconds'))
+        "#,
+    );
+    let queued = on_db!(pool, pool => sqlx::query(sql)
+        .bind(kind)
+        .bind(key)
+        .bind(source)
+        .bind(error)
+        .bind(retry_delay(1))
+        .execute(pool)
+        .await
+        .map(|_| ()));
+    if let Err(e) = queued {
+        error!("Failed to queue {} retry for {}: {}", kind, key, e);
+    }
+}
+
+/// Claims up to `limit` due retries by pushing their `run_at` out by a lease, so
+/// another server polling the same queue skips them, as does this one after a crash.
+pub(crate) async fn claim_due_retries(pool: &DbPool, limit: i64) -> Result<Vec<QueuedRetry>, sqlx::Error> {
+    let sql = pool.sql(
+        r#"
+        UPDATE retry_queue
+        SET run_at = CURRENT_TIMESTAMP + make_interval(secs => $2), updated_at = CURRENT_TIMESTAMP
+        WHERE id IN (
+            SELECT id FROM retry_queue
+            WHERE status = 'pending' AND run_at <= CURRENT_TIMESTAMP
+            ORDER BY run_at
+            LIMIT $1
+            FOR UPDATE SKIP LOCKED
+        )
+        RETURNING id, kind, object_key, source_key, status, attempts, last_error, run_at, created_at, updated_at
+        "#,
+        // SQLite runs one writer at a time, so there are no row locks to skip.
+        r#"
+        UPDATE retry_queue
+        SET run_at = datetime('now', $2 || ' seconds'), updated_at = CURRENT_TIMESTAMP
+        WHERE id IN (
+            SELECT id FROM retry_queue
+            WHERE status = 'pending' AND run_at <= CURRENT_TIMESTAMP
+            ORDER BY run_at
+            LIMIT $1
+        )
+        RETURNING id, kind, object_key, source_key, status, attempts, last_error, run_at, created_at, updated_at
+        "#,
+    );
+    on_db!(pool, pool => sqlx::query_as::<_, QueuedRetry>(sql)
+        .bind(limit)
+        .bind(RETRY_LEASE_SECS as f64)
+        .fetch_all(pool)
+        .await)
+}
+
+/// Drops a retry that succeeded or no longer needs to run.
+pub(crate) async fn finish_retry(pool: &DbPool, id: i32) -> Result<(), sqlx::Error> {
+    on_db!(pool, pool => sqlx::query("DELETE FROM retry_queue WHERE id = $1")
+        .bind(id)
+        .execute(pool)
+        .await
+        .map(|_| ()))
+}
+
+/// Records another failed attempt, rescheduling the retry with backoff or marking
+/// it `dead` once `max_attempts` are used up.
+pub(crate) async fn fail_retry(pool: &DbPool, retry: &QueuedRetry, error: &str, max_attempts: i32) -> Result<(), sqlx::Error> {
+    let attempts = retry.attempts + 1;
+    let sql = pool.sql(
+        r#"
+        UPDATE retry_queue
+        SET attempts = $2,
+            last_error = $3,
+            status = CASE WHEN $2 >= $4 THEN 'dead' ELSE 'pending' END,
+            run_at = CURRENT_TIMESTAMP + make_interval(secs => $5),
+            updated_at = CURRENT_TIMESTAMP
+        WHERE id = $1
+        "#,
+        r#"
+        UPDATE retry_queue
+        SET attempts = $2,
+            last_error = $3,
+            status = CASE WHEN $2 >= $4 THEN 'dead' ELSE 'pending' END,
+            run_at = datetime('now', $5 || ' seconds'),
+            updated_at = CURRENT_TIMESTAMP
+        WHERE id = $1
+        "#,
+    );
+    on_db!(pool, pool => sqlx::query(sql)
+        .bind(retry.id)
+        .bind(attempts)
+        .bind(error)
+        .bind(max_attempts)
+        .bind(retry_delay(attempts))
+        .execute(pool)
+        .await
+        .map(|_| ()))
+}
@@ -0,0 +1,186 @@
+use std::{env, sync::Arc, time::Duration};
+
+use axum::http::header;
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use sha2::Sha256;
+use tracing::{warn, Instrument};
+
+use crate::{
+    audit, env_or, webhooks,
+    models::{AuthUser, FileChange, Operation, SyncEvent, SyncResponse},
+    AppState, OPERATION_ORDER,
+};
+
+#[derive(Serialize)]
+struct WebhookPayload<'a> {
+    event: &'static str,
+    timestamp: i64,
+    user: &'a str,
+    changes: &'a [FileChange],
+}
+
+/// Endpoints notified after each successful sync, configured via `WEBHOOK_URLS`.
+pub(crate) struct Webhooks {
+    /// Also sends the deliveries of the webhooks users register.
+    pub(crate) client: reqwest::Client,
+    urls: Vec<String>,
+    secret: Vec<u8>,
+    pub(crate) max_attempts: u32,
+}
+
+impl Webhooks {
+    pub(crate) fn from_env() -> Self {
+        let urls: Vec<String> = env::var("WEBHOOK_URLS")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|url| !url.is_empty())
+            .map(String::from)
+            .collect();
+
+        // Unsigned deliveries would let anyone who finds a receiver forge them.
+        let secret = env::var("WEBHOOK_SECRET").unwrap_or_default();
+        if !urls.is_empty() && secret.is_empty() {
+            panic!("WEBHOOK_SECRET must be set when WEBHOOK_URLS is");
+        }
+
+        Self {
+            client: reqwest::Client::builder()
+                .timeout(Duration::from_secs(env_or("WEBHOOK_TIMEOUT_SECS", 10)))
+                .build()
+                .expect("Failed to build webhook client"),
+            urls,
+            secret: secret.into_bytes(),
+            max_attempts: env_or("WEBHOOK_MAX_ATTEMPTS", 5).max(1),
+        }
+    }
+
+    fn sign(&self, body: &[u8]) -> String {
+        sign(&self.secret, body)
+    }
+}
+
+/// Hex HMAC-SHA256 of the body, sent as `X-Pocket-Signature: sha256=<hex>`.
+pub(crate) fn sign(secret: &[u8], body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret)
+        .expect("HMAC accepts keys of any length");
+    mac.update(body);
+    hex::encode(mac.finalize().into_bytes())
+}
+
+/// Broadcasts the successfully applied changes of a sync to `/events` subscribers.
+pub(crate) fn publish_sync_event(state: &AppState, user: &AuthUser, response: &SyncResponse) {
+    let changes: Vec<FileChange> = OPERATION_ORDER
+        .iter()
+        .filter_map(|cmd| response.get(cmd).map(|result| (cmd, result)))
+        .flat_map(|(cmd, result)| result.success.iter().map(move |file| (*cmd, file)))
+        .flat_map(|(cmd, file)| match (cmd, &file.from_path) {
+            // Published the way `/move` publishes the files it moves.
+            (Operation::Move, Some(from)) => vec![
+                FileChange { operation: Operation::Delete, file_path: from.clone() },
+                FileChange { operation: Operation::Insert, file_path: file.file_path.clone() },
+            ],
+            _ => vec![FileChange { operation: cmd, file_path: file.file_path.clone() }],
+        })
+        .collect();
+
+    notify_webhooks(state, user, &changes);
+    publish_changes(state, user, changes);
+}
+
+/// Delivers the sync's changes to every configured webhook from a background task,
+/// so slow or failing receivers never hold up the sync response.
+fn notify_webhooks(state: &AppState, user: &AuthUser, changes: &[FileChange]) {
+    if changes.is_empty() || state.webhooks.urls.is_empty() {
+        return;
+    }
+
+    let body = match serde_json::to_vec(&WebhookPayload {
+        event: "sync",
+        timestamp: chrono::Utc::now().timestamp(),
+        user: &user.username,
+        changes,
+    }) {
+        Ok(body) => body,
+        Err(e) => {
+            warn!("Failed to encode webhook payload: {}", e);
+            return;
+        }
+    };
+    let signature = format!("sha256={}", state.webhooks.sign(&body));
+
+    for url in &state.webhooks.urls {
+        tokio::spawn(deliver_webhook(
+            state.webhooks.clone(),
+            url.clone(),
+            body.clone(),
+            signature.clone(),
+        ).in_current_span());
+    }
+}
+
+async fn deliver_webhook(webhooks: Arc<Webhooks>, url: String, body: Vec<u8>, signature: String) {
+    let mut backoff = Duration::from_secs(1);
+
+    for attempt in 1..=webhooks.max_attempts {
+        let result = webhooks
+            .client
+            .post(&url)
+            .header(header::CONTENT_TYPE, "application/json")
+            .header("X-Pocket-Signature", &signature)
+            .body(body.clone())
+            .send()
+            .await
+            .and_then(|res| res.error_for_status());
+
+        match result {
+            Ok(_) => return,
+            Err(e) if attempt < webhooks.max_attempts => {
+                warn!(
+                    "Webhook {} attempt {}/{} failed: {}; retrying in {:?}",
+                    url, attempt, webhooks.max_attempts, e, backoff
+                );
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(Duration::from_secs(60));
+            }
+            Err(e) => warn!("Webhook {} failed after {} attempts: {}", url, attempt, e),
+        }
+    }
+}
+
+/// Tells the user's subscribed devices about changes, and records them in the audit log.
+pub(crate) fn publish_changes(state: &AppState, user: &AuthUser, changes: Vec<FileChange>) {
+    if changes.is_empty() {
+        return;
+    }
+    audit::record_changes(state, user, &changes);
+    broadcast_changes(state, user.user_id, user.device_id.clone(), changes);
+}
+
+/// Tells the user's subscribed devices about files brought back from the trash,
+/// which the audit log records as restores rather than inserts.
+pub(crate) fn publish_restores(state: &AppState, user: &AuthUser, changes: Vec<FileChange>) {
+    if changes.is_empty() {
+        return;
+    }
+    audit::record_restores(state, user, changes.iter().map(|change| change.file_path.clone()).collect());
+    broadcast_changes(state, user.user_id, user.device_id.clone(), changes);
+}
+
+/// Tells every one of the user's devices about changes the server made on its own,
+/// such as quarantining a file. Those are logged where they are made, since no
+/// user made them.
+pub(crate) fn publish_server_changes(state: &AppState, user_id: i32, changes: Vec<FileChange>) {
+    if changes.is_empty() {
+        return;
+    }
+    broadcast_changes(state, user_id, None, changes);
+}
+
+fn broadcast_changes(state: &AppState, user_id: i32, device_id: Option<String>, changes: Vec<FileChange>) {
+    webhooks::queue_changes(state, user_id, &changes);
+
+    // Sending only fails when nobody is subscribed, which is fine.
+    let _ = state.events.send(SyncEvent { user_id, device_id, changes });
+}
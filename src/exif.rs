@@ -0,0 +1,167 @@
+//! Reads the few EXIF fields the photo timeline needs: when a picture was taken,
+//! with what camera, and where. EXIF data is a TIFF structure, a chain of
+//! directories of tagged entries, which `image` hands over raw for JPEG, PNG,
+//! WebP and TIFF files.
+
+use std::io::Cursor;
+
+use chrono::NaiveDateTime;
+use image::{ImageDecoder, ImageReader};
+
+const TAG_MAKE: u16 = 0x010F;
+const TAG_MODEL: u16 = 0x0110;
+const TAG_DATE_TIME: u16 = 0x0132;
+const TAG_EXIF_IFD: u16 = 0x8769;
+const TAG_GPS_IFD: u16 = 0x8825;
+const TAG_DATE_TIME_ORIGINAL: u16 = 0x9003;
+const TAG_GPS_LATITUDE_REF: u16 = 1;
+const TAG_GPS_LATITUDE: u16 = 2;
+const TAG_GPS_LONGITUDE_REF: u16 = 3;
+const TAG_GPS_LONGITUDE: u16 = 4;
+
+const TYPE_ASCII: u16 = 2;
+const TYPE_SHORT: u16 = 3;
+const TYPE_LONG: u16 = 4;
+const TYPE_RATIONAL: u16 = 5;
+
+/// What an image's EXIF data says about it. Every field is `None` when the image
+/// has no EXIF data, or doesn't record it.
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct Exif {
+    /// When the picture was taken, in the camera's local time, which EXIF doesn't
+    /// say the offset of.
+    pub(crate) taken_at: Option<NaiveDateTime>,
+    pub(crate) camera_make: Option<String>,
+    pub(crate) camera_model: Option<String>,
+    /// Degrees, north and east positive.
+    pub(crate) latitude: Option<f64>,
+    pub(crate) longitude: Option<f64>,
+}
+
+/// The EXIF fields of the image in `data`.
+pub(crate) fn read_exif(data: &[u8]) -> Exif {
+    let raw = ImageReader::new(Cursor::new(data))
+        .with_guessed_format()
+        .ok()
+        .and_then(|reader| reader.into_decoder().ok())
+        .and_then(|mut decoder| decoder.exif_metadata().ok().flatten());
+    raw.map(|raw| parse(&raw)).unwrap_or_default()
+}
+
+/// Parses a raw EXIF block, which starts at its TIFF header. Entries that are
+/// missing or malformed are left out.
+fn parse(raw: &[u8]) -> Exif {
+    let Some(tiff) = Tiff::new(raw) else { return Exif::default() };
+    let Some(ifd0) = tiff.u32(4).and_then(|offset| tiff.directory(offset)) else { return Exif::default() };
+
+    let mut exif = Exif {
+        camera_make: tiff.ascii(&ifd0, TAG_MAKE),
+        camera_model: tiff.ascii(&ifd0, TAG_MODEL),
+        ..Default::default()
+    };
+    let original = tiff
+        .pointer(&ifd0, TAG_EXIF_IFD)
+        .and_then(|sub| tiff.ascii(&sub, TAG_DATE_TIME_ORIGINAL));
+    exif.taken_at = original
+        .or_else(|| tiff.ascii(&ifd0, TAG_DATE_TIME))
+        .and_then(|value| NaiveDateTime::parse_from_str(&value, "%Y:%m:%d %H:%M:%S").ok());
+
+    if let Some(gps) = tiff.pointer(&ifd0, TAG_GPS_IFD) {
+        let coordinate = |value_tag, ref_tag, negative: &str| {
+            let [degrees, minutes, seconds] = tiff.rationals(&gps, value_tag)?;
+            let value = degrees + minutes / 60.0 + seconds / 3600.0;
+            let reference = tiff.ascii(&gps, ref_tag)?;
+            Some(if reference == negative { -value } else { value })
+        };
+        exif.latitude = coordinate(TAG_GPS_LATITUDE, TAG_GPS_LATITUDE_REF, "S").filter(|v| v.abs() <= 90.0);
+        exif.longitude = coordinate(TAG_GPS_LONGITUDE, TAG_GPS_LONGITUDE_REF, "W").filter(|v| v.abs() <= 180.0);
+    }
+    exif
+}
+
+/// One 12-byte directory entry.
+struct Entry {
+    tag: u16,
+    kind: u16,
+    count: u32,
+    /// Where the value starts: inside the entry when it fits in four bytes, and at
+    /// the offset the entry holds otherwise.
+    value_at: usize,
+}
+
+struct Tiff<'a> {
+    data: &'a [u8],
+    little_endian: bool,
+}
+
+impl<'a> Tiff<'a> {
+    fn new(data: &'a [u8]) -> Option<Self> {
+        let little_endian = match data.get(..4)? {
+            [b'I', b'I', 42, 0] => true,
+            [b'M', b'M', 0, 42] => false,
+            _ => return None,
+        };
+        Some(Self { data, little_endian })
+    }
+
+    fn u16(&self, at: usize) -> Option<u16> {
+        let bytes: [u8; 2] = self.data.get(at..at + 2)?.try_into().ok()?;
+        Some(if self.little_endian { u16::from_le_bytes(bytes) } else { u16::from_be_bytes(bytes) })
+    }
+
+    fn u32(&self, at: usize) -> Option<u32> {
+        let bytes: [u8; 4] = self.data.get(at..at + 4)?.try_into().ok()?;
+        Some(if self.little_endian { u32::from_le_bytes(bytes) } else { u32::from_be_bytes(bytes) })
+    }
+
+    /// The entries of the directory at `offset`.
+    fn directory(&self, offset: u32) -> Option<Vec<Entry>> {
+        let start = offset as usize;
+        let count = self.u16(start)? as usize;
+        (0..count)
+            .map(|i| {
+                let at = start + 2 + i * 12;
+                let kind = self.u16(at + 2)?;
+                let count = self.u32(at + 4)?;
+                let size = match kind {
+                    TYPE_SHORT => 2,
+                    TYPE_LONG => 4,
+                    TYPE_RATIONAL => 8,
+                    _ => 1,
+                } * count as usize;
+                let value_at = if size <= 4 { at + 8 } else { self.u32(at + 8)? as usize };
+                Some(Entry { tag: self.u16(at)?, kind, count, value_at })
+            })
+            .collect()
+    }
+
+    fn find(entries: &[Entry], tag: u16) -> Option<&Entry> {
+        entries.iter().find(|entry| entry.tag == tag)
+    }
+
+    /// The text of an ASCII entry, trimmed of its terminating NULs and padding.
+    fn ascii(&self, entries: &[Entry], tag: u16) -> Option<String> {
+        let entry = Self::find(entries, tag).filter(|entry| entry.kind == TYPE_ASCII)?;
+        let bytes = self.data.get(entry.value_at..entry.value_at + entry.count as usize)?;
+        let text = String::from_utf8_lossy(bytes);
+        let text = text.trim_end_matches('\0').trim();
+        (!text.is_empty()).then(|| text.to_string())
+    }
+
+    /// The directory a pointer entry leads to.
+    fn pointer(&self, entries: &[Entry], tag: u16) -> Option<Vec<Entry>> {
+        let entry = Self::find(entries, tag).filter(|entry| entry.kind == TYPE_LONG)?;
+        self.directory(self.u32(entry.value_at)?)
+    }
+
+    /// The three values of a RATIONAL entry, such as GPS degrees, minutes and seconds.
+    fn rationals(&self, entries: &[Entry], tag: u16) -> Option<[f64; 3]> {
+        let entry = Self::find(entries, tag).filter(|entry| entry.kind == TYPE_RATIONAL && entry.count == 3)?;
+        let value = |i: usize| {
+            let numerator = self.u32(entry.value_at + i * 8)?;
+            let denominator = self.u32(entry.value_at + i * 8 + 4)?;
+            (denominator != 0).then(|| f64::from(numerator) / f64::from(denominator))
+        };
+        Some([value(0)?, value(1)?, value(2)?])
+    }
+}
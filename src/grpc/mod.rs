@@ -0,0 +1,353 @@
+//! The `PocketSync` gRPC service of `proto/pocket.proto`. It is mounted into the
+//! axum router, so calls share the HTTP API's port, auth and rate limits, and it
+//! stores and reads files through the same code as the HTTP handlers.
+
+use std::convert::Infallible;
+
+use axum::{
+    body::{to_bytes, Body, Bytes},
+    extract::Request,
+    http::StatusCode,
+    response::Response,
+};
+use futures::{
+    stream::{self, BoxStream},
+    StreamExt,
+};
+use tokio::sync::{broadcast, mpsc};
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{
+    codegen::{BoxFuture, Context, Poll, Service},
+    server::{ClientStreamingService, Grpc, NamedService, ServerStreamingService, StreamingService},
+    Code, Status, Streaming,
+};
+use tonic_prost::ProstCodec;
+use tracing::{info, warn, Instrument};
+
+use crate::{
+    audit,
+    db::on_db,
+    handlers::{files::trim_slashes, maintenance::in_maintenance, sync::put_file, uploads::resolve_content_type},
+    models::{AuthUser, FileEntry, Operation},
+    AppState, GRPC_CHUNK_BYTES,
+};
+
+use self::proto::{
+    download_response, upload_request, ChangeEvent, DownloadRequest, DownloadResponse, FileChange, FileInfo,
+    UploadRequest, WatchRequest,
+};
+
+pub(crate) mod proto;
+
+/// Change events buffered for a `WatchChanges` caller that is slow to read them.
+const WATCH_BUFFER: usize = 16;
+
+/// Routes each call to its method. Runs inside `require_auth`, which has already
+/// put the caller in the request's extensions.
+#[derive(Clone)]
+pub(crate) struct PocketSync {
+    state: AppState,
+}
+
+impl PocketSync {
+    pub(crate) fn new(state: AppState) -> Self {
+        Self { state }
+    }
+}
+
+impl NamedService for PocketSync {
+    const NAME: &'static str = "pocket.v1.PocketSync";
+}
+
+impl Service<Request> for PocketSync {
+    type Response = axum::http::Response<tonic::body::Body>;
+    type Error = Infallible;
+    type Future = BoxFuture<Self::Response, Infallible>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: Request) -> Self::Future {
+        let state = self.state.clone();
+        let method = req.uri().path().rsplit('/').next().unwrap_or_default().to_string();
+        Box::pin(async move {
+            Ok(match method.as_str() {
+                "Upload" => Grpc::new(ProstCodec::default()).client_streaming(Upload(state), req).await,
+                "Download" => Grpc::new(ProstCodec::default()).server_streaming(Download(state), req).await,
+                "WatchChanges" => Grpc::new(ProstCodec::default()).streaming(WatchChanges(state), req).await,
+                _ => Status::unimplemented(format!("No method {}", method)).into_http(),
+            })
+        })
+    }
+}
+
+struct Upload(AppState);
+
+impl ClientStreamingService<UploadRequest> for Upload {
+    type Response = FileInfo;
+    type Future = BoxFuture<tonic::Response<FileInfo>, Status>;
+
+    fn call(&mut self, request: tonic::Request<Streaming<UploadRequest>>) -> Self::Future {
+        Box::pin(upload(self.0.clone(), request))
+    }
+}
+
+struct Download(AppState);
+
+impl ServerStreamingService<DownloadRequest> for Download {
+    type Response = DownloadResponse;
+    type ResponseStream = BoxStream<'static, Result<DownloadResponse, Status>>;
+    type Future = BoxFuture<tonic::Response<Self::ResponseStream>, Status>;
+
+    fn call(&mut self, request: tonic::Request<DownloadRequest>) -> Self::Future {
+        Box::pin(download(self.0.clone(), request))
+    }
+}
+
+struct WatchChanges(AppState);
+
+impl StreamingService<WatchRequest> for WatchChanges {
+    type Response = ChangeEvent;
+    type ResponseStream = ReceiverStream<Result<ChangeEvent, Status>>;
+    type Future = BoxFuture<tonic::Response<Self::ResponseStream>, Status>;
+
+    fn call(&mut self, request: tonic::Request<Streaming<WatchRequest>>) -> Self::Future {
+        Box::pin(watch_changes(self.0.clone(), request))
+    }
+}
+
+/// The caller `require_auth` authenticated.
+fn caller<T>(request: &tonic::Request<T>) -> Result<AuthUser, Status> {
+    request
+        .extensions()
+        .get::<AuthUser>()
+        .cloned()
+        .ok_or_else(|| Status::unauthenticated("Missing or invalid token"))
+}
+
+/// The gRPC status for an HTTP error response, keeping its `error` message.
+pub(crate) async fn status_of(response: Response) -> Status {
+    let code = match response.status() {
+        StatusCode::BAD_REQUEST => Code::InvalidArgument,
+        StatusCode::UNAUTHORIZED => Code::Unauthenticated,
+        StatusCode::FORBIDDEN => Code::PermissionDenied,
+        StatusCode::NOT_FOUND => Code::NotFound,
+        StatusCode::CONFLICT => Code::Aborted,
+        StatusCode::PAYLOAD_TOO_LARGE | StatusCode::TOO_MANY_REQUESTS => Code::ResourceExhausted,
+        StatusCode::BAD_GATEWAY | StatusCode::SERVICE_UNAVAILABLE => Code::Unavailable,
+        _ => Code::Internal,
+    };
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap_or_default();
+    let message = serde_json::from_slice::<serde_json::Value>(&body)
+        .ok()
+        .and_then(|body| body["error"].as_str().map(str::to_string))
+        .unwrap_or_default();
+    Status::new(code, message)
+}
+
+fn file_info(entry: &FileEntry) -> FileInfo {
+    let name = entry.file_path.rsplit('/').next().unwrap_or(&entry.file_path);
+    FileInfo {
+        path: entry.file_path.clone(),
+        hash: entry.file_hash.clone(),
+        size: entry.file_size,
+        modified_time: entry.modified_time,
+        content_type: entry
+            .content_type
+            .clone()
+            .unwrap_or_else(|| resolve_content_type(None, name)),
+        etag: entry.etag.clone(),
+    }
+}
+
+/// Stores the streamed file the way a `/sync` update would, replacing any earlier
+/// content at its path.
+async fn upload(
+    state: AppState,
+    request: tonic::Request<Streaming<UploadRequest>>,
+) -> Result<tonic::Response<FileInfo>, Status> {
+    let user = caller(&request)?;
+    if in_maintenance(&state).await {
+        return Err(Status::unavailable("The server is in maintenance mode; changes to files are paused"));
+    }
+    let mut messages = request.into_inner();
+    let header = match messages.message().await? {
+        Some(UploadRequest { kind: Some(upload_request::Kind::Header(header)) }) => header,
+        _ => return Err(Status::invalid_argument("The first message must be the upload header")),
+    };
+
+    let path = trim_slashes(&header.path).to_string();
+    if path.is_empty() {
+        return Err(Status::invalid_argument("Missing path"));
+    }
+    let name = path.rsplit('/').next().unwrap_or(&path).to_string();
+    let content_type = resolve_content_type(Some(&header.content_type), &name);
+    let modified_time = match header.modified_time {
+        0 => chrono::Utc::now().timestamp(),
+        time => time,
+    };
+    let file = FileEntry {
+        file_name: name,
+        file_path: path.clone(),
+        file_size: header.size,
+        modified_time,
+        content_type: Some(content_type.clone()),
+        ..FileEntry::default()
+    };
+
+    let chunks = messages.map(|message| match message.map(|m| m.kind) {
+        Ok(Some(upload_request::Kind::Chunk(chunk))) => Ok(Bytes::from(chunk)),
+        Ok(_) => Err("Only content chunks may follow the upload header".to_string()),
+        Err(status) => Err(status.message().to_string()),
+    });
+    match put_file(&state, &user, file, &content_type, Body::from_stream(chunks)).await {
+        Ok(entry) => {
+            info!(user_id = user.user_id, "GRPC UPLOAD {}", path);
+            Ok(tonic::Response::new(file_info(&entry)))
+        }
+        Err(response) => Err(status_of(response).await),
+    }
+}
+
+/// Streams the file's info, then `length` bytes of its content from `offset` in
+/// messages of at most `GRPC_CHUNK_BYTES`.
+async fn download(
+    state: AppState,
+    request: tonic::Request<DownloadRequest>,
+) -> Result<tonic::Response<BoxStream<'static, Result<DownloadResponse, Status>>>, Status> {
+    let user = caller(&request)?;
+    let DownloadRequest { path, offset, length } = request.into_inner();
+
+    let entry = on_db!(&state.pool, pool => sqlx::query_as::<_, FileEntry>(
+        "SELECT file_path, file_hash, file_size, modified_time, system_path AS file_name, content_type, etag,
+                created_at, updated_at
+         FROM filehash
+         WHERE user_id = $1 AND file_path = $2"
+    )
+    .bind(user.user_id)
+    .bind(trim_slashes(&path))
+    .fetch_optional(pool)
+    .await)
+    .map_err(|e| Status::internal(e.to_string()))?
+    .ok_or_else(|| Status::not_found("File not found"))?;
+
+    let size = state
+        .storage
+        .size(&entry.file_name)
+        .await
+        .map_err(|e| Status::not_found(format!("File not found in storage: {}", e)))? as u64;
+    if offset > size {
+        return Err(Status::out_of_range(format!("offset is past the end of the {} byte file", size)));
+    }
+    let len = length.map_or(size - offset, |length| length.min(size - offset));
+
+    let content = if len == 0 {
+        stream::empty().boxed()
+    } else {
+        state
+            .storage
+            .get_range(&entry.file_name, offset, len)
+            .await
+            .map_err(|e| Status::unavailable(format!("Failed to read file: {}", e)))?
+    };
+    let chunks = content.flat_map(|chunk| {
+        let messages: Vec<_> = match chunk {
+            Ok(bytes) => bytes
+                .chunks(GRPC_CHUNK_BYTES)
+                .map(|part| Ok(DownloadResponse { kind: Some(download_response::Kind::Chunk(part.to_vec())) }))
+                .collect(),
+            Err(e) => vec![Err(Status::unavailable(format!("Failed to read file: {}", e)))],
+        };
+        stream::iter(messages)
+    });
+    let info = DownloadResponse { kind: Some(download_response::Kind::Info(file_info(&entry))) };
+
+    info!(user_id = user.user_id, "GRPC DOWNLOAD {}", entry.file_path);
+    audit::record_downloads(&state, &user, vec![entry.file_path.clone()]);
+    Ok(tonic::Response::new(stream::once(async { Ok(info) }).chain(chunks).boxed()))
+}
+
+async fn watch_changes(
+    state: AppState,
+    request: tonic::Request<Streaming<WatchRequest>>,
+) -> Result<tonic::Response<ReceiverStream<Result<ChangeEvent, Status>>>, Status> {
+    let user = caller(&request)?;
+    let (tx, rx) = mpsc::channel(WATCH_BUFFER);
+    tokio::spawn(push_changes(state, user, request.into_inner(), tx).in_current_span());
+    Ok(tonic::Response::new(ReceiverStream::new(rx)))
+}
+
+/// Sends the user's change events that pass the latest `WatchRequest` filter, until
+/// the caller goes away or the server shuts down.
+async fn push_changes(
+    state: AppState,
+    user: AuthUser,
+    mut requests: Streaming<WatchRequest>,
+    tx: mpsc::Sender<Result<ChangeEvent, Status>>,
+) {
+    info!(user_id = user.user_id, "GRPC WATCH STARTED");
+    let mut events = state.events.subscribe();
+    let mut device_id = user.device_id.clone();
+    let mut prefixes: Vec<String> = Vec::new();
+    // Cleared once the caller closes its side; it may still be reading ours.
+    let mut reading = true;
+
+    loop {
+        tokio::select! {
+            event = events.recv() => match event {
+                Ok(event) if event.user_id != user.user_id => continue,
+                Ok(event) if device_id.is_some() && event.device_id == device_id => continue,
+                Ok(event) => {
+                    let changes: Vec<FileChange> = event
+                        .changes
+                        .iter()
+                        .filter(|change| prefixes.is_empty() || prefixes.iter().any(|p| is_under(&change.file_path, p)))
+                        .map(|change| FileChange {
+                            operation: proto_operation(change.operation) as i32,
+                            path: change.file_path.clone(),
+                        })
+                        .collect();
+                    if changes.is_empty() {
+                        continue;
+                    }
+                    if tx.send(Ok(ChangeEvent { device_id: event.device_id, changes })).await.is_err() {
+                        break;
+                    }
+                }
+                // Missed events can't be replayed here; clients catch up through `/changes`.
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    warn!("gRPC watcher lagged by {} events", missed);
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+            request = requests.next(), if reading => match request {
+                Some(Ok(request)) => {
+                    device_id = Some(request.device_id).filter(|id| !id.is_empty()).or(user.device_id.clone());
+                    prefixes = request.prefixes.iter().map(|p| trim_slashes(p).to_string()).collect();
+                }
+                Some(Err(_)) => break,
+                None => reading = false,
+            },
+            _ = tx.closed() => break,
+            _ = state.shutdown.cancelled() => break,
+        }
+    }
+
+    info!(user_id = user.user_id, "GRPC WATCH ENDED");
+}
+
+/// Whether `path` is `folder` or inside it. Every path is under the root, `""`.
+fn is_under(path: &str, folder: &str) -> bool {
+    folder.is_empty() || path.strip_prefix(folder).is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+fn proto_operation(operation: Operation) -> proto::Operation {
+    match operation {
+        Operation::Insert => proto::Operation::Insert,
+        Operation::Update => proto::Operation::Update,
+        Operation::Delete => proto::Operation::Delete,
+        // Moves are published as a delete of the old path and an insert of the new one.
+        Operation::Move => proto::Operation::Unspecified,
+    }
+}
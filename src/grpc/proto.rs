@@ -0,0 +1,105 @@
+//! The messages of `proto/pocket.proto`, written out with `prost` derives the way
+//! `prost-build` would generate them, so building needs no `protoc`.
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub(crate) struct FileInfo {
+    #[prost(string, tag = "1")]
+    pub(crate) path: String,
+    #[prost(string, optional, tag = "2")]
+    pub(crate) hash: Option<String>,
+    #[prost(int64, tag = "3")]
+    pub(crate) size: i64,
+    #[prost(int64, tag = "4")]
+    pub(crate) modified_time: i64,
+    #[prost(string, tag = "5")]
+    pub(crate) content_type: String,
+    #[prost(string, optional, tag = "6")]
+    pub(crate) etag: Option<String>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub(crate) struct UploadHeader {
+    #[prost(string, tag = "1")]
+    pub(crate) path: String,
+    #[prost(int64, tag = "2")]
+    pub(crate) size: i64,
+    #[prost(int64, tag = "3")]
+    pub(crate) modified_time: i64,
+    #[prost(string, tag = "4")]
+    pub(crate) content_type: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub(crate) struct UploadRequest {
+    #[prost(oneof = "upload_request::Kind", tags = "1, 2")]
+    pub(crate) kind: Option<upload_request::Kind>,
+}
+
+pub(crate) mod upload_request {
+    #[derive(Clone, PartialEq, prost::Oneof)]
+    pub(crate) enum Kind {
+        #[prost(message, tag = "1")]
+        Header(super::UploadHeader),
+        #[prost(bytes, tag = "2")]
+        Chunk(Vec<u8>),
+    }
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub(crate) struct DownloadRequest {
+    #[prost(string, tag = "1")]
+    pub(crate) path: String,
+    #[prost(uint64, tag = "2")]
+    pub(crate) offset: u64,
+    #[prost(uint64, optional, tag = "3")]
+    pub(crate) length: Option<u64>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub(crate) struct DownloadResponse {
+    #[prost(oneof = "download_response::Kind", tags = "1, 2")]
+    pub(crate) kind: Option<download_response::Kind>,
+}
+
+pub(crate) mod download_response {
+    #[derive(Clone, PartialEq, prost::Oneof)]
+    pub(crate) enum Kind {
+        #[prost(message, tag = "1")]
+        Info(super::FileInfo),
+        #[prost(bytes, tag = "2")]
+        Chunk(Vec<u8>),
+    }
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub(crate) struct WatchRequest {
+    #[prost(string, tag = "1")]
+    pub(crate) device_id: String,
+    #[prost(string, repeated, tag = "2")]
+    pub(crate) prefixes: Vec<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+pub(crate) enum Operation {
+    Unspecified = 0,
+    Insert = 1,
+    Update = 2,
+    Delete = 3,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub(crate) struct FileChange {
+    #[prost(enumeration = "Operation", tag = "1")]
+    pub(crate) operation: i32,
+    #[prost(string, tag = "2")]
+    pub(crate) path: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub(crate) struct ChangeEvent {
+    #[prost(string, optional, tag = "1")]
+    pub(crate) device_id: Option<String>,
+    #[prost(message, repeated, tag = "2")]
+    pub(crate) changes: Vec<FileChange>,
+}
@@ -0,0 +1,201 @@
+//! Operator views across every user, and the admin actions that act on other
+//! users' data. The roles each route needs are set where the routes are built.
+
+use axum::{
+    extract::{Extension, Path, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use tracing::info;
+
+use crate::{
+    db::on_db,
+    error::AppError,
+    events::publish_changes,
+    handlers::files::purge_file,
+    models::{
+        AdminJob, AdminListParams, AdminShare, AuthUser, BatchDeleteRequest, BatchDeleteResponse,
+        FileChange, FileFailure, Operation, UserStats, UserSummary,
+    },
+    AppState, DEFAULT_PAGE_LIMIT, MAX_PAGE_LIMIT,
+};
+
+/// Lists every user with their role, quota and what they store, by id.
+pub(crate) async fn handle_list_users(
+    State(state): State<AppState>,
+    Query(params): Query<AdminListParams>,
+) -> Result<Response, AppError> {
+    let users = on_db!(&state.pool, pool => sqlx::query_as::<_, UserSummary>(
+        r#"
+        SELECT u.id, u.username, u.role, u.quota_bytes, u.created_at,
+               COALESCE(us.bytes, 0) AS bytes, COALESCE(us.files, 0) AS files
+        FROM users u
+        LEFT JOIN user_usage us ON us.user_id = u.id
+        ORDER BY u.id
+        LIMIT $1 OFFSET $2
+        "#
+    )
+    .bind(params.limit.unwrap_or(DEFAULT_PAGE_LIMIT).clamp(1, MAX_PAGE_LIMIT))
+    .bind(params.offset.unwrap_or(0).max(0))
+    .fetch_all(pool)
+    .await)?;
+
+    Ok((StatusCode::OK, Json(serde_json::json!({ "data": users }))).into_response())
+}
+
+/// Reports one user's live files, trash, versions, devices, shares and tokens.
+pub(crate) async fn handle_user_stats(
+    State(state): State<AppState>,
+    Path(id): Path<i32>,
+) -> Result<Response, AppError> {
+    let stats = on_db!(&state.pool, pool => sqlx::query_as::<_, UserStats>(
+        r#"
+        SELECT u.id, u.username, u.role, u.quota_bytes, u.created_at,
+               COALESCE(us.bytes, 0) AS bytes, COALESCE(us.files, 0) AS files,
+               COALESCE(us.trash_bytes, 0) AS trash_bytes, COALESCE(us.trash_files, 0) AS trash_files,
+               (SELECT CAST(COALESCE(SUM(file_size), 0) AS BIGINT) FROM file_versions WHERE user_id = u.id) AS version_bytes,
+               (SELECT COUNT(*) FROM file_versions WHERE user_id = u.id) AS versions,
+               (SELECT COUNT(*) FROM devices WHERE user_id = u.id) AS devices,
+               (SELECT MAX(last_seen_at) FROM devices WHERE user_id = u.id) AS last_seen_at,
+               (SELECT COUNT(*) FROM shares
+                WHERE user_id = u.id AND revoked_at IS NULL
+                  AND (expires_at IS NULL OR expires_at > CURRENT_TIMESTAMP)) AS active_shares,
+               (SELECT COUNT(*) FROM api_tokens WHERE user_id = u.id AND revoked_at IS NULL) AS active_tokens
+        FROM users u
+        LEFT JOIN user_usage us ON us.user_id = u.id
+        WHERE u.id = $1
+        "#
+    )
+    .bind(id)
+    .fetch_optional(pool)
+    .await)?
+    .ok_or_else(|| AppError::NotFound("User not found".into()))?;
+
+    Ok((StatusCode::OK, Json(serde_json::json!({ "data": stats }))).into_response())
+}
+
+/// Deletes files of any user for good, without going through their trash. Their
+/// devices are told the files were deleted, as for any other delete.
+pub(crate) async fn handle_purge_files(
+    State(state): State<AppState>,
+    Extension(admin): Extension<AuthUser>,
+    Path(id): Path<i32>,
+    Json(req): Json<BatchDeleteRequest>,
+) -> Result<Response, AppError> {
+    let max_delete_batch = state.settings.current().max_delete_batch;
+    if req.paths.len() > max_delete_batch {
+        return Err(AppError::BadRequest(format!(
+            "At most {} paths can be deleted per request",
+            max_delete_batch
+        )));
+    }
+
+    let exists = on_db!(&state.pool, pool => sqlx::query_scalar::<_, bool>("SELECT EXISTS (SELECT 1 FROM users WHERE id = $1)")
+        .bind(id)
+        .fetch_one(pool)
+        .await)?;
+    if !exists {
+        return Err(AppError::NotFound("User not found".into()));
+    }
+    // The owner's devices hear of the deletes; the audit log names the admin.
+    let owner = AuthUser { user_id: id, device_id: None, ..admin };
+
+    info!(user_id = owner.user_id, "PURGING {} FILES", req.paths.len());
+    let mut success = Vec::new();
+    let mut failure = Vec::new();
+    for file_path in req.paths {
+        match purge_file(&state, owner.user_id, &file_path).await {
+            Ok(()) => success.push(file_path),
+            Err(error) => failure.push(FileFailure::new(file_path, error)),
+        }
+    }
+
+    publish_changes(
+        &state,
+        &owner,
+        success
+            .iter()
+            .map(|file_path| FileChange {
+                operation: Operation::Delete,
+                file_path: file_path.clone(),
+            })
+            .collect(),
+    );
+    Ok((StatusCode::OK, Json(BatchDeleteResponse { success, failure })).into_response())
+}
+
+/// Lists the share links still in use, newest first, of every user or of `user_id`.
+pub(crate) async fn handle_list_shares(
+    State(state): State<AppState>,
+    Query(params): Query<AdminListParams>,
+) -> Result<Response, AppError> {
+    let shares = on_db!(&state.pool, pool => sqlx::query_as::<_, AdminShare>(
+        r#"
+        SELECT s.id, s.user_id, u.username, s.file_path, s.password_hash IS NOT NULL AS password_protected,
+               s.folder, s.created_at, s.expires_at
+        FROM shares s
+        JOIN users u ON u.id = s.user_id
+        WHERE s.revoked_at IS NULL
+          AND (s.expires_at IS NULL OR s.expires_at > CURRENT_TIMESTAMP)
+          AND (CAST($1 AS INTEGER) IS NULL OR s.user_id = $1)
+        ORDER BY s.id DESC
+        LIMIT $2 OFFSET $3
+        "#
+    )
+    .bind(params.user_id)
+    .bind(params.limit.unwrap_or(DEFAULT_PAGE_LIMIT).clamp(1, MAX_PAGE_LIMIT))
+    .bind(params.offset.unwrap_or(0).max(0))
+    .fetch_all(pool)
+    .await)?;
+
+    Ok((StatusCode::OK, Json(serde_json::json!({ "data": shares }))).into_response())
+}
+
+/// Revokes any user's share link.
+pub(crate) async fn handle_admin_revoke_share(
+    State(state): State<AppState>,
+    Path(id): Path<i32>,
+) -> Result<Response, AppError> {
+    let revoked = on_db!(&state.pool, pool => sqlx::query(
+        "UPDATE shares SET revoked_at = CURRENT_TIMESTAMP WHERE id = $1 AND revoked_at IS NULL"
+    )
+    .bind(id)
+    .execute(pool)
+    .await
+    .map(|r| r.rows_affected()))?;
+
+    if revoked == 0 {
+        return Err(AppError::NotFound("Share not found".into()));
+    }
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+/// Lists background jobs of every user and of the server, unfinished ones first,
+/// then the most recently updated.
+pub(crate) async fn handle_list_jobs(
+    State(state): State<AppState>,
+    Query(params): Query<AdminListParams>,
+) -> Result<Response, AppError> {
+    let jobs = on_db!(&state.pool, pool => sqlx::query_as::<_, AdminJob>(
+        r#"
+        SELECT j.id, j.user_id, u.username, j.kind, j.status, j.progress, j.total, j.error,
+               j.created_at, j.updated_at
+        FROM jobs j
+        LEFT JOIN users u ON u.id = j.user_id
+        WHERE (CAST($1 AS TEXT) IS NULL OR j.status = $1)
+          AND (CAST($2 AS INTEGER) IS NULL OR j.user_id = $2)
+        ORDER BY j.status IN ('completed', 'failed'), j.updated_at DESC, j.id DESC
+        LIMIT $3 OFFSET $4
+        "#
+    )
+    .bind(&params.status)
+    .bind(params.user_id)
+    .bind(params.limit.unwrap_or(DEFAULT_PAGE_LIMIT).clamp(1, MAX_PAGE_LIMIT))
+    .bind(params.offset.unwrap_or(0).max(0))
+    .fetch_all(pool)
+    .await)?;
+
+    Ok((StatusCode::OK, Json(serde_json::json!({ "data": jobs }))).into_response())
+}
+
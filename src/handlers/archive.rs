@@ -0,0 +1,443 @@
+//! Archive storage classes. Users mark files or folders to keep in `STANDARD_IA`
+//! or `GLACIER`, and a sweep every `ARCHIVE_SWEEP_INTERVAL_SECS`, also run as soon
+//! as a mark changes, moves the objects of the files they cover by copying each
+//! onto itself in the new class. New uploads land in `STANDARD` and are moved on
+//! the next sweep. Glacial files have to be restored before they can be read
+//! again, which `/restore-archive` asks for and the sweep follows up on.
+
+use std::time::Duration;
+
+use axum::{
+    extract::{Extension, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use sqlx::FromRow;
+use tracing::{info, info_span, warn, Instrument};
+
+use crate::{
+    db::on_db,
+    env_or,
+    error::AppError,
+    handlers::{files::trim_slashes, maintenance::in_maintenance},
+    models::{
+        ArchivePathParams, ArchiveRestore, ArchiveRule, ArchiveRuleRequest, ArchivedFile, AuthUser, FileFailure,
+        RestoreArchiveRequest,
+    },
+    storage::{RestoreState, StorageClass},
+    AppState, DEFAULT_ARCHIVE_RESTORE_DAYS,
+};
+
+/// A file the sweep may have to move: one a mark covers, or one it moved before.
+#[derive(FromRow)]
+struct Candidate {
+    id: i32,
+    file_path: String,
+    system_path: String,
+    change_seq: Option<i64>,
+    archived_class: Option<String>,
+    archived_seq: Option<i64>,
+    restore_status: Option<String>,
+    /// The class of the mark closest to the file.
+    wanted: Option<String>,
+}
+
+#[utoipa::path(
+    get, path = "/archive", tag = "files",
+    summary = "Lists the files and folders the caller keeps archived",
+    responses((status = 200, description = "The caller's marks", body = crate::openapi::Data<Vec<ArchiveRule>>))
+)]
+pub(crate) async fn handle_list_archive_rules(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+) -> Result<Response, AppError> {
+    let rules = on_db!(&state.pool, pool => sqlx::query_as::<_, ArchiveRule>(
+        "SELECT path, storage_class, created_at FROM archive_rules WHERE user_id = $1 ORDER BY path"
+    )
+    .bind(user.user_id)
+    .fetch_all(pool)
+    .await)?;
+    Ok((StatusCode::OK, Json(serde_json::json!({ "data": rules }))).into_response())
+}
+
+/// Keeps a file, or every file under a folder, in an archive storage class. The
+/// objects are moved in the background.
+#[utoipa::path(
+    put, path = "/archive", tag = "files",
+    request_body = ArchiveRuleRequest,
+    responses(
+        (status = 200, description = "The stored mark", body = ArchiveRule),
+        (status = 400, description = "Invalid request", body = crate::openapi::ErrorBody),
+    )
+)]
+pub(crate) async fn handle_put_archive_rule(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Json(req): Json<ArchiveRuleRequest>,
+) -> Result<Response, AppError> {
+    let path = trim_slashes(&req.path);
+    if path.is_empty() {
+        return Err(AppError::BadRequest("path must name a file or folder".into()));
+    }
+    if req.storage_class == StorageClass::Standard {
+        return Err(AppError::BadRequest("storage_class must be STANDARD_IA or GLACIER".into()));
+    }
+
+    let rule = on_db!(&state.pool, pool => sqlx::query_as::<_, ArchiveRule>(
+        r#"
+        INSERT INTO archive_rules (user_id, path, storage_class) VALUES ($1, $2, $3)
+        ON CONFLICT (user_id, path) DO UPDATE SET storage_class = EXCLUDED.storage_class
+        RETURNING path, storage_class, created_at
+        "#
+    )
+    .bind(user.user_id)
+    .bind(path)
+    .bind(req.storage_class.as_str())
+    .fetch_one(pool)
+    .await)?;
+
+    info!(user_id = user.user_id, "ARCHIVE MARKED: {} as {}", path, req.storage_class.as_str());
+    spawn_sweep(&state, user.user_id);
+    Ok((StatusCode::OK, Json(rule)).into_response())
+}
+
+/// Stops keeping a file or folder archived. Its files move back to `STANDARD` in
+/// the background, glacial ones once a restore of them finished.
+#[utoipa::path(
+    delete, path = "/archive", tag = "files",
+    params(ArchivePathParams),
+    responses(
+        (status = 204, description = "Removed"),
+        (status = 404, description = "The path isn't marked", body = crate::openapi::ErrorBody),
+    )
+)]
+pub(crate) async fn handle_delete_archive_rule(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Query(params): Query<ArchivePathParams>,
+) -> Result<Response, AppError> {
+    let path = params.path.as_deref().map(trim_slashes).unwrap_or_default();
+    let deleted = on_db!(&state.pool, pool => sqlx::query("DELETE FROM archive_rules WHERE user_id = $1 AND path = $2")
+    .bind(user.user_id)
+    .bind(path)
+    .execute(pool)
+    .await
+    .map(|r| r.rows_affected()))?;
+    if deleted == 0 {
+        return Err(AppError::NotFound("Path isn't marked for archiving".into()));
+    }
+
+    info!(user_id = user.user_id, "ARCHIVE UNMARKED: {}", path);
+    spawn_sweep(&state, user.user_id);
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+/// The caller's files that were moved to an archive class, under `path` when given.
+#[utoipa::path(
+    get, path = "/archive/files", tag = "files",
+    params(ArchivePathParams),
+    responses((status = 200, description = "The archived files", body = crate::openapi::Data<Vec<ArchivedFile>>))
+)]
+pub(crate) async fn handle_list_archived_files(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Query(params): Query<ArchivePathParams>,
+) -> Result<Response, AppError> {
+    let path = params.path.as_deref().map(trim_slashes).unwrap_or_default();
+    let files = archived_files(&state, user.user_id, path).await?;
+    Ok((StatusCode::OK, Json(serde_json::json!({ "data": files }))).into_response())
+}
+
+/// Asks for readable copies of a glacial file, or of every glacial file under a
+/// folder, or reports how far the restores asked for before got. Restores take
+/// hours; calling again, or `/archive/files`, shows when they're done.
+#[utoipa::path(
+    post, path = "/restore-archive", tag = "files",
+    request_body = RestoreArchiveRequest,
+    responses(
+        (status = 200, description = "Where each file's restore stands", body = crate::openapi::Data<ArchiveRestore>),
+        (status = 400, description = "Invalid request", body = crate::openapi::ErrorBody),
+        (status = 404, description = "No glacial files at the path", body = crate::openapi::ErrorBody),
+    )
+)]
+pub(crate) async fn handle_restore_archive(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Json(req): Json<RestoreArchiveRequest>,
+) -> Result<Response, AppError> {
+    let days = req.days.unwrap_or_else(restore_days);
+    if !(1..=365).contains(&days) {
+        return Err(AppError::BadRequest("days must be between 1 and 365".into()));
+    }
+    let path = trim_slashes(&req.path);
+    if path.is_empty() {
+        return Err(AppError::BadRequest("path must name a file or folder".into()));
+    }
+
+    let glacial = on_db!(&state.pool, pool => sqlx::query_as::<_, (i32, String, String)>(
+        r#"
+        SELECT f.id, f.file_path, f.system_path
+        FROM archived_files a
+        JOIN filehash f ON f.id = a.file_id
+        WHERE f.user_id = $1 AND a.storage_class = 'GLACIER'
+          AND (f.file_path = $2 OR SUBSTR(f.file_path, 1, LENGTH($2) + 1) = $2 || '/')
+        "#
+    )
+    .bind(user.user_id)
+    .bind(path)
+    .fetch_all(pool)
+    .await)?;
+    if glacial.is_empty() {
+        return Err(AppError::NotFound(format!("No glacial files at {}", path)));
+    }
+
+    let mut failed = Vec::new();
+    for (id, file_path, system_path) in glacial {
+        let result = match state.storage.restore(&system_path, days).await {
+            Ok(restore) => record_restore(&state, id, restore).await.map_err(|e| e.to_string()),
+            Err(e) => Err(e.to_string()),
+        };
+        if let Err(e) = result {
+            failed.push(FileFailure::new(file_path, e));
+        }
+    }
+
+    let files = archived_files(&state, user.user_id, path)
+        .await?
+        .into_iter()
+        .filter(|file| file.storage_class.is_glacial())
+        .collect();
+    info!(user_id = user.user_id, "ARCHIVE RESTORE of {} for {} days", path, days);
+    Ok((StatusCode::OK, Json(serde_json::json!({ "data": ArchiveRestore { files, failed } }))).into_response())
+}
+
+async fn archived_files(state: &AppState, user_id: i32, path: &str) -> Result<Vec<ArchivedFile>, sqlx::Error> {
+    on_db!(&state.pool, pool => sqlx::query_as::<_, ArchivedFile>(
+        r#"
+        SELECT f.file_path, a.storage_class, a.restore_status, a.restore_expires_at,
+               (a.storage_class <> 'GLACIER' OR COALESCE(a.restore_status, '') = 'restored') AS readable
+        FROM archived_files a
+        JOIN filehash f ON f.id = a.file_id
+        WHERE f.user_id = $1
+          AND ($2 = '' OR f.file_path = $2 OR SUBSTR(f.file_path, 1, LENGTH($2) + 1) = $2 || '/')
+        ORDER BY f.file_path
+        "#
+    )
+    .bind(user_id)
+    .bind(path)
+    .fetch_all(pool)
+    .await)
+}
+
+async fn record_restore(state: &AppState, file_id: i32, restore: RestoreState) -> Result<(), sqlx::Error> {
+    let (status, expires_at) = match restore {
+        RestoreState::InProgress => ("in_progress", None),
+        RestoreState::Restored { expires_at } => {
+            ("restored", expires_at.and_then(|at| chrono::DateTime::from_timestamp(at, 0)).map(|at| at.naive_utc()))
+        }
+        // Nothing has to be restored to read it.
+        RestoreState::Readable => ("restored", None),
+    };
+    on_db!(&state.pool, pool => sqlx::query(
+        r#"
+        UPDATE archived_files
+        SET restore_status = $2, restore_expires_at = $3, updated_at = CURRENT_TIMESTAMP
+        WHERE file_id = $1
+        "#
+    )
+    .bind(file_id)
+    .bind(status)
+    .bind(expires_at)
+    .execute(pool)
+    .await
+    .map(|_| ()))
+}
+
+/// How long restored copies stay readable unless a restore asks otherwise.
+fn restore_days() -> i32 {
+    env_or("ARCHIVE_RESTORE_DAYS", DEFAULT_ARCHIVE_RESTORE_DAYS).clamp(1, 365)
+}
+
+/// Applies the user's marks right away, from a background task.
+fn spawn_sweep(state: &AppState, user_id: i32) {
+    let tasks = state.tasks.clone();
+    let state = state.clone();
+    tasks.spawn(async move {
+        if let Err(e) = apply_archive_rules(&state, user_id).await {
+            warn!("Failed to apply the archive marks of user {}: {}", user_id, e);
+        }
+    }.instrument(info_span!("archive_sweep", user_id)));
+}
+
+/// Moves each of the user's files to the class the mark closest to it names, and
+/// back to `STANDARD` the files no mark covers any more. Returns how many moved.
+pub(crate) async fn apply_archive_rules(state: &AppState, user_id: i32) -> Result<usize, String> {
+    let _sweeping = state.archiving.lock().await;
+    let candidates = on_db!(&state.pool, pool => sqlx::query_as::<_, Candidate>(
+        r#"
+        SELECT * FROM (
+            SELECT f.id, f.file_path, f.system_path, f.change_seq,
+                   a.storage_class AS archived_class, a.change_seq AS archived_seq, a.restore_status,
+                   (SELECT r.storage_class FROM archive_rules r
+                    WHERE r.user_id = f.user_id
+                      AND (r.path = f.file_path OR SUBSTR(f.file_path, 1, LENGTH(r.path) + 1) = r.path || '/')
+                    ORDER BY LENGTH(r.path) DESC
+                    LIMIT 1) AS wanted
+            FROM filehash f
+            LEFT JOIN archived_files a ON a.file_id = f.id
+            WHERE f.user_id = $1
+        ) candidates
+        WHERE wanted IS NOT NULL OR archived_class IS NOT NULL
+        "#
+    )
+    .bind(user_id)
+    .fetch_all(pool)
+    .await)
+    .map_err(|e| e.to_string())?;
+
+    let days = restore_days();
+    let mut moved = 0;
+    for file in candidates {
+        let wanted = file.wanted.and_then(|class| StorageClass::try_from(class).ok()).unwrap_or(StorageClass::Standard);
+        let current = file.archived_class.and_then(|class| StorageClass::try_from(class).ok());
+        // A file changed since it was moved may have been rewritten in STANDARD,
+        // so it's moved again; backends skip objects already in the class.
+        if current == Some(wanted) && file.archived_seq == file.change_seq {
+            continue;
+        }
+
+        // Glacial objects can only be copied out of once restored.
+        if current.is_some_and(StorageClass::is_glacial)
+            && current != Some(wanted)
+            && file.restore_status.as_deref() != Some("restored")
+        {
+            match state.storage.restore(&file.system_path, days).await {
+                Ok(RestoreState::InProgress) => {
+                    if file.restore_status.is_none() {
+                        record_restore(state, file.id, RestoreState::InProgress).await.map_err(|e| e.to_string())?;
+                    }
+                    continue;
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    warn!("Failed to restore {} to move it out of {:?}: {}", file.file_path, current, e);
+                    continue;
+                }
+            }
+        }
+
+        if let Err(e) = state.storage.set_storage_class(&file.system_path, wanted).await {
+            warn!("Failed to move {} to {}: {}", file.file_path, wanted.as_str(), e);
+            continue;
+        }
+        let recorded = if wanted == StorageClass::Standard {
+            on_db!(&state.pool, pool => sqlx::query("DELETE FROM archived_files WHERE file_id = $1")
+                .bind(file.id)
+                .execute(pool)
+                .await
+                .map(|_| ()))
+        } else {
+            on_db!(&state.pool, pool => sqlx::query(
+                r#"
+                INSERT INTO archived_files (file_id, storage_class, change_seq) VALUES ($1, $2, $3)
+                ON CONFLICT (file_id) DO UPDATE
+                SET storage_class = EXCLUDED.storage_class, change_seq = EXCLUDED.change_seq,
+                    restore_status = NULL, restore_expires_at = NULL, updated_at = CURRENT_TIMESTAMP
+                "#
+            )
+            .bind(file.id)
+            .bind(wanted.as_str())
+            .bind(file.change_seq)
+            .execute(pool)
+            .await
+            .map(|_| ()))
+        };
+        recorded.map_err(|e| e.to_string())?;
+        moved += 1;
+    }
+    Ok(moved)
+}
+
+/// Follows up on restores still in progress, and forgets those whose copies expired.
+async fn poll_restores(state: &AppState) -> Result<(), String> {
+    let pending = on_db!(&state.pool, pool => sqlx::query_as::<_, (i32, String)>(
+        r#"
+        SELECT a.file_id, f.system_path
+        FROM archived_files a
+        JOIN filehash f ON f.id = a.file_id
+        WHERE a.restore_status = 'in_progress'
+        "#
+    )
+    .fetch_all(pool)
+    .await)
+    .map_err(|e| e.to_string())?;
+
+    let days = restore_days();
+    for (file_id, system_path) in pending {
+        match state.storage.restore(&system_path, days).await {
+            Ok(RestoreState::InProgress) => {}
+            Ok(restore) => record_restore(state, file_id, restore).await.map_err(|e| e.to_string())?,
+            Err(e) => warn!("Failed to check the restore of {}: {}", system_path, e),
+        }
+    }
+
+    on_db!(&state.pool, pool => sqlx::query(
+        r#"
+        UPDATE archived_files
+        SET restore_status = NULL, restore_expires_at = NULL, updated_at = CURRENT_TIMESTAMP
+        WHERE restore_status = 'restored' AND restore_expires_at <= CURRENT_TIMESTAMP
+        "#
+    )
+    .execute(pool)
+    .await
+    .map(|_| ()))
+    .map_err(|e| e.to_string())
+}
+
+/// Applies every user's archive marks and follows up on restores every `every`,
+/// outside maintenance.
+pub(crate) async fn archive_periodically(state: AppState, every: Duration) {
+    let mut interval = tokio::time::interval(every);
+    loop {
+        interval.tick().await;
+        if in_maintenance(&state).await {
+            continue;
+        }
+        sweep(&state).await;
+    }
+}
+
+/// Applies every user's archive marks once, and follows up on their restores.
+pub(crate) async fn sweep(state: &AppState) {
+    let users = on_db!(&state.pool, pool => sqlx::query_scalar::<_, i32>(
+        r#"
+        SELECT user_id FROM archive_rules
+        UNION
+        SELECT f.user_id FROM archived_files a JOIN filehash f ON f.id = a.file_id
+        "#
+    )
+    .fetch_all(pool)
+    .await);
+    let users = match users {
+        Ok(users) => users,
+        Err(e) => {
+            warn!("Failed to list users with archived files: {}", e);
+            return;
+        }
+    };
+
+    let mut moved = 0;
+    for user_id in users {
+        match apply_archive_rules(state, user_id).await {
+            Ok(count) => moved += count,
+            Err(e) => warn!("Failed to apply the archive marks of user {}: {}", user_id, e),
+        }
+    }
+    if moved > 0 {
+        info!("MOVED {} FILES BETWEEN STORAGE CLASSES", moved);
+    }
+    if let Err(e) = poll_restores(state).await {
+        warn!("Failed to check archive restores: {}", e);
+    }
+}
@@ -0,0 +1,98 @@
+use axum::{
+    extract::{Extension, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+
+use crate::{
+    db::on_db,
+    error::AppError,
+    models::{ActivityEntry, ActivityPage, ActivityParams, AuditEntry, AuditParams, AuthUser, Role},
+    AppState, DEFAULT_PAGE_LIMIT, MAX_PAGE_LIMIT,
+};
+
+/// Lists what was done to the caller's files, or one of them, newest first.
+/// Operators and admins may look at any user's with `user_id`.
+#[utoipa::path(
+    get, path = "/audit", tag = "files",
+    params(AuditParams),
+    responses(
+        (status = 200, description = "The matching audit log entries", body = crate::openapi::Data<Vec<AuditEntry>>),
+        (status = 401, description = "Missing or invalid bearer token", body = crate::openapi::ErrorBody),
+        (status = 403, description = "`user_id` names another user and the caller isn't an operator", body = crate::openapi::ErrorBody),
+    )
+)]
+pub(crate) async fn handle_audit(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Query(params): Query<AuditParams>,
+) -> Result<Response, AppError> {
+    let user_id = params.user_id.unwrap_or(user.user_id);
+    if user_id != user.user_id && user.role < Role::Operator {
+        return Err(AppError::Forbidden("Only operators can see other users' audit logs".into()));
+    }
+
+    let entries = on_db!(&state.pool, pool => sqlx::query_as::<_, AuditEntry>(
+        r#"
+        SELECT id, user_id, username, device_id, ip, operation, file_path, created_at
+        FROM audit_log
+        WHERE user_id = $1 AND (CAST($2 AS TEXT) IS NULL OR file_path = $2)
+        ORDER BY id DESC
+        LIMIT $3 OFFSET $4
+        "#
+    )
+    .bind(user_id)
+    .bind(&params.path)
+    .bind(params.limit.unwrap_or(DEFAULT_PAGE_LIMIT).clamp(1, MAX_PAGE_LIMIT))
+    .bind(params.offset.unwrap_or(0).max(0))
+    .fetch_all(pool)
+    .await)?;
+
+    Ok((StatusCode::OK, Json(serde_json::json!({ "data": entries }))).into_response())
+}
+
+/// What was recently done to the caller's files, from any of their devices or by
+/// collaborators, newest first: uploads, edits, moves, deletions, restores and
+/// shares. Downloads are left to `/audit`.
+#[utoipa::path(
+    get, path = "/activity", tag = "files",
+    params(ActivityParams),
+    responses(
+        (status = 200, description = "One page of the activity feed", body = ActivityPage),
+        (status = 401, description = "Missing or invalid bearer token", body = crate::openapi::ErrorBody),
+    )
+)]
+pub(crate) async fn handle_activity(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Query(params): Query<ActivityParams>,
+) -> Result<Response, AppError> {
+    let limit = params.limit.unwrap_or(DEFAULT_PAGE_LIMIT).clamp(1, MAX_PAGE_LIMIT);
+
+    // One more than asked for, to tell whether there's another page.
+    let mut entries = on_db!(&state.pool, pool => sqlx::query_as::<_, ActivityEntry>(
+        r#"
+        SELECT a.id, a.operation, a.file_path, a.username, a.device_id, d.name AS device_name, a.created_at
+        FROM audit_log a
+        LEFT JOIN devices d ON d.user_id = a.user_id AND d.id = a.device_id
+        WHERE a.user_id = $1 AND a.operation <> 'download' AND (CAST($2 AS BIGINT) IS NULL OR a.id < $2)
+        ORDER BY a.id DESC
+        LIMIT $3
+        "#
+    )
+    .bind(user.user_id)
+    .bind(params.before)
+    .bind(limit + 1)
+    .fetch_all(pool)
+    .await)?;
+
+    let next_before = if entries.len() as i64 > limit {
+        entries.truncate(limit as usize);
+        entries.last().map(|entry| entry.id)
+    } else {
+        None
+    };
+
+    Ok((StatusCode::OK, Json(ActivityPage { data: entries, next_before })).into_response())
+}
@@ -0,0 +1,429 @@
+use axum::{
+    extract::{Extension, Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use tracing::info;
+
+use crate::{
+    auth::{generate_token, hash_password, hash_token, issue_jwt, verify_password},
+    db::on_db,
+    error::AppError,
+    models::{
+        AuthUser, CreateApiTokenRequest, CreateTokenRequest, CreateUserRequest, LoginRequest, Role, TokenInfo,
+        TokenScope, TotpCodeRequest, TotpEnrollment,
+    },
+    totp, AppState, TOTP_ISSUER,
+};
+
+/// A user's two-factor state: their secret, when it was confirmed, and the time
+/// step of the last code accepted.
+type TotpRow = (Option<String>, Option<chrono::NaiveDateTime>, Option<i64>);
+
+/// Exchanges a username and password for a session JWT. Users with two-factor
+/// authentication also send the current code from their authenticator app.
+#[utoipa::path(
+    post, path = "/auth/login", tag = "auth", security(()),
+    request_body = LoginRequest,
+    responses(
+        (status = 200, description = "Session token", body = crate::openapi::LoginResponse),
+        (status = 401, description = "Invalid credentials, or a missing or invalid two-factor code", body = crate::openapi::ErrorBody),
+    )
+)]
+pub(crate) async fn handle_login(
+    State(state): State<AppState>,
+    Json(req): Json<LoginRequest>,
+) -> Result<Response, AppError> {
+    let row = on_db!(&state.pool, pool => sqlx::query_as::<_, (i32, Role, Option<String>, Option<String>, Option<chrono::NaiveDateTime>, Option<i64>)>(
+        "SELECT id, role, password_hash, totp_secret, totp_enabled_at, totp_last_step FROM users WHERE username = $1"
+    )
+    .bind(&req.username)
+    .fetch_optional(pool)
+    .await)?;
+
+    let (user, totp) = match row {
+        Some((user_id, role, Some(hash), secret, enabled_at, last_step)) if verify_password(&req.password, &hash) => (
+            AuthUser {
+                user_id,
+                username: req.username,
+                role,
+                scope: TokenScope::Full,
+                device_id: None,
+                ip: None,
+            },
+            secret.filter(|_| enabled_at.is_some()).map(|secret| (secret, last_step)),
+        ),
+        _ => return Err(AppError::Unauthorized("Invalid username or password".into())),
+    };
+
+    if let Some((secret, last_step)) = totp {
+        let Some(code) = &req.totp_code else {
+            return Err(AppError::Unauthorized("Two-factor code required".into()));
+        };
+        if !accept_totp_code(&state, user.user_id, &secret, last_step, code).await? {
+            return Err(AppError::Unauthorized("Invalid two-factor code".into()));
+        }
+    }
+
+    let token = issue_jwt(&state.jwt, &user)
+        .map_err(|e| AppError::Internal(format!("Failed to issue token: {}", e)))?;
+    info!(user_id = user.user_id, username = %user.username, "LOGIN");
+    Ok((StatusCode::OK, Json(serde_json::json!({
+        "token": token,
+        "token_type": "Bearer",
+        "expires_in_seconds": state.jwt.expiry_secs
+    }))).into_response())
+}
+
+/// Whether `code` is a current code of `secret`, marking its time step used so
+/// the same code can't be accepted again, even by a concurrent request.
+async fn accept_totp_code(
+    state: &AppState,
+    user_id: i32,
+    secret: &str,
+    last_step: Option<i64>,
+    code: &str,
+) -> Result<bool, AppError> {
+    let Some(step) = totp::verify(secret, code, chrono::Utc::now().timestamp(), last_step) else {
+        return Ok(false);
+    };
+    let claimed = on_db!(&state.pool, pool => sqlx::query(
+        "UPDATE users SET totp_last_step = $2 WHERE id = $1 AND (totp_last_step IS NULL OR totp_last_step < $2)"
+    )
+    .bind(user_id)
+    .bind(step)
+    .execute(pool)
+    .await
+    .map(|r| r.rows_affected()))?;
+    Ok(claimed == 1)
+}
+
+async fn load_totp(state: &AppState, user_id: i32) -> Result<TotpRow, AppError> {
+    let row = on_db!(&state.pool, pool => sqlx::query_as::<_, TotpRow>(
+        "SELECT totp_secret, totp_enabled_at, totp_last_step FROM users WHERE id = $1"
+    )
+    .bind(user_id)
+    .fetch_optional(pool)
+    .await)?;
+    row.ok_or_else(|| AppError::NotFound("User not found".into()))
+}
+
+/// Starts two-factor enrollment with a new secret for the caller's authenticator
+/// app. Logins don't ask for codes until one is confirmed with `/auth/2fa/verify`;
+/// enrolling again before then replaces the secret.
+#[utoipa::path(
+    post, path = "/auth/2fa/enroll", tag = "auth",
+    responses(
+        (status = 200, description = "The secret to add to an authenticator app", body = TotpEnrollment),
+        (status = 409, description = "Two-factor authentication is already enabled", body = crate::openapi::ErrorBody),
+    )
+)]
+pub(crate) async fn handle_totp_enroll(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+) -> Result<Response, AppError> {
+    let secret = totp::generate_secret();
+    let enrolled = on_db!(&state.pool, pool => sqlx::query(
+        "UPDATE users SET totp_secret = $2, totp_last_step = NULL WHERE id = $1 AND totp_enabled_at IS NULL"
+    )
+    .bind(user.user_id)
+    .bind(&secret)
+    .execute(pool)
+    .await
+    .map(|r| r.rows_affected()))?;
+    if enrolled == 0 {
+        return Err(AppError::Conflict("Two-factor authentication is already enabled".into()));
+    }
+
+    info!(user_id = user.user_id, username = %user.username, "TOTP ENROLLED");
+    let otpauth_url = totp::otpauth_url(TOTP_ISSUER, &user.username, &secret);
+    Ok((StatusCode::OK, Json(TotpEnrollment { secret, otpauth_url })).into_response())
+}
+
+/// Confirms enrollment with a code from the new secret, turning two-factor
+/// authentication on.
+#[utoipa::path(
+    post, path = "/auth/2fa/verify", tag = "auth",
+    request_body = TotpCodeRequest,
+    responses(
+        (status = 200, description = "Two-factor authentication is enabled"),
+        (status = 400, description = "Invalid code, or no enrollment to confirm", body = crate::openapi::ErrorBody),
+        (status = 409, description = "Two-factor authentication is already enabled", body = crate::openapi::ErrorBody),
+    )
+)]
+pub(crate) async fn handle_totp_verify(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Json(req): Json<TotpCodeRequest>,
+) -> Result<Response, AppError> {
+    let (secret, enabled_at, last_step) = load_totp(&state, user.user_id).await?;
+    if enabled_at.is_some() {
+        return Err(AppError::Conflict("Two-factor authentication is already enabled".into()));
+    }
+    let Some(secret) = secret else {
+        return Err(AppError::BadRequest("Start enrollment with /auth/2fa/enroll first".into()));
+    };
+    if !accept_totp_code(&state, user.user_id, &secret, last_step, &req.code).await? {
+        return Err(AppError::BadRequest("Invalid two-factor code".into()));
+    }
+
+    // The secret is matched so an enrollment started meanwhile isn't enabled unconfirmed.
+    let enabled = on_db!(&state.pool, pool => sqlx::query(
+        "UPDATE users SET totp_enabled_at = CURRENT_TIMESTAMP WHERE id = $1 AND totp_secret = $2"
+    )
+    .bind(user.user_id)
+    .bind(&secret)
+    .execute(pool)
+    .await
+    .map(|r| r.rows_affected()))?;
+    if enabled == 0 {
+        return Err(AppError::Conflict("Enrollment was restarted; confirm a code from the new secret".into()));
+    }
+
+    info!(user_id = user.user_id, username = %user.username, "TOTP ENABLED");
+    Ok((StatusCode::OK, Json(serde_json::json!({ "enabled": true }))).into_response())
+}
+
+/// Turns two-factor authentication off, given a current code.
+#[utoipa::path(
+    post, path = "/auth/2fa/disable", tag = "auth",
+    request_body = TotpCodeRequest,
+    responses(
+        (status = 204, description = "Two-factor authentication is disabled"),
+        (status = 400, description = "Invalid code, or two-factor authentication isn't enabled", body = crate::openapi::ErrorBody),
+    )
+)]
+pub(crate) async fn handle_totp_disable(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Json(req): Json<TotpCodeRequest>,
+) -> Result<Response, AppError> {
+    let (secret, enabled_at, last_step) = load_totp(&state, user.user_id).await?;
+    let Some(secret) = secret.filter(|_| enabled_at.is_some()) else {
+        return Err(AppError::BadRequest("Two-factor authentication isn't enabled".into()));
+    };
+    if !accept_totp_code(&state, user.user_id, &secret, last_step, &req.code).await? {
+        return Err(AppError::BadRequest("Invalid two-factor code".into()));
+    }
+
+    clear_totp(&state, user.user_id).await?;
+    info!(user_id = user.user_id, username = %user.username, "TOTP DISABLED");
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+async fn clear_totp(state: &AppState, user_id: i32) -> Result<u64, AppError> {
+    Ok(on_db!(&state.pool, pool => sqlx::query(
+        "UPDATE users SET totp_secret = NULL, totp_enabled_at = NULL, totp_last_step = NULL WHERE id = $1"
+    )
+    .bind(user_id)
+    .execute(pool)
+    .await
+    .map(|r| r.rows_affected()))?)
+}
+
+/// Turns a user's two-factor authentication off, for when they have lost their
+/// authenticator.
+pub(crate) async fn handle_reset_totp(
+    State(state): State<AppState>,
+    Path(id): Path<i32>,
+) -> Result<Response, AppError> {
+    if clear_totp(&state, id).await? == 0 {
+        return Err(AppError::NotFound("User not found".into()));
+    }
+    info!(user_id = id, "TOTP RESET");
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+/// Creates a user, or resets an existing user's password, role and quota.
+pub(crate) async fn handle_create_user(
+    State(state): State<AppState>,
+    Json(req): Json<CreateUserRequest>,
+) -> Result<Response, AppError> {
+    let password_hash = hash_password(&req.password)
+        .map_err(|e| AppError::Internal(format!("Failed to hash password: {}", e)))?;
+
+    let id = on_db!(&state.pool, pool => sqlx::query_scalar::<_, i32>(
+        r#"
+        INSERT INTO users (username, role, password_hash, quota_bytes)
+        VALUES ($1, $2, $3, $4)
+        ON CONFLICT (username) DO UPDATE
+        SET role = EXCLUDED.role,
+            password_hash = EXCLUDED.password_hash,
+            quota_bytes = EXCLUDED.quota_bytes
+        RETURNING id
+        "#
+    )
+    .bind(&req.username)
+    .bind(req.role)
+    .bind(password_hash)
+    .bind(req.quota_bytes)
+    .fetch_one(pool)
+    .await)?;
+
+    Ok((StatusCode::CREATED, Json(serde_json::json!({
+        "id": id,
+        "username": req.username,
+        "role": req.role,
+        "quota_bytes": req.quota_bytes
+    }))).into_response())
+}
+
+/// Mints a token for `username`, creating the user if needed. The plaintext
+/// token is only ever returned here; the database keeps its hash.
+pub(crate) async fn handle_create_token(
+    State(state): State<AppState>,
+    Json(req): Json<CreateTokenRequest>,
+) -> Result<Response, AppError> {
+    let user_id = on_db!(&state.pool, pool => sqlx::query_scalar::<_, i32>(
+        r#"
+        INSERT INTO users (username, role)
+        VALUES ($1, $2)
+        ON CONFLICT (username) DO UPDATE SET username = EXCLUDED.username
+        RETURNING id
+        "#
+    )
+    .bind(&req.username)
+    .bind(req.role)
+    .fetch_one(pool)
+    .await)?;
+
+    insert_token(&state, user_id, &req.username, req.scope, req.name).await
+}
+
+/// Stores the hash of a new token for `user_id`, answering with the token itself.
+async fn insert_token(
+    state: &AppState,
+    user_id: i32,
+    username: &str,
+    scope: TokenScope,
+    name: Option<String>,
+) -> Result<Response, AppError> {
+    let token = generate_token();
+    let (id, created_at) = on_db!(&state.pool, pool => sqlx::query_as::<_, (i32, Option<chrono::NaiveDateTime>)>(
+        r#"
+        INSERT INTO api_tokens (user_id, token_hash, scope, name)
+        VALUES ($1, $2, $3, $4)
+        RETURNING id, created_at
+        "#
+    )
+    .bind(user_id)
+    .bind(hash_token(&token))
+    .bind(scope)
+    .bind(&name)
+    .fetch_one(pool)
+    .await)?;
+
+    info!(user_id, token_id = id, scope = scope.as_str(), "TOKEN CREATED");
+    Ok((StatusCode::CREATED, Json(serde_json::json!({
+        "id": id,
+        "username": username,
+        "name": name,
+        "scope": scope,
+        "token": token,
+        "created_at": created_at
+    }))).into_response())
+}
+
+pub(crate) async fn handle_list_tokens(State(state): State<AppState>) -> Result<Response, AppError> {
+    let tokens = on_db!(&state.pool, pool => sqlx::query_as::<_, TokenInfo>(
+        r#"
+        SELECT t.id, u.username, t.name, t.scope, t.created_at, t.last_used_at, t.revoked_at
+        FROM api_tokens t
+        JOIN users u ON u.id = t.user_id
+        ORDER BY t.id
+        "#
+    )
+    .fetch_all(pool)
+    .await)?;
+
+    Ok((StatusCode::OK, Json(serde_json::json!({ "data": tokens }))).into_response())
+}
+
+pub(crate) async fn handle_revoke_token(
+    State(state): State<AppState>,
+    Path(id): Path<i32>,
+) -> Result<Response, AppError> {
+    revoke_token(&state, id, None).await
+}
+
+/// Revokes token `id`, when it belongs to `owner` if one is given.
+async fn revoke_token(state: &AppState, id: i32, owner: Option<i32>) -> Result<Response, AppError> {
+    let revoked = on_db!(&state.pool, pool => sqlx::query(
+        r#"
+        UPDATE api_tokens SET revoked_at = CURRENT_TIMESTAMP
+        WHERE id = $1 AND revoked_at IS NULL AND (CAST($2 AS INTEGER) IS NULL OR user_id = $2)
+        "#
+    )
+    .bind(id)
+    .bind(owner)
+    .execute(pool)
+    .await
+    .map(|r| r.rows_affected()))?;
+
+    if revoked == 0 {
+        return Err(AppError::NotFound("Token not found".into()));
+    }
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+/// Mints an API token for the caller, such as a read-only one for a script or
+/// an upload-only one for a backup agent. Tokens skip two-factor authentication,
+/// so the plaintext is only ever returned here; the database keeps its hash.
+#[utoipa::path(
+    post, path = "/tokens", tag = "auth",
+    request_body = CreateApiTokenRequest,
+    responses(
+        (status = 201, description = "The new token", body = crate::openapi::TokenCreated),
+        (status = 401, description = "Missing or invalid bearer token", body = crate::openapi::ErrorBody),
+    )
+)]
+pub(crate) async fn handle_create_api_token(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Json(req): Json<CreateApiTokenRequest>,
+) -> Result<Response, AppError> {
+    insert_token(&state, user.user_id, &user.username, req.scope, req.name).await
+}
+
+#[utoipa::path(
+    get, path = "/tokens", tag = "auth",
+    summary = "Lists the caller's API tokens",
+    responses((status = 200, description = "The caller's tokens", body = crate::openapi::Data<Vec<TokenInfo>>))
+)]
+pub(crate) async fn handle_list_api_tokens(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+) -> Result<Response, AppError> {
+    let tokens = on_db!(&state.pool, pool => sqlx::query_as::<_, TokenInfo>(
+        r#"
+        SELECT t.id, u.username, t.name, t.scope, t.created_at, t.last_used_at, t.revoked_at
+        FROM api_tokens t
+        JOIN users u ON u.id = t.user_id
+        WHERE t.user_id = $1
+        ORDER BY t.id
+        "#
+    )
+    .bind(user.user_id)
+    .fetch_all(pool)
+    .await)?;
+
+    Ok((StatusCode::OK, Json(serde_json::json!({ "data": tokens }))).into_response())
+}
+
+#[utoipa::path(
+    delete, path = "/tokens/{id}", tag = "auth",
+    summary = "Revokes one of the caller's API tokens",
+    params(("id" = i32, Path)),
+    responses(
+        (status = 204, description = "Revoked"),
+        (status = 404, description = "No such token", body = crate::openapi::ErrorBody),
+    )
+)]
+pub(crate) async fn handle_revoke_api_token(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Path(id): Path<i32>,
+) -> Result<Response, AppError> {
+    revoke_token(&state, id, Some(user.user_id)).await
+}
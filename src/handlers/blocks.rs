@@ -0,0 +1,394 @@
+use std::{collections::HashMap, io};
+
+use axum::{
+    body::{Body, Bytes},
+    extract::{multipart::Field, Extension, Multipart, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use futures::{Stream, StreamExt};
+use sha2::{Digest, Sha256};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::{info, warn};
+
+use crate::{
+    chunking::{Chunk, Chunker},
+    db::{on_db, Array},
+    error::AppError,
+    handlers::{
+        devices::require_device,
+        sync::{put_file, read_payload_field},
+        uploads::resolve_content_type,
+    },
+    models::{AuthUser, BlockManifest, BlockRef, BlockSyncPayload, BlockSyncResult, FileEntry, ManifestBlock},
+    AppState, BLOCK_MASK_BITS, MAX_BLOCK_BYTES, MIN_BLOCK_BYTES,
+};
+
+/// What a `/sync/blocks` builds on: the stored copy of the file and its chunks.
+struct BaseFile {
+    system_path: String,
+    file_hash: Option<String>,
+    file_size: i64,
+    chunks: Vec<Chunk>,
+}
+
+/// How much of a `/sync/blocks` came from the stored copy and how much was sent.
+#[derive(Default)]
+struct BlockCounts {
+    reused: usize,
+    uploaded: usize,
+    bytes_uploaded: i64,
+}
+
+type ChunkSender = mpsc::Sender<Result<Bytes, io::Error>>;
+
+#[utoipa::path(
+    get, path = "/sync/blocks", tag = "sync",
+    summary = "Returns the chunks a file's content was last stored as",
+    params(("file_path" = String, Query)),
+    responses(
+        (status = 200, description = "The file's chunks, in order", body = BlockManifest),
+        (status = 404, description = "No such file, or its content wasn't stored through `/sync/blocks`", body = crate::openapi::ErrorBody),
+    )
+)]
+pub(crate) async fn handle_block_manifest(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Response, AppError> {
+    let file_path = params
+        .get("file_path")
+        .ok_or_else(|| AppError::BadRequest("Missing file_path".into()))?;
+    let base = load_base(&state, user.user_id, file_path)
+        .await?
+        .ok_or_else(|| AppError::NotFound("File not found".into()))?;
+    if base.chunks.is_empty() && base.file_size > 0 {
+        return Err(AppError::NotFound(
+            "The file has no block manifest; store its content through POST /sync/blocks first".into(),
+        ));
+    }
+
+    let mut offset = 0;
+    let chunks = base
+        .chunks
+        .into_iter()
+        .map(|chunk| {
+            let block = ManifestBlock { hash: chunk.sha256, size: chunk.size, offset };
+            offset += chunk.size;
+            block
+        })
+        .collect();
+    Ok((StatusCode::OK, Json(BlockManifest {
+        file_path: file_path.clone(),
+        file_hash: base.file_hash,
+        file_size: base.file_size,
+        chunks,
+        min_chunk_bytes: MIN_BLOCK_BYTES,
+        max_chunk_bytes: MAX_BLOCK_BYTES,
+        mask_bits: BLOCK_MASK_BITS,
+    })).into_response())
+}
+
+#[utoipa::path(
+    post, path = "/sync/blocks", tag = "sync",
+    summary = "Stores a file from the chunks that changed since its last block sync",
+    params(("X-Device-Id" = String, Header, description = "A device registered through `/devices/register`")),
+    request_body(content = crate::openapi::BlockSyncForm, content_type = "multipart/form-data"),
+    responses(
+        (status = 200, description = "File stored, with its new manifest", body = BlockSyncResult),
+        (status = 400, description = "Invalid payload, or a chunk that doesn't match its hash", body = crate::openapi::ErrorBody),
+        (status = 409, description = "The file changed since `base_hash`", body = crate::openapi::ErrorBody),
+        (status = 413, description = "The file is too large, or the quota is exceeded", body = crate::openapi::ErrorBody),
+    )
+)]
+pub(crate) async fn handle_block_sync(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    mut multipart: Multipart,
+) -> Result<Response, AppError> {
+    require_device(&state, &user).await?;
+    let _active = state.metrics.sync_started();
+
+    let field = next_part(&mut multipart)
+        .await?
+        .filter(|field| field.name() == Some("payload"))
+        .ok_or_else(|| AppError::BadRequest("The payload part must come first".into()))?;
+    let text = read_payload_field(field, state.config.max_body_bytes).await?;
+    let payload: BlockSyncPayload = serde_json::from_slice(&text)
+        .map_err(|e| AppError::BadRequest(format!("Invalid payload: {}", e)))?;
+    if payload.file_path.is_empty() {
+        return Err(AppError::BadRequest("file_path must not be empty".into()));
+    }
+
+    let base = load_base(&state, user.user_id, &payload.file_path).await?;
+    if let Some(base_hash) = &payload.base_hash
+        && base.as_ref().and_then(|base| base.file_hash.as_ref()) != Some(base_hash)
+    {
+        return Err(AppError::Conflict(
+            "The file changed on the server since base_hash; fetch its manifest again".into(),
+        ));
+    }
+    let declared_size = match &payload.chunks {
+        Some(chunks) => check_chunks(chunks)?,
+        None if payload.file_size < 0 => {
+            return Err(AppError::BadRequest("file_size must not be negative".into()));
+        }
+        None => payload.file_size,
+    };
+
+    let name = payload.file_path.rsplit('/').next().unwrap_or(&payload.file_path);
+    let content_type = resolve_content_type(None, name);
+    let file = FileEntry {
+        file_name: name.to_string(),
+        file_path: payload.file_path.clone(),
+        file_size: declared_size,
+        modified_time: payload.modified_time,
+        content_type: Some(content_type.clone()),
+        ..FileEntry::default()
+    };
+
+    // The content is assembled as it is stored: chunks go through the channel in
+    // order, read from the stored copy or from the request.
+    let (tx, rx) = mpsc::channel(16);
+    let feed = async {
+        let tx = tx;
+        let fed = match &payload.chunks {
+            Some(chunks) => feed_chunks(&state, base.as_ref(), chunks, &mut multipart, &tx).await,
+            None => feed_content(&mut multipart, &tx).await,
+        };
+        // The upload has to fail too, rather than store what arrived so far.
+        if let Err(e) = &fed {
+            let _ = tx.send(Err(io::Error::other(e.message().to_string()))).await;
+        }
+        fed
+    };
+    let upload = put_file(&state, &user, file, &content_type, Body::from_stream(ReceiverStream::new(rx)));
+    let (fed, stored) = tokio::join!(feed, upload);
+    let (chunks, counts) = fed?;
+    let entry = match stored {
+        Ok(entry) => entry,
+        Err(response) => return Ok(response),
+    };
+
+    if let Err(e) = store_manifest(&state, user.user_id, &entry, &chunks).await {
+        warn!("Failed to store the block manifest of {}: {}", entry.file_path, e);
+    }
+    info!(
+        user_id = user.user_id,
+        "Block sync of {}: {} chunks reused, {} uploaded", entry.file_path, counts.reused, counts.uploaded
+    );
+    Ok((StatusCode::OK, Json(BlockSyncResult {
+        data: entry,
+        chunks_reused: counts.reused,
+        chunks_uploaded: counts.uploaded,
+        bytes_uploaded: counts.bytes_uploaded,
+    })).into_response())
+}
+
+/// Checks a client's chunk list and returns the length of the file it makes up.
+fn check_chunks(chunks: &[BlockRef]) -> Result<i64, AppError> {
+    for (i, chunk) in chunks.iter().enumerate() {
+        let valid_hash = chunk.hash.len() == 64 && chunk.hash.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'));
+        if !valid_hash {
+            return Err(AppError::BadRequest(format!("chunk {}: hash must be a lowercase hex SHA-256", i)));
+        }
+        if chunk.size <= 0 || chunk.size > MAX_BLOCK_BYTES as i64 {
+            return Err(AppError::BadRequest(format!(
+                "chunk {}: size must be between 1 and {} bytes",
+                i, MAX_BLOCK_BYTES
+            )));
+        }
+    }
+    Ok(chunks.iter().map(|chunk| chunk.size).sum())
+}
+
+async fn next_part<'a>(multipart: &'a mut Multipart) -> Result<Option<Field<'a>>, AppError> {
+    multipart
+        .next_field()
+        .await
+        .map_err(|e| AppError::BadRequest(format!("Malformed multipart body: {}", e)))
+}
+
+/// Sends each of `chunks` in turn, from the stored copy when `base` has it and
+/// from the next `chunk` part otherwise, checking every one against its hash.
+async fn feed_chunks(
+    state: &AppState,
+    base: Option<&BaseFile>,
+    chunks: &[BlockRef],
+    multipart: &mut Multipart,
+    tx: &ChunkSender,
+) -> Result<(Vec<Chunk>, BlockCounts), AppError> {
+    let mut stored = HashMap::new();
+    if let Some(base) = base {
+        let mut offset = 0;
+        for chunk in &base.chunks {
+            stored.entry((chunk.sha256.as_str(), chunk.size)).or_insert(offset);
+            offset += chunk.size;
+        }
+    }
+
+    let mut counts = BlockCounts::default();
+    for (i, chunk) in chunks.iter().enumerate() {
+        let mut hasher = Sha256::new();
+        let mut received = 0;
+        let mut inspect = |bytes: &[u8]| {
+            hasher.update(bytes);
+            received += bytes.len() as i64;
+        };
+        let offset = stored.get(&(chunk.hash.as_str(), chunk.size));
+        let sent = match (base, offset) {
+            (Some(base), Some(&offset)) => {
+                let body = state
+                    .storage
+                    .get_range(&base.system_path, offset as u64, chunk.size as u64)
+                    .await
+                    .map_err(|e| AppError::BadGateway(format!("Failed to read chunk {}: {}", i, e)))?;
+                relay(body, tx, &mut inspect)
+                    .await
+                    .map_err(|e| AppError::BadGateway(format!("Failed to read chunk {}: {}", i, e)))?
+            }
+            _ => {
+                let field = next_part(multipart)
+                    .await?
+                    .ok_or_else(|| AppError::BadRequest(format!("Missing the part for chunk {}", i)))?;
+                if field.name() != Some("chunk") {
+                    return Err(AppError::BadRequest(format!("Expected a chunk part for chunk {}", i)));
+                }
+                relay(field, tx, &mut inspect)
+                    .await
+                    .map_err(|e| AppError::BadRequest(format!("Failed to read chunk {}: {}", i, e)))?
+            }
+        };
+        if !sent {
+            // The upload stopped reading; its error is the one reported.
+            return Ok((Vec::new(), counts));
+        }
+
+        if received != chunk.size || hex::encode(hasher.finalize()) != chunk.hash {
+            return Err(match offset {
+                Some(_) => AppError::Conflict(format!(
+                    "chunk {}: the stored copy changed while it was read; fetch the manifest again",
+                    i
+                )),
+                None => AppError::BadRequest(format!("chunk {}: the part doesn't match its hash and size", i)),
+            });
+        }
+        if offset.is_some() {
+            counts.reused += 1;
+        } else {
+            counts.uploaded += 1;
+            counts.bytes_uploaded += chunk.size;
+        }
+    }
+    if next_part(multipart).await?.is_some() {
+        return Err(AppError::BadRequest("Unexpected part after the last chunk".into()));
+    }
+
+    let chunks = chunks.iter().map(|c| Chunk { sha256: c.hash.clone(), size: c.size }).collect();
+    Ok((chunks, counts))
+}
+
+/// Sends the one `content` part, cutting it into chunks on the way.
+async fn feed_content(multipart: &mut Multipart, tx: &ChunkSender) -> Result<(Vec<Chunk>, BlockCounts), AppError> {
+    let field = next_part(multipart)
+        .await?
+        .filter(|field| field.name() == Some("content"))
+        .ok_or_else(|| AppError::BadRequest("Expected a content part, or chunks in the payload".into()))?;
+    let mut chunker = Chunker::default();
+    let mut bytes_uploaded = 0;
+    let sent = relay(field, tx, |bytes| {
+        chunker.update(bytes);
+        bytes_uploaded += bytes.len() as i64;
+    })
+    .await
+    .map_err(|e| AppError::BadRequest(format!("Failed to read content: {}", e)))?;
+
+    let chunks = if sent { chunker.finish() } else { Vec::new() };
+    let counts = BlockCounts { reused: 0, uploaded: chunks.len(), bytes_uploaded };
+    Ok((chunks, counts))
+}
+
+/// Passes `stream` on to the upload, showing each piece to `inspect` first.
+/// Returns `false` if the upload stopped reading before the stream ended.
+async fn relay<S, E>(stream: S, tx: &ChunkSender, mut inspect: impl FnMut(&[u8])) -> Result<bool, String>
+where
+    S: Stream<Item = Result<Bytes, E>>,
+    E: std::fmt::Display,
+{
+    let mut stream = std::pin::pin!(stream);
+    while let Some(bytes) = stream.next().await {
+        let bytes = bytes.map_err(|e| e.to_string())?;
+        inspect(&bytes);
+        if tx.send(Ok(bytes)).await.is_err() {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+async fn load_base(state: &AppState, user_id: i32, file_path: &str) -> Result<Option<BaseFile>, sqlx::Error> {
+    let row = on_db!(&state.pool, pool => sqlx::query_as::<_, (i32, String, Option<String>, i64)>(
+        "SELECT id, system_path, file_hash, file_size FROM filehash WHERE user_id = $1 AND file_path = $2"
+    )
+    .bind(user_id)
+    .bind(file_path)
+    .fetch_optional(pool)
+    .await)?;
+    let Some((id, system_path, file_hash, file_size)) = row else {
+        return Ok(None);
+    };
+
+    let chunks = on_db!(&state.pool, pool => sqlx::query_as::<_, (String, i64)>(
+        "SELECT sha256, size FROM file_chunks WHERE file_id = $1 ORDER BY seq"
+    )
+    .bind(id)
+    .fetch_all(pool)
+    .await)?
+    .into_iter()
+    .map(|(sha256, size)| Chunk { sha256, size })
+    .collect();
+    Ok(Some(BaseFile { system_path, file_hash, file_size, chunks }))
+}
+
+/// Records `chunks` as the manifest of the file `entry` stored, unless another
+/// write has replaced that content in the meantime.
+async fn store_manifest(state: &AppState, user_id: i32, entry: &FileEntry, chunks: &[Chunk]) -> Result<(), sqlx::Error> {
+    let hashes: Vec<String> = chunks.iter().map(|chunk| chunk.sha256.clone()).collect();
+    let sizes: Vec<i64> = chunks.iter().map(|chunk| chunk.size).collect();
+
+    let mut tx = state.pool.begin().await?;
+    on_db!(tx.as_conn(), conn => sqlx::query(
+        "DELETE FROM file_chunks WHERE file_id IN (SELECT id FROM filehash WHERE user_id = $1 AND file_path = $2)"
+    )
+    .bind(user_id)
+    .bind(&entry.file_path)
+    .execute(conn)
+    .await
+    .map(|_| ()))?;
+    let sql = state.pool.sql(
+        r#"
+        INSERT INTO file_chunks (file_id, seq, sha256, size)
+        SELECT f.id, c.seq - 1, c.sha256, c.size
+        FROM filehash f, UNNEST($4::TEXT[], $5::BIGINT[]) WITH ORDINALITY AS c(sha256, size, seq)
+        WHERE f.user_id = $1 AND f.file_path = $2 AND f.file_hash = $3
+        "#,
+        r#"
+        INSERT INTO file_chunks (file_id, seq, sha256, size)
+        SELECT f.id, h.key, h.value, $5 ->> h.key
+        FROM filehash f, json_each($4) h
+        WHERE f.user_id = $1 AND f.file_path = $2 AND f.file_hash = $3
+        "#,
+    );
+    on_db!(tx.as_conn(), conn => sqlx::query(sql)
+        .bind(user_id)
+        .bind(&entry.file_path)
+        .bind(&entry.file_hash)
+        .bind(Array(&hashes))
+        .bind(Array(&sizes))
+        .execute(conn)
+        .await
+        .map(|_| ()))?;
+    tx.commit().await
+}
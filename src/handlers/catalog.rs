@@ -0,0 +1,301 @@
+//! `/export` and `/import`: the metadata catalog of a deployment, meaning its
+//! users, files with their tags and metadata, and share links, in a form another
+//! deployment reading the same bucket can take in. File contents never pass
+//! through; each imported file is checked against the object it names instead.
+
+use std::collections::HashMap;
+
+use axum::{
+    body::Bytes,
+    extract::{Extension, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use futures::StreamExt;
+use tracing::info;
+
+use crate::{
+    db::{annotate_file, on_db},
+    error::AppError,
+    handlers::listing::tags_sql,
+    models::{
+        AuthUser, Catalog, CatalogFile, CatalogRecord, CatalogShare, CatalogUser, ExportParams, FileEntry, ImportReport,
+        ImportSkip, CATALOG_VERSION,
+    },
+    AppState,
+};
+
+const NDJSON: &str = "application/x-ndjson";
+
+/// Refuses catalogs where stored objects depend on state they don't carry:
+/// deduplicated blobs, compressed objects and per-file encryption keys.
+fn ensure_portable(state: &AppState) -> Result<(), AppError> {
+    let config = &state.config;
+    if config.dedup || config.compression.enabled || config.encryption_master_key.is_some() {
+        return Err(AppError::BadRequest(
+            "Catalogs can't be moved while deduplication, compression or encryption is on".into(),
+        ));
+    }
+    Ok(())
+}
+
+/// Every user, live file and active share link, as one JSON document or, with
+/// `format=ndjson`, one record a line.
+pub(crate) async fn handle_export(
+    State(state): State<AppState>,
+    Query(params): Query<ExportParams>,
+) -> Result<Response, AppError> {
+    ensure_portable(&state)?;
+    let ndjson = match params.format.as_deref() {
+        None | Some("json") => false,
+        Some("ndjson") => true,
+        Some(other) => return Err(AppError::BadRequest(format!("Unknown format {}", other))),
+    };
+
+    let users = on_db!(&state.pool, pool => sqlx::query_as::<_, CatalogUser>(
+        "SELECT username, role, quota_bytes, password_hash FROM users ORDER BY id"
+    )
+    .fetch_all(pool)
+    .await)?;
+    let sql = format!(
+        r#"
+        SELECT u.username, filehash.file_path, filehash.file_name, filehash.file_hash, filehash.file_size,
+               filehash.modified_time, filehash.content_type, filehash.etag, filehash.system_path,
+               filehash.created_at, filehash.metadata, {} AS tags
+        FROM filehash
+        JOIN users u ON u.id = filehash.user_id
+        ORDER BY u.username, filehash.file_path
+        "#,
+        tags_sql(&state.pool),
+    );
+    let files = on_db!(&state.pool, pool => sqlx::query_as::<_, CatalogFile>(&sql).fetch_all(pool).await)?;
+    let shares = on_db!(&state.pool, pool => sqlx::query_as::<_, CatalogShare>(
+        r#"
+        SELECT u.username, s.file_path, s.folder, s.token_hash, s.password_hash, s.expires_at, s.created_at
+        FROM shares s
+        JOIN users u ON u.id = s.user_id
+        WHERE s.revoked_at IS NULL
+          AND (s.expires_at IS NULL OR s.expires_at > CURRENT_TIMESTAMP)
+        ORDER BY s.id
+        "#
+    )
+    .fetch_all(pool)
+    .await)?;
+
+    info!("CATALOG EXPORTED: {} users, {} files, {} shares", users.len(), files.len(), shares.len());
+    let exported_at = Some(chrono::Utc::now().naive_utc());
+    if !ndjson {
+        let catalog = Catalog { version: CATALOG_VERSION, exported_at, users, files, shares };
+        return Ok((StatusCode::OK, Json(catalog)).into_response());
+    }
+
+    let records = std::iter::once(CatalogRecord::Header { version: CATALOG_VERSION, exported_at })
+        .chain(users.into_iter().map(CatalogRecord::User))
+        .chain(files.into_iter().map(CatalogRecord::File))
+        .chain(shares.into_iter().map(CatalogRecord::Share));
+    let mut body = Vec::new();
+    for record in records {
+        serde_json::to_writer(&mut body, &record).map_err(|e| AppError::Internal(e.to_string()))?;
+        body.push(b'\n');
+    }
+    Ok((StatusCode::OK, [(header::CONTENT_TYPE, NDJSON)], body).into_response())
+}
+
+fn parse_catalog(headers: &HeaderMap, body: &[u8]) -> Result<Catalog, AppError> {
+    let is_ndjson = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with(NDJSON));
+    let invalid = |e: serde_json::Error| AppError::BadRequest(format!("Invalid catalog: {}", e));
+
+    let catalog = if is_ndjson {
+        let mut lines = body.split(|b| *b == b'\n').filter(|line| !line.trim_ascii().is_empty());
+        let mut catalog = match lines.next().map(serde_json::from_slice::<CatalogRecord>).transpose().map_err(invalid)? {
+            Some(CatalogRecord::Header { version, exported_at }) => {
+                Catalog { version, exported_at, users: Vec::new(), files: Vec::new(), shares: Vec::new() }
+            }
+            _ => return Err(AppError::BadRequest("Invalid catalog: it must start with a header".into())),
+        };
+        for line in lines {
+            match serde_json::from_slice(line).map_err(invalid)? {
+                CatalogRecord::Header { .. } => {
+                    return Err(AppError::BadRequest("Invalid catalog: it has more than one header".into()));
+                }
+                CatalogRecord::User(user) => catalog.users.push(user),
+                CatalogRecord::File(file) => catalog.files.push(file),
+                CatalogRecord::Share(share) => catalog.shares.push(share),
+            }
+        }
+        catalog
+    } else {
+        serde_json::from_slice(body).map_err(invalid)?
+    };
+
+    if catalog.version != CATALOG_VERSION {
+        return Err(AppError::BadRequest(format!("Unsupported catalog version {}", catalog.version)));
+    }
+    Ok(catalog)
+}
+
+/// Takes in a catalog `/export` wrote, sent as JSON or NDJSON. Users are matched
+/// by name and created when missing. A file is only imported when the object it
+/// names is in storage at the size recorded, and never over a file already at its
+/// path; the rest are reported as skipped.
+pub(crate) async fn handle_import(
+    State(state): State<AppState>,
+    Extension(admin): Extension<AuthUser>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Response, AppError> {
+    ensure_portable(&state)?;
+    let catalog = parse_catalog(&headers, &body)?;
+    let mut report = ImportReport::default();
+
+    for user in &catalog.users {
+        let created = on_db!(&state.pool, pool => sqlx::query(
+            r#"
+            INSERT INTO users (username, role, quota_bytes, password_hash) VALUES ($1, $2, $3, $4)
+            ON CONFLICT (username) DO NOTHING
+            "#
+        )
+        .bind(&user.username)
+        .bind(user.role)
+        .bind(user.quota_bytes)
+        .bind(&user.password_hash)
+        .execute(pool)
+        .await
+        .map(|r| r.rows_affected()))?;
+        if created > 0 {
+            report.users_created += 1;
+        } else {
+            report.users_existing += 1;
+        }
+    }
+    let user_ids: HashMap<String, i32> = on_db!(&state.pool, pool => sqlx::query_as::<_, (String, i32)>(
+        "SELECT username, id FROM users"
+    )
+    .fetch_all(pool)
+    .await)?
+    .into_iter()
+    .collect();
+
+    // Checking the objects is the slow part, so it runs concurrently.
+    let mut checked = futures::stream::iter(catalog.files)
+        .map(|file| async {
+            let size = state.storage.size(&file.system_path).await;
+            (file, size)
+        })
+        .buffer_unordered(state.sync_concurrency);
+    while let Some((file, size)) = checked.next().await {
+        let skip = |reason: String| ImportSkip {
+            username: file.username.clone(),
+            file_path: file.file_path.clone(),
+            reason,
+        };
+        let Some(&user_id) = user_ids.get(&file.username) else {
+            report.files_skipped.push(skip("Unknown user".into()));
+            continue;
+        };
+        match size {
+            Ok(size) if size == file.file_size => {}
+            Ok(size) => {
+                report.files_skipped.push(skip(format!("Stored object is {} bytes, not {}", size, file.file_size)));
+                continue;
+            }
+            Err(e) => {
+                report.files_skipped.push(skip(format!("Stored object not found: {}", e)));
+                continue;
+            }
+        }
+
+        match import_file(&state, user_id, &file).await? {
+            true => report.files_imported += 1,
+            false => report.files_skipped.push(skip("A file is already at this path or in this object".into())),
+        }
+    }
+
+    for share in &catalog.shares {
+        let skip = |reason: &str| ImportSkip {
+            username: share.username.clone(),
+            file_path: share.file_path.clone(),
+            reason: reason.into(),
+        };
+        let Some(&user_id) = user_ids.get(&share.username) else {
+            report.shares_skipped.push(skip("Unknown user"));
+            continue;
+        };
+        let imported = on_db!(&state.pool, pool => sqlx::query(
+            r#"
+            INSERT INTO shares (user_id, file_path, folder, token_hash, password_hash, expires_at, created_at)
+            VALUES ($1, $2, $3, $4, $5, $6, COALESCE($7, CURRENT_TIMESTAMP))
+            ON CONFLICT (token_hash) DO NOTHING
+            "#
+        )
+        .bind(user_id)
+        .bind(&share.file_path)
+        .bind(share.folder)
+        .bind(&share.token_hash)
+        .bind(&share.password_hash)
+        .bind(share.expires_at)
+        .bind(share.created_at)
+        .execute(pool)
+        .await
+        .map(|r| r.rows_affected()))?;
+        if imported > 0 {
+            report.shares_imported += 1;
+        } else {
+            report.shares_skipped.push(skip("Share token already in use"));
+        }
+    }
+
+    info!(
+        user_id = admin.user_id,
+        "CATALOG IMPORTED: {} users created, {} files imported, {} skipped, {} shares imported",
+        report.users_created,
+        report.files_imported,
+        report.files_skipped.len(),
+        report.shares_imported,
+    );
+    Ok((StatusCode::OK, Json(serde_json::json!({ "data": report }))).into_response())
+}
+
+/// Inserts the row of `file` with its tags and metadata, returning `false` when
+/// its path or object is already taken.
+async fn import_file(state: &AppState, user_id: i32, file: &CatalogFile) -> Result<bool, AppError> {
+    let mut tx = state.pool.begin().await?;
+    let inserted = on_db!(tx.as_conn(), conn => sqlx::query(
+        r#"
+        INSERT INTO filehash (user_id, file_path, file_name, file_hash, file_size, modified_time,
+                              content_type, etag, system_path, created_at, version)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, COALESCE($10, CURRENT_TIMESTAMP),
+                (SELECT COALESCE(MAX(version), 0) + 1 FROM path_versions WHERE user_id = $1 AND file_path = $2))
+        ON CONFLICT DO NOTHING
+        "#
+    )
+    .bind(user_id)
+    .bind(&file.file_path)
+    .bind(&file.file_name)
+    .bind(&file.file_hash)
+    .bind(file.file_size)
+    .bind(file.modified_time)
+    .bind(&file.content_type)
+    .bind(&file.etag)
+    .bind(&file.system_path)
+    .bind(file.created_at)
+    .execute(conn)
+    .await
+    .map(|r| r.rows_affected()))?;
+    if inserted == 0 {
+        return Ok(false);
+    }
+
+    let annotations = FileEntry {
+        tags: (!file.tags.is_empty()).then(|| file.tags.clone()),
+        metadata: file.metadata.clone(),
+        ..Default::default()
+    };
+    annotate_file(tx.as_conn(), user_id, &file.file_path, &annotations).await?;
+    tx.commit().await?;
+    Ok(true)
+}
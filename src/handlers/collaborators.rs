@@ -0,0 +1,226 @@
+//! Folders shared between registered users. An owner grants a collaborator `read`
+//! or `write` access to one of their folders, and the collaborator reaches it by
+//! passing `owner=<username>` to `/get`, `/download` and `/sync`. Requests that do
+//! act on the owner's tree, under the owner's quota, as if the owner made them;
+//! the audit log still names the collaborator. Links for people without an
+//! account are `shares` instead.
+
+use axum::{
+    extract::{Extension, Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use tracing::info;
+
+use crate::{
+    audit,
+    db::on_db,
+    error::AppError,
+    handlers::{files::trim_slashes, profiles::PathFilter},
+    models::{AuthUser, FolderGrant, FolderPermission, GrantFolderRequest},
+    AppState,
+};
+
+/// Shares one of the caller's folders with another user, or changes the
+/// permission of an existing grant.
+#[utoipa::path(
+    post, path = "/collaborators", tag = "files",
+    request_body = GrantFolderRequest,
+    responses(
+        (status = 201, description = "The grant", body = FolderGrant),
+        (status = 400, description = "Invalid request", body = crate::openapi::ErrorBody),
+        (status = 404, description = "No such user", body = crate::openapi::ErrorBody),
+    )
+)]
+pub(crate) async fn handle_grant_folder(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Json(req): Json<GrantFolderRequest>,
+) -> Result<Response, AppError> {
+    let folder = trim_slashes(&req.folder);
+    if folder.is_empty() {
+        return Err(AppError::BadRequest("A folder is required; the whole tree can't be shared".into()));
+    }
+
+    let grantee = on_db!(&state.pool, pool => sqlx::query_scalar::<_, i32>("SELECT id FROM users WHERE username = $1")
+        .bind(&req.username)
+        .fetch_optional(pool)
+        .await)?
+        .ok_or_else(|| AppError::NotFound("User not found".into()))?;
+    if grantee == user.user_id {
+        return Err(AppError::BadRequest("Folders can't be shared with their owner".into()));
+    }
+
+    let grant = on_db!(&state.pool, pool => sqlx::query_as::<_, FolderGrant>(
+        r#"
+        INSERT INTO folder_acl (owner_id, folder, grantee_id, permission)
+        VALUES ($1, $2, $3, $4)
+        ON CONFLICT (owner_id, folder, grantee_id) DO UPDATE SET permission = excluded.permission
+        RETURNING id, folder, CAST($5 AS TEXT) AS username, permission, created_at
+        "#
+    )
+    .bind(user.user_id)
+    .bind(folder)
+    .bind(grantee)
+    .bind(req.permission)
+    .bind(&req.username)
+    .fetch_one(pool)
+    .await)?;
+
+    info!(user_id = user.user_id, grantee, "SHARED FOLDER {}", folder);
+    audit::record_share(&state, &user, folder.to_string());
+    Ok((StatusCode::CREATED, Json(grant)).into_response())
+}
+
+/// Lists who the caller shares folders with.
+#[utoipa::path(
+    get, path = "/collaborators", tag = "files",
+    responses((status = 200, description = "The caller's grants", body = Vec<FolderGrant>))
+)]
+pub(crate) async fn handle_list_collaborators(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+) -> Result<Response, AppError> {
+    let grants = on_db!(&state.pool, pool => sqlx::query_as::<_, FolderGrant>(
+        r#"
+        SELECT a.id, a.folder, u.username, a.permission, a.created_at
+        FROM folder_acl a
+        JOIN users u ON u.id = a.grantee_id
+        WHERE a.owner_id = $1
+        ORDER BY a.folder, u.username
+        "#
+    )
+    .bind(user.user_id)
+    .fetch_all(pool)
+    .await)?;
+
+    Ok((StatusCode::OK, Json(serde_json::json!({ "data": grants }))).into_response())
+}
+
+/// Stops sharing a folder with a collaborator.
+#[utoipa::path(
+    delete, path = "/collaborators/{id}", tag = "files",
+    params(("id" = i32, Path)),
+    responses(
+        (status = 204, description = "Revoked"),
+        (status = 404, description = "No such grant", body = crate::openapi::ErrorBody),
+    )
+)]
+pub(crate) async fn handle_revoke_collaborator(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Path(id): Path<i32>,
+) -> Result<Response, AppError> {
+    let revoked = on_db!(&state.pool, pool => sqlx::query("DELETE FROM folder_acl WHERE id = $1 AND owner_id = $2")
+        .bind(id)
+        .bind(user.user_id)
+        .execute(pool)
+        .await
+        .map(|r| r.rows_affected()))?;
+    if revoked == 0 {
+        return Err(AppError::NotFound("Grant not found".into()));
+    }
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+/// Lists the folders other users share with the caller.
+#[utoipa::path(
+    get, path = "/shared", tag = "files",
+    responses((status = 200, description = "Folders shared with the caller", body = Vec<FolderGrant>))
+)]
+pub(crate) async fn handle_shared_with_me(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+) -> Result<Response, AppError> {
+    Ok((StatusCode::OK, Json(serde_json::json!({ "data": shared_folders(&state, user.user_id).await? }))).into_response())
+}
+
+/// The folders shared with `grantee`, each with its owner as `username`.
+pub(crate) async fn shared_folders(state: &AppState, grantee: i32) -> Result<Vec<FolderGrant>, sqlx::Error> {
+    on_db!(&state.pool, pool => sqlx::query_as::<_, FolderGrant>(
+        r#"
+        SELECT a.id, a.folder, u.username, a.permission, a.created_at
+        FROM folder_acl a
+        JOIN users u ON u.id = a.owner_id
+        WHERE a.grantee_id = $1
+        ORDER BY u.username, a.folder
+        "#
+    )
+    .bind(grantee)
+    .fetch_all(pool)
+    .await)
+}
+
+/// The id of `owner` and the folders they share with `user`, failing with 404
+/// when they share none, so a collaborator can't probe for usernames.
+async fn grants_from(state: &AppState, user: &AuthUser, owner: &str) -> Result<(i32, Vec<(String, FolderPermission)>), AppError> {
+    let rows = on_db!(&state.pool, pool => sqlx::query_as::<_, (i32, String, FolderPermission)>(
+        r#"
+        SELECT a.owner_id, a.folder, a.permission
+        FROM folder_acl a
+        JOIN users u ON u.id = a.owner_id
+        WHERE u.username = $1 AND a.grantee_id = $2
+        "#
+    )
+    .bind(owner)
+    .bind(user.user_id)
+    .fetch_all(pool)
+    .await)?;
+
+    let owner_id = rows
+        .first()
+        .map(|(owner_id, _, _)| *owner_id)
+        .ok_or_else(|| AppError::NotFound(format!("{} shares no folders with you", owner)))?;
+    Ok((owner_id, rows.into_iter().map(|(_, folder, permission)| (folder, permission)).collect()))
+}
+
+/// `user`, acting on `owner`'s files, once every one of `paths` is in a folder
+/// `owner` shares with them with at least `needed`. The audit log still names
+/// `user`, and their device still tells their changes apart.
+pub(crate) async fn act_for_owner(
+    state: &AppState,
+    user: &AuthUser,
+    owner: &str,
+    paths: &[&str],
+    needed: FolderPermission,
+) -> Result<AuthUser, AppError> {
+    let (owner_id, grants) = grants_from(state, user, owner).await?;
+    for path in paths {
+        let allowed = grants
+            .iter()
+            .any(|(folder, permission)| *permission >= needed && in_folder(folder, path));
+        if !allowed {
+            return Err(AppError::Forbidden(match needed {
+                FolderPermission::Read => format!("{} is not in a folder {} shares with you", path, owner),
+                FolderPermission::Write => format!("{} is not in a folder {} lets you change", path, owner),
+            }));
+        }
+    }
+    Ok(AuthUser { user_id: owner_id, ..user.clone() })
+}
+
+/// `owner`'s id and a filter selecting the paths of every folder they share with
+/// `user`, for listing them.
+pub(crate) async fn shared_filter(state: &AppState, user: &AuthUser, owner: &str) -> Result<(i32, PathFilter), AppError> {
+    let (owner_id, grants) = grants_from(state, user, owner).await?;
+    let include = grants.iter().map(|(folder, _)| folder_regex(folder)).collect();
+    Ok((owner_id, PathFilter { include, exclude: Vec::new() }))
+}
+
+fn in_folder(folder: &str, path: &str) -> bool {
+    path.strip_prefix(folder).is_some_and(|rest| rest.starts_with('/'))
+}
+
+/// A regex matching the paths below `folder`.
+fn folder_regex(folder: &str) -> String {
+    let mut regex = String::from("^");
+    for c in folder.chars() {
+        if "\\.+*?()|[]{}^$".contains(c) {
+            regex.push('\\');
+        }
+        regex.push(c);
+    }
+    regex.push('/');
+    regex
+}
@@ -0,0 +1,458 @@
+//! A WebDAV (class 1) view of each user's file tree, mounted at `DAV_PREFIX`, so OS
+//! file managers and tools like rclone can use the drive without a custom client.
+//! Folders are implied by file paths as everywhere else; `MKCOL` records empty ones
+//! in `folders` so they can be listed before anything is put in them.
+
+use axum::{
+    body::Body,
+    extract::{Extension, State},
+    http::{header, HeaderMap, Method, StatusCode, Uri},
+    response::{IntoResponse, Response},
+};
+use tracing::{info, warn};
+
+use crate::{
+    audit,
+    db::{on_db, Array},
+    error::AppError,
+    events::publish_changes,
+    handlers::{
+        downloads::{entity_tag, serve_file},
+        files::{delete_file, move_files, trim_slashes},
+        listing::{escape_like, load_dir},
+        maintenance::{active_maintenance, maintenance_response},
+        sync::put_file,
+        uploads::resolve_content_type,
+    },
+    models::{AuthUser, FileChange, FileEntry, Operation},
+    AppState, DAV_PREFIX,
+};
+
+const ALLOWED_METHODS: &str = "OPTIONS, PROPFIND, GET, HEAD, PUT, DELETE, MKCOL, MOVE";
+
+/// What a WebDAV path names.
+enum Resource {
+    File(Box<FileEntry>),
+    Folder,
+}
+
+/// Answers every WebDAV method on `DAV_PREFIX` and the paths under it.
+pub(crate) async fn handle_dav(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    method: Method,
+    uri: Uri,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<Response, AppError> {
+    let path = dav_path(uri.path())?;
+    if matches!(method.as_str(), "PUT" | "DELETE" | "MKCOL" | "MOVE")
+        && let Some(maintenance) = active_maintenance(&state).await
+    {
+        return Ok(maintenance_response(&maintenance));
+    }
+    match method.as_str() {
+        "OPTIONS" => Ok((
+            StatusCode::OK,
+            [("DAV", "1"), ("MS-Author-Via", "DAV"), (header::ALLOW.as_str(), ALLOWED_METHODS)],
+        ).into_response()),
+        "PROPFIND" => propfind(&state, &user, &path, &headers).await,
+        "GET" => match resolve(&state, user.user_id, &path).await? {
+            Some(Resource::File(file)) => {
+                let file_path = file.file_path.clone();
+                let response = serve_file(&state, *file, &headers).await?;
+                audit::record_downloads(&state, &user, vec![file_path]);
+                Ok(response)
+            }
+            Some(Resource::Folder) => Ok(method_not_allowed()),
+            None => Err(AppError::NotFound("File not found".into())),
+        },
+        "HEAD" => match resolve(&state, user.user_id, &path).await? {
+            Some(Resource::File(file)) => Ok(head(&file)),
+            Some(Resource::Folder) => Ok(method_not_allowed()),
+            None => Ok(StatusCode::NOT_FOUND.into_response()),
+        },
+        "PUT" => put(&state, &user, &path, &headers, body).await,
+        "DELETE" => {
+            match resolve(&state, user.user_id, &path).await? {
+                None => return Err(AppError::NotFound("No file or folder at this path".into())),
+                Some(Resource::Folder) if path.is_empty() => {
+                    return Err(AppError::Forbidden("The root folder can't be deleted".into()))
+                }
+                Some(resource) => remove(&state, &user, &path, resource).await?,
+            }
+            Ok(StatusCode::NO_CONTENT.into_response())
+        }
+        "MKCOL" => mkcol(&state, &user, &path, &headers).await,
+        "MOVE" => move_resource(&state, &user, &path, &headers).await,
+        _ => Ok(method_not_allowed()),
+    }
+}
+
+fn method_not_allowed() -> Response {
+    (StatusCode::METHOD_NOT_ALLOWED, [(header::ALLOW, ALLOWED_METHODS)]).into_response()
+}
+
+/// The file path a request path under `DAV_PREFIX` names; empty for the root.
+fn dav_path(uri_path: &str) -> Result<String, AppError> {
+    let raw = uri_path.strip_prefix(DAV_PREFIX).unwrap_or(uri_path);
+    let decoded = urlencoding::decode(raw).map_err(|_| AppError::BadRequest("Path is not valid UTF-8".into()))?;
+    let path = trim_slashes(&decoded);
+    if !path.is_empty() && path.split('/').any(|segment| matches!(segment, "" | "." | "..")) {
+        return Err(AppError::BadRequest("Path has an empty, `.` or `..` segment".into()));
+    }
+    Ok(path.to_string())
+}
+
+/// The request path of `path`, with each segment percent-encoded. Folders end in `/`.
+fn href(path: &str, folder: bool) -> String {
+    let mut href = format!("{}/", DAV_PREFIX);
+    if !path.is_empty() {
+        let segments: Vec<_> = path.split('/').map(urlencoding::encode).collect();
+        href.push_str(&segments.join("/"));
+        if folder {
+            href.push('/');
+        }
+    }
+    href
+}
+
+/// Whether `path` is a file, a folder (one holding files, or made by `MKCOL`), or nothing.
+async fn resolve(state: &AppState, user_id: i32, path: &str) -> Result<Option<Resource>, sqlx::Error> {
+    if path.is_empty() {
+        return Ok(Some(Resource::Folder));
+    }
+
+    let file = on_db!(&state.pool, pool => sqlx::query_as::<_, FileEntry>(
+        "SELECT file_path, file_hash, file_size, modified_time, system_path AS file_name, content_type, etag,
+                created_at, updated_at
+         FROM filehash
+         WHERE user_id = $1 AND file_path = $2"
+    )
+    .bind(user_id)
+    .bind(path)
+    .fetch_optional(pool)
+    .await)?;
+    if let Some(file) = file {
+        return Ok(Some(Resource::File(Box::new(file))));
+    }
+
+    let folder = on_db!(&state.pool, pool => sqlx::query_scalar::<_, bool>(
+        "SELECT EXISTS (SELECT 1 FROM filehash WHERE user_id = $1 AND file_path LIKE $3 ESCAPE '\\')
+             OR EXISTS (SELECT 1 FROM folders WHERE user_id = $1 AND (path = $2 OR path LIKE $3 ESCAPE '\\'))"
+    )
+    .bind(user_id)
+    .bind(path)
+    .bind(format!("{}/%", escape_like(path)))
+    .fetch_one(pool)
+    .await)?;
+    Ok(folder.then_some(Resource::Folder))
+}
+
+/// Whether a file sits where one of `path`'s parent folders would have to be.
+async fn parent_is_file(state: &AppState, user_id: i32, path: &str) -> Result<bool, sqlx::Error> {
+    let parents: Vec<String> = path
+        .match_indices('/')
+        .map(|(at, _)| path[..at].to_string())
+        .collect();
+    if parents.is_empty() {
+        return Ok(false);
+    }
+    let sql = state.pool.sql(
+        "SELECT EXISTS (SELECT 1 FROM filehash WHERE user_id = $1 AND file_path = ANY($2))",
+        "SELECT EXISTS (SELECT 1 FROM filehash WHERE user_id = $1 AND file_path IN (SELECT value FROM json_each($2)))",
+    );
+    on_db!(&state.pool, pool => sqlx::query_scalar::<_, bool>(sql)
+        .bind(user_id)
+        .bind(Array(&parents))
+        .fetch_one(pool)
+        .await)
+}
+
+/// Lists the properties of a file, or of a folder and (at `Depth: 1`) its children.
+/// Every live property is always returned, whichever the request body asks for.
+async fn propfind(state: &AppState, user: &AuthUser, path: &str, headers: &HeaderMap) -> Result<Response, AppError> {
+    let depth = headers.get("Depth").and_then(|v| v.to_str().ok()).unwrap_or("infinity");
+    let children = match depth {
+        "0" => false,
+        "1" => true,
+        _ => return Ok((
+            StatusCode::FORBIDDEN,
+            [(header::CONTENT_TYPE, "application/xml; charset=utf-8")],
+            "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<D:error xmlns:D=\"DAV:\"><D:propfind-finite-depth/></D:error>\n",
+        ).into_response()),
+    };
+
+    let resource = resolve(state, user.user_id, path)
+        .await?
+        .ok_or_else(|| AppError::NotFound("No file or folder at this path".into()))?;
+
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<D:multistatus xmlns:D=\"DAV:\">\n");
+    match resource {
+        Resource::File(file) => push_file(&mut xml, &file),
+        Resource::Folder => {
+            push_folder(&mut xml, path, None);
+            if children {
+                let listing = load_dir(state, user.user_id, path).await?;
+                for folder in &listing.folders {
+                    push_folder(&mut xml, &folder.path, folder.modified_time);
+                }
+                for file in &listing.files {
+                    push_file(&mut xml, file);
+                }
+            }
+        }
+    }
+    xml.push_str("</D:multistatus>\n");
+
+    Ok((
+        StatusCode::MULTI_STATUS,
+        [(header::CONTENT_TYPE, "application/xml; charset=utf-8")],
+        xml,
+    ).into_response())
+}
+
+fn push_file(xml: &mut String, file: &FileEntry) {
+    let name = file.file_path.rsplit('/').next().unwrap_or(&file.file_path);
+    let content_type = file
+        .content_type
+        .clone()
+        .unwrap_or_else(|| resolve_content_type(None, name));
+    let mut props = format!(
+        "<D:displayname>{}</D:displayname><D:resourcetype/><D:getcontentlength>{}</D:getcontentlength>\
+         <D:getcontenttype>{}</D:getcontenttype>",
+        escape_xml(name),
+        file.file_size,
+        escape_xml(&content_type),
+    );
+    if let Some(modified) = http_date(file.modified_time) {
+        props.push_str(&format!("<D:getlastmodified>{}</D:getlastmodified>", modified));
+    }
+    if let Some(tag) = entity_tag(file) {
+        props.push_str(&format!("<D:getetag>{}</D:getetag>", escape_xml(&tag)));
+    }
+    if let Some(created) = file.created_at {
+        props.push_str(&format!("<D:creationdate>{}</D:creationdate>", created.format("%Y-%m-%dT%H:%M:%SZ")));
+    }
+    push_response(xml, &href(&file.file_path, false), &props);
+}
+
+fn push_folder(xml: &mut String, path: &str, modified_time: Option<i64>) {
+    let name = path.rsplit('/').next().unwrap_or(path);
+    let mut props = format!(
+        "<D:displayname>{}</D:displayname><D:resourcetype><D:collection/></D:resourcetype>",
+        escape_xml(name),
+    );
+    if let Some(modified) = modified_time.and_then(http_date) {
+        props.push_str(&format!("<D:getlastmodified>{}</D:getlastmodified>", modified));
+    }
+    push_response(xml, &href(path, true), &props);
+}
+
+fn push_response(xml: &mut String, href: &str, props: &str) {
+    xml.push_str(&format!(
+        "<D:response><D:href>{}</D:href><D:propstat><D:prop>{}</D:prop>\
+         <D:status>HTTP/1.1 200 OK</D:status></D:propstat></D:response>\n",
+        escape_xml(href),
+        props,
+    ));
+}
+
+pub(crate) fn escape_xml(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// A Unix timestamp as an HTTP date, e.g. `Tue, 13 Oct 2026 09:30:00 GMT`.
+fn http_date(secs: i64) -> Option<String> {
+    chrono::DateTime::from_timestamp(secs, 0).map(|time| time.format("%a, %d %b %Y %H:%M:%S GMT").to_string())
+}
+
+fn head(file: &FileEntry) -> Response {
+    let name = file.file_path.rsplit('/').next().unwrap_or(&file.file_path);
+    let content_type = file
+        .content_type
+        .clone()
+        .unwrap_or_else(|| resolve_content_type(None, name));
+    let mut response = (
+        StatusCode::OK,
+        [
+            (header::CONTENT_TYPE, content_type),
+            (header::CONTENT_LENGTH, file.file_size.to_string()),
+            (header::ACCEPT_RANGES, "bytes".to_string()),
+        ],
+    ).into_response();
+    if let Some(tag) = entity_tag(file).and_then(|t| t.parse().ok()) {
+        response.headers_mut().insert(header::ETAG, tag);
+    }
+    if let Some(modified) = http_date(file.modified_time).and_then(|t| t.parse().ok()) {
+        response.headers_mut().insert(header::LAST_MODIFIED, modified);
+    }
+    response
+}
+
+/// Stores the body as the file at `path`, replacing any earlier content the way a
+/// `/sync` update would.
+async fn put(
+    state: &AppState,
+    user: &AuthUser,
+    path: &str,
+    headers: &HeaderMap,
+    body: Body,
+) -> Result<Response, AppError> {
+    let existing = match resolve(state, user.user_id, path).await? {
+        Some(Resource::Folder) => return Ok(method_not_allowed()),
+        existing => existing,
+    };
+    if parent_is_file(state, user.user_id, path).await? {
+        return Err(AppError::Conflict("A parent of this path is a file".into()));
+    }
+
+    let name = path.rsplit('/').next().unwrap_or(path);
+    let content_type = resolve_content_type(
+        headers.get(header::CONTENT_TYPE).and_then(|v| v.to_str().ok()),
+        name,
+    );
+    // The size is only used for the quota check up front; the stored row gets the
+    // number of bytes actually received.
+    let declared_size = headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(0);
+    let file = FileEntry {
+        file_name: name.to_string(),
+        file_path: path.to_string(),
+        file_size: declared_size,
+        modified_time: chrono::Utc::now().timestamp(),
+        content_type: Some(content_type.clone()),
+        ..FileEntry::default()
+    };
+
+    let stored = match put_file(state, user, file, &content_type, body).await {
+        Ok(stored) => stored,
+        Err(response) => return Ok(response),
+    };
+    info!(user_id = user.user_id, "DAV PUT {}", path);
+
+    let status = if existing.is_some() { StatusCode::NO_CONTENT } else { StatusCode::CREATED };
+    let mut response = status.into_response();
+    if let Some(tag) = entity_tag(&stored).and_then(|t| t.parse().ok()) {
+        response.headers_mut().insert(header::ETAG, tag);
+    }
+    Ok(response)
+}
+
+/// Moves the file, or every file under the folder, at `path` to the trash, and
+/// forgets the folder.
+async fn remove(state: &AppState, user: &AuthUser, path: &str, resource: Resource) -> Result<(), AppError> {
+    let file_paths = match resource {
+        Resource::File(file) => vec![file.file_path],
+        Resource::Folder => {
+            on_db!(&state.pool, pool => sqlx::query_scalar::<_, String>(
+                "SELECT file_path FROM filehash WHERE user_id = $1 AND file_path LIKE $2 ESCAPE '\\'"
+            )
+            .bind(user.user_id)
+            .bind(format!("{}/%", escape_like(path)))
+            .fetch_all(pool)
+            .await)?
+        }
+    };
+
+    let total = file_paths.len();
+    let mut deleted = Vec::new();
+    for file_path in file_paths {
+        match delete_file(state, user.user_id, &file_path).await {
+            Ok(()) => deleted.push(FileChange { operation: Operation::Delete, file_path }),
+            Err(e) => warn!("DAV DELETE of {} failed: {}", file_path, e),
+        }
+    }
+    let failed = total - deleted.len();
+    publish_changes(state, user, deleted);
+    if failed > 0 {
+        return Err(AppError::BadGateway(format!("Failed to delete {} of {} files", failed, total)));
+    }
+
+    on_db!(&state.pool, pool => sqlx::query("DELETE FROM folders WHERE user_id = $1 AND (path = $2 OR path LIKE $3 ESCAPE '\\')")
+        .bind(user.user_id)
+        .bind(path)
+        .bind(format!("{}/%", escape_like(path)))
+        .execute(pool)
+        .await
+        .map(|_| ()))?;
+    info!(user_id = user.user_id, "DAV DELETE {}", path);
+    Ok(())
+}
+
+/// Creates an empty folder. Missing parent folders are implied, like everywhere else.
+async fn mkcol(state: &AppState, user: &AuthUser, path: &str, headers: &HeaderMap) -> Result<Response, AppError> {
+    let has_body = headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v != "0");
+    if has_body {
+        return Ok(StatusCode::UNSUPPORTED_MEDIA_TYPE.into_response());
+    }
+    if path.is_empty() || resolve(state, user.user_id, path).await?.is_some() {
+        return Ok(method_not_allowed());
+    }
+    if parent_is_file(state, user.user_id, path).await? {
+        return Err(AppError::Conflict("A parent of this path is a file".into()));
+    }
+
+    on_db!(&state.pool, pool => sqlx::query("INSERT INTO folders (user_id, path) VALUES ($1, $2) ON CONFLICT DO NOTHING")
+        .bind(user.user_id)
+        .bind(path)
+        .execute(pool)
+        .await
+        .map(|_| ()))?;
+    Ok(StatusCode::CREATED.into_response())
+}
+
+/// Moves the file or folder at `path` to the `Destination` header's path, first
+/// replacing what is there unless `Overwrite: F` is given.
+async fn move_resource(state: &AppState, user: &AuthUser, path: &str, headers: &HeaderMap) -> Result<Response, AppError> {
+    let destination = headers
+        .get("Destination")
+        .and_then(|v| v.to_str().ok())
+        .ok_or_else(|| AppError::BadRequest("Missing Destination header".into()))?;
+    // The destination may be a full URL; only its path matters.
+    let destination = match destination.split_once("://") {
+        Some((_, rest)) => rest.find('/').map_or("/", |at| &rest[at..]),
+        None => destination,
+    };
+    if destination != DAV_PREFIX && !destination.starts_with(&format!("{}/", DAV_PREFIX)) {
+        return Err(AppError::BadRequest(format!("Destination must be under {}", DAV_PREFIX)));
+    }
+    let to = dav_path(destination)?;
+    let overwrite = headers.get("Overwrite").and_then(|v| v.to_str().ok()) != Some("F");
+
+    if path.is_empty() || to.is_empty() {
+        return Err(AppError::Forbidden("The root folder can't be moved".into()));
+    }
+    if to == path || to.starts_with(&format!("{}/", path)) {
+        return Err(AppError::Forbidden("Cannot move a path onto itself or into its own folder".into()));
+    }
+    if resolve(state, user.user_id, path).await?.is_none() {
+        return Err(AppError::NotFound("No file or folder at this path".into()));
+    }
+    if parent_is_file(state, user.user_id, &to).await? {
+        return Err(AppError::Conflict("A parent of the destination is a file".into()));
+    }
+
+    let replaced = match resolve(state, user.user_id, &to).await? {
+        Some(_) if !overwrite => return Ok(StatusCode::PRECONDITION_FAILED.into_response()),
+        Some(target) => {
+            remove(state, user, &to, target).await?;
+            true
+        }
+        None => false,
+    };
+    move_files(state, user, path, &to).await?;
+    info!(user_id = user.user_id, "DAV MOVE {} -> {}", path, to);
+
+    Ok(if replaced { StatusCode::NO_CONTENT } else { StatusCode::CREATED }.into_response())
+}
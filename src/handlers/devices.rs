@@ -0,0 +1,189 @@
+use axum::{
+    extract::{Extension, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use tracing::{info, warn};
+
+use crate::{
+    db::on_db,
+    error::AppError,
+    models::{AuthUser, DeviceInfo, RegisterDeviceRequest},
+    AppState, MAX_DEVICE_ID_LEN,
+};
+
+/// Registers one of the caller's devices, which it then names in `X-Device-Id`.
+/// Registering an existing `device_id` again updates its name and profile rather
+/// than failing, so a client can re-register on every start.
+#[utoipa::path(
+    post, path = "/devices/register", tag = "devices",
+    request_body = RegisterDeviceRequest,
+    responses(
+        (status = 201, description = "The registered device", body = DeviceInfo),
+        (status = 400, description = "Invalid request", body = crate::openapi::ErrorBody),
+    )
+)]
+pub(crate) async fn handle_register_device(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Json(req): Json<RegisterDeviceRequest>,
+) -> Result<Response, AppError> {
+    let name = req.name.trim();
+    if name.is_empty() {
+        return Err(AppError::BadRequest("name must not be empty".into()));
+    }
+    let device_id = match req.device_id {
+        Some(id) if id.is_empty() || id.len() > MAX_DEVICE_ID_LEN => {
+            return Err(AppError::BadRequest(format!(
+                "device_id must be between 1 and {} bytes",
+                MAX_DEVICE_ID_LEN
+            )));
+        }
+        Some(id) => id,
+        None => hex::encode(rand::random::<[u8; 16]>()),
+    };
+
+    if let Some(profile) = &req.profile {
+        let exists = on_db!(&state.pool, pool => sqlx::query_scalar::<_, bool>(
+            "SELECT EXISTS (SELECT 1 FROM sync_profiles WHERE user_id = $1 AND name = $2)"
+        )
+        .bind(user.user_id)
+        .bind(profile)
+        .fetch_one(pool)
+        .await)?;
+        if !exists {
+            return Err(AppError::BadRequest(format!("No sync profile named {}", profile)));
+        }
+    }
+
+    let sql = state.pool.sql(
+        r#"
+        INSERT INTO devices (user_id, id, name, platform, profile, last_seen_at)
+        VALUES ($1, $2, $3, $4, $5, CURRENT_TIMESTAMP)
+        ON CONFLICT (user_id, id) DO UPDATE
+        SET name = EXCLUDED.name, platform = EXCLUDED.platform, profile = EXCLUDED.profile,
+            last_seen_at = EXCLUDED.last_seen_at
+        RETURNING id AS device_id, name, platform, profile, cursor_seq AS cursor,
+            GREATEST(COALESCE((SELECT seq FROM change_counters WHERE user_id = $1), 0) - cursor_seq, 0) AS behind,
+            created_at, last_seen_at, last_sync_at
+        "#,
+        r#"
+        INSERT INTO devices (user_id, id, name, platform, profile, last_seen_at)
+        VALUES ($1, $2, $3, $4, $5, CURRENT_TIMESTAMP)
+        ON CONFLICT (user_id, id) DO UPDATE
+        SET name = EXCLUDED.name, platform = EXCLUDED.platform, profile = EXCLUDED.profile,
+            last_seen_at = EXCLUDED.last_seen_at
+        RETURNING id AS device_id, name, platform, profile, cursor_seq AS cursor,
+            MAX(COALESCE((SELECT seq FROM change_counters WHERE user_id = $1), 0) - cursor_seq, 0) AS behind,
+            created_at, last_seen_at, last_sync_at
+        "#,
+    );
+    let device = on_db!(&state.pool, pool => sqlx::query_as::<_, DeviceInfo>(sql)
+    .bind(user.user_id)
+    .bind(&device_id)
+    .bind(name)
+    .bind(&req.platform)
+    .bind(&req.profile)
+    .fetch_one(pool)
+    .await)?;
+
+    info!(user_id = user.user_id, device_id = %device.device_id, "Registered device {}", device.name);
+    Ok((StatusCode::CREATED, Json(device)).into_response())
+}
+
+/// The caller's devices, each with how many changes it has yet to pick up.
+#[utoipa::path(
+    get, path = "/devices", tag = "devices",
+    responses((status = 200, description = "The caller's devices", body = crate::openapi::DeviceList))
+)]
+pub(crate) async fn handle_list_devices(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+) -> Result<Response, AppError> {
+    let sql = state.pool.sql(
+        r#"
+        SELECT d.id AS device_id, d.name, d.platform, d.profile, d.cursor_seq AS cursor,
+            GREATEST(COALESCE(c.seq, 0) - d.cursor_seq, 0) AS behind,
+            d.created_at, d.last_seen_at, d.last_sync_at
+        FROM devices d
+        LEFT JOIN change_counters c ON c.user_id = d.user_id
+        WHERE d.user_id = $1
+        ORDER BY d.created_at, d.id
+        "#,
+        r#"
+        SELECT d.id AS device_id, d.name, d.platform, d.profile, d.cursor_seq AS cursor,
+            MAX(COALESCE(c.seq, 0) - d.cursor_seq, 0) AS behind,
+            d.created_at, d.last_seen_at, d.last_sync_at
+        FROM devices d
+        LEFT JOIN change_counters c ON c.user_id = d.user_id
+        WHERE d.user_id = $1
+        ORDER BY d.created_at, d.id
+        "#,
+    );
+    let devices = on_db!(&state.pool, pool => sqlx::query_as::<_, DeviceInfo>(sql)
+    .bind(user.user_id)
+    .fetch_all(pool)
+    .await)?;
+
+    let out_of_date: Vec<&str> = devices
+        .iter()
+        .filter(|device| device.behind > 0)
+        .map(|device| device.device_id.as_str())
+        .collect();
+    Ok((StatusCode::OK, Json(serde_json::json!({
+        "data": devices,
+        "out_of_date": out_of_date
+    }))).into_response())
+}
+
+/// Fails unless the request names one of the caller's registered devices in
+/// `X-Device-Id`, and records that the device synced. Returns the device's name.
+pub(crate) async fn require_device(state: &AppState, user: &AuthUser) -> Result<String, AppError> {
+    let Some(device_id) = &user.device_id else {
+        return Err(AppError::BadRequest("The X-Device-Id header is required".into()));
+    };
+
+    let name = on_db!(&state.pool, pool => sqlx::query_scalar::<_, String>(
+        r#"
+        UPDATE devices SET last_seen_at = CURRENT_TIMESTAMP, last_sync_at = CURRENT_TIMESTAMP
+        WHERE user_id = $1 AND id = $2
+        RETURNING name
+        "#
+    )
+    .bind(user.user_id)
+    .bind(device_id)
+    .fetch_optional(pool)
+    .await)?;
+
+    name.ok_or_else(|| AppError::Forbidden(
+        "Unknown device; register it with POST /devices/register first".into(),
+    ))
+}
+
+/// Moves the caller's device cursor forward to `since`: asking for the changes
+/// after it means the device already has everything up to it.
+pub(crate) async fn acknowledge_changes(state: &AppState, user: &AuthUser, since: i64) {
+    let Some(device_id) = &user.device_id else { return };
+
+    let sql = state.pool.sql(
+        r#"
+        UPDATE devices SET cursor_seq = GREATEST(cursor_seq, $3), last_seen_at = CURRENT_TIMESTAMP
+        WHERE user_id = $1 AND id = $2
+        "#,
+        r#"
+        UPDATE devices SET cursor_seq = MAX(cursor_seq, $3), last_seen_at = CURRENT_TIMESTAMP
+        WHERE user_id = $1 AND id = $2
+        "#,
+    );
+    let result = on_db!(&state.pool, pool => sqlx::query(sql)
+    .bind(user.user_id)
+    .bind(device_id)
+    .bind(since)
+    .execute(pool)
+    .await
+    .map(|_| ()));
+    if let Err(e) = result {
+        warn!("Failed to record cursor for device {}: {}", device_id, e);
+    }
+}
@@ -0,0 +1,170 @@
+//! Differential listings. A client sends the digests of its folders, a Merkle tree
+//! over the paths and hashes of its files, and gets back only the folders whose
+//! digests differ from the server's, instead of the whole catalog.
+
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, HashMap},
+};
+
+use axum::{
+    extract::{Extension, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use sha2::{Digest, Sha256};
+use tracing::info;
+
+use crate::{
+    db::{on_db, Array},
+    error::AppError,
+    handlers::{
+        files::trim_slashes,
+        listing::tags_sql,
+        profiles::{device_filter, PathFilter},
+    },
+    models::{AuthUser, DiffRequest, DiffResponse, FileEntry},
+    AppState,
+};
+
+/// One folder of the server's tree.
+#[derive(Default)]
+struct Folder {
+    /// The folder's subfolders, by name, to their paths.
+    subfolders: BTreeMap<String, String>,
+    /// The files directly in the folder, as indexes into the listing.
+    files: Vec<usize>,
+    digest: String,
+}
+
+#[utoipa::path(
+    post, path = "/get/diff", tag = "sync",
+    summary = "Lists only the folders whose digests differ from the client's",
+    request_body = DiffRequest,
+    responses(
+        (status = 200, description = "The server's root digest, and the files of every folder that differs", body = DiffResponse),
+        (status = 400, description = "Invalid request", body = crate::openapi::ErrorBody),
+        (status = 401, description = "Missing or invalid bearer token", body = crate::openapi::ErrorBody),
+    )
+)]
+pub(crate) async fn handle_get_diff(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Json(req): Json<DiffRequest>,
+) -> Result<Response, AppError> {
+    let server_time = chrono::Utc::now().timestamp();
+    let filter = device_filter(&state, &user).await?;
+    let query = format!(
+        r#"
+        SELECT file_path, file_hash, file_size, modified_time, system_path AS file_name, content_type, etag, created_at, updated_at, version,
+               metadata, {} AS tags
+        FROM filehash
+        WHERE user_id = $1 AND {}
+        ORDER BY file_path
+        "#,
+        tags_sql(&state.pool),
+        PathFilter::sql(&state.pool, 2, 3),
+    );
+    let files = on_db!(&state.pool, pool => sqlx::query_as::<_, FileEntry>(&query)
+        .bind(user.user_id)
+        .bind(Array(&filter.include))
+        .bind(Array(&filter.exclude))
+        .fetch_all(pool)
+        .await)?;
+
+    let tree = build_tree(&files);
+    let digests: HashMap<&str, &str> = req
+        .digests
+        .iter()
+        .map(|(path, digest)| (trim_slashes(path), digest.as_str()))
+        .collect();
+
+    let mut folders = Vec::new();
+    let mut listed = Vec::new();
+    let mut pending = vec![""];
+    while let Some(path) = pending.pop() {
+        let folder = &tree[path];
+        if digests.get(path) == Some(&folder.digest.as_str()) {
+            continue;
+        }
+        folders.push(path.to_string());
+        listed.extend(folder.files.iter().copied());
+        pending.extend(folder.subfolders.values().map(String::as_str));
+    }
+    folders.sort();
+    listed.sort();
+
+    // Only the outermost of the folders the server doesn't have: what's inside
+    // them is gone with them.
+    let mut missing: Vec<&str> = digests.keys().copied().filter(|path| !tree.contains_key(*path)).collect();
+    missing.sort();
+    let mut removed: Vec<String> = Vec::new();
+    for path in missing {
+        if !removed.last().is_some_and(|outer| path.starts_with(&format!("{}/", outer))) {
+            removed.push(path.to_string());
+        }
+    }
+
+    info!("Diffed listing for user {}: {} of {} folders differ", user.user_id, folders.len(), tree.len());
+    let mut files: Vec<Option<FileEntry>> = files.into_iter().map(Some).collect();
+    let data = listed.into_iter().filter_map(|i| files[i].take()).collect();
+    let response = DiffResponse { digest: tree[""].digest.clone(), folders, data, removed, server_time };
+    Ok((StatusCode::OK, Json(response)).into_response())
+}
+
+/// The folder a path is in, and its name within it.
+fn split_path(path: &str) -> (&str, &str) {
+    path.rsplit_once('/').unwrap_or(("", path))
+}
+
+/// Every folder holding `files`, directly or not, by path, with its digest as
+/// `DiffRequest` describes it. The root is `""`, and is there even with no files.
+fn build_tree(files: &[FileEntry]) -> HashMap<String, Folder> {
+    let mut tree: HashMap<String, Folder> = HashMap::new();
+    tree.insert(String::new(), Folder::default());
+    for (i, file) in files.iter().enumerate() {
+        let (parent, _) = split_path(&file.file_path);
+        tree.entry(parent.to_string()).or_default().files.push(i);
+        // Links the folder into its parent, and so on up until one already was.
+        let mut path = parent;
+        while !path.is_empty() {
+            let (grandparent, name) = split_path(path);
+            let linked = tree
+                .entry(grandparent.to_string())
+                .or_default()
+                .subfolders
+                .insert(name.to_string(), path.to_string());
+            if linked.is_some() {
+                break;
+            }
+            path = grandparent;
+        }
+    }
+
+    // Deepest first, so a folder's subfolders all have their digests before it.
+    let mut paths: Vec<String> = tree.keys().cloned().collect();
+    paths.sort_by_key(|path| Reverse(if path.is_empty() { 0 } else { path.matches('/').count() + 1 }));
+    for path in paths {
+        let folder = &tree[&path];
+        let mut lines: Vec<String> = folder
+            .subfolders
+            .iter()
+            .map(|(name, subfolder)| format!("d {}\t{}\n", name, tree[subfolder].digest))
+            .chain(folder.files.iter().map(|&i| {
+                let file = &files[i];
+                format!("f {}\t{}\n", split_path(&file.file_path).1, file.file_hash.as_deref().unwrap_or_default())
+            }))
+            .collect();
+        lines.sort();
+        let mut hasher = Sha256::new();
+        for line in &lines {
+            hasher.update(line);
+        }
+        let digest = hex::encode(hasher.finalize());
+        if let Some(folder) = tree.get_mut(&path) {
+            folder.digest = digest;
+        }
+    }
+    tree
+}
@@ -0,0 +1,34 @@
+use axum::{
+    response::{Html, IntoResponse},
+    Json,
+};
+use utoipa::OpenApi;
+
+use crate::openapi::ApiDoc;
+
+/// Swagger UI, loaded from a CDN and pointed at `/openapi.json`.
+const SWAGGER_UI_PAGE: &str = r##"<!doctype html>
+<html lang="en">
+<head>
+  <meta charset="utf-8">
+  <title>Pocket Drive API</title>
+  <link rel="stylesheet" href="https://unpkg.com/swagger-ui-dist@5/swagger-ui.css">
+</head>
+<body>
+  <div id="swagger-ui"></div>
+  <script src="https://unpkg.com/swagger-ui-dist@5/swagger-ui-bundle.js"></script>
+  <script>
+    window.ui = SwaggerUIBundle({ url: "/openapi.json", dom_id: "#swagger-ui" });
+  </script>
+</body>
+</html>
+"##;
+
+/// The OpenAPI 3 description of every client-facing endpoint.
+pub(crate) async fn handle_openapi() -> impl IntoResponse {
+    Json(ApiDoc::openapi())
+}
+
+pub(crate) async fn handle_docs() -> Html<&'static str> {
+    Html(SWAGGER_UI_PAGE)
+}
@@ -0,0 +1,828 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    net::SocketAddr,
+    sync::Arc,
+    time::Duration,
+};
+
+use async_zip::{tokio::write::ZipFileWriter, Compression, ZipDateTime, ZipEntryBuilder};
+use axum::{
+    body::Body,
+    extract::{ConnectInfo, Extension, Path, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::Datelike;
+use futures::{future, AsyncWriteExt, StreamExt};
+use jsonwebtoken::errors::ErrorKind;
+use tokio::io::DuplexStream;
+use tokio_util::io::ReaderStream;
+use tracing::{warn, Instrument};
+
+use crate::{
+    audit,
+    auth::{issue_download_token, verify_download_token},
+    db::{on_db, Array},
+    error::AppError,
+    handlers::{
+        collaborators::act_for_owner,
+        files::trim_slashes,
+        listing::{escape_like, tags_sql},
+        uploads::resolve_content_type,
+    },
+    models::{
+        ArchiveRequest, AuthUser, BatchDownload, BatchDownloadResponse, DownloadTokenRequest, DownloadUrl,
+        DownloadUrlsRequest, FileEntry, FileInfo, FolderPermission, TokenScope,
+    },
+    storage::{
+        spool::{spool, Spooled},
+        StorageBackend, StreamParams,
+    },
+    AppState, ARCHIVE_BUFFER_BYTES, FILE_NOT_FOUND_MESSAGE, MAX_ARCHIVE_FILES, MAX_DOWNLOAD_BATCH,
+};
+
+#[utoipa::path(
+    get, path = "/download", tag = "files",
+    summary = "Presigns a download URL for one file",
+    params(
+        ("file_path" = String, Query),
+        ("owner" = Option<String>, Query, description = "The user whose shared folder the file is in"),
+        ("If-None-Match" = Option<String>, Header, description = "The `ETag` of the copy the client already has"),
+    ),
+    responses(
+        (status = 200, description = "Presigned URL", body = crate::openapi::PresignedDownload),
+        (status = 304, description = "The client's copy is current"),
+        (status = 404, description = "No such file", body = crate::openapi::ErrorBody),
+    )
+)]
+pub(crate) async fn handle_file_download(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Query(params): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let file_path = params
+        .get("file_path")
+        .ok_or_else(|| AppError::BadRequest("Missing file_path".into()))?;
+    let user = match params.get("owner") {
+        Some(owner) => act_for_owner(&state, &user, owner, &[file_path], FolderPermission::Read).await?,
+        None => user,
+    };
+
+    // Only keys recorded in the DB can be presigned; the client never names a storage key itself.
+    let entry = on_db!(&state.pool, pool => sqlx::query_as::<_, FileEntry>(
+        r#"
+        SELECT file_path, file_hash, file_size, modified_time, system_path AS file_name, content_type, etag, created_at, updated_at, version
+        FROM filehash
+        WHERE user_id = $1 AND file_path = $2
+        "#
+    )
+    .bind(user.user_id)
+    .bind(file_path)
+    .fetch_optional(pool)
+    .await)?
+    .ok_or_else(|| AppError::NotFound("File not found".into()))?;
+
+    let etag = entity_tag(&entry);
+    if let Some(tag) = &etag
+        && if_none_match(&headers, tag)
+    {
+        return Ok((StatusCode::NOT_MODIFIED, [(header::ETAG, tag.clone())]).into_response());
+    }
+
+    let url = presign_file(&state, &entry.file_name, entry.content_type)
+        .await
+        .map_err(|e| AppError::Internal(format!("Failed to generate URL: {}", e)))?;
+    audit::record_downloads(&state, &user, vec![file_path.clone()]);
+
+    let mut response = (
+        StatusCode::OK,
+        Json(serde_json::json!({
+            "url": url,
+            "expires_in_seconds": state.settings.current().presign_expiry_secs
+        }))
+    ).into_response();
+    if let Some(tag) = etag.and_then(|t| t.parse().ok()) {
+        response.headers_mut().insert(header::ETAG, tag);
+    }
+    Ok(response)
+}
+
+/// Presigns several files at once; paths that can't be resolved get a null URL and an error.
+#[utoipa::path(
+    post, path = "/download/urls", tag = "files",
+    request_body = DownloadUrlsRequest,
+    responses(
+        (status = 200, description = "One URL per path, keyed by path", body = HashMap<String, DownloadUrl>),
+        (status = 400, description = "Invalid request", body = crate::openapi::ErrorBody),
+    )
+)]
+pub(crate) async fn handle_download_urls(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Json(req): Json<DownloadUrlsRequest>,
+) -> Result<Response, AppError> {
+    let expires_in_seconds = state.settings.current().presign_expiry_secs;
+    let urls: HashMap<String, DownloadUrl> = presign_files(&state, &user, req.paths)
+        .await?
+        .into_iter()
+        .map(|(file_path, presigned)| {
+            let url = match presigned {
+                Ok((_, url)) => DownloadUrl { url: Some(url), expires_in_seconds: Some(expires_in_seconds), error: None },
+                Err(error) => DownloadUrl { url: None, expires_in_seconds: None, error: Some(error) },
+            };
+            (file_path, url)
+        })
+        .collect();
+
+    Ok((StatusCode::OK, Json(urls)).into_response())
+}
+
+/// Presigns up to `MAX_DOWNLOAD_BATCH` files at once, listing each with its size,
+/// hash and `ETag` so a client fetching a whole tree can check what it downloads.
+#[utoipa::path(
+    post, path = "/download/batch", tag = "files",
+    request_body = DownloadUrlsRequest,
+    responses(
+        (status = 200, description = "One entry per path, in request order", body = BatchDownloadResponse),
+        (status = 400, description = "Invalid request", body = crate::openapi::ErrorBody),
+    )
+)]
+pub(crate) async fn handle_download_batch(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Json(req): Json<DownloadUrlsRequest>,
+) -> Result<Response, AppError> {
+    let files = presign_files(&state, &user, req.paths)
+        .await?
+        .into_iter()
+        .map(|(file_path, presigned)| match presigned {
+            Ok((entry, url)) => BatchDownload {
+                file_path,
+                url: Some(url),
+                file_size: Some(entry.file_size),
+                etag: entity_tag(&entry),
+                file_hash: entry.file_hash,
+                content_type: entry.content_type,
+                error: None,
+            },
+            Err(error) => BatchDownload::failed(file_path, error),
+        })
+        .collect();
+
+    Ok((StatusCode::OK, Json(BatchDownloadResponse {
+        data: files,
+        expires_in_seconds: state.settings.current().presign_expiry_secs,
+    })).into_response())
+}
+
+/// Looks up each of `paths` among `user`'s files and presigns it, for
+/// `/download/urls` and `/download/batch`. Returns, in request order, each file
+/// with its URL or why it has none, and records the presigned ones as downloaded.
+async fn presign_files(
+    state: &AppState,
+    user: &AuthUser,
+    paths: Vec<String>,
+) -> Result<Vec<(String, Result<(FileEntry, String), String>)>, AppError> {
+    if paths.len() > MAX_DOWNLOAD_BATCH {
+        return Err(AppError::BadRequest(format!(
+            "At most {} paths can be requested at once",
+            MAX_DOWNLOAD_BATCH
+        )));
+    }
+
+    let sql = state.pool.sql(
+        r#"
+        SELECT file_path, file_hash, file_size, modified_time, system_path AS file_name, content_type, etag
+        FROM filehash
+        WHERE user_id = $1 AND file_path = ANY($2)
+        "#,
+        r#"
+        SELECT file_path, file_hash, file_size, modified_time, system_path AS file_name, content_type, etag
+        FROM filehash
+        WHERE user_id = $1 AND file_path IN (SELECT value FROM json_each($2))
+        "#,
+    );
+    let entries: HashMap<String, FileEntry> = on_db!(&state.pool, pool => sqlx::query_as::<_, FileEntry>(sql)
+        .bind(user.user_id)
+        .bind(Array(&paths))
+        .fetch_all(pool)
+        .await)?
+    .into_iter()
+    .map(|entry| (entry.file_path.clone(), entry))
+    .collect();
+
+    let mut results = Vec::with_capacity(paths.len());
+    let mut presigned = Vec::new();
+    for file_path in paths {
+        let result = match entries.get(&file_path) {
+            Some(entry) => presign_file(state, &entry.file_name, entry.content_type.clone())
+                .await
+                .map(|url| (entry.clone(), url)),
+            None => Err(FILE_NOT_FOUND_MESSAGE.to_string()),
+        };
+        if result.is_ok() {
+            presigned.push(file_path.clone());
+        }
+        results.push((file_path, result));
+    }
+    audit::record_downloads(state, user, presigned);
+    Ok(results)
+}
+
+/// Streams a ZIP of the requested files, written while their objects are read from
+/// storage one after another, so a folder downloads in one request. Entries are
+/// stored uncompressed and named by path; under a `prefix`, relative to the folder
+/// that holds it. A storage failure part way aborts the response rather than end
+/// it with a truncated archive.
+#[utoipa::path(
+    post, path = "/download/archive", tag = "files",
+    request_body = ArchiveRequest,
+    responses(
+        (status = 200, description = "ZIP archive", content_type = "application/zip"),
+        (status = 400, description = "Invalid request", body = crate::openapi::ErrorBody),
+        (status = 404, description = "Listed files are missing, or the folder is empty"),
+    )
+)]
+pub(crate) async fn handle_download_archive(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Json(req): Json<ArchiveRequest>,
+) -> Result<Response, AppError> {
+    let (rows, base, archive_name) = match (req.paths, req.prefix) {
+        (Some(paths), None) => {
+            if paths.is_empty() || paths.len() > MAX_ARCHIVE_FILES {
+                return Err(AppError::BadRequest(format!("Between 1 and {} paths can be archived at once", MAX_ARCHIVE_FILES)));
+            }
+            let sql = state.pool.sql(
+                r#"
+                SELECT file_path, system_path, modified_time FROM filehash
+                WHERE user_id = $1 AND file_path = ANY($2)
+                ORDER BY file_path
+                "#,
+                r#"
+                SELECT file_path, system_path, modified_time FROM filehash
+                WHERE user_id = $1 AND file_path IN (SELECT value FROM json_each($2))
+                ORDER BY file_path
+                "#,
+            );
+            let rows = on_db!(&state.pool, pool => sqlx::query_as::<_, (String, String, i64)>(sql)
+            .bind(user.user_id)
+            .bind(Array(&paths))
+            .fetch_all(pool)
+            .await)?;
+
+            let found: HashSet<&str> = rows.iter().map(|(file_path, _, _)| file_path.as_str()).collect();
+            let missing: Vec<&String> = paths.iter().filter(|p| !found.contains(p.as_str())).collect();
+            if !missing.is_empty() {
+                return Ok((StatusCode::NOT_FOUND, Json(serde_json::json!({
+                    "error": "Some files were not found",
+                    "missing": missing
+                }))).into_response());
+            }
+            (rows, 0, "files".to_string())
+        }
+        (None, Some(prefix)) => {
+            let dir = trim_slashes(&prefix);
+            let pattern = if dir.is_empty() { "%".to_string() } else { format!("{}/%", escape_like(dir)) };
+            let rows = on_db!(&state.pool, pool => sqlx::query_as::<_, (String, String, i64)>(
+                r#"
+                SELECT file_path, system_path, modified_time FROM filehash
+                WHERE user_id = $1 AND file_path LIKE $2 ESCAPE '\'
+                ORDER BY file_path
+                LIMIT $3
+                "#
+            )
+            .bind(user.user_id)
+            .bind(&pattern)
+            .bind(MAX_ARCHIVE_FILES as i64 + 1)
+            .fetch_all(pool)
+            .await)?;
+
+            if rows.is_empty() {
+                return Err(AppError::NotFound("No files under this prefix".into()));
+            }
+            if rows.len() > MAX_ARCHIVE_FILES {
+                return Err(AppError::BadRequest(format!("More than {} files are under this prefix", MAX_ARCHIVE_FILES)));
+            }
+            let base = dir.rfind('/').map_or(0, |slash| slash + 1);
+            let name = dir.rsplit('/').next().filter(|name| !name.is_empty()).unwrap_or("files");
+            (rows, base, name.to_string())
+        }
+        _ => return Err(AppError::BadRequest("Give either paths or prefix".into())),
+    };
+
+    audit::record_downloads(&state, &user, rows.iter().map(|(file_path, _, _)| file_path.clone()).collect());
+    let entries = rows
+        .into_iter()
+        .map(|(file_path, key, modified_time)| (file_path[base..].to_string(), key, modified_time))
+        .collect();
+    let (writer, reader) = tokio::io::duplex(ARCHIVE_BUFFER_BYTES);
+    let writing = tokio::spawn(write_archive(state.storage.clone(), writer, entries).in_current_span());
+    let outcome = futures::stream::once(async move {
+        let error = match writing.await {
+            Ok(Ok(())) => return None,
+            Ok(Err(e)) => e,
+            Err(e) => e.to_string(),
+        };
+        warn!("Archive download failed: {}", error);
+        Some(Err(io::Error::other(error)))
+    })
+    .filter_map(future::ready);
+
+    Ok((
+        StatusCode::OK,
+        [
+            (header::CONTENT_TYPE, "application/zip".to_string()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename*=UTF-8''{}.zip", urlencoding::encode(&archive_name)),
+            ),
+        ],
+        Body::from_stream(ReaderStream::new(reader).chain(outcome)),
+    ).into_response())
+}
+
+/// 1980-01-01, the earliest time a ZIP entry can carry.
+const ZIP_EPOCH: chrono::DateTime<chrono::Utc> = chrono::DateTime::from_timestamp_nanos(315_532_800_000_000_000);
+
+/// Writes each `(name, key, modified_time)` entry's object into a ZIP on `writer`.
+async fn write_archive(
+    storage: Arc<dyn StorageBackend>,
+    writer: DuplexStream,
+    entries: Vec<(String, String, i64)>,
+) -> Result<(), String> {
+    let mut zip = ZipFileWriter::with_tokio(writer);
+    for (name, key, modified_time) in entries {
+        let size = storage.size(&key).await? as u64;
+
+        // ZIP timestamps can't express anything before 1980.
+        let modified = chrono::DateTime::from_timestamp(modified_time, 0)
+            .filter(|time| time.year() >= 1980)
+            .unwrap_or(ZIP_EPOCH);
+        let builder = ZipEntryBuilder::new(name.into(), Compression::Stored)
+            .unix_permissions(0o644)
+            .last_modification_date(ZipDateTime::from_chrono(&modified));
+        let mut entry = zip.write_entry_stream(builder).await.map_err(|e| e.to_string())?;
+        if size > 0 {
+            let mut body = storage.get_range(&key, 0, size).await?;
+            while let Some(chunk) = body.next().await {
+                entry.write_all(&chunk?).await.map_err(|e| e.to_string())?;
+            }
+        }
+        entry.close().await.map_err(|e| e.to_string())?;
+    }
+    zip.close().await.map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+async fn presign_file(
+    state: &AppState,
+    key: &str,
+    content_type: Option<String>,
+) -> Result<String, String> {
+    state
+        .storage
+        .presign_download(key, content_type, Duration::from_secs(state.settings.current().presign_expiry_secs))
+        .await
+        .map_err(String::from)
+}
+
+/// Streams a file through the server instead of handing out a presigned URL, for
+/// clients that can't reach storage themselves. Honors a single `Range`.
+#[utoipa::path(
+    get, path = "/download/direct", tag = "files",
+    params(
+        ("file_path" = String, Query),
+        ("owner" = Option<String>, Query, description = "The user whose shared folder the file is in"),
+        ("Range" = Option<String>, Header, description = "A single `bytes=` range"),
+        ("If-None-Match" = Option<String>, Header, description = "The `ETag` of the copy the client already has"),
+    ),
+    responses(
+        (status = 200, description = "The file's content", content_type = "application/octet-stream"),
+        (status = 206, description = "The requested range", content_type = "application/octet-stream"),
+        (status = 304, description = "The client's copy is current"),
+        (status = 404, description = "No such file", body = crate::openapi::ErrorBody),
+        (status = 416, description = "The range can't be satisfied"),
+    )
+)]
+pub(crate) async fn handle_direct_download(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Query(params): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let file_path = params
+        .get("file_path")
+        .ok_or_else(|| AppError::BadRequest("Missing file_path".into()))?;
+    let user = match params.get("owner") {
+        Some(owner) => act_for_owner(&state, &user, owner, &[file_path], FolderPermission::Read).await?,
+        None => user,
+    };
+
+    let entry = on_db!(&state.pool, pool => sqlx::query_as::<_, FileEntry>(
+        r#"
+        SELECT file_path, file_hash, file_size, modified_time, system_path AS file_name, content_type, etag, created_at, updated_at, version
+        FROM filehash
+        WHERE user_id = $1 AND file_path = $2
+        "#
+    )
+    .bind(user.user_id)
+    .bind(file_path)
+    .fetch_optional(pool)
+    .await)?
+    .ok_or_else(|| AppError::NotFound("File not found".into()))?;
+
+    let file_path = entry.file_path.clone();
+    let response = serve_file(&state, entry, &headers).await?;
+    audit::record_downloads(&state, &user, vec![file_path]);
+    Ok(response)
+}
+
+/// Issues a short-lived token that downloads one file through `/download/signed/{token}`
+/// with no other credentials, for media players and browsers that can't send a
+/// bearer token.
+#[utoipa::path(
+    post, path = "/download/token", tag = "files",
+    request_body = DownloadTokenRequest,
+    responses(
+        (status = 200, description = "The token and the URL it downloads from", body = crate::openapi::DownloadToken),
+        (status = 404, description = "No such file", body = crate::openapi::ErrorBody),
+    )
+)]
+pub(crate) async fn handle_create_download_token(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Json(req): Json<DownloadTokenRequest>,
+) -> Result<Response, AppError> {
+    let exists = on_db!(&state.pool, pool => sqlx::query_scalar::<_, i32>(
+        "SELECT 1 FROM filehash WHERE user_id = $1 AND file_path = $2"
+    )
+    .bind(user.user_id)
+    .bind(&req.file_path)
+    .fetch_optional(pool)
+    .await)?;
+    if exists.is_none() {
+        return Err(AppError::NotFound("File not found".into()));
+    }
+
+    let max = state.jwt.download_expiry_secs;
+    let expires_in = req.expires_in_secs.unwrap_or(max).clamp(1, max);
+    let token = issue_download_token(&state.jwt, &user, &req.file_path, expires_in).map_err(AppError::Internal)?;
+    let url = format!("{}/download/signed/{}", state.config.public_base_url.trim_end_matches('/'), token);
+
+    Ok((StatusCode::OK, Json(serde_json::json!({
+        "token": token,
+        "url": url,
+        "expires_in_seconds": expires_in
+    }))).into_response())
+}
+
+/// Streams the file a `/download/token` token names, like `/download/direct`.
+#[utoipa::path(
+    get, path = "/download/signed/{token}", tag = "files", security(()),
+    params(
+        ("token" = String, Path),
+        ("Range" = Option<String>, Header, description = "A single `bytes=` range"),
+        ("If-None-Match" = Option<String>, Header, description = "The `ETag` of the copy the client already has"),
+    ),
+    responses(
+        (status = 200, description = "The file's content", content_type = "application/octet-stream"),
+        (status = 206, description = "The requested range", content_type = "application/octet-stream"),
+        (status = 304, description = "The client's copy is current"),
+        (status = 401, description = "The token isn't valid", body = crate::openapi::ErrorBody),
+        (status = 404, description = "No such file", body = crate::openapi::ErrorBody),
+        (status = 410, description = "The token expired", body = crate::openapi::ErrorBody),
+        (status = 416, description = "The range can't be satisfied"),
+    )
+)]
+pub(crate) async fn handle_signed_download(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(token): Path<String>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let claims = verify_download_token(&state.jwt, &token).map_err(|e| match e.kind() {
+        ErrorKind::ExpiredSignature => AppError::Gone("This download token has expired".into()),
+        _ => AppError::Unauthorized("Invalid download token".into()),
+    })?;
+
+    let entry = on_db!(&state.pool, pool => sqlx::query_as::<_, FileEntry>(
+        r#"
+        SELECT file_path, file_hash, file_size, modified_time, system_path AS file_name, content_type, etag, created_at, updated_at, version
+        FROM filehash
+        WHERE user_id = $1 AND file_path = $2
+        "#
+    )
+    .bind(claims.sub)
+    .bind(&claims.path)
+    .fetch_optional(pool)
+    .await)?
+    .ok_or_else(|| AppError::NotFound("File not found".into()))?;
+
+    let response = serve_file(&state, entry, &headers).await?;
+    let user = AuthUser {
+        user_id: claims.sub,
+        username: claims.username,
+        role: claims.role,
+        scope: TokenScope::Read,
+        device_id: None,
+        ip: Some(addr.ip()),
+    };
+    audit::record_downloads(&state, &user, vec![claims.path]);
+    Ok(response)
+}
+
+/// Streams the stored object behind `entry` as an attachment, honoring a single `Range`,
+/// or answers `304 Not Modified` when `If-None-Match` names its current tag.
+pub(crate) async fn serve_file(state: &AppState, entry: FileEntry, headers: &HeaderMap) -> Result<Response, AppError> {
+    let etag = entity_tag(&entry);
+    if let Some(tag) = &etag
+        && if_none_match(headers, tag)
+    {
+        return Ok((StatusCode::NOT_MODIFIED, [(header::ETAG, tag.clone())]).into_response());
+    }
+
+    let size = state
+        .storage
+        .size(&entry.file_name)
+        .await
+        .map_err(|e| AppError::NotFound(format!("File not found in storage: {}", e)))? as u64;
+
+    let range = match headers.get(header::RANGE).and_then(|v| v.to_str().ok()) {
+        Some(value) => match parse_range(value, size) {
+            Some(range) => range,
+            None => return Ok((
+                StatusCode::RANGE_NOT_SATISFIABLE,
+                [(header::CONTENT_RANGE, format!("bytes */{}", size))],
+            ).into_response()),
+        },
+        None => None,
+    };
+    let (status, start, len) = match range {
+        Some((start, end)) => (StatusCode::PARTIAL_CONTENT, start, end - start + 1),
+        None => (StatusCode::OK, 0, size),
+    };
+
+    let body = if len == 0 {
+        Body::empty()
+    } else {
+        let stream = state
+            .storage
+            .get_range(&entry.file_name, start, len)
+            .await
+            .map_err(|e| AppError::BadGateway(format!("Failed to read file: {}", e)))?;
+        Body::from_stream(stream)
+    };
+
+    let file_name = entry.file_path.rsplit('/').next().unwrap_or(&entry.file_path);
+    let content_type = entry
+        .content_type
+        .clone()
+        .unwrap_or_else(|| resolve_content_type(None, file_name));
+
+    let mut response = (
+        status,
+        [
+            (header::CONTENT_TYPE, content_type),
+            (header::CONTENT_LENGTH, len.to_string()),
+            (header::ACCEPT_RANGES, "bytes".to_string()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename*=UTF-8''{}", urlencoding::encode(file_name)),
+            ),
+        ],
+        body,
+    ).into_response();
+    if range.is_some()
+        && let Ok(value) = format!("bytes {}-{}/{}", start, start + len - 1, size).parse()
+    {
+        response.headers_mut().insert(header::CONTENT_RANGE, value);
+    }
+    if let Some(tag) = etag.and_then(|t| t.parse().ok()) {
+        response.headers_mut().insert(header::ETAG, tag);
+    }
+    Ok(response)
+}
+
+/// Parses a `Range` header against an object of `size` bytes into an inclusive
+/// byte range. `Some(None)` means serve the whole object (multiple ranges, or a
+/// unit other than bytes); `None` means the range can't be satisfied.
+fn parse_range(value: &str, size: u64) -> Option<Option<(u64, u64)>> {
+    let Some(spec) = value.trim().strip_prefix("bytes=") else { return Some(None) };
+    if spec.contains(',') {
+        return Some(None);
+    }
+    let (start, end) = spec.split_once('-')?;
+
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", suffix) => {
+            let suffix: u64 = suffix.parse().ok()?;
+            if suffix == 0 {
+                return None;
+            }
+            (size.saturating_sub(suffix), size.checked_sub(1)?)
+        }
+        (start, "") => (start.parse().ok()?, size.checked_sub(1)?),
+        (start, end) => {
+            let end: u64 = end.parse().ok()?;
+            (start.parse().ok()?, end.min(size.checked_sub(1)?))
+        }
+    };
+
+    (start <= end && start < size).then_some(Some((start, end)))
+}
+
+/// Serves an object for a signed `/stream` download URL issued by the storage backend.
+pub(crate) async fn handle_stream_get(
+    State(state): State<AppState>,
+    Query(params): Query<StreamParams>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    if !state.storage.verify_stream("GET", &params) {
+        return Err(AppError::Forbidden("Invalid or expired stream URL".into()));
+    }
+
+    let entry = on_db!(&state.pool, pool => sqlx::query_as::<_, FileEntry>(
+        r#"
+        SELECT file_path, file_hash, file_size, modified_time, system_path AS file_name, content_type, etag, created_at, updated_at, version
+        FROM filehash
+        WHERE system_path = $1
+        "#
+    )
+    .bind(&params.key)
+    .fetch_optional(pool)
+    .await)
+    .ok()
+    .flatten();
+
+    let etag = entry.as_ref().and_then(entity_tag);
+    if let Some(tag) = &etag
+        && if_none_match(&headers, tag)
+    {
+        return Ok((StatusCode::NOT_MODIFIED, [(header::ETAG, tag.clone())]).into_response());
+    }
+
+    let content_type = entry
+        .and_then(|e| e.content_type)
+        .unwrap_or_else(|| resolve_content_type(None, &params.key));
+
+    let data = state
+        .storage
+        .get(&params.key)
+        .await
+        .map_err(|e| AppError::NotFound(format!("File not found: {}", e)))?;
+    let mut response = (StatusCode::OK, [(header::CONTENT_TYPE, content_type)], data).into_response();
+    if let Some(tag) = etag.and_then(|t| t.parse().ok()) {
+        response.headers_mut().insert(header::ETAG, tag);
+    }
+    Ok(response)
+}
+
+/// Returns the stored metadata for one file, with an `ETag` so clients can
+/// revalidate using `If-None-Match`.
+#[utoipa::path(
+    get, path = "/metadata", tag = "files",
+    params(
+        ("path" = String, Query),
+        ("If-None-Match" = Option<String>, Header),
+    ),
+    responses(
+        (status = 200, description = "The file's metadata", body = FileEntry),
+        (status = 304, description = "Unchanged since the given ETag"),
+        (status = 404, description = "No such file", body = crate::openapi::ErrorBody),
+    )
+)]
+pub(crate) async fn handle_metadata(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Query(params): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let FileInfo { file, .. } = find_file(&state, &user, &params).await?;
+    Ok(with_entity_tag(&headers, &file, Json(&file)))
+}
+
+/// One file's server state, its hash, size and version among it, for clients to
+/// check before deciding whether to upload it. `HEAD` sends only the headers.
+#[utoipa::path(
+    get, path = "/file", tag = "files",
+    params(
+        ("path" = String, Query),
+        ("If-None-Match" = Option<String>, Header),
+    ),
+    responses(
+        (status = 200, description = "The file", body = FileInfo),
+        (status = 304, description = "Unchanged since the given ETag"),
+        (status = 404, description = "No such file", body = crate::openapi::ErrorBody),
+    )
+)]
+pub(crate) async fn handle_get_file(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Query(params): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let info = find_file(&state, &user, &params).await?;
+    Ok(with_entity_tag(&headers, &info.file, Json(&info)))
+}
+
+/// The caller's file at the `path` of `params`.
+async fn find_file(state: &AppState, user: &AuthUser, params: &HashMap<String, String>) -> Result<FileInfo, AppError> {
+    let path = params
+        .get("path")
+        .ok_or_else(|| AppError::BadRequest("Missing path".into()))?;
+
+    let query = format!(
+        r#"
+        SELECT file_path, file_hash, file_size, modified_time, system_path AS file_name, content_type, etag, created_at, updated_at, version,
+               metadata, {} AS tags, COALESCE(change_seq, 0) AS version_id
+        FROM filehash
+        WHERE user_id = $1 AND file_path = $2
+        "#,
+        tags_sql(&state.pool),
+    );
+    on_db!(&state.pool, pool => sqlx::query_as::<_, FileInfo>(&query)
+    .bind(user.user_id)
+    .bind(path)
+    .fetch_optional(pool)
+    .await)?
+    .ok_or_else(|| AppError::NotFound(FILE_NOT_FOUND_MESSAGE.into()))
+}
+
+/// `body` with `entry`'s `ETag`, or a `304` when it matches the request's
+/// `If-None-Match`.
+fn with_entity_tag(headers: &HeaderMap, entry: &FileEntry, body: impl IntoResponse) -> Response {
+    let Some(tag) = entity_tag(entry) else {
+        return (StatusCode::OK, body).into_response();
+    };
+
+    if if_none_match(headers, &tag) {
+        return (StatusCode::NOT_MODIFIED, [(header::ETAG, tag)]).into_response();
+    }
+    (StatusCode::OK, [(header::ETAG, tag)], body).into_response()
+}
+
+/// The quoted entity tag for a file: the storage ETag when known, else the client hash.
+pub(crate) fn entity_tag(entry: &FileEntry) -> Option<String> {
+    entry
+        .etag
+        .as_ref()
+        .or(entry.file_hash.as_ref())
+        .map(|tag| format!("\"{}\"", tag))
+}
+
+/// Whether the request's `If-None-Match` header matches `tag` (weak comparison).
+pub(crate) fn if_none_match(headers: &HeaderMap, tag: &str) -> bool {
+    let Some(value) = headers.get(header::IF_NONE_MATCH).and_then(|v| v.to_str().ok()) else {
+        return false;
+    };
+
+    value
+        .split(',')
+        .map(|candidate| candidate.trim())
+        .any(|candidate| candidate == "*" || candidate.trim_start_matches("W/") == tag)
+}
+
+/// Accepts the body for a signed `/stream` upload URL issued by the storage backend.
+/// The body is spooled, to disk once it passes `spool_threshold_bytes`, and only
+/// stored once it's known to be the signed size, so a short or long one never
+/// reaches storage.
+pub(crate) async fn handle_stream_put(
+    State(state): State<AppState>,
+    Query(params): Query<StreamParams>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<Response, AppError> {
+    if !state.storage.verify_stream("PUT", &params) {
+        return Err(AppError::Forbidden("Invalid or expired stream URL".into()));
+    }
+    let Some(size) = params.size.and_then(|size| u64::try_from(size).ok()) else {
+        return Err(AppError::BadRequest("Body length does not match the signed size".into()));
+    };
+
+    let mut chunks = body.into_data_stream().map(|chunk| chunk.map_err(|e| e.to_string()));
+    let spooled = spool(&mut chunks, state.config.spool_threshold_bytes, size, &state.config.spool_dir)
+        .await
+        .map_err(|e| AppError::BadRequest(format!("Failed to read upload: {}", e)))?;
+    if spooled.len() != size {
+        return Err(AppError::BadRequest("Body length does not match the signed size".into()));
+    }
+
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("application/octet-stream");
+
+    let stored = match spooled {
+        Spooled::Memory(data) => state.storage.put(&params.key, data, content_type).await,
+        Spooled::File(file) => match file.chunks().await {
+            Ok(mut chunks) => state.storage.put_stream(&params.key, content_type, &mut chunks).await,
+            Err(e) => Err(e.into()),
+        },
+    };
+    stored.map_err(|e| AppError::Internal(format!("Upload failed: {}", e)))?;
+    Ok(StatusCode::OK.into_response())
+}
@@ -0,0 +1,265 @@
+//! Finding files stored more than once, and removing the extra copies or having
+//! them share one stored object.
+
+use axum::{
+    extract::{Extension, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use tracing::info;
+
+use crate::{
+    db::on_db,
+    error::AppError,
+    events::publish_changes,
+    handlers::files::delete_file,
+    models::{
+        AuthUser, DuplicateAction, DuplicateCluster, DuplicateFile, DuplicateMatch, DuplicatesParams,
+        DuplicatesResponse, FileChange, FileFailure, Operation, ResolveDuplicatesRequest, ResolveDuplicatesResponse,
+    },
+    AppState, DEFAULT_PAGE_LIMIT, MAX_PAGE_LIMIT,
+};
+
+/// A file that has a copy: its path, modified time, content type, hash or name, and size.
+type CopyRow = (String, i64, Option<String>, Option<String>, i64);
+
+/// Lists clusters of the caller's files with the same hash and size, largest
+/// savings first, with what removing the extra copies would free. With
+/// `match_names`, files without a hash are clustered by name and size too.
+#[utoipa::path(
+    get, path = "/duplicates", tag = "files",
+    params(DuplicatesParams),
+    responses(
+        (status = 200, description = "The duplicate clusters", body = DuplicatesResponse),
+        (status = 401, description = "Missing or invalid bearer token", body = crate::openapi::ErrorBody),
+    )
+)]
+pub(crate) async fn handle_list_duplicates(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Query(params): Query<DuplicatesParams>,
+) -> Result<Response, AppError> {
+    let limit = params.limit.unwrap_or(DEFAULT_PAGE_LIMIT).clamp(1, MAX_PAGE_LIMIT) as usize;
+    let offset = params.offset.unwrap_or(0).max(0) as usize;
+
+    let mut clusters = group_copies(DuplicateMatch::Hash, load_copies(&state, user.user_id, DuplicateMatch::Hash).await?);
+    if params.match_names {
+        let by_name = load_copies(&state, user.user_id, DuplicateMatch::NameSize).await?;
+        clusters.extend(group_copies(DuplicateMatch::NameSize, by_name));
+    }
+    clusters.sort_by_key(|cluster| std::cmp::Reverse(cluster.reclaimable_bytes));
+
+    let total = clusters.len();
+    let reclaimable_bytes = clusters.iter().map(|cluster| cluster.reclaimable_bytes).sum();
+    let data = clusters.into_iter().skip(offset).take(limit).collect();
+    Ok((StatusCode::OK, Json(DuplicatesResponse { data, total, reclaimable_bytes })).into_response())
+}
+
+/// The user's files that share a hash and size with another, or for
+/// `NameSize`, the files without a hash sharing a name and size, ordered so
+/// each cluster's files are adjacent and oldest first.
+async fn load_copies(state: &AppState, user_id: i32, matched_by: DuplicateMatch) -> Result<Vec<CopyRow>, AppError> {
+    let sql = match matched_by {
+        DuplicateMatch::Hash => {
+            r#"
+            SELECT file_path, modified_time, content_type, file_hash, file_size FROM (
+                SELECT file_path, modified_time, content_type, file_hash, file_size,
+                       COUNT(*) OVER (PARTITION BY file_hash, file_size) AS copies
+                FROM filehash
+                WHERE user_id = $1 AND file_hash <> ''
+            ) candidates
+            WHERE copies > 1
+            ORDER BY file_hash, file_size, modified_time, file_path
+            "#
+        }
+        DuplicateMatch::NameSize => {
+            r#"
+            SELECT file_path, modified_time, content_type, file_name, file_size FROM (
+                SELECT file_path, modified_time, content_type, file_name, file_size,
+                       COUNT(*) OVER (PARTITION BY file_name, file_size) AS copies
+                FROM filehash
+                WHERE user_id = $1 AND COALESCE(file_hash, '') = '' AND file_name IS NOT NULL
+            ) candidates
+            WHERE copies > 1
+            ORDER BY file_name, file_size, modified_time, file_path
+            "#
+        }
+    };
+    Ok(on_db!(&state.pool, pool => sqlx::query_as::<_, CopyRow>(sql)
+    .bind(user_id)
+    .fetch_all(pool)
+    .await)?)
+}
+
+/// Groups rows from `load_copies` into their clusters.
+fn group_copies(matched_by: DuplicateMatch, rows: Vec<CopyRow>) -> Vec<DuplicateCluster> {
+    let mut clusters: Vec<DuplicateCluster> = Vec::new();
+    for (file_path, modified_time, content_type, key, file_size) in rows {
+        let file = DuplicateFile { file_path, modified_time, content_type };
+        let same = |cluster: &&mut DuplicateCluster| {
+            let cluster_key = match matched_by {
+                DuplicateMatch::Hash => &cluster.file_hash,
+                DuplicateMatch::NameSize => &cluster.file_name,
+            };
+            cluster.file_size == file_size && *cluster_key == key
+        };
+        if let Some(cluster) = clusters.last_mut().filter(same) {
+            cluster.reclaimable_bytes += file_size;
+            cluster.files.push(file);
+            continue;
+        }
+        let (file_hash, file_name) = match matched_by {
+            DuplicateMatch::Hash => (key, None),
+            DuplicateMatch::NameSize => (None, key),
+        };
+        clusters.push(DuplicateCluster {
+            matched_by,
+            file_hash,
+            file_name,
+            file_size,
+            reclaimable_bytes: 0,
+            files: vec![file],
+        });
+    }
+    clusters
+}
+
+/// Deletes or links the listed copies of `keep`. Each must be a copy by the same
+/// rules `/duplicates` clusters them by, and only copies with the same hash can be
+/// linked. Deleted copies go to the trash, as with `/delete`.
+#[utoipa::path(
+    post, path = "/duplicates/resolve", tag = "files",
+    request_body = ResolveDuplicatesRequest,
+    responses(
+        (status = 200, description = "Per-path results", body = ResolveDuplicatesResponse),
+        (status = 400, description = "Invalid request", body = crate::openapi::ErrorBody),
+        (status = 404, description = "No such file to keep", body = crate::openapi::ErrorBody),
+    )
+)]
+pub(crate) async fn handle_resolve_duplicates(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Json(req): Json<ResolveDuplicatesRequest>,
+) -> Result<Response, AppError> {
+    let max_delete_batch = state.settings.current().max_delete_batch;
+    if req.remove.len() > max_delete_batch {
+        return Err(AppError::BadRequest(format!(
+            "At most {} copies can be resolved per request",
+            max_delete_batch
+        )));
+    }
+    if matches!(req.action, DuplicateAction::Link) && !state.config.dedup {
+        return Err(AppError::BadRequest("Linking copies needs deduplicated storage (STORAGE_DEDUP)".into()));
+    }
+    let keep = load_original(&state, user.user_id, &req.keep)
+        .await?
+        .ok_or_else(|| AppError::NotFound("File to keep not found".into()))?;
+
+    info!("RESOLVING {} COPIES OF {} ({:?})", req.remove.len(), req.keep, req.action);
+    let mut success = Vec::new();
+    let mut failure = Vec::new();
+    let mut reclaimed_bytes = 0;
+
+    for file_path in req.remove {
+        let outcome = async {
+            if file_path == req.keep {
+                return Err("is the copy being kept".to_string());
+            }
+            let copy = load_original(&state, user.user_id, &file_path)
+                .await
+                .map_err(|e| e.to_string())?
+                .ok_or_else(|| "file not found".to_string())?;
+            match copy_match(&keep, &copy) {
+                Some(DuplicateMatch::Hash) => {}
+                Some(DuplicateMatch::NameSize) if matches!(req.action, DuplicateAction::Delete) => {}
+                Some(DuplicateMatch::NameSize) => return Err("only copies with the same hash can be linked".into()),
+                None => return Err(format!("is not a copy of {}", req.keep)),
+            }
+            match req.action {
+                DuplicateAction::Delete => delete_file(&state, user.user_id, &file_path).await.map(|_| copy.file_size),
+                DuplicateAction::Link => link_copy(&state, &keep.system_path, &copy.system_path)
+                    .await
+                    .map(|linked| if linked { copy.file_size } else { 0 }),
+            }
+        }
+        .await;
+        match outcome {
+            Ok(size) => {
+                reclaimed_bytes += size;
+                success.push(file_path);
+            }
+            Err(error) => failure.push(FileFailure::new(file_path, error)),
+        }
+    }
+
+    // Linked copies keep their path and content, so only deletions are changes.
+    if matches!(req.action, DuplicateAction::Delete) {
+        publish_changes(
+            &state,
+            &user,
+            success
+                .iter()
+                .map(|file_path| FileChange {
+                    operation: Operation::Delete,
+                    file_path: file_path.clone(),
+                })
+                .collect(),
+        );
+    }
+
+    info!("RESOLVED: {} bytes", reclaimed_bytes);
+    Ok((StatusCode::OK, Json(ResolveDuplicatesResponse { success, failure, reclaimed_bytes })).into_response())
+}
+
+/// What deciding whether two files are copies takes.
+struct Original {
+    system_path: String,
+    file_hash: Option<String>,
+    file_name: Option<String>,
+    file_size: i64,
+}
+
+async fn load_original(state: &AppState, user_id: i32, file_path: &str) -> Result<Option<Original>, sqlx::Error> {
+    let row = on_db!(&state.pool, pool => sqlx::query_as::<_, (String, Option<String>, Option<String>, i64)>(
+        "SELECT system_path, file_hash, file_name, file_size FROM filehash WHERE user_id = $1 AND file_path = $2"
+    )
+    .bind(user_id)
+    .bind(file_path)
+    .fetch_optional(pool)
+    .await)?;
+    Ok(row.map(|(system_path, file_hash, file_name, file_size)| Original {
+        system_path,
+        file_hash: file_hash.filter(|hash| !hash.is_empty()),
+        file_name,
+        file_size,
+    }))
+}
+
+/// How `copy` matches `keep`, by the rules `/duplicates` clusters by.
+fn copy_match(keep: &Original, copy: &Original) -> Option<DuplicateMatch> {
+    if keep.file_size != copy.file_size {
+        return None;
+    }
+    match (&keep.file_hash, &copy.file_hash) {
+        (Some(a), Some(b)) => (a == b).then_some(DuplicateMatch::Hash),
+        (None, None) => (keep.file_name.is_some() && keep.file_name == copy.file_name).then_some(DuplicateMatch::NameSize),
+        _ => None,
+    }
+}
+
+/// Points the copy's key at the kept file's content. Deduplicated storage makes
+/// that a second reference to the same blob, releasing the one the copy held.
+/// Returns whether it linked anything: copies whose stored content the server
+/// hashed the same already refer to one blob.
+async fn link_copy(state: &AppState, keep: &str, copy: &str) -> Result<bool, String> {
+    // Client-supplied hashes can collide by mistake; the server's can't.
+    let (kept, copied) = tokio::try_join!(state.storage.sha256(keep), state.storage.sha256(copy))?;
+    if let (Some(kept), Some(copied)) = (kept, copied) {
+        if kept != copied {
+            return Err("stored content differs from the kept copy".into());
+        }
+        return Ok(false);
+    }
+    state.storage.copy(keep, copy).await.map(|_| true).map_err(String::from)
+}
@@ -0,0 +1,108 @@
+use std::convert::Infallible;
+
+use axum::{
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        Extension, Query, State,
+    },
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        Response,
+    },
+};
+use tokio::sync::broadcast;
+use tokio_stream::{wrappers::BroadcastStream, Stream, StreamExt};
+use tracing::{info, warn};
+
+use crate::{
+    models::{AuthUser, WsParams},
+    AppState,
+};
+
+#[utoipa::path(
+    get, path = "/events", tag = "sync",
+    responses((status = 200, description = "Server-sent `sync` events", content_type = "text/event-stream", body = crate::models::SyncEvent))
+)]
+pub(crate) async fn handle_events(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    info!("EVENTS SUBSCRIBED");
+    let closed = state.shutdown.clone().cancelled_owned();
+    let stream = BroadcastStream::new(state.events.subscribe()).filter_map(move |msg| match msg {
+        Ok(event) if event.user_id != user.user_id => None,
+        Ok(event) => match Event::default().event("sync").json_data(&event) {
+            Ok(sse) => Some(Ok(sse)),
+            Err(e) => {
+                warn!("Failed to encode sync event: {}", e);
+                None
+            }
+        },
+        // The subscriber fell behind; drop the missed events and keep going.
+        Err(e) => {
+            warn!("Events subscriber lagged: {}", e);
+            None
+        }
+    });
+    let stream = futures::StreamExt::take_until(stream, closed);
+
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
+
+/// Pushes the user's change events over a WebSocket as they happen, skipping the
+/// ones made by this connection's own `device_id`.
+#[utoipa::path(
+    get, path = "/ws", tag = "sync",
+    params(WsParams),
+    responses((status = 101, description = "WebSocket of `sync` events"))
+)]
+pub(crate) async fn handle_ws(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Query(params): Query<WsParams>,
+    ws: WebSocketUpgrade,
+) -> Response {
+    let device_id = params.device_id.or(user.device_id.clone());
+    ws.on_upgrade(move |socket| push_events(socket, state, user, device_id))
+}
+
+async fn push_events(mut socket: WebSocket, state: AppState, user: AuthUser, device_id: Option<String>) {
+    info!(user_id = user.user_id, "WEBSOCKET CONNECTED");
+    let mut events = state.events.subscribe();
+
+    loop {
+        tokio::select! {
+            event = events.recv() => match event {
+                Ok(event) if event.user_id != user.user_id => continue,
+                Ok(event) if device_id.is_some() && event.device_id == device_id => continue,
+                Ok(event) => {
+                    let text = match serde_json::to_string(&event) {
+                        Ok(text) => text,
+                        Err(e) => {
+                            warn!("Failed to encode sync event: {}", e);
+                            continue;
+                        }
+                    };
+                    if socket.send(Message::Text(text.into())).await.is_err() {
+                        break;
+                    }
+                }
+                // Missed events can't be replayed here; clients catch up through `/changes`.
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    warn!("WebSocket subscriber lagged by {} events", missed);
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+            incoming = socket.recv() => match incoming {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => {}
+            },
+            _ = state.shutdown.cancelled() => {
+                let _ = socket.send(Message::Close(None)).await;
+                break;
+            }
+        }
+    }
+
+    info!(user_id = user.user_id, "WEBSOCKET DISCONNECTED");
+}
@@ -0,0 +1,422 @@
+//! File request links. `POST /filedrop` opens a link to one of the caller's
+//! folders, and anyone holding it can upload files there from a plain form at
+//! `/drop/{token}` until it expires. Dropped files never replace the owner's: one
+//! whose name is taken is stored as `name (2).ext`, and so on. Each is tagged
+//! `filedrop`, with the name its sender gave in its `dropped_by` metadata.
+
+use std::{collections::BTreeMap, fmt::Write, io, net::SocketAddr};
+
+use axum::{
+    body::{Body, Bytes},
+    extract::{multipart::Field, ConnectInfo, Extension, Multipart, Path, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use sqlx::types::Json as SqlJson;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::{info, warn};
+
+use crate::{
+    auth::hash_token,
+    db::on_db,
+    error::AppError,
+    handlers::{
+        dav::escape_xml,
+        files::trim_slashes,
+        gallery::{format_size, html_response, page},
+        sync::put_file,
+    },
+    models::{AuthUser, CreateFileDropRequest, FileDrop, FileDropLink, FileEntry, Role, TokenScope},
+    paths::normalize_path,
+    AppState, MAX_DROPPER_NAME_LEN, MAX_FILEDROP_EXPIRY_SECS,
+};
+
+/// Tag every dropped file carries.
+const FILEDROP_TAG: &str = "filedrop";
+
+/// Numbered names tried for a dropped file before giving up on it.
+const MAX_NAME_ATTEMPTS: u32 = 100;
+
+/// Opens a link anyone can upload files into the caller's `folder` with, until
+/// it expires. The plaintext token is only returned here.
+#[utoipa::path(
+    post, path = "/filedrop", tag = "files",
+    request_body = CreateFileDropRequest,
+    responses(
+        (status = 201, description = "The new link", body = crate::openapi::FileDropCreated),
+        (status = 400, description = "Invalid request", body = crate::openapi::ErrorBody),
+    )
+)]
+pub(crate) async fn handle_create_filedrop(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Json(req): Json<CreateFileDropRequest>,
+) -> Result<Response, AppError> {
+    if !(1..=MAX_FILEDROP_EXPIRY_SECS).contains(&req.expires_in_secs) {
+        return Err(AppError::BadRequest(format!(
+            "expires_in_secs must be between 1 and {}",
+            MAX_FILEDROP_EXPIRY_SECS
+        )));
+    }
+    if req.max_file_bytes.is_some_and(|max| max <= 0) || req.max_files.is_some_and(|max| max <= 0) {
+        return Err(AppError::BadRequest("max_file_bytes and max_files must be positive".into()));
+    }
+    let folder = normalize_path(trim_slashes(&req.folder));
+
+    let token = hex::encode(rand::random::<[u8; 32]>());
+    let sql = state.pool.sql(
+        r#"
+        INSERT INTO filedrops (user_id, folder, token_hash, expires_at, max_file_bytes, max_files)
+        VALUES ($1, $2, $3, CURRENT_TIMESTAMP + make_interval(secs => $4), $5, $6)
+        RETURNING id, expires_at
+        "#,
+        r#"
+        INSERT INTO filedrops (user_id, folder, token_hash, expires_at, max_file_bytes, max_files)
+        VALUES ($1, $2, $3, datetime('now', $4 || ' seconds'), $5, $6)
+        RETURNING id, expires_at
+        "#,
+    );
+    let (id, expires_at) = on_db!(&state.pool, pool => sqlx::query_as::<_, (i32, chrono::NaiveDateTime)>(sql)
+    .bind(user.user_id)
+    .bind(&folder)
+    .bind(hash_token(&token))
+    .bind(req.expires_in_secs as f64)
+    .bind(req.max_file_bytes)
+    .bind(req.max_files)
+    .fetch_one(pool)
+    .await)?;
+
+    info!(user_id = user.user_id, "Opened file drop {} into {:?}", id, folder);
+    Ok((StatusCode::CREATED, Json(serde_json::json!({
+        "id": id,
+        "url": format!("{}/drop/{}", state.config.public_base_url.trim_end_matches('/'), token),
+        "token": token,
+        "folder": folder,
+        "expires_at": expires_at,
+        "max_file_bytes": req.max_file_bytes,
+        "max_files": req.max_files
+    }))).into_response())
+}
+
+#[utoipa::path(
+    get, path = "/filedrop", tag = "files",
+    summary = "Lists the caller's file request links",
+    responses((status = 200, description = "The caller's links, newest first", body = crate::openapi::Data<Vec<FileDrop>>))
+)]
+pub(crate) async fn handle_list_filedrops(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+) -> Result<Response, AppError> {
+    let drops = on_db!(&state.pool, pool => sqlx::query_as::<_, FileDrop>(
+        r#"
+        SELECT id, folder, created_at, expires_at, revoked_at, max_file_bytes, max_files, file_count
+        FROM filedrops
+        WHERE user_id = $1
+        ORDER BY id DESC
+        "#
+    )
+    .bind(user.user_id)
+    .fetch_all(pool)
+    .await)?;
+
+    Ok((StatusCode::OK, Json(serde_json::json!({ "data": drops }))).into_response())
+}
+
+/// Closes one of the caller's file request links; files already dropped stay.
+#[utoipa::path(
+    delete, path = "/filedrop/{id}", tag = "files",
+    params(("id" = i32, Path)),
+    responses(
+        (status = 204, description = "Revoked"),
+        (status = 404, description = "No such link", body = crate::openapi::ErrorBody),
+    )
+)]
+pub(crate) async fn handle_revoke_filedrop(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Path(id): Path<i32>,
+) -> Result<Response, AppError> {
+    let revoked = on_db!(&state.pool, pool => sqlx::query(
+        "UPDATE filedrops SET revoked_at = CURRENT_TIMESTAMP WHERE id = $1 AND user_id = $2 AND revoked_at IS NULL"
+    )
+    .bind(id)
+    .bind(user.user_id)
+    .execute(pool)
+    .await
+    .map(|r| r.rows_affected()))?;
+
+    if revoked == 0 {
+        return Err(AppError::NotFound("File drop not found".into()));
+    }
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+/// The form files are dropped with.
+pub(crate) async fn handle_filedrop_page(State(state): State<AppState>, Path(token): Path<String>) -> Response {
+    let drop = match find_filedrop(&state, &token).await {
+        Ok(drop) => drop,
+        Err(e) => return error_page(e),
+    };
+    let mut limits = String::new();
+    if let Some(max) = drop.max_file_bytes {
+        let _ = write!(limits, "<p>Files up to {} each.</p>", format_size(max));
+    }
+    if let Some(max) = drop.max_files {
+        let _ = write!(limits, "<p>{} more files can be sent.</p>", max - drop.file_count);
+    }
+    let body = format!(
+        "<h1>Send files</h1>{}\
+         <form method=\"post\" enctype=\"multipart/form-data\">\
+         <p><input name=\"name\" maxlength=\"{}\" placeholder=\"Your name\" required></p>\
+         <p><input type=\"file\" name=\"file\" multiple required></p>\
+         <p><button>Upload</button></p></form>",
+        limits, MAX_DROPPER_NAME_LEN
+    );
+    html_response(StatusCode::OK, page("Send files", &body))
+}
+
+/// Stores the files of a `multipart/form-data` body in the link's folder: a
+/// `name` part naming the sender, then `file` parts, with their file names.
+/// Browsers are answered with a page, and other clients with the files stored.
+pub(crate) async fn handle_filedrop_upload(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(token): Path<String>,
+    headers: HeaderMap,
+    multipart: Multipart,
+) -> Response {
+    let wants_html = headers
+        .get(header::ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|accept| accept.contains("text/html"));
+    match receive_files(&state, addr, &token, multipart).await {
+        Ok(files) if wants_html => {
+            let mut list = String::new();
+            for file in &files {
+                let _ = write!(
+                    list,
+                    "<li>{}<span class=\"size\">{}</span></li>",
+                    escape_xml(&file.file_name),
+                    format_size(file.file_size)
+                );
+            }
+            let body = format!("<h1>Thank you</h1><p>These files were sent:</p><ul>{}</ul>", list);
+            html_response(StatusCode::CREATED, page("Files sent", &body))
+        }
+        Ok(files) => {
+            let files: Vec<_> = files
+                .iter()
+                .map(|file| serde_json::json!({ "file_name": file.file_name, "file_size": file.file_size }))
+                .collect();
+            (StatusCode::CREATED, Json(serde_json::json!({ "data": files }))).into_response()
+        }
+        Err(e) if wants_html => error_page(e),
+        Err(e) => e.into_response(),
+    }
+}
+
+/// The link `token` opens, failing with 404 when there's none and 410 when it was
+/// revoked, has expired, or has taken all the files it takes.
+async fn find_filedrop(state: &AppState, token: &str) -> Result<FileDropLink, AppError> {
+    let drop = on_db!(&state.pool, pool => sqlx::query_as::<_, FileDropLink>(
+        r#"
+        SELECT id, user_id, folder, max_file_bytes, max_files, file_count,
+               revoked_at IS NOT NULL OR expires_at <= CURRENT_TIMESTAMP AS expired
+        FROM filedrops
+        WHERE token_hash = $1
+        "#
+    )
+    .bind(hash_token(token))
+    .fetch_optional(pool)
+    .await)?
+    .ok_or_else(|| AppError::NotFound("File drop not found".into()))?;
+
+    if drop.expired {
+        return Err(AppError::Gone("This link has expired or been revoked".into()));
+    }
+    if drop.max_files.is_some_and(|max| drop.file_count >= max) {
+        return Err(AppError::Gone("This link takes no more files".into()));
+    }
+    Ok(drop)
+}
+
+/// Stores each `file` part as one of the owner's files, returning them in order.
+async fn receive_files(
+    state: &AppState,
+    addr: SocketAddr,
+    token: &str,
+    mut multipart: Multipart,
+) -> Result<Vec<FileEntry>, AppError> {
+    let drop = find_filedrop(state, token).await?;
+    let (username, role) = on_db!(&state.pool, pool => sqlx::query_as::<_, (String, Role)>(
+        "SELECT username, role FROM users WHERE id = $1"
+    )
+    .bind(drop.user_id)
+    .fetch_one(pool)
+    .await)?;
+    let owner = AuthUser {
+        user_id: drop.user_id,
+        username,
+        role,
+        scope: TokenScope::Upload,
+        device_id: None,
+        ip: Some(addr.ip()),
+    };
+
+    let mut dropped_by = None;
+    let mut files = Vec::new();
+    while let Some(field) = multipart
+        .next_field()
+        .await
+        .map_err(|e| AppError::BadRequest(format!("Malformed multipart body: {}", e)))?
+    {
+        match field.name() {
+            Some("name") => {
+                let name = field
+                    .text()
+                    .await
+                    .map_err(|e| AppError::BadRequest(format!("Malformed multipart body: {}", e)))?;
+                let name: String = name.trim().chars().filter(|c| !c.is_control()).take(MAX_DROPPER_NAME_LEN).collect();
+                dropped_by = Some(name).filter(|name| !name.is_empty());
+            }
+            Some("file") => {
+                // Browsers send an empty part when no file was picked.
+                let Some(name) = field.file_name().and_then(file_name) else { continue };
+                let dropped_by = dropped_by.clone().unwrap_or_else(|| "anonymous".to_string());
+                files.push(store_dropped_file(state, &owner, &drop, name, dropped_by, field).await?);
+            }
+            _ => {}
+        }
+    }
+
+    if files.is_empty() {
+        return Err(AppError::BadRequest("No files were sent".into()));
+    }
+    info!(user_id = owner.user_id, "Received {} files through file drop {}", files.len(), drop.id);
+    Ok(files)
+}
+
+/// Stores one dropped file under a name none of the owner's files has, once the
+/// link has room for it.
+async fn store_dropped_file(
+    state: &AppState,
+    owner: &AuthUser,
+    drop: &FileDropLink,
+    name: String,
+    dropped_by: String,
+    field: Field<'_>,
+) -> Result<FileEntry, AppError> {
+    let claimed = on_db!(&state.pool, pool => sqlx::query(
+        r#"
+        UPDATE filedrops SET file_count = file_count + 1
+        WHERE id = $1 AND (max_files IS NULL OR file_count < max_files)
+            AND revoked_at IS NULL AND expires_at > CURRENT_TIMESTAMP
+        "#
+    )
+    .bind(drop.id)
+    .execute(pool)
+    .await
+    .map(|r| r.rows_affected()))?;
+    if claimed == 0 {
+        return Err(AppError::Gone("This link takes no more files".into()));
+    }
+
+    let stored = async {
+        let file_path = free_path(state, owner.user_id, &drop.folder, &name).await?;
+        let content_type = field.content_type().unwrap_or_default().to_string();
+        let file = FileEntry {
+            file_name: file_path.rsplit('/').next().unwrap_or(&file_path).to_string(),
+            file_path,
+            modified_time: chrono::Utc::now().timestamp(),
+            tags: Some(vec![FILEDROP_TAG.to_string()]),
+            metadata: Some(SqlJson(BTreeMap::from([("dropped_by".to_string(), dropped_by)]))),
+            ..FileEntry::default()
+        };
+
+        let (tx, rx) = mpsc::channel(16);
+        let feed = relay(field, tx, drop.max_file_bytes);
+        let upload = put_file(state, owner, file, &content_type, Body::from_stream(ReceiverStream::new(rx)));
+        let (fed, stored) = tokio::join!(feed, upload);
+        fed?;
+        stored.map_err(|_| AppError::BadGateway("Failed to store the file".into()))
+    }
+    .await;
+
+    if stored.is_err() {
+        release_slot(state, drop.id).await;
+    }
+    stored
+}
+
+/// Gives back the slot of a file that wasn't stored.
+async fn release_slot(state: &AppState, id: i32) {
+    let released = on_db!(&state.pool, pool => sqlx::query("UPDATE filedrops SET file_count = file_count - 1 WHERE id = $1")
+        .bind(id)
+        .execute(pool)
+        .await
+        .map(|_| ()));
+    if let Err(e) = released {
+        warn!("Failed to release a slot of file drop {}: {}", id, e);
+    }
+}
+
+/// Passes the part on to the upload, failing it too once it passes `max_bytes`.
+async fn relay(mut field: Field<'_>, tx: mpsc::Sender<Result<Bytes, io::Error>>, max_bytes: Option<i64>) -> Result<(), AppError> {
+    let mut received = 0;
+    let fed = loop {
+        match field.chunk().await {
+            Ok(Some(bytes)) => {
+                received += bytes.len() as i64;
+                if let Some(max) = max_bytes && received > max {
+                    break Err(AppError::PayloadTooLarge(format!("Files sent here are limited to {} bytes", max)));
+                }
+                if tx.send(Ok(bytes)).await.is_err() {
+                    break Ok(());
+                }
+            }
+            Ok(None) => break Ok(()),
+            Err(e) => break Err(AppError::BadRequest(format!("Malformed multipart body: {}", e))),
+        }
+    };
+    if let Err(e) = &fed {
+        let _ = tx.send(Err(io::Error::other(e.message().to_string()))).await;
+    }
+    fed
+}
+
+/// The last segment of a file name a browser sent, or `None` when it names nothing.
+fn file_name(name: &str) -> Option<String> {
+    let name = normalize_path(name.rsplit(['/', '\\']).next().unwrap_or(name));
+    Some(name).filter(|name| !name.is_empty() && name != "." && name != "..")
+}
+
+/// `name` in `folder`, numbered `name (2).ext` and on when the owner has a file there.
+async fn free_path(state: &AppState, user_id: i32, folder: &str, name: &str) -> Result<String, AppError> {
+    let (stem, ext) = match name.rfind('.') {
+        Some(i) if i > 0 => name.split_at(i),
+        _ => (name, ""),
+    };
+    for attempt in 1..=MAX_NAME_ATTEMPTS {
+        let name = if attempt == 1 { name.to_string() } else { format!("{} ({}){}", stem, attempt, ext) };
+        let path = if folder.is_empty() { name } else { format!("{}/{}", folder, name) };
+        let taken = on_db!(&state.pool, pool => sqlx::query_scalar::<_, bool>(
+            "SELECT EXISTS (SELECT 1 FROM filehash WHERE user_id = $1 AND file_path = $2)"
+        )
+        .bind(user_id)
+        .bind(&path)
+        .fetch_one(pool)
+        .await)?;
+        if !taken {
+            return Ok(path);
+        }
+    }
+    Err(AppError::Conflict(format!("Too many files named {} were sent already", name)))
+}
+
+fn error_page(err: AppError) -> Response {
+    let status = err.status();
+    let body = format!("<h1>{}</h1><p>{}</p>", status, escape_xml(err.message()));
+    html_response(status, page("Send files", &body))
+}
@@ -0,0 +1,464 @@
+use std::collections::HashSet;
+
+use axum::{
+    extract::{Extension, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use sqlx::Connection;
+use tracing::info;
+
+use crate::{
+    db::{on_db, record_tombstone, Array, Db, DbConn},
+    error::AppError,
+    events::publish_changes,
+    handlers::{
+        jobs::delete_or_retry,
+        listing::escape_like,
+        versions::{copy_object, purge_versions},
+    },
+    models::{
+        AuthUser, BatchDeleteRequest, BatchDeleteResponse, FileChange, FileEntry, FileFailure,
+        MoveRequest, Operation, RenameRequest,
+    },
+    paths::spell_move,
+    AppState, FILE_NOT_FOUND_MESSAGE, TRASH_PREFIX,
+};
+
+/// What `remove_file_row` took out of `filehash`, for `finish_removal` to clean up
+/// once the removal is committed.
+pub(crate) struct RemovedFile {
+    system_path: String,
+    /// Where the content was copied when it went to the trash.
+    pub(crate) trash_key: Option<String>,
+}
+
+/// Removes the user's file at `file_path` and its stored revisions. Shared by the
+/// sync `Delete` operation and `/delete`. The content goes to the trash unless the
+/// trash is turned off.
+pub(crate) async fn delete_file(state: &AppState, user_id: i32, file_path: &str) -> Result<(), String> {
+    let mut conn = state.pool.acquire().await.map_err(|e| e.to_string())?;
+    let removed = remove_file_row(state, conn.as_conn(), user_id, file_path, None).await?;
+    drop(conn);
+    finish_removal(state, user_id, file_path, removed).await;
+    Ok(())
+}
+
+/// The database half of `delete_file`: drops the row, moving it into `trash` when
+/// the trash is on, and records the tombstone. Storage is left for `finish_removal`.
+/// With a `base_version`, a row at any other version is left alone as if missing.
+pub(crate) async fn remove_file_row(
+    state: &AppState,
+    mut conn: DbConn<'_>,
+    user_id: i32,
+    file_path: &str,
+    base_version: Option<i64>,
+) -> Result<RemovedFile, String> {
+    let removed = if state.settings.current().trash_retention_days > 0 {
+        move_to_trash(state, conn.as_conn(), user_id, file_path, base_version).await?
+    } else {
+        delete_row(conn.as_conn(), user_id, file_path, base_version).await?
+    };
+
+    record_tombstone(conn, user_id, file_path).await;
+    Ok(removed)
+}
+
+/// Deletes the user's file at `file_path` for good, bypassing the trash, along with
+/// its stored revisions. Lets admins remove content the owner could otherwise restore.
+pub(crate) async fn purge_file(state: &AppState, user_id: i32, file_path: &str) -> Result<(), String> {
+    let mut conn = state.pool.acquire().await.map_err(|e| e.to_string())?;
+    let removed = delete_row(conn.as_conn(), user_id, file_path, None).await?;
+    record_tombstone(conn.as_conn(), user_id, file_path).await;
+    drop(conn);
+    finish_removal(state, user_id, file_path, removed).await;
+    Ok(())
+}
+
+async fn delete_row(conn: DbConn<'_>, user_id: i32, file_path: &str, base_version: Option<i64>) -> Result<RemovedFile, String> {
+    let system_path = on_db!(conn, conn => sqlx::query_scalar::<_, String>(
+        r#"
+        DELETE FROM filehash
+        WHERE user_id = $1 AND file_path = $2 AND (CAST($3 AS BIGINT) IS NULL OR version = $3)
+        RETURNING system_path
+        "#
+    )
+    .bind(user_id)
+    .bind(file_path)
+    .bind(base_version)
+    .fetch_optional(conn)
+    .await)
+    .map_err(|e| e.to_string())?
+    .ok_or_else(|| FILE_NOT_FOUND_MESSAGE.to_string())?;
+    Ok(RemovedFile { system_path, trash_key: None })
+}
+
+/// The storage half of `delete_file`, run once the row is gone for good. The file is
+/// deleted as far as the client can tell, so a failed storage delete is retried in
+/// the background instead of being reported.
+pub(crate) async fn finish_removal(state: &AppState, user_id: i32, file_path: &str, removed: RemovedFile) {
+    delete_or_retry(state, &removed.system_path).await;
+    purge_versions(state, user_id, file_path).await;
+}
+
+/// Copies the file's object under `trash/`, then moves its row from `filehash` into
+/// `trash` in one statement, so a failure part way never loses the only copy.
+async fn move_to_trash(
+    state: &AppState,
+    mut conn: DbConn<'_>,
+    user_id: i32,
+    file_path: &str,
+    base_version: Option<i64>,
+) -> Result<RemovedFile, String> {
+    let system_path = on_db!(conn.as_conn(), conn => sqlx::query_scalar::<_, String>(
+        "SELECT system_path FROM filehash WHERE user_id = $1 AND file_path = $2 AND (CAST($3 AS BIGINT) IS NULL OR version = $3)"
+    )
+    .bind(user_id)
+    .bind(file_path)
+    .bind(base_version)
+    .fetch_optional(conn)
+    .await)
+    .map_err(|e| e.to_string())?
+    .ok_or_else(|| FILE_NOT_FOUND_MESSAGE.to_string())?;
+
+    let trash_key = format!("{}/{}/{}", TRASH_PREFIX, hex::encode(rand::random::<[u8; 8]>()), system_path);
+    copy_object(state, &system_path, &trash_key)
+        .await
+        .map_err(|e| format!("File delete failed: {}", e))?;
+
+    let moved = match conn {
+        Db::Postgres(conn) => sqlx::query(
+            r#"
+            WITH removed AS (
+                DELETE FROM filehash
+                WHERE user_id = $1 AND file_path = $2 AND system_path = $3 AND (CAST($5 AS BIGINT) IS NULL OR version = $5)
+                RETURNING user_id, file_path, file_hash, file_size, modified_time, content_type, etag, system_path, created_at, file_name,
+                          metadata, ARRAY(SELECT tag FROM file_tags WHERE file_id = filehash.id ORDER BY tag) AS tags
+            )
+            INSERT INTO trash (user_id, file_path, file_hash, file_size, modified_time, content_type, etag, system_path, created_at, file_name, metadata, tags, trash_key)
+            SELECT user_id, file_path, file_hash, file_size, modified_time, content_type, etag, system_path, created_at, file_name, metadata, tags, $4
+            FROM removed
+            "#
+        )
+        .bind(user_id)
+        .bind(file_path)
+        .bind(&system_path)
+        .bind(&trash_key)
+        .bind(base_version)
+        .execute(conn)
+        .await
+        .map(|r| r.rows_affected()),
+        // SQLite has no data-modifying CTEs: copy the row, then delete it, in a
+        // transaction of their own.
+        Db::Sqlite(conn) => async {
+            let mut tx = conn.begin().await?;
+            sqlx::query(
+                r#"
+                INSERT INTO trash (user_id, file_path, file_hash, file_size, modified_time, content_type, etag, system_path, created_at, file_name, metadata, tags, trash_key)
+                SELECT user_id, file_path, file_hash, file_size, modified_time, content_type, etag, system_path, created_at, file_name, metadata,
+                       (SELECT json_group_array(tag) FROM (SELECT tag FROM file_tags WHERE file_id = filehash.id ORDER BY tag)), $4
+                FROM filehash
+                WHERE user_id = $1 AND file_path = $2 AND system_path = $3 AND (CAST($5 AS BIGINT) IS NULL OR version = $5)
+                "#
+            )
+            .bind(user_id)
+            .bind(file_path)
+            .bind(&system_path)
+            .bind(&trash_key)
+            .bind(base_version)
+            .execute(&mut *tx)
+            .await?;
+            let deleted = sqlx::query(
+                "DELETE FROM filehash WHERE user_id = $1 AND file_path = $2 AND system_path = $3 AND (CAST($4 AS BIGINT) IS NULL OR version = $4)",
+            )
+            .bind(user_id)
+            .bind(file_path)
+            .bind(&system_path)
+            .bind(base_version)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+            tx.commit().await?;
+            Ok(deleted)
+        }
+        .await,
+    };
+
+    match moved {
+        Ok(deleted) if deleted > 0 => Ok(RemovedFile { system_path, trash_key: Some(trash_key) }),
+        outcome => {
+            delete_or_retry(state, &trash_key).await;
+            Err(match outcome {
+                Err(e) => e.to_string(),
+                Ok(_) => FILE_NOT_FOUND_MESSAGE.into(),
+            })
+        }
+    }
+}
+
+/// Strips the slashes around a folder or file path, so `a/b/`, `/a/b` and `a/b` agree.
+pub(crate) fn trim_slashes(path: &str) -> &str {
+    path.trim_matches('/')
+}
+
+#[utoipa::path(
+    post, path = "/move", tag = "files",
+    request_body = MoveRequest,
+    responses(
+        (status = 200, description = "The moved files at their new paths", body = crate::openapi::FileList),
+        (status = 400, description = "Invalid request", body = crate::openapi::ErrorBody),
+        (status = 404, description = "No such file", body = crate::openapi::ErrorBody),
+        (status = 409, description = "Something already exists at the destination", body = crate::openapi::ErrorBody),
+    )
+)]
+pub(crate) async fn handle_move(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Json(req): Json<MoveRequest>,
+) -> Result<Response, AppError> {
+    move_path(&state, &user, trim_slashes(&req.from), trim_slashes(&req.to)).await
+}
+
+#[utoipa::path(
+    post, path = "/rename", tag = "files",
+    request_body = RenameRequest,
+    responses(
+        (status = 200, description = "The renamed files at their new paths", body = crate::openapi::FileList),
+        (status = 400, description = "Invalid request", body = crate::openapi::ErrorBody),
+        (status = 404, description = "No such file", body = crate::openapi::ErrorBody),
+        (status = 409, description = "Something already exists at the destination", body = crate::openapi::ErrorBody),
+    )
+)]
+pub(crate) async fn handle_rename(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Json(req): Json<RenameRequest>,
+) -> Result<Response, AppError> {
+    if req.new_name.is_empty() || req.new_name.contains('/') {
+        return Err(AppError::BadRequest("new_name must be a single, non-empty path segment".into()));
+    }
+
+    let from = trim_slashes(&req.file_path);
+    let to = match from.rsplit_once('/') {
+        Some((parent, _)) => format!("{}/{}", parent, req.new_name),
+        None => req.new_name.clone(),
+    };
+    move_path(&state, &user, from, &to).await
+}
+
+async fn move_path(state: &AppState, user: &AuthUser, from: &str, to: &str) -> Result<Response, AppError> {
+    let (from, to) = spell_move(state, user.user_id, from, to).await?;
+    let rows = move_files(state, user, &from, &to).await?;
+    Ok((StatusCode::OK, Json(serde_json::json!({ "data": rows }))).into_response())
+}
+
+/// Re-points the file at `from`, and every file and folder under the folder `from`,
+/// at the same place under `to`, in one transaction. Only paths change: the objects
+/// keep their storage keys, and revisions move along with their file. Returns the
+/// moved files at their new paths.
+pub(crate) async fn move_files(state: &AppState, user: &AuthUser, from: &str, to: &str) -> Result<Vec<FileEntry>, AppError> {
+    let mut tx = state.pool.begin().await?;
+    let rows = move_rows(tx.as_conn(), user.user_id, from, to).await?;
+    tx.commit().await?;
+
+    info!("MOVED {} FILES FROM {} TO {}", rows.len(), from, to);
+    let changes = rows
+        .iter()
+        .flat_map(|row| [
+            FileChange {
+                operation: Operation::Delete,
+                file_path: format!("{}{}", from, &row.file_path[to.len()..]),
+            },
+            FileChange {
+                operation: Operation::Insert,
+                file_path: row.file_path.clone(),
+            },
+        ])
+        .collect();
+    publish_changes(state, user, changes);
+    Ok(rows)
+}
+
+/// The database half of `move_files`, run over `conn` so the caller decides what
+/// transaction it is part of.
+pub(crate) async fn move_rows(mut conn: DbConn<'_>, user_id: i32, from: &str, to: &str) -> Result<Vec<FileEntry>, AppError> {
+    if from.is_empty() || to.is_empty() {
+        return Err(AppError::BadRequest("Both paths must be non-empty".into()));
+    }
+    if to == from || to.starts_with(&format!("{}/", from)) {
+        return Err(AppError::BadRequest("Cannot move a path onto itself or into its own folder".into()));
+    }
+
+    let children = format!("{}/%", escape_like(from));
+    let rest_from = from.chars().count() as i32 + 1;
+
+    let moved: Result<(Vec<FileEntry>, u64), sqlx::Error> = async {
+        let rows = on_db!(conn.as_conn(), conn => sqlx::query_as::<_, FileEntry>(
+            r#"
+            UPDATE filehash
+            SET file_path = $3 || substr(file_path, $4), updated_at = CURRENT_TIMESTAMP,
+                version = 1 + (
+                    SELECT CASE WHEN MAX(p.version) > filehash.version THEN MAX(p.version) ELSE filehash.version END
+                    FROM path_versions p WHERE p.user_id = $1 AND p.file_path = $3 || substr(filehash.file_path, $4)
+                )
+            WHERE user_id = $1 AND (file_path = $2 OR file_path LIKE $5 ESCAPE '\')
+            RETURNING file_path, file_hash, file_size, modified_time, system_path AS file_name, content_type, etag, created_at, updated_at, version
+            "#
+        )
+        .bind(user_id)
+        .bind(from)
+        .bind(to)
+        .bind(rest_from)
+        .bind(&children)
+        .fetch_all(conn)
+        .await)?;
+        let folders = on_db!(conn.as_conn(), conn => sqlx::query(
+            r#"
+            UPDATE folders
+            SET path = $3 || substr(path, $4)
+            WHERE user_id = $1 AND (path = $2 OR path LIKE $5 ESCAPE '\')
+            "#
+        )
+        .bind(user_id)
+        .bind(from)
+        .bind(to)
+        .bind(rest_from)
+        .bind(&children)
+        .execute(conn)
+        .await
+        .map(|r| r.rows_affected()))?;
+        if rows.is_empty() {
+            return Ok((rows, folders));
+        }
+
+        on_db!(conn.as_conn(), conn => sqlx::query(
+            r#"
+            UPDATE file_versions
+            SET file_path = $3 || substr(file_path, $4)
+            WHERE user_id = $1 AND (file_path = $2 OR file_path LIKE $5 ESCAPE '\')
+            "#
+        )
+        .bind(user_id)
+        .bind(from)
+        .bind(to)
+        .bind(rest_from)
+        .bind(&children)
+        .execute(conn)
+        .await
+        .map(|_| ()))?;
+
+        on_db!(conn.as_conn(), conn => sqlx::query(
+            r#"
+            UPDATE shares
+            SET file_path = $3 || substr(file_path, $4)
+            WHERE user_id = $1 AND (file_path = $2 OR file_path LIKE $5 ESCAPE '\')
+            "#
+        )
+        .bind(user_id)
+        .bind(from)
+        .bind(to)
+        .bind(rest_from)
+        .bind(&children)
+        .execute(conn)
+        .await
+        .map(|_| ()))?;
+
+        let (old_paths, new_paths): (Vec<String>, Vec<String>) = rows
+            .iter()
+            .map(|row| (format!("{}{}", from, &row.file_path[to.len()..]), row.file_path.clone()))
+            .unzip();
+        // A path vacated by one file can be taken by another in the same move.
+        let taken: HashSet<&String> = new_paths.iter().collect();
+        let vacated: Vec<&String> = old_paths.iter().filter(|path| !taken.contains(path)).collect();
+        let sql = conn.sql(
+            "DELETE FROM tombstones WHERE user_id = $1 AND file_path = ANY($2)",
+            "DELETE FROM tombstones WHERE user_id = $1 AND file_path IN (SELECT value FROM json_each($2))",
+        );
+        on_db!(conn.as_conn(), conn => sqlx::query(sql)
+            .bind(user_id)
+            .bind(Array(&new_paths))
+            .execute(conn)
+            .await
+            .map(|_| ()))?;
+        let sql = conn.sql(
+            r#"
+            INSERT INTO tombstones (user_id, file_path)
+            SELECT $1, UNNEST($2::TEXT[])
+            ON CONFLICT (user_id, file_path) DO UPDATE SET deleted_at = CURRENT_TIMESTAMP
+            "#,
+            r#"
+            INSERT INTO tombstones (user_id, file_path)
+            SELECT $1, value FROM json_each($2) WHERE true
+            ON CONFLICT (user_id, file_path) DO UPDATE SET deleted_at = CURRENT_TIMESTAMP
+            "#,
+        );
+        on_db!(conn.as_conn(), conn => sqlx::query(sql)
+            .bind(user_id)
+            .bind(Array(&vacated))
+            .execute(conn)
+            .await
+            .map(|_| ()))?;
+
+        Ok((rows, folders))
+    }
+    .await;
+
+    match moved {
+        Ok((rows, 0)) if rows.is_empty() => Err(AppError::NotFound("No file or folder at this path".into())),
+        Ok((rows, _)) => Ok(rows),
+        Err(sqlx::Error::Database(err)) if err.is_unique_violation() => {
+            Err(AppError::Conflict("A file already exists at the destination".into()))
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+
+#[utoipa::path(
+    post, path = "/delete", tag = "files",
+    request_body = BatchDeleteRequest,
+    responses(
+        (status = 200, description = "Per-path results", body = BatchDeleteResponse),
+        (status = 400, description = "Invalid request", body = crate::openapi::ErrorBody),
+    )
+)]
+pub(crate) async fn handle_batch_delete(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Json(req): Json<BatchDeleteRequest>,
+) -> Result<Response, AppError> {
+    let max_delete_batch = state.settings.current().max_delete_batch;
+    if req.paths.len() > max_delete_batch {
+        return Err(AppError::BadRequest(format!(
+            "At most {} paths can be deleted per request",
+            max_delete_batch
+        )));
+    }
+
+    info!("DELETING {} FILES", req.paths.len());
+    let mut success = Vec::new();
+    let mut failure = Vec::new();
+
+    for file_path in req.paths {
+        match delete_file(&state, user.user_id, &file_path).await {
+            Ok(()) => success.push(file_path),
+            Err(error) => failure.push(FileFailure::new(file_path, error)),
+        }
+    }
+
+    publish_changes(
+        &state,
+        &user,
+        success
+            .iter()
+            .map(|file_path| FileChange {
+                operation: Operation::Delete,
+                file_path: file_path.clone(),
+            })
+            .collect(),
+    );
+
+    info!("DELETED");
+    Ok((StatusCode::OK, Json(BatchDeleteResponse { success, failure })).into_response())
+}
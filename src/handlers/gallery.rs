@@ -0,0 +1,277 @@
+//! The public pages of folder shares: a plain HTML listing of each folder under
+//! the shared one at `/s/{token}/`, with its images as a grid of thumbnails, so a
+//! link can be opened in any browser. Files are served from the same URLs, as
+//! single-file links serve theirs.
+
+use std::{fmt::Write, net::SocketAddr};
+
+use axum::{
+    extract::{ConnectInfo, Path, Query, State},
+    http::{header, HeaderMap, StatusCode, Uri},
+    response::{Html, IntoResponse, Redirect, Response},
+};
+
+use crate::{
+    error::AppError,
+    handlers::{
+        dav::escape_xml,
+        files::trim_slashes,
+        listing::load_dir,
+        shares::{check_share_password, find_share, load_shared_file, record_share_view, serve_shared_file},
+        thumbnails::is_thumbnailable,
+    },
+    models::{DirListing, ShareParams},
+    AppState, DEFAULT_THUMBNAIL_SIZE,
+};
+
+/// Pages only load their own thumbnails, and don't pass the token on in `Referer`.
+const PAGE_CSP: &str = "default-src 'none'; img-src 'self'; style-src 'unsafe-inline'; form-action 'self'";
+
+const PAGE_STYLE: &str = "body{font-family:system-ui,sans-serif;margin:2rem auto;max-width:60rem;padding:0 1rem;color:#222}\
+a{color:#0b57d0;text-decoration:none}a:hover{text-decoration:underline}\
+.grid{display:grid;grid-template-columns:repeat(auto-fill,minmax(160px,1fr));gap:.5rem;margin:1rem 0}\
+.grid a{display:block;aspect-ratio:1;background:#eee;overflow:hidden}\
+.grid img{width:100%;height:100%;object-fit:cover}\
+ul{list-style:none;padding:0}li{padding:.3rem 0;border-bottom:1px solid #eee}\
+.size{color:#777;margin-left:.5rem}";
+
+/// The shared folder itself.
+pub(crate) async fn handle_share_folder_root(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(token): Path<String>,
+    Query(params): Query<ShareParams>,
+    headers: HeaderMap,
+) -> Response {
+    share_folder_page(&state, addr, &token, "", &params, &headers).await
+}
+
+/// A file or subfolder of the shared folder. Folders are listed at URLs ending in
+/// `/`, so the relative links on their pages resolve.
+pub(crate) async fn handle_share_folder_path(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path((token, path)): Path<(String, String)>,
+    Query(params): Query<ShareParams>,
+    uri: Uri,
+    headers: HeaderMap,
+) -> Response {
+    if !path.ends_with('/') {
+        match serve_path(&state, addr, &token, &path, &params, &headers).await {
+            Ok(Some(response)) => return response,
+            // Not a file: the folder's page is at the URL with a slash.
+            Ok(None) => {
+                let name = path.rsplit('/').next().unwrap_or(&path);
+                let query = uri.query().map(|q| format!("?{}", q)).unwrap_or_default();
+                return Redirect::to(&format!("{}/{}", urlencoding::encode(name), query)).into_response();
+            }
+            Err(e) => return error_page(e, &params),
+        }
+    }
+    share_folder_page(&state, addr, &token, &path, &params, &headers).await
+}
+
+/// Serves the file at `path` under the share, or `None` when there's no such file.
+async fn serve_path(
+    state: &AppState,
+    addr: SocketAddr,
+    token: &str,
+    path: &str,
+    params: &ShareParams,
+    headers: &HeaderMap,
+) -> Result<Option<Response>, AppError> {
+    let share = find_share(state, token).await?;
+    if !share.folder {
+        return Err(AppError::NotFound("Share not found".into()));
+    }
+    check_share_password(&share, params, headers)?;
+
+    match load_shared_file(state, share.user_id, &join(&share.file_path, trim_slashes(path))).await? {
+        Some(file) => serve_shared_file(state, addr, &share, file, params, headers).await.map(Some),
+        None => Ok(None),
+    }
+}
+
+/// The page of the folder at `path` under the share, which counts as a view of it.
+async fn share_folder_page(
+    state: &AppState,
+    addr: SocketAddr,
+    token: &str,
+    path: &str,
+    params: &ShareParams,
+    headers: &HeaderMap,
+) -> Response {
+    let page = async {
+        let share = find_share(state, token).await?;
+        if !share.folder {
+            return Err(AppError::NotFound("Share not found".into()));
+        }
+        check_share_password(&share, params, headers)?;
+
+        let sub = trim_slashes(path);
+        let folder = join(&share.file_path, sub);
+        let listing = load_dir(state, share.user_id, &folder).await?;
+        if !sub.is_empty() && listing.files.is_empty() && listing.folders.is_empty() {
+            return Err(AppError::NotFound("Folder not found".into()));
+        }
+        record_share_view(state, addr, &share, folder);
+        let root = share.file_path.rsplit('/').next().filter(|name| !name.is_empty()).unwrap_or("Shared files");
+        Ok(render_listing(root, sub, &listing, params))
+    }
+    .await;
+
+    match page {
+        Ok(body) => html_response(StatusCode::OK, body),
+        Err(e) => error_page(e, params),
+    }
+}
+
+/// `sub` under the shared `root`, either of which may be empty.
+fn join(root: &str, sub: &str) -> String {
+    match (root.is_empty(), sub.is_empty()) {
+        (true, _) => sub.to_string(),
+        (false, true) => root.to_string(),
+        (false, false) => format!("{}/{}", root, sub),
+    }
+}
+
+/// The query string a page's links carry, so a password given once keeps working.
+fn link_query(params: &ShareParams, size: Option<u32>) -> String {
+    let mut pairs = Vec::new();
+    if let Some(size) = size {
+        pairs.push(format!("size={}", size));
+    }
+    if let Some(password) = &params.password {
+        pairs.push(format!("password={}", urlencoding::encode(password)));
+    }
+    if pairs.is_empty() { String::new() } else { format!("?{}", pairs.join("&")) }
+}
+
+fn render_listing(root: &str, sub: &str, listing: &DirListing, params: &ShareParams) -> String {
+    let query = escape_xml(&link_query(params, None));
+    let depth = if sub.is_empty() { 0 } else { sub.split('/').count() };
+
+    // Breadcrumbs back up to the shared folder, each relative to this page.
+    let mut crumbs = String::new();
+    for (i, name) in std::iter::once(root).chain(sub.split('/').filter(|s| !s.is_empty())).enumerate() {
+        if i > 0 {
+            crumbs.push_str(" / ");
+        }
+        match depth - i {
+            0 => crumbs.push_str(&escape_xml(name)),
+            up => {
+                let _ = write!(crumbs, "<a href=\"{}{}\">{}</a>", "../".repeat(up), query, escape_xml(name));
+            }
+        }
+    }
+
+    let (images, others): (Vec<_>, Vec<_>) = listing
+        .files
+        .iter()
+        .partition(|file| is_thumbnailable(file.content_type.as_deref()));
+    let name_of = |path: &str| path.rsplit('/').next().unwrap_or(path).to_string();
+
+    let mut body = String::new();
+    if !images.is_empty() {
+        let thumb = escape_xml(&link_query(params, Some(DEFAULT_THUMBNAIL_SIZE)));
+        body.push_str("<div class=\"grid\">");
+        for file in images {
+            let name = name_of(&file.file_path);
+            let href = urlencoding::encode(&name);
+            let _ = write!(
+                body,
+                "<a href=\"{href}{query}\" title=\"{title}\"><img src=\"{href}{thumb}\" alt=\"{title}\" loading=\"lazy\"></a>",
+                title = escape_xml(&name),
+            );
+        }
+        body.push_str("</div>");
+    }
+    if !listing.folders.is_empty() || !others.is_empty() {
+        body.push_str("<ul>");
+        for folder in &listing.folders {
+            let _ = write!(
+                body,
+                "<li><a href=\"{}/{}\">{}/</a><span class=\"size\">{} {}</span></li>",
+                urlencoding::encode(&folder.name),
+                query,
+                escape_xml(&folder.name),
+                folder.file_count,
+                if folder.file_count == 1 { "file" } else { "files" },
+            );
+        }
+        for file in others {
+            let name = name_of(&file.file_path);
+            let _ = write!(
+                body,
+                "<li><a href=\"{}{}\">{}</a><span class=\"size\">{}</span></li>",
+                urlencoding::encode(&name),
+                query,
+                escape_xml(&name),
+                format_size(file.file_size),
+            );
+        }
+        body.push_str("</ul>");
+    }
+    if body.is_empty() {
+        body.push_str("<p>This folder is empty.</p>");
+    }
+
+    let title = if sub.is_empty() { root } else { sub.rsplit('/').next().unwrap_or(sub) };
+    page(title, &format!("<h1>{}</h1>{}", crumbs, body))
+}
+
+/// An error as a page, since links are opened in browsers. A missing or wrong
+/// password gets a form to enter one.
+fn error_page(err: AppError, params: &ShareParams) -> Response {
+    let status = err.status();
+    let body = match err {
+        AppError::Unauthorized(_) => {
+            let hint = if params.password.is_some() { "<p>That password isn't right.</p>" } else { "" };
+            format!(
+                "<h1>This folder is password protected</h1>{}\
+                 <form method=\"get\"><input type=\"password\" name=\"password\" autofocus> <button>Open</button></form>",
+                hint
+            )
+        }
+        err => format!("<h1>{}</h1><p>{}</p>", status, escape_xml(err.message())),
+    };
+    html_response(status, page("Pocket Drive", &body))
+}
+
+pub(crate) fn page(title: &str, body: &str) -> String {
+    format!(
+        "<!doctype html>\n<html lang=\"en\">\n<head>\n<meta charset=\"utf-8\">\n\
+         <meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\n\
+         <title>{}</title>\n<style>{}</style>\n</head>\n<body>\n{}\n</body>\n</html>\n",
+        escape_xml(title),
+        PAGE_STYLE,
+        body
+    )
+}
+
+pub(crate) fn html_response(status: StatusCode, body: String) -> Response {
+    (
+        status,
+        [
+            (header::CONTENT_SECURITY_POLICY, PAGE_CSP),
+            (header::REFERRER_POLICY, "no-referrer"),
+            (header::CACHE_CONTROL, "no-store"),
+        ],
+        Html(body),
+    ).into_response()
+}
+
+/// A byte count for people, e.g. `1.5 MB`.
+pub(crate) fn format_size(bytes: i64) -> String {
+    const UNITS: [&str; 4] = ["KB", "MB", "GB", "TB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
@@ -0,0 +1,110 @@
+use axum::{
+    extract::State,
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use tracing::{debug, warn};
+
+use crate::{
+    auth::bearer_token,
+    db::on_db,
+    error::AppError,
+    models::{Capabilities, Features},
+    AppState, MAX_BLOCK_BYTES, MAX_UPLOAD_CHUNK_BYTES, MIN_UPLOAD_CHUNK_BYTES, READINESS_CHECK_TIMEOUT, SYNC_PAYLOAD_VERSION,
+};
+
+pub(crate) async fn root() -> &'static str {
+    debug!("ROOT HIT");
+    "Pocket Drive is running!"
+}
+
+/// Liveness: the process is up and serving requests.
+pub(crate) async fn handle_healthz() -> Response {
+    (StatusCode::OK, Json(serde_json::json!({ "status": "ok" }))).into_response()
+}
+
+/// Readiness: the database answers a query and storage is reachable. A 503 once a
+/// shutdown has started, so load balancers stop routing here first.
+pub(crate) async fn handle_readyz(State(state): State<AppState>) -> Response {
+    if state.shutdown.is_cancelled() {
+        return (StatusCode::SERVICE_UNAVAILABLE, Json(serde_json::json!({
+            "status": "shutting down"
+        }))).into_response();
+    }
+
+    let database = async {
+        on_db!(&state.pool, pool => sqlx::query("SELECT 1").execute(pool).await.map(|_| ())).map_err(|e| e.to_string())
+    };
+    let storage = async { state.storage.check().await.map_err(String::from) };
+    let (database, storage) = tokio::join!(
+        tokio::time::timeout(READINESS_CHECK_TIMEOUT, database),
+        tokio::time::timeout(READINESS_CHECK_TIMEOUT, storage),
+    );
+    let describe = |result: Result<Result<(), String>, tokio::time::error::Elapsed>| match result {
+        Ok(Ok(())) => None,
+        Ok(Err(e)) => Some(e),
+        Err(_) => Some(format!("no response within {:?}", READINESS_CHECK_TIMEOUT)),
+    };
+    let checks = [("database", describe(database)), ("storage", describe(storage))];
+
+    let ready = checks.iter().all(|(_, error)| error.is_none());
+    let mut body = serde_json::json!({ "status": if ready { "ready" } else { "unavailable" }, "checks": {} });
+    for (name, error) in checks {
+        body["checks"][name] = match error {
+            None => "ok".into(),
+            Some(e) => {
+                warn!("Readiness check {} failed: {}", name, e);
+                e.into()
+            }
+        };
+    }
+    let status = if ready { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    (status, Json(body)).into_response()
+}
+
+/// The server's version, limits and optional features, so clients can check what
+/// they can rely on instead of guessing from the version alone.
+#[utoipa::path(
+    get, path = "/capabilities", tag = "sync", security(()),
+    summary = "Describes what this server supports",
+    responses((status = 200, description = "The server's capabilities", body = Capabilities))
+)]
+pub(crate) async fn handle_capabilities(State(state): State<AppState>) -> Response {
+    let config = &state.config;
+    let capabilities = Capabilities {
+        version: env!("CARGO_PKG_VERSION"),
+        sync_payload_versions: (1..=SYNC_PAYLOAD_VERSION).collect(),
+        max_upload_bytes: state.settings.current().max_upload_bytes,
+        max_request_bytes: config.max_request_bytes,
+        min_upload_chunk_bytes: MIN_UPLOAD_CHUNK_BYTES,
+        max_upload_chunk_bytes: MAX_UPLOAD_CHUNK_BYTES,
+        max_block_bytes: MAX_BLOCK_BYTES,
+        features: Features {
+            dedup: config.dedup,
+            encryption: config.encryption_master_key.is_some(),
+            compression: config.compression.enabled,
+            scanning: state.scanner.is_some(),
+            websocket: true,
+            grpc: true,
+            webdav: true,
+            upload_precheck: true,
+        },
+        auth_methods: vec!["password", "totp", "api_token", "basic"],
+    };
+    (StatusCode::OK, Json(capabilities)).into_response()
+}
+
+/// Prometheus metrics. Open unless `METRICS_TOKEN` is set, in which case the
+/// scraper must send it as a bearer token.
+pub(crate) async fn handle_metrics(State(state): State<AppState>, headers: HeaderMap) -> Result<Response, AppError> {
+    if let Some(expected) = &state.config.metrics_token
+        && bearer_token(&headers) != Some(expected.as_str())
+    {
+        return Err(AppError::Unauthorized("Invalid metrics token".into()));
+    }
+    Ok((
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        state.metrics.render(&state.pool),
+    ).into_response())
+}
@@ -0,0 +1,247 @@
+//! The integrity check, which samples stored files and confirms their objects are
+//! still there with the size and checksum their rows give. The checksum comes from
+//! the backend when it keeps one, such as S3's `x-amz-checksum-sha256`, and from
+//! reading the object back otherwise. Files whose `file_hash` isn't a SHA-256 only
+//! have their size checked. What's found wrong is kept in `integrity_issues` for
+//! admins, and cleared once a later check finds the object intact.
+
+use std::{collections::BTreeMap, time::Duration};
+
+use axum::{
+    extract::{Extension, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use sha2::{Digest, Sha256};
+use tokio_stream::StreamExt;
+use tracing::{info, info_span, warn, Instrument};
+
+use crate::{
+    db::{create_job, finish_job, mark_job_running, on_db},
+    error::AppError,
+    handlers::{maintenance::in_maintenance, sync::is_sha256_hex},
+    models::{AdminListParams, AuthUser, IntegrityIssue, IntegrityReport},
+    notifications,
+    AppState, DEFAULT_PAGE_LIMIT, MAX_PAGE_LIMIT,
+};
+
+/// What checking one object found.
+enum Outcome {
+    Intact,
+    Missing(String),
+    Corrupted(String),
+    /// The object couldn't be read, for reasons that may pass.
+    Unknown(String),
+}
+
+/// Checks `sample` files every `every`, outside maintenance.
+pub(crate) async fn verify_periodically(state: AppState, every: Duration, sample: i64) {
+    let mut interval = tokio::time::interval(every);
+    // The first tick fires immediately; give the server a full interval to settle.
+    interval.tick().await;
+
+    loop {
+        interval.tick().await;
+        if in_maintenance(&state).await {
+            continue;
+        }
+        if let Err(e) = verify_sample(&state, sample).await {
+            warn!("Integrity check failed: {}", e);
+        }
+    }
+}
+
+/// Checks the objects of `sample` files picked at random, recording what's wrong
+/// with them in `integrity_issues`.
+pub(crate) async fn verify_sample(state: &AppState, sample: i64) -> Result<IntegrityReport, String> {
+    info!("VERIFYING {} OBJECTS", sample);
+    // Issues of files deleted or rewritten since are moot.
+    on_db!(&state.pool, pool => sqlx::query(
+        "DELETE FROM integrity_issues WHERE system_path NOT IN (SELECT system_path FROM filehash)"
+    )
+    .execute(pool)
+    .await
+    .map(|_| ()))
+    .map_err(|e| e.to_string())?;
+
+    let rows = on_db!(&state.pool, pool => sqlx::query_as::<_, (String, Option<String>, i64, String)>(
+        "SELECT file_path, file_hash, file_size, system_path FROM filehash ORDER BY random() LIMIT $1"
+    )
+    .bind(sample.max(1))
+    .fetch_all(pool)
+    .await)
+    .map_err(|e| e.to_string())?;
+
+    let mut report = IntegrityReport { sampled: rows.len(), ..Default::default() };
+    let mut found = BTreeMap::<i32, Vec<String>>::new();
+    for (file_path, file_hash, file_size, system_path) in rows {
+        let (kind, detail) = match check_object(state, &system_path, file_size, file_hash.as_deref()).await {
+            Outcome::Intact => {
+                clear_issue(state, &system_path).await;
+                report.verified += 1;
+                continue;
+            }
+            Outcome::Unknown(e) => {
+                warn!("Couldn't verify {}: {}", file_path, e);
+                report.skipped += 1;
+                continue;
+            }
+            Outcome::Missing(detail) => ("missing", detail),
+            Outcome::Corrupted(detail) => ("corrupted", detail),
+        };
+
+        warn!("Integrity check found {} {}: {}", file_path, kind, detail);
+        let recorded = record_issue(state, &system_path, kind, &detail).await;
+        if let Ok(Some((user_id, true))) = recorded {
+            found.entry(user_id).or_default().push(file_path.clone());
+        }
+        match recorded {
+            // The file's content changed while it was checked, so it's the new object that counts.
+            Ok(None) => report.skipped += 1,
+            Ok(Some(_)) if kind == "missing" => report.missing.push(file_path),
+            Ok(Some(_)) => report.corrupted.push(file_path),
+            Err(e) => {
+                warn!("Failed to record the integrity issue of {}: {}", file_path, e);
+                report.skipped += 1;
+            }
+        }
+    }
+
+    info!(
+        "Integrity check verified {} objects, found {} missing and {} corrupted, skipped {}",
+        report.verified,
+        report.missing.len(),
+        report.corrupted.len(),
+        report.skipped
+    );
+    // Owners hear of an issue once, when it's first found.
+    for (user_id, file_paths) in found {
+        notifications::notify_integrity_issues(state, user_id, &file_paths);
+    }
+    Ok(report)
+}
+
+/// Compares the object at `key` with the size and, when it's a SHA-256, the hash of its file.
+async fn check_object(state: &AppState, key: &str, file_size: i64, file_hash: Option<&str>) -> Outcome {
+    let size = match state.storage.size(key).await {
+        Ok(size) => size,
+        Err(e) if e.is_transient() => return Outcome::Unknown(e.to_string()),
+        Err(e) => return Outcome::Missing(e.to_string()),
+    };
+    if size != file_size {
+        return Outcome::Corrupted(format!("stored object is {} bytes, not {}", size, file_size));
+    }
+
+    let Some(expected) = file_hash.filter(|h| is_sha256_hex(h)) else {
+        return Outcome::Intact;
+    };
+    let actual = match state.storage.sha256(key).await {
+        Ok(Some(actual)) => Ok(actual),
+        Ok(None) => hash_object(state, key, size).await,
+        Err(e) => Err(e.to_string()),
+    };
+    match actual {
+        Ok(actual) if actual.eq_ignore_ascii_case(expected) => Outcome::Intact,
+        Ok(actual) => Outcome::Corrupted(format!("stored object hashes to {}, not {}", actual, expected)),
+        Err(e) => Outcome::Unknown(e),
+    }
+}
+
+/// Hex SHA-256 of the object at `key`, read back from storage.
+async fn hash_object(state: &AppState, key: &str, size: i64) -> Result<String, String> {
+    let mut body = state.storage.get_range(key, 0, size as u64).await?;
+    let mut hasher = Sha256::new();
+    while let Some(chunk) = body.next().await {
+        hasher.update(&chunk?);
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// Records what's wrong with the object at `system_path`, if a file still has it.
+/// Returns the owner of the file, and whether the issue wasn't known before.
+async fn record_issue(
+    state: &AppState,
+    system_path: &str,
+    kind: &str,
+    detail: &str,
+) -> Result<Option<(i32, bool)>, sqlx::Error> {
+    on_db!(&state.pool, pool => sqlx::query_as::<_, (i32, bool)>(
+        r#"
+        INSERT INTO integrity_issues (user_id, file_path, system_path, kind, detail)
+        SELECT user_id, file_path, system_path, $2, $3 FROM filehash WHERE system_path = $1 LIMIT 1
+        ON CONFLICT (system_path) DO UPDATE
+        SET file_path = excluded.file_path, kind = excluded.kind, detail = excluded.detail, checked_at = CURRENT_TIMESTAMP
+        RETURNING user_id, detected_at = checked_at
+        "#
+    )
+    .bind(system_path)
+    .bind(kind)
+    .bind(detail)
+    .fetch_optional(pool)
+    .await)
+}
+
+async fn clear_issue(state: &AppState, system_path: &str) {
+    let cleared = on_db!(&state.pool, pool => sqlx::query("DELETE FROM integrity_issues WHERE system_path = $1")
+        .bind(system_path)
+        .execute(pool)
+        .await
+        .map(|_| ()));
+    if let Err(e) = cleared {
+        warn!("Failed to clear the integrity issue of {}: {}", system_path, e);
+    }
+}
+
+/// Lists the objects the integrity check flagged, of every user or of `user_id`,
+/// newest first.
+pub(crate) async fn handle_list_integrity_issues(
+    State(state): State<AppState>,
+    Query(params): Query<AdminListParams>,
+) -> Result<Response, AppError> {
+    let issues = on_db!(&state.pool, pool => sqlx::query_as::<_, IntegrityIssue>(
+        r#"
+        SELECT i.id, i.user_id, u.username, i.file_path, i.system_path, i.kind, i.detail, i.detected_at, i.checked_at
+        FROM integrity_issues i
+        JOIN users u ON u.id = i.user_id
+        WHERE CAST($1 AS INTEGER) IS NULL OR i.user_id = $1
+        ORDER BY i.id DESC
+        LIMIT $2 OFFSET $3
+        "#
+    )
+    .bind(params.user_id)
+    .bind(params.limit.unwrap_or(DEFAULT_PAGE_LIMIT).clamp(1, MAX_PAGE_LIMIT))
+    .bind(params.offset.unwrap_or(0).max(0))
+    .fetch_all(pool)
+    .await)?;
+
+    Ok((StatusCode::OK, Json(serde_json::json!({ "data": issues }))).into_response())
+}
+
+/// Starts an integrity check of `integrity_sample_size` files as a background job
+/// and returns its id.
+pub(crate) async fn handle_verify_integrity(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+) -> Result<Response, AppError> {
+    let job_id = create_job(&state.pool, Some(user.user_id), "integrity", 0)
+        .await
+        .map_err(|e| AppError::Internal(format!("Failed to create job: {}", e)))?;
+
+    let tasks = state.tasks.clone();
+    tasks.spawn(async move {
+        mark_job_running(&state.pool, job_id).await;
+        let outcome = verify_sample(&state, state.integrity_sample_size)
+            .await
+            .and_then(|report| serde_json::to_value(report).map_err(|e| e.to_string()));
+        finish_job(&state.pool, job_id, outcome).await;
+    }.instrument(info_span!("integrity_job", job_id)));
+
+    Ok((
+        StatusCode::ACCEPTED,
+        Json(serde_json::json!({
+            "job_id": job_id,
+            "status_url": format!("/jobs/{}", job_id)
+        }))
+    ).into_response())
+}
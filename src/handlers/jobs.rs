@@ -0,0 +1,388 @@
+use std::{collections::HashSet, time::Duration};
+
+use axum::{
+    extract::{Extension, Path, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use tracing::{info, info_span, warn, Instrument};
+
+use crate::{
+    db::{
+        claim_due_retries, create_job, enqueue_retry, fail_retry, finish_job, finish_retry,
+        mark_job_running, on_db, RETRY_ABORT, RETRY_COPY, RETRY_DELETE,
+    },
+    error::AppError,
+    handlers::{maintenance::in_maintenance, thumbnails::thumbnail_source},
+    models::{AuthUser, Job, QueuedRetry, ReconcileReport, RetryQueueParams},
+    webhooks::run_due_deliveries,
+    AppState, DEFAULT_PAGE_LIMIT, MAX_PAGE_LIMIT, RECONCILE_HISTORY, RETRY_BATCH_SIZE,
+    THUMBNAIL_PREFIX, TRASH_PREFIX, VERSIONS_PREFIX,
+};
+
+/// Runs `reconcile` every `every`, recording each report as a job nobody owns so
+/// the latest one can be read back through `GET /admin/reconcile`.
+pub(crate) async fn reconcile_periodically(state: AppState, every: Duration) {
+    let mut interval = tokio::time::interval(every);
+    // The first tick fires immediately; give the server a full interval to settle.
+    interval.tick().await;
+
+    loop {
+        interval.tick().await;
+        if in_maintenance(&state).await {
+            continue;
+        }
+        if let Err(e) = reconcile_on_schedule(&state).await {
+            warn!("{}", e);
+        }
+    }
+}
+
+/// Runs a reconciliation whose report is kept with the latest `RECONCILE_HISTORY`
+/// scheduled ones.
+pub(crate) async fn reconcile_on_schedule(state: &AppState) -> Result<(), String> {
+    let job_id = create_job(&state.pool, None, "reconcile", 0)
+        .await
+        .map_err(|e| format!("Failed to create reconcile job: {}", e))?;
+    run_reconcile_job(state, job_id).await;
+    prune_scheduled_reconciles(state).await;
+    Ok(())
+}
+
+async fn run_reconcile_job(state: &AppState, job_id: i32) {
+    mark_job_running(&state.pool, job_id).await;
+    let outcome = reconcile(state)
+        .await
+        .and_then(|report| serde_json::to_value(report).map_err(|e| e.to_string()));
+    if let Err(e) = &outcome {
+        warn!("Reconciliation failed: {}", e);
+    }
+    finish_job(&state.pool, job_id, outcome).await;
+}
+
+/// Keeps only the latest `RECONCILE_HISTORY` scheduled reports.
+async fn prune_scheduled_reconciles(state: &AppState) {
+    let pruned = on_db!(&state.pool, pool => sqlx::query(
+        r#"
+        DELETE FROM jobs
+        WHERE kind = 'reconcile' AND user_id IS NULL AND id NOT IN (
+            SELECT id FROM jobs WHERE kind = 'reconcile' AND user_id IS NULL ORDER BY id DESC LIMIT $1
+        )
+        "#
+    )
+    .bind(RECONCILE_HISTORY)
+    .execute(pool)
+    .await
+    .map(|_| ()));
+    if let Err(e) = pruned {
+        warn!("Failed to prune old reconcile reports: {}", e);
+    }
+}
+
+/// Cross-references stored objects under `data/`, `trash/` and `versions/` with the
+/// rows that own them, and `filehash` rows with their objects, logging both kinds of
+/// mismatch and cleaning them up when `RECONCILE_DELETE_ORPHANS` is set. Thumbnails
+/// under `thumbs/` count as orphans once the object they were made from is gone.
+/// Anything newer than `reconcile_min_age_secs` is left alone.
+pub(crate) async fn reconcile(state: &AppState) -> Result<ReconcileReport, String> {
+    info!("RECONCILING");
+    let cutoff = chrono::Utc::now().timestamp() - state.reconcile_min_age_secs;
+
+    let mut objects = Vec::new();
+    for prefix in [
+        "data/",
+        &format!("{}/", TRASH_PREFIX),
+        &format!("{}/", VERSIONS_PREFIX),
+        &format!("{}/", THUMBNAIL_PREFIX),
+    ] {
+        objects.extend(state.storage.list(prefix).await?);
+    }
+    let sql = state.pool.sql(
+        r#"
+        SELECT file_path, system_path
+        FROM filehash
+        WHERE created_at < NOW() - make_interval(secs => $1)
+        "#,
+        r#"
+        SELECT file_path, system_path
+        FROM filehash
+        WHERE created_at < datetime('now', -$1 || ' seconds')
+        "#,
+    );
+    let rows = on_db!(&state.pool, pool => sqlx::query_as::<_, (String, String)>(sql)
+    .bind(state.reconcile_min_age_secs as f64)
+    .fetch_all(pool)
+    .await)
+    .map_err(|e| e.to_string())?;
+    let known = on_db!(&state.pool, pool => sqlx::query_scalar::<_, String>(
+        r#"
+        SELECT system_path FROM filehash
+        UNION SELECT system_path FROM upload_reservations
+        UNION SELECT system_path FROM upload_sessions
+        UNION SELECT trash_key FROM trash
+        UNION SELECT s3_key FROM file_versions
+        "#
+    )
+    .fetch_all(pool)
+    .await)
+    .map_err(|e| e.to_string())?;
+
+    let known: HashSet<String> = known.into_iter().collect();
+    let stored: HashSet<&str> = objects.iter().map(|o| o.key.as_str()).collect();
+
+    let mut report = ReconcileReport {
+        scanned_objects: objects.len(),
+        scanned_rows: rows.len(),
+        ..Default::default()
+    };
+
+    report.orphaned_objects = objects
+        .iter()
+        .filter(|o| o.modified.is_some_and(|m| m < cutoff))
+        .filter(|o| !known.contains(thumbnail_source(&o.key).unwrap_or(&o.key)))
+        .map(|o| o.key.clone())
+        .collect();
+    let dangling: Vec<(String, String)> = rows
+        .into_iter()
+        .filter(|(_, system_path)| !stored.contains(system_path.as_str()))
+        .collect();
+    report.dangling_rows = dangling.iter().map(|(file_path, _)| file_path.clone()).collect();
+
+    info!(
+        "Reconciliation found {} orphaned objects and {} dangling rows",
+        report.orphaned_objects.len(),
+        report.dangling_rows.len()
+    );
+
+    if state.settings.current().reconcile_delete_orphans {
+        for key in &report.orphaned_objects {
+            match state.storage.delete(key).await {
+                Ok(()) => report.deleted_objects += 1,
+                Err(e) => warn!("Failed to delete orphaned object {}: {}", key, e),
+            }
+        }
+
+        for (file_path, system_path) in &dangling {
+            let deleted = on_db!(&state.pool, pool => sqlx::query("DELETE FROM filehash WHERE file_path = $1 AND system_path = $2")
+                .bind(file_path)
+                .bind(system_path)
+                .execute(pool)
+                .await
+                .map(|r| r.rows_affected()));
+            match deleted {
+                Ok(deleted) => report.deleted_rows += deleted as usize,
+                Err(e) => warn!("Failed to delete dangling row {}: {}", file_path, e),
+            }
+        }
+    }
+
+    info!("RECONCILED");
+    Ok(report)
+}
+
+/// Starts a reconciliation run as a background job and returns its id.
+pub(crate) async fn handle_reconcile(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+) -> Result<Response, AppError> {
+    let job_id = create_job(&state.pool, Some(user.user_id), "reconcile", 0)
+        .await
+        .map_err(|e| AppError::Internal(format!("Failed to create job: {}", e)))?;
+
+    let tasks = state.tasks.clone();
+    tasks.spawn(async move {
+        run_reconcile_job(&state, job_id).await;
+    }.instrument(info_span!("reconcile_job", job_id)));
+
+    Ok((
+        StatusCode::ACCEPTED,
+        Json(serde_json::json!({
+            "job_id": job_id,
+            "status_url": format!("/jobs/{}", job_id)
+        }))
+    ).into_response())
+}
+
+/// The latest finished reconciliation, scheduled or started through
+/// `POST /admin/reconcile`.
+pub(crate) async fn handle_latest_reconcile(State(state): State<AppState>) -> Result<Response, AppError> {
+    let job = on_db!(&state.pool, pool => sqlx::query_as::<_, Job>(
+        r#"
+        SELECT id, kind, status, progress, total, result, error, created_at, updated_at
+        FROM jobs
+        WHERE kind = 'reconcile' AND status IN ('completed', 'failed')
+        ORDER BY updated_at DESC, id DESC
+        LIMIT 1
+        "#
+    )
+    .fetch_optional(pool)
+    .await)?
+    .ok_or_else(|| AppError::NotFound("No reconciliation has run yet".into()))?;
+
+    Ok((StatusCode::OK, Json(serde_json::json!({ "data": job }))).into_response())
+}
+
+#[utoipa::path(
+    get, path = "/jobs/{id}", tag = "sync",
+    summary = "Reports the progress and result of a background job",
+    params(("id" = i32, Path)),
+    responses(
+        (status = 200, description = "The job", body = Job),
+        (status = 404, description = "No such job", body = crate::openapi::ErrorBody),
+    )
+)]
+pub(crate) async fn handle_get_job(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Path(id): Path<i32>,
+) -> Result<Response, AppError> {
+    let job = on_db!(&state.pool, pool => sqlx::query_as::<_, Job>(
+        r#"
+        SELECT id, kind, status, progress, total, result, error, created_at, updated_at
+        FROM jobs
+        WHERE id = $1 AND user_id = $2
+        "#
+    )
+    .bind(id)
+    .bind(user.user_id)
+    .fetch_optional(pool)
+    .await)?
+    .ok_or_else(|| AppError::NotFound("Job not found".into()))?;
+
+    Ok((StatusCode::OK, Json(job)).into_response())
+}
+
+/// Deletes `key` from storage, queueing a background retry when that fails so the
+/// object isn't leaked.
+pub(crate) async fn delete_or_retry(state: &AppState, key: &str) {
+    if let Err(e) = state.storage.delete(key).await {
+        warn!("Failed to delete {}, will retry: {}", key, e);
+        enqueue_retry(&state.pool, RETRY_DELETE, key, None, &e.to_string()).await;
+    }
+}
+
+pub(crate) async fn retry_periodically(state: AppState, every: Duration, max_attempts: i32) {
+    let mut interval = tokio::time::interval(every);
+    loop {
+        interval.tick().await;
+        // Webhooks still go out; it's stored files that are left alone.
+        if !in_maintenance(&state).await {
+            run_due_retries(&state, max_attempts).await;
+        }
+        run_due_deliveries(&state).await;
+    }
+}
+
+/// Runs every due retry once. A retry whose object has been reused since it was
+/// queued is dropped rather than run, so a late delete or copy never clobbers
+/// newer data.
+async fn run_due_retries(state: &AppState, max_attempts: i32) {
+    let due = match claim_due_retries(&state.pool, RETRY_BATCH_SIZE).await {
+        Ok(due) => due,
+        Err(e) => {
+            warn!("Failed to claim due retries: {}", e);
+            return;
+        }
+    };
+    if due.is_empty() {
+        return;
+    }
+
+    info!("RETRYING {} STORAGE OPERATIONS", due.len());
+    for retry in due {
+        let outcome = match superseded(state, &retry).await {
+            Ok(true) => {
+                info!("Dropping {} retry for {}: superseded since it was queued", retry.kind, retry.object_key);
+                Ok(())
+            }
+            Ok(false) => match (retry.kind.as_str(), &retry.source_key) {
+                (RETRY_DELETE, _) => state.storage.delete(&retry.object_key).await,
+                (RETRY_COPY, Some(source)) => state.storage.copy(source, &retry.object_key).await,
+                (RETRY_ABORT, Some(upload_id)) => state.storage.abort_multipart(&retry.object_key, upload_id).await,
+                (kind, _) => Err(format!("unknown retry kind {}", kind).into()),
+            },
+            Err(e) => Err(e.to_string().into()),
+        };
+
+        let recorded = match &outcome {
+            Ok(()) => finish_retry(&state.pool, retry.id).await,
+            Err(e) => {
+                warn!("Retry {} of {} {} failed: {}", retry.attempts, retry.kind, retry.object_key, e);
+                fail_retry(&state.pool, &retry, &e.to_string(), max_attempts).await
+            }
+        };
+        if let Err(e) = recorded {
+            warn!("Failed to record outcome of retry {}: {}", retry.id, e);
+        }
+    }
+}
+
+/// Whether a retry must no longer run: a delete whose key something references
+/// again, or a copy onto a file that is gone or has been written since. Aborts
+/// name their multipart upload, so they can always run.
+async fn superseded(state: &AppState, retry: &QueuedRetry) -> Result<bool, sqlx::Error> {
+    if retry.kind == RETRY_ABORT {
+        return Ok(false);
+    }
+    if retry.kind == RETRY_DELETE {
+        return on_db!(&state.pool, pool => sqlx::query_scalar::<_, bool>(
+            r#"
+            SELECT EXISTS (SELECT 1 FROM filehash WHERE system_path = $1)
+                OR EXISTS (SELECT 1 FROM upload_reservations WHERE system_path = $1)
+                OR EXISTS (SELECT 1 FROM upload_sessions WHERE system_path = $1)
+                OR EXISTS (SELECT 1 FROM trash WHERE trash_key = $1)
+                OR EXISTS (SELECT 1 FROM file_versions WHERE s3_key = $1)
+            "#
+        )
+        .bind(&retry.object_key)
+        .fetch_one(pool)
+        .await);
+    }
+
+    on_db!(&state.pool, pool => sqlx::query_scalar::<_, bool>(
+        r#"
+        SELECT NOT EXISTS (
+            SELECT 1 FROM filehash
+            WHERE system_path = $1 AND (updated_at IS NULL OR updated_at <= $2)
+        )
+        "#
+    )
+    .bind(&retry.object_key)
+    .bind(retry.created_at)
+    .fetch_one(pool)
+    .await)
+}
+
+/// Lists the retry queue, most recently failed first; `status` narrows it to
+/// `pending` or `dead` entries.
+pub(crate) async fn handle_list_retries(
+    State(state): State<AppState>,
+    Query(params): Query<RetryQueueParams>,
+) -> Result<Response, AppError> {
+    let retries = on_db!(&state.pool, pool => sqlx::query_as::<_, QueuedRetry>(
+        r#"
+        SELECT id, kind, object_key, source_key, status, attempts, last_error, run_at, created_at, updated_at
+        FROM retry_queue
+        WHERE CAST($1 AS TEXT) IS NULL OR status = $1
+        ORDER BY updated_at DESC, id DESC
+        LIMIT $2
+        "#
+    )
+    .bind(&params.status)
+    .bind(params.limit.unwrap_or(DEFAULT_PAGE_LIMIT).clamp(1, MAX_PAGE_LIMIT))
+    .fetch_all(pool)
+    .await)?;
+    let counts = on_db!(&state.pool, pool => sqlx::query_as::<_, (String, i64)>(
+        "SELECT status, COUNT(*) FROM retry_queue GROUP BY status"
+    )
+    .fetch_all(pool)
+    .await)?;
+
+    let count = |status: &str| counts.iter().find(|(s, _)| s == status).map_or(0, |(_, n)| *n);
+    Ok((StatusCode::OK, Json(serde_json::json!({
+        "data": retries,
+        "pending": count("pending"),
+        "dead": count("dead")
+    }))).into_response())
+}
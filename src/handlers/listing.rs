@@ -0,0 +1,805 @@
+use std::{io, time::Duration};
+
+use axum::{
+    body::Body,
+    extract::{Extension, Query, RawQuery, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{NaiveDateTime, Utc};
+use futures::{Stream, TryStreamExt};
+use sha2::{Digest, Sha256};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::{info, warn, Instrument};
+
+use crate::{
+    db::{on_db, Array, DbPool},
+    error::AppError,
+    handlers::{
+        collaborators::{shared_filter, shared_folders},
+        devices::acknowledge_changes,
+        downloads::if_none_match,
+        files::trim_slashes,
+        maintenance::in_maintenance,
+        profiles::{device_filter, PathFilter},
+    },
+    models::{
+        AuthUser, Change, ChangedFile, ChangesParams, ChangesResponse, DirListing, FileEntry,
+        FolderEntry, FolderGrant, GetAllParams, GetAllResponse, ListDirParams, ListFormat, SearchParams, TaggedParams,
+        Tombstone,
+    },
+    AppState, DEFAULT_PAGE_LIMIT, MAX_PAGE_LIMIT, MIN_SEARCH_QUERY_LEN, NDJSON_CHUNK_BYTES,
+};
+
+/// Lists the immediate children of `dir`: its files, and one entry per subfolder
+/// with the count, total size and latest modification time of everything inside.
+#[utoipa::path(
+    get, path = "/list", tag = "files",
+    params(ListDirParams),
+    responses(
+        (status = 200, description = "The folder's contents", body = crate::openapi::Data<DirListing>),
+        (status = 401, description = "Missing or invalid bearer token", body = crate::openapi::ErrorBody),
+    )
+)]
+pub(crate) async fn handle_list_dir(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Query(params): Query<ListDirParams>,
+) -> Result<Response, AppError> {
+    let dir = trim_slashes(params.dir.as_deref().unwrap_or(""));
+    let listing = load_dir(&state, user.user_id, dir).await?;
+    Ok((StatusCode::OK, Json(serde_json::json!({ "data": listing }))).into_response())
+}
+
+/// The files directly in `dir`, and its subfolders: those holding files, and empty
+/// ones created explicitly.
+pub(crate) async fn load_dir(state: &AppState, user_id: i32, dir: &str) -> Result<DirListing, sqlx::Error> {
+    let prefix = if dir.is_empty() { String::new() } else { format!("{}/", dir) };
+    let pattern = format!("{}%", escape_like(&prefix));
+    // `substr` counts characters, not bytes.
+    let rest_from = prefix.chars().count() as i32 + 1;
+
+    let files = on_db!(&state.pool, pool => sqlx::query_as::<_, FileEntry>(
+        r#"
+        SELECT file_path, file_hash, file_size, modified_time, system_path AS file_name, content_type, etag, created_at, updated_at, version
+        FROM filehash
+        WHERE user_id = $1 AND file_path LIKE $2 ESCAPE '\' AND substr(file_path, $3) NOT LIKE '%/%'
+        ORDER BY file_path
+        "#
+    )
+    .bind(user_id)
+    .bind(&pattern)
+    .bind(rest_from)
+    .fetch_all(pool)
+    .await)?;
+    let sql = state.pool.sql(
+        r#"
+        SELECT name, $4 || name AS path, COUNT(file_size) AS file_count,
+               CAST(COALESCE(SUM(file_size), 0) AS BIGINT) AS total_size, MAX(modified_time) AS modified_time
+        FROM (
+            SELECT split_part(substr(file_path, $3), '/', 1) AS name, file_size, modified_time
+            FROM filehash
+            WHERE user_id = $1 AND file_path LIKE $2 ESCAPE '\' AND substr(file_path, $3) LIKE '%/%'
+            UNION ALL
+            SELECT split_part(substr(path, $3), '/', 1), NULL, NULL
+            FROM folders
+            WHERE user_id = $1 AND path LIKE $2 ESCAPE '\'
+        ) children
+        GROUP BY name
+        ORDER BY name
+        "#,
+        r#"
+        SELECT name, $4 || name AS path, COUNT(file_size) AS file_count,
+               CAST(COALESCE(SUM(file_size), 0) AS BIGINT) AS total_size, MAX(modified_time) AS modified_time
+        FROM (
+            SELECT substr(rest, 1, instr(rest, '/') - 1) AS name, file_size, modified_time
+            FROM (SELECT substr(file_path, $3) AS rest, file_size, modified_time FROM filehash
+                  WHERE user_id = $1 AND file_path LIKE $2 ESCAPE '\')
+            WHERE rest LIKE '%/%'
+            UNION ALL
+            SELECT substr(rest, 1, instr(rest || '/', '/') - 1), NULL, NULL
+            FROM (SELECT substr(path, $3) AS rest FROM folders WHERE user_id = $1 AND path LIKE $2 ESCAPE '\')
+        ) children
+        GROUP BY name
+        ORDER BY name
+        "#,
+    );
+    let folders = on_db!(&state.pool, pool => sqlx::query_as::<_, FolderEntry>(sql)
+    .bind(user_id)
+    .bind(&pattern)
+    .bind(rest_from)
+    .bind(&prefix)
+    .fetch_all(pool)
+    .await)?;
+
+    Ok(DirListing { dir: dir.to_string(), folders, files })
+}
+
+/// Lists the files carrying a tag, with their tags and metadata, ordered by path.
+#[utoipa::path(
+    get, path = "/files", tag = "files",
+    params(TaggedParams),
+    responses(
+        (status = 200, description = "The tagged files", body = crate::openapi::FileList),
+        (status = 400, description = "Invalid request", body = crate::openapi::ErrorBody),
+        (status = 401, description = "Missing or invalid bearer token", body = crate::openapi::ErrorBody),
+    )
+)]
+pub(crate) async fn handle_list_tagged(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Query(params): Query<TaggedParams>,
+) -> Result<Response, AppError> {
+    let tag = params
+        .tag
+        .as_deref()
+        .map(str::trim)
+        .filter(|tag| !tag.is_empty())
+        .ok_or_else(|| AppError::BadRequest("Missing tag".into()))?;
+    let limit = params.limit.unwrap_or(DEFAULT_PAGE_LIMIT).clamp(1, MAX_PAGE_LIMIT);
+    let offset = params.offset.unwrap_or(0).max(0);
+
+    let query = format!(
+        r#"
+        SELECT file_path, file_hash, file_size, modified_time, system_path AS file_name, content_type, etag, created_at, updated_at, version,
+               metadata, {} AS tags
+        FROM filehash
+        WHERE user_id = $1 AND id IN (SELECT file_id FROM file_tags WHERE tag = $2)
+        ORDER BY file_path
+        LIMIT $3 OFFSET $4
+        "#,
+        tags_sql(&state.pool),
+    );
+    let files = on_db!(&state.pool, pool => sqlx::query_as::<_, FileEntry>(&query)
+    .bind(user.user_id)
+    .bind(tag)
+    .bind(limit)
+    .bind(offset)
+    .fetch_all(pool)
+    .await)?;
+
+    Ok((StatusCode::OK, Json(serde_json::json!({ "data": files }))).into_response())
+}
+
+#[utoipa::path(
+    get, path = "/get", tag = "sync",
+    summary = "Lists the caller's files, or those changed since `since`",
+    params(
+        GetAllParams,
+        ("If-None-Match" = Option<String>, Header, description = "The `ETag` of an earlier identical request"),
+    ),
+    responses(
+        (status = 200, description = "The files, and deletions when `since` is given. With `format=ndjson`, \
+            one file per line as `application/x-ndjson` instead, ending early if the listing fails partway", body = GetAllResponse),
+        (status = 304, description = "Nothing the listing covers changed since that `ETag`"),
+        (status = 400, description = "`format=ndjson` with `since`", body = crate::openapi::ErrorBody),
+        (status = 401, description = "Missing or invalid bearer token", body = crate::openapi::ErrorBody),
+        (status = 404, description = "`owner` shares no folders with the caller", body = crate::openapi::ErrorBody),
+    )
+)]
+pub(crate) async fn handle_get_all(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Query(params): Query<GetAllParams>,
+    RawQuery(query): RawQuery,
+    headers: HeaderMap,
+) -> Response {
+    let failed = |err: sqlx::Error| (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        Json(GetAllResponse {
+            error: Some(err.to_string()),
+            ..Default::default()
+        }),
+    ).into_response();
+
+    if params.format == ListFormat::Ndjson && params.since.is_some() {
+        return AppError::BadRequest("format=ndjson only streams full listings; leave out since".into()).into_response();
+    }
+
+    // Another user's files are only listed in the folders they share with the
+    // caller. The caller's own listing names those folders instead.
+    let (owner_id, filter, shared) = match &params.owner {
+        Some(owner) => match shared_filter(&state, &user, owner).await {
+            Ok((owner_id, filter)) => (owner_id, filter, Vec::new()),
+            Err(err) => return err.into_response(),
+        },
+        None => match (device_filter(&state, &user).await, shared_folders(&state, user.user_id).await) {
+            (Ok(filter), Ok(shared)) => (user.user_id, filter, shared),
+            (Err(err), _) | (_, Err(err)) => return failed(err),
+        },
+    };
+
+    // Read before the listing, so a change landing meanwhile can only make the tag
+    // older than the content, never newer.
+    let etag = listing_tag(&state.pool, owner_id, query.as_deref(), &filter, &shared).await;
+    if let Some(tag) = &etag
+        && if_none_match(&headers, tag)
+    {
+        return (StatusCode::NOT_MODIFIED, [(header::ETAG, tag.clone())]).into_response();
+    }
+
+    let mut response = if params.format == ListFormat::Ndjson {
+        stream_all(&state, owner_id, params, filter)
+    } else {
+        let (status, Json(mut body)) = list_all(&state, owner_id, params, filter).await;
+        if !shared.is_empty() {
+            body.shared = Some(shared);
+        }
+        (status, Json(body)).into_response()
+    };
+    if response.status() == StatusCode::OK
+        && let Some(tag) = etag.and_then(|t| t.parse().ok())
+    {
+        response.headers_mut().insert(header::ETAG, tag);
+    }
+    response
+}
+
+/// Tag of a `/get` response: the latest change number of the user's files, which
+/// every write to them bumps, and a digest of what selects the listing and of the
+/// folders shared with the caller.
+async fn listing_tag(
+    pool: &DbPool,
+    user_id: i32,
+    query: Option<&str>,
+    filter: &PathFilter,
+    shared: &[FolderGrant],
+) -> Option<String> {
+    let seq = on_db!(pool, pool => sqlx::query_scalar::<_, i64>(
+        "SELECT COALESCE((SELECT seq FROM change_counters WHERE user_id = $1), 0)"
+    )
+    .bind(user_id)
+    .fetch_one(pool)
+    .await)
+    .ok()?;
+
+    let mut hasher = Sha256::new();
+    hasher.update(query.unwrap_or_default());
+    for pattern in filter.include.iter().chain([&String::new()]).chain(&filter.exclude) {
+        hasher.update(b"\n");
+        hasher.update(pattern);
+    }
+    for grant in shared {
+        hasher.update(format!("\n{}:{:?}", grant.id, grant.permission));
+    }
+    Some(format!("\"{}-{}\"", seq, &hex::encode(hasher.finalize())[..16]))
+}
+
+/// The query of a full `/get` listing, taking the user as `$1`, the `LIKE` pattern
+/// of the prefix as `$2`, the page as `$3` and `$4` and the path filter as `$5` and
+/// `$6`.
+fn full_listing_sql(pool: &DbPool, params: &GetAllParams) -> String {
+    // The path breaks ties so pages stay stable when sorting by size or time.
+    let direction = params.order.keyword();
+    format!(
+        r#"
+        SELECT file_path, file_hash, file_size, modified_time, system_path AS file_name, content_type, etag, created_at, updated_at, version,
+               metadata, {} AS tags
+        FROM filehash
+        WHERE user_id = $1 AND (CAST($2 AS TEXT) IS NULL OR file_path LIKE $2 ESCAPE '\')
+          AND {}
+        ORDER BY {} {}, file_path {}
+        LIMIT $3 OFFSET $4
+        "#,
+        tags_sql(pool),
+        PathFilter::sql(pool, 5, 6),
+        params.sort.column(),
+        direction,
+        direction,
+    )
+}
+
+fn page_limit(params: &GetAllParams) -> i64 {
+    params.limit.map_or(i64::MAX, |limit| limit.clamp(1, MAX_PAGE_LIMIT))
+}
+
+/// Streams a full listing as NDJSON, sending rows on as the database returns them
+/// so the server holds no more than a chunk of them however many files there are.
+/// The status is sent before the first row, so a failure partway can only cut the
+/// body short; every complete line is a file.
+fn stream_all(state: &AppState, user_id: i32, params: GetAllParams, filter: PathFilter) -> Response {
+    let pool = state.pool.clone();
+    let query = full_listing_sql(&pool, &params);
+    let prefix = params.prefix.as_deref().filter(|p| !p.is_empty()).map(|p| format!("{}%", escape_like(p)));
+    let limit = page_limit(&params);
+    let offset = params.offset.unwrap_or(0).max(0);
+
+    let (tx, rx) = mpsc::channel(4);
+    tokio::spawn(async move {
+        let sent = on_db!(&pool, pool => {
+            let rows = sqlx::query_as::<_, FileEntry>(&query)
+                .bind(user_id)
+                .bind(&prefix)
+                .bind(limit)
+                .bind(offset)
+                .bind(Array(&filter.include))
+                .bind(Array(&filter.exclude))
+                .fetch(pool);
+            send_lines(rows, &tx).await
+        });
+        if let Err(e) = sent {
+            warn!("Failed to stream the listing of user {}: {}", user_id, e);
+            let _ = tx.send(Err(io::Error::other(e.to_string()))).await;
+        }
+    }.in_current_span());
+
+    (
+        StatusCode::OK,
+        [(header::CONTENT_TYPE, "application/x-ndjson")],
+        Body::from_stream(ReceiverStream::new(rx)),
+    ).into_response()
+}
+
+/// Sends `rows` down `tx` one JSON line each, gathered into chunks of about
+/// `NDJSON_CHUNK_BYTES`. Stops early, without an error, once the client is gone.
+async fn send_lines(
+    rows: impl Stream<Item = Result<FileEntry, sqlx::Error>>,
+    tx: &mpsc::Sender<Result<Vec<u8>, io::Error>>,
+) -> Result<(), sqlx::Error> {
+    let mut rows = std::pin::pin!(rows);
+    let mut chunk = Vec::with_capacity(NDJSON_CHUNK_BYTES);
+    while let Some(file) = rows.try_next().await? {
+        serde_json::to_writer(&mut chunk, &file).map_err(|e| sqlx::Error::Decode(Box::new(e)))?;
+        chunk.push(b'\n');
+        if chunk.len() >= NDJSON_CHUNK_BYTES {
+            let full = std::mem::replace(&mut chunk, Vec::with_capacity(NDJSON_CHUNK_BYTES));
+            if tx.send(Ok(full)).await.is_err() {
+                return Ok(());
+            }
+        }
+    }
+    if !chunk.is_empty() {
+        let _ = tx.send(Ok(chunk)).await;
+    }
+    Ok(())
+}
+
+async fn list_all(
+    state: &AppState,
+    user_id: i32,
+    params: GetAllParams,
+    filter: PathFilter,
+) -> (StatusCode, Json<GetAllResponse>) {
+    info!("FETCHING");
+    let server_time = chrono::Utc::now().timestamp();
+
+    let prefix = params.prefix.as_deref().filter(|p| !p.is_empty()).map(|p| format!("{}%", escape_like(p)));
+
+    let Some(since) = params.since else {
+        let query = full_listing_sql(&state.pool, &params);
+        let result = on_db!(&state.pool, pool => sqlx::query_as::<_, FileEntry>(&query)
+            .bind(user_id)
+            .bind(&prefix)
+            .bind(page_limit(&params))
+            .bind(params.offset.unwrap_or(0).max(0))
+            .bind(Array(&filter.include))
+            .bind(Array(&filter.exclude))
+            .fetch_all(pool)
+            .await);
+        let query = format!(
+            r#"
+            SELECT COUNT(*) FROM filehash
+            WHERE user_id = $1 AND (CAST($2 AS TEXT) IS NULL OR file_path LIKE $2 ESCAPE '\')
+              AND {}
+            "#,
+            PathFilter::sql(&state.pool, 3, 4),
+        );
+        let total = on_db!(&state.pool, pool => sqlx::query_scalar::<_, i64>(&query)
+            .bind(user_id)
+            .bind(&prefix)
+            .bind(Array(&filter.include))
+            .bind(Array(&filter.exclude))
+            .fetch_one(pool)
+            .await);
+
+        info!("FETCHED");
+        return match (result, total) {
+            (Ok(rows), Ok(total)) => (
+                StatusCode::OK,
+                Json(GetAllResponse {
+                    data: Some(rows),
+                    server_time: Some(server_time),
+                    total: Some(total),
+                    ..Default::default()
+                }),
+            ),
+            (Err(err), _) | (_, Err(err)) => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(GetAllResponse {
+                    error: Some(err.to_string()),
+                    ..Default::default()
+                }),
+            ),
+        };
+    };
+
+    // `>=` rather than `>`: a row changed in the same second as the previous
+    // listing is returned twice instead of being missed.
+    let since_sql = state.pool.sql("to_timestamp($2)::timestamp", "datetime($2, 'unixepoch')");
+    let query = format!(
+        r#"
+        SELECT file_path, file_hash, file_size, modified_time, system_path AS file_name, content_type, etag, created_at, updated_at, version,
+               metadata, {} AS tags
+        FROM filehash
+        WHERE user_id = $1 AND updated_at >= {}
+          AND (CAST($3 AS TEXT) IS NULL OR file_path LIKE $3 ESCAPE '\')
+          AND {}
+        ORDER BY updated_at
+        "#,
+        tags_sql(&state.pool),
+        since_sql,
+        PathFilter::sql(&state.pool, 4, 5),
+    );
+    let changed = on_db!(&state.pool, pool => sqlx::query_as::<_, FileEntry>(&query)
+        .bind(user_id)
+        .bind(since as f64)
+        .bind(&prefix)
+        .bind(Array(&filter.include))
+        .bind(Array(&filter.exclude))
+        .fetch_all(pool)
+        .await);
+    let query = format!(
+        r#"
+        SELECT file_path, deleted_at
+        FROM tombstones
+        WHERE user_id = $1 AND deleted_at >= {}
+          AND (CAST($3 AS TEXT) IS NULL OR file_path LIKE $3 ESCAPE '\')
+          AND {}
+        ORDER BY deleted_at
+        "#,
+        since_sql,
+        PathFilter::sql(&state.pool, 4, 5),
+    );
+    let deleted = on_db!(&state.pool, pool => sqlx::query_as::<_, Tombstone>(&query)
+        .bind(user_id)
+        .bind(since as f64)
+        .bind(&prefix)
+        .bind(Array(&filter.include))
+        .bind(Array(&filter.exclude))
+        .fetch_all(pool)
+        .await);
+
+    info!("FETCHED");
+    match (changed, deleted) {
+        (Ok(rows), Ok(deleted)) => (
+            StatusCode::OK,
+            Json(GetAllResponse {
+                data: Some(rows),
+                deleted: Some(deleted),
+                server_time: Some(server_time),
+                total: None,
+                ..Default::default()
+            }),
+        ),
+        (Err(err), _) | (_, Err(err)) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(GetAllResponse {
+                error: Some(err.to_string()),
+                ..Default::default()
+            }),
+        ),
+    }
+}
+
+/// Incremental sync: every insert, update and delete bumps a per-user `change_seq`,
+/// and this returns what changed after `since` in sequence order, a page at a time.
+/// Files changed more than once since the cursor appear only once, with their
+/// latest state. Deletions are only remembered for `tombstone_retention_days`, so
+/// a cursor older than the last one forgotten gets 410 and must start over.
+#[utoipa::path(
+    get, path = "/changes", tag = "sync",
+    params(ChangesParams),
+    responses(
+        (status = 200, description = "One page of the change feed", body = ChangesResponse),
+        (status = 410, description = "Deletions after `since` have been forgotten; list again from the beginning", body = ChangesResponse),
+        (status = 401, description = "Missing or invalid bearer token", body = crate::openapi::ErrorBody),
+    )
+)]
+pub(crate) async fn handle_changes(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Query(params): Query<ChangesParams>,
+) -> impl IntoResponse {
+    let since = params.since.unwrap_or(0);
+    let limit = params.limit.unwrap_or(DEFAULT_PAGE_LIMIT).clamp(1, MAX_PAGE_LIMIT);
+    acknowledge_changes(&state, &user, since).await;
+
+    let filter = match device_filter(&state, &user).await {
+        Ok(filter) => filter,
+        Err(err) => return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(ChangesResponse {
+                error: Some(err.to_string()),
+                ..Default::default()
+            }),
+        ),
+    };
+    // Read before the changes: every sequence number up to it is already committed,
+    // so a caught-up cursor can skip past changes the device's profile filters out.
+    let counter = on_db!(&state.pool, pool => sqlx::query_as::<_, (i64, i64)>(
+        "SELECT COALESCE(MAX(seq), 0), COALESCE(MAX(pruned_seq), 0) FROM change_counters WHERE user_id = $1"
+    )
+    .bind(user.user_id)
+    .fetch_one(pool)
+    .await);
+    let head = match counter {
+        // A device starting from the beginning needs no deletions.
+        Ok((_, pruned_seq)) if since > 0 && since < pruned_seq => return (
+            StatusCode::GONE,
+            Json(ChangesResponse {
+                error: Some(format!(
+                    "Deletions after {} are no longer remembered; list again without since",
+                    since
+                )),
+                ..Default::default()
+            }),
+        ),
+        counter => counter.map(|(head, _)| head),
+    };
+
+    // Each side fetches one extra row so the merged page can tell whether more remain.
+    let query = format!(
+        r#"
+        SELECT change_seq, file_path, file_hash, file_size, modified_time, system_path AS file_name, content_type, etag, created_at, updated_at, version
+        FROM filehash
+        WHERE user_id = $1 AND change_seq > $2 AND {}
+        ORDER BY change_seq
+        LIMIT $3
+        "#,
+        PathFilter::sql(&state.pool, 4, 5),
+    );
+    let changed = on_db!(&state.pool, pool => sqlx::query_as::<_, ChangedFile>(&query)
+        .bind(user.user_id)
+        .bind(since)
+        .bind(limit + 1)
+        .bind(Array(&filter.include))
+        .bind(Array(&filter.exclude))
+        .fetch_all(pool)
+        .await);
+    let query = format!(
+        r#"
+        SELECT change_seq, file_path, deleted_at
+        FROM tombstones
+        WHERE user_id = $1 AND change_seq > $2 AND {}
+        ORDER BY change_seq
+        LIMIT $3
+        "#,
+        PathFilter::sql(&state.pool, 4, 5),
+    );
+    let deleted = on_db!(&state.pool, pool => sqlx::query_as::<_, (i64, String, NaiveDateTime)>(&query)
+        .bind(user.user_id)
+        .bind(since)
+        .bind(limit + 1)
+        .bind(Array(&filter.include))
+        .bind(Array(&filter.exclude))
+        .fetch_all(pool)
+        .await);
+
+    let (head, changed, deleted) = match (head, changed, deleted) {
+        (Ok(head), Ok(changed), Ok(deleted)) => (head, changed, deleted),
+        (Err(err), _, _) | (_, Err(err), _) | (_, _, Err(err)) => return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(ChangesResponse {
+                error: Some(err.to_string()),
+                ..Default::default()
+            }),
+        ),
+    };
+
+    let mut changes: Vec<Change> = changed
+        .into_iter()
+        .map(|row| Change {
+            change_seq: row.change_seq,
+            file_path: row.file.file_path.clone(),
+            deleted: false,
+            deleted_at: None,
+            file: Some(row.file),
+        })
+        .chain(deleted.into_iter().map(|(change_seq, file_path, deleted_at)| Change {
+            change_seq,
+            file_path,
+            deleted: true,
+            deleted_at: Some(deleted_at),
+            file: None,
+        }))
+        .collect();
+    changes.sort_by_key(|change| change.change_seq);
+
+    let has_more = changes.len() > limit as usize;
+    changes.truncate(limit as usize);
+    let cursor = changes.last().map_or(since, |change| change.change_seq);
+    let cursor = if has_more { cursor } else { cursor.max(head) };
+
+    (
+        StatusCode::OK,
+        Json(ChangesResponse {
+            data: Some(changes),
+            cursor: Some(cursor),
+            has_more,
+            error: None,
+        }),
+    )
+}
+
+/// Forgets deletions older than `tombstone_retention_days` every `every`, outside
+/// maintenance.
+pub(crate) async fn prune_tombstones_periodically(state: AppState, every: Duration) {
+    let mut interval = tokio::time::interval(every);
+    loop {
+        interval.tick().await;
+        if in_maintenance(&state).await {
+            continue;
+        }
+        if let Err(e) = prune_tombstones(&state).await {
+            warn!("Failed to prune tombstones: {}", e);
+        }
+    }
+}
+
+/// Deletes tombstones older than `tombstone_retention_days`, first moving each
+/// user's `pruned_seq` past them so `/changes` can turn away cursors that would
+/// miss them.
+pub(crate) async fn prune_tombstones(state: &AppState) -> Result<(), sqlx::Error> {
+    let retention_days = state.settings.current().tombstone_retention_days;
+    if retention_days == 0 {
+        return Ok(());
+    }
+    let cutoff = (Utc::now() - chrono::Duration::days(retention_days)).naive_utc();
+
+    let sql = state.pool.sql(
+        r#"
+        UPDATE change_counters
+        SET pruned_seq = GREATEST(pruned_seq, (
+            SELECT MAX(change_seq) FROM tombstones t WHERE t.user_id = change_counters.user_id AND t.deleted_at < $1
+        ))
+        WHERE user_id IN (SELECT user_id FROM tombstones WHERE deleted_at < $1)
+        "#,
+        r#"
+        UPDATE change_counters
+        SET pruned_seq = MAX(pruned_seq, (
+            SELECT MAX(change_seq) FROM tombstones t WHERE t.user_id = change_counters.user_id AND t.deleted_at < $1
+        ))
+        WHERE user_id IN (SELECT user_id FROM tombstones WHERE deleted_at < $1)
+        "#,
+    );
+    on_db!(&state.pool, pool => sqlx::query(sql)
+        .bind(cutoff)
+        .execute(pool)
+        .await
+        .map(|_| ()))?;
+
+    let pruned = on_db!(&state.pool, pool => sqlx::query("DELETE FROM tombstones WHERE deleted_at < $1")
+        .bind(cutoff)
+        .execute(pool)
+        .await
+        .map(|r| r.rows_affected()))?;
+    if pruned > 0 {
+        info!("PRUNED {} TOMBSTONES", pruned);
+    }
+    Ok(())
+}
+
+/// Finds files by a substring of their path or original name, narrowed by extension,
+/// size and modification time. `q` may be left out when a filter is given.
+#[utoipa::path(
+    get, path = "/search", tag = "files",
+    params(SearchParams),
+    responses(
+        (status = 200, description = "Matching files", body = GetAllResponse),
+        (status = 400, description = "`q` is too short and no filter is given", body = GetAllResponse),
+        (status = 401, description = "Missing or invalid bearer token", body = crate::openapi::ErrorBody),
+    )
+)]
+pub(crate) async fn handle_search(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Query(params): Query<SearchParams>,
+) -> impl IntoResponse {
+    let query = params.q.as_deref().map(str::trim).filter(|q| !q.is_empty());
+    let too_short = match query {
+        Some(query) => query.chars().count() < MIN_SEARCH_QUERY_LEN,
+        None => !params.has_filters(),
+    };
+    if too_short {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(GetAllResponse {
+                error: Some(format!(
+                    "Query must be at least {} characters",
+                    MIN_SEARCH_QUERY_LEN
+                )),
+                ..Default::default()
+            }),
+        );
+    }
+
+    let limit = params.limit.unwrap_or(DEFAULT_PAGE_LIMIT).clamp(1, MAX_PAGE_LIMIT);
+    let offset = params.offset.unwrap_or(0).max(0);
+
+    info!("SEARCHING: {}", query.unwrap_or_default());
+    // SQLite has neither `ILIKE` nor regex captures; an extension there is what
+    // follows the path's last dot, given no dot or slash comes after it.
+    let sql = state.pool.sql(
+        r#"
+        SELECT file_path, file_hash, file_size, modified_time, system_path AS file_name, content_type, etag, created_at, updated_at, version
+        FROM filehash
+        WHERE user_id = $1
+          AND (CAST($2 AS TEXT) IS NULL OR file_path ILIKE $2 ESCAPE '\' OR file_name ILIKE $2 ESCAPE '\')
+          AND ($3::TEXT[] IS NULL OR lower(substring(file_path FROM '\.([^./]+)$')) = ANY($3))
+          AND (CAST($4 AS BIGINT) IS NULL OR file_size >= $4)
+          AND (CAST($5 AS BIGINT) IS NULL OR file_size <= $5)
+          AND (CAST($6 AS BIGINT) IS NULL OR modified_time >= $6)
+          AND (CAST($7 AS BIGINT) IS NULL OR modified_time <= $7)
+        ORDER BY file_path
+        LIMIT $8 OFFSET $9
+        "#,
+        r#"
+        SELECT file_path, file_hash, file_size, modified_time, system_path AS file_name, content_type, etag, created_at, updated_at, version
+        FROM filehash
+        WHERE user_id = $1
+          AND ($2 IS NULL OR lower(file_path) LIKE lower($2) ESCAPE '\' OR lower(file_name) LIKE lower($2) ESCAPE '\')
+          AND ($3 IS NULL OR EXISTS (
+              SELECT 1 FROM json_each($3)
+              WHERE substr(lower(file_path), -length(value) - 1) = '.' || value AND instr(value, '.') + instr(value, '/') = 0
+          ))
+          AND ($4 IS NULL OR file_size >= $4)
+          AND ($5 IS NULL OR file_size <= $5)
+          AND ($6 IS NULL OR modified_time >= $6)
+          AND ($7 IS NULL OR modified_time <= $7)
+        ORDER BY file_path
+        LIMIT $8 OFFSET $9
+        "#,
+    );
+    let extensions = params.extensions();
+    let result = on_db!(&state.pool, pool => sqlx::query_as::<_, FileEntry>(sql)
+    .bind(user.user_id)
+    .bind(query.map(|q| format!("%{}%", escape_like(q))))
+    .bind(extensions.as_deref().map(Array))
+    .bind(params.min_size)
+    .bind(params.max_size)
+    .bind(params.modified_after)
+    .bind(params.modified_before)
+    .bind(limit)
+    .bind(offset)
+    .fetch_all(pool)
+    .await);
+
+    match result {
+        Ok(rows) => (
+            StatusCode::OK,
+            Json(GetAllResponse {
+                data: Some(rows),
+                ..Default::default()
+            }),
+        ),
+        Err(err) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(GetAllResponse {
+                error: Some(err.to_string()),
+                ..Default::default()
+            }),
+        ),
+    }
+}
+
+/// The sorted tags of each `filehash` row, as a `TEXT[]` or a JSON array.
+pub(crate) fn tags_sql(pool: &DbPool) -> &'static str {
+    pool.sql(
+        "ARRAY(SELECT tag FROM file_tags WHERE file_id = filehash.id ORDER BY tag)",
+        "(SELECT json_group_array(tag) FROM (SELECT tag FROM file_tags WHERE file_id = filehash.id ORDER BY tag))",
+    )
+}
+
+/// Escapes `LIKE` wildcards so user input is matched literally.
+pub(crate) fn escape_like(input: &str) -> String {
+    let mut escaped = String::with_capacity(input.len());
+    for c in input.chars() {
+        if matches!(c, '\\' | '%' | '_') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
@@ -0,0 +1,161 @@
+use axum::{
+    extract::{Extension, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use tracing::info;
+
+use crate::{
+    db::{find_foreign_lock, on_db},
+    error::AppError,
+    handlers::devices::require_device,
+    models::{AuthUser, FileLock, LockRequest, UnlockParams},
+    AppState, DEFAULT_LOCK_TTL_SECS, MAX_LOCK_TTL_SECS,
+};
+
+/// Locks one of the caller's files for the device in `X-Device-Id`, so `/sync`
+/// refuses updates of it from the user's other devices until the lease runs out.
+/// Locking a file the device already holds renews the lease.
+#[utoipa::path(
+    post, path = "/lock", tag = "sync",
+    params(("X-Device-Id" = String, Header, description = "The device taking the lock")),
+    request_body = LockRequest,
+    responses(
+        (status = 200, description = "The lock, now held by the caller's device", body = FileLock),
+        (status = 400, description = "Invalid request", body = crate::openapi::ErrorBody),
+        (status = 404, description = "No such file", body = crate::openapi::ErrorBody),
+        (status = 409, description = "Another device holds the lock", body = crate::openapi::ErrorBody),
+    )
+)]
+pub(crate) async fn handle_lock(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Json(req): Json<LockRequest>,
+) -> Result<Response, AppError> {
+    require_device(&state, &user).await?;
+    let device_id = user.device_id.as_deref().unwrap_or_default();
+    let expires_in_secs = req.expires_in_secs.unwrap_or(DEFAULT_LOCK_TTL_SECS);
+    if !(1..=MAX_LOCK_TTL_SECS).contains(&expires_in_secs) {
+        return Err(AppError::BadRequest(format!(
+            "expires_in_secs must be between 1 and {}",
+            MAX_LOCK_TTL_SECS
+        )));
+    }
+
+    let exists = on_db!(&state.pool, pool => sqlx::query_scalar::<_, bool>(
+        "SELECT EXISTS (SELECT 1 FROM filehash WHERE user_id = $1 AND file_path = $2)"
+    )
+    .bind(user.user_id)
+    .bind(&req.file_path)
+    .fetch_one(pool)
+    .await)?;
+    if !exists {
+        return Err(AppError::NotFound("File not found".into()));
+    }
+
+    let _ = on_db!(&state.pool, pool => sqlx::query(
+        "DELETE FROM file_locks WHERE user_id = $1 AND expires_at <= CURRENT_TIMESTAMP"
+    )
+    .bind(user.user_id)
+    .execute(pool)
+    .await
+    .map(|_| ()));
+
+    // Taken when nobody holds the lock, renewed when this device does. The
+    // update is skipped for a lock another device holds, so nothing is returned.
+    let sql = state.pool.sql(
+        r#"
+        INSERT INTO file_locks (user_id, file_path, device_id, expires_at)
+        VALUES ($1, $2, $3, CURRENT_TIMESTAMP + make_interval(secs => $4))
+        ON CONFLICT (user_id, file_path) DO UPDATE
+        SET expires_at = EXCLUDED.expires_at,
+            acquired_at = CASE WHEN file_locks.device_id = EXCLUDED.device_id
+                THEN file_locks.acquired_at ELSE EXCLUDED.acquired_at END,
+            device_id = EXCLUDED.device_id
+        WHERE file_locks.device_id = EXCLUDED.device_id OR file_locks.expires_at <= CURRENT_TIMESTAMP
+        RETURNING file_path, device_id, acquired_at, expires_at
+        "#,
+        r#"
+        INSERT INTO file_locks (user_id, file_path, device_id, expires_at)
+        VALUES ($1, $2, $3, datetime('now', $4 || ' seconds'))
+        ON CONFLICT (user_id, file_path) DO UPDATE
+        SET expires_at = EXCLUDED.expires_at,
+            acquired_at = CASE WHEN file_locks.device_id = EXCLUDED.device_id
+                THEN file_locks.acquired_at ELSE EXCLUDED.acquired_at END,
+            device_id = EXCLUDED.device_id
+        WHERE file_locks.device_id = EXCLUDED.device_id OR file_locks.expires_at <= CURRENT_TIMESTAMP
+        RETURNING file_path, device_id, acquired_at, expires_at
+        "#,
+    );
+    let lock = on_db!(&state.pool, pool => sqlx::query_as::<_, FileLock>(sql)
+    .bind(user.user_id)
+    .bind(&req.file_path)
+    .bind(device_id)
+    .bind(expires_in_secs as f64)
+    .fetch_optional(pool)
+    .await)?;
+
+    match lock {
+        Some(lock) => {
+            info!(user_id = user.user_id, device_id, "Locked {} until {}", lock.file_path, lock.expires_at);
+            Ok((StatusCode::OK, Json(lock)).into_response())
+        }
+        None => Err(held_elsewhere(&state, &user, &req.file_path).await),
+    }
+}
+
+/// Releases the lock the device in `X-Device-Id` holds on a file. A lock that has
+/// already run out can be released by any of the user's devices.
+#[utoipa::path(
+    delete, path = "/lock", tag = "sync",
+    params(
+        UnlockParams,
+        ("X-Device-Id" = String, Header, description = "The device holding the lock"),
+    ),
+    responses(
+        (status = 204, description = "The lock was released"),
+        (status = 404, description = "The file isn't locked", body = crate::openapi::ErrorBody),
+        (status = 409, description = "Another device holds the lock", body = crate::openapi::ErrorBody),
+    )
+)]
+pub(crate) async fn handle_unlock(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Query(params): Query<UnlockParams>,
+) -> Result<Response, AppError> {
+    require_device(&state, &user).await?;
+
+    let released = on_db!(&state.pool, pool => sqlx::query_scalar::<_, String>(
+        r#"
+        DELETE FROM file_locks
+        WHERE user_id = $1 AND file_path = $2 AND (device_id = $3 OR expires_at <= CURRENT_TIMESTAMP)
+        RETURNING file_path
+        "#
+    )
+    .bind(user.user_id)
+    .bind(&params.file_path)
+    .bind(&user.device_id)
+    .fetch_optional(pool)
+    .await)?;
+
+    if released.is_some() {
+        info!(user_id = user.user_id, device_id = user.device_id, "Unlocked {}", params.file_path);
+        return Ok(StatusCode::NO_CONTENT.into_response());
+    }
+    Err(held_elsewhere(&state, &user, &params.file_path).await)
+}
+
+/// The error for a lock the caller's device couldn't take or release: a conflict
+/// naming the holder, or not found once the other device's lease is gone too.
+async fn held_elsewhere(state: &AppState, user: &AuthUser, file_path: &str) -> AppError {
+    let device_id = user.device_id.as_deref();
+    match find_foreign_lock(state.pool.executor(), user.user_id, file_path, device_id).await {
+        Ok(Some(lock)) => AppError::Conflict(format!(
+            "{} is locked by device {} until {}",
+            lock.file_path, lock.device_id, lock.expires_at
+        )),
+        Ok(None) => AppError::NotFound("The file isn't locked".into()),
+        Err(e) => e.into(),
+    }
+}
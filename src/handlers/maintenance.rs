@@ -0,0 +1,102 @@
+//! Maintenance mode: a switch admins flip during migrations or bucket moves to
+//! freeze every write to files while reads carry on. It lives in the database, so
+//! every instance behind a load balancer sees it at once.
+
+use axum::{
+    extract::{Extension, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use tracing::{info, warn};
+
+use crate::{
+    db::on_db,
+    error::AppError,
+    models::{AuthUser, Maintenance, MaintenanceRequest},
+    AppState,
+};
+
+/// The maintenance mode the server is in, or `None` when writes are allowed.
+pub(crate) async fn current_maintenance(state: &AppState) -> Result<Option<Maintenance>, sqlx::Error> {
+    on_db!(&state.pool, pool => sqlx::query_as::<_, Maintenance>(
+        r#"
+        SELECT m.reason, u.username AS started_by, m.started_at
+        FROM maintenance m
+        LEFT JOIN users u ON u.id = m.started_by
+        "#
+    )
+    .fetch_optional(pool)
+    .await)
+}
+
+/// The maintenance mode writes are checked against. A lookup that fails lets them
+/// through: with the database down they fail anyway, with their own error.
+pub(crate) async fn active_maintenance(state: &AppState) -> Option<Maintenance> {
+    current_maintenance(state).await.unwrap_or_else(|e| {
+        warn!("Failed to look up maintenance mode: {}", e);
+        None
+    })
+}
+
+/// Whether background tasks that change stored files should skip their turn.
+pub(crate) async fn in_maintenance(state: &AppState) -> bool {
+    active_maintenance(state).await.is_some()
+}
+
+/// The `503` a write gets during maintenance, with a `code` clients can tell it
+/// apart from an overloaded server by.
+pub(crate) fn maintenance_response(maintenance: &Maintenance) -> Response {
+    (StatusCode::SERVICE_UNAVAILABLE, Json(serde_json::json!({
+        "error": "The server is in maintenance mode; changes to files are paused",
+        "code": "maintenance",
+        "reason": maintenance.reason,
+        "since": maintenance.started_at,
+    }))).into_response()
+}
+
+pub(crate) async fn handle_get_maintenance(State(state): State<AppState>) -> Result<Response, AppError> {
+    let maintenance = current_maintenance(&state).await?;
+    Ok((StatusCode::OK, Json(maintenance_state(maintenance))).into_response())
+}
+
+/// Turns maintenance mode on or off. Turning it on again only updates the reason,
+/// so `since` keeps telling how long writes have been frozen.
+pub(crate) async fn handle_set_maintenance(
+    State(state): State<AppState>,
+    Extension(admin): Extension<AuthUser>,
+    Json(req): Json<MaintenanceRequest>,
+) -> Result<Response, AppError> {
+    if req.enabled {
+        on_db!(&state.pool, pool => sqlx::query(
+            r#"
+            INSERT INTO maintenance (id, reason, started_by) VALUES (1, $1, $2)
+            ON CONFLICT (id) DO UPDATE SET reason = EXCLUDED.reason
+            "#
+        )
+        .bind(&req.reason)
+        .bind(admin.user_id)
+        .execute(pool)
+        .await
+        .map(|_| ()))?;
+        warn!(user_id = admin.user_id, reason = ?req.reason, "MAINTENANCE MODE ON: writes are frozen");
+    } else {
+        on_db!(&state.pool, pool => sqlx::query("DELETE FROM maintenance")
+            .execute(pool)
+            .await
+            .map(|_| ()))?;
+        info!(user_id = admin.user_id, "MAINTENANCE MODE OFF");
+    }
+
+    let maintenance = current_maintenance(&state).await?;
+    Ok((StatusCode::OK, Json(maintenance_state(maintenance))).into_response())
+}
+
+fn maintenance_state(maintenance: Option<Maintenance>) -> serde_json::Value {
+    serde_json::json!({
+        "data": {
+            "enabled": maintenance.is_some(),
+            "maintenance": maintenance,
+        }
+    })
+}
@@ -0,0 +1,238 @@
+//! `POST /admin/migrate`: a background job copying every stored object to
+//! `MIGRATION_BUCKET`, for moving a deployment to a new bucket. `POST /admin/reroute`:
+//! one moving files to the bucket the routing table now sends them to.
+
+use axum::{
+    extract::{Extension, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use futures::StreamExt;
+use tracing::{info, info_span, warn, Instrument};
+
+use crate::{
+    db::{create_job, finish_job, mark_job_running, on_db, report_progress, report_total},
+    error::AppError,
+    models::{AuthUser, MigrationFailure, MigrationReport, RerouteReport},
+    storage::{
+        migrate::{BucketMigration, Copied},
+        routing::{target_of, with_target},
+    },
+    AppState, MIGRATION_PROGRESS_INTERVAL,
+};
+
+/// Starts copying every object to `MIGRATION_BUCKET` as a background job, whose
+/// progress counts the objects worked through. Run it with maintenance mode on, so
+/// nothing is written meanwhile; a run repeated to catch up skips the objects an
+/// earlier one copied. Keys are kept, so once it's done the server only needs
+/// `S3_BUCKET` changed.
+pub(crate) async fn handle_migrate(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+) -> Result<Response, AppError> {
+    let Some(bucket) = state.config.migration_bucket.clone() else {
+        return Err(AppError::BadRequest("MIGRATION_BUCKET isn't set".into()));
+    };
+    if state.config.storage_backend != "s3" {
+        return Err(AppError::BadRequest("Only S3 storage can be migrated to another bucket".into()));
+    }
+    if bucket == state.config.bucket {
+        return Err(AppError::BadRequest(format!("{} is already the bucket in use", bucket)));
+    }
+    let migration = BucketMigration::new(&state.config, &bucket)
+        .await
+        .map_err(|e| AppError::BadGateway(format!("Bucket {} is unavailable: {}", bucket, e)))?;
+
+    let job_id = create_job(&state.pool, Some(user.user_id), "migrate", 0)
+        .await
+        .map_err(|e| AppError::Internal(format!("Failed to create job: {}", e)))?;
+    info!(user_id = user.user_id, job_id, "MIGRATION STARTED: to bucket {}", bucket);
+
+    let tasks = state.tasks.clone();
+    tasks.spawn(async move {
+        mark_job_running(&state.pool, job_id).await;
+        let outcome = migrate(&state, &migration, job_id)
+            .await
+            .and_then(|report| serde_json::to_value(report).map_err(|e| e.to_string()));
+        if let Err(e) = &outcome {
+            warn!("Bucket migration failed: {}", e);
+        }
+        finish_job(&state.pool, job_id, outcome).await;
+    }.instrument(info_span!("migration_job", job_id)));
+
+    Ok((
+        StatusCode::ACCEPTED,
+        Json(serde_json::json!({
+            "job_id": job_id,
+            "status_url": format!("/jobs/{}", job_id)
+        }))
+    ).into_response())
+}
+
+async fn migrate(state: &AppState, migration: &BucketMigration, job_id: i32) -> Result<MigrationReport, String> {
+    let objects = migration.objects().await?;
+    report_total(&state.pool, job_id, objects.len() as i32).await;
+    let mut report = MigrationReport {
+        target_bucket: migration.target_bucket().to_string(),
+        scanned_objects: objects.len(),
+        ..Default::default()
+    };
+
+    let mut copies = futures::stream::iter(objects)
+        .map(|object| async move {
+            let copied = migration.copy(&object.key).await;
+            (object.key, copied)
+        })
+        .buffer_unordered(state.sync_concurrency);
+    let mut processed = 0;
+    while let Some((key, copied)) = copies.next().await {
+        match copied {
+            Ok(Copied::ServerSide) => report.copied += 1,
+            Ok(Copied::Streamed) => report.streamed += 1,
+            Ok(Copied::Present) => report.already_present += 1,
+            Err(error) => {
+                warn!("Failed to migrate {}: {}", key, error);
+                report.failed.push(MigrationFailure { key, error });
+            }
+        }
+        processed += 1;
+        if processed % MIGRATION_PROGRESS_INTERVAL == 0 {
+            report_progress(&state.pool, Some(job_id), processed).await;
+        }
+    }
+    report_progress(&state.pool, Some(job_id), processed).await;
+
+    info!(
+        "MIGRATED: {} copied, {} streamed, {} already present, {} failed",
+        report.copied,
+        report.streamed,
+        report.already_present,
+        report.failed.len()
+    );
+    Ok(report)
+}
+
+/// Starts moving every file and vault item whose object isn't in the bucket its
+/// route names to that bucket, as a background job whose progress counts those
+/// looked at. Each gets a new key in its bucket, copied to before the row is
+/// switched over and the old object deleted; one changed meanwhile is left for a
+/// later run. Trash entries and revisions stay where they were written.
+pub(crate) async fn handle_reroute(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+) -> Result<Response, AppError> {
+    let job_id = create_job(&state.pool, Some(user.user_id), "reroute", 0)
+        .await
+        .map_err(|e| AppError::Internal(format!("Failed to create job: {}", e)))?;
+    info!(user_id = user.user_id, job_id, "REROUTE STARTED");
+
+    let tasks = state.tasks.clone();
+    tasks.spawn(async move {
+        mark_job_running(&state.pool, job_id).await;
+        let outcome = reroute(&state, job_id)
+            .await
+            .and_then(|report| serde_json::to_value(report).map_err(|e| e.to_string()));
+        if let Err(e) = &outcome {
+            warn!("Rerouting failed: {}", e);
+        }
+        finish_job(&state.pool, job_id, outcome).await;
+    }.instrument(info_span!("reroute_job", job_id)));
+
+    Ok((
+        StatusCode::ACCEPTED,
+        Json(serde_json::json!({
+            "job_id": job_id,
+            "status_url": format!("/jobs/{}", job_id)
+        }))
+    ).into_response())
+}
+
+/// A stored object, by the table holding its key and the row's owner and id there.
+struct Routed {
+    table: &'static str,
+    id_column: &'static str,
+    user_id: i32,
+    id: String,
+    key: String,
+}
+
+async fn reroute(state: &AppState, job_id: i32) -> Result<RerouteReport, String> {
+    let files = on_db!(&state.pool, pool => sqlx::query_as::<_, (i32, String, String)>(
+        "SELECT user_id, file_path, system_path FROM filehash"
+    )
+    .fetch_all(pool)
+    .await)
+    .map_err(|e| e.to_string())?;
+    let items = on_db!(&state.pool, pool => sqlx::query_as::<_, (i32, String, String)>(
+        "SELECT user_id, item_id, system_path FROM vault_items"
+    )
+    .fetch_all(pool)
+    .await)
+    .map_err(|e| e.to_string())?;
+    let mut report = RerouteReport { scanned: files.len() + items.len(), ..Default::default() };
+    report_total(&state.pool, job_id, report.scanned as i32).await;
+
+    let routing = &state.config.routing;
+    let files = files.into_iter().map(|(user_id, path, key)| {
+        let target = routing.target_for(user_id, &path).map(String::from);
+        (Routed { table: "filehash", id_column: "file_path", user_id, id: path, key }, target)
+    });
+    let items = items.into_iter().map(|(user_id, item_id, key)| {
+        let target = routing.target_for(user_id, "").map(String::from);
+        (Routed { table: "vault_items", id_column: "item_id", user_id, id: item_id, key }, target)
+    });
+    let moves: Vec<_> = files.chain(items).filter(|(routed, target)| target_of(&routed.key) != target.as_deref()).collect();
+    let unmoved = report.scanned - moves.len();
+
+    let mut moved = futures::stream::iter(moves)
+        .map(|(routed, target)| async move {
+            let moved = move_object(state, &routed, target.as_deref()).await;
+            (routed.key, moved)
+        })
+        .buffer_unordered(state.sync_concurrency);
+    let mut processed = unmoved as i32;
+    while let Some((key, moved)) = moved.next().await {
+        match moved {
+            Ok(true) => report.moved += 1,
+            Ok(false) => report.skipped += 1,
+            Err(error) => {
+                warn!("Failed to reroute {}: {}", key, error);
+                report.failed.push(MigrationFailure { key, error });
+            }
+        }
+        processed += 1;
+        if processed % MIGRATION_PROGRESS_INTERVAL == 0 {
+            report_progress(&state.pool, Some(job_id), processed).await;
+        }
+    }
+    report_progress(&state.pool, Some(job_id), processed).await;
+
+    info!("REROUTED: {} moved, {} skipped, {} failed", report.moved, report.skipped, report.failed.len());
+    Ok(report)
+}
+
+/// Moves `routed` to a key in `target`, and returns whether its row still held the
+/// old key to switch over.
+async fn move_object(state: &AppState, routed: &Routed, target: Option<&str>) -> Result<bool, String> {
+    let key = with_target(&routed.key, target);
+    state.storage.copy(&routed.key, &key).await?;
+    let sql = format!(
+        "UPDATE {} SET system_path = $4 WHERE user_id = $1 AND {} = $2 AND system_path = $3",
+        routed.table, routed.id_column
+    );
+    let switched = on_db!(&state.pool, pool => sqlx::query(&sql)
+        .bind(routed.user_id)
+        .bind(&routed.id)
+        .bind(&routed.key)
+        .bind(&key)
+        .execute(pool)
+        .await
+        .map(|r| r.rows_affected() > 0))
+    .map_err(|e| e.to_string())?;
+    let stale = if switched { &routed.key } else { &key };
+    if let Err(e) = state.storage.delete(stale).await {
+        warn!("Failed to delete {} after rerouting: {}", stale, e);
+    }
+    Ok(switched)
+}
@@ -0,0 +1,43 @@
+pub(crate) mod admin;
+pub(crate) mod archive;
+pub(crate) mod audit;
+pub(crate) mod auth;
+pub(crate) mod blocks;
+pub(crate) mod catalog;
+pub(crate) mod collaborators;
+pub(crate) mod dav;
+pub(crate) mod devices;
+pub(crate) mod diff;
+pub(crate) mod docs;
+pub(crate) mod downloads;
+pub(crate) mod duplicates;
+pub(crate) mod events;
+pub(crate) mod filedrops;
+pub(crate) mod files;
+pub(crate) mod gallery;
+pub(crate) mod health;
+pub(crate) mod integrity;
+pub(crate) mod jobs;
+pub(crate) mod listing;
+pub(crate) mod locks;
+pub(crate) mod maintenance;
+pub(crate) mod migration;
+pub(crate) mod notifications;
+pub(crate) mod photos;
+pub(crate) mod policies;
+pub(crate) mod profiles;
+pub(crate) mod quarantine;
+pub(crate) mod remote;
+pub(crate) mod schedule;
+pub(crate) mod settings;
+pub(crate) mod shares;
+pub(crate) mod snapshots;
+pub(crate) mod storage;
+pub(crate) mod sync;
+pub(crate) mod thumbnails;
+pub(crate) mod trash;
+pub(crate) mod uploads;
+pub(crate) mod vault;
+pub(crate) mod usage;
+pub(crate) mod versions;
+pub(crate) mod webhooks;
@@ -0,0 +1,96 @@
+use axum::{
+    extract::{Extension, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use tracing::info;
+
+use crate::{
+    db::{on_db, Array},
+    error::AppError,
+    models::{AuthUser, NotificationPreferences},
+    notifications::{load_preferences, NOTIFICATION_EVENTS},
+    AppState,
+};
+
+/// The caller's notification preferences, and the channels this server can send
+/// through.
+#[utoipa::path(
+    get, path = "/notifications", tag = "notifications",
+    responses((status = 200, description = "The caller's preferences", body = crate::openapi::Data<NotificationPreferences>))
+)]
+pub(crate) async fn handle_get_notifications(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+) -> Result<Response, AppError> {
+    let preferences = load_preferences(&state, user.user_id).await?.unwrap_or_default();
+    let channels = state.notifier.as_ref().map(|notifier| notifier.channels()).unwrap_or_default();
+
+    Ok((StatusCode::OK, Json(serde_json::json!({ "data": preferences, "channels": channels }))).into_response())
+}
+
+/// Replaces the caller's notification preferences. A channel left out isn't
+/// notified on.
+#[utoipa::path(
+    put, path = "/notifications", tag = "notifications",
+    request_body = NotificationPreferences,
+    responses(
+        (status = 200, description = "The caller's preferences", body = crate::openapi::Data<NotificationPreferences>),
+        (status = 400, description = "Invalid request", body = crate::openapi::ErrorBody),
+    )
+)]
+pub(crate) async fn handle_update_notifications(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Json(req): Json<NotificationPreferences>,
+) -> Result<Response, AppError> {
+    if let Some(unknown) = req.events.iter().find(|event| !NOTIFICATION_EVENTS.contains(&event.as_str())) {
+        return Err(AppError::BadRequest(format!(
+            "Unknown event {}; expected one of {}",
+            unknown,
+            NOTIFICATION_EVENTS.join(", ")
+        )));
+    }
+    let trimmed = |value: Option<String>| value.map(|v| v.trim().to_string()).filter(|v| !v.is_empty());
+    let email = trimmed(req.email);
+    let ntfy_topic = trimmed(req.ntfy_topic);
+    let push_token = trimmed(req.push_token);
+    if let Some(email) = &email
+        && (!email.contains('@') || email.chars().any(|c| c.is_whitespace() || c.is_control() || c == '<' || c == '>'))
+    {
+        return Err(AppError::BadRequest(format!("Invalid email: {}", email)));
+    }
+
+    let channels = state.notifier.as_ref().map(|notifier| notifier.channels()).unwrap_or_default();
+    for (channel, set) in [("email", email.is_some()), ("ntfy", ntfy_topic.is_some()), ("push", push_token.is_some())] {
+        if set && !channels.contains(&channel) {
+            return Err(AppError::BadRequest(format!("This server doesn't send notifications by {}", channel)));
+        }
+    }
+    let mut events = req.events;
+    events.sort();
+    events.dedup();
+
+    on_db!(&state.pool, pool => sqlx::query(
+        r#"
+        INSERT INTO notification_preferences (user_id, email, ntfy_topic, push_token, events)
+        VALUES ($1, $2, $3, $4, $5)
+        ON CONFLICT (user_id) DO UPDATE
+        SET email = excluded.email, ntfy_topic = excluded.ntfy_topic, push_token = excluded.push_token,
+            events = excluded.events, updated_at = CURRENT_TIMESTAMP
+        "#
+    )
+    .bind(user.user_id)
+    .bind(&email)
+    .bind(&ntfy_topic)
+    .bind(&push_token)
+    .bind(Array(&events))
+    .execute(pool)
+    .await
+    .map(|_| ()))?;
+
+    info!(user_id = user.user_id, "Updated notification preferences");
+    let preferences = NotificationPreferences { email, ntfy_topic, push_token, events };
+    Ok((StatusCode::OK, Json(serde_json::json!({ "data": preferences }))).into_response())
+}
@@ -0,0 +1,135 @@
+//! The photo timeline: the caller's images by the day they were taken, as their
+//! EXIF data records it. The data is read by the job that generates thumbnails
+//! after each upload, into `media_metadata`.
+
+use std::sync::Arc;
+
+use axum::{
+    extract::{Extension, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{Days, NaiveTime};
+use tracing::warn;
+
+use crate::{
+    db::on_db,
+    error::AppError,
+    exif::read_exif,
+    models::{AuthUser, TimelineDay, TimelineParams, TimelinePhoto},
+    AppState, DEFAULT_PAGE_LIMIT, MAX_PAGE_LIMIT,
+};
+
+/// Records what the EXIF data of the image stored at `key` says, for every file
+/// entry holding it. Images without EXIF data get a row too, all `NULL`.
+pub(crate) async fn record_media_metadata(state: &AppState, key: &str, data: Arc<Vec<u8>>) {
+    let exif = match tokio::task::spawn_blocking(move || read_exif(&data)).await {
+        Ok(exif) => exif,
+        Err(e) => {
+            warn!("Failed to read EXIF data of {}: {}", key, e);
+            return;
+        }
+    };
+
+    let result = on_db!(&state.pool, pool => sqlx::query(
+        r#"
+        INSERT INTO media_metadata (file_id, taken_at, camera_make, camera_model, latitude, longitude)
+        SELECT id, $2, $3, $4, $5, $6 FROM filehash WHERE system_path = $1
+        ON CONFLICT (file_id) DO UPDATE SET
+            taken_at = EXCLUDED.taken_at,
+            camera_make = EXCLUDED.camera_make,
+            camera_model = EXCLUDED.camera_model,
+            latitude = EXCLUDED.latitude,
+            longitude = EXCLUDED.longitude
+        "#
+    )
+    .bind(key)
+    .bind(exif.taken_at)
+    .bind(&exif.camera_make)
+    .bind(&exif.camera_model)
+    .bind(exif.latitude)
+    .bind(exif.longitude)
+    .execute(pool)
+    .await
+    .map(|_| ()));
+    if let Err(e) = result {
+        warn!("Failed to record media metadata of {}: {}", key, e);
+    }
+}
+
+/// Lists the caller's images grouped by the day they were taken, newest first.
+/// Images whose EXIF data doesn't say, or that haven't been read yet, are placed
+/// by their modified time. `limit` and `offset` page through photos rather than
+/// days, so a day can continue on the next page.
+#[utoipa::path(
+    get, path = "/photos/timeline", tag = "files",
+    params(TimelineParams),
+    responses(
+        (status = 200, description = "The photos, by day", body = crate::openapi::Data<Vec<TimelineDay>>),
+        (status = 400, description = "Invalid request", body = crate::openapi::ErrorBody),
+        (status = 401, description = "Missing or invalid bearer token", body = crate::openapi::ErrorBody),
+    )
+)]
+pub(crate) async fn handle_timeline(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Query(params): Query<TimelineParams>,
+) -> Result<Response, AppError> {
+    if let (Some(from), Some(to)) = (params.from, params.to)
+        && from > to
+    {
+        return Err(AppError::BadRequest("from must not be after to".into()));
+    }
+    let from = params.from.map(|day| day.and_time(NaiveTime::MIN));
+    let until = params
+        .to
+        .and_then(|day| day.checked_add_days(Days::new(1)))
+        .map(|day| day.and_time(NaiveTime::MIN));
+    let limit = params.limit.unwrap_or(DEFAULT_PAGE_LIMIT).clamp(1, MAX_PAGE_LIMIT);
+    let offset = params.offset.unwrap_or(0).max(0);
+
+    let modified = state.pool.sql(
+        "to_timestamp(f.modified_time) AT TIME ZONE 'UTC'",
+        "datetime(f.modified_time, 'unixepoch')",
+    );
+    let query = format!(
+        r#"
+        SELECT * FROM (
+            SELECT f.file_path, f.file_size, f.content_type, f.etag, COALESCE(m.taken_at, {}) AS taken_at,
+                   m.camera_make, m.camera_model, m.latitude, m.longitude
+            FROM filehash f
+            LEFT JOIN media_metadata m ON m.file_id = f.id
+            WHERE f.user_id = $1 AND f.content_type LIKE 'image/%'
+        ) photos
+        WHERE (CAST($2 AS TIMESTAMP) IS NULL OR taken_at >= $2)
+          AND (CAST($3 AS TIMESTAMP) IS NULL OR taken_at < $3)
+        ORDER BY taken_at DESC, file_path
+        LIMIT $4 OFFSET $5
+        "#,
+        modified,
+    );
+    let photos = on_db!(&state.pool, pool => sqlx::query_as::<_, TimelinePhoto>(&query)
+    .bind(user.user_id)
+    .bind(from)
+    .bind(until)
+    .bind(limit)
+    .bind(offset)
+    .fetch_all(pool)
+    .await)?;
+
+    Ok((StatusCode::OK, Json(serde_json::json!({ "data": group_by_day(photos) }))).into_response())
+}
+
+/// Groups photos ordered by time into runs of the same day.
+fn group_by_day(photos: Vec<TimelinePhoto>) -> Vec<TimelineDay> {
+    let mut days: Vec<TimelineDay> = Vec::new();
+    for photo in photos {
+        let date = photo.taken_at.date();
+        match days.last_mut() {
+            Some(day) if day.date == date => day.photos.push(photo),
+            _ => days.push(TimelineDay { date, photos: vec![photo] }),
+        }
+    }
+    days
+}
@@ -0,0 +1,104 @@
+//! `/policy` and `/admin/users/{id}/policy`: the upload policies `/sync` holds
+//! files to. The server-wide one is the `upload_policy` setting.
+
+use axum::{
+    extract::{Extension, Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use tracing::info;
+
+use crate::{
+    db::on_db,
+    error::AppError,
+    models::AuthUser,
+    policy::{effective_policy, user_policy, UploadPolicy},
+    AppState,
+};
+
+/// The policy the caller's uploads are held to, so clients can leave out files
+/// it would refuse.
+#[utoipa::path(
+    get, path = "/policy", tag = "uploads",
+    responses((status = 200, description = "The server's policy and the caller's own, combined", body = crate::openapi::Data<UploadPolicy>))
+)]
+pub(crate) async fn handle_get_policy(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+) -> Result<Response, AppError> {
+    let policy = effective_policy(&state, user.user_id).await?;
+    Ok((StatusCode::OK, Json(serde_json::json!({ "data": policy }))).into_response())
+}
+
+/// The policy an admin gave the user, and what they're held to with the server's.
+pub(crate) async fn handle_get_user_policy(
+    State(state): State<AppState>,
+    Path(id): Path<i32>,
+) -> Result<Response, AppError> {
+    require_user(&state, id).await?;
+    user_policy_response(&state, id).await
+}
+
+/// Replaces the user's own policy.
+pub(crate) async fn handle_put_user_policy(
+    State(state): State<AppState>,
+    Extension(admin): Extension<AuthUser>,
+    Path(id): Path<i32>,
+    Json(policy): Json<UploadPolicy>,
+) -> Result<Response, AppError> {
+    policy.validate().map_err(AppError::BadRequest)?;
+    require_user(&state, id).await?;
+    let value = serde_json::to_string(&policy).map_err(|e| AppError::Internal(e.to_string()))?;
+
+    on_db!(&state.pool, pool => sqlx::query(
+        r#"
+        INSERT INTO upload_policies (user_id, policy, updated_by) VALUES ($1, $2, $3)
+        ON CONFLICT (user_id) DO UPDATE
+        SET policy = EXCLUDED.policy, updated_by = EXCLUDED.updated_by, updated_at = CURRENT_TIMESTAMP
+        "#
+    )
+    .bind(id)
+    .bind(value)
+    .bind(admin.user_id)
+    .execute(pool)
+    .await
+    .map(|_| ()))?;
+
+    info!(user_id = admin.user_id, "UPLOAD POLICY SET for user {}", id);
+    user_policy_response(&state, id).await
+}
+
+/// Drops the user's own policy, leaving only the server's.
+pub(crate) async fn handle_delete_user_policy(
+    State(state): State<AppState>,
+    Extension(admin): Extension<AuthUser>,
+    Path(id): Path<i32>,
+) -> Result<Response, AppError> {
+    let deleted = on_db!(&state.pool, pool => sqlx::query("DELETE FROM upload_policies WHERE user_id = $1")
+        .bind(id)
+        .execute(pool)
+        .await
+        .map(|r| r.rows_affected()))?;
+    if deleted == 0 {
+        return Err(AppError::NotFound("User has no upload policy of their own".into()));
+    }
+
+    info!(user_id = admin.user_id, "UPLOAD POLICY REMOVED for user {}", id);
+    user_policy_response(&state, id).await
+}
+
+async fn require_user(state: &AppState, id: i32) -> Result<(), AppError> {
+    on_db!(&state.pool, pool => sqlx::query_scalar::<_, i32>("SELECT id FROM users WHERE id = $1")
+        .bind(id)
+        .fetch_optional(pool)
+        .await)?
+    .map(|_| ())
+    .ok_or_else(|| AppError::NotFound("User not found".into()))
+}
+
+async fn user_policy_response(state: &AppState, id: i32) -> Result<Response, AppError> {
+    let own = user_policy(state, id).await?;
+    let effective = effective_policy(state, id).await?;
+    Ok((StatusCode::OK, Json(serde_json::json!({ "data": { "policy": own, "effective": effective } }))).into_response())
+}
@@ -0,0 +1,172 @@
+use axum::{
+    extract::{Extension, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+
+use crate::{
+    db::{on_db, Array, Db, DbPool, TextList},
+    error::AppError,
+    models::{AuthUser, SyncProfile},
+    AppState,
+};
+
+/// Creates or replaces one of the caller's sync profiles.
+#[utoipa::path(
+    put, path = "/profiles", tag = "devices",
+    request_body = SyncProfile,
+    responses(
+        (status = 200, description = "The stored profile", body = SyncProfile),
+        (status = 400, description = "Invalid request", body = crate::openapi::ErrorBody),
+    )
+)]
+pub(crate) async fn handle_put_profile(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Json(req): Json<SyncProfile>,
+) -> Result<Response, AppError> {
+    let name = req.name.trim();
+    if name.is_empty() {
+        return Err(AppError::BadRequest("name must not be empty".into()));
+    }
+    let clean = |patterns: Vec<String>| -> Result<Vec<String>, AppError> {
+        patterns
+            .into_iter()
+            .map(|p| match p.trim() {
+                "" | "/" => Err(AppError::BadRequest("Patterns must not be empty".into())),
+                p => Ok(p.to_string()),
+            })
+            .collect()
+    };
+    let include = clean(req.include)?;
+    let exclude = clean(req.exclude)?;
+
+    let profile = on_db!(&state.pool, pool => sqlx::query_as::<_, SyncProfile>(
+        r#"
+        INSERT INTO sync_profiles (user_id, name, include_patterns, exclude_patterns)
+        VALUES ($1, $2, $3, $4)
+        ON CONFLICT (user_id, name) DO UPDATE
+        SET include_patterns = EXCLUDED.include_patterns,
+            exclude_patterns = EXCLUDED.exclude_patterns,
+            updated_at = CURRENT_TIMESTAMP
+        RETURNING name, include_patterns, exclude_patterns, updated_at
+        "#
+    )
+    .bind(user.user_id)
+    .bind(name)
+    .bind(Array(&include))
+    .bind(Array(&exclude))
+    .fetch_one(pool)
+    .await)?;
+
+    Ok((StatusCode::OK, Json(profile)).into_response())
+}
+
+#[utoipa::path(
+    get, path = "/profiles", tag = "devices",
+    summary = "Lists the caller's sync profiles",
+    responses((status = 200, description = "The caller's profiles", body = crate::openapi::Data<Vec<SyncProfile>>))
+)]
+pub(crate) async fn handle_list_profiles(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+) -> Result<Response, AppError> {
+    let profiles = on_db!(&state.pool, pool => sqlx::query_as::<_, SyncProfile>(
+        r#"
+        SELECT name, include_patterns, exclude_patterns, updated_at
+        FROM sync_profiles
+        WHERE user_id = $1
+        ORDER BY name
+        "#
+    )
+    .bind(user.user_id)
+    .fetch_all(pool)
+    .await)?;
+
+    Ok((StatusCode::OK, Json(serde_json::json!({ "data": profiles }))).into_response())
+}
+
+/// The requesting device's profile as regexes, bound into listing queries as
+/// `(cardinality(include) = 0 OR file_path ~ ANY(include)) AND NOT file_path ~ ANY(exclude)`;
+/// see `PathFilter::sql`.
+#[derive(Default)]
+pub(crate) struct PathFilter {
+    pub(crate) include: Vec<String>,
+    pub(crate) exclude: Vec<String>,
+}
+
+impl PathFilter {
+    /// The condition on `file_path` for a filter whose include and exclude patterns
+    /// are bound at `$include` and `$exclude`.
+    pub(crate) fn sql(pool: &DbPool, include: u8, exclude: u8) -> String {
+        match pool {
+            Db::Postgres(_) => format!(
+                "(cardinality(${include}::TEXT[]) = 0 OR file_path ~ ANY(${include})) AND NOT file_path ~ ANY(${exclude}::TEXT[])"
+            ),
+            Db::Sqlite(_) => format!(
+                "(json_array_length(${include}) = 0 OR EXISTS (SELECT 1 FROM json_each(${include}) WHERE file_path REGEXP value)) \
+                 AND NOT EXISTS (SELECT 1 FROM json_each(${exclude}) WHERE file_path REGEXP value)"
+            ),
+        }
+    }
+}
+
+/// Loads the profile of the caller's `X-Device-Id`; requests without a device, or
+/// from a device without a profile, see everything.
+pub(crate) async fn device_filter(state: &AppState, user: &AuthUser) -> Result<PathFilter, sqlx::Error> {
+    let Some(device_id) = &user.device_id else {
+        return Ok(PathFilter::default());
+    };
+
+    let patterns = on_db!(&state.pool, pool => sqlx::query_as::<_, (TextList, TextList)>(
+        r#"
+        SELECT p.include_patterns, p.exclude_patterns
+        FROM devices d
+        JOIN sync_profiles p ON p.user_id = d.user_id AND p.name = d.profile
+        WHERE d.user_id = $1 AND d.id = $2
+        "#
+    )
+    .bind(user.user_id)
+    .bind(device_id)
+    .fetch_optional(pool)
+    .await)?;
+
+    Ok(patterns.map_or_else(PathFilter::default, |(include, exclude)| PathFilter {
+        include: Vec::from(include).iter().map(|p| glob_to_regex(p)).collect(),
+        exclude: Vec::from(exclude).iter().map(|p| glob_to_regex(p)).collect(),
+    }))
+}
+
+/// Translates a profile glob into a regex matching the paths it covers: `*` and
+/// `?` stay within one path segment, `**` crosses them, and a match on a folder
+/// extends to everything below it.
+pub(crate) fn glob_to_regex(pattern: &str) -> String {
+    let body = pattern.trim_matches('/');
+    let anchored = pattern.starts_with('/') || body.contains('/');
+    let mut regex = String::from(if anchored { "^" } else { "(^|/)" });
+
+    let mut chars = body.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                if chars.peek() == Some(&'/') {
+                    chars.next();
+                    regex.push_str("(.*/)?");
+                } else {
+                    regex.push_str(".*");
+                }
+            }
+            '*' => regex.push_str("[^/]*"),
+            '?' => regex.push_str("[^/]"),
+            c if "\\.+()|[]{}^$".contains(c) => {
+                regex.push('\\');
+                regex.push(c);
+            }
+            c => regex.push(c),
+        }
+    }
+    regex.push_str("(/|$)");
+    regex
+}
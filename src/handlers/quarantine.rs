@@ -0,0 +1,232 @@
+//! The quarantine of files a content scan found infected. Files are scanned in the
+//! background once written, as thumbnails are made, so they can be read until their
+//! scan finishes. An infected one is moved out of its owner's tree, content and row
+//! alike, until an admin releases it back or deletes it for good.
+
+use axum::{
+    extract::{Extension, Path, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use tracing::{debug, info, warn, Instrument};
+
+use crate::{
+    audit,
+    db::{on_db, record_tombstone, Db},
+    error::AppError,
+    events::{publish_changes, publish_server_changes},
+    handlers::{jobs::delete_or_retry, trash::reinstate_file, versions::copy_object},
+    models::{AdminListParams, AuthUser, FileChange, FileEntry, Operation, QuarantinedFile},
+    scanning::Verdict,
+    AppState, DEFAULT_PAGE_LIMIT, MAX_PAGE_LIMIT, QUARANTINE_PREFIX,
+};
+
+/// Scans the files among `files` whose content was just written, from one
+/// background job, one file after another. Does nothing without a scanner.
+pub(crate) fn queue_scans<'a>(state: &AppState, user_id: i32, files: impl IntoIterator<Item = &'a FileEntry>) {
+    if state.scanner.is_none() {
+        return;
+    }
+    let files: Vec<FileEntry> = files.into_iter().filter(|file| !file.skipped).cloned().collect();
+    if files.is_empty() {
+        return;
+    }
+
+    let state = state.clone();
+    state.tasks.clone().spawn(async move {
+        for file in files {
+            scan_file(&state, user_id, &file).await;
+        }
+    }.in_current_span());
+}
+
+/// Scans one stored file and quarantines it if it's infected. A file that can't be
+/// scanned is left where it is.
+async fn scan_file(state: &AppState, user_id: i32, file: &FileEntry) {
+    let Some(scanner) = &state.scanner else { return };
+    if file.file_size > scanner.max_bytes {
+        info!("Skipped scanning {}: larger than {} bytes", file.file_path, scanner.max_bytes);
+        return;
+    }
+
+    let data = match state.storage.get(&file.file_name).await {
+        Ok(data) => data,
+        Err(e) => {
+            warn!("Failed to read {} for scanning: {}", file.file_path, e);
+            return;
+        }
+    };
+    match scanner.scan(&data).await {
+        Ok(Verdict::Clean) => debug!("Scanned {}: clean", file.file_path),
+        Ok(Verdict::Infected(signature)) => {
+            warn!(user_id, "{} is infected with {}; quarantining it", file.file_path, signature);
+            if let Err(e) = quarantine_file(state, user_id, file, &signature).await {
+                warn!("Failed to quarantine {}: {}", file.file_path, e);
+            }
+        }
+        Err(e) => warn!("Failed to scan {} with {}: {}", file.file_path, scanner.name(), e),
+    }
+}
+
+/// Moves a scanned file into the quarantine: its content to a key under
+/// `QUARANTINE_PREFIX`, and its row, tags and metadata to `quarantine`. A file
+/// whose content changed after the scan read it is left alone, since it's the new
+/// content's own scan that counts.
+async fn quarantine_file(state: &AppState, user_id: i32, file: &FileEntry, signature: &str) -> Result<(), String> {
+    let system_path = &file.file_name;
+    let quarantine_key = format!("{}/{}/{}", QUARANTINE_PREFIX, hex::encode(rand::random::<[u8; 8]>()), system_path);
+    copy_object(state, system_path, &quarantine_key).await?;
+
+    let moved = match &state.pool {
+        Db::Postgres(pool) => sqlx::query(
+            r#"
+            WITH removed AS (
+                DELETE FROM filehash
+                WHERE user_id = $1 AND file_path = $2 AND system_path = $3 AND file_hash IS NOT DISTINCT FROM $4
+                RETURNING user_id, file_path, file_hash, file_size, modified_time, content_type, etag, system_path, created_at, file_name,
+                          metadata, ARRAY(SELECT tag FROM file_tags WHERE file_id = filehash.id ORDER BY tag) AS tags
+            )
+            INSERT INTO quarantine (user_id, file_path, file_hash, file_size, modified_time, content_type, etag, system_path, created_at, file_name, metadata, tags, quarantine_key, signature)
+            SELECT user_id, file_path, file_hash, file_size, modified_time, content_type, etag, system_path, created_at, file_name, metadata, tags, $5, $6
+            FROM removed
+            "#
+        )
+        .bind(user_id)
+        .bind(&file.file_path)
+        .bind(system_path)
+        .bind(&file.file_hash)
+        .bind(&quarantine_key)
+        .bind(signature)
+        .execute(pool)
+        .await
+        .map(|r| r.rows_affected()),
+        // SQLite has no data-modifying CTEs: copy the row, then delete it, in a
+        // transaction of their own.
+        Db::Sqlite(pool) => async {
+            let mut tx = pool.begin_with("BEGIN IMMEDIATE").await?;
+            sqlx::query(
+                r#"
+                INSERT INTO quarantine (user_id, file_path, file_hash, file_size, modified_time, content_type, etag, system_path, created_at, file_name, metadata, tags, quarantine_key, signature)
+                SELECT user_id, file_path, file_hash, file_size, modified_time, content_type, etag, system_path, created_at, file_name, metadata,
+                       (SELECT json_group_array(tag) FROM (SELECT tag FROM file_tags WHERE file_id = filehash.id ORDER BY tag)), $5, $6
+                FROM filehash
+                WHERE user_id = $1 AND file_path = $2 AND system_path = $3 AND file_hash IS $4
+                "#
+            )
+            .bind(user_id)
+            .bind(&file.file_path)
+            .bind(system_path)
+            .bind(&file.file_hash)
+            .bind(&quarantine_key)
+            .bind(signature)
+            .execute(&mut *tx)
+            .await?;
+            let deleted = sqlx::query(
+                "DELETE FROM filehash WHERE user_id = $1 AND file_path = $2 AND system_path = $3 AND file_hash IS $4"
+            )
+            .bind(user_id)
+            .bind(&file.file_path)
+            .bind(system_path)
+            .bind(&file.file_hash)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+            tx.commit().await?;
+            Ok(deleted)
+        }
+        .await,
+    };
+
+    match moved {
+        Ok(moved) if moved > 0 => {}
+        outcome => {
+            delete_or_retry(state, &quarantine_key).await;
+            return match outcome {
+                Err(e) => Err(e.to_string()),
+                Ok(_) => {
+                    info!("{} changed after it was scanned; left in place", file.file_path);
+                    Ok(())
+                }
+            };
+        }
+    }
+
+    record_tombstone(state.pool.executor(), user_id, &file.file_path).await;
+    delete_or_retry(state, system_path).await;
+    audit::record_quarantine(state, user_id, file.file_path.clone());
+    publish_server_changes(state, user_id, vec![FileChange {
+        operation: Operation::Delete,
+        file_path: file.file_path.clone(),
+    }]);
+    Ok(())
+}
+
+/// Lists the quarantined files of every user, or of `user_id`, newest first.
+pub(crate) async fn handle_list_quarantine(
+    State(state): State<AppState>,
+    Query(params): Query<AdminListParams>,
+) -> Result<Response, AppError> {
+    let files = on_db!(&state.pool, pool => sqlx::query_as::<_, QuarantinedFile>(
+        r#"
+        SELECT q.id, q.user_id, u.username, q.file_path, q.file_hash, q.file_size, q.content_type, q.signature,
+               q.quarantined_at
+        FROM quarantine q
+        JOIN users u ON u.id = q.user_id
+        WHERE CAST($1 AS INTEGER) IS NULL OR q.user_id = $1
+        ORDER BY q.id DESC
+        LIMIT $2 OFFSET $3
+        "#
+    )
+    .bind(params.user_id)
+    .bind(params.limit.unwrap_or(DEFAULT_PAGE_LIMIT).clamp(1, MAX_PAGE_LIMIT))
+    .bind(params.offset.unwrap_or(0).max(0))
+    .fetch_all(pool)
+    .await)?;
+
+    Ok((StatusCode::OK, Json(serde_json::json!({ "data": files }))).into_response())
+}
+
+/// Puts a quarantined file back at its path in its owner's tree, for a scan that
+/// got it wrong. Fails with 409 when another file has taken the path since. The
+/// owner's devices hear of it as an insert; the audit log names the admin.
+pub(crate) async fn handle_release_quarantined(
+    State(state): State<AppState>,
+    Extension(admin): Extension<AuthUser>,
+    Path(id): Path<i32>,
+) -> Result<Response, AppError> {
+    let owner = on_db!(&state.pool, pool => sqlx::query_scalar::<_, i32>("SELECT user_id FROM quarantine WHERE id = $1")
+        .bind(id)
+        .fetch_optional(pool)
+        .await)?
+        .ok_or_else(|| AppError::NotFound("Quarantined file not found".into()))?;
+
+    let row = reinstate_file(&state, "quarantine", "quarantine_key", id, owner)
+        .await?
+        .ok_or_else(|| AppError::NotFound("Quarantined file not found".into()))?;
+
+    info!(user_id = owner, "Released {} from the quarantine", row.file_path);
+    let owner = AuthUser { user_id: owner, device_id: None, ..admin };
+    publish_changes(&state, &owner, vec![FileChange {
+        operation: Operation::Insert,
+        file_path: row.file_path.clone(),
+    }]);
+    Ok((StatusCode::OK, Json(serde_json::json!({ "data": row }))).into_response())
+}
+
+/// Deletes a quarantined file for good.
+pub(crate) async fn handle_delete_quarantined(
+    State(state): State<AppState>,
+    Path(id): Path<i32>,
+) -> Result<Response, AppError> {
+    let key = on_db!(&state.pool, pool => sqlx::query_scalar::<_, String>(
+        "DELETE FROM quarantine WHERE id = $1 RETURNING quarantine_key"
+    )
+    .bind(id)
+    .fetch_optional(pool)
+    .await)?
+    .ok_or_else(|| AppError::NotFound("Quarantined file not found".into()))?;
+
+    delete_or_retry(&state, &key).await;
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
@@ -0,0 +1,258 @@
+//! Uploads the server downloads itself. `POST /remote-upload` names a URL, and a
+//! background job streams it into storage as one of the caller's files, through
+//! the same path as a WebDAV `PUT`. Only public addresses are fetched unless
+//! `allow_private_addresses` is set; each hop of a redirect is checked, and the
+//! connection is pinned to the addresses that were.
+
+use std::net::{IpAddr, SocketAddr};
+
+use axum::{
+    body::{to_bytes, Body},
+    extract::{Extension, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use futures::StreamExt;
+use reqwest::{redirect::Policy, Url};
+use tokio::time::{timeout_at, Instant};
+use tracing::{info, info_span, warn, Instrument};
+
+use crate::{
+    db::{create_job, finish_job, mark_job_running},
+    error::AppError,
+    handlers::{files::trim_slashes, sync::put_file},
+    models::{AuthUser, FileEntry, RemoteUploadRequest},
+    paths::normalize_path,
+    AppState, RemoteUploadConfig, MAX_REMOTE_UPLOAD_REDIRECTS,
+};
+
+/// Starts downloading `url` into one of the caller's files as a background job,
+/// and returns its id. The job's result is the stored file.
+#[utoipa::path(
+    post, path = "/remote-upload", tag = "uploads",
+    request_body = RemoteUploadRequest,
+    responses(
+        (status = 202, description = "The download was queued as a job", body = crate::openapi::JobAccepted),
+        (status = 400, description = "Invalid request", body = crate::openapi::ErrorBody),
+    )
+)]
+pub(crate) async fn handle_remote_upload(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Json(req): Json<RemoteUploadRequest>,
+) -> Result<Response, AppError> {
+    let url = Url::parse(&req.url).map_err(|e| AppError::BadRequest(format!("Invalid url: {}", e)))?;
+    check_scheme(&url).map_err(AppError::BadRequest)?;
+
+    let job_id = create_job(&state.pool, Some(user.user_id), "remote_upload", 0)
+        .await
+        .map_err(|e| AppError::Internal(format!("Failed to create job: {}", e)))?;
+
+    let tasks = state.tasks.clone();
+    tasks.spawn(run_remote_upload(state, user, job_id, url, req).instrument(info_span!("remote_upload_job", job_id)));
+
+    Ok((
+        StatusCode::ACCEPTED,
+        Json(serde_json::json!({
+            "job_id": job_id,
+            "status_url": format!("/jobs/{}", job_id)
+        }))
+    ).into_response())
+}
+
+async fn run_remote_upload(state: AppState, user: AuthUser, job_id: i32, url: Url, req: RemoteUploadRequest) {
+    mark_job_running(&state.pool, job_id).await;
+    let outcome = remote_upload(&state, &user, url, req)
+        .await
+        .and_then(|entry| serde_json::to_value(entry).map_err(|e| e.to_string()));
+    match &outcome {
+        Ok(_) => info!(user_id = user.user_id, "REMOTE UPLOAD FINISHED"),
+        Err(e) => warn!("Remote upload failed: {}", e),
+    }
+    finish_job(&state.pool, job_id, outcome).await;
+}
+
+async fn remote_upload(state: &AppState, user: &AuthUser, url: Url, req: RemoteUploadRequest) -> Result<FileEntry, String> {
+    let config = &state.config.remote_upload;
+    let deadline = Instant::now() + std::time::Duration::from_secs(config.timeout_secs);
+    let response = fetch(config, url, deadline).await?;
+
+    let max_bytes = config.max_bytes;
+    let declared_size = response.content_length();
+    if let Some(size) = declared_size && size > max_bytes {
+        return Err(format!("The file is {} bytes, over the {} byte limit on remote uploads", size, max_bytes));
+    }
+
+    let name = remote_file_name(&response);
+    let file_path = match req.file_path.as_deref().map(|path| (trim_slashes(path), path.ends_with('/'))) {
+        Some(("", _)) | None => name.clone(),
+        Some((folder, true)) => format!("{}/{}", folder, name),
+        Some((path, false)) => path.to_string(),
+    };
+    let file_path = normalize_path(&file_path);
+    let content_type = req.content_type.or_else(|| {
+        response.headers().get(header::CONTENT_TYPE).and_then(|v| v.to_str().ok()).map(str::to_string)
+    });
+    let file = FileEntry {
+        file_name: name,
+        file_path,
+        file_size: declared_size.unwrap_or(0) as i64,
+        modified_time: req.modified_time.unwrap_or_else(|| chrono::Utc::now().timestamp()),
+        content_type: content_type.clone(),
+        ..FileEntry::default()
+    };
+
+    let mut received: u64 = 0;
+    let chunks = futures::stream::unfold(Some(response), move |response| async move {
+        let mut response = response?;
+        match timeout_at(deadline, response.chunk()).await {
+            Ok(Ok(Some(bytes))) => Some((Ok(bytes), Some(response))),
+            Ok(Ok(None)) => None,
+            Ok(Err(e)) => Some((Err(format!("Download failed: {}", e)), None)),
+            Err(_) => Some((Err("Download timed out".to_string()), None)),
+        }
+    })
+    .map(move |chunk| {
+        let bytes = chunk?;
+        received += bytes.len() as u64;
+        if received > max_bytes {
+            return Err(format!("The file exceeds the {} byte limit on remote uploads", max_bytes));
+        }
+        Ok(bytes)
+    });
+
+    let content_type = content_type.unwrap_or_default();
+    match put_file(state, user, file, &content_type, Body::from_stream(chunks)).await {
+        Ok(entry) => Ok(entry),
+        Err(response) => Err(error_message(response).await),
+    }
+}
+
+/// Sends the `GET` for `url`, following up to `MAX_REMOTE_UPLOAD_REDIRECTS`
+/// redirects, and returns the successful response whose body is still to be read.
+async fn fetch(config: &RemoteUploadConfig, mut url: Url, deadline: Instant) -> Result<reqwest::Response, String> {
+    for _ in 0..=MAX_REMOTE_UPLOAD_REDIRECTS {
+        let addrs = resolve(&url, deadline).await?;
+        if !config.allow_private_addresses
+            && let Some(addr) = addrs.iter().find(|addr| !is_public(addr.ip()))
+        {
+            return Err(format!("{} is at {}, which isn't a public address", url.host_str().unwrap_or_default(), addr.ip()));
+        }
+
+        // Redirects are followed here, so each hop's addresses are checked too.
+        let mut client = reqwest::Client::builder().redirect(Policy::none());
+        if let Some(domain) = url.domain() {
+            client = client.resolve_to_addrs(domain, &addrs);
+        }
+        let client = client.build().map_err(|e| format!("Failed to build the download client: {}", e))?;
+        let response = timeout_at(deadline, client.get(url.clone()).send())
+            .await
+            .map_err(|_| "Download timed out".to_string())?
+            .map_err(|e| format!("Download failed: {}", e))?;
+
+        let status = response.status();
+        if status.is_redirection() {
+            let location = response
+                .headers()
+                .get(header::LOCATION)
+                .and_then(|v| v.to_str().ok())
+                .ok_or_else(|| format!("{} redirected without a Location", url))?;
+            url = url.join(location).map_err(|e| format!("Invalid redirect to {}: {}", location, e))?;
+            check_scheme(&url)?;
+            continue;
+        }
+        if !status.is_success() {
+            return Err(format!("{} answered {}", url, status));
+        }
+        return Ok(response);
+    }
+    Err(format!("More than {} redirects", MAX_REMOTE_UPLOAD_REDIRECTS))
+}
+
+fn check_scheme(url: &Url) -> Result<(), String> {
+    if !matches!(url.scheme(), "http" | "https") || url.host().is_none() {
+        return Err("Only http and https URLs can be fetched".into());
+    }
+    Ok(())
+}
+
+/// The addresses `url` connects to.
+async fn resolve(url: &Url, deadline: Instant) -> Result<Vec<SocketAddr>, String> {
+    let port = url.port_or_known_default().unwrap_or(80);
+    let Some(host) = url.domain() else {
+        // IPv6 literals keep their brackets in the URL.
+        let literal = url.host_str().unwrap_or_default().trim_start_matches('[').trim_end_matches(']');
+        let ip: IpAddr = literal.parse().map_err(|_| "The URL has no host".to_string())?;
+        return Ok(vec![SocketAddr::new(ip, port)]);
+    };
+    let addrs: Vec<SocketAddr> = timeout_at(deadline, tokio::net::lookup_host((host, port)))
+        .await
+        .map_err(|_| format!("Timed out resolving {}", host))?
+        .map_err(|e| format!("Failed to resolve {}: {}", host, e))?
+        .collect();
+    if addrs.is_empty() {
+        return Err(format!("{} has no addresses", host));
+    }
+    Ok(addrs)
+}
+
+/// Whether `ip` is reachable from the internet at large, rather than loopback,
+/// private, link-local, shared or otherwise reserved.
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                || ip.is_documentation()
+                || a == 0
+                || (a == 100 && b & 0xc0 == 64))
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public(IpAddr::V4(ip)),
+            None => !(ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                || ip.is_unique_local()
+                || ip.is_unicast_link_local()),
+        },
+    }
+}
+
+/// The name the remote server gives the file in `Content-Disposition`, or else the
+/// last segment of the URL it was served from.
+fn remote_file_name(response: &reqwest::Response) -> String {
+    let disposition = response
+        .headers()
+        .get(header::CONTENT_DISPOSITION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split(';').find_map(|param| param.trim().strip_prefix("filename=")))
+        .map(|name| name.trim_matches('"').to_string());
+    let from_url = || {
+        response
+            .url()
+            .path_segments()
+            .and_then(|mut segments| segments.next_back())
+            .map(|segment| urlencoding::decode(segment).map(|s| s.into_owned()).unwrap_or_else(|_| segment.to_string()))
+    };
+    disposition
+        .or_else(from_url)
+        .map(|name| name.replace(['/', '\\'], "_"))
+        .filter(|name| !name.is_empty() && name != "." && name != "..")
+        .unwrap_or_else(|| "download".to_string())
+}
+
+/// The `error` of a failure response.
+async fn error_message(response: Response) -> String {
+    let status = response.status();
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap_or_default();
+    serde_json::from_slice::<serde_json::Value>(&body)
+        .ok()
+        .and_then(|body| body["error"].as_str().map(str::to_string))
+        .unwrap_or_else(|| status.to_string())
+}
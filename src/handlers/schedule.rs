@@ -0,0 +1,80 @@
+use std::collections::HashMap;
+
+use axum::{
+    extract::{Extension, Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::NaiveDateTime;
+use tracing::{info, info_span, Instrument};
+
+use crate::{
+    db::on_db,
+    error::AppError,
+    models::{AuthUser, ScheduledJob},
+    scheduler::run_job,
+    AppState,
+};
+
+type JobRow = (String, Option<NaiveDateTime>, Option<NaiveDateTime>, Option<NaiveDateTime>, Option<String>, Option<String>);
+
+/// Every background job, with its schedule and how its last run went.
+pub(crate) async fn handle_list_schedule(State(state): State<AppState>) -> Result<Response, AppError> {
+    let rows = on_db!(&state.pool, pool => sqlx::query_as::<_, JobRow>(
+        "SELECT name, next_run_at, last_started_at, last_finished_at, last_status, last_error FROM scheduled_jobs"
+    )
+    .fetch_all(pool)
+    .await)?;
+    let mut rows: HashMap<String, JobRow> = rows.into_iter().map(|row| (row.0.clone(), row)).collect();
+
+    let jobs: Vec<ScheduledJob> = state
+        .scheduler
+        .jobs()
+        .iter()
+        .map(|job| {
+            let (_, next_run_at, last_started_at, last_finished_at, last_status, last_error) =
+                rows.remove(job.name).unwrap_or_default();
+            ScheduledJob {
+                name: job.name,
+                schedule: job.schedule().map(str::to_string),
+                running: job.is_running(),
+                next_run_at: next_run_at.filter(|_| job.schedule().is_some()),
+                last_started_at,
+                last_finished_at,
+                last_status,
+                last_error,
+            }
+        })
+        .collect();
+
+    Ok((StatusCode::OK, Json(serde_json::json!({ "data": jobs }))).into_response())
+}
+
+/// Starts a run of the job `name` in the background, unless one is going.
+pub(crate) async fn handle_run_job(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Path(name): Path<String>,
+) -> Result<Response, AppError> {
+    let job = state
+        .scheduler
+        .job(&name)
+        .ok_or_else(|| AppError::NotFound(format!("No job named {}", name)))?;
+    if job.is_running() {
+        return Err(AppError::Conflict(format!("Job {} is already running", job.name)));
+    }
+    let name = job.name;
+
+    info!(user_id = user.user_id, "Job {} started on demand", name);
+    let tasks = state.tasks.clone();
+    tasks.spawn(async move {
+        let scheduler = state.scheduler.clone();
+        let job = scheduler.job(name).expect("jobs are never unregistered");
+        if run_job(&state, job).await.is_none() {
+            info!("Job {} was started by its schedule first", name);
+        }
+    }.instrument(info_span!("scheduled_job", job = name)));
+
+    Ok((StatusCode::ACCEPTED, Json(serde_json::json!({ "job": name, "status_url": "/admin/schedule" }))).into_response())
+}
@@ -0,0 +1,161 @@
+//! `/admin/settings`: the overrides of the tunables in `Settings`, and the task
+//! that keeps every instance's copy of them current.
+
+use std::time::Duration;
+
+use axum::{
+    extract::{Extension, Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde_json::{Map, Value};
+use tracing::{info, warn};
+
+use crate::{
+    db::on_db,
+    error::AppError,
+    models::{AuthUser, SettingEntry, SettingOverride},
+    AppState,
+};
+
+async fn load_overrides(state: &AppState) -> Result<Vec<SettingOverride>, sqlx::Error> {
+    on_db!(&state.pool, pool => sqlx::query_as::<_, SettingOverride>(
+        r#"
+        SELECT s.key, s.value, u.username AS updated_by, s.updated_at
+        FROM settings s
+        LEFT JOIN users u ON u.id = s.updated_by
+        ORDER BY s.key
+        "#
+    )
+    .fetch_all(pool)
+    .await)
+}
+
+/// Re-reads the overrides and puts them in force over the defaults. An override
+/// this instance can't apply, say one written by a newer version, is skipped and
+/// the rest still apply; skips are only logged when something changed, so an
+/// unchanged one isn't logged again on every refresh.
+pub(crate) async fn refresh_settings(state: &AppState) {
+    let overrides = match load_overrides(state).await {
+        Ok(overrides) => overrides,
+        Err(e) => {
+            warn!("Failed to load settings: {}", e);
+            return;
+        }
+    };
+
+    let mut settings = state.settings.defaults().clone();
+    let mut skipped = Vec::new();
+    for row in &overrides {
+        let applied = serde_json::from_str::<Value>(&row.value)
+            .map_err(|e| e.to_string())
+            .and_then(|value| settings.with_overrides([(row.key.as_str(), &value)]));
+        match applied {
+            Ok(applied) => settings = applied,
+            Err(e) => skipped.push((&row.key, e)),
+        }
+    }
+    if state.settings.replace(settings) {
+        info!("SETTINGS RELOADED: {} overridden", overrides.len() - skipped.len());
+        for (key, e) in skipped {
+            warn!("Skipped the {} setting: {}", key, e);
+        }
+    }
+}
+
+pub(crate) async fn refresh_settings_periodically(state: AppState, every: Duration) {
+    let mut interval = tokio::time::interval(every);
+    // The first tick fires immediately, and the settings were just loaded.
+    interval.tick().await;
+
+    loop {
+        interval.tick().await;
+        refresh_settings(&state).await;
+    }
+}
+
+/// Every tunable with the value in force, its default, and who last overrode it.
+pub(crate) async fn handle_list_settings(State(state): State<AppState>) -> Result<Response, AppError> {
+    let overrides = load_overrides(&state).await?;
+    Ok((StatusCode::OK, Json(serde_json::json!({ "data": setting_entries(&state, overrides) }))).into_response())
+}
+
+/// Overrides the tunables named in the body, a JSON object of names to values.
+/// The change is in force here at once; other instances pick it up on their next
+/// refresh.
+pub(crate) async fn handle_update_settings(
+    State(state): State<AppState>,
+    Extension(admin): Extension<AuthUser>,
+    Json(req): Json<Map<String, Value>>,
+) -> Result<Response, AppError> {
+    if req.is_empty() {
+        return Err(AppError::BadRequest("No settings given".into()));
+    }
+    state
+        .settings
+        .current()
+        .with_overrides(req.iter().map(|(key, value)| (key.as_str(), value)))
+        .map_err(AppError::BadRequest)?;
+
+    let mut tx = state.pool.begin().await?;
+    for (key, value) in &req {
+        on_db!(tx.as_conn(), conn => sqlx::query(
+            r#"
+            INSERT INTO settings (key, value, updated_by) VALUES ($1, $2, $3)
+            ON CONFLICT (key) DO UPDATE
+            SET value = EXCLUDED.value, updated_by = EXCLUDED.updated_by, updated_at = CURRENT_TIMESTAMP
+            "#
+        )
+        .bind(key)
+        .bind(value.to_string())
+        .bind(admin.user_id)
+        .execute(conn)
+        .await
+        .map(|_| ()))?;
+    }
+    tx.commit().await?;
+
+    info!(user_id = admin.user_id, "SETTINGS UPDATED: {}", req.keys().cloned().collect::<Vec<_>>().join(", "));
+    refresh_settings(&state).await;
+    handle_list_settings(State(state)).await
+}
+
+/// Drops the override of `key`, so its default applies again.
+pub(crate) async fn handle_reset_setting(
+    State(state): State<AppState>,
+    Extension(admin): Extension<AuthUser>,
+    Path(key): Path<String>,
+) -> Result<Response, AppError> {
+    let deleted = on_db!(&state.pool, pool => sqlx::query("DELETE FROM settings WHERE key = $1")
+        .bind(&key)
+        .execute(pool)
+        .await
+        .map(|r| r.rows_affected()))?;
+    if deleted == 0 {
+        return Err(AppError::NotFound("Setting isn't overridden".into()));
+    }
+
+    info!(user_id = admin.user_id, "SETTING RESET: {}", key);
+    refresh_settings(&state).await;
+    handle_list_settings(State(state)).await
+}
+
+fn setting_entries(state: &AppState, overrides: Vec<SettingOverride>) -> Vec<SettingEntry> {
+    let current = state.settings.current().fields();
+    let defaults = state.settings.defaults().fields();
+    current
+        .into_iter()
+        .map(|(key, value)| {
+            let row = overrides.iter().find(|row| row.key == key);
+            SettingEntry {
+                default: defaults.get(&key).cloned().unwrap_or(Value::Null),
+                overridden: row.is_some(),
+                updated_by: row.and_then(|row| row.updated_by.clone()),
+                updated_at: row.map(|row| row.updated_at),
+                key,
+                value,
+            }
+        })
+        .collect()
+}
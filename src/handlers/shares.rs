@@ -0,0 +1,348 @@
+use std::net::SocketAddr;
+
+use axum::{
+    extract::{ConnectInfo, Extension, Path, Query, State},
+    http::{header, HeaderMap, StatusCode, Uri},
+    response::{IntoResponse, Redirect, Response},
+    Json,
+};
+use tracing::{warn, Instrument};
+
+use crate::{
+    audit,
+    auth::{hash_password, hash_token, verify_password},
+    db::on_db,
+    error::AppError,
+    handlers::{
+        downloads::serve_file,
+        files::trim_slashes,
+        listing::escape_like,
+        thumbnails::{serve_thumbnail, thumbnail_size},
+    },
+    models::{AuthUser, CreateShareRequest, FileEntry, ShareLink, ShareParams, ShareStats},
+    notifications, webhooks, AppState,
+};
+
+/// Creates a public link to one of the user's files, or with `folder` to one of
+/// their folders. The plaintext token is only returned here; the database keeps
+/// its hash, and the password's argon2 hash.
+#[utoipa::path(
+    post, path = "/share", tag = "files",
+    request_body = CreateShareRequest,
+    responses(
+        (status = 201, description = "The new link", body = crate::openapi::ShareCreated),
+        (status = 400, description = "Invalid request", body = crate::openapi::ErrorBody),
+        (status = 404, description = "No such file or folder", body = crate::openapi::ErrorBody),
+    )
+)]
+pub(crate) async fn handle_create_share(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Json(req): Json<CreateShareRequest>,
+) -> Result<Response, AppError> {
+    if req.expires_in_secs.is_some_and(|secs| secs <= 0) {
+        return Err(AppError::BadRequest("expires_in_secs must be positive".into()));
+    }
+
+    let file_path = if req.folder { trim_slashes(&req.file_path) } else { req.file_path.as_str() };
+    let exists = if req.folder {
+        let prefix = if file_path.is_empty() { String::new() } else { format!("{}/", file_path) };
+        on_db!(&state.pool, pool => sqlx::query_scalar::<_, bool>(
+            r#"
+            SELECT EXISTS (SELECT 1 FROM filehash WHERE user_id = $1 AND file_path LIKE $2 ESCAPE '\')
+                OR EXISTS (SELECT 1 FROM folders WHERE user_id = $1 AND path = $3)
+            "#
+        )
+        .bind(user.user_id)
+        .bind(format!("{}%", escape_like(&prefix)))
+        .bind(file_path)
+        .fetch_one(pool)
+        .await)?
+    } else {
+        on_db!(&state.pool, pool => sqlx::query_scalar::<_, bool>(
+            "SELECT EXISTS (SELECT 1 FROM filehash WHERE user_id = $1 AND file_path = $2)"
+        )
+        .bind(user.user_id)
+        .bind(file_path)
+        .fetch_one(pool)
+        .await)?
+    };
+    if !exists {
+        return Err(AppError::NotFound(if req.folder { "Folder not found" } else { "File not found" }.into()));
+    }
+
+    let password_hash = req
+        .password
+        .as_deref()
+        .filter(|p| !p.is_empty())
+        .map(hash_password)
+        .transpose()
+        .map_err(AppError::Internal)?;
+
+    let token = hex::encode(rand::random::<[u8; 32]>());
+    let sql = state.pool.sql(
+        r#"
+        INSERT INTO shares (user_id, file_path, token_hash, password_hash, expires_at, folder)
+        VALUES ($1, $2, $3, $4, CURRENT_TIMESTAMP + make_interval(secs => $5), $6)
+        RETURNING id, expires_at
+        "#,
+        r#"
+        INSERT INTO shares (user_id, file_path, token_hash, password_hash, expires_at, folder)
+        VALUES ($1, $2, $3, $4, datetime('now', $5 || ' seconds'), $6)
+        RETURNING id, expires_at
+        "#,
+    );
+    let (id, expires_at) = on_db!(&state.pool, pool => sqlx::query_as::<_, (i32, Option<chrono::NaiveDateTime>)>(sql)
+    .bind(user.user_id)
+    .bind(file_path)
+    .bind(hash_token(&token))
+    .bind(&password_hash)
+    .bind(req.expires_in_secs.map(|secs| secs as f64))
+    .bind(req.folder)
+    .fetch_one(pool)
+    .await)?;
+    audit::record_share(&state, &user, file_path.to_string());
+
+    Ok((StatusCode::CREATED, Json(serde_json::json!({
+        "id": id,
+        "url": format!(
+            "{}/s/{}{}",
+            state.config.public_base_url.trim_end_matches('/'),
+            token,
+            if req.folder { "/" } else { "" }
+        ),
+        "token": token,
+        "expires_at": expires_at,
+        "password_protected": password_hash.is_some(),
+        "folder": req.folder
+    }))).into_response())
+}
+
+/// The link `token` opens, failing with 404 when there's none and 410 when it was
+/// revoked or has expired.
+pub(crate) async fn find_share(state: &AppState, token: &str) -> Result<ShareLink, AppError> {
+    let share = on_db!(&state.pool, pool => sqlx::query_as::<_, ShareLink>(
+        r#"
+        SELECT id, user_id, file_path, folder, password_hash,
+               revoked_at IS NOT NULL OR COALESCE(expires_at <= CURRENT_TIMESTAMP, FALSE) AS expired
+        FROM shares
+        WHERE token_hash = $1
+        "#
+    )
+    .bind(hash_token(token))
+    .fetch_optional(pool)
+    .await)?
+    .ok_or_else(|| AppError::NotFound("Share not found".into()))?;
+
+    if share.expired {
+        return Err(AppError::Gone("This link has expired or been revoked".into()));
+    }
+    Ok(share)
+}
+
+/// Checks the password given for a share, from `X-Share-Password` or the
+/// `password` query parameter, when the share has one.
+pub(crate) fn check_share_password(share: &ShareLink, params: &ShareParams, headers: &HeaderMap) -> Result<(), AppError> {
+    let Some(hash) = &share.password_hash else { return Ok(()) };
+    let password = headers
+        .get("X-Share-Password")
+        .and_then(|v| v.to_str().ok())
+        .or(params.password.as_deref());
+    if !password.is_some_and(|p| verify_password(p, hash)) {
+        return Err(AppError::Unauthorized("A valid password is required".into()));
+    }
+    Ok(())
+}
+
+/// Serves a file of the share's owner to someone holding the link: its thumbnail
+/// when `size` is given, otherwise the file itself, which the owner's webhooks and
+/// audit log hear of. The thumbnail of a single-file link counts as a view of it;
+/// those of a folder's grid don't, its page already did.
+pub(crate) async fn serve_shared_file(
+    state: &AppState,
+    addr: SocketAddr,
+    share: &ShareLink,
+    file: FileEntry,
+    params: &ShareParams,
+    headers: &HeaderMap,
+) -> Result<Response, AppError> {
+    if let Some(size) = &params.size {
+        let size = thumbnail_size(Some(size))?;
+        let response = serve_thumbnail(state, &file.file_name, file.content_type.as_deref(), size, headers).await?;
+        if !share.folder {
+            record_share_view(state, addr, share, file.file_path);
+        }
+        return Ok(response);
+    }
+
+    let file_path = file.file_path.clone();
+    let response = serve_file(state, file, headers).await?;
+    if is_full_download(&response) {
+        count_share_use(state, share.id, false);
+        notifications::notify_share_access(state, share.user_id, &file_path);
+    }
+    webhooks::queue_share_access(state, share.user_id, share.id, file_path.clone(), addr.ip());
+    audit::record_share_download(state, share.user_id, file_path, addr.ip());
+    Ok(response)
+}
+
+/// Counts a view of `share`, of the page or preview of `file_path`, and tells the
+/// owner's webhooks.
+pub(crate) fn record_share_view(state: &AppState, addr: SocketAddr, share: &ShareLink, file_path: String) {
+    count_share_use(state, share.id, true);
+    webhooks::queue_share_view(state, share.user_id, share.id, file_path, addr.ip());
+}
+
+/// Whether `response` carries a file from its start, so resumed and seeking
+/// range requests, and revalidations, aren't counted as downloads of their own.
+fn is_full_download(response: &Response) -> bool {
+    match response.status() {
+        StatusCode::OK => true,
+        StatusCode::PARTIAL_CONTENT => response
+            .headers()
+            .get(header::CONTENT_RANGE)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|range| range.starts_with("bytes 0-")),
+        _ => false,
+    }
+}
+
+/// Adds a view or a download to the counts of share `id`, in the background.
+fn count_share_use(state: &AppState, id: i32, view: bool) {
+    let pool = state.pool.clone();
+    let (views, downloads) = if view { (1i64, 0i64) } else { (0, 1) };
+    tokio::spawn(async move {
+        let result = on_db!(&pool, pool => sqlx::query(
+            r#"
+            UPDATE shares
+            SET view_count = view_count + $1, download_count = download_count + $2, last_accessed_at = CURRENT_TIMESTAMP
+            WHERE id = $3
+            "#
+        )
+        .bind(views)
+        .bind(downloads)
+        .bind(id)
+        .execute(pool)
+        .await
+        .map(|_| ()));
+
+        if let Err(e) = result {
+            warn!("Failed to count a use of share {}: {}", id, e);
+        }
+    }.in_current_span());
+}
+
+/// The file of `user_id`'s at `file_path`, if there is one.
+pub(crate) async fn load_shared_file(state: &AppState, user_id: i32, file_path: &str) -> Result<Option<FileEntry>, AppError> {
+    Ok(on_db!(&state.pool, pool => sqlx::query_as::<_, FileEntry>(
+        r#"
+        SELECT file_path, file_hash, file_size, modified_time, system_path AS file_name, content_type, etag, created_at, updated_at, version
+        FROM filehash
+        WHERE user_id = $1 AND file_path = $2
+        "#
+    )
+    .bind(user_id)
+    .bind(file_path)
+    .fetch_optional(pool)
+    .await)?)
+}
+
+/// Serves a shared file to anyone holding the link. The password, when the share
+/// has one, comes from `X-Share-Password` or the `password` query parameter. A
+/// folder link is sent on to its gallery page at `/s/{token}/`.
+#[utoipa::path(
+    get, path = "/s/{token}", tag = "files", security(()),
+    params(
+        ("token" = String, Path),
+        ShareParams,
+        ("X-Share-Password" = Option<String>, Header),
+    ),
+    responses(
+        (status = 200, description = "The shared file", content_type = "application/octet-stream"),
+        (status = 303, description = "A folder link, sent on to its gallery page"),
+        (status = 401, description = "Wrong or missing password", body = crate::openapi::ErrorBody),
+        (status = 404, description = "No such link", body = crate::openapi::ErrorBody),
+        (status = 410, description = "The link was revoked or expired", body = crate::openapi::ErrorBody),
+    )
+)]
+pub(crate) async fn handle_share_download(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(token): Path<String>,
+    Query(params): Query<ShareParams>,
+    uri: Uri,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let share = find_share(&state, &token).await?;
+    if share.folder {
+        let query = uri.query().map(|q| format!("?{}", q)).unwrap_or_default();
+        return Ok(Redirect::to(&format!("{}/{}", token, query)).into_response());
+    }
+    check_share_password(&share, &params, &headers)?;
+
+    let file = load_shared_file(&state, share.user_id, &share.file_path)
+        .await?
+        .ok_or_else(|| AppError::NotFound("Share not found".into()))?;
+    serve_shared_file(&state, addr, &share, file, &params, &headers).await
+}
+
+/// How often one of the caller's links was viewed and downloaded, and when it was
+/// last used.
+#[utoipa::path(
+    get, path = "/share/{id}/stats", tag = "files",
+    params(("id" = i32, Path)),
+    responses(
+        (status = 200, description = "The link's counts", body = ShareStats),
+        (status = 404, description = "No such link of the caller's", body = crate::openapi::ErrorBody),
+    )
+)]
+pub(crate) async fn handle_share_stats(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Path(id): Path<i32>,
+) -> Result<Response, AppError> {
+    let stats = on_db!(&state.pool, pool => sqlx::query_as::<_, ShareStats>(
+        r#"
+        SELECT id, file_path, folder, password_hash IS NOT NULL AS password_protected, created_at, expires_at,
+               revoked_at, view_count AS views, download_count AS downloads, last_accessed_at
+        FROM shares
+        WHERE id = $1 AND user_id = $2
+        "#
+    )
+    .bind(id)
+    .bind(user.user_id)
+    .fetch_optional(pool)
+    .await)?
+    .ok_or_else(|| AppError::NotFound("Share not found".into()))?;
+
+    Ok((StatusCode::OK, Json(stats)).into_response())
+}
+
+#[utoipa::path(
+    delete, path = "/share/{id}", tag = "files",
+    summary = "Revokes one of the caller's links",
+    params(("id" = i32, Path)),
+    responses(
+        (status = 204, description = "Revoked"),
+        (status = 404, description = "No such active link", body = crate::openapi::ErrorBody),
+    )
+)]
+pub(crate) async fn handle_revoke_share(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Path(id): Path<i32>,
+) -> Result<Response, AppError> {
+    let revoked = on_db!(&state.pool, pool => sqlx::query(
+        "UPDATE shares SET revoked_at = CURRENT_TIMESTAMP WHERE id = $1 AND user_id = $2 AND revoked_at IS NULL"
+    )
+    .bind(id)
+    .bind(user.user_id)
+    .execute(pool)
+    .await
+    .map(|r| r.rows_affected()))?;
+
+    if revoked == 0 {
+        return Err(AppError::NotFound("Share not found".into()));
+    }
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
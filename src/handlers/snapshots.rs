@@ -0,0 +1,409 @@
+//! Snapshots of each user's tree, taken every `SNAPSHOT_INTERVAL_SECS` while it
+//! keeps changing. A snapshot only records paths and content hashes; restoring
+//! one looks for each file's content among the user's current files, stored
+//! revisions and trash, so a client that overwrote or deleted everything can be
+//! undone for as long as those are kept.
+
+use std::{collections::{HashMap, HashSet}, time::Duration};
+
+use axum::{
+    extract::{Extension, Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use tracing::{info, warn};
+
+use crate::{
+    db::{clear_tombstone, lock_current, on_db},
+    env_or,
+    error::AppError,
+    events::{publish_changes, publish_restores},
+    handlers::{
+        files::delete_file,
+        jobs::delete_or_retry,
+        maintenance::in_maintenance,
+        trash::reinstate_file,
+        uploads::generate_system_path,
+        versions::{copy_object, prune_versions, record_version},
+    },
+    models::{AuthUser, FileChange, FileFailure, Operation, Snapshot, SnapshotFile, SnapshotRestore},
+    AppState, DEFAULT_SNAPSHOT_RETENTION,
+};
+
+/// Records the user's tree as it is now, then drops their oldest snapshots beyond
+/// `SNAPSHOT_RETENTION`. Returns the new snapshot's id.
+pub(crate) async fn take_snapshot(state: &AppState, user_id: i32) -> Result<i32, sqlx::Error> {
+    let mut tx = state.pool.begin().await?;
+    let id = on_db!(tx.as_conn(), conn => sqlx::query_scalar::<_, i32>(
+        r#"
+        INSERT INTO snapshots (user_id, change_seq)
+        VALUES ($1, COALESCE((SELECT seq FROM change_counters WHERE user_id = $1), 0))
+        RETURNING id
+        "#
+    )
+    .bind(user_id)
+    .fetch_one(conn)
+    .await)?;
+    on_db!(tx.as_conn(), conn => sqlx::query(
+        r#"
+        INSERT INTO snapshot_files (snapshot_id, file_path, file_name, file_hash, file_size, modified_time, content_type, etag)
+        SELECT $1, file_path, file_name, file_hash, file_size, modified_time, content_type, etag
+        FROM filehash
+        WHERE user_id = $2
+        "#
+    )
+    .bind(id)
+    .bind(user_id)
+    .execute(conn)
+    .await
+    .map(|_| ()))?;
+    tx.commit().await?;
+
+    let retention: i64 = env_or("SNAPSHOT_RETENTION", DEFAULT_SNAPSHOT_RETENTION).max(1);
+    on_db!(&state.pool, pool => sqlx::query(
+        r#"
+        DELETE FROM snapshots
+        WHERE user_id = $1
+          AND id NOT IN (SELECT id FROM snapshots WHERE user_id = $1 ORDER BY id DESC LIMIT $2)
+        "#
+    )
+    .bind(user_id)
+    .bind(retention)
+    .execute(pool)
+    .await
+    .map(|_| ()))?;
+    Ok(id)
+}
+
+/// Like `take_snapshot`, but `None` without taking one when nothing changed since
+/// the user's last snapshot, or ever.
+async fn snapshot_if_changed(state: &AppState, user_id: i32) -> Result<Option<i32>, sqlx::Error> {
+    let changed = on_db!(&state.pool, pool => sqlx::query_scalar::<_, bool>(
+        r#"
+        SELECT COALESCE((SELECT seq FROM change_counters WHERE user_id = $1), 0)
+            > COALESCE((SELECT MAX(change_seq) FROM snapshots WHERE user_id = $1), 0)
+        "#
+    )
+    .bind(user_id)
+    .fetch_one(pool)
+    .await)?;
+    if !changed {
+        return Ok(None);
+    }
+    take_snapshot(state, user_id).await.map(Some)
+}
+
+pub(crate) async fn snapshot_trees_periodically(state: AppState, every: Duration) {
+    let mut interval = tokio::time::interval(every);
+    loop {
+        interval.tick().await;
+        if in_maintenance(&state).await {
+            continue;
+        }
+        snapshot_trees(&state).await;
+    }
+}
+
+/// Snapshots every user's tree that changed since its last snapshot.
+pub(crate) async fn snapshot_trees(state: &AppState) {
+    let users = on_db!(&state.pool, pool => sqlx::query_scalar::<_, i32>("SELECT id FROM users")
+        .fetch_all(pool)
+        .await);
+    let users = match users {
+        Ok(users) => users,
+        Err(e) => {
+            warn!("Failed to list users to snapshot: {}", e);
+            return;
+        }
+    };
+
+    let mut taken = 0;
+    for user_id in users {
+        match snapshot_if_changed(state, user_id).await {
+            Ok(Some(_)) => taken += 1,
+            Ok(None) => {}
+            Err(e) => warn!("Failed to snapshot the files of user {}: {}", user_id, e),
+        }
+    }
+    if taken > 0 {
+        info!("SNAPSHOTTED THE FILES OF {} USERS", taken);
+    }
+}
+
+async fn find_snapshot(state: &AppState, user_id: i32, id: i32) -> Result<Option<Snapshot>, sqlx::Error> {
+    on_db!(&state.pool, pool => sqlx::query_as::<_, Snapshot>(
+        r#"
+        SELECT id, created_at, (SELECT COUNT(*) FROM snapshot_files WHERE snapshot_id = snapshots.id) AS files
+        FROM snapshots
+        WHERE id = $1 AND user_id = $2
+        "#
+    )
+    .bind(id)
+    .bind(user_id)
+    .fetch_optional(pool)
+    .await)
+}
+
+/// Lists the snapshots of the caller's tree, newest first.
+#[utoipa::path(
+    get, path = "/snapshots", tag = "files",
+    responses((status = 200, description = "The caller's snapshots", body = crate::openapi::Data<Vec<Snapshot>>))
+)]
+pub(crate) async fn handle_list_snapshots(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+) -> Result<Response, AppError> {
+    let rows = on_db!(&state.pool, pool => sqlx::query_as::<_, Snapshot>(
+        r#"
+        SELECT id, created_at, (SELECT COUNT(*) FROM snapshot_files WHERE snapshot_id = snapshots.id) AS files
+        FROM snapshots
+        WHERE user_id = $1
+        ORDER BY id DESC
+        "#
+    )
+    .bind(user.user_id)
+    .fetch_all(pool)
+    .await)?;
+
+    Ok((StatusCode::OK, Json(serde_json::json!({ "data": rows }))).into_response())
+}
+
+/// Snapshots the caller's tree now, whether or not it changed since the last one.
+#[utoipa::path(
+    post, path = "/snapshots", tag = "files",
+    responses((status = 201, description = "The new snapshot", body = crate::openapi::Data<Snapshot>))
+)]
+pub(crate) async fn handle_create_snapshot(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+) -> Result<Response, AppError> {
+    let id = take_snapshot(&state, user.user_id).await?;
+    let snapshot = find_snapshot(&state, user.user_id, id)
+        .await?
+        .ok_or_else(|| AppError::Internal("Snapshot vanished as it was taken".into()))?;
+
+    Ok((StatusCode::CREATED, Json(serde_json::json!({ "data": snapshot }))).into_response())
+}
+
+/// Puts the caller's tree back the way snapshot `id` recorded it: files since
+/// changed get their old content back, files since deleted come back, and files
+/// since added go to the trash. The tree is snapshotted first, so the restore can
+/// itself be undone. Files whose content is gone for good are left as they are.
+#[utoipa::path(
+    post, path = "/restore-snapshot/{id}", tag = "files",
+    params(("id" = i32, Path)),
+    responses(
+        (status = 200, description = "What the restore did", body = crate::openapi::Data<SnapshotRestore>),
+        (status = 404, description = "No such snapshot", body = crate::openapi::ErrorBody),
+    )
+)]
+pub(crate) async fn handle_restore_snapshot(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Path(id): Path<i32>,
+) -> Result<Response, AppError> {
+    find_snapshot(&state, user.user_id, id)
+        .await?
+        .ok_or_else(|| AppError::NotFound("Snapshot not found".into()))?;
+
+    let files = on_db!(&state.pool, pool => sqlx::query_as::<_, SnapshotFile>(
+        r#"
+        SELECT file_path, file_name, file_hash, file_size, modified_time, content_type, etag
+        FROM snapshot_files
+        WHERE snapshot_id = $1
+        ORDER BY file_path
+        "#
+    )
+    .bind(id)
+    .fetch_all(pool)
+    .await)?;
+    let current: HashMap<String, String> = on_db!(&state.pool, pool => sqlx::query_as::<_, (String, String)>(
+        "SELECT file_path, file_hash FROM filehash WHERE user_id = $1"
+    )
+    .bind(user.user_id)
+    .fetch_all(pool)
+    .await)?
+    .into_iter()
+    .collect();
+
+    let undo_snapshot = take_snapshot(&state, user.user_id).await?;
+    let mut outcome = SnapshotRestore {
+        restored: Vec::new(),
+        deleted: Vec::new(),
+        missing: Vec::new(),
+        failed: Vec::new(),
+        undo_snapshot,
+    };
+
+    let mut restored = Vec::new();
+    for file in &files {
+        let operation = match current.get(&file.file_path) {
+            Some(hash) if *hash == file.file_hash => continue,
+            Some(_) => Operation::Update,
+            None => Operation::Insert,
+        };
+        match restore_file(&state, user.user_id, file, operation == Operation::Update).await {
+            Ok(true) => {
+                outcome.restored.push(file.file_path.clone());
+                restored.push(FileChange { operation, file_path: file.file_path.clone() });
+            }
+            Ok(false) => outcome.missing.push(file.file_path.clone()),
+            Err(e) => outcome.failed.push(FileFailure::new(file.file_path.clone(), e)),
+        }
+    }
+
+    // Added files go last, since their content may have been what restored another.
+    let kept: HashSet<&str> = files.iter().map(|file| file.file_path.as_str()).collect();
+    let mut added: Vec<&String> = current.keys().filter(|path| !kept.contains(path.as_str())).collect();
+    added.sort();
+    let mut deleted = Vec::new();
+    for file_path in added {
+        match delete_file(&state, user.user_id, file_path).await {
+            Ok(()) => {
+                outcome.deleted.push(file_path.clone());
+                deleted.push(FileChange { operation: Operation::Delete, file_path: file_path.clone() });
+            }
+            Err(e) => outcome.failed.push(FileFailure::new(file_path.clone(), e)),
+        }
+    }
+
+    info!(
+        user_id = user.user_id,
+        "RESTORED SNAPSHOT {}: {} RESTORED, {} DELETED, {} MISSING",
+        id,
+        outcome.restored.len(),
+        outcome.deleted.len(),
+        outcome.missing.len()
+    );
+    publish_restores(&state, &user, restored);
+    publish_changes(&state, &user, deleted);
+
+    Ok((StatusCode::OK, Json(serde_json::json!({ "data": outcome }))).into_response())
+}
+
+/// Gives `file` back the content the snapshot recorded, over the current file at
+/// its path when `exists`. `false` when that content isn't kept anywhere anymore.
+async fn restore_file(state: &AppState, user_id: i32, file: &SnapshotFile, exists: bool) -> Result<bool, String> {
+    if !exists {
+        // A file deleted since comes back from the trash whole, tags and all.
+        let trashed = on_db!(&state.pool, pool => sqlx::query_scalar::<_, i32>(
+            r#"
+            SELECT id FROM trash
+            WHERE user_id = $1 AND file_path = $2 AND file_hash = $3
+            ORDER BY deleted_at DESC, id DESC
+            LIMIT 1
+            "#
+        )
+        .bind(user_id)
+        .bind(&file.file_path)
+        .bind(&file.file_hash)
+        .fetch_optional(pool)
+        .await)
+        .map_err(|e| e.to_string())?;
+        if let Some(trash_id) = trashed
+            && reinstate_file(state, "trash", "trash_key", trash_id, user_id)
+                .await
+                .map_err(|e| e.message().to_string())?
+                .is_some()
+        {
+            return Ok(true);
+        }
+    }
+
+    let Some(source) = content_source(state, user_id, &file.file_hash).await? else {
+        return Ok(false);
+    };
+
+    let file_name = file.file_name.as_deref().unwrap_or_else(|| file.file_path.rsplit('/').next().unwrap_or_default());
+    let system_path = generate_system_path(state, user_id, &file.file_path, file_name);
+    copy_object(state, &source, &system_path).await?;
+
+    if exists {
+        if let Err(e) = replace_content(state, user_id, file, &system_path).await {
+            delete_or_retry(state, &system_path).await;
+            return Err(e.to_string());
+        }
+        prune_versions(state, user_id, &file.file_path).await;
+    } else {
+        let inserted = on_db!(&state.pool, pool => sqlx::query(
+            r#"
+            INSERT INTO filehash (user_id, file_path, file_hash, file_size, modified_time, content_type, etag, system_path, file_name, version)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9,
+                    (SELECT COALESCE(MAX(version), 0) + 1 FROM path_versions WHERE user_id = $1 AND file_path = $2))
+            "#
+        )
+        .bind(user_id)
+        .bind(&file.file_path)
+        .bind(&file.file_hash)
+        .bind(file.file_size)
+        .bind(file.modified_time)
+        .bind(&file.content_type)
+        .bind(&file.etag)
+        .bind(&system_path)
+        .bind(&file.file_name)
+        .execute(pool)
+        .await
+        .map(|_| ()));
+        if let Err(e) = inserted {
+            delete_or_retry(state, &system_path).await;
+            return Err(e.to_string());
+        }
+        clear_tombstone(state.pool.executor(), user_id, &file.file_path).await;
+    }
+    Ok(true)
+}
+
+/// Moves the row at `file`'s path onto `system_path`, where the snapshot's content
+/// was copied. The revision it replaces is recorded as a version in the same
+/// transaction, and keeps its object.
+async fn replace_content(state: &AppState, user_id: i32, file: &SnapshotFile, system_path: &str) -> Result<(), sqlx::Error> {
+    let mut tx = state.pool.begin().await?;
+    let current = lock_current(tx.as_conn(), user_id, &file.file_path).await?.ok_or(sqlx::Error::RowNotFound)?;
+
+    on_db!(tx.as_conn(), conn => sqlx::query(
+        r#"
+        UPDATE filehash
+        SET file_hash = $3, file_size = $4, modified_time = $5, content_type = $6, etag = $7, system_path = $8,
+            updated_at = CURRENT_TIMESTAMP, version = version + 1
+        WHERE user_id = $1 AND file_path = $2
+        "#
+    )
+    .bind(user_id)
+    .bind(&file.file_path)
+    .bind(&file.file_hash)
+    .bind(file.file_size)
+    .bind(file.modified_time)
+    .bind(&file.content_type)
+    .bind(&file.etag)
+    .bind(system_path)
+    .execute(conn)
+    .await
+    .map(|_| ()))?;
+
+    record_version(tx.as_conn(), user_id, &current).await?;
+    tx.commit().await
+}
+
+/// A storage key holding content with `file_hash` among the user's files, their
+/// stored revisions or their trash, in that order of preference.
+async fn content_source(state: &AppState, user_id: i32, file_hash: &str) -> Result<Option<String>, String> {
+    on_db!(&state.pool, pool => sqlx::query_scalar::<_, String>(
+        r#"
+        SELECT storage_key FROM (
+            SELECT system_path AS storage_key, 0 AS preference FROM filehash WHERE user_id = $1 AND file_hash = $2
+            UNION ALL
+            SELECT s3_key, 1 FROM file_versions WHERE user_id = $1 AND file_hash = $2
+            UNION ALL
+            SELECT trash_key, 2 FROM trash WHERE user_id = $1 AND file_hash = $2
+        ) AS sources
+        ORDER BY preference
+        LIMIT 1
+        "#
+    )
+    .bind(user_id)
+    .bind(file_hash)
+    .fetch_optional(pool)
+    .await)
+    .map_err(|e| e.to_string())
+}
@@ -0,0 +1,39 @@
+use axum::{
+    extract::{Extension, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use tracing::{info, warn};
+
+use crate::{config::AppConfig, error::AppError, models::AuthUser, AppState};
+
+/// Reads the S3 credential settings again, from the config file and the
+/// environment, and rebuilds the storage clients with them, for keys rotated or a
+/// role changed since startup. The rest of the settings stay as the server started
+/// with them, and the old clients stay in use when the new ones don't work.
+pub(crate) async fn handle_reload_storage(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+) -> Result<Response, AppError> {
+    let loaded = AppConfig::try_load().map_err(AppError::Internal)?;
+    let mut config = (*state.config).clone();
+    config.access_key_id = loaded.access_key_id;
+    config.secret_access_key = loaded.secret_access_key;
+    config.credentials = loaded.credentials;
+
+    if let Err(e) = state.storage.reload(&config).await {
+        warn!(user_id = user.user_id, "Storage reload failed: {}", e);
+        return Err(AppError::BadGateway(format!("Storage wasn't reloaded: {}", e)));
+    }
+    info!(user_id = user.user_id, "Storage clients reloaded");
+
+    Ok((
+        StatusCode::OK,
+        Json(serde_json::json!({
+            "backend": state.config.storage_backend,
+            "reloaded_at": chrono::Utc::now(),
+        })),
+    )
+        .into_response())
+}
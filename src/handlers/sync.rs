@@ -0,0 +1,2456 @@
+use std::collections::{HashMap, HashSet};
+
+use axum::{
+    body::{Body, Bytes},
+    extract::{multipart::Field, Extension, Multipart, Query, State},
+    http::{HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use futures::stream::FuturesUnordered;
+use sha2::{Digest, Sha256};
+use tokio_stream::StreamExt;
+use tracing::{debug, info, info_span, warn, Instrument};
+
+use crate::{
+    db::{
+        annotate_file, claim_idempotency_key, clear_tombstone, create_job, describe_error, find_foreign_lock,
+        find_foreign_locks, find_stored_contents, find_unchanged, find_unchanged_files, find_update_conflict,
+        find_update_conflicts, finish_job, mark_job_running, on_db, report_progress, stored_bytes, Array, DbConn, KeyClaim,
+    },
+    error::AppError,
+    events::publish_sync_event,
+    handlers::{
+        collaborators::act_for_owner,
+        devices::require_device,
+        files::{finish_removal, move_rows, remove_file_row, RemovedFile},
+        jobs::delete_or_retry,
+        quarantine::queue_scans,
+        thumbnails::queue_thumbnails,
+        uploads::{detect_content_type, generate_system_path, resolve_content_type},
+        usage::reject_over_quota,
+        versions::{prune_versions, record_version},
+    },
+    models::{
+        AuthUser, FailureCode, FileConflict, FileEntry, FileFailure, FileLocked, FileSyncPayload, FolderPermission, OnConflict, Operation,
+        OperationEntry, OperationError, OperationResult, PrecheckRequest, PrecheckResponse, StoredObject, SyncOptions, SyncParams, SyncPayload,
+        SyncReport, SyncResponse, TokenScope,
+    },
+    notifications,
+    paths::{ancestors, collision, colliding_spelling, existing_spellings, normalize_path, path_key, stored_spelling},
+    policy::{effective_policy, PolicyCheck},
+    retry::{after_attempts, RetryPolicy},
+    storage::{read_part, StorageError},
+    AppState, BUFFERED_UPLOAD_BYTES, DB_UNAVAILABLE_ERROR, DUPLICATE_PATH_MESSAGE, FILE_NOT_FOUND_MESSAGE,
+    INSERT_CONFLICT_MESSAGE, INTEGRITY_FAILURE_MESSAGE, MAX_METADATA_BYTES, MAX_PRECHECK_FILES, MAX_TAGS_PER_FILE, MAX_TAG_LEN,
+    MISSING_UPLOAD_MESSAGE, OPERATION_ORDER, ROLLED_BACK_MESSAGE, SIZE_MISMATCH_MESSAGE,
+    BASE_VERSION_PAYLOAD_VERSION, OPERATION_LIST_PAYLOAD_VERSION, SYNC_PAYLOAD_VERSION, UPLOAD_ID_PAYLOAD_VERSION, UPLOAD_PART_PREFIX,
+};
+
+#[utoipa::path(
+    post, path = "/sync", tag = "sync",
+    summary = "Applies a batch of inserts, updates and deletes",
+    params(
+        SyncParams,
+        ("X-Device-Id" = String, Header, description = "A device registered through `/devices/register`"),
+        ("Idempotency-Key" = Option<String>, Header, description = "Replays the first response for retries of the same request"),
+    ),
+    request_body(content = crate::openapi::SyncForm, content_type = "multipart/form-data"),
+    responses(
+        (status = 200, description = "Every file applied", body = SyncReport),
+        (status = 202, description = "Queued as a job, with `async=true`", body = crate::openapi::JobAccepted),
+        (status = 207, description = "Some files failed, conflicted or were locked", body = SyncReport),
+        (status = 400, description = "Invalid request", body = crate::openapi::ErrorBody),
+        (status = 401, description = "Missing or invalid bearer token", body = crate::openapi::ErrorBody),
+        (status = 403, description = "A path is outside the folders `owner` lets the caller change", body = crate::openapi::ErrorBody),
+        (status = 413, description = "A file or the request is too large, or the quota is exceeded", body = crate::openapi::ErrorBody),
+        (status = 422, description = "No file applied", body = SyncReport),
+    )
+)]
+pub(crate) async fn handle_sync(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Query(params): Query<SyncParams>,
+    headers: HeaderMap,
+    multipart: Multipart,
+) -> Result<Response, AppError> {
+    let (caller, device_id) = (user.user_id, user.device_id.clone());
+    let result = apply_sync(state.clone(), user, params, headers, multipart).await;
+    // Anything short of every file applied, or queued, counts toward a failing sync.
+    let failed = !result.as_ref().is_ok_and(|r| matches!(r.status(), StatusCode::OK | StatusCode::ACCEPTED));
+    if let Some(device_id) = device_id {
+        notifications::record_sync_outcome(&state, caller, device_id, failed);
+    }
+    result
+}
+
+async fn apply_sync(
+    state: AppState,
+    mut user: AuthUser,
+    params: SyncParams,
+    headers: HeaderMap,
+    mut multipart: Multipart,
+) -> Result<Response, AppError> {
+    let device = require_device(&state, &user).await?;
+    let _active = state.metrics.sync_started();
+    // Keys are namespaced per user so two users can't replay each other's responses.
+    let idempotency_key = headers
+        .get("Idempotency-Key")
+        .and_then(|v| v.to_str().ok())
+        .filter(|_| !params.dry_run)
+        .map(|v| format!("{}:{}", user.user_id, v));
+    let mut held_key = None;
+
+    // Files are streamed to storage as they arrive, so the payload has to come
+    // first: it decides which objects get skipped beforehand.
+    let mut payload: Option<FileSyncPayload> = None;
+    let mut skip_uploads = Vec::new();
+    let mut redirects = HashMap::new();
+    let mut respelled = HashMap::new();
+    let mut stored = HashMap::new();
+    let mut failed_uploads = HashMap::new();
+    let mut uploading = FuturesUnordered::new();
+    let mut uploading_keys = HashSet::new();
+    let mut received_ids = HashSet::new();
+
+    while let Some(field) = multipart
+        .next_field()
+        .await
+        .map_err(|e| AppError::BadRequest(format!("Malformed multipart body: {}", e)))?
+    {
+        let name = field.name().unwrap_or("");
+
+        if name == "payload" {
+            let text = read_payload_field(field, state.config.max_body_bytes).await?;
+            // Checked before anything is applied, so a retry never runs the operations twice.
+            if let Some(key) = &idempotency_key {
+                let request_hash = hex::encode(Sha256::digest(&text));
+                let claim = claim_idempotency_key(&state.pool, key, &request_hash)
+                    .await
+                    .map_err(|e| AppError::Internal(format!("Failed to check idempotency key: {}", e)))?;
+                match claim {
+                    KeyClaim::Acquired(held) => held_key = Some(held),
+                    KeyClaim::InProgress => {
+                        return Err(AppError::Conflict(
+                            "A request with this Idempotency-Key is still being processed".into(),
+                        ));
+                    }
+                    KeyClaim::Completed { request_hash: Some(hash), .. } if hash != request_hash => {
+                        return Err(AppError::BadRequest(
+                            "Idempotency-Key was already used with a different payload".into(),
+                        ));
+                    }
+                    KeyClaim::Completed { status, response, .. } => {
+                        debug!("Replaying stored response for idempotency key {}", key);
+                        let status = StatusCode::from_u16(status as u16).unwrap_or(StatusCode::ACCEPTED);
+                        return Ok((status, [("Idempotent-Replayed", "true")], Json(response)).into_response());
+                    }
+                }
+            }
+            let (version, conflict_copies, mut parsed) = parse_payload(&text)?;
+            // A move takes the file from its old path, like `/move`, which is no
+            // more an upload route than deleting is.
+            let removes = [Operation::Delete, Operation::Move]
+                .iter()
+                .any(|cmd| parsed.get(cmd).is_some_and(|files| !files.is_empty()));
+            if user.scope == TokenScope::Upload && removes {
+                return Err(AppError::Forbidden("Upload-only tokens can't delete or move files".into()));
+            }
+            respelled = normalize_payload_paths(&state, &mut parsed);
+            if let Some(owner) = &params.owner {
+                let paths = parsed
+                    .values()
+                    .flatten()
+                    .flat_map(|file| std::iter::once(file.file_path.as_str()).chain(file.from_path.as_deref()))
+                    .collect::<Vec<_>>();
+                user = act_for_owner(&state, &user, owner, &paths, FolderPermission::Write).await?;
+            }
+            check_declared_sizes(&state, &parsed)?;
+            check_annotations(&mut parsed)?;
+            check_upload_ids(&parsed, version)?;
+            check_base_versions(&parsed, version)?;
+            if state.config.case_insensitive_paths {
+                match_stored_case(&state, user.user_id, &mut parsed, &mut respelled).await?;
+            }
+            flag_policy_violations(&state, user.user_id, &mut parsed).await?;
+
+            let conflicts = find_conflicting_paths(&parsed);
+            if !conflicts.is_empty() {
+                return Ok((
+                    StatusCode::BAD_REQUEST,
+                    Json(serde_json::json!({
+                        "error": "File paths appear under more than one operation",
+                        "code": FailureCode::DuplicatePath,
+                        "conflicting_paths": conflicts
+                    }))
+                ).into_response());
+            }
+
+            let unhashed = find_unhashed_paths(&parsed);
+            if !unhashed.is_empty() {
+                return Ok((
+                    StatusCode::BAD_REQUEST,
+                    Json(serde_json::json!({
+                        "error": "Inserts and updates need a file_hash",
+                        "code": FailureCode::InvalidRequest,
+                        "unhashed_paths": unhashed
+                    }))
+                ).into_response());
+            }
+
+            if conflict_copies {
+                redirects = make_conflict_copies(&state, user.user_id, &device, &mut parsed).await?;
+            }
+            assign_storage_keys(&state, user.user_id, &mut parsed);
+            let growth = payload_growth(&state, user.user_id, &parsed).await?;
+            if let Some(mut response) = reject_over_quota(&state, user.user_id, growth).await {
+                if params.dry_run {
+                    response.headers_mut().insert("Dry-Run", HeaderValue::from_static("true"));
+                }
+                return Ok(response);
+            }
+            if !params.dry_run {
+                skip_uploads = prepare_sync(&state, &user, &parsed, params.on_conflict).await;
+            }
+            // Refused files never reach storage.
+            skip_uploads.extend(
+                [Operation::Insert, Operation::Update]
+                    .iter()
+                    .filter_map(|cmd| parsed.get(cmd))
+                    .flatten()
+                    .filter(|file| file.refusal.is_some())
+                    .map(|file| storage_key(file).to_string()),
+            );
+            let linked = link_contents(&state, user.user_id, &parsed, &skip_uploads, params.dry_run, &mut stored, &mut failed_uploads).await?;
+            skip_uploads.extend(linked);
+            payload = Some(parsed);
+        }
+        else if name == "files" || name.starts_with(UPLOAD_PART_PREFIX) {
+            let Some(parsed) = &payload else {
+                return Err(AppError::BadRequest("The payload field must be sent before any files".into()));
+            };
+
+            let upload_id = name.strip_prefix(UPLOAD_PART_PREFIX).map(str::to_string);
+            let named = upload_id.as_deref().and_then(|id| entry_for_upload(parsed, id));
+            let filename = field
+                .file_name()
+                .map(|s| s.to_string())
+                .or_else(|| named.map(|file| file.file_path.clone()))
+                .unwrap_or_else(|| "unknown".to_string());
+            let key = match upload_id {
+                Some(id) => {
+                    let Some(file) = named else {
+                        return Err(AppError::BadRequest(format!("No file in the payload has upload_id {}", id)));
+                    };
+                    let key = storage_key(file).to_string();
+                    if !received_ids.insert(id) {
+                        return Err(AppError::BadRequest(format!("The part for {} was sent twice", file.file_path)));
+                    }
+                    key
+                }
+                None => {
+                    let name = respelled.get(&filename).unwrap_or(&filename);
+                    let target = redirects.get(name).unwrap_or(name);
+                    let Some(key) = upload_key(parsed, target, &stored, &failed_uploads, &uploading_keys) else {
+                        debug!("Ignoring upload of {}: no insert or update in the payload names it", filename);
+                        continue;
+                    };
+                    key
+                }
+            };
+            if skip_uploads.contains(&key) {
+                debug!("Skipping upload of unchanged or conflicting file: {}", key);
+                continue;
+            }
+            if params.dry_run {
+                // Read but not stored, so the prediction can check it against the payload.
+                let content_type = resolve_content_type(field.content_type(), &filename);
+                let (sha256, size) = hash_part(field).await?;
+                stored.insert(key, StoredObject { content_type, etag: None, sha256, size });
+                continue;
+            }
+
+            // The first chunk is read ahead so a file of unknown type can be told by its content.
+            let declared = field.content_type().map(str::to_string);
+            let mut field = field;
+            let first = field.next().await;
+            let head = first.as_ref().and_then(|chunk| chunk.as_ref().ok()).map(|b| &b[..]).unwrap_or_default();
+            let content_type = detect_content_type(declared.as_deref(), &filename, head);
+            debug!("Receiving file: {} ({})", filename, content_type);
+            let field = futures::stream::iter(first).chain(field);
+
+            // The declared sizes were checked against the limit already; this stops a
+            // client that sends more bytes than it declared.
+            let max_size = state.settings.current().max_upload_bytes;
+            let mut hasher = Sha256::new();
+            let mut received: u64 = 0;
+            let mut chunks = field.map(|chunk| {
+                let bytes = chunk.map_err(|e| e.to_string())?;
+                received += bytes.len() as u64;
+                if let Some(max) = max_size && received > max {
+                    return Err(format!("{} exceeds the {} byte upload limit", filename, max));
+                }
+                hasher.update(&bytes);
+                Ok(bytes)
+            });
+            // Small files are read whole and sent to storage in the background while
+            // the next ones are read; larger ones are streamed through as before.
+            let result = match read_part(&mut chunks, BUFFERED_UPLOAD_BYTES).await {
+                Ok((data, true)) => {
+                    drop(chunks);
+                    let sha256 = hex::encode(hasher.finalize());
+                    let object = StoredObject { content_type, etag: None, sha256, size: received as i64 };
+                    let storage = state.storage.clone();
+                    // A file sent twice is stored in the order it was sent.
+                    if !uploading_keys.insert(key.clone()) {
+                        while let Some(upload) = uploading.next().await {
+                            finish_upload(upload, &mut stored, &mut failed_uploads)?;
+                        }
+                    }
+                    uploading.push(async move {
+                        let result = storage.put(&key, data, &object.content_type).await;
+                        (key, object, result)
+                    });
+                    if uploading.len() >= state.sync_concurrency
+                        && let Some(upload) = uploading.next().await
+                    {
+                        finish_upload(upload, &mut stored, &mut failed_uploads)?;
+                    }
+                    continue;
+                }
+                Ok((data, false)) => {
+                    let mut rest = futures::stream::iter([Ok(Bytes::from(data))]).chain(&mut chunks);
+                    state.storage.put_stream(&key, &content_type, &mut rest).await
+                }
+                Err(e) => Err(e.into()),
+            };
+            drop(chunks);
+
+            if let Some(max) = max_size && received > max {
+                return Err(AppError::PayloadTooLarge(format!(
+                    "{} exceeds the {} byte upload limit",
+                    filename, max
+                )));
+            }
+            let sha256 = hex::encode(hasher.finalize());
+            let object = StoredObject { content_type, etag: None, sha256, size: received as i64 };
+            finish_upload((key, object, result), &mut stored, &mut failed_uploads)?;
+        }
+    }
+    while let Some(upload) = uploading.next().await {
+        finish_upload(upload, &mut stored, &mut failed_uploads)?;
+    }
+
+    let payload = payload.ok_or_else(|| AppError::BadRequest("Missing payload".into()))?;
+    fail_missing_uploads(&payload, &received_ids, &skip_uploads, &mut failed_uploads);
+    let options = SyncOptions { on_conflict: params.on_conflict, atomic: params.atomic };
+
+    if params.dry_run {
+        let results = predict_sync(&state, &user, payload, &stored, &failed_uploads, params.on_conflict).await;
+        return Ok((
+            StatusCode::OK,
+            [("Dry-Run", "true")],
+            Json(SyncReport::new(results, true)),
+        ).into_response());
+    }
+
+    if params.run_async {
+        let total: usize = payload.values().map(Vec::len).sum();
+        let job_id = create_job(&state.pool, Some(user.user_id), "sync", total as i32)
+            .await
+            .map_err(|e| AppError::Internal(format!("Failed to create job: {}", e)))?;
+
+        state.tasks.spawn(run_sync_job(
+            state.clone(),
+            user.clone(),
+            job_id,
+            payload,
+            stored,
+            failed_uploads,
+            options,
+        ).instrument(info_span!("sync_job", job_id)));
+
+        let body = serde_json::json!({
+            "job_id": job_id,
+            "status_url": format!("/jobs/{}", job_id)
+        });
+        if let Some(held) = held_key {
+            held.record(StatusCode::ACCEPTED, &body).await;
+        }
+        return Ok((StatusCode::ACCEPTED, Json(body)).into_response());
+    }
+
+    let response = process_sync(
+        &state,
+        &user,
+        payload,
+        &stored,
+        &failed_uploads,
+        options,
+        None,
+    )
+    .await;
+    let report = SyncReport::new(response, false);
+    let status = report.status();
+    if let Some(held) = held_key {
+        match serde_json::to_value(&report) {
+            Ok(body) => held.record(status, &body).await,
+            Err(e) => warn!("Failed to serialize response for idempotency key: {}", e),
+        }
+    }
+    Ok((status, Json(report)).into_response())
+}
+
+/// Tells which of the listed contents the caller already has, so a client can
+/// `link` those in its next `/sync` rather than upload them again. Only the
+/// caller's own files are looked at.
+#[utoipa::path(
+    post, path = "/sync/precheck", tag = "sync",
+    summary = "Finds which contents don't need uploading",
+    request_body = PrecheckRequest,
+    responses(
+        (status = 200, description = "The contents the caller has, and those it doesn't", body = PrecheckResponse),
+        (status = 400, description = "Too many files, or a `file_hash` that isn't a SHA-256", body = crate::openapi::ErrorBody),
+        (status = 401, description = "Missing or invalid bearer token", body = crate::openapi::ErrorBody),
+    )
+)]
+pub(crate) async fn handle_sync_precheck(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Json(req): Json<PrecheckRequest>,
+) -> Result<Response, AppError> {
+    if req.files.len() > MAX_PRECHECK_FILES {
+        return Err(AppError::BadRequest(format!("At most {} files can be checked at once", MAX_PRECHECK_FILES)));
+    }
+    if let Some(file) = req.files.iter().find(|file| !is_sha256_hex(&file.file_hash)) {
+        return Err(AppError::BadRequest(format!("{} is not a hex SHA-256", file.file_hash)));
+    }
+
+    let hashes: Vec<String> = req.files.iter().map(|file| file.file_hash.clone()).collect();
+    let contents = find_stored_contents(&state.pool, user.user_id, &hashes).await?;
+    let (available, missing) = req
+        .files
+        .into_iter()
+        .partition(|file| contents.contains_key(&(file.file_hash.clone(), file.file_size)));
+    Ok((StatusCode::OK, Json(PrecheckResponse { available, missing })).into_response())
+}
+
+/// Reads the `payload` field into memory, failing with a 413 once it passes `limit`
+/// bytes; the body limit on `/sync` is sized for the files, not for this.
+pub(crate) async fn read_payload_field(mut field: Field<'_>, limit: usize) -> Result<Vec<u8>, AppError> {
+    let mut text = Vec::new();
+    while let Some(chunk) = field
+        .chunk()
+        .await
+        .map_err(|e| AppError::BadRequest(format!("Failed to read payload: {}", e)))?
+    {
+        if text.len() + chunk.len() > limit {
+            return Err(AppError::PayloadTooLarge(format!("payload exceeds the {} byte limit", limit)));
+        }
+        text.extend_from_slice(&chunk);
+    }
+    Ok(text)
+}
+
+/// The payload's version, its `conflict_copies` flag and its files by operation,
+/// each operation's in the order they were sent. Fails with a 400 for a version
+/// this server doesn't read, or files laid out as another version lists them.
+fn parse_payload(text: &[u8]) -> Result<(u32, bool, FileSyncPayload), AppError> {
+    let SyncPayload { schema_version, conflict_copies, operations, by_operation } = serde_json::from_slice(text)
+        .map_err(|e| AppError::BadRequest(format!("Invalid payload: {}", e)))?;
+    let version = schema_version.unwrap_or(1);
+    if !(1..=SYNC_PAYLOAD_VERSION).contains(&version) {
+        return Err(AppError::BadRequest(format!(
+            "Unsupported payload version {}; this server reads versions 1 to {}",
+            version, SYNC_PAYLOAD_VERSION
+        )));
+    }
+
+    let parsed = match operations {
+        Some(_) if version < OPERATION_LIST_PAYLOAD_VERSION => {
+            return Err(AppError::BadRequest(format!(
+                "Files are listed under operations from payload version {}; this payload is version {}",
+                OPERATION_LIST_PAYLOAD_VERSION, version
+            )));
+        }
+        Some(_) if !by_operation.is_empty() => {
+            return Err(AppError::BadRequest(
+                "Files are listed under operations or under their operation's name, not both".into(),
+            ));
+        }
+        Some(entries) => {
+            let mut parsed: FileSyncPayload = HashMap::new();
+            for OperationEntry { op, file } in entries {
+                parsed.entry(op).or_default().push(file);
+            }
+            parsed
+        }
+        None if version >= OPERATION_LIST_PAYLOAD_VERSION && !by_operation.is_empty() => {
+            return Err(AppError::BadRequest(format!(
+                "Payload version {} lists files under operations, each with its op",
+                version
+            )));
+        }
+        None => by_operation,
+    };
+    Ok((version, conflict_copies, parsed))
+}
+
+/// Rejects the whole request when a file about to be uploaded declares a negative
+/// size, or one over `max_upload_bytes`, before any of its bytes are read.
+fn check_declared_sizes(state: &AppState, payload: &FileSyncPayload) -> Result<(), AppError> {
+    let uploads = [Operation::Insert, Operation::Update]
+        .iter()
+        .filter_map(|cmd| payload.get(cmd))
+        .flatten();
+    for file in uploads {
+        if file.file_size < 0 {
+            return Err(AppError::BadRequest(format!("{}: file_size must not be negative", file.file_path)));
+        }
+        if let Some(max) = state.settings.current().max_upload_bytes && file.file_size as u64 > max {
+            return Err(AppError::PayloadTooLarge(format!(
+                "{}: file_size exceeds the {} byte upload limit",
+                file.file_path, max
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Marks the files the upload policy of `user_id` refuses, so each fails with the
+/// reason rather than being stored.
+async fn flag_policy_violations(state: &AppState, user_id: i32, payload: &mut FileSyncPayload) -> Result<(), AppError> {
+    let policy = effective_policy(state, user_id).await?;
+    if policy.is_empty() {
+        return Ok(());
+    }
+    let check = PolicyCheck::new(&policy);
+    for (cmd, files) in payload.iter_mut() {
+        for file in files.iter_mut().filter(|file| file.refusal.is_none()) {
+            file.refusal = check.violation(*cmd, file);
+        }
+    }
+    Ok(())
+}
+
+/// Rewrites the payload's paths in the spelling paths are kept in, and returns the
+/// paths it rewrote, by the spelling they were sent in, so their parts can be told
+/// by either. An entry whose path only matches an earlier one's once normalized,
+/// or is in a folder spelled unlike the same folder of an earlier one, is refused
+/// instead and keeps the path it was sent with.
+fn normalize_payload_paths(state: &AppState, payload: &mut FileSyncPayload) -> HashMap<String, String> {
+    let insensitive = state.config.case_insensitive_paths;
+    // The first spelling of each path, as sent and normalized, and of each folder.
+    let mut paths: HashMap<String, (String, String)> = HashMap::new();
+    let mut folders: HashMap<String, String> = HashMap::new();
+    let mut respelled = HashMap::new();
+
+    for cmd in OPERATION_ORDER {
+        let Some(files) = payload.get_mut(&cmd) else { continue };
+        for file in files {
+            let sent: Vec<String> = std::iter::once(file.file_path.clone()).chain(file.from_path.clone()).collect();
+            let normalized: Vec<String> = sent.iter().map(|path| normalize_path(path)).collect();
+            let collides = sent.iter().zip(&normalized).find_map(|(sent, path)| {
+                if let Some((first, spelled)) = paths.get(&path_key(path, insensitive)) && first != sent {
+                    return Some(spelled.clone());
+                }
+                ancestors(path).find_map(|folder| {
+                    folders.get(&path_key(folder, insensitive)).filter(|spelled| *spelled != folder).cloned()
+                })
+            });
+            if let Some(other) = collides {
+                file.refusal = Some(collision(&other));
+                continue;
+            }
+
+            for (sent, path) in sent.into_iter().zip(&normalized) {
+                for folder in ancestors(path) {
+                    folders.entry(path_key(folder, insensitive)).or_insert_with(|| folder.to_string());
+                }
+                paths.entry(path_key(path, insensitive)).or_insert_with(|| (sent.clone(), path.clone()));
+                if sent != *path {
+                    respelled.insert(sent, path.clone());
+                }
+            }
+            let mut normalized = normalized.into_iter();
+            file.file_path = normalized.next().unwrap_or_default();
+            file.from_path = normalized.next();
+        }
+    }
+    respelled
+}
+
+/// With `case_insensitive_paths`, gives updates, deletes and the sources of moves
+/// that name an existing file in other case that file's path, and refuses inserts
+/// and move destinations an existing file or folder has in other case.
+async fn match_stored_case(
+    state: &AppState,
+    user_id: i32,
+    payload: &mut FileSyncPayload,
+    respelled: &mut HashMap<String, String>,
+) -> Result<(), AppError> {
+    let paths: Vec<String> = payload
+        .values()
+        .flatten()
+        .filter(|file| file.refusal.is_none())
+        .flat_map(|file| std::iter::once(file.file_path.clone()).chain(file.from_path.clone()))
+        .collect();
+    let paths: Vec<&str> = paths.iter().map(String::as_str).collect();
+    let spellings = existing_spellings(state, user_id, &paths).await?;
+    if spellings.is_empty() {
+        return Ok(());
+    }
+
+    let mut respell = |path: &mut String| {
+        let Some(stored) = stored_spelling(&spellings, path).map(str::to_string) else { return };
+        for spelled in respelled.values_mut().filter(|spelled| **spelled == *path) {
+            *spelled = stored.clone();
+        }
+        respelled.insert(std::mem::replace(path, stored.clone()), stored);
+    };
+    for (cmd, files) in payload.iter_mut() {
+        for file in files.iter_mut().filter(|file| file.refusal.is_none()) {
+            match cmd {
+                Operation::Update | Operation::Delete => respell(&mut file.file_path),
+                Operation::Move => {
+                    if let Some(from) = &mut file.from_path {
+                        respell(from);
+                    }
+                }
+                Operation::Insert => {}
+            }
+        }
+    }
+    for (cmd, files) in payload.iter_mut() {
+        if !matches!(cmd, Operation::Insert | Operation::Move) {
+            continue;
+        }
+        for file in files.iter_mut().filter(|file| file.refusal.is_none()) {
+            if let Some(other) = colliding_spelling(&spellings, &file.file_path, file.from_path.as_deref()) {
+                file.refusal = Some(collision(other));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Rejects the whole request when a file carries too many or malformed tags, or
+/// too much metadata. Tags are trimmed and deduplicated on the way.
+fn check_annotations(payload: &mut FileSyncPayload) -> Result<(), AppError> {
+    let written = payload
+        .iter_mut()
+        .filter(|(cmd, _)| **cmd != Operation::Delete)
+        .flat_map(|(_, files)| files);
+    for file in written {
+        if let Some(tags) = &mut file.tags {
+            for tag in tags.iter_mut() {
+                *tag = tag.trim().to_string();
+                if tag.is_empty() || tag.chars().count() > MAX_TAG_LEN {
+                    return Err(AppError::BadRequest(format!(
+                        "{}: tags must be 1 to {} characters",
+                        file.file_path, MAX_TAG_LEN
+                    )));
+                }
+            }
+            tags.sort();
+            tags.dedup();
+            if tags.len() > MAX_TAGS_PER_FILE {
+                return Err(AppError::BadRequest(format!(
+                    "{}: at most {} tags are allowed",
+                    file.file_path, MAX_TAGS_PER_FILE
+                )));
+            }
+        }
+        if let Some(metadata) = &file.metadata {
+            let size: usize = metadata.iter().map(|(key, value)| key.len() + value.len()).sum();
+            if size > MAX_METADATA_BYTES {
+                return Err(AppError::BadRequest(format!(
+                    "{}: metadata exceeds {} bytes",
+                    file.file_path, MAX_METADATA_BYTES
+                )));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Rejects the whole request when two files share an `upload_id`, when a file to
+/// `link` says where its bytes are otherwise or has no SHA-256 to link by, or,
+/// from `UPLOAD_ID_PAYLOAD_VERSION` on, when an insert neither names its part nor
+/// is `metadata_only` or linked.
+fn check_upload_ids(payload: &FileSyncPayload, version: u32) -> Result<(), AppError> {
+    let mut seen = HashSet::new();
+    for (cmd, files) in payload {
+        for file in files {
+            if file.link {
+                if file.upload_id.is_some() || file.metadata_only || !matches!(cmd, Operation::Insert | Operation::Update) {
+                    return Err(AppError::BadRequest(format!(
+                        "{}: only inserts and updates without an upload_id or metadata_only can link",
+                        file.file_path
+                    )));
+                }
+                if !file.file_hash.as_deref().is_some_and(is_sha256_hex) {
+                    return Err(AppError::BadRequest(format!(
+                        "{}: a linked file needs its SHA-256 as file_hash",
+                        file.file_path
+                    )));
+                }
+                continue;
+            }
+            match (&file.upload_id, file.metadata_only) {
+                (Some(_), true) => {
+                    return Err(AppError::BadRequest(format!(
+                        "{}: a metadata_only file can't have an upload_id",
+                        file.file_path
+                    )));
+                }
+                (Some(id), false) if !seen.insert(id.as_str()) => {
+                    return Err(AppError::BadRequest(format!("upload_id {} is given to more than one file", id)));
+                }
+                (None, false) if *cmd == Operation::Insert && version >= UPLOAD_ID_PAYLOAD_VERSION => {
+                    return Err(AppError::BadRequest(format!(
+                        "{}: inserts need an upload_id, metadata_only or link from payload version {}",
+                        file.file_path, UPLOAD_ID_PAYLOAD_VERSION
+                    )));
+                }
+                _ => {}
+            }
+        }
+    }
+    Ok(())
+}
+
+/// From `BASE_VERSION_PAYLOAD_VERSION` on, rejects the whole request when an update
+/// or delete doesn't say which version of the file it was made against.
+fn check_base_versions(payload: &FileSyncPayload, version: u32) -> Result<(), AppError> {
+    if version < BASE_VERSION_PAYLOAD_VERSION {
+        return Ok(());
+    }
+    let unversioned = [Operation::Update, Operation::Delete]
+        .iter()
+        .filter_map(|cmd| payload.get(cmd))
+        .flatten()
+        .find(|file| file.base_version.is_none());
+    match unversioned {
+        Some(file) => Err(AppError::BadRequest(format!(
+            "{}: updates and deletes need a base_version from payload version {}",
+            file.file_path, BASE_VERSION_PAYLOAD_VERSION
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// The insert or update whose bytes come in the part for `upload_id`.
+fn entry_for_upload<'p>(payload: &'p FileSyncPayload, upload_id: &str) -> Option<&'p FileEntry> {
+    [Operation::Insert, Operation::Update]
+        .iter()
+        .filter_map(|cmd| payload.get(cmd))
+        .flatten()
+        .find(|file| file.upload_id.as_deref() == Some(upload_id))
+}
+
+/// Fails every insert and update whose `upload_id` no part was sent for, but for
+/// those in `skipped`, which needed no bytes.
+fn fail_missing_uploads(
+    payload: &FileSyncPayload,
+    received: &HashSet<String>,
+    skipped: &[String],
+    failed_uploads: &mut HashMap<String, String>,
+) {
+    let expected = [Operation::Insert, Operation::Update]
+        .iter()
+        .filter_map(|cmd| payload.get(cmd))
+        .flatten()
+        .filter_map(|file| Some((file, file.upload_id.as_deref()?)));
+    for (file, id) in expected {
+        let key = storage_key(file);
+        if !received.contains(id) && !skipped.iter().any(|skip| skip == key) {
+            failed_uploads.insert(key.to_string(), format!("{}: no files:{} part was sent", MISSING_UPLOAD_MESSAGE, id));
+        }
+    }
+}
+
+/// Gives every insert and update that sets `link` the content of a file of the
+/// user's with its `file_hash` and `file_size`, copied to its storage key as if it
+/// had been uploaded; a dry run only looks the file up. Those in `skipped` are
+/// left alone. One with no such file, or whose copy failed, fails as a missing
+/// upload. Returns the keys given content, whose parts, if any arrive, are ignored.
+async fn link_contents(
+    state: &AppState,
+    user_id: i32,
+    payload: &FileSyncPayload,
+    skipped: &[String],
+    dry_run: bool,
+    stored: &mut HashMap<String, StoredObject>,
+    failed_uploads: &mut HashMap<String, String>,
+) -> Result<Vec<String>, AppError> {
+    let linked: Vec<&FileEntry> = [Operation::Insert, Operation::Update]
+        .iter()
+        .filter_map(|cmd| payload.get(cmd))
+        .flatten()
+        .filter(|file| file.link && !skipped.iter().any(|key| key == storage_key(file)))
+        .collect();
+    if linked.is_empty() {
+        return Ok(Vec::new());
+    }
+    let hashes: Vec<String> = linked.iter().filter_map(|file| file.file_hash.clone()).collect();
+    let contents = find_stored_contents(&state.pool, user_id, &hashes).await?;
+
+    let copies: Vec<_> = linked
+        .into_iter()
+        .map(|file| {
+            let source = contents.get(&(file.file_hash.clone().unwrap_or_default(), file.file_size));
+            link_content(state, file, source, dry_run)
+        })
+        .collect();
+    let mut results = futures::StreamExt::buffer_unordered(futures::stream::iter(copies), state.sync_concurrency);
+    let mut given = Vec::new();
+    while let Some((key, result)) = results.next().await {
+        match result {
+            Ok(object) => {
+                stored.insert(key.clone(), object);
+                given.push(key);
+            }
+            Err(e) => {
+                warn!("Failed to link {}: {}", key, e);
+                failed_uploads.insert(key, e);
+            }
+        }
+    }
+    Ok(given)
+}
+
+/// Copies the content of `source`, when there is one, to the storage key of
+/// `file`, and describes it as `handle_sync` describes an upload.
+async fn link_content(
+    state: &AppState,
+    file: &FileEntry,
+    source: Option<&FileEntry>,
+    dry_run: bool,
+) -> (String, Result<StoredObject, String>) {
+    let key = storage_key(file).to_string();
+    let Some(source) = source else {
+        let error = format!("{}: no file of yours has this file_hash and file_size to link", MISSING_UPLOAD_MESSAGE);
+        return (key, Err(error));
+    };
+    // The source's name is its storage key.
+    if !dry_run {
+        debug!("Linking {} to the content of {}", file.file_path, source.file_path);
+        if let Err(e) = state.storage.copy(&source.file_name, &key).await {
+            return (key, Err(e.to_string()));
+        }
+    }
+    let object = StoredObject {
+        content_type: source.content_type.clone().unwrap_or_else(|| resolve_content_type(None, &file.file_name)),
+        etag: None,
+        sha256: source.file_hash.clone().unwrap_or_default(),
+        size: source.file_size,
+    };
+    (key, Ok(object))
+}
+
+/// Turns every update that conflicts with the server copy into an insert of a
+/// conflicted copy beside it, so both versions are kept. Returns the path of each
+/// copy by the names its upload may arrive under: the path or name of the update.
+async fn make_conflict_copies(
+    state: &AppState,
+    user_id: i32,
+    device: &str,
+    payload: &mut FileSyncPayload,
+) -> Result<HashMap<String, String>, AppError> {
+    let mut redirects = HashMap::new();
+    let Some(updates) = payload.remove(&Operation::Update) else {
+        return Ok(redirects);
+    };
+
+    let date = chrono::Utc::now().format("%Y-%m-%d").to_string();
+    let mut kept = Vec::with_capacity(updates.len());
+    let mut copies = Vec::new();
+    for file in updates {
+        if find_update_conflict(state.pool.executor(), user_id, &file).await?.is_none() {
+            kept.push(file);
+            continue;
+        }
+
+        // A second conflict on the same day gets a numbered copy.
+        let mut attempt = 1;
+        let (file_path, file_name) = loop {
+            let file_path = conflicted_copy_name(&file.file_path, device, &date, attempt);
+            let file_name = conflicted_copy_name(&file.file_name, device, &date, attempt);
+            let taken = on_db!(&state.pool, pool => sqlx::query_scalar::<_, bool>(
+                "SELECT EXISTS (SELECT 1 FROM filehash WHERE user_id = $1 AND file_path = $2)"
+            )
+            .bind(user_id)
+            .bind(&file_path)
+            .fetch_one(pool)
+            .await)?;
+            if !taken {
+                break (file_path, file_name);
+            }
+            attempt += 1;
+        };
+
+        info!("Keeping conflicting update of {} as {}", file.file_path, file_path);
+        redirects.insert(file.file_path.clone(), file_path.clone());
+        redirects.insert(file.file_name.clone(), file_path.clone());
+        copies.push(FileEntry {
+            conflict_copy_of: Some(file.file_path.clone()),
+            file_path,
+            file_name,
+            base_modified_time: None,
+            base_hash: None,
+            base_version: None,
+            ..file
+        });
+    }
+
+    if !kept.is_empty() {
+        payload.insert(Operation::Update, kept);
+    }
+    if !copies.is_empty() {
+        payload.entry(Operation::Insert).or_default().extend(copies);
+    }
+    Ok(redirects)
+}
+
+/// `path` with ` (conflicted copy from <device> <date>)` added before its extension,
+/// numbered from the second `attempt` on.
+fn conflicted_copy_name(path: &str, device: &str, date: &str, attempt: u32) -> String {
+    let (dir, name) = match path.rsplit_once('/') {
+        Some((dir, name)) => (Some(dir), name),
+        None => (None, path),
+    };
+    let (stem, ext) = match name.rfind('.') {
+        Some(i) if i > 0 => name.split_at(i),
+        _ => (name, ""),
+    };
+    let number = if attempt > 1 { format!(" {}", attempt) } else { String::new() };
+    let name = format!(
+        "{} (conflicted copy from {} {}{}){}",
+        stem,
+        device.replace('/', "-"),
+        date,
+        number,
+        ext
+    );
+    match dir {
+        Some(dir) => format!("{}/{}", dir, name),
+        None => name,
+    }
+}
+
+/// Picks the storage key each insert and update is written to: always a new one, so
+/// no upload ever lands on an object a row still points at. The write that takes
+/// the upload moves the row onto its key, and the replaced object is kept as a
+/// version by that same write.
+fn assign_storage_keys(state: &AppState, user_id: i32, payload: &mut FileSyncPayload) {
+    for (cmd, files) in payload.iter_mut() {
+        if matches!(cmd, Operation::Delete | Operation::Move) {
+            continue;
+        }
+        for file in files {
+            file.storage_key = Some(generate_system_path(state, user_id, &file.file_path, &file.file_name));
+        }
+    }
+}
+
+/// The storage key `assign_storage_keys` picked for an insert or update.
+fn storage_key(file: &FileEntry) -> &str {
+    file.storage_key.as_deref().unwrap_or_default()
+}
+
+/// Finds the key an uploaded part belongs to. A part names its file by path, or by
+/// name as older clients do; files that share a name take their uploads in payload
+/// order.
+fn upload_key(
+    payload: &FileSyncPayload,
+    name: &str,
+    stored: &HashMap<String, StoredObject>,
+    failed_uploads: &HashMap<String, String>,
+    uploading: &HashSet<String>,
+) -> Option<String> {
+    let files: Vec<&FileEntry> = [Operation::Insert, Operation::Update]
+        .iter()
+        .filter_map(|cmd| payload.get(cmd))
+        .flatten()
+        .collect();
+    let received = |file: &&&FileEntry| {
+        let key = storage_key(file);
+        stored.contains_key(key) || failed_uploads.contains_key(key) || uploading.contains(key)
+    };
+    files
+        .iter()
+        .find(|file| file.file_path == name)
+        .or_else(|| files.iter().filter(|file| file.file_name == name).find(|file| !received(file)))
+        .map(|file| storage_key(file).to_string())
+}
+
+/// Records the outcome of storing an upload. One that failed transiently, even
+/// after retries, only fails the files that depend on it; the client can retry them.
+fn finish_upload(
+    (key, mut object, result): (String, StoredObject, Result<Option<String>, StorageError>),
+    stored: &mut HashMap<String, StoredObject>,
+    failed_uploads: &mut HashMap<String, String>,
+) -> Result<(), AppError> {
+    match result {
+        Ok(etag) => {
+            debug!("Uploaded to storage with key: {}", key);
+            object.etag = etag;
+            stored.insert(key, object);
+        }
+        Err(e) if e.is_transient() => {
+            warn!("Upload of {} failed: {}", key, e);
+            failed_uploads.insert(key, e.to_string());
+        }
+        Err(e) => return Err(AppError::BadGateway(format!("Upload of {} failed: {}", key, e))),
+    }
+    Ok(())
+}
+
+/// Hex SHA-256 and length of a part, read to the end.
+async fn hash_part(mut field: Field<'_>) -> Result<(String, i64), AppError> {
+    let mut hasher = Sha256::new();
+    let mut size = 0;
+    while let Some(chunk) = field.next().await {
+        let chunk = chunk.map_err(|e| AppError::BadRequest(format!("Malformed multipart body: {}", e)))?;
+        size += chunk.len() as i64;
+        hasher.update(&chunk);
+    }
+    Ok((hex::encode(hasher.finalize()), size))
+}
+
+/// Returns the storage keys whose uploads must be skipped: inserts whose content is
+/// unchanged, and updates that conflict with the server copy or are locked by
+/// another device, and so can't be applied. Replaced content is kept as a version
+/// by the write that replaces it.
+async fn prepare_sync(
+    state: &AppState,
+    user: &AuthUser,
+    payload: &FileSyncPayload,
+    on_conflict: OnConflict,
+) -> Vec<String> {
+    let user_id = user.user_id;
+    let updates: Vec<&FileEntry> = payload.get(&Operation::Update).into_iter().flatten().collect();
+    let upserts = payload
+        .get(&Operation::Insert)
+        .filter(|_| on_conflict == OnConflict::Update);
+    let overwrites: Vec<&FileEntry> = updates
+        .iter()
+        .copied()
+        .chain(upserts.into_iter().flatten())
+        .filter(|file| file.refusal.is_none())
+        .collect();
+
+    // One query per check rather than per file, which is what large payloads spend their time on.
+    let update_paths: Vec<&str> = updates.iter().map(|f| f.file_path.as_str()).collect();
+    let locked = find_foreign_locks(&state.pool, user_id, &update_paths, user.device_id.as_deref())
+        .await
+        .unwrap_or_default();
+    let conflicted = find_update_conflicts(&state.pool, user_id, &overwrites).await.unwrap_or_default();
+
+    let mut skip: Vec<String> = overwrites
+        .into_iter()
+        .filter(|file| locked.contains(&file.file_path) || conflicted.contains(&file.file_path))
+        .map(|file| storage_key(file).to_string())
+        .collect();
+
+    if let Some(inserts) = payload.get(&Operation::Insert) {
+        let unchanged = find_unchanged_files(&state.pool, user_id, inserts).await.unwrap_or_default();
+        skip.extend(inserts.iter().filter(|file| unchanged.contains_key(&file.file_path)).map(|file| storage_key(file).to_string()));
+    }
+    skip
+}
+
+/// Predicts the outcome of `process_sync` using read-only checks, against the
+/// parts `handle_sync` read and hashed as `stored` and those it found missing.
+/// Nothing is uploaded, deleted or written to the database.
+async fn predict_sync(
+    state: &AppState,
+    user: &AuthUser,
+    mut payload: FileSyncPayload,
+    stored: &HashMap<String, StoredObject>,
+    failed_uploads: &HashMap<String, String>,
+    on_conflict: OnConflict,
+) -> SyncResponse {
+    info!("DRY RUN SYNCING");
+    let user_id = user.user_id;
+
+    let mut response: SyncResponse = HashMap::new();
+
+    for cmd in OPERATION_ORDER {
+        let Some(mut files) = payload.remove(&cmd) else { continue };
+        let mut success = Vec::new();
+        let mut failure = take_repeated_inserts(cmd, &mut files);
+        let mut conflict = Vec::new();
+        let mut locked = Vec::new();
+
+        for file in files {
+            if let Some(refusal) = file.refusal.clone() {
+                failure.push(FileFailure::new(file.file_path, refusal));
+                continue;
+            }
+            if cmd == Operation::Move {
+                match predict_move(state, user, file).await {
+                    Ok(entry) => success.push(entry),
+                    Err(OperationError::Failure(f)) => failure.push(f),
+                    Err(OperationError::Conflict(c)) => conflict.push(c),
+                    Err(OperationError::Locked(l)) => locked.push(l),
+                }
+                continue;
+            }
+
+            let existing = load_row(state, user_id, &file.file_path).await;
+
+            let existing = match existing {
+                Ok(row) => row,
+                Err(e) => {
+                    failure.push(FileFailure::from_db(file.file_path, &e));
+                    continue;
+                }
+            };
+
+            let unchanged = cmd == Operation::Insert
+                && file.file_hash.is_some()
+                && existing.as_ref().is_some_and(|row| row.file_hash == file.file_hash);
+            if let Some(row) = existing.as_ref().filter(|_| unchanged) {
+                success.push(FileEntry { skipped: true, ..row.clone() });
+                continue;
+            }
+            if let Some(error) = upload_problem(stored, failed_uploads, cmd, &file) {
+                failure.push(FileFailure::new(file.file_path, error));
+                continue;
+            }
+
+            if cmd == Operation::Update && existing.is_some() {
+                let device_id = user.device_id.as_deref();
+                match find_foreign_lock(state.pool.executor(), user_id, &file.file_path, device_id).await {
+                    Ok(Some(lock)) => {
+                        locked.push(FileLocked::new(file.file_path, lock));
+                        continue;
+                    }
+                    Ok(None) => {}
+                    Err(e) => {
+                        failure.push(FileFailure::from_db(file.file_path, &e));
+                        continue;
+                    }
+                }
+            }
+
+            if matches!(cmd, Operation::Update | Operation::Delete)
+                && let Some(row) = existing.as_ref().filter(|row| conflicts_with_base(&file, row))
+            {
+                conflict.push(FileConflict::new(&file, row.clone()));
+                continue;
+            }
+
+            let error = match (cmd, &existing) {
+                (Operation::Insert, Some(_)) if on_conflict == OnConflict::Fail => {
+                    Some(INSERT_CONFLICT_MESSAGE.to_string())
+                }
+                (Operation::Update, None) | (Operation::Delete, None) => {
+                    Some(FILE_NOT_FOUND_MESSAGE.to_string())
+                }
+                _ => None,
+            };
+
+            if let Some(error) = error {
+                failure.push(FileFailure::new(file.file_path, error));
+                continue;
+            }
+
+            match (cmd, existing) {
+                (Operation::Insert, _) => success.push(FileEntry {
+                    content_type: Some(resolve_content_type(None, &file.file_name)),
+                    file_name: storage_key(&file).to_string(),
+                    ..file
+                }),
+                (Operation::Update, Some(row)) => success.push(FileEntry {
+                    file_name: if stored.contains_key(storage_key(&file)) {
+                        storage_key(&file).to_string()
+                    } else {
+                        row.file_name
+                    },
+                    content_type: row.content_type,
+                    ..file
+                }),
+                _ => success.push(file),
+            }
+        }
+
+        response.insert(cmd, OperationResult { success, failure, conflict, locked });
+    }
+
+    info!("DRY RUN SYNCED");
+    response
+}
+
+/// Predicts the outcome of `move_entry` for `file` without moving anything.
+async fn predict_move(state: &AppState, user: &AuthUser, file: FileEntry) -> Result<FileEntry, OperationError> {
+    let failure = |error: String| OperationError::Failure(FileFailure::new(file.file_path.clone(), error));
+    let Some(from) = file.from_path.clone() else {
+        return Err(invalid_move(&file.file_path, "from_path is required for a move"));
+    };
+    if file.file_path == from || file.file_path.starts_with(&format!("{}/", from)) {
+        return Err(invalid_move(&file.file_path, "Cannot move a path onto itself or into its own folder"));
+    }
+
+    match find_foreign_lock(state.pool.executor(), user.user_id, &from, user.device_id.as_deref()).await {
+        Ok(Some(lock)) => {
+            return Err(OperationError::Locked(FileLocked::new(file.file_path, lock)));
+        }
+        Ok(None) => {}
+        Err(e) => return Err(failure(describe_error(&e))),
+    }
+
+    let source = load_row(state, user.user_id, &from).await.map_err(|e| failure(describe_error(&e)))?;
+    let Some(row) = source else {
+        return Err(move_failure(&file.file_path, &AppError::NotFound("No file or folder at this path".to_string())));
+    };
+    let destination = load_row(state, user.user_id, &file.file_path).await.map_err(|e| failure(describe_error(&e)))?;
+    if destination.is_some() {
+        return Err(move_failure(&file.file_path, &AppError::Conflict("A file already exists at the destination".to_string())));
+    }
+    Ok(FileEntry {
+        file_path: file.file_path,
+        from_path: Some(from),
+        tags: file.tags,
+        metadata: file.metadata,
+        ..row
+    })
+}
+
+/// The user's file at `file_path`, with its storage key as `file_name`.
+async fn load_row(state: &AppState, user_id: i32, file_path: &str) -> Result<Option<FileEntry>, sqlx::Error> {
+    on_db!(&state.pool, pool => sqlx::query_as::<_, FileEntry>(
+        r#"
+        SELECT file_path, file_hash, file_size, modified_time, system_path AS file_name, content_type, etag, created_at, updated_at, version
+        FROM filehash
+        WHERE user_id = $1 AND file_path = $2
+        "#
+    )
+    .bind(user_id)
+    .bind(file_path)
+    .fetch_optional(pool)
+    .await)
+}
+
+/// Whether the server row has moved on from the base the client's update was made against.
+pub(crate) fn conflicts_with_base(file: &FileEntry, row: &FileEntry) -> bool {
+    file.base_modified_time.is_some_and(|t| t != row.modified_time)
+        || file.base_hash.as_ref().is_some_and(|h| row.file_hash.as_ref() != Some(h))
+        || file.base_version.is_some_and(|v| row.version != Some(v))
+}
+
+/// Returns every file path that is listed under more than one operation, sorted.
+/// A move lists both the path it moves from and the one it moves to.
+fn find_conflicting_paths(payload: &FileSyncPayload) -> Vec<String> {
+    let mut seen: HashMap<&str, Operation> = HashMap::new();
+    let mut conflicts = Vec::new();
+
+    for (cmd, files) in payload {
+        let paths = files
+            .iter()
+            .flat_map(|file| std::iter::once(&file.file_path).chain(file.from_path.as_ref().filter(|_| *cmd == Operation::Move)));
+        for path in paths {
+            match seen.get(path.as_str()) {
+                Some(prev) if prev != cmd => conflicts.push(path.clone()),
+                Some(_) => {}
+                None => {
+                    seen.insert(path, *cmd);
+                }
+            }
+        }
+    }
+
+    conflicts.sort();
+    conflicts.dedup();
+    conflicts
+}
+
+/// Takes every insert whose path an earlier insert in `files` already takes out of
+/// `files`, returning them as failures so only the first of them is applied.
+fn take_repeated_inserts(cmd: Operation, files: &mut Vec<FileEntry>) -> Vec<FileFailure> {
+    if cmd != Operation::Insert {
+        return Vec::new();
+    }
+
+    let mut seen = HashSet::new();
+    let (first, repeated) = files.drain(..).partition(|file| seen.insert(file.file_path.clone()));
+    *files = first;
+    repeated
+        .into_iter()
+        .map(|file: FileEntry| FileFailure::new(file.file_path, DUPLICATE_PATH_MESSAGE.to_string()))
+        .collect()
+}
+
+/// Returns every inserted or updated file path that has no `file_hash`, sorted.
+/// Stored rows always carry one, so these could never be written.
+fn find_unhashed_paths(payload: &FileSyncPayload) -> Vec<String> {
+    let mut unhashed: Vec<String> = [Operation::Insert, Operation::Update]
+        .iter()
+        .filter_map(|cmd| payload.get(cmd))
+        .flatten()
+        .filter(|file| file.file_hash.is_none())
+        .map(|file| file.file_path.clone())
+        .collect();
+
+    unhashed.sort();
+    unhashed
+}
+
+pub(crate) async fn process_sync(
+    state: &AppState,
+    user: &AuthUser,
+    mut payload: FileSyncPayload,
+    stored: &HashMap<String, StoredObject>,
+    failed_uploads: &HashMap<String, String>,
+    options: SyncOptions,
+    job_id: Option<i32>,
+) -> SyncResponse {
+    if options.atomic {
+        let total = payload.values().map(Vec::len).sum::<usize>() as i32;
+        let response = process_sync_atomic(state, user, payload, stored, failed_uploads, options.on_conflict).await;
+        report_progress(&state.pool, job_id, total).await;
+        publish_sync_event(state, user, &response);
+        queue_sync_tasks(state, user, &response);
+        return response;
+    }
+
+    info!("SYNCING");
+
+    let mut response: SyncResponse = HashMap::new();
+    let mut processed = 0;
+
+    for cmd in OPERATION_ORDER {
+        let Some(mut files) = payload.remove(&cmd) else { continue };
+        let mut success = Vec::new();
+        let mut failure = take_repeated_inserts(cmd, &mut files);
+        let mut conflict = Vec::new();
+        let mut locked = Vec::new();
+
+        let mut record = |result| match result {
+            Ok(entry) => success.push(entry),
+            Err(OperationError::Failure(fail)) => failure.push(fail),
+            Err(OperationError::Conflict(c)) => conflict.push(c),
+            Err(OperationError::Locked(l)) => locked.push(l),
+        };
+
+        if cmd != Operation::Delete {
+            let results = match cmd {
+                Operation::Insert => insert_files(state, user, stored, failed_uploads, options.on_conflict, files).await,
+                Operation::Move => move_entries(state, user, files).await,
+                _ => update_files(state, user, stored, failed_uploads, options.on_conflict, files).await,
+            };
+            processed += results.len() as i32;
+            results.into_iter().for_each(&mut record);
+            report_progress(&state.pool, job_id, processed).await;
+        } else {
+            // Deletes move objects in storage one by one, so they run concurrently;
+            // operations themselves still run one after another.
+            let mut results = futures::StreamExt::buffer_unordered(
+                futures::stream::iter(files)
+                    .map(|file| apply_operation(state, user, stored, failed_uploads, options.on_conflict, cmd, file)),
+                state.sync_concurrency,
+            );
+
+            while let Some(result) = results.next().await {
+                record(result);
+                processed += 1;
+                report_progress(&state.pool, job_id, processed).await;
+            }
+        }
+
+        response.insert(cmd, OperationResult { success, failure, conflict, locked });
+    }
+
+    annotate_written(state, user.user_id, &mut response).await;
+    publish_sync_event(state, user, &response);
+    queue_sync_tasks(state, user, &response);
+    info!("SYNCED");
+    response
+}
+
+/// Saves the tags and metadata carried by the files a sync inserted or updated. A
+/// file whose annotations couldn't be saved is reported without them.
+async fn annotate_written(state: &AppState, user_id: i32, response: &mut SyncResponse) {
+    let written = response
+        .iter_mut()
+        .filter(|(cmd, _)| **cmd != Operation::Delete)
+        .flat_map(|(_, result)| &mut result.success);
+    futures::StreamExt::for_each_concurrent(futures::stream::iter(written), state.sync_concurrency, |file| async move {
+        if let Err(e) = annotate_file(state.pool.executor(), user_id, &file.file_path, file).await {
+            warn!("Failed to save tags and metadata of {}: {}", file.file_path, e);
+            file.tags = None;
+            file.metadata = None;
+        }
+    })
+    .await;
+}
+
+/// Queues thumbnails of the images a sync inserted or updated, and scans of all
+/// the files it did.
+fn queue_sync_tasks(state: &AppState, user: &AuthUser, response: &SyncResponse) {
+    let written = || {
+        [Operation::Insert, Operation::Update]
+            .iter()
+            .filter_map(|cmd| response.get(cmd))
+            .flat_map(|result| &result.success)
+    };
+    queue_thumbnails(state, written());
+    queue_scans(state, user.user_id, written());
+}
+
+/// Runs the whole payload in one transaction: either every operation commits, or
+/// none does and every file is reported as failed. Storage is put back to match on
+/// rollback, and objects of deleted files are only removed after the commit.
+async fn process_sync_atomic(
+    state: &AppState,
+    user: &AuthUser,
+    mut payload: FileSyncPayload,
+    stored: &HashMap<String, StoredObject>,
+    failed_uploads: &HashMap<String, String>,
+    on_conflict: OnConflict,
+) -> SyncResponse {
+    info!("SYNCING ATOMICALLY");
+
+    let batches: Vec<(Operation, Vec<FileEntry>)> = OPERATION_ORDER
+        .iter()
+        .filter_map(|cmd| payload.remove(cmd).map(|files| (*cmd, files)))
+        .collect();
+
+    let mut applied: Vec<(Operation, FileEntry)> = Vec::new();
+    let mut removed: Vec<(String, RemovedFile)> = Vec::new();
+    let mut replaced: Vec<FileEntry> = Vec::new();
+    let mut rejected: Option<(Operation, OperationError)> = None;
+
+    match state.pool.begin().await {
+        Ok(mut tx) => {
+            'apply: for (cmd, files) in &batches {
+                for file in files {
+                    let result = match upload_problem(stored, failed_uploads, *cmd, file) {
+                        Some(error) => Err(FileFailure::new(file.file_path.clone(), error).into()),
+                        None => match cmd {
+                            Operation::Insert => insert_file(tx.as_conn(), user.user_id, stored, on_conflict, file.clone())
+                                .await
+                                .map(|(entry, old)| {
+                                    replaced.extend(old);
+                                    entry
+                                })
+                                .map_err(OperationError::from),
+                            Operation::Update => update_file(tx.as_conn(), user, stored, file.clone()).await.map(|(entry, old)| {
+                                replaced.extend(old);
+                                entry
+                            }),
+                            Operation::Move => move_entry(tx.as_conn(), user, file).await,
+                            Operation::Delete => {
+                                match remove_file_row(state, tx.as_conn(), user.user_id, &file.file_path, file.base_version).await {
+                                    Ok(removal) => {
+                                        removed.push((file.file_path.clone(), removal));
+                                        Ok(file.clone())
+                                    }
+                                    Err(error) => Err(removal_error(tx.as_conn(), user.user_id, file, error).await),
+                                }
+                            }
+                        },
+                    };
+
+                    let result = match result {
+                        Ok(entry) if *cmd != Operation::Delete => annotate_file(tx.as_conn(), user.user_id, &entry.file_path, &entry)
+                            .await
+                            .map(|()| entry)
+                            .map_err(|e| FileFailure::from_db(file.file_path.clone(), &e).into()),
+                        result => result,
+                    };
+                    match result {
+                        Ok(entry) => applied.push((*cmd, entry)),
+                        Err(e) => {
+                            rejected = Some((*cmd, e));
+                            break 'apply;
+                        }
+                    }
+                }
+            }
+
+            if rejected.is_none()
+                && let Err(e) = tx.commit().await
+            {
+                rejected = Some((Operation::Insert, FileFailure::from_db(String::new(), &e).into()));
+            }
+        }
+        Err(e) => {
+            rejected = Some((Operation::Insert, FileFailure::from_db(String::new(), &e).into()));
+        }
+    }
+
+    let mut response: SyncResponse = HashMap::new();
+
+    let Some((failed_cmd, error)) = rejected else {
+        for (cmd, _) in &batches {
+            response.insert(*cmd, OperationResult::default());
+        }
+        for (file_path, removal) in removed {
+            finish_removal(state, user.user_id, &file_path, removal).await;
+        }
+        prune_replaced(state, user.user_id, replaced).await;
+        for (cmd, entry) in applied {
+            response
+                .entry(cmd)
+                .or_default()
+                .success
+                .push(entry);
+        }
+        info!("SYNCED ATOMICALLY");
+        return response;
+    };
+
+    // The transaction is gone, so put storage back the way the database now describes it.
+    for (cmd, files) in &batches {
+        if matches!(cmd, Operation::Delete | Operation::Move) {
+            continue;
+        }
+        for file in files {
+            let key = storage_key(file);
+            if stored.contains_key(key) {
+                discard_upload(state, key).await;
+            }
+        }
+    }
+    for (_, removal) in &removed {
+        if let Some(trash_key) = &removal.trash_key {
+            delete_or_retry(state, trash_key).await;
+        }
+    }
+
+    let (cause, failed_path) = match &error {
+        OperationError::Failure(f) => (f.error.clone(), f.file_path.clone()),
+        OperationError::Conflict(c) => (c.error.clone(), c.file_path.clone()),
+        OperationError::Locked(l) => (l.error.clone(), l.file_path.clone()),
+    };
+    warn!("ATOMIC SYNC ROLLED BACK: {}", cause);
+
+    let mut error = Some(error);
+    for (cmd, files) in batches {
+        let result = response.entry(cmd).or_default();
+        for file in files {
+            if cmd == failed_cmd && file.file_path == failed_path {
+                match error.take() {
+                    Some(OperationError::Failure(f)) => result.failure.push(f),
+                    Some(OperationError::Conflict(c)) => result.conflict.push(c),
+                    Some(OperationError::Locked(l)) => result.locked.push(l),
+                    None => {}
+                }
+                continue;
+            }
+            result.failure.push(FileFailure::new(file.file_path, format!("{}: {}", ROLLED_BACK_MESSAGE, cause)));
+        }
+    }
+    response
+}
+
+async fn apply_operation(
+    state: &AppState,
+    user: &AuthUser,
+    stored: &HashMap<String, StoredObject>,
+    failed_uploads: &HashMap<String, String>,
+    on_conflict: OnConflict,
+    cmd: Operation,
+    file: FileEntry,
+) -> Result<FileEntry, OperationError> {
+    let user_id = user.user_id;
+    if let Some(error) = upload_problem(stored, failed_uploads, cmd, &file) {
+        let key = storage_key(&file);
+        if stored.contains_key(key) {
+            discard_upload(state, key).await;
+        }
+        return Err(FileFailure::new(file.file_path, error).into());
+    }
+
+    if cmd == Operation::Delete {
+        return delete_entry(state, user_id, file).await;
+    }
+
+    let policy = RetryPolicy::database(&state.config.retry);
+    let write = || async {
+        let db_failure = |e: sqlx::Error| OperationError::from(FileFailure::from_db(file.file_path.clone(), &e));
+        let mut tx = state.pool.begin().await.map_err(db_failure)?;
+        let written = match cmd {
+            Operation::Insert => insert_file(tx.as_conn(), user_id, stored, on_conflict, file.clone()).await?,
+            _ => update_file(tx.as_conn(), user, stored, file.clone()).await?,
+        };
+        tx.commit().await.map_err(db_failure)?;
+        Ok(written)
+    };
+    let transient = |e: &OperationError| {
+        matches!(e, OperationError::Failure(failure) if failure.error.starts_with(DB_UNAVAILABLE_ERROR))
+    };
+    let timed_out = || {
+        let error = format!("{}: no response within {}s", DB_UNAVAILABLE_ERROR, policy.timeout().as_secs());
+        FileFailure::new(file.file_path.clone(), error).into()
+    };
+    let (result, attempts) = policy.run("sync write", write, transient, timed_out).await;
+    match result {
+        Ok((entry, replaced)) => {
+            prune_replaced(state, user_id, replaced).await;
+            Ok(entry)
+        }
+        Err(e) => {
+            let key = storage_key(&file);
+            if stored.contains_key(key) {
+                discard_upload(state, key).await;
+            }
+            Err(match e {
+                OperationError::Failure(failure) => {
+                    FileFailure { error: after_attempts(failure.error, attempts), ..failure }.into()
+                }
+                refused => refused,
+            })
+        }
+    }
+}
+
+/// Applies a `Delete`, only if the row is still at the client's `base_version` when
+/// one is given. Otherwise the server copy is left alone and the conflict returned.
+async fn delete_entry(state: &AppState, user_id: i32, file: FileEntry) -> Result<FileEntry, OperationError> {
+    let mut conn = match state.pool.acquire().await {
+        Ok(conn) => conn,
+        Err(e) => return Err(FileFailure::from_db(file.file_path, &e).into()),
+    };
+    match remove_file_row(state, conn.as_conn(), user_id, &file.file_path, file.base_version).await {
+        Ok(removed) => {
+            drop(conn);
+            finish_removal(state, user_id, &file.file_path, removed).await;
+            Ok(file)
+        }
+        Err(error) => Err(removal_error(conn.as_conn(), user_id, &file, error).await),
+    }
+}
+
+/// Why the `Delete` of `file` failed with `error`: a conflict when the row is there
+/// after all, at another version than the client's base.
+async fn removal_error(conn: DbConn<'_>, user_id: i32, file: &FileEntry, error: String) -> OperationError {
+    if error == FILE_NOT_FOUND_MESSAGE
+        && let Ok(Some(server)) = find_update_conflict(conn, user_id, file).await
+    {
+        return OperationError::Conflict(FileConflict::new(file, server));
+    }
+    FileFailure::new(file.file_path.clone(), error).into()
+}
+
+/// Why the upload behind an insert or update can't be used, if it can't: the upload
+/// policy refuses it or its path collides, it never reached storage, or its bytes
+/// don't match the payload's SHA-256 `file_hash`. Moves can be refused too.
+fn upload_problem(
+    stored: &HashMap<String, StoredObject>,
+    failed_uploads: &HashMap<String, String>,
+    cmd: Operation,
+    file: &FileEntry,
+) -> Option<String> {
+    if let Some(refusal) = &file.refusal {
+        return Some(refusal.clone());
+    }
+    if matches!(cmd, Operation::Delete | Operation::Move) {
+        return None;
+    }
+
+    let key = storage_key(file);
+    if let Some(error) = failed_uploads.get(key) {
+        return Some(error.clone());
+    }
+
+    let object = stored.get(key)?;
+    if object.size != file.file_size {
+        warn!("Size mismatch for {}: payload {}, received {}", file.file_path, file.file_size, object.size);
+        return Some(format!(
+            "{}: payload says {} bytes, received {}",
+            SIZE_MISMATCH_MESSAGE, file.file_size, object.size
+        ));
+    }
+    let claimed = file.file_hash.as_deref().filter(|h| is_sha256_hex(h))?;
+    if claimed.eq_ignore_ascii_case(&object.sha256) {
+        return None;
+    }
+    warn!("Hash mismatch for {}: payload {}, received {}", file.file_path, claimed, object.sha256);
+    Some(format!("{}: payload says {}, received bytes hash to {}", INTEGRITY_FAILURE_MESSAGE, claimed, object.sha256))
+}
+
+/// Whether `hash` looks like a hex SHA-256, the only kind of `file_hash` the server checks.
+pub(crate) fn is_sha256_hex(hash: &str) -> bool {
+    hash.len() == 64 && hash.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// Deletes the object of a rejected upload. Every upload has a key of its own, so
+/// no file's content is stored under it.
+async fn discard_upload(state: &AppState, key: &str) {
+    delete_or_retry(state, key).await;
+}
+
+/// Of the rows `current` held before a bulk write, those the write's `rows` gave
+/// new content, each recorded over `conn` as a version of its file.
+async fn replaced_rows(
+    mut conn: DbConn<'_>,
+    user_id: i32,
+    rows: &[FileEntry],
+    mut current: HashMap<String, FileEntry>,
+) -> Result<Vec<FileEntry>, sqlx::Error> {
+    let mut replaced = Vec::new();
+    for row in rows {
+        if let Some(old) = current.remove(&row.file_path).filter(|old| old.file_name != row.file_name) {
+            record_version(conn.as_conn(), user_id, &old).await?;
+            replaced.push(old);
+        }
+    }
+    Ok(replaced)
+}
+
+/// Prunes the versions of the files whose committed writes recorded a new one.
+async fn prune_replaced(state: &AppState, user_id: i32, replaced: impl IntoIterator<Item = FileEntry>) {
+    for row in replaced {
+        prune_versions(state, user_id, &row.file_path).await;
+    }
+}
+
+/// Inserts a whole `Insert` operation with one `UNNEST` statement. Files whose
+/// content is unchanged are skipped first; if the bulk statement fails as a whole,
+/// for instance because two files share a storage key, each file is retried on its
+/// own so only the offending ones fail.
+async fn insert_files(
+    state: &AppState,
+    user: &AuthUser,
+    stored: &HashMap<String, StoredObject>,
+    failed_uploads: &HashMap<String, String>,
+    on_conflict: OnConflict,
+    files: Vec<FileEntry>,
+) -> Vec<Result<FileEntry, OperationError>> {
+    let user_id = user.user_id;
+    let mut results = Vec::with_capacity(files.len());
+    let mut pending = Vec::with_capacity(files.len());
+
+    for file in files {
+        match upload_problem(stored, failed_uploads, Operation::Insert, &file) {
+            Some(error) => {
+                let key = storage_key(&file);
+                if stored.contains_key(key) {
+                    discard_upload(state, key).await;
+                }
+                results.push(Err(FileFailure::new(file.file_path, error).into()));
+            }
+            None => pending.push(file),
+        }
+    }
+
+    let mut unchanged = find_unchanged_files(&state.pool, user_id, &pending).await.unwrap_or_else(|e| {
+        warn!("Existence check failed: {}", e);
+        HashMap::new()
+    });
+
+    let mut inserting = Vec::with_capacity(pending.len());
+    for file in pending {
+        match unchanged.remove(&file.file_path) {
+            Some(existing) => results.push(Ok(FileEntry {
+                skipped: true,
+                tags: file.tags,
+                metadata: file.metadata,
+                ..existing
+            })),
+            None => inserting.push(file),
+        }
+    }
+    if inserting.is_empty() {
+        return results;
+    }
+
+    let objects: Vec<Option<&StoredObject>> = inserting.iter().map(|f| stored.get(storage_key(f))).collect();
+    let content_types: Vec<String> = inserting
+        .iter()
+        .zip(&objects)
+        .map(|(f, o)| o.map(|o| o.content_type.clone()).unwrap_or_else(|| resolve_content_type(None, &f.file_name)))
+        .collect();
+    let etags: Vec<Option<String>> = objects.iter().map(|o| o.and_then(|o| o.etag.clone())).collect();
+
+    // SQLite reads the columns out of JSON arrays, the `WHERE` keeping its parser
+    // from taking `ON CONFLICT` for part of a join.
+    let query = match on_conflict {
+        OnConflict::Fail => state.pool.sql(
+            r#"
+            INSERT INTO filehash (file_path, file_hash, file_size, modified_time, system_path, content_type, etag, file_name, user_id, version)
+            SELECT *, $9, (SELECT COALESCE(MAX(p.version), 0) + 1 FROM path_versions p WHERE p.user_id = $9 AND p.file_path = u.path)
+            FROM UNNEST($1::TEXT[], $2::TEXT[], $3::BIGINT[], $4::BIGINT[], $5::TEXT[], $6::TEXT[], $7::TEXT[], $8::TEXT[])
+                AS u(path, hash, size, modified_time, system_path, content_type, etag, file_name)
+            ON CONFLICT (user_id, file_path) DO NOTHING
+            RETURNING file_path, file_hash, file_size, modified_time, system_path AS file_name, content_type, etag, created_at, updated_at, version
+            "#,
+            r#"
+            INSERT INTO filehash (file_path, file_hash, file_size, modified_time, system_path, content_type, etag, file_name, user_id, version)
+            SELECT value, $2 ->> key, $3 ->> key, $4 ->> key, $5 ->> key, $6 ->> key, $7 ->> key, $8 ->> key, $9,
+                   (SELECT COALESCE(MAX(p.version), 0) + 1 FROM path_versions p WHERE p.user_id = $9 AND p.file_path = value)
+            FROM json_each($1) WHERE true
+            ON CONFLICT (user_id, file_path) DO NOTHING
+            RETURNING file_path, file_hash, file_size, modified_time, system_path AS file_name, content_type, etag, created_at, updated_at, version
+            "#,
+        ),
+        OnConflict::Update => state.pool.sql(
+            r#"
+            INSERT INTO filehash (file_path, file_hash, file_size, modified_time, system_path, content_type, etag, file_name, user_id, version)
+            SELECT *, $9, (SELECT COALESCE(MAX(p.version), 0) + 1 FROM path_versions p WHERE p.user_id = $9 AND p.file_path = u.path)
+            FROM UNNEST($1::TEXT[], $2::TEXT[], $3::BIGINT[], $4::BIGINT[], $5::TEXT[], $6::TEXT[], $7::TEXT[], $8::TEXT[])
+                AS u(path, hash, size, modified_time, system_path, content_type, etag, file_name)
+            ON CONFLICT (user_id, file_path) DO UPDATE
+            SET file_hash = EXCLUDED.file_hash,
+                file_size = EXCLUDED.file_size,
+                modified_time = EXCLUDED.modified_time,
+                system_path = EXCLUDED.system_path,
+                content_type = EXCLUDED.content_type,
+                etag = EXCLUDED.etag,
+                updated_at = CURRENT_TIMESTAMP,
+                version = filehash.version + 1
+            RETURNING file_path, file_hash, file_size, modified_time, system_path AS file_name, content_type, etag, created_at, updated_at, version
+            "#,
+            r#"
+            INSERT INTO filehash (file_path, file_hash, file_size, modified_time, system_path, content_type, etag, file_name, user_id, version)
+            SELECT value, $2 ->> key, $3 ->> key, $4 ->> key, $5 ->> key, $6 ->> key, $7 ->> key, $8 ->> key, $9,
+                   (SELECT COALESCE(MAX(p.version), 0) + 1 FROM path_versions p WHERE p.user_id = $9 AND p.file_path = value)
+            FROM json_each($1) WHERE true
+            ON CONFLICT (user_id, file_path) DO UPDATE
+            SET file_hash = EXCLUDED.file_hash,
+                file_size = EXCLUDED.file_size,
+                modified_time = EXCLUDED.modified_time,
+                system_path = EXCLUDED.system_path,
+                content_type = EXCLUDED.content_type,
+                etag = EXCLUDED.etag,
+                updated_at = CURRENT_TIMESTAMP,
+                version = filehash.version + 1
+            RETURNING file_path, file_hash, file_size, modified_time, system_path AS file_name, content_type, etag, created_at, updated_at, version
+            "#,
+        ),
+    };
+    let file_paths: Vec<String> = inserting.iter().map(|f| f.file_path.clone()).collect();
+    let file_hashes: Vec<Option<String>> = inserting.iter().map(|f| f.file_hash.clone()).collect();
+    let file_sizes: Vec<i64> = inserting.iter().map(|f| f.file_size).collect();
+    let modified_times: Vec<i64> = inserting.iter().map(|f| f.modified_time).collect();
+    let file_names: Vec<String> = inserting.iter().map(|f| f.file_name.clone()).collect();
+    let inserted = async {
+        let mut tx = state.pool.begin().await?;
+        let current = match on_conflict {
+            OnConflict::Fail => HashMap::new(),
+            OnConflict::Update => lock_rows(tx.as_conn(), user_id, &file_paths).await?,
+        };
+        // A file given no new content keeps its object.
+        let system_paths: Vec<String> = inserting
+            .iter()
+            .zip(&objects)
+            .map(|(f, o)| match (o, current.get(&f.file_path)) {
+                (None, Some(row)) => row.file_name.clone(),
+                _ => storage_key(f).to_string(),
+            })
+            .collect();
+        let rows = on_db!(tx.as_conn(), conn => sqlx::query_as::<_, FileEntry>(query)
+            .bind(Array(&file_paths))
+            .bind(Array(&file_hashes))
+            .bind(Array(&file_sizes))
+            .bind(Array(&modified_times))
+            .bind(Array(&system_paths))
+            .bind(Array(&content_types))
+            .bind(Array(&etags))
+            .bind(Array(&file_names))
+            .bind(user_id)
+            .fetch_all(conn)
+            .await)?;
+        let replaced = replaced_rows(tx.as_conn(), user_id, &rows, current).await?;
+        tx.commit().await?;
+        Ok::<_, sqlx::Error>((rows, replaced))
+    }
+    .await;
+
+    match inserted {
+        Ok((rows, replaced)) => {
+            let mut rows: HashMap<String, FileEntry> = rows
+                .into_iter()
+                .map(|row| (row.file_path.clone(), row))
+                .collect();
+            let cleared: Vec<String> = rows.keys().cloned().collect();
+            let sql = state.pool.sql(
+                "DELETE FROM tombstones WHERE user_id = $1 AND file_path = ANY($2)",
+                "DELETE FROM tombstones WHERE user_id = $1 AND file_path IN (SELECT value FROM json_each($2))",
+            );
+            if let Err(e) = on_db!(&state.pool, pool => sqlx::query(sql)
+                .bind(user_id)
+                .bind(Array(&cleared))
+                .execute(pool)
+                .await
+                .map(|_| ()))
+            {
+                warn!("Failed to clear tombstones: {}", e);
+            }
+
+            for file in inserting {
+                results.push(match rows.remove(&file.file_path) {
+                    Some(row) => Ok(FileEntry {
+                        conflict_copy_of: file.conflict_copy_of,
+                        tags: file.tags,
+                        metadata: file.metadata,
+                        ..row
+                    }),
+                    None => {
+                        let key = storage_key(&file);
+                        if stored.contains_key(key) {
+                            discard_upload(state, key).await;
+                        }
+                        Err(FileFailure::new(file.file_path, INSERT_CONFLICT_MESSAGE.into()).into())
+                    }
+                });
+            }
+            prune_replaced(state, user_id, replaced).await;
+        }
+        Err(e) => {
+            warn!("Bulk insert failed, inserting one by one: {}", e);
+            let retried = futures::StreamExt::buffer_unordered(
+                futures::stream::iter(inserting)
+                    .map(|file| apply_operation(state, user, stored, failed_uploads, on_conflict, Operation::Insert, file)),
+                state.sync_concurrency,
+            );
+            results.extend(retried.collect::<Vec<_>>().await);
+        }
+    }
+    results
+}
+
+/// Applies a whole `Update` operation with one statement, as `insert_files` does
+/// inserts. Files it leaves alone, because another device holds a lock, the client's
+/// base is stale or there is no such file, are run again on their own to find out
+/// which; if the statement fails as a whole, every file is.
+async fn update_files(
+    state: &AppState,
+    user: &AuthUser,
+    stored: &HashMap<String, StoredObject>,
+    failed_uploads: &HashMap<String, String>,
+    on_conflict: OnConflict,
+    files: Vec<FileEntry>,
+) -> Vec<Result<FileEntry, OperationError>> {
+    let user_id = user.user_id;
+    let mut results = Vec::with_capacity(files.len());
+    let mut pending = Vec::with_capacity(files.len());
+
+    for file in files {
+        match upload_problem(stored, failed_uploads, Operation::Update, &file) {
+            Some(error) => {
+                let key = storage_key(&file);
+                if stored.contains_key(key) {
+                    discard_upload(state, key).await;
+                }
+                results.push(Err(FileFailure::new(file.file_path, error).into()));
+            }
+            None => pending.push(file),
+        }
+    }
+    if pending.is_empty() {
+        return results;
+    }
+
+    let objects: Vec<Option<&StoredObject>> = pending.iter().map(|f| stored.get(storage_key(f))).collect();
+    let file_paths: Vec<String> = pending.iter().map(|f| f.file_path.clone()).collect();
+    let file_hashes: Vec<Option<String>> = pending.iter().map(|f| f.file_hash.clone()).collect();
+    let file_sizes: Vec<i64> = pending.iter().map(|f| f.file_size).collect();
+    let modified_times: Vec<i64> = pending.iter().map(|f| f.modified_time).collect();
+    let content_types: Vec<Option<String>> = objects.iter().map(|o| o.map(|o| o.content_type.clone())).collect();
+    let etags: Vec<Option<String>> = objects.iter().map(|o| o.and_then(|o| o.etag.clone())).collect();
+    let base_modified_times: Vec<Option<i64>> = pending.iter().map(|f| f.base_modified_time).collect();
+    let base_hashes: Vec<Option<String>> = pending.iter().map(|f| f.base_hash.clone()).collect();
+    let base_versions: Vec<Option<i64>> = pending.iter().map(|f| f.base_version).collect();
+    // Files given no new content keep their objects.
+    let storage_keys: Vec<Option<&str>> = pending.iter().zip(&objects).map(|(f, o)| o.map(|_| storage_key(f))).collect();
+
+    // The same conditions as `update_file`'s, checked per row against the batch.
+    let sql = state.pool.sql(
+        r#"
+        UPDATE filehash AS f
+        SET file_hash = u.hash,
+            file_size = u.size,
+            modified_time = u.mtime,
+            system_path = COALESCE(u.new_key, f.system_path),
+            content_type = COALESCE(u.ctype, f.content_type),
+            etag = COALESCE(u.tag, f.etag),
+            updated_at = CURRENT_TIMESTAMP,
+            version = f.version + 1
+        FROM UNNEST($1::TEXT[], $2::TEXT[], $3::BIGINT[], $4::BIGINT[], $5::TEXT[], $6::TEXT[], $7::BIGINT[], $8::TEXT[], $11::BIGINT[], $12::TEXT[])
+            AS u(path, hash, size, mtime, ctype, tag, base_mtime, base_hash, base_version, new_key)
+        WHERE f.user_id = $9 AND f.file_path = u.path
+          AND (u.base_mtime IS NULL OR f.modified_time = u.base_mtime)
+          AND (u.base_hash IS NULL OR f.file_hash = u.base_hash)
+          AND (u.base_version IS NULL OR f.version = u.base_version)
+          AND NOT EXISTS (
+              SELECT 1 FROM file_locks l
+              WHERE l.user_id = $9 AND l.file_path = u.path AND l.expires_at > CURRENT_TIMESTAMP
+                AND COALESCE(l.device_id <> $10, TRUE)
+          )
+        RETURNING f.file_path, f.file_hash, f.file_size, f.modified_time, f.system_path AS file_name, f.content_type, f.etag, f.created_at, f.updated_at, f.version
+        "#,
+        r#"
+        UPDATE filehash AS f
+        SET file_hash = u.hash,
+            file_size = u.size,
+            modified_time = u.mtime,
+            system_path = COALESCE(u.new_key, f.system_path),
+            content_type = COALESCE(u.ctype, f.content_type),
+            etag = COALESCE(u.tag, f.etag),
+            updated_at = CURRENT_TIMESTAMP,
+            version = f.version + 1
+        FROM (
+            SELECT value AS path, $2 ->> key AS hash, $3 ->> key AS size, $4 ->> key AS mtime, $5 ->> key AS ctype,
+                   $6 ->> key AS tag, $7 ->> key AS base_mtime, $8 ->> key AS base_hash, $11 ->> key AS base_version,
+                   $12 ->> key AS new_key
+            FROM json_each($1)
+        ) AS u
+        WHERE f.user_id = $9 AND f.file_path = u.path
+          AND (u.base_mtime IS NULL OR f.modified_time = u.base_mtime)
+          AND (u.base_hash IS NULL OR f.file_hash = u.base_hash)
+          AND (u.base_version IS NULL OR f.version = u.base_version)
+          AND NOT EXISTS (
+              SELECT 1 FROM file_locks l
+              WHERE l.user_id = $9 AND l.file_path = u.path AND l.expires_at > CURRENT_TIMESTAMP
+                AND COALESCE(l.device_id <> $10, TRUE)
+          )
+        RETURNING file_path, file_hash, file_size, modified_time, system_path AS file_name, content_type, etag, created_at, updated_at, version
+        "#,
+    );
+    let updated = async {
+        let mut tx = state.pool.begin().await?;
+        let current = lock_rows(tx.as_conn(), user_id, &file_paths).await?;
+        let rows = on_db!(tx.as_conn(), conn => sqlx::query_as::<_, FileEntry>(sql)
+            .bind(Array(&file_paths))
+            .bind(Array(&file_hashes))
+            .bind(Array(&file_sizes))
+            .bind(Array(&modified_times))
+            .bind(Array(&content_types))
+            .bind(Array(&etags))
+            .bind(Array(&base_modified_times))
+            .bind(Array(&base_hashes))
+            .bind(user_id)
+            .bind(user.device_id.as_deref())
+            .bind(Array(&base_versions))
+            .bind(Array(&storage_keys))
+            .fetch_all(conn)
+            .await)?;
+        let replaced = replaced_rows(tx.as_conn(), user_id, &rows, current).await?;
+        tx.commit().await?;
+        Ok::<_, sqlx::Error>((rows, replaced))
+    }
+    .await;
+
+    let left = match updated {
+        Ok((rows, replaced)) => {
+            let mut rows: HashMap<String, FileEntry> = rows
+                .into_iter()
+                .map(|row| (row.file_path.clone(), row))
+                .collect();
+            let mut left = Vec::new();
+            for file in pending {
+                match rows.remove(&file.file_path) {
+                    Some(row) => results.push(Ok(FileEntry { tags: file.tags, metadata: file.metadata, ..row })),
+                    None => left.push(file),
+                }
+            }
+            prune_replaced(state, user_id, replaced).await;
+            left
+        }
+        Err(e) => {
+            warn!("Bulk update failed, updating one by one: {}", e);
+            pending
+        }
+    };
+    let retried = futures::StreamExt::buffer_unordered(
+        futures::stream::iter(left)
+            .map(|file| apply_operation(state, user, stored, failed_uploads, on_conflict, Operation::Update, file)),
+        state.sync_concurrency,
+    );
+    results.extend(retried.collect::<Vec<_>>().await);
+    results
+}
+
+/// Inserts `file` over `conn`, which must be a transaction for `on_conflict=update`.
+/// Returns the row, and the row it replaced when an upload gave the file new content.
+async fn insert_file(
+    mut conn: DbConn<'_>,
+    user_id: i32,
+    stored: &HashMap<String, StoredObject>,
+    on_conflict: OnConflict,
+    file: FileEntry,
+) -> Result<(FileEntry, Option<FileEntry>), FileFailure> {
+    match find_unchanged(conn.as_conn(), user_id, &file).await {
+        Ok(Some(existing)) => {
+            return Ok((FileEntry { skipped: true, tags: file.tags, metadata: file.metadata, ..existing }, None))
+        }
+        Ok(None) => {}
+        Err(e) => warn!("Existence check failed for {}: {}", file.file_path, e),
+    }
+
+    let mut current = match on_conflict {
+        OnConflict::Fail => HashMap::new(),
+        OnConflict::Update => lock_rows(conn.as_conn(), user_id, std::slice::from_ref(&file.file_path))
+            .await
+            .map_err(|e| FileFailure::from_db(file.file_path.clone(), &e))?,
+    };
+    let object = stored.get(storage_key(&file));
+    // A file given no new content keeps its object.
+    let filename = match (object, current.get(&file.file_path)) {
+        (None, Some(row)) => row.file_name.clone(),
+        _ => storage_key(&file).to_string(),
+    };
+    let content_type = object
+        .map(|o| o.content_type.clone())
+        .unwrap_or_else(|| resolve_content_type(None, &file.file_name));
+    let etag = object.and_then(|o| o.etag.clone());
+    let query = match on_conflict {
+        OnConflict::Fail => r#"
+        INSERT INTO filehash (file_path, file_hash, file_size, modified_time, system_path, content_type, etag, user_id, file_name, version)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9,
+                (SELECT COALESCE(MAX(version), 0) + 1 FROM path_versions WHERE user_id = $8 AND file_path = $1))
+        RETURNING file_path, file_hash, file_size, modified_time, system_path AS file_name, content_type, etag, created_at, updated_at, version
+        "#,
+        OnConflict::Update => r#"
+        INSERT INTO filehash (file_path, file_hash, file_size, modified_time, system_path, content_type, etag, user_id, file_name, version)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9,
+                (SELECT COALESCE(MAX(version), 0) + 1 FROM path_versions WHERE user_id = $8 AND file_path = $1))
+        ON CONFLICT (user_id, file_path) DO UPDATE
+        SET file_hash = EXCLUDED.file_hash,
+            file_size = EXCLUDED.file_size,
+            modified_time = EXCLUDED.modified_time,
+            system_path = EXCLUDED.system_path,
+            content_type = EXCLUDED.content_type,
+            etag = EXCLUDED.etag,
+            updated_at = CURRENT_TIMESTAMP,
+            version = filehash.version + 1
+        RETURNING file_path, file_hash, file_size, modified_time, system_path AS file_name, content_type, etag, created_at, updated_at, version
+        "#,
+    };
+    let data = on_db!(conn.as_conn(), conn => sqlx::query_as::<_, FileEntry>(query)
+    .bind(file.file_path.clone())
+    .bind(file.file_hash)
+    .bind(file.file_size)
+    .bind(file.modified_time)
+    .bind(filename)
+    .bind(content_type)
+    .bind(etag)
+    .bind(user_id)
+    .bind(&file.file_name)
+    .fetch_one(conn)
+    .await);
+
+    match data {
+        Ok(res) => {
+            clear_tombstone(conn.as_conn(), user_id, &res.file_path).await;
+            let replaced = current.remove(&res.file_path).filter(|row| row.file_name != res.file_name);
+            if let Some(old) = &replaced {
+                record_version(conn.as_conn(), user_id, old)
+                    .await
+                    .map_err(|e| FileFailure::from_db(res.file_path.clone(), &e))?;
+            }
+            let entry = FileEntry { conflict_copy_of: file.conflict_copy_of, tags: file.tags, metadata: file.metadata, ..res };
+            Ok((entry, replaced))
+        }
+        Err(sqlx::Error::Database(err)) if err.is_unique_violation() => Err(
+            FileFailure::new(file.file_path, INSERT_CONFLICT_MESSAGE.into())
+        ),
+        Err(err) => Err(
+            FileFailure::from_db(file.file_path, &err)
+        ),
+    }
+}
+
+/// Applies an update over `conn`, which must be a transaction, only if no other
+/// device holds a lock on the file and the row still matches the client's base when
+/// one is given. Otherwise the server copy is left alone, and the lock or the
+/// conflict is returned. Returns the row, and the row it replaced when an upload
+/// gave the file new content.
+async fn update_file(
+    mut conn: DbConn<'_>,
+    user: &AuthUser,
+    stored: &HashMap<String, StoredObject>,
+    file: FileEntry,
+) -> Result<(FileEntry, Option<FileEntry>), OperationError> {
+    let user_id = user.user_id;
+    let device_id = user.device_id.as_deref();
+    let failure = |error: String| OperationError::Failure(FileFailure::new(file.file_path.clone(), error));
+    let mut current = lock_rows(conn.as_conn(), user_id, std::slice::from_ref(&file.file_path))
+        .await
+        .map_err(|e| failure(describe_error(&e)))?;
+    let object = stored.get(storage_key(&file));
+    let data = on_db!(conn.as_conn(), conn => sqlx::query_as::<_, FileEntry>(
+        r#"
+        UPDATE filehash
+        SET file_hash = $1,
+            file_size = $2,
+            modified_time = $3,
+            system_path = COALESCE($12, system_path),
+            content_type = COALESCE($5, content_type),
+            etag = COALESCE($6, etag),
+            updated_at = CURRENT_TIMESTAMP,
+            version = version + 1
+        WHERE file_path = $4 AND user_id = $7
+          AND (CAST($8 AS BIGINT) IS NULL OR modified_time = $8)
+          AND (CAST($9 AS TEXT) IS NULL OR file_hash = $9)
+          AND (CAST($11 AS BIGINT) IS NULL OR version = $11)
+          AND NOT EXISTS (
+              SELECT 1 FROM file_locks l
+              WHERE l.user_id = $7 AND l.file_path = $4 AND l.expires_at > CURRENT_TIMESTAMP
+                AND COALESCE(l.device_id <> $10, TRUE)
+          )
+        RETURNING file_path, file_hash, file_size, modified_time, system_path AS file_name, content_type, etag, created_at, updated_at, version
+        "#,
+    )
+    .bind(&file.file_hash)
+    .bind(file.file_size)
+    .bind(file.modified_time)
+    .bind(file.file_path.clone())
+    .bind(object.map(|o| o.content_type.clone()))
+    .bind(object.and_then(|o| o.etag.clone()))
+    .bind(user_id)
+    .bind(file.base_modified_time)
+    .bind(&file.base_hash)
+    .bind(device_id)
+    .bind(file.base_version)
+    .bind(object.map(|_| storage_key(&file)))
+    .fetch_optional(conn)
+    .await);
+
+    match data {
+        Ok(Some(row)) => {
+            let replaced = current.remove(&row.file_path).filter(|old| old.file_name != row.file_name);
+            if let Some(old) = &replaced {
+                record_version(conn.as_conn(), user_id, old).await.map_err(|e| failure(describe_error(&e)))?;
+            }
+            Ok((FileEntry { tags: file.tags, metadata: file.metadata, ..row }, replaced))
+        }
+        Ok(None) => {
+            match find_foreign_lock(conn.as_conn(), user_id, &file.file_path, device_id).await {
+                Ok(Some(lock)) => {
+                    return Err(OperationError::Locked(FileLocked::new(file.file_path, lock)));
+                }
+                Ok(None) => {}
+                Err(e) => return Err(failure(describe_error(&e))),
+            }
+            match find_update_conflict(conn.as_conn(), user_id, &file).await {
+                Ok(Some(server)) => Err(OperationError::Conflict(FileConflict::new(&file, server))),
+                Ok(None) => Err(failure(FILE_NOT_FOUND_MESSAGE.to_string())),
+                Err(e) => Err(failure(describe_error(&e))),
+            }
+        }
+        Err(e) => Err(failure(describe_error(&e))),
+    }
+}
+
+/// The rows at `paths`, read in the transaction about to overwrite them: Postgres
+/// locks them until it ends, and SQLite ones hold the write lock from the start.
+/// Each row's name is its storage key.
+async fn lock_rows(conn: DbConn<'_>, user_id: i32, paths: &[String]) -> Result<HashMap<String, FileEntry>, sqlx::Error> {
+    let sql = conn.sql(
+        r#"
+        SELECT file_path, file_hash, file_size, modified_time, system_path AS file_name, content_type, etag, created_at, updated_at, version
+        FROM filehash
+        WHERE user_id = $1 AND file_path = ANY($2)
+        FOR UPDATE
+        "#,
+        r#"
+        SELECT file_path, file_hash, file_size, modified_time, system_path AS file_name, content_type, etag, created_at, updated_at, version
+        FROM filehash
+        WHERE user_id = $1 AND file_path IN (SELECT value FROM json_each($2))
+        "#,
+    );
+    let rows = on_db!(conn, conn => sqlx::query_as::<_, FileEntry>(sql)
+        .bind(user_id)
+        .bind(Array(paths))
+        .fetch_all(conn)
+        .await)?;
+    Ok(rows.into_iter().map(|row| (row.file_path.clone(), row)).collect())
+}
+
+/// Applies a `Move` operation one file at a time, in the order listed, so a file
+/// can take the path another has just left. Each move is its own transaction.
+async fn move_entries(state: &AppState, user: &AuthUser, files: Vec<FileEntry>) -> Vec<Result<FileEntry, OperationError>> {
+    let mut results = Vec::with_capacity(files.len());
+    for file in files {
+        let result = async {
+            let failure = |e: sqlx::Error| FileFailure::from_db(file.file_path.clone(), &e);
+            let mut tx = state.pool.begin().await.map_err(failure)?;
+            let entry = move_entry(tx.as_conn(), user, &file).await?;
+            tx.commit().await.map_err(failure)?;
+            Ok(entry)
+        }
+        .await;
+        results.push(result);
+    }
+    results
+}
+
+/// Gives the file at `from_path` the entry's `file_path` over `conn`. Only the row
+/// changes; the object keeps its storage key. Another device's lock on the file
+/// refuses the move, as it would an update.
+async fn move_entry(mut conn: DbConn<'_>, user: &AuthUser, file: &FileEntry) -> Result<FileEntry, OperationError> {
+    let failure = |error: String| OperationError::Failure(FileFailure::new(file.file_path.clone(), error));
+    let Some(from) = file.from_path.as_deref() else {
+        return Err(invalid_move(&file.file_path, "from_path is required for a move"));
+    };
+    if let Some(refusal) = &file.refusal {
+        return Err(failure(refusal.clone()));
+    }
+
+    match find_foreign_lock(conn.as_conn(), user.user_id, from, user.device_id.as_deref()).await {
+        Ok(Some(lock)) => {
+            return Err(OperationError::Locked(FileLocked::new(file.file_path.clone(), lock)));
+        }
+        Ok(None) => {}
+        Err(e) => return Err(failure(describe_error(&e))),
+    }
+
+    // Only a file is moved; the caller rolls back a move that took a bare folder.
+    let rows = move_rows(conn, user.user_id, from, &file.file_path)
+        .await
+        .map_err(|e| move_failure(&file.file_path, &e))?;
+    match rows.into_iter().find(|row| row.file_path == file.file_path) {
+        Some(row) => Ok(FileEntry {
+            from_path: Some(from.to_string()),
+            tags: file.tags.clone(),
+            metadata: file.metadata.clone(),
+            ..row
+        }),
+        None => Err(failure(FILE_NOT_FOUND_MESSAGE.to_string())),
+    }
+}
+
+fn invalid_move(file_path: &str, error: &str) -> OperationError {
+    move_failure(file_path, &AppError::BadRequest(error.to_string()))
+}
+
+/// A move's failure, coded by the kind of error the move hit.
+fn move_failure(file_path: &str, error: &AppError) -> OperationError {
+    let code = match error {
+        AppError::NotFound(_) => FailureCode::NotFound,
+        AppError::Conflict(_) => FailureCode::AlreadyExists,
+        AppError::BadRequest(_) => FailureCode::InvalidRequest,
+        _ => FailureCode::of(error.message()),
+    };
+    OperationError::Failure(FileFailure { file_path: file_path.to_string(), code, error: error.message().to_string() })
+}
+
+async fn run_sync_job(
+    state: AppState,
+    user: AuthUser,
+    job_id: i32,
+    payload: FileSyncPayload,
+    stored: HashMap<String, StoredObject>,
+    failed_uploads: HashMap<String, String>,
+    options: SyncOptions,
+) {
+    let _active = state.metrics.sync_started();
+    mark_job_running(&state.pool, job_id).await;
+
+    let response = process_sync(
+        &state,
+        &user,
+        payload,
+        &stored,
+        &failed_uploads,
+        options,
+        Some(job_id),
+    )
+    .await;
+
+    let report = SyncReport::new(response, false);
+    finish_job(&state.pool, job_id, serde_json::to_value(&report).map_err(|e| e.to_string())).await;
+}
+
+/// Stores one file sent outside `/sync`, such as by a WebDAV `PUT`, replacing any
+/// file at its path the way an `Insert` with `on_conflict=update` would. The size
+/// and hash are those of the bytes received; `file_size` is only the declared size
+/// the quota is checked against. Fails with the response to send back.
+pub(crate) async fn put_file(
+    state: &AppState,
+    user: &AuthUser,
+    file: FileEntry,
+    content_type: &str,
+    body: Body,
+) -> Result<FileEntry, Response> {
+    let options = SyncOptions { on_conflict: OnConflict::Update, atomic: false };
+    let mut payload: FileSyncPayload = HashMap::from([(Operation::Insert, vec![file])]);
+    assign_storage_keys(state, user.user_id, &mut payload);
+    let growth = payload_growth(state, user.user_id, &payload)
+        .await
+        .map_err(|e| AppError::from(e).into_response())?;
+    if let Some(response) = reject_over_quota(state, user.user_id, growth).await {
+        return Err(response);
+    }
+
+    let file = &mut payload.get_mut(&Operation::Insert).expect("payload holds the file")[0];
+    let key = storage_key(file).to_string();
+    let max_size = state.settings.current().max_upload_bytes;
+    let mut hasher = Sha256::new();
+    let mut received: u64 = 0;
+    let mut data = body.into_data_stream();
+    let first = data.next().await;
+    let head = first.as_ref().and_then(|chunk| chunk.as_ref().ok()).map(|b| &b[..]).unwrap_or_default();
+    let content_type = &detect_content_type(Some(content_type), &file.file_name, head);
+    let mut chunks = futures::stream::iter(first).chain(data).map(|chunk| {
+        let bytes = chunk.map_err(|e| e.to_string())?;
+        received += bytes.len() as u64;
+        if let Some(max) = max_size && received > max {
+            return Err(format!("File exceeds the {} byte upload limit", max));
+        }
+        hasher.update(&bytes);
+        Ok(bytes)
+    });
+    let result = state.storage.put_stream(&key, content_type, &mut chunks).await;
+    drop(chunks);
+
+    // A failed upload is left for `process_sync` to report. It went to a key of
+    // its own, so the file's current content is untouched either way.
+    let mut stored = HashMap::new();
+    let mut failed_uploads = HashMap::new();
+    match result {
+        Ok(etag) => {
+            let sha256 = hex::encode(hasher.finalize());
+            file.file_size = received as i64;
+            file.file_hash = Some(sha256.clone());
+            stored.insert(key, StoredObject {
+                content_type: content_type.to_string(),
+                etag,
+                sha256,
+                size: received as i64,
+            });
+        }
+        Err(e) => {
+            warn!("Upload of {} failed: {}", key, e);
+            failed_uploads.insert(key, e.to_string());
+        }
+    }
+    let too_large = max_size.is_some_and(|max| received > max);
+
+    let mut response = process_sync(state, user, payload, &stored, &failed_uploads, options, None).await;
+    let mut result = response.remove(&Operation::Insert).unwrap_or_default();
+    if let Some(entry) = result.success.pop() {
+        return Ok(entry);
+    }
+    let error = result.failure.pop().map(|f| f.error).unwrap_or_default();
+    Err(if too_large {
+        AppError::PayloadTooLarge(error)
+    } else {
+        AppError::BadGateway(format!("Failed to store file: {}", error))
+    }
+    .into_response())
+}
+
+/// How many bytes `payload` would add to the user's usage once applied.
+async fn payload_growth(state: &AppState, user_id: i32, payload: &FileSyncPayload) -> Result<i64, sqlx::Error> {
+    let incoming: i64 = [Operation::Insert, Operation::Update]
+        .iter()
+        .filter_map(|cmd| payload.get(cmd))
+        .flatten()
+        .map(|file| file.file_size)
+        .sum();
+    // Moves keep their bytes, and can't land on a file.
+    let touched: Vec<String> = payload
+        .iter()
+        .filter(|(cmd, _)| **cmd != Operation::Move)
+        .flat_map(|(_, files)| files)
+        .map(|file| file.file_path.clone())
+        .collect();
+    Ok(incoming - stored_bytes(&state.pool, user_id, &touched).await?)
+}
@@ -0,0 +1,236 @@
+use std::{collections::HashMap, io::Cursor, sync::Arc};
+
+use axum::{
+    extract::{Extension, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+use image::{codecs::jpeg::JpegEncoder, DynamicImage, ImageFormat, ImageReader};
+use sha2::{Digest, Sha256};
+use tracing::{info, warn, Instrument};
+
+use crate::{
+    db::on_db,
+    error::AppError,
+    handlers::{downloads::if_none_match, photos::record_media_metadata},
+    models::{AuthUser, FileEntry},
+    AppState, DEFAULT_THUMBNAIL_SIZE, MAX_THUMBNAIL_SOURCE_BYTES, THUMBNAIL_PREFIX, THUMBNAIL_SIZES,
+};
+
+const THUMBNAIL_QUALITY: u8 = 80;
+
+/// Why a thumbnail couldn't be produced.
+enum ThumbnailError {
+    /// The file isn't an image this server can decode, or is too large to.
+    Unsupported(String),
+    Storage(String),
+}
+
+impl From<ThumbnailError> for AppError {
+    fn from(err: ThumbnailError) -> Self {
+        match err {
+            ThumbnailError::Unsupported(e) => AppError::NotFound(format!("No thumbnail available: {}", e)),
+            ThumbnailError::Storage(e) => AppError::BadGateway(format!("Failed to generate thumbnail: {}", e)),
+        }
+    }
+}
+
+/// The storage key of the `size` thumbnail of the object at `system_path`.
+fn thumbnail_key(system_path: &str, size: u32) -> String {
+    format!("{}/{}/{}.jpg", THUMBNAIL_PREFIX, system_path, size)
+}
+
+/// The object a thumbnail key was generated from, if `key` is one.
+pub(crate) fn thumbnail_source(key: &str) -> Option<&str> {
+    let rest = key.strip_prefix(THUMBNAIL_PREFIX)?.strip_prefix('/')?;
+    rest.rsplit_once('/').map(|(source, _)| source)
+}
+
+/// Whether files of `content_type` are images thumbnails can be made from.
+pub(crate) fn is_thumbnailable(content_type: Option<&str>) -> bool {
+    content_type
+        .and_then(ImageFormat::from_mime_type)
+        .is_some_and(|format| format.reading_enabled())
+}
+
+/// Generates thumbnails for the image files among `files` from one background job,
+/// one file after another, so a large upload doesn't saturate the CPU, and records
+/// what each image's EXIF data says for the photo timeline.
+pub(crate) fn queue_thumbnails<'a>(state: &AppState, files: impl IntoIterator<Item = &'a FileEntry>) {
+    let keys: Vec<String> = files
+        .into_iter()
+        .filter(|file| is_thumbnailable(file.content_type.as_deref()))
+        .map(|file| file.file_name.clone())
+        .collect();
+    if keys.is_empty() {
+        return;
+    }
+
+    let state = state.clone();
+    state.tasks.clone().spawn(async move {
+        for key in keys {
+            let outcome = match read_source(&state, &key).await {
+                Ok(data) => {
+                    let data = Arc::new(data);
+                    record_media_metadata(&state, &key, data.clone()).await;
+                    store_thumbnails(&state, &key, data).await
+                }
+                Err(e) => Err(e),
+            };
+            match outcome {
+                Ok(()) => {}
+                Err(ThumbnailError::Unsupported(e)) => info!("Skipped thumbnails for {}: {}", key, e),
+                Err(ThumbnailError::Storage(e)) => warn!("Failed to generate thumbnails for {}: {}", key, e),
+            }
+        }
+    }.in_current_span());
+}
+
+/// Renders and stores every `THUMBNAIL_SIZES` thumbnail of the object at `key`,
+/// replacing those of any earlier content.
+async fn generate_thumbnails(state: &AppState, key: &str) -> Result<(), ThumbnailError> {
+    let data = read_source(state, key).await?;
+    store_thumbnails(state, key, Arc::new(data)).await
+}
+
+/// The image at `key`, unless it's too large to make thumbnails of.
+async fn read_source(state: &AppState, key: &str) -> Result<Vec<u8>, ThumbnailError> {
+    let size = state.storage.size(key).await.map_err(|e| ThumbnailError::Storage(e.to_string()))?;
+    if size > MAX_THUMBNAIL_SOURCE_BYTES {
+        return Err(ThumbnailError::Unsupported(format!("image is larger than {} bytes", MAX_THUMBNAIL_SOURCE_BYTES)));
+    }
+    state.storage.get(key).await.map_err(|e| ThumbnailError::Storage(e.to_string()))
+}
+
+async fn store_thumbnails(state: &AppState, key: &str, data: Arc<Vec<u8>>) -> Result<(), ThumbnailError> {
+    let thumbnails = tokio::task::spawn_blocking(move || render(&data))
+        .await
+        .map_err(|e| ThumbnailError::Storage(e.to_string()))??;
+
+    for (size, bytes) in thumbnails {
+        state
+            .storage
+            .put(&thumbnail_key(key, size), bytes, "image/jpeg")
+            .await
+            .map_err(|e| ThumbnailError::Storage(e.to_string()))?;
+    }
+    Ok(())
+}
+
+/// Decodes an image and encodes it as a JPEG fitted into each of `THUMBNAIL_SIZES`.
+/// Images already smaller than a size are kept at their own dimensions.
+fn render(data: &[u8]) -> Result<Vec<(u32, Vec<u8>)>, ThumbnailError> {
+    let image = ImageReader::new(Cursor::new(data))
+        .with_guessed_format()
+        .map_err(|e| ThumbnailError::Unsupported(e.to_string()))?
+        .decode()
+        .map_err(|e| ThumbnailError::Unsupported(e.to_string()))?;
+
+    THUMBNAIL_SIZES
+        .iter()
+        .map(|&size| {
+            let fitted = if image.width() <= size && image.height() <= size {
+                image.clone()
+            } else {
+                image.thumbnail(size, size)
+            };
+            let mut bytes = Vec::new();
+            JpegEncoder::new_with_quality(&mut bytes, THUMBNAIL_QUALITY)
+                .encode_image(&DynamicImage::ImageRgb8(fitted.to_rgb8()))
+                .map_err(|e| ThumbnailError::Unsupported(e.to_string()))?;
+            Ok((size, bytes))
+        })
+        .collect()
+}
+
+/// Serves a JPEG thumbnail of an image file, fitted into `size` pixels (256 unless
+/// given), so galleries don't need the full-size files. Thumbnails are generated in
+/// the background after each upload; one that isn't there yet is generated now.
+#[utoipa::path(
+    get, path = "/thumbnail", tag = "files",
+    params(
+        ("path" = String, Query),
+        ("size" = Option<u32>, Query, description = "128, 256 or 512"),
+        ("If-None-Match" = Option<String>, Header),
+    ),
+    responses(
+        (status = 200, description = "JPEG thumbnail", content_type = "image/jpeg"),
+        (status = 304, description = "Unchanged since the given ETag"),
+        (status = 400, description = "Invalid request", body = crate::openapi::ErrorBody),
+        (status = 404, description = "No such file, or not an image", body = crate::openapi::ErrorBody),
+    )
+)]
+pub(crate) async fn handle_thumbnail(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Query(params): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let path = params
+        .get("path")
+        .ok_or_else(|| AppError::BadRequest("Missing path".into()))?;
+    let size = thumbnail_size(params.get("size").map(String::as_str))?;
+
+    let (system_path, content_type) = on_db!(&state.pool, pool => sqlx::query_as::<_, (String, Option<String>)>(
+        "SELECT system_path, content_type FROM filehash WHERE user_id = $1 AND file_path = $2"
+    )
+    .bind(user.user_id)
+    .bind(path)
+    .fetch_optional(pool)
+    .await)?
+    .ok_or_else(|| AppError::NotFound("File not found".into()))?;
+
+    serve_thumbnail(&state, &system_path, content_type.as_deref(), size, &headers).await
+}
+
+/// The size a `size` parameter asks for, or `DEFAULT_THUMBNAIL_SIZE` without one.
+pub(crate) fn thumbnail_size(param: Option<&str>) -> Result<u32, AppError> {
+    match param {
+        Some(size) => size.parse().ok().filter(|s| THUMBNAIL_SIZES.contains(s)).ok_or_else(|| {
+            AppError::BadRequest(format!("size must be one of {:?}", THUMBNAIL_SIZES))
+        }),
+        None => Ok(DEFAULT_THUMBNAIL_SIZE),
+    }
+}
+
+/// Serves the `size` thumbnail of the object at `system_path`, generating it when
+/// it isn't there yet.
+pub(crate) async fn serve_thumbnail(
+    state: &AppState,
+    system_path: &str,
+    content_type: Option<&str>,
+    size: u32,
+    headers: &HeaderMap,
+) -> Result<Response, AppError> {
+    if !is_thumbnailable(content_type) {
+        return Err(AppError::NotFound("Thumbnails are only generated for images".into()));
+    }
+
+    let key = thumbnail_key(system_path, size);
+    let bytes = match state.storage.get(&key).await {
+        Ok(bytes) => bytes,
+        Err(_) => {
+            generate_thumbnails(state, system_path).await?;
+            state
+                .storage
+                .get(&key)
+                .await
+                .map_err(|e| AppError::BadGateway(format!("Failed to read thumbnail: {}", e)))?
+        }
+    };
+
+    // Tagged by content, since thumbnails are regenerated under the same key.
+    let tag = format!("\"{}\"", hex::encode(Sha256::digest(&bytes)));
+    if if_none_match(headers, &tag) {
+        return Ok((StatusCode::NOT_MODIFIED, [(header::ETAG, tag)]).into_response());
+    }
+    Ok((
+        StatusCode::OK,
+        [
+            (header::CONTENT_TYPE, "image/jpeg".to_string()),
+            (header::CACHE_CONTROL, "private, no-cache".to_string()),
+            (header::ETAG, tag),
+        ],
+        bytes,
+    ).into_response())
+}
@@ -0,0 +1,261 @@
+use std::time::Duration;
+
+use axum::{
+    extract::{Extension, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use tracing::{info, warn};
+
+use crate::{
+    db::{clear_tombstone, on_db, Db},
+    error::AppError,
+    events::publish_restores,
+    handlers::{jobs::delete_or_retry, listing::tags_sql, maintenance::in_maintenance, versions::copy_object},
+    models::{AuthUser, FileChange, FileEntry, Operation, TrashEntry, TrashRestoreRequest},
+    AppState,
+};
+
+/// Lists the user's trashed files, most recently deleted first.
+#[utoipa::path(
+    get, path = "/trash", tag = "files",
+    responses((status = 200, description = "The caller's trash", body = crate::openapi::Data<Vec<TrashEntry>>))
+)]
+pub(crate) async fn handle_list_trash(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+) -> Result<Response, AppError> {
+    let sql = state.pool.sql(
+        r#"
+        SELECT id, file_path, file_hash, file_size, modified_time, content_type, deleted_at,
+               deleted_at + make_interval(days => $2) AS expires_at
+        FROM trash
+        WHERE user_id = $1
+        ORDER BY deleted_at DESC, id DESC
+        "#,
+        r#"
+        SELECT id, file_path, file_hash, file_size, modified_time, content_type, deleted_at,
+               datetime(deleted_at, $2 || ' days') AS expires_at
+        FROM trash
+        WHERE user_id = $1
+        ORDER BY deleted_at DESC, id DESC
+        "#,
+    );
+    let rows = on_db!(&state.pool, pool => sqlx::query_as::<_, TrashEntry>(sql)
+    .bind(user.user_id)
+    .bind(state.settings.current().trash_retention_days as i32)
+    .fetch_all(pool)
+    .await)?;
+
+    Ok((StatusCode::OK, Json(serde_json::json!({ "data": rows }))).into_response())
+}
+
+/// Puts a trashed file back at its original path. Fails with 409 when another
+/// file has taken that path, or its storage key, in the meantime.
+#[utoipa::path(
+    post, path = "/trash/restore", tag = "files",
+    request_body = TrashRestoreRequest,
+    responses(
+        (status = 200, description = "The restored file", body = crate::openapi::Data<FileEntry>),
+        (status = 404, description = "No such trash entry", body = crate::openapi::ErrorBody),
+        (status = 409, description = "A file already exists at its path", body = crate::openapi::ErrorBody),
+    )
+)]
+pub(crate) async fn handle_trash_restore(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Json(req): Json<TrashRestoreRequest>,
+) -> Result<Response, AppError> {
+    let row = reinstate_file(&state, "trash", "trash_key", req.id, user.user_id)
+        .await?
+        .ok_or_else(|| AppError::NotFound("Trash entry not found".into()))?;
+
+    publish_restores(&state, &user, vec![FileChange {
+        operation: Operation::Insert,
+        file_path: row.file_path.clone(),
+    }]);
+
+    Ok((StatusCode::OK, Json(serde_json::json!({ "data": row }))).into_response())
+}
+
+/// Moves a file held in `table`, the trash or the quarantine, back into `filehash`
+/// with its tags and metadata, and its content back from the key in `key_column`.
+/// `None` when the user has no entry `id` there. Fails with 409 when another file
+/// has taken its path, or its storage key, in the meantime.
+pub(crate) async fn reinstate_file(
+    state: &AppState,
+    table: &str,
+    key_column: &str,
+    id: i32,
+    user_id: i32,
+) -> Result<Option<FileEntry>, AppError> {
+    let held = on_db!(&state.pool, pool => sqlx::query_as::<_, (String, String, String)>(&format!(
+        "SELECT file_path, system_path, {} FROM {} WHERE id = $1 AND user_id = $2",
+        key_column, table
+    ))
+    .bind(id)
+    .bind(user_id)
+    .fetch_optional(pool)
+    .await)?;
+    let Some((file_path, system_path, held_key)) = held else {
+        return Ok(None);
+    };
+
+    let taken = on_db!(&state.pool, pool => sqlx::query_scalar::<_, bool>(
+        r#"
+        SELECT EXISTS (
+            SELECT 1 FROM filehash
+            WHERE (user_id = $1 AND file_path = $2) OR system_path = $3
+        )
+        "#
+    )
+    .bind(user_id)
+    .bind(&file_path)
+    .bind(&system_path)
+    .fetch_one(pool)
+    .await)?;
+    if taken {
+        return Err(AppError::Conflict("A file already exists at this path".into()));
+    }
+
+    copy_object(state, &held_key, &system_path).await.map_err(AppError::Internal)?;
+
+    let restored = match &state.pool {
+        Db::Postgres(pool) => sqlx::query_as::<_, FileEntry>(&format!(
+            r#"
+            WITH restored AS (
+                DELETE FROM {table}
+                WHERE id = $1 AND user_id = $2
+                RETURNING user_id, file_path, file_hash, file_size, modified_time, content_type, etag, system_path, created_at, file_name,
+                          metadata, tags
+            ), inserted AS (
+                INSERT INTO filehash (user_id, file_path, file_hash, file_size, modified_time, content_type, etag, system_path, created_at, file_name, metadata,
+                                      version)
+                SELECT user_id, file_path, file_hash, file_size, modified_time, content_type, etag, system_path, created_at, file_name, metadata,
+                       (SELECT COALESCE(MAX(p.version), 0) + 1 FROM path_versions p WHERE p.user_id = restored.user_id AND p.file_path = restored.file_path)
+                FROM restored
+                RETURNING id, file_path, file_hash, file_size, modified_time, system_path AS file_name, content_type, etag, created_at, updated_at, version, metadata
+            ), tagged AS (
+                INSERT INTO file_tags (file_id, tag)
+                SELECT inserted.id, UNNEST(restored.tags) FROM inserted, restored
+            )
+            SELECT file_path, file_hash, file_size, modified_time, file_name, content_type, etag, created_at, updated_at, version, metadata,
+                   (SELECT tags FROM restored) AS tags
+            FROM inserted
+            "#
+        ))
+        .bind(id)
+        .bind(user_id)
+        .fetch_optional(pool)
+        .await,
+        // SQLite has no data-modifying CTEs: the same steps, one statement each.
+        Db::Sqlite(pool) => async {
+            let mut tx = pool.begin_with("BEGIN IMMEDIATE").await?;
+            let Some(file_id) = sqlx::query_scalar::<_, i32>(&format!(
+                r#"
+                INSERT INTO filehash (user_id, file_path, file_hash, file_size, modified_time, content_type, etag, system_path, created_at, file_name, metadata,
+                                      version)
+                SELECT user_id, file_path, file_hash, file_size, modified_time, content_type, etag, system_path, created_at, file_name, metadata,
+                       (SELECT COALESCE(MAX(p.version), 0) + 1 FROM path_versions p WHERE p.user_id = {table}.user_id AND p.file_path = {table}.file_path)
+                FROM {table}
+                WHERE id = $1 AND user_id = $2
+                RETURNING id
+                "#
+            ))
+            .bind(id)
+            .bind(user_id)
+            .fetch_optional(&mut *tx)
+            .await?
+            else {
+                return Ok(None);
+            };
+
+            sqlx::query(&format!(
+                "INSERT INTO file_tags (file_id, tag) SELECT $1, value FROM {table}, json_each({table}.tags) WHERE {table}.id = $2"
+            ))
+            .bind(file_id)
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+            sqlx::query(&format!("DELETE FROM {} WHERE id = $1", table)).bind(id).execute(&mut *tx).await?;
+            let row = sqlx::query_as::<_, FileEntry>(&format!(
+                r#"
+                SELECT file_path, file_hash, file_size, modified_time, system_path AS file_name, content_type, etag, created_at, updated_at, version,
+                       metadata, {} AS tags
+                FROM filehash
+                WHERE id = $1
+                "#,
+                tags_sql(&state.pool),
+            ))
+            .bind(file_id)
+            .fetch_one(&mut *tx)
+            .await?;
+            tx.commit().await.map(|_| Some(row))
+        }
+        .await,
+    };
+
+    let row = match restored {
+        Ok(Some(row)) => row,
+        Ok(None) => return Ok(None),
+        Err(e) => {
+            delete_or_retry(state, &system_path).await;
+            return Err(AppError::Conflict(format!("Failed to restore file: {}", e)));
+        }
+    };
+
+    delete_or_retry(state, &held_key).await;
+    clear_tombstone(state.pool.executor(), user_id, &file_path).await;
+    Ok(Some(row))
+}
+
+pub(crate) async fn purge_trash_periodically(state: AppState, every: Duration) {
+    let mut interval = tokio::time::interval(every);
+    loop {
+        interval.tick().await;
+        if in_maintenance(&state).await {
+            continue;
+        }
+        purge_trash(&state).await;
+    }
+}
+
+/// Permanently deletes trash entries older than `trash_retention_days`. Entries
+/// left from before the trash was turned off are kept until it's back on.
+pub(crate) async fn purge_trash(state: &AppState) {
+    let retention_days = state.settings.current().trash_retention_days;
+    if retention_days == 0 {
+        return;
+    }
+    let sql = state.pool.sql(
+        "SELECT id, trash_key FROM trash WHERE deleted_at < NOW() - make_interval(days => $1)",
+        "SELECT id, trash_key FROM trash WHERE deleted_at < datetime('now', -$1 || ' days')",
+    );
+    let expired = on_db!(&state.pool, pool => sqlx::query_as::<_, (i32, String)>(sql)
+    .bind(retention_days as i32)
+    .fetch_all(pool)
+    .await);
+
+    let expired = match expired {
+        Ok(rows) => rows,
+        Err(e) => {
+            warn!("Failed to list expired trash: {}", e);
+            return;
+        }
+    };
+    if expired.is_empty() {
+        return;
+    }
+
+    info!("PURGING {} TRASHED FILES", expired.len());
+    for (id, trash_key) in expired {
+        if let Err(e) = state.storage.delete(&trash_key).await {
+            warn!("Failed to delete trash object {}: {}", trash_key, e);
+            continue;
+        }
+        if let Err(e) = on_db!(&state.pool, pool => sqlx::query("DELETE FROM trash WHERE id = $1").bind(id).execute(pool).await.map(|_| ())) {
+            warn!("Failed to delete trash entry {}: {}", id, e);
+        }
+    }
+}
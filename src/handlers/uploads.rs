@@ -0,0 +1,722 @@
+use std::time::Duration;
+
+use axum::{
+    body::Bytes,
+    extract::{Extension, Path, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use tracing::{info, warn};
+
+use crate::{
+    audit,
+    db::{clear_tombstone, enqueue_retry, find_upload_session, on_db, stored_bytes, RETRY_ABORT},
+    error::AppError,
+    events::publish_changes,
+    handlers::{
+        maintenance::in_maintenance, quarantine::queue_scans, sync::is_sha256_hex, thumbnails::queue_thumbnails,
+        usage::reject_over_quota,
+    },
+    models::{
+        AuthUser, ByteRange, FileChange, FileEntry, Operation, UploadInitRequest, UploadStatus,
+        UploadUrlRequest, UploadedPart,
+    },
+    paths::{check_spelling, normalize_path},
+    storage::routing::with_target,
+    AppState, INSERT_CONFLICT_MESSAGE, MAX_UPLOAD_CHUNK_BYTES, MIN_UPLOAD_CHUNK_BYTES,
+    UPLOAD_SESSION_TTL_HOURS,
+};
+
+/// Fails with a 413 when `file_size` exceeds the configured `max_upload_bytes`.
+fn check_upload_size(state: &AppState, file_size: i64) -> Result<(), AppError> {
+    match state.settings.current().max_upload_bytes {
+        Some(max) if file_size as u64 > max => Err(AppError::PayloadTooLarge(format!(
+            "file_size exceeds the {} byte upload limit",
+            max
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// Reserves a `system_path` and returns a presigned PUT for it, so the client can
+/// upload straight to S3. The signature pins the key, length and content type.
+#[utoipa::path(
+    post, path = "/upload-url", tag = "uploads",
+    request_body = UploadUrlRequest,
+    responses(
+        (status = 200, description = "Where to upload the file", body = crate::openapi::PresignedUpload),
+        (status = 400, description = "Invalid request", body = crate::openapi::ErrorBody),
+        (status = 413, description = "The file is too large, or the quota is exceeded", body = crate::openapi::ErrorBody),
+    )
+)]
+pub(crate) async fn handle_upload_url(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Json(req): Json<UploadUrlRequest>,
+) -> Result<Response, AppError> {
+    if req.file_size < 0 {
+        return Err(AppError::BadRequest("file_size must not be negative".into()));
+    }
+    check_upload_size(&state, req.file_size)?;
+    if let Some(response) = reject_over_quota(&state, user.user_id, req.file_size).await {
+        return Ok(response);
+    }
+
+    let sha256 = req.sha256.as_deref().map(str::to_ascii_lowercase);
+    if let Some(digest) = &sha256
+        && !is_sha256_hex(digest)
+    {
+        return Err(AppError::BadRequest("sha256 must be 64 hex characters".into()));
+    }
+
+    let system_path = generate_system_path(&state, user.user_id, "", &req.file_name);
+    let content_type = resolve_content_type(req.content_type.as_deref(), &req.file_name);
+    let expiry_secs = state.settings.current().presign_expiry_secs;
+
+    let _ = on_db!(&state.pool, pool => sqlx::query("DELETE FROM upload_reservations WHERE expires_at < CURRENT_TIMESTAMP")
+        .execute(pool)
+        .await
+        .map(|_| ()));
+
+    let sql = state.pool.sql(
+        r#"
+        INSERT INTO upload_reservations (system_path, file_size, content_type, expires_at, sha256, user_id, file_name)
+        SELECT $1, $2, $3, NOW() + make_interval(secs => $4), $5, $6, $7
+        WHERE NOT EXISTS (SELECT 1 FROM filehash WHERE system_path = $1)
+        ON CONFLICT (system_path) DO NOTHING
+        "#,
+        r#"
+        INSERT INTO upload_reservations (system_path, file_size, content_type, expires_at, sha256, user_id, file_name)
+        SELECT $1, $2, $3, datetime('now', $4 || ' seconds'), $5, $6, $7
+        WHERE NOT EXISTS (SELECT 1 FROM filehash WHERE system_path = $1)
+        ON CONFLICT (system_path) DO NOTHING
+        "#,
+    );
+    let reserved = on_db!(&state.pool, pool => sqlx::query(sql)
+    .bind(&system_path)
+    .bind(req.file_size)
+    .bind(&content_type)
+    .bind(expiry_secs as f64)
+    .bind(&sha256)
+    .bind(user.user_id)
+    .bind(&req.file_name)
+    .execute(pool)
+    .await
+    .map(|r| r.rows_affected()))?;
+    if reserved == 0 {
+        return Err(AppError::Conflict("system path is already in use or reserved".into()));
+    }
+
+    let url = state.storage
+        .presign_upload(
+            &system_path,
+            req.file_size,
+            &content_type,
+            sha256.as_deref(),
+            Duration::from_secs(expiry_secs),
+        )
+        .await
+        .map_err(|e| AppError::Internal(format!("Failed to generate URL: {}", e)))?;
+
+    let mut upload_headers = serde_json::json!({
+        "content-type": content_type,
+        "content-length": req.file_size
+    });
+    for (name, value) in state.storage.upload_headers(sha256.as_deref()) {
+        upload_headers[name] = value.into();
+    }
+
+    Ok((
+        StatusCode::OK,
+        Json(serde_json::json!({
+            "url": url,
+            "method": "PUT",
+            "system_path": system_path,
+            "headers": upload_headers,
+            "expires_in_seconds": expiry_secs
+        }))
+    ).into_response())
+}
+
+/// Records the DB row for an object uploaded through `/upload-url`, after
+/// checking the reservation and that the object landed in S3 with the right size.
+/// `file_name` is the name the upload was reserved under, or the `system_path` it
+/// was given; the latest reservation wins when one name was reserved twice.
+#[utoipa::path(
+    post, path = "/upload-confirm", tag = "uploads",
+    request_body(content = FileEntry, description = "The file, with `file_name` set to the `system_path` from `/upload-url`"),
+    responses(
+        (status = 201, description = "The stored file", body = crate::openapi::Data<FileEntry>),
+        (status = 400, description = "Invalid request", body = crate::openapi::ErrorBody),
+        (status = 409, description = "The uploaded content doesn't match the declared sha256"),
+    )
+)]
+pub(crate) async fn handle_upload_confirm(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Json(file): Json<FileEntry>,
+) -> Result<Response, AppError> {
+    let (system_path, file_name, reserved_size, content_type, expected_sha256) =
+        on_db!(&state.pool, pool => sqlx::query_as::<_, (String, Option<String>, i64, String, Option<String>)>(
+        r#"
+        SELECT system_path, file_name, file_size, content_type, sha256
+        FROM upload_reservations
+        WHERE user_id = $1 AND (file_name = $2 OR system_path = $2) AND expires_at >= CURRENT_TIMESTAMP
+        ORDER BY created_at DESC, id DESC
+        LIMIT 1
+        "#
+    )
+    .bind(user.user_id)
+    .bind(&file.file_name)
+    .fetch_optional(pool)
+    .await)?
+    .ok_or_else(|| AppError::NotFound("no active upload reservation for this file".into()))?;
+
+    match state.storage.size(&system_path).await {
+        Ok(size) if size == reserved_size => {}
+        Ok(_) => return Err(AppError::Conflict("uploaded object size does not match the reservation".into())),
+        Err(e) => return Err(AppError::Conflict(format!("upload not found in storage: {}", e))),
+    }
+
+    if let Some(expected) = &expected_sha256 {
+        let actual = state
+            .storage
+            .sha256(&system_path)
+            .await
+            .map_err(|e| AppError::BadGateway(format!("Failed to verify checksum: {}", e)))?;
+        if actual.as_ref() != Some(expected) {
+            // The bytes are wrong, so drop them; the reservation stays for a retry.
+            let _ = state.storage.delete(&system_path).await;
+            return Ok((StatusCode::CONFLICT, Json(serde_json::json!({
+                "error": "uploaded object does not match the declared sha256",
+                "expected_sha256": expected,
+                "actual_sha256": actual
+            }))).into_response());
+        }
+    }
+
+    let row = on_db!(&state.pool, pool => sqlx::query_as::<_, FileEntry>(
+        r#"
+        INSERT INTO filehash (file_path, file_hash, file_size, modified_time, system_path, content_type, user_id, file_name, version)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8,
+                (SELECT COALESCE(MAX(version), 0) + 1 FROM path_versions WHERE user_id = $7 AND file_path = $1))
+        RETURNING file_path, file_hash, file_size, modified_time, system_path AS file_name, content_type, etag, created_at, updated_at, version
+        "#,
+    )
+    .bind(&file.file_path)
+    .bind(file.file_hash)
+    .bind(reserved_size)
+    .bind(file.modified_time)
+    .bind(&system_path)
+    .bind(content_type)
+    .bind(user.user_id)
+    .bind(file_name)
+    .fetch_one(pool)
+    .await)?;
+
+    clear_tombstone(state.pool.executor(), user.user_id, &row.file_path).await;
+    let _ = on_db!(&state.pool, pool => sqlx::query("DELETE FROM upload_reservations WHERE system_path = $1")
+        .bind(&system_path)
+        .execute(pool)
+        .await
+        .map(|_| ()));
+    queue_thumbnails(&state, [&row]);
+    queue_scans(&state, user.user_id, [&row]);
+    audit::record_changes(&state, &user, &[FileChange {
+        operation: Operation::Insert,
+        file_path: row.file_path.clone(),
+    }]);
+    Ok((StatusCode::CREATED, Json(serde_json::json!({ "data": row }))).into_response())
+}
+
+/// Opens a resumable upload session. The client then sends the file in chunks with
+/// `PATCH /upload/{id}` and finishes with `POST /upload/{id}/complete`; the session
+/// lives in the database, so an interrupted upload can resume after a restart. A
+/// session no chunk has reached for `UPLOAD_SESSION_TTL_HOURS` is abandoned.
+#[utoipa::path(
+    post, path = "/upload/init", tag = "uploads",
+    request_body = UploadInitRequest,
+    responses(
+        (status = 201, description = "The new upload session", body = crate::openapi::UploadSessionCreated),
+        (status = 400, description = "Invalid request", body = crate::openapi::ErrorBody),
+        (status = 409, description = "The path exists in other case", body = crate::openapi::ErrorBody),
+        (status = 413, description = "The file is too large, or the quota is exceeded", body = crate::openapi::ErrorBody),
+    )
+)]
+pub(crate) async fn handle_upload_init(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Json(mut req): Json<UploadInitRequest>,
+) -> Result<Response, AppError> {
+    if req.file_size <= 0 {
+        return Err(AppError::BadRequest("file_size must be positive".into()));
+    }
+    check_upload_size(&state, req.file_size)?;
+    req.file_path = normalize_path(&req.file_path);
+    check_spelling(&state, user.user_id, &req.file_path).await?;
+    let replaced = stored_bytes(&state.pool, user.user_id, std::slice::from_ref(&req.file_path)).await?;
+    if let Some(response) = reject_over_quota(&state, user.user_id, req.file_size - replaced).await {
+        return Ok(response);
+    }
+
+    expire_upload_sessions(&state).await;
+
+    let system_path = generate_system_path(&state, user.user_id, &req.file_path, &req.file_name);
+    let content_type = resolve_content_type(req.content_type.as_deref(), &req.file_name);
+    let storage_upload_id = state
+        .storage
+        .create_multipart(&system_path, &content_type)
+        .await
+        .map_err(|e| AppError::BadGateway(format!("Failed to start upload: {}", e)))?;
+
+    let id = hex::encode(rand::random::<[u8; 16]>());
+    let sql = state.pool.sql(
+        r#"
+        INSERT INTO upload_sessions
+            (id, expires_at, user_id, file_path, file_hash, file_size, modified_time, content_type, system_path, storage_upload_id, file_name)
+        SELECT $1, NOW() + make_interval(hours => $2), $3, $4, $5, $6, $7, $8, $9, $10, $11
+        WHERE NOT EXISTS (SELECT 1 FROM filehash WHERE system_path = $9)
+        ON CONFLICT (system_path) DO NOTHING
+        "#,
+        r#"
+        INSERT INTO upload_sessions
+            (id, expires_at, user_id, file_path, file_hash, file_size, modified_time, content_type, system_path, storage_upload_id, file_name)
+        SELECT $1, datetime('now', $2 || ' hours'), $3, $4, $5, $6, $7, $8, $9, $10, $11
+        WHERE NOT EXISTS (SELECT 1 FROM filehash WHERE system_path = $9)
+        ON CONFLICT (system_path) DO NOTHING
+        "#,
+    );
+    let created = on_db!(&state.pool, pool => sqlx::query(sql)
+    .bind(&id)
+    .bind(UPLOAD_SESSION_TTL_HOURS)
+    .bind(user.user_id)
+    .bind(&req.file_path)
+    .bind(&req.file_hash)
+    .bind(req.file_size)
+    .bind(req.modified_time)
+    .bind(&content_type)
+    .bind(&system_path)
+    .bind(&storage_upload_id)
+    .bind(&req.file_name)
+    .execute(pool)
+    .await
+    .map(|r| r.rows_affected()));
+
+    match created {
+        Ok(1) => {}
+        other => {
+            let _ = state.storage.abort_multipart(&system_path, &storage_upload_id).await;
+            return Err(match other {
+                Err(e) => e.into(),
+                Ok(_) => AppError::Conflict("system path is already in use or being uploaded".into()),
+            });
+        }
+    }
+
+    info!(upload_id = %id, %system_path, file_size = req.file_size, "UPLOAD STARTED");
+    Ok((
+        StatusCode::CREATED,
+        [("Upload-Offset", "0".to_string()), ("Upload-Length", req.file_size.to_string())],
+        Json(serde_json::json!({
+            "upload_id": id,
+            "upload_url": format!("/upload/{}", id),
+            "offset": 0,
+            "min_chunk_bytes": MIN_UPLOAD_CHUNK_BYTES,
+            "max_chunk_bytes": MAX_UPLOAD_CHUNK_BYTES
+        }))
+    ).into_response())
+}
+
+/// Reports how much of an upload the server has, so a client knows where to resume.
+#[utoipa::path(
+    head, path = "/upload/{id}", tag = "uploads",
+    summary = "Reports how much of an upload session has arrived",
+    params(("id" = String, Path)),
+    responses(
+        (status = 200, description = "`Upload-Offset` and `Upload-Length` headers"),
+        (status = 404, description = "No such upload session"),
+    )
+)]
+pub(crate) async fn handle_upload_status(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Path(id): Path<String>,
+) -> Result<Response, AppError> {
+    // A HEAD response has no body, so a missing upload is a bare 404.
+    let Some(session) = find_upload_session(&state.pool, user.user_id, &id).await? else {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    };
+    Ok((
+        StatusCode::OK,
+        [
+            ("Upload-Offset", session.upload_offset.to_string()),
+            ("Upload-Length", session.file_size.to_string()),
+            (header::CACHE_CONTROL.as_str(), "no-store".to_string()),
+        ],
+    ).into_response())
+}
+
+/// Reports where an upload session stands: what has arrived, what is still
+/// missing, and when the session is abandoned.
+#[utoipa::path(
+    get, path = "/upload/{id}/status", tag = "uploads",
+    params(("id" = String, Path)),
+    responses(
+        (status = 200, description = "The session's progress", body = crate::openapi::Data<UploadStatus>),
+        (status = 404, description = "No such upload session", body = crate::openapi::ErrorBody),
+    )
+)]
+pub(crate) async fn handle_upload_session_status(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Path(id): Path<String>,
+) -> Result<Response, AppError> {
+    let session = find_upload_session(&state.pool, user.user_id, &id)
+        .await?
+        .ok_or_else(|| AppError::NotFound("Upload not found".into()))?;
+
+    // Chunks arrive in order, so all that can be missing is the rest of the file.
+    let complete = session.upload_offset == session.file_size;
+    let missing_ranges = if complete {
+        Vec::new()
+    } else {
+        vec![ByteRange { start: session.upload_offset, end: session.file_size }]
+    };
+    let status = UploadStatus {
+        upload_id: id,
+        file_path: session.file_path,
+        file_size: session.file_size,
+        bytes_received: session.upload_offset,
+        parts: session.parts.len(),
+        missing_ranges,
+        complete,
+        expires_at: session.expires_at,
+        updated_at: session.updated_at,
+    };
+    Ok((
+        StatusCode::OK,
+        [(header::CACHE_CONTROL, "no-store")],
+        Json(serde_json::json!({ "data": status })),
+    ).into_response())
+}
+
+/// Appends one chunk at `Upload-Offset`. Each chunk becomes one multipart part,
+/// and keeps the session from expiring for another `UPLOAD_SESSION_TTL_HOURS`.
+#[utoipa::path(
+    patch, path = "/upload/{id}", tag = "uploads",
+    params(
+        ("id" = String, Path),
+        ("Upload-Offset" = i64, Header, description = "Where this chunk starts; must match the server's offset"),
+    ),
+    request_body(content = Vec<u8>, content_type = "application/octet-stream"),
+    responses(
+        (status = 204, description = "Chunk stored; `Upload-Offset` is the new offset"),
+        (status = 404, description = "No such upload session", body = crate::openapi::ErrorBody),
+        (status = 409, description = "`Upload-Offset` doesn't match the server's offset", body = crate::openapi::ErrorBody),
+    )
+)]
+pub(crate) async fn handle_upload_chunk(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Response, AppError> {
+    let offset = headers
+        .get("Upload-Offset")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<i64>().ok())
+        .ok_or_else(|| AppError::BadRequest("Missing or invalid Upload-Offset header".into()))?;
+
+    let session = find_upload_session(&state.pool, user.user_id, &id)
+        .await?
+        .ok_or_else(|| AppError::NotFound("Upload not found".into()))?;
+
+    if offset != session.upload_offset {
+        return Ok((
+            StatusCode::CONFLICT,
+            [("Upload-Offset", session.upload_offset.to_string())],
+            Json(serde_json::json!({
+                "error": "Upload-Offset does not match the server's offset"
+            })),
+        ).into_response());
+    }
+
+    let end = offset + body.len() as i64;
+    if end > session.file_size {
+        return Err(AppError::BadRequest("Chunk extends past the declared file size".into()));
+    }
+    if body.is_empty() || (body.len() < MIN_UPLOAD_CHUNK_BYTES && end < session.file_size) {
+        return Err(AppError::BadRequest(format!(
+            "Chunks must be at least {} bytes, except the last",
+            MIN_UPLOAD_CHUNK_BYTES
+        )));
+    }
+
+    let part_number = session.parts.len() as i32 + 1;
+    let etag = state.storage
+        .upload_part(&session.system_path, &session.storage_upload_id, part_number, body.to_vec())
+        .await
+        .map_err(|e| AppError::BadGateway(format!("Failed to store chunk: {}", e)))?;
+
+    // Guarded on the old offset, so a concurrent PATCH for the same range can't
+    // record its part twice.
+    let part = sqlx::types::Json(vec![UploadedPart { part_number, etag }]);
+    let sql = state.pool.sql(
+        r#"
+        UPDATE upload_sessions
+        SET upload_offset = $1,
+            parts = parts || $2,
+            updated_at = CURRENT_TIMESTAMP,
+            expires_at = NOW() + make_interval(hours => $5)
+        WHERE id = $3 AND upload_offset = $4
+        "#,
+        r#"
+        UPDATE upload_sessions
+        SET upload_offset = $1,
+            parts = json_insert(parts, '$[#]', json($2) -> 0),
+            updated_at = CURRENT_TIMESTAMP,
+            expires_at = datetime('now', $5 || ' hours')
+        WHERE id = $3 AND upload_offset = $4
+        "#,
+    );
+    let advanced = on_db!(&state.pool, pool => sqlx::query(sql)
+    .bind(end)
+    .bind(part)
+    .bind(&id)
+    .bind(offset)
+    .bind(UPLOAD_SESSION_TTL_HOURS)
+    .execute(pool)
+    .await
+    .map(|r| r.rows_affected()))?;
+
+    if advanced == 0 {
+        return Err(AppError::Conflict("Upload was advanced concurrently; check its offset and retry".into()));
+    }
+    Ok((StatusCode::NO_CONTENT, [("Upload-Offset", end.to_string())]).into_response())
+}
+
+/// Assembles a fully uploaded session into its object and records the DB row.
+#[utoipa::path(
+    post, path = "/upload/{id}/complete", tag = "uploads",
+    params(("id" = String, Path)),
+    responses(
+        (status = 201, description = "The stored file", body = crate::openapi::Data<FileEntry>),
+        (status = 404, description = "No such upload session", body = crate::openapi::ErrorBody),
+        (status = 409, description = "Not every byte has arrived", body = crate::openapi::ErrorBody),
+    )
+)]
+pub(crate) async fn handle_upload_complete(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Path(id): Path<String>,
+) -> Result<Response, AppError> {
+    let session = find_upload_session(&state.pool, user.user_id, &id)
+        .await?
+        .ok_or_else(|| AppError::NotFound("Upload not found".into()))?;
+
+    if session.upload_offset != session.file_size {
+        return Ok((
+            StatusCode::CONFLICT,
+            [("Upload-Offset", session.upload_offset.to_string())],
+            Json(serde_json::json!({
+                "error": format!(
+                    "Upload is incomplete: {} of {} bytes received",
+                    session.upload_offset, session.file_size
+                )
+            })),
+        ).into_response());
+    }
+
+    let parts: Vec<(i32, String)> = session.parts
+        .iter()
+        .map(|p| (p.part_number, p.etag.clone()))
+        .collect();
+    let etag = state.storage
+        .complete_multipart(&session.system_path, &session.storage_upload_id, &parts)
+        .await
+        .map_err(|e| AppError::BadGateway(format!("Failed to assemble upload: {}", e)))?;
+
+    let data = on_db!(&state.pool, pool => sqlx::query_as::<_, FileEntry>(
+        r#"
+        INSERT INTO filehash (file_path, file_hash, file_size, modified_time, system_path, content_type, etag, user_id, file_name, version)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9,
+                (SELECT COALESCE(MAX(version), 0) + 1 FROM path_versions WHERE user_id = $8 AND file_path = $1))
+        RETURNING file_path, file_hash, file_size, modified_time, system_path AS file_name, content_type, etag, created_at, updated_at, version
+        "#,
+    )
+    .bind(&session.file_path)
+    .bind(&session.file_hash)
+    .bind(session.file_size)
+    .bind(session.modified_time)
+    .bind(&session.system_path)
+    .bind(&session.content_type)
+    .bind(etag)
+    .bind(session.user_id)
+    .bind(&session.file_name)
+    .fetch_one(pool)
+    .await);
+
+    let row = match data {
+        Ok(row) => row,
+        Err(sqlx::Error::Database(err)) if err.is_unique_violation() => {
+            return Err(AppError::Conflict(INSERT_CONFLICT_MESSAGE.into()))
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    clear_tombstone(state.pool.executor(), user.user_id, &row.file_path).await;
+    let _ = on_db!(&state.pool, pool => sqlx::query("DELETE FROM upload_sessions WHERE id = $1")
+        .bind(&id)
+        .execute(pool)
+        .await
+        .map(|_| ()));
+    publish_changes(&state, &user, vec![FileChange {
+        operation: Operation::Insert,
+        file_path: row.file_path.clone(),
+    }]);
+    queue_thumbnails(&state, [&row]);
+    queue_scans(&state, user.user_id, [&row]);
+    info!(upload_id = %id, "UPLOAD COMPLETED");
+    Ok((StatusCode::CREATED, Json(serde_json::json!({ "data": row }))).into_response())
+}
+
+pub(crate) async fn expire_upload_sessions_periodically(state: AppState, every: Duration) {
+    let mut interval = tokio::time::interval(every);
+    loop {
+        interval.tick().await;
+        if in_maintenance(&state).await {
+            continue;
+        }
+        expire_upload_sessions(&state).await;
+        abort_orphaned_multipart(&state).await;
+    }
+}
+
+/// Drops expired upload sessions and aborts their unfinished multipart uploads,
+/// queueing a retry for any abort that fails so their parts don't linger.
+pub(crate) async fn expire_upload_sessions(state: &AppState) {
+    let expired = on_db!(&state.pool, pool => sqlx::query_as::<_, (String, String)>(
+        "DELETE FROM upload_sessions WHERE expires_at < CURRENT_TIMESTAMP RETURNING system_path, storage_upload_id"
+    )
+    .fetch_all(pool)
+    .await);
+    let expired = match expired {
+        Ok(expired) => expired,
+        Err(e) => {
+            warn!("Failed to expire upload sessions: {}", e);
+            return;
+        }
+    };
+    if !expired.is_empty() {
+        info!("EXPIRING {} UPLOAD SESSIONS", expired.len());
+    }
+
+    for (system_path, upload_id) in expired {
+        if let Err(e) = state.storage.abort_multipart(&system_path, &upload_id).await {
+            warn!("Failed to abort expired upload {}, will retry: {}", system_path, e);
+            enqueue_retry(&state.pool, RETRY_ABORT, &system_path, Some(&upload_id), &e.to_string()).await;
+        }
+    }
+}
+
+/// Aborts multipart uploads older than any upload session could be that no session
+/// tracks: those of sessions lost with their rows and of streamed uploads cut off
+/// by a crash. The first sweep after startup catches the previous run's leftovers.
+pub(crate) async fn abort_orphaned_multipart(state: &AppState) {
+    let live = on_db!(&state.pool, pool => sqlx::query_scalar::<_, String>("SELECT storage_upload_id FROM upload_sessions")
+        .fetch_all(pool)
+        .await);
+    let keep = match live {
+        Ok(live) => live.into_iter().collect(),
+        Err(e) => {
+            warn!("Failed to list upload sessions: {}", e);
+            return;
+        }
+    };
+
+    let started_before = chrono::Utc::now().timestamp() - i64::from(UPLOAD_SESSION_TTL_HOURS) * 3600;
+    match state.storage.abort_stale_multipart(started_before, &keep).await {
+        Ok(0) => {}
+        Ok(aborted) => info!("ABORTED {} ORPHANED MULTIPART UPLOADS", aborted),
+        Err(e) => warn!("Failed to sweep orphaned multipart uploads: {}", e),
+    }
+}
+
+/// Picks the content type for an upload: the multipart part's own header if it is
+/// meaningful, otherwise a guess from the file extension, otherwise octet-stream.
+pub(crate) fn resolve_content_type(declared: Option<&str>, filename: &str) -> String {
+    match declared {
+        Some(ct) if !ct.is_empty() && ct != "application/octet-stream" => ct.to_string(),
+        _ => mime_guess::from_path(filename)
+            .first_raw()
+            .unwrap_or("application/octet-stream")
+            .to_string(),
+    }
+}
+
+/// Like `resolve_content_type`, but falls back on the content's leading bytes when
+/// neither the part's header nor the extension says what it is.
+pub(crate) fn detect_content_type(declared: Option<&str>, filename: &str, head: &[u8]) -> String {
+    let resolved = resolve_content_type(declared, filename);
+    if resolved != "application/octet-stream" {
+        return resolved;
+    }
+    sniff_content_type(head).map(String::from).unwrap_or(resolved)
+}
+
+/// Recognises common formats by their magic bytes.
+fn sniff_content_type(head: &[u8]) -> Option<&'static str> {
+    const SIGNATURES: &[(&[u8], &str)] = &[
+        (b"%PDF-", "application/pdf"),
+        (b"PK\x03\x04", "application/zip"),
+        (b"\x1f\x8b", "application/gzip"),
+        (b"7z\xbc\xaf\x27\x1c", "application/x-7z-compressed"),
+        (b"ID3", "audio/mpeg"),
+        (b"OggS", "audio/ogg"),
+        (b"fLaC", "audio/flac"),
+        (b"\x1a\x45\xdf\xa3", "video/webm"),
+    ];
+    if let Ok(format) = image::guess_format(head) {
+        return Some(format.to_mime_type());
+    }
+    if let Some((_, mime)) = SIGNATURES.iter().find(|(magic, _)| head.starts_with(magic)) {
+        return Some(mime);
+    }
+    // MP4 and QuickTime files open with a box size, then `ftyp`.
+    (head.get(4..8) == Some(b"ftyp")).then_some("video/mp4")
+}
+
+/// Storage key for one of `user_id`'s files; each user gets their own prefix under
+/// `data/`, and each object a random folder in it, so two files that share a name
+/// never overwrite each other. The key names the storage target routed to for
+/// `file_path`, if any.
+pub(crate) fn generate_system_path(state: &AppState, user_id: i32, file_path: &str, filename: &str) -> String {
+    let key = format!("data/{}/{}/{}", user_id, hex::encode(rand::random::<[u8; 16]>()), filename);
+    with_target(&key, state.config.routing.target_for(user_id, file_path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::resolve_content_type;
+
+    #[test]
+    fn guesses_from_the_extension() {
+        assert_eq!(resolve_content_type(None, "photo.png"), "image/png");
+        assert_eq!(resolve_content_type(None, "scan.PDF"), "application/pdf");
+    }
+
+    #[test]
+    fn falls_back_to_octet_stream() {
+        assert_eq!(resolve_content_type(None, "notes.unknownext"), "application/octet-stream");
+        assert_eq!(resolve_content_type(None, "README"), "application/octet-stream");
+    }
+
+    #[test]
+    fn prefers_a_meaningful_declared_type() {
+        assert_eq!(resolve_content_type(Some("text/markdown"), "notes.txt"), "text/markdown");
+        assert_eq!(resolve_content_type(Some("application/octet-stream"), "photo.png"), "image/png");
+        assert_eq!(resolve_content_type(Some(""), "scan.pdf"), "application/pdf");
+    }
+}
@@ -0,0 +1,114 @@
+use axum::{
+    extract::{Extension, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+
+use crate::{
+    db::{load_usage, on_db},
+    error::AppError,
+    models::{AuthUser, FailureCode, LargestFile, StorageStats, UsageRollup},
+    AppState, STATS_TOP_N,
+};
+
+/// Returns a 413 when storing `additional` more bytes would take the user past
+/// their quota. Uploads that free space or stay put are always allowed.
+pub(crate) async fn reject_over_quota(state: &AppState, user_id: i32, additional: i64) -> Option<Response> {
+    if additional <= 0 {
+        return None;
+    }
+
+    let usage = match load_usage(state, user_id).await {
+        Ok(usage) => usage,
+        Err(e) => return Some((StatusCode::INTERNAL_SERVER_ERROR, Json(serde_json::json!({
+            "error": format!("Failed to check quota: {}", e)
+        }))).into_response()),
+    };
+    let quota = usage.quota_bytes?;
+
+    (usage.bytes + additional > quota).then(|| (StatusCode::PAYLOAD_TOO_LARGE, Json(serde_json::json!({
+        "error": "Storage quota exceeded",
+        "code": FailureCode::QuotaExceeded,
+        "quota_bytes": quota,
+        "used_bytes": usage.bytes,
+        "requested_bytes": additional
+    }))).into_response())
+}
+
+#[utoipa::path(
+    get, path = "/usage", tag = "files",
+    summary = "Reports how much the caller stores, against their quota",
+    responses((status = 200, description = "The caller's usage", body = crate::models::Usage))
+)]
+pub(crate) async fn handle_usage(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+) -> Result<Response, AppError> {
+    Ok((StatusCode::OK, Json(load_usage(&state, user.user_id).await?)).into_response())
+}
+
+/// A dashboard view of the caller's storage: usage with trash, rollups by folder
+/// and extension, and the largest files.
+#[utoipa::path(
+    get, path = "/stats", tag = "files",
+    responses((status = 200, description = "The caller's storage", body = crate::openapi::Data<StorageStats>))
+)]
+pub(crate) async fn handle_stats(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+) -> Result<Response, AppError> {
+    let usage = load_usage(&state, user.user_id).await?;
+    let (trash_bytes, trash_files) = on_db!(&state.pool, pool => sqlx::query_as::<_, (i64, i64)>(
+        "SELECT trash_bytes, trash_files FROM user_usage WHERE user_id = $1"
+    )
+    .bind(user.user_id)
+    .fetch_optional(pool)
+    .await)?
+    .unwrap_or_default();
+
+    let folders = on_db!(&state.pool, pool => sqlx::query_as::<_, UsageRollup>(
+        "SELECT folder AS name, bytes, files FROM folder_usage WHERE user_id = $1 ORDER BY bytes DESC, folder"
+    )
+    .bind(user.user_id)
+    .fetch_all(pool)
+    .await)?;
+    let extensions = on_db!(&state.pool, pool => sqlx::query_as::<_, UsageRollup>(
+        r#"
+        SELECT extension AS name, bytes, files FROM extension_usage
+        WHERE user_id = $1
+        ORDER BY bytes DESC, extension
+        LIMIT $2
+        "#
+    )
+    .bind(user.user_id)
+    .bind(STATS_TOP_N)
+    .fetch_all(pool)
+    .await)?;
+    let largest_files = on_db!(&state.pool, pool => sqlx::query_as::<_, LargestFile>(
+        r#"
+        SELECT file_path, file_size, modified_time, content_type FROM filehash
+        WHERE user_id = $1
+        ORDER BY file_size DESC
+        LIMIT $2
+        "#
+    )
+    .bind(user.user_id)
+    .bind(STATS_TOP_N)
+    .fetch_all(pool)
+    .await)?;
+
+    let stats = StorageStats {
+        total_bytes: usage.bytes + trash_bytes,
+        used_bytes: usage.bytes,
+        files: usage.files,
+        trash_bytes,
+        trash_files,
+        quota_bytes: usage.quota_bytes,
+        remaining_bytes: usage.remaining_bytes,
+        folders,
+        extensions,
+        largest_files,
+    };
+    Ok((StatusCode::OK, Json(serde_json::json!({ "data": stats }))).into_response())
+}
@@ -0,0 +1,352 @@
+//! The vault, for files their clients encrypt end to end. A client uploads the
+//! ciphertext under an id of its choosing, along with its file name and metadata
+//! encrypted the same way, and the server keeps only what it needs to store and
+//! sync them: the id, the size and a version vector. Since the server can't read
+//! paths, vault items are reached by id alone and never show up in `/get`,
+//! `/changes` or any other endpoint that works on paths.
+//!
+//! Version vectors stand in for the hashes `/sync` compares. A write must descend
+//! from the version stored, so a client that hasn't seen another device's change
+//! gets a 409 with the current item to merge with.
+
+use axum::{
+    body::Body,
+    extract::{Extension, Path, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use futures::StreamExt;
+use sqlx::types::Json as DbJson;
+use tracing::info;
+
+use crate::{
+    db::on_db,
+    error::AppError,
+    handlers::{jobs::delete_or_retry, usage::reject_over_quota},
+    models::{AuthUser, FailureCode, VaultItem, VersionVector},
+    storage::routing::with_target,
+    AppState, MAX_VAULT_ITEM_ID_LEN, VAULT_PREFIX,
+};
+
+/// Lists the caller's vault items, without their content.
+#[utoipa::path(
+    get, path = "/vault", tag = "vault",
+    responses((status = 200, description = "Every vault item of the caller", body = Vec<VaultItem>))
+)]
+pub(crate) async fn handle_list_vault(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+) -> Result<Response, AppError> {
+    let items = on_db!(&state.pool, pool => sqlx::query_as::<_, VaultItem>(
+        r#"
+        SELECT item_id, encrypted_name, encrypted_metadata, file_size, version_vector, created_at, updated_at, system_path
+        FROM vault_items
+        WHERE user_id = $1
+        ORDER BY item_id
+        "#
+    )
+    .bind(user.user_id)
+    .fetch_all(pool)
+    .await)?;
+
+    Ok((StatusCode::OK, Json(serde_json::json!({ "data": items }))).into_response())
+}
+
+/// Stores the body, already encrypted, as the vault item `item_id`, creating it
+/// or replacing its content.
+#[utoipa::path(
+    put, path = "/vault/{item_id}", tag = "vault",
+    params(
+        ("item_id" = String, Path, description = "Up to 128 letters, digits, `-` and `_`, chosen by the client"),
+        ("X-Encrypted-Name" = String, Header, description = "The item's file name, encrypted"),
+        ("X-Encrypted-Metadata" = Option<String>, Header, description = "Anything else the client keeps about the item, encrypted"),
+        ("X-Version-Vector" = String, Header, description = "The new version, as a JSON object of counters by device"),
+    ),
+    request_body(content = Vec<u8>, content_type = "application/octet-stream"),
+    responses(
+        (status = 201, description = "Created", body = VaultItem),
+        (status = 200, description = "Replaced", body = VaultItem),
+        (status = 400, description = "Invalid id or headers", body = crate::openapi::ErrorBody),
+        (status = 409, description = "The version doesn't descend from the one stored", body = crate::openapi::ErrorBody),
+        (status = 413, description = "Over the upload limit or the caller's quota", body = crate::openapi::ErrorBody),
+    )
+)]
+pub(crate) async fn handle_put_vault_item(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Path(item_id): Path<String>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<Response, AppError> {
+    check_item_id(&item_id)?;
+    let encrypted_name = header_text(&headers, "X-Encrypted-Name")
+        .ok_or_else(|| AppError::BadRequest("X-Encrypted-Name is required".into()))?;
+    let encrypted_metadata = header_text(&headers, "X-Encrypted-Metadata");
+    let version_vector = version_vector(&headers)?
+        .ok_or_else(|| AppError::BadRequest("X-Version-Vector is required".into()))?;
+
+    let existing = match find_item(&state, user.user_id, &item_id).await? {
+        Some(item) if !descends(&version_vector, &item.version_vector) => return Ok(conflict(item)),
+        existing => existing,
+    };
+
+    // The size is only used for the quota check up front; the stored row gets the
+    // number of bytes actually received.
+    let declared_size: i64 = headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(0);
+    let growth = declared_size - existing.as_ref().map_or(0, |item| item.file_size);
+    if let Some(response) = reject_over_quota(&state, user.user_id, growth).await {
+        return Ok(response);
+    }
+
+    let key = format!("{}/{}/{}", VAULT_PREFIX, user.user_id, hex::encode(rand::random::<[u8; 16]>()));
+    // Item names are encrypted, so only routes for the user's every file apply.
+    let key = with_target(&key, state.config.routing.target_for(user.user_id, ""));
+    let max_size = state.settings.current().max_upload_bytes;
+    let mut received: u64 = 0;
+    let mut chunks = body.into_data_stream().map(|chunk| {
+        let bytes = chunk.map_err(|e| e.to_string())?;
+        received += bytes.len() as u64;
+        if let Some(max) = max_size && received > max {
+            return Err(format!("File exceeds the {} byte upload limit", max));
+        }
+        Ok(bytes)
+    });
+    let result = state.storage.put_stream(&key, "application/octet-stream", &mut chunks).await;
+    drop(chunks);
+    if let Err(e) = result {
+        delete_or_retry(&state, &key).await;
+        return Err(match max_size {
+            Some(max) if received > max => AppError::PayloadTooLarge(e.to_string()),
+            _ => AppError::BadGateway(format!("Failed to store vault item: {}", e)),
+        });
+    }
+
+    // Every write moves the item to a new object, so `system_path` tells whether
+    // another write got in between.
+    let written = match &existing {
+        None => on_db!(&state.pool, pool => sqlx::query(
+            r#"
+            INSERT INTO vault_items (user_id, item_id, encrypted_name, encrypted_metadata, file_size, system_path, version_vector)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)
+            ON CONFLICT (user_id, item_id) DO NOTHING
+            "#
+        )
+        .bind(user.user_id)
+        .bind(&item_id)
+        .bind(&encrypted_name)
+        .bind(&encrypted_metadata)
+        .bind(received as i64)
+        .bind(&key)
+        .bind(DbJson(&version_vector))
+        .execute(pool)
+        .await
+        .map(|r| r.rows_affected())),
+        Some(previous) => on_db!(&state.pool, pool => sqlx::query(
+            r#"
+            UPDATE vault_items
+            SET encrypted_name = $3, encrypted_metadata = $4, file_size = $5, system_path = $6,
+                version_vector = $7, updated_at = CURRENT_TIMESTAMP
+            WHERE user_id = $1 AND item_id = $2 AND system_path = $8
+            "#
+        )
+        .bind(user.user_id)
+        .bind(&item_id)
+        .bind(&encrypted_name)
+        .bind(&encrypted_metadata)
+        .bind(received as i64)
+        .bind(&key)
+        .bind(DbJson(&version_vector))
+        .bind(&previous.system_path)
+        .execute(pool)
+        .await
+        .map(|r| r.rows_affected())),
+    };
+    let written = match written {
+        Ok(written) => written,
+        Err(e) => {
+            delete_or_retry(&state, &key).await;
+            return Err(e.into());
+        }
+    };
+    if written == 0 {
+        delete_or_retry(&state, &key).await;
+        return match find_item(&state, user.user_id, &item_id).await? {
+            Some(current) => Ok(conflict(current)),
+            None => Err(AppError::Conflict("The vault item was deleted while it was written".into())),
+        };
+    }
+
+    if let Some(previous) = &existing {
+        delete_or_retry(&state, &previous.system_path).await;
+    }
+    info!(user_id = user.user_id, "STORED VAULT ITEM {} ({} bytes)", item_id, received);
+
+    let item = find_item(&state, user.user_id, &item_id)
+        .await?
+        .ok_or_else(|| AppError::Conflict("The vault item was deleted while it was written".into()))?;
+    let status = if existing.is_some() { StatusCode::OK } else { StatusCode::CREATED };
+    Ok((status, Json(item)).into_response())
+}
+
+/// Streams the encrypted content of the vault item `item_id`.
+#[utoipa::path(
+    get, path = "/vault/{item_id}", tag = "vault",
+    params(("item_id" = String, Path)),
+    responses(
+        (status = 200, description = "The item's ciphertext, with its version in `X-Version-Vector`", content_type = "application/octet-stream"),
+        (status = 404, description = "No such item", body = crate::openapi::ErrorBody),
+    )
+)]
+pub(crate) async fn handle_get_vault_item(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Path(item_id): Path<String>,
+) -> Result<Response, AppError> {
+    let item = find_item(&state, user.user_id, &item_id)
+        .await?
+        .ok_or_else(|| AppError::NotFound("Vault item not found".into()))?;
+
+    let body = if item.file_size == 0 {
+        Body::empty()
+    } else {
+        let stream = state
+            .storage
+            .get_range(&item.system_path, 0, item.file_size as u64)
+            .await
+            .map_err(|e| AppError::BadGateway(format!("Failed to read vault item: {}", e)))?;
+        Body::from_stream(stream)
+    };
+
+    let mut response = (
+        StatusCode::OK,
+        [
+            (header::CONTENT_TYPE, "application/octet-stream".to_string()),
+            (header::CONTENT_LENGTH, item.file_size.to_string()),
+        ],
+        body,
+    ).into_response();
+    if let Ok(value) = serde_json::to_string(&item.version_vector.0).unwrap_or_default().parse() {
+        response.headers_mut().insert("X-Version-Vector", value);
+    }
+    Ok(response)
+}
+
+/// Deletes the vault item `item_id`. Given `X-Version-Vector`, only that version
+/// is deleted, so a device can't remove a change it hasn't seen.
+#[utoipa::path(
+    delete, path = "/vault/{item_id}", tag = "vault",
+    params(
+        ("item_id" = String, Path),
+        ("X-Version-Vector" = Option<String>, Header, description = "The version the client means to delete"),
+    ),
+    responses(
+        (status = 204, description = "Deleted"),
+        (status = 404, description = "No such item", body = crate::openapi::ErrorBody),
+        (status = 409, description = "The item has changed since that version", body = crate::openapi::ErrorBody),
+    )
+)]
+pub(crate) async fn handle_delete_vault_item(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Path(item_id): Path<String>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let expected = version_vector(&headers)?;
+    let item = find_item(&state, user.user_id, &item_id)
+        .await?
+        .ok_or_else(|| AppError::NotFound("Vault item not found".into()))?;
+    if expected.is_some_and(|expected| expected != item.version_vector.0) {
+        return Ok(conflict(item));
+    }
+
+    let deleted = on_db!(&state.pool, pool => sqlx::query(
+        "DELETE FROM vault_items WHERE user_id = $1 AND item_id = $2 AND system_path = $3"
+    )
+    .bind(user.user_id)
+    .bind(&item_id)
+    .bind(&item.system_path)
+    .execute(pool)
+    .await
+    .map(|r| r.rows_affected()))?;
+    if deleted == 0 {
+        return match find_item(&state, user.user_id, &item_id).await? {
+            Some(current) => Ok(conflict(current)),
+            None => Ok(StatusCode::NO_CONTENT.into_response()),
+        };
+    }
+
+    delete_or_retry(&state, &item.system_path).await;
+    info!(user_id = user.user_id, "DELETED VAULT ITEM {}", item_id);
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+async fn find_item(state: &AppState, user_id: i32, item_id: &str) -> Result<Option<VaultItem>, sqlx::Error> {
+    on_db!(&state.pool, pool => sqlx::query_as::<_, VaultItem>(
+        r#"
+        SELECT item_id, encrypted_name, encrypted_metadata, file_size, version_vector, created_at, updated_at, system_path
+        FROM vault_items
+        WHERE user_id = $1 AND item_id = $2
+        "#
+    )
+    .bind(user_id)
+    .bind(item_id)
+    .fetch_optional(pool)
+    .await)
+}
+
+fn check_item_id(item_id: &str) -> Result<(), AppError> {
+    let valid = !item_id.is_empty()
+        && item_id.len() <= MAX_VAULT_ITEM_ID_LEN
+        && item_id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if !valid {
+        return Err(AppError::BadRequest(format!(
+            "Vault item ids are 1 to {} letters, digits, '-' and '_'",
+            MAX_VAULT_ITEM_ID_LEN
+        )));
+    }
+    Ok(())
+}
+
+fn header_text(headers: &HeaderMap, name: &str) -> Option<String> {
+    headers
+        .get(name)
+        .and_then(|v| v.to_str().ok())
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+        .map(str::to_string)
+}
+
+fn version_vector(headers: &HeaderMap) -> Result<Option<VersionVector>, AppError> {
+    header_text(headers, "X-Version-Vector")
+        .map(|value| {
+            serde_json::from_str(&value).map_err(|_| {
+                AppError::BadRequest("X-Version-Vector must be a JSON object of counters by device".into())
+            })
+        })
+        .transpose()
+}
+
+/// Whether `newer` has seen every change `older` has, and made at least one more.
+fn descends(newer: &VersionVector, older: &VersionVector) -> bool {
+    newer != older
+        && older
+            .iter()
+            .all(|(device, count)| newer.get(device).is_some_and(|n| n >= count))
+}
+
+/// A 409 with the version stored, for the client to merge with.
+fn conflict(current: VaultItem) -> Response {
+    (
+        StatusCode::CONFLICT,
+        Json(serde_json::json!({
+            "error": "The vault item has changed since that version",
+            "code": FailureCode::Conflict,
+            "current": current
+        })),
+    ).into_response()
+}
@@ -0,0 +1,255 @@
+use std::collections::HashMap;
+
+use axum::{
+    extract::{Extension, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use tracing::warn;
+
+use crate::{
+    audit,
+    db::{lock_current, on_db, DbConn},
+    error::AppError,
+    handlers::{jobs::delete_or_retry, uploads::generate_system_path},
+    models::{AuthUser, FileEntry, FileVersion, RevertRequest, VersionsResponse},
+    AppState,
+};
+
+pub(crate) async fn copy_object(state: &AppState, from: &str, to: &str) -> Result<(), String> {
+    state.storage
+        .copy(from, to)
+        .await
+        .map_err(|e| format!("Storage copy failed: {}", e))
+}
+
+/// Records `replaced`, the row an overwrite just gave new content, as the next
+/// version of its file over `conn`, so it commits or rolls back with the overwrite.
+/// Its object stays where it is, now owned by the version. Callers are responsible
+/// for calling `prune_versions` once the overwrite commits.
+pub(crate) async fn record_version(conn: DbConn<'_>, user_id: i32, replaced: &FileEntry) -> Result<(), sqlx::Error> {
+    on_db!(conn, conn => sqlx::query(
+        r#"
+        INSERT INTO file_versions (user_id, file_path, version, file_hash, file_size, modified_time, content_type, etag, s3_key)
+        SELECT $1, $2, COALESCE(MAX(version), 0) + 1, $3, $4, $5, $6, $7, $8
+        FROM file_versions
+        WHERE user_id = $1 AND file_path = $2
+        "#
+    )
+    .bind(user_id)
+    .bind(&replaced.file_path)
+    .bind(&replaced.file_hash)
+    .bind(replaced.file_size)
+    .bind(replaced.modified_time)
+    .bind(&replaced.content_type)
+    .bind(&replaced.etag)
+    .bind(&replaced.file_name)
+    .execute(conn)
+    .await
+    .map(|_| ()))
+}
+
+/// Deletes the oldest revisions of `file_path` beyond `max_versions`.
+pub(crate) async fn prune_versions(state: &AppState, user_id: i32, file_path: &str) {
+    let stale = on_db!(&state.pool, pool => sqlx::query_as::<_, (i32, String)>(
+        r#"
+        SELECT version, s3_key
+        FROM file_versions
+        WHERE user_id = $1 AND file_path = $2
+        ORDER BY version DESC
+        OFFSET $3
+        "#
+    )
+    .bind(user_id)
+    .bind(file_path)
+    .bind(state.settings.current().max_file_versions)
+    .fetch_all(pool)
+    .await)
+    .unwrap_or_default();
+
+    for (version, s3_key) in stale {
+        delete_version(state, user_id, file_path, version, &s3_key).await;
+    }
+}
+
+/// Deletes every stored revision of `file_path`.
+pub(crate) async fn purge_versions(state: &AppState, user_id: i32, file_path: &str) {
+    let versions = on_db!(&state.pool, pool => sqlx::query_as::<_, (i32, String)>(
+        "SELECT version, s3_key FROM file_versions WHERE user_id = $1 AND file_path = $2"
+    )
+    .bind(user_id)
+    .bind(file_path)
+    .fetch_all(pool)
+    .await)
+    .unwrap_or_default();
+
+    for (version, s3_key) in versions {
+        delete_version(state, user_id, file_path, version, &s3_key).await;
+    }
+}
+
+async fn delete_version(state: &AppState, user_id: i32, file_path: &str, version: i32, s3_key: &str) {
+    if let Err(e) = state.storage.delete(s3_key).await {
+        warn!("Failed to delete version object {}: {}", s3_key, e);
+        return;
+    }
+
+    if let Err(e) = on_db!(&state.pool, pool => sqlx::query("DELETE FROM file_versions WHERE user_id = $1 AND file_path = $2 AND version = $3")
+        .bind(user_id)
+        .bind(file_path)
+        .bind(version)
+        .execute(pool)
+        .await
+        .map(|_| ()))
+    {
+        warn!("Failed to delete version {} of {}: {}", version, file_path, e);
+    }
+}
+
+#[utoipa::path(
+    get, path = "/versions", tag = "files",
+    summary = "Lists the stored revisions of a file, newest first",
+    params(("path" = String, Query)),
+    responses(
+        (status = 200, description = "The file's revisions", body = VersionsResponse),
+        (status = 400, description = "`path` is missing", body = VersionsResponse),
+    )
+)]
+pub(crate) async fn handle_list_versions(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Query(params): Query<HashMap<String, String>>,
+) -> impl IntoResponse {
+    let Some(path) = params.get("path") else {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(VersionsResponse { data: None, error: Some("Missing path".into()) }),
+        );
+    };
+
+    let result = on_db!(&state.pool, pool => sqlx::query_as::<_, FileVersion>(
+        r#"
+        SELECT version, file_path, file_hash, file_size, modified_time, content_type, etag, s3_key, created_at
+        FROM file_versions
+        WHERE user_id = $1 AND file_path = $2
+        ORDER BY version DESC
+        "#
+    )
+    .bind(user.user_id)
+    .bind(path)
+    .fetch_all(pool)
+    .await);
+
+    match result {
+        Ok(rows) => (StatusCode::OK, Json(VersionsResponse { data: Some(rows), error: None })),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(VersionsResponse { data: None, error: Some(e.to_string()) }),
+        ),
+    }
+}
+
+/// Makes a stored revision current again (`/restore`, or its older name `/revert`).
+/// The revision being replaced is kept as a version, so a restore can be undone.
+#[utoipa::path(
+    post, path = "/revert", tag = "files",
+    request_body = RevertRequest,
+    responses(
+        (status = 200, description = "The file as restored", body = crate::openapi::Data<FileEntry>),
+        (status = 400, description = "Invalid request", body = crate::openapi::ErrorBody),
+        (status = 404, description = "No such file or revision", body = crate::openapi::ErrorBody),
+    )
+)]
+pub(crate) async fn handle_revert(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Json(req): Json<RevertRequest>,
+) -> Result<Response, AppError> {
+    if req.version.is_some() == req.file_hash.is_some() {
+        return Err(AppError::BadRequest("Provide exactly one of version or file_hash".into()));
+    }
+
+    let target = on_db!(&state.pool, pool => sqlx::query_as::<_, FileVersion>(
+        r#"
+        SELECT version, file_path, file_hash, file_size, modified_time, content_type, etag, s3_key, created_at
+        FROM file_versions
+        WHERE user_id = $1
+          AND file_path = $2
+          AND (CAST($3 AS INTEGER) IS NULL OR version = $3)
+          AND (CAST($4 AS TEXT) IS NULL OR file_hash = $4)
+        ORDER BY version DESC
+        LIMIT 1
+        "#
+    )
+    .bind(user.user_id)
+    .bind(&req.file_path)
+    .bind(req.version)
+    .bind(&req.file_hash)
+    .fetch_optional(pool)
+    .await)?
+    .ok_or_else(|| AppError::NotFound("Version not found".into()))?;
+
+    let file_name = on_db!(&state.pool, pool => sqlx::query_scalar::<_, Option<String>>(
+        "SELECT file_name FROM filehash WHERE user_id = $1 AND file_path = $2"
+    )
+    .bind(user.user_id)
+    .bind(&req.file_path)
+    .fetch_optional(pool)
+    .await)?
+    .ok_or_else(|| AppError::NotFound("file not found in DB".into()))?
+    .unwrap_or_else(|| req.file_path.rsplit('/').next().unwrap_or_default().to_string());
+
+    let system_path = generate_system_path(&state, user.user_id, &req.file_path, &file_name);
+    copy_object(&state, &target.s3_key, &system_path).await.map_err(AppError::Internal)?;
+
+    let row = match revert_file(&state, user.user_id, target, &system_path).await {
+        Ok(row) => row,
+        Err(e) => {
+            delete_or_retry(&state, &system_path).await;
+            return Err(e.into());
+        }
+    };
+    prune_versions(&state, user.user_id, &req.file_path).await;
+    audit::record_restores(&state, &user, vec![req.file_path]);
+
+    Ok((StatusCode::OK, Json(serde_json::json!({ "data": row }))).into_response())
+}
+
+/// Makes `target`, already copied to `system_path`, the current revision of its file.
+/// The revision it replaces is recorded as a version in the same transaction, and
+/// keeps its object.
+async fn revert_file(state: &AppState, user_id: i32, target: FileVersion, system_path: &str) -> Result<FileEntry, sqlx::Error> {
+    let mut tx = state.pool.begin().await?;
+    let current = lock_current(tx.as_conn(), user_id, &target.file_path).await?.ok_or(sqlx::Error::RowNotFound)?;
+
+    let row = on_db!(tx.as_conn(), conn => sqlx::query_as::<_, FileEntry>(
+        r#"
+        UPDATE filehash
+        SET file_hash = $1,
+            file_size = $2,
+            modified_time = $3,
+            content_type = $4,
+            etag = $6,
+            system_path = $8,
+            updated_at = CURRENT_TIMESTAMP,
+            version = version + 1
+        WHERE file_path = $5 AND user_id = $7
+        RETURNING file_path, file_hash, file_size, modified_time, system_path AS file_name, content_type, etag, created_at, updated_at, version
+        "#
+    )
+    .bind(target.file_hash)
+    .bind(target.file_size)
+    .bind(target.modified_time)
+    .bind(target.content_type)
+    .bind(&target.file_path)
+    .bind(target.etag)
+    .bind(user_id)
+    .bind(system_path)
+    .fetch_one(conn)
+    .await)?;
+
+    record_version(tx.as_conn(), user_id, &current).await?;
+    tx.commit().await?;
+    Ok(row)
+}
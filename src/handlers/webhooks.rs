@@ -0,0 +1,161 @@
+use axum::{
+    extract::{Extension, Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use tracing::info;
+
+use crate::{
+    db::{on_db, Array},
+    error::AppError,
+    models::{AuthUser, CreateWebhookRequest, Webhook, WebhookDelivery},
+    webhooks::WEBHOOK_EVENTS,
+    AppState, WEBHOOK_DELIVERY_HISTORY,
+};
+
+/// Registers a URL to be sent the caller's file events. The secret deliveries are
+/// signed with is only returned here.
+#[utoipa::path(
+    post, path = "/webhooks", tag = "webhooks",
+    request_body = CreateWebhookRequest,
+    responses(
+        (status = 201, description = "The new webhook", body = crate::openapi::WebhookCreated),
+        (status = 400, description = "Invalid request", body = crate::openapi::ErrorBody),
+    )
+)]
+pub(crate) async fn handle_create_webhook(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Json(req): Json<CreateWebhookRequest>,
+) -> Result<Response, AppError> {
+    let url = reqwest::Url::parse(req.url.trim())
+        .map_err(|e| AppError::BadRequest(format!("Invalid url: {}", e)))?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(AppError::BadRequest("url must be http or https".into()));
+    }
+    if let Some(unknown) = req.events.iter().find(|event| !WEBHOOK_EVENTS.contains(&event.as_str())) {
+        return Err(AppError::BadRequest(format!(
+            "Unknown event {}; expected one of {}",
+            unknown,
+            WEBHOOK_EVENTS.join(", ")
+        )));
+    }
+    let mut events = req.events;
+    events.sort();
+    events.dedup();
+
+    let secret = hex::encode(rand::random::<[u8; 32]>());
+    let webhook = on_db!(&state.pool, pool => sqlx::query_as::<_, Webhook>(
+        r#"
+        INSERT INTO webhooks (user_id, url, secret, events)
+        VALUES ($1, $2, $3, $4)
+        RETURNING id, url, events, created_at
+        "#
+    )
+    .bind(user.user_id)
+    .bind(url.as_str())
+    .bind(&secret)
+    .bind(Array(&events))
+    .fetch_one(pool)
+    .await)?;
+
+    info!(user_id = user.user_id, "Registered webhook {} for {}", webhook.id, webhook.url);
+    Ok((StatusCode::CREATED, Json(serde_json::json!({
+        "id": webhook.id,
+        "url": webhook.url,
+        "events": webhook.events,
+        "created_at": webhook.created_at,
+        "secret": secret
+    }))).into_response())
+}
+
+#[utoipa::path(
+    get, path = "/webhooks", tag = "webhooks",
+    summary = "Lists the caller's webhooks",
+    responses((status = 200, description = "The caller's webhooks", body = crate::openapi::Data<Vec<Webhook>>))
+)]
+pub(crate) async fn handle_list_webhooks(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+) -> Result<Response, AppError> {
+    let webhooks = on_db!(&state.pool, pool => sqlx::query_as::<_, Webhook>(
+        "SELECT id, url, events, created_at FROM webhooks WHERE user_id = $1 ORDER BY id"
+    )
+    .bind(user.user_id)
+    .fetch_all(pool)
+    .await)?;
+
+    Ok((StatusCode::OK, Json(serde_json::json!({ "data": webhooks }))).into_response())
+}
+
+/// Removes one of the caller's webhooks, with its delivery log and anything it
+/// still had pending.
+#[utoipa::path(
+    delete, path = "/webhooks/{id}", tag = "webhooks",
+    params(("id" = i32, Path)),
+    responses(
+        (status = 204, description = "Removed"),
+        (status = 404, description = "No such webhook", body = crate::openapi::ErrorBody),
+    )
+)]
+pub(crate) async fn handle_delete_webhook(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Path(id): Path<i32>,
+) -> Result<Response, AppError> {
+    let deleted = on_db!(&state.pool, pool => sqlx::query("DELETE FROM webhooks WHERE id = $1 AND user_id = $2")
+    .bind(id)
+    .bind(user.user_id)
+    .execute(pool)
+    .await
+    .map(|r| r.rows_affected()))?;
+
+    if deleted == 0 {
+        return Err(AppError::NotFound("Webhook not found".into()));
+    }
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+/// The latest `WEBHOOK_DELIVERY_HISTORY` deliveries to one of the caller's
+/// webhooks, newest first.
+#[utoipa::path(
+    get, path = "/webhooks/{id}/deliveries", tag = "webhooks",
+    params(("id" = i32, Path)),
+    responses(
+        (status = 200, description = "The webhook's deliveries", body = crate::openapi::Data<Vec<WebhookDelivery>>),
+        (status = 404, description = "No such webhook", body = crate::openapi::ErrorBody),
+    )
+)]
+pub(crate) async fn handle_list_deliveries(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Path(id): Path<i32>,
+) -> Result<Response, AppError> {
+    let exists = on_db!(&state.pool, pool => sqlx::query_scalar::<_, bool>(
+        "SELECT EXISTS (SELECT 1 FROM webhooks WHERE id = $1 AND user_id = $2)"
+    )
+    .bind(id)
+    .bind(user.user_id)
+    .fetch_one(pool)
+    .await)?;
+    if !exists {
+        return Err(AppError::NotFound("Webhook not found".into()));
+    }
+
+    let deliveries = on_db!(&state.pool, pool => sqlx::query_as::<_, WebhookDelivery>(
+        r#"
+        SELECT id, event, status, attempts, response_status, last_error, created_at, delivered_at
+        FROM webhook_deliveries
+        WHERE webhook_id = $1
+        ORDER BY id DESC
+        LIMIT $2
+        "#
+    )
+    .bind(id)
+    .bind(WEBHOOK_DELIVERY_HISTORY)
+    .fetch_all(pool)
+    .await)?;
+
+    Ok((StatusCode::OK, Json(serde_json::json!({ "data": deliveries }))).into_response())
+}
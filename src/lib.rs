@@ -0,0 +1,701 @@
+use std::{env, str::FromStr, sync::Arc, time::Duration};
+
+use tokio::sync::broadcast;
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+use tracing::{info, info_span, Instrument};
+
+use crate::{
+    auth::{bootstrap_admin_token, JwtKeys},
+    bandwidth::Bandwidth,
+    db::connect_with_retry,
+    events::Webhooks,
+    handlers::{
+        archive::archive_periodically,
+        integrity::verify_periodically,
+        jobs::{reconcile_periodically, retry_periodically},
+        listing::prune_tombstones_periodically,
+        settings::{refresh_settings, refresh_settings_periodically},
+        snapshots::snapshot_trees_periodically,
+        trash::purge_trash_periodically,
+        uploads::{expire_upload_sessions, expire_upload_sessions_periodically},
+    },
+    metrics::{MeteredBackend, Metrics},
+    models::{Operation, SyncEvent},
+    notifications::{notify_periodically, Notifier},
+    policy::UploadPolicy,
+    rate_limit::{InMemoryRateLimitStore, RateLimiter},
+    retry::RetryPolicy,
+    scanning::Scanner,
+    scheduler::{run_on_schedule, Scheduler},
+    settings::{LiveSettings, Settings},
+    storage::{
+        build_storage, cache::CachedBackend, compression::CompressedBackend, dedup::DedupBackend, encryption::EncryptedBackend, retry::RetryingBackend, StorageBackend,
+    },
+};
+
+mod audit;
+mod auth;
+mod bandwidth;
+mod chunking;
+mod config;
+mod db;
+mod error;
+mod events;
+mod exif;
+mod grpc;
+mod handlers;
+mod metrics;
+mod models;
+mod notifications;
+mod openapi;
+mod paths;
+mod policy;
+mod rate_limit;
+mod retry;
+mod routes;
+mod scanning;
+mod scheduler;
+mod settings;
+mod storage;
+mod tls;
+mod totp;
+mod webhooks;
+
+pub use config::{
+    AppConfig, CacheConfig, CompressionConfig, CorsConfig, CredentialsConfig, NotificationConfig, RateLimitConfig,
+    RateLimitSettings, RemoteUploadConfig, RetryConfig, RoutingConfig, ScanConfig, ScheduleConfig, SmtpSecurity,
+    StorageRoute, StorageTarget, TlsConfig,
+};
+pub use db::{Db, DbPool};
+pub use routes::build_router;
+pub use tls::TlsListener;
+
+/// How long a stored `Idempotency-Key` result is replayed before it is discarded.
+const IDEMPOTENCY_TTL_HOURS: i32 = 24;
+
+/// How long an `Idempotency-Key` claimed by a request that never finished blocks
+/// retries, in case the server stopped before it could release the key.
+const IDEMPOTENCY_CLAIM_TIMEOUT_SECS: i32 = 900;
+
+/// Bytes buffered per S3 multipart part when streaming an upload. S3 requires at
+/// least 5 MiB for every part but the last.
+const MULTIPART_PART_SIZE: usize = 8 * 1024 * 1024;
+
+/// Seconds between attempts to write a cached upload behind to storage.
+const WRITE_BEHIND_RETRY_SECS: u64 = 30;
+
+/// Redirects a remote upload follows before giving up.
+const MAX_REMOTE_UPLOAD_REDIRECTS: usize = 5;
+
+/// Where the local backend keeps the parts of unfinished multipart uploads.
+const LOCAL_MULTIPART_PREFIX: &str = "multipart";
+
+/// Smallest chunk a resumable upload accepts, other than the one that finishes it.
+/// Chunks map one-to-one onto S3 parts, which share this minimum.
+const MIN_UPLOAD_CHUNK_BYTES: usize = 5 * 1024 * 1024;
+
+/// Largest chunk a resumable upload accepts in one `PATCH`.
+const MAX_UPLOAD_CHUNK_BYTES: usize = 64 * 1024 * 1024;
+
+/// Default cap on bodies that aren't file uploads, overridable via `MAX_BODY_BYTES`.
+const DEFAULT_MAX_BODY_BYTES: usize = 2 * 1024 * 1024;
+
+/// Hours an unfinished resumable upload is kept before it is abandoned.
+const UPLOAD_SESSION_TTL_HOURS: i32 = 24;
+
+/// Number of sync events buffered per `/events` subscriber before it starts lagging.
+const EVENT_CHANNEL_CAPACITY: usize = 256;
+
+/// Shortest `/search` query accepted; anything shorter can't use the trigram index.
+const MIN_SEARCH_QUERY_LEN: usize = 3;
+
+const DEFAULT_PAGE_LIMIT: i64 = 100;
+
+/// Number of extensions, and of largest files, `/stats` lists.
+const STATS_TOP_N: i64 = 20;
+
+const MAX_PAGE_LIMIT: i64 = 1000;
+
+/// Default number of previous revisions retained per file, overridable via `MAX_FILE_VERSIONS`.
+const DEFAULT_MAX_FILE_VERSIONS: i64 = 10;
+
+/// Default number of snapshots of each user's tree kept, overridable via
+/// `SNAPSHOT_RETENTION`.
+const DEFAULT_SNAPSHOT_RETENTION: i64 = 24;
+
+/// Days restored copies of glacial files stay readable, unless a restore asks for
+/// others; `ARCHIVE_RESTORE_DAYS`.
+const DEFAULT_ARCHIVE_RESTORE_DAYS: i32 = 7;
+
+/// Default number of days a deleted file stays in the trash, overridable via
+/// `TRASH_RETENTION_DAYS`. Zero turns the trash off and deletes immediately.
+const DEFAULT_TRASH_RETENTION_DAYS: i64 = 30;
+
+/// Default number of days a deletion is remembered for the change feed, overridable
+/// via `TOMBSTONE_RETENTION_DAYS`. Zero keeps tombstones forever.
+const DEFAULT_TOMBSTONE_RETENTION_DAYS: i64 = 90;
+
+/// Where deleted objects wait in storage until they are restored or purged.
+const TRASH_PREFIX: &str = "trash";
+
+/// Where previous revisions of files are kept in storage.
+const VERSIONS_PREFIX: &str = "versions";
+
+/// Where the content of quarantined files is kept in storage.
+const QUARANTINE_PREFIX: &str = "quarantine";
+
+/// Where the end-to-end encrypted content of vault items is kept in storage.
+const VAULT_PREFIX: &str = "vault";
+
+/// Longest id a client may give a vault item.
+const MAX_VAULT_ITEM_ID_LEN: usize = 128;
+
+/// Where generated thumbnails are kept in storage.
+const THUMBNAIL_PREFIX: &str = "thumbs";
+
+/// Bounding boxes thumbnails are generated at, in pixels.
+const THUMBNAIL_SIZES: [u32; 3] = [128, 256, 512];
+
+/// Size served by `/thumbnail` when the request doesn't name one.
+const DEFAULT_THUMBNAIL_SIZE: u32 = 256;
+
+/// Images larger than this many bytes are not thumbnailed, to bound decoding memory.
+const MAX_THUMBNAIL_SOURCE_BYTES: i64 = 50 * 1024 * 1024;
+
+/// Most tags one file may carry.
+const MAX_TAGS_PER_FILE: usize = 32;
+
+/// Longest tag accepted, in characters.
+const MAX_TAG_LEN: usize = 64;
+
+/// Largest metadata one file may carry, counting the bytes of every key and value.
+const MAX_METADATA_BYTES: usize = 8 * 1024;
+
+/// Default lifetime of presigned download and upload URLs, overridable via `PRESIGN_EXPIRY_SECS`.
+const DEFAULT_PRESIGN_EXPIRY_SECS: u64 = 300;
+
+/// Default size past which `/stream` uploads are spooled to disk rather than held
+/// in memory, overridable via `SPOOL_THRESHOLD_BYTES`.
+const DEFAULT_SPOOL_THRESHOLD_BYTES: usize = 8 * 1024 * 1024;
+
+/// Prefix of storage errors caused by a timeout, which are worth retrying as-is.
+const STORAGE_TIMEOUT_ERROR: &str = "storage request timed out";
+
+/// Prefix of storage errors the service reported as passing, such as a 5xx or
+/// throttling, or of requests that never reached it.
+const STORAGE_UNAVAILABLE_ERROR: &str = "storage temporarily unavailable";
+
+/// Prefix of database errors worth retrying as-is: lost connections, deadlocks,
+/// serialization failures and a busy SQLite file.
+const DB_UNAVAILABLE_ERROR: &str = "database temporarily unavailable";
+
+/// Longest `device_id` a client may register.
+const MAX_DEVICE_ID_LEN: usize = 128;
+
+/// Longest a file request link may stay open.
+const MAX_FILEDROP_EXPIRY_SECS: i64 = 30 * 24 * 3600;
+
+/// Longest name someone dropping files may give, in characters.
+const MAX_DROPPER_NAME_LEN: usize = 100;
+
+/// Lease of a `/lock` that doesn't ask for one.
+const DEFAULT_LOCK_TTL_SECS: i64 = 300;
+
+/// Longest lease a `/lock` may ask for; the holder renews by locking again.
+const MAX_LOCK_TTL_SECS: i64 = 3600;
+
+/// Wait before the first retry of a failed storage operation; doubles per attempt.
+const RETRY_BASE_DELAY_SECS: u64 = 30;
+
+/// Longest wait between two retries of a failed storage operation.
+const RETRY_MAX_DELAY_SECS: u64 = 3600;
+
+/// How long a claimed retry is hidden from other workers while it runs.
+const RETRY_LEASE_SECS: u64 = 300;
+
+/// Attempts before a retry is marked `dead`, overridable via `MAX_RETRY_ATTEMPTS`.
+const DEFAULT_MAX_RETRY_ATTEMPTS: i32 = 10;
+
+/// Retries claimed per run of the retry worker.
+const RETRY_BATCH_SIZE: i64 = 100;
+
+/// Deliveries kept per webhook for `/webhooks/{id}/deliveries`; older finished ones are pruned.
+const WEBHOOK_DELIVERY_HISTORY: i64 = 100;
+
+/// Default lifetime of a `/auth/login` session token, overridable via `JWT_EXPIRY_SECS`.
+const DEFAULT_JWT_EXPIRY_SECS: i64 = 3600;
+
+/// Longest a `/download/token` token lives, overridable via `DOWNLOAD_TOKEN_EXPIRY_SECS`.
+const DEFAULT_DOWNLOAD_TOKEN_EXPIRY_SECS: i64 = 300;
+
+/// Prefix of long-lived API tokens, which tells them apart from session JWTs.
+const API_TOKEN_PREFIX: &str = "pk_";
+
+/// The issuer authenticator apps list two-factor codes under.
+const TOTP_ISSUER: &str = "Pocket Drive";
+
+/// Maximum number of paths accepted by one `/download/urls` request.
+const MAX_DOWNLOAD_BATCH: usize = 1000;
+
+/// Most contents one `/sync/precheck` may ask about.
+const MAX_PRECHECK_FILES: usize = 10_000;
+
+/// Most files one `/download/archive` may bundle.
+const MAX_ARCHIVE_FILES: usize = 10_000;
+
+/// Bytes of an archive buffered between writing it and sending it.
+const ARCHIVE_BUFFER_BYTES: usize = 256 * 1024;
+
+/// Bytes of NDJSON rows gathered before they're sent as one chunk of a
+/// `/get?format=ndjson` response.
+const NDJSON_CHUNK_BYTES: usize = 64 * 1024;
+
+/// Where the file tree is mounted for WebDAV clients.
+const DAV_PREFIX: &str = "/dav";
+
+/// Most content bytes in one gRPC `Download` message, well under the 4 MiB
+/// clients accept by default.
+const GRPC_CHUNK_BYTES: usize = 1024 * 1024;
+
+/// How long a client may take over its TLS handshake before it is dropped.
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often certificate files are checked for a renewed certificate.
+const TLS_RELOAD_INTERVAL: Duration = Duration::from_secs(300);
+
+/// Let's Encrypt's production directory, the default ACME CA.
+const LETS_ENCRYPT_DIRECTORY_URL: &str = "https://acme-v02.api.letsencrypt.org/directory";
+
+/// An ACME certificate is renewed once it has fewer days left than this.
+#[cfg_attr(not(feature = "acme"), allow(dead_code))]
+const ACME_RENEW_BEFORE_DAYS: i64 = 30;
+
+/// How often the ACME certificate's expiry is checked.
+#[cfg_attr(not(feature = "acme"), allow(dead_code))]
+const ACME_CHECK_INTERVAL: Duration = Duration::from_secs(12 * 3600);
+
+/// Reported when an `Update` names a base the server copy no longer matches.
+const UPDATE_CONFLICT_MESSAGE: &str = "conflict: the file changed on the server since the given base";
+
+/// Reported when an `Update` or `Delete` names a `base_version` the server copy
+/// has moved on from.
+const VERSION_MISMATCH_MESSAGE: &str = "version mismatch: the file changed on the server since the given base_version";
+
+/// Prefix of the failure reported when uploaded bytes don't match their `file_hash`.
+const INTEGRITY_FAILURE_MESSAGE: &str = "integrity failure";
+
+/// Prefix of the failure reported when an upload's length doesn't match its `file_size`.
+const SIZE_MISMATCH_MESSAGE: &str = "size mismatch";
+
+/// Reported when an `Update` targets a file another device holds a lock on.
+const LOCKED_MESSAGE: &str = "locked: another device holds a lock on this file";
+
+/// Failure reported when an `Insert` collides with an existing file.
+const INSERT_CONFLICT_MESSAGE: &str = "conflict: a file already exists at this path";
+
+/// Failure reported when a sync operation names a file the user doesn't have.
+const FILE_NOT_FOUND_MESSAGE: &str = "file not found in DB";
+
+/// Failure reported when an `Insert` lists the same path twice.
+const DUPLICATE_PATH_MESSAGE: &str = "file appears more than once in payload";
+
+/// Prefix of the failure reported when no part was sent for an entry's `upload_id`.
+const MISSING_UPLOAD_MESSAGE: &str = "missing upload";
+
+/// Prefix of the failures of the other files of an atomic sync that failed.
+const ROLLED_BACK_MESSAGE: &str = "rolled back";
+
+/// Prefix of the failure reported when the upload policy refuses a file.
+const POLICY_VIOLATION_MESSAGE: &str = "policy violation";
+
+/// Prefix of the failure reported when a path only differs in spelling from another.
+const PATH_COLLISION_MESSAGE: &str = "path collision";
+
+/// Responses smaller than this many bytes are sent uncompressed.
+const DEFAULT_COMPRESSION_MIN_BYTES: u16 = 1024;
+
+/// Stored content shorter than this is kept uncompressed, since zstd's framing
+/// would outweigh what it saves.
+const MIN_COMPRESSED_OBJECT_BYTES: usize = 4 * 1024;
+
+/// Default cap on the number of paths accepted by `/delete`, overridable via `MAX_DELETE_BATCH`.
+const DEFAULT_MAX_DELETE_BATCH: usize = 1000;
+
+/// Once this many rate-limit buckets exist, idle ones are evicted.
+const RATE_LIMIT_MAX_TRACKED_KEYS: usize = 10_000;
+
+/// Objects and rows younger than this are skipped by reconciliation, since they
+/// may belong to a sync that is still in flight.
+const DEFAULT_RECONCILE_MIN_AGE_SECS: i64 = 3600;
+
+/// Number of scheduled reconciliation reports kept in `jobs`.
+const RECONCILE_HISTORY: i64 = 20;
+
+/// Default number of files each integrity check reads back.
+const DEFAULT_INTEGRITY_SAMPLE_SIZE: i64 = 100;
+
+/// Objects a bucket migration copies between updates of its job's progress.
+const MIGRATION_PROGRESS_INTERVAL: i32 = 100;
+
+/// Default number of files a single sync operation processes at once.
+const DEFAULT_SYNC_CONCURRENCY: usize = 8;
+
+/// Uploads in a sync up to this size are held in memory, so that several can be
+/// on their way to storage while the rest of the request is read.
+const BUFFERED_UPLOAD_BYTES: usize = 1024 * 1024;
+
+/// How long `/readyz` waits on each dependency before calling it down.
+const READINESS_CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Shortest chunk `/sync/blocks` cuts a file into; only a file's last chunk may be shorter.
+const MIN_BLOCK_BYTES: usize = 256 * 1024;
+
+/// Past the minimum a chunk ends where this many low bits of the rolling hash are
+/// zero, which makes chunks about 1 MiB longer than the minimum on average.
+const BLOCK_MASK_BITS: u32 = 20;
+
+/// Longest chunk `/sync/blocks` cuts a file into.
+const MAX_BLOCK_BYTES: usize = 4 * 1024 * 1024;
+
+/// Order in which the operations of a sync payload are applied, each finishing
+/// before the next starts: deletes first, so a rename sent as a delete and an
+/// insert never has both paths at once and the space it frees counts towards the
+/// rest, then moves, then updates, then inserts.
+const OPERATION_ORDER: [Operation; 4] = [Operation::Delete, Operation::Move, Operation::Update, Operation::Insert];
+
+/// Newest `/sync` payload format this server reads. Version 1 is a map from
+/// operation to files, applied in `OPERATION_ORDER`. Version 2 requires every
+/// insert to name the part its bytes come in by `upload_id`, or to be
+/// `metadata_only` or to `link` to content the user has. Version 3 lists every file under `operations`, each with the
+/// `op` to apply to it. Version 4 requires every update and delete to give the
+/// `base_version` of the file it was made against.
+const SYNC_PAYLOAD_VERSION: u32 = 4;
+
+/// Payload version from which inserts must say where their bytes are.
+const UPLOAD_ID_PAYLOAD_VERSION: u32 = 2;
+
+/// Payload version from which files come in one list instead of under their
+/// operation.
+const OPERATION_LIST_PAYLOAD_VERSION: u32 = 3;
+
+/// Payload version from which updates and deletes must say which version of the
+/// file they replace.
+const BASE_VERSION_PAYLOAD_VERSION: u32 = 4;
+
+/// Prefix of the name of a `/sync` part holding the bytes of the entry whose
+/// `upload_id` follows it.
+const UPLOAD_PART_PREFIX: &str = "files:";
+
+#[derive(Clone)]
+pub struct AppState{
+    pool: DbPool,
+    storage: Arc<dyn StorageBackend>,
+    events: broadcast::Sender<SyncEvent>,
+    /// The tunables admins can change without a restart.
+    settings: Arc<LiveSettings>,
+    rate_limiter: Arc<RateLimiter>,
+    bandwidth: Arc<Bandwidth>,
+    webhooks: Arc<Webhooks>,
+    /// Scans what users upload; `None` when scanning is off.
+    scanner: Option<Arc<Scanner>>,
+    /// Sends users the notifications they asked for; `None` when no channel is set up.
+    notifier: Option<Arc<Notifier>>,
+    /// The background jobs, and which of them run on a cron schedule.
+    scheduler: Arc<Scheduler>,
+    jwt: Arc<JwtKeys>,
+    /// Files processed at once within one sync operation; never above the DB pool size.
+    sync_concurrency: usize,
+    config: Arc<AppConfig>,
+    reconcile_min_age_secs: i64,
+    /// Files each integrity check samples.
+    integrity_sample_size: i64,
+    /// Background jobs a shutdown waits for.
+    tasks: TaskTracker,
+    /// Cancelled once a shutdown starts, to end long-lived event streams.
+    shutdown: CancellationToken,
+    metrics: Arc<Metrics>,
+    /// Held while archive marks are applied, so a sweep never works from marks
+    /// another one is about to change objects for.
+    archiving: Arc<tokio::sync::Mutex<()>>,
+}
+
+/// Connects to the database and storage, runs migrations, and reads the rest of the
+/// server's configuration from the environment.
+pub async fn build_state(db_url: &str, config: AppConfig) -> AppState {
+    let pool = connect_with_retry(db_url).await;
+
+    pool.migrate().await.expect("Migrations failed");
+
+    if let Ok(token) = env::var("ADMIN_BOOTSTRAP_TOKEN") {
+        bootstrap_admin_token(&pool, &token).await.expect("Failed to install admin bootstrap token");
+    }
+
+    let state = state_with_pool(pool, config).await;
+    refresh_settings(&state).await;
+    state
+}
+
+/// Builds the state around an existing `pool` without running migrations or
+/// queries, so tests can drive the router against a pool that never connects.
+pub async fn state_with_pool(pool: DbPool, config: AppConfig) -> AppState {
+    // Innermost, so a retried write sends the same bytes as the first attempt.
+    let storage: Arc<dyn StorageBackend> =
+        Arc::new(RetryingBackend::new(build_storage(&config).await, RetryPolicy::storage(&config.retry)));
+    // Below encryption and compression, so the disk holds only what the bucket does.
+    let storage: Arc<dyn StorageBackend> = if config.cache.enabled {
+        info!("Caching objects up to {} bytes under {}", config.cache.max_object_bytes, config.cache.dir.display());
+        Arc::new(CachedBackend::new(storage, &config).expect("Failed to open the storage cache"))
+    } else {
+        storage
+    };
+    // Below dedup, so blobs are encrypted too, with the shared key.
+    let storage: Arc<dyn StorageBackend> = if config.encryption_master_key.is_some() {
+        info!("Encrypting stored content with per-user keys");
+        Arc::new(EncryptedBackend::new(storage, pool.clone(), &config))
+    } else {
+        storage
+    };
+    // Above encryption, since ciphertext doesn't compress.
+    let storage: Arc<dyn StorageBackend> = if config.compression.enabled {
+        info!("Compressing stored content with zstd");
+        Arc::new(CompressedBackend::new(storage, pool.clone(), &config))
+    } else {
+        storage
+    };
+    let storage: Arc<dyn StorageBackend> = if config.dedup {
+        info!("Deduplicating stored content by SHA-256");
+        Arc::new(DedupBackend::new(storage, pool.clone()))
+    } else {
+        storage
+    };
+    let metrics = Arc::new(Metrics::default());
+    let storage: Arc<dyn StorageBackend> = Arc::new(MeteredBackend::new(storage, metrics.clone()));
+
+    let (events, _) = broadcast::channel(EVENT_CHANNEL_CAPACITY);
+    let settings = Settings {
+        presign_expiry_secs: config.presign_expiry_secs,
+        max_upload_bytes: config.max_upload_bytes,
+        default_quota_bytes: config.default_quota_bytes,
+        trash_retention_days: env_or("TRASH_RETENTION_DAYS", DEFAULT_TRASH_RETENTION_DAYS).max(0),
+        tombstone_retention_days: env_or("TOMBSTONE_RETENTION_DAYS", DEFAULT_TOMBSTONE_RETENTION_DAYS).max(0),
+        max_file_versions: env_or("MAX_FILE_VERSIONS", DEFAULT_MAX_FILE_VERSIONS),
+        max_delete_batch: env_or("MAX_DELETE_BATCH", DEFAULT_MAX_DELETE_BATCH).max(1),
+        reconcile_delete_orphans: env::var("RECONCILE_DELETE_ORPHANS").is_ok_and(|v| v == "true"),
+        user_upload_bytes_per_sec: env_rate("BANDWIDTH_USER_UPLOAD_BYTES_PER_SEC"),
+        user_download_bytes_per_sec: env_rate("BANDWIDTH_USER_DOWNLOAD_BYTES_PER_SEC"),
+        device_upload_bytes_per_sec: env_rate("BANDWIDTH_DEVICE_UPLOAD_BYTES_PER_SEC"),
+        device_download_bytes_per_sec: env_rate("BANDWIDTH_DEVICE_DOWNLOAD_BYTES_PER_SEC"),
+        upload_policy: UploadPolicy {
+            blocked_extensions: env_list("BLOCKED_EXTENSIONS"),
+            forbidden_paths: env_list("FORBIDDEN_PATHS"),
+            ..UploadPolicy::default()
+        },
+    };
+    let sync_concurrency = env_or("SYNC_CONCURRENCY", DEFAULT_SYNC_CONCURRENCY)
+        .clamp(1, pool.max_connections() as usize);
+
+    AppState {
+        pool,
+        storage,
+        events,
+        settings: Arc::new(LiveSettings::new(settings)),
+        rate_limiter: Arc::new(RateLimiter::new(
+            Arc::new(InMemoryRateLimitStore::default()),
+            &config.rate_limits,
+        )),
+        bandwidth: Arc::new(Bandwidth::default()),
+        webhooks: Arc::new(Webhooks::from_env()),
+        scanner: Scanner::from_config(&config.scanning).map(|scanner| {
+            info!("Scanning uploaded content with the {} scanner", scanner.name());
+            Arc::new(scanner)
+        }),
+        notifier: Notifier::from_config(&config.notifications).map(|notifier| {
+            info!("Sending notifications by {}", notifier.channels().join(", "));
+            Arc::new(notifier)
+        }),
+        scheduler: Arc::new(Scheduler::from_config(&config.schedule)),
+        jwt: Arc::new(JwtKeys::from_env()),
+        sync_concurrency,
+        config: Arc::new(config),
+        reconcile_min_age_secs: env_or("RECONCILE_MIN_AGE_SECS", DEFAULT_RECONCILE_MIN_AGE_SECS),
+        integrity_sample_size: env_or("INTEGRITY_SAMPLE_SIZE", DEFAULT_INTEGRITY_SAMPLE_SIZE).max(1),
+        tasks: TaskTracker::new(),
+        shutdown: CancellationToken::new(),
+        metrics,
+        archiving: Arc::default(),
+    }
+}
+
+/// Resolves on SIGINT or SIGTERM, then closes open event streams so the server's
+/// graceful shutdown only has ordinary requests left to drain.
+pub async fn shutdown_signal(state: AppState) {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c().await.expect("Failed to listen for SIGINT");
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Failed to listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+    info!("SHUTTING DOWN: draining in-flight requests");
+    state.shutdown.cancel();
+}
+
+/// Finishes what in-flight work the server can before exiting: waits up to
+/// `shutdown_timeout_secs` for background sync and reconcile jobs, then aborts the
+/// multipart uploads of expired upload sessions. Sessions still live are kept, since
+/// clients can resume them once the server is back.
+pub async fn drain(state: &AppState) {
+    state.tasks.close();
+    if !state.tasks.is_empty() {
+        info!("SHUTTING DOWN: waiting for {} background jobs", state.tasks.len());
+    }
+    let timeout = Duration::from_secs(state.config.shutdown_timeout_secs);
+    if tokio::time::timeout(timeout, state.tasks.wait()).await.is_err() {
+        info!("SHUTTING DOWN: {} background jobs still running after {:?}", state.tasks.len(), timeout);
+    }
+    expire_upload_sessions(state).await;
+    info!("SHUTDOWN COMPLETE");
+}
+
+/// Starts the task that empties expired trash, every `TRASH_PURGE_INTERVAL_SECS`.
+/// It runs even with the trash turned off, since a setting can turn it back on.
+pub fn spawn_trash_purger(state: &AppState) {
+    if state.scheduler.schedules("trash_purge") {
+        return;
+    }
+    let every = Duration::from_secs(env_or("TRASH_PURGE_INTERVAL_SECS", 3600).max(1));
+    tokio::spawn(purge_trash_periodically(state.clone(), every).instrument(info_span!("trash_purger")));
+}
+
+/// Starts the task that forgets expired tombstones, every `TOMBSTONE_PRUNE_INTERVAL_SECS`.
+pub fn spawn_tombstone_pruner(state: &AppState) {
+    if state.scheduler.schedules("tombstone_prune") {
+        return;
+    }
+    let every = Duration::from_secs(env_or("TOMBSTONE_PRUNE_INTERVAL_SECS", 3600).max(1));
+    tokio::spawn(prune_tombstones_periodically(state.clone(), every).instrument(info_span!("tombstone_pruner")));
+}
+
+/// Starts the task that snapshots every user's tree that changed, every
+/// `SNAPSHOT_INTERVAL_SECS`; zero turns scheduled snapshots off.
+pub fn spawn_snapshotter(state: &AppState) {
+    if state.scheduler.schedules("snapshots") {
+        return;
+    }
+    let interval: u64 = env_or("SNAPSHOT_INTERVAL_SECS", 3600);
+    if interval > 0 {
+        let every = Duration::from_secs(interval);
+        tokio::spawn(snapshot_trees_periodically(state.clone(), every).instrument(info_span!("snapshotter")));
+    }
+}
+
+/// Starts the task that moves files to the storage classes their archive marks
+/// name and follows up on restores, every `ARCHIVE_SWEEP_INTERVAL_SECS`.
+pub fn spawn_archiver(state: &AppState) {
+    if state.scheduler.schedules("archive") {
+        return;
+    }
+    let every = Duration::from_secs(env_or("ARCHIVE_SWEEP_INTERVAL_SECS", 3600).max(1));
+    tokio::spawn(archive_periodically(state.clone(), every).instrument(info_span!("archiver")));
+}
+
+/// Starts the task that re-reads the settings admins override, every
+/// `SETTINGS_REFRESH_SECS`.
+pub fn spawn_settings_watcher(state: &AppState) {
+    let every = Duration::from_secs(env_or("SETTINGS_REFRESH_SECS", 30).max(1));
+    tokio::spawn(refresh_settings_periodically(state.clone(), every).instrument(info_span!("settings_watcher")));
+}
+
+/// Starts the task that abandons expired upload sessions and aborts orphaned
+/// multipart uploads, every `UPLOAD_SWEEP_INTERVAL_SECS`.
+pub fn spawn_upload_sweeper(state: &AppState) {
+    if state.scheduler.schedules("upload_sweep") {
+        return;
+    }
+    let every = Duration::from_secs(env_or("UPLOAD_SWEEP_INTERVAL_SECS", 600).max(1));
+    tokio::spawn(expire_upload_sessions_periodically(state.clone(), every).instrument(info_span!("upload_sweeper")));
+}
+
+/// Starts the periodic reconciliation task when `RECONCILE_INTERVAL_SECS` is set.
+pub fn spawn_reconciler(state: &AppState) {
+    if state.scheduler.schedules("reconcile") {
+        return;
+    }
+    let reconcile_interval: u64 = env_or("RECONCILE_INTERVAL_SECS", 0);
+    if reconcile_interval > 0 {
+        let every = Duration::from_secs(reconcile_interval);
+        tokio::spawn(reconcile_periodically(state.clone(), every).instrument(info_span!("reconciler")));
+    }
+}
+
+/// Starts the integrity check of `INTEGRITY_SAMPLE_SIZE` random files, every
+/// `INTEGRITY_CHECK_INTERVAL_SECS` when that's set.
+pub fn spawn_integrity_checker(state: &AppState) {
+    if state.scheduler.schedules("integrity_check") {
+        return;
+    }
+    let interval: u64 = env_or("INTEGRITY_CHECK_INTERVAL_SECS", 0);
+    if interval > 0 {
+        let every = Duration::from_secs(interval);
+        let sample = state.integrity_sample_size;
+        tokio::spawn(verify_periodically(state.clone(), every, sample).instrument(info_span!("integrity_checker")));
+    }
+}
+
+/// Starts the task that retries failed storage operations and webhook deliveries,
+/// every `RETRY_INTERVAL_SECS`.
+pub fn spawn_retry_worker(state: &AppState) {
+    let every = Duration::from_secs(env_or("RETRY_INTERVAL_SECS", 30).max(1));
+    let max_attempts = env_or("MAX_RETRY_ATTEMPTS", DEFAULT_MAX_RETRY_ATTEMPTS).max(1);
+    tokio::spawn(retry_periodically(state.clone(), every, max_attempts).instrument(info_span!("retry_worker")));
+}
+
+/// Starts a task for each job `ScheduleConfig` puts on a cron schedule. Those
+/// jobs' own `spawn_*` leave them to it.
+pub fn spawn_scheduler(state: &AppState) {
+    for (index, job) in state.scheduler.jobs().iter().enumerate() {
+        if let Some(schedule) = job.schedule() {
+            info!("Running job {} on the schedule {:?}", job.name, schedule);
+            tokio::spawn(run_on_schedule(state.clone(), index).instrument(info_span!("scheduler", job = job.name)));
+        }
+    }
+}
+
+/// Starts the task that warns of quotas nearly used and syncs that keep failing,
+/// every `NOTIFY_SWEEP_INTERVAL_SECS`, when a notification channel is set up.
+pub fn spawn_notifier(state: &AppState) {
+    if state.notifier.is_some() {
+        let every = Duration::from_secs(state.config.notifications.sweep_interval_secs.max(1));
+        tokio::spawn(notify_periodically(state.clone(), every).instrument(info_span!("notifier")));
+    }
+}
+
+/// Reads `name` from the environment, falling back to `default` when unset or unparsable.
+fn env_or<T: FromStr>(name: &str, default: T) -> T {
+    env::var(name)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(default)
+}
+
+/// A comma-separated list from the environment; empty when unset.
+fn env_list(name: &str) -> Vec<String> {
+    env::var(name)
+        .map(|v| v.split(',').map(str::trim).filter(|item| !item.is_empty()).map(String::from).collect())
+        .unwrap_or_default()
+}
+
+/// A byte rate from the environment; unset or zero means no limit.
+fn env_rate(name: &str) -> Option<u64> {
+    Some(env_or(name, 0)).filter(|&rate| rate > 0)
+}
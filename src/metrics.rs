@@ -0,0 +1,382 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    fmt::Write as _,
+    future::Future,
+    sync::{
+        atomic::{AtomicI64, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use axum::{
+    extract::{MatchedPath, Request, State},
+    middleware::Next,
+    response::Response,
+};
+use tokio_stream::StreamExt;
+use tracing::{debug, debug_span, Instrument};
+
+use crate::{
+    config::AppConfig,
+    db::{on_db, DbPool},
+    storage::{ByteChunks, ObjectBody, ObjectInfo, RestoreState, StorageBackend, StorageClass, StorageError, StreamParams},
+    AppState,
+};
+
+/// Upper bounds, in seconds, of the request latency histogram buckets.
+const LATENCY_BUCKETS: [f64; 11] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+#[derive(Default)]
+struct RouteStats {
+    /// Responses by status code.
+    responses: BTreeMap<u16, u64>,
+    /// Cumulative counts per `LATENCY_BUCKETS` entry.
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    count: u64,
+    seconds: f64,
+}
+
+#[derive(Default)]
+struct StorageStats {
+    calls: u64,
+    errors: u64,
+}
+
+/// Counters behind `/metrics`, rendered in the Prometheus text format.
+#[derive(Default)]
+pub(crate) struct Metrics {
+    /// Keyed by method and matched route, so path parameters don't add series.
+    routes: Mutex<BTreeMap<(String, String), RouteStats>>,
+    storage: Mutex<BTreeMap<&'static str, StorageStats>>,
+    bytes_uploaded: AtomicU64,
+    bytes_downloaded: AtomicU64,
+    active_syncs: AtomicI64,
+}
+
+impl Metrics {
+    fn observe_request(&self, method: &str, route: &str, status: u16, elapsed: Duration) {
+        let seconds = elapsed.as_secs_f64();
+        let mut routes = self.routes.lock().unwrap();
+        let stats = routes.entry((method.to_string(), route.to_string())).or_default();
+        *stats.responses.entry(status).or_default() += 1;
+        for (bucket, bound) in stats.buckets.iter_mut().zip(LATENCY_BUCKETS) {
+            if seconds <= bound {
+                *bucket += 1;
+            }
+        }
+        stats.count += 1;
+        stats.seconds += seconds;
+    }
+
+    fn observe_storage<T>(&self, operation: &'static str, result: &Result<T, StorageError>) {
+        let mut storage = self.storage.lock().unwrap();
+        let stats = storage.entry(operation).or_default();
+        stats.calls += 1;
+        if result.is_err() {
+            stats.errors += 1;
+        }
+    }
+
+    /// Counts a sync as active until the returned guard is dropped.
+    pub(crate) fn sync_started(self: &Arc<Self>) -> ActiveSync {
+        self.active_syncs.fetch_add(1, Ordering::Relaxed);
+        ActiveSync(self.clone())
+    }
+
+    pub(crate) fn render(&self, pool: &DbPool) -> String {
+        let mut out = String::new();
+
+        out.push_str("# HELP pocket_http_requests_total Responses served, by route and status.\n");
+        out.push_str("# TYPE pocket_http_requests_total counter\n");
+        let routes = self.routes.lock().unwrap();
+        for ((method, route), stats) in routes.iter() {
+            for (status, count) in &stats.responses {
+                let _ = writeln!(
+                    out,
+                    "pocket_http_requests_total{{method=\"{}\",route=\"{}\",status=\"{}\"}} {}",
+                    method, escape(route), status, count
+                );
+            }
+        }
+
+        out.push_str("# HELP pocket_http_request_duration_seconds Time until the response headers were ready.\n");
+        out.push_str("# TYPE pocket_http_request_duration_seconds histogram\n");
+        for ((method, route), stats) in routes.iter() {
+            let labels = format!("method=\"{}\",route=\"{}\"", method, escape(route));
+            for (bound, count) in LATENCY_BUCKETS.iter().zip(stats.buckets) {
+                let _ = writeln!(out, "pocket_http_request_duration_seconds_bucket{{{},le=\"{}\"}} {}", labels, bound, count);
+            }
+            let _ = writeln!(out, "pocket_http_request_duration_seconds_bucket{{{},le=\"+Inf\"}} {}", labels, stats.count);
+            let _ = writeln!(out, "pocket_http_request_duration_seconds_sum{{{}}} {}", labels, stats.seconds);
+            let _ = writeln!(out, "pocket_http_request_duration_seconds_count{{{}}} {}", labels, stats.count);
+        }
+        drop(routes);
+
+        out.push_str("# HELP pocket_storage_operations_total Calls made to the storage backend.\n");
+        out.push_str("# TYPE pocket_storage_operations_total counter\n");
+        let storage = self.storage.lock().unwrap();
+        for (operation, stats) in storage.iter() {
+            let _ = writeln!(out, "pocket_storage_operations_total{{operation=\"{}\"}} {}", operation, stats.calls);
+        }
+        out.push_str("# HELP pocket_storage_errors_total Storage backend calls that failed.\n");
+        out.push_str("# TYPE pocket_storage_errors_total counter\n");
+        for (operation, stats) in storage.iter() {
+            let _ = writeln!(out, "pocket_storage_errors_total{{operation=\"{}\"}} {}", operation, stats.errors);
+        }
+        drop(storage);
+
+        let _ = writeln!(
+            out,
+            "# HELP pocket_bytes_uploaded_total File bytes written to storage through the server.\n\
+             # TYPE pocket_bytes_uploaded_total counter\n\
+             pocket_bytes_uploaded_total {}",
+            self.bytes_uploaded.load(Ordering::Relaxed)
+        );
+        let _ = writeln!(
+            out,
+            "# HELP pocket_bytes_downloaded_total File bytes read from storage through the server.\n\
+             # TYPE pocket_bytes_downloaded_total counter\n\
+             pocket_bytes_downloaded_total {}",
+            self.bytes_downloaded.load(Ordering::Relaxed)
+        );
+        let _ = writeln!(
+            out,
+            "# HELP pocket_active_syncs Sync requests and background sync jobs in progress.\n\
+             # TYPE pocket_active_syncs gauge\n\
+             pocket_active_syncs {}",
+            self.active_syncs.load(Ordering::Relaxed)
+        );
+
+        let _ = writeln!(
+            out,
+            "# HELP pocket_db_pool_connections Open database connections.\n\
+             # TYPE pocket_db_pool_connections gauge\n\
+             pocket_db_pool_connections {}\n\
+             # HELP pocket_db_pool_idle_connections Open database connections not in use.\n\
+             # TYPE pocket_db_pool_idle_connections gauge\n\
+             pocket_db_pool_idle_connections {}\n\
+             # HELP pocket_db_pool_max_connections Largest number of connections the pool opens.\n\
+             # TYPE pocket_db_pool_max_connections gauge\n\
+             pocket_db_pool_max_connections {}",
+            on_db!(pool, pool => pool.size()),
+            on_db!(pool, pool => pool.num_idle()),
+            pool.max_connections()
+        );
+        out
+    }
+}
+
+/// Escapes a label value per the Prometheus text format.
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// Keeps a sync counted in `pocket_active_syncs` while alive.
+pub(crate) struct ActiveSync(Arc<Metrics>);
+
+impl Drop for ActiveSync {
+    fn drop(&mut self) {
+        self.0.active_syncs.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Records the count, status and latency of every routed request.
+pub(crate) async fn track_requests(State(state): State<AppState>, req: Request, next: Next) -> Response {
+    let method = req.method().to_string();
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|p| p.as_str().to_string())
+        .unwrap_or_default();
+    let started = Instant::now();
+    let response = next.run(req).await;
+    state.metrics.observe_request(&method, &route, response.status().as_u16(), started.elapsed());
+    response
+}
+
+/// Counts calls, failures and bytes moved on the way to the wrapped backend, and
+/// traces each call under the request that made it.
+pub(crate) struct MeteredBackend {
+    inner: Arc<dyn StorageBackend>,
+    metrics: Arc<Metrics>,
+}
+
+impl MeteredBackend {
+    pub(crate) fn new(inner: Arc<dyn StorageBackend>, metrics: Arc<Metrics>) -> Self {
+        Self { inner, metrics }
+    }
+
+    /// Runs one backend call inside a span, so the request it serves shows in its logs,
+    /// and records how it went.
+    async fn observe<T>(
+        &self,
+        operation: &'static str,
+        key: &str,
+        call: impl Future<Output = Result<T, StorageError>>,
+    ) -> Result<T, StorageError> {
+        let started = Instant::now();
+        let result = call.instrument(debug_span!("storage", operation, key)).await;
+        debug!(operation, key, elapsed_ms = started.elapsed().as_millis() as u64, ok = result.is_ok(), "storage call");
+        self.metrics.observe_storage(operation, &result);
+        result
+    }
+
+    fn uploaded(&self, bytes: usize) {
+        self.metrics.bytes_uploaded.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+}
+
+#[async_trait]
+impl StorageBackend for MeteredBackend {
+    async fn put(&self, key: &str, data: Vec<u8>, content_type: &str) -> Result<Option<String>, StorageError> {
+        let len = data.len();
+        let result = self.observe("put", key, self.inner.put(key, data, content_type)).await;
+        if result.is_ok() {
+            self.uploaded(len);
+        }
+        result
+    }
+
+    async fn put_stream(
+        &self,
+        key: &str,
+        content_type: &str,
+        chunks: &mut ByteChunks<'_>,
+    ) -> Result<Option<String>, StorageError> {
+        let mut size = 0;
+        let result = {
+            let mut counting = chunks.map(|chunk| {
+                if let Ok(bytes) = &chunk {
+                    size += bytes.len();
+                }
+                chunk
+            });
+            self.observe("put", key, self.inner.put_stream(key, content_type, &mut counting)).await
+        };
+        if result.is_ok() {
+            self.uploaded(size);
+        }
+        result
+    }
+
+    async fn get(&self, key: &str) -> Result<Vec<u8>, StorageError> {
+        let result = self.observe("get", key, self.inner.get(key)).await;
+        if let Ok(data) = &result {
+            self.metrics.bytes_downloaded.fetch_add(data.len() as u64, Ordering::Relaxed);
+        }
+        result
+    }
+
+    async fn get_range(&self, key: &str, start: u64, len: u64) -> Result<ObjectBody, StorageError> {
+        let body = self.observe("get", key, self.inner.get_range(key, start, len)).await?;
+        let metrics = self.metrics.clone();
+        Ok(Box::pin(body.map(move |chunk| {
+            if let Ok(bytes) = &chunk {
+                metrics.bytes_downloaded.fetch_add(bytes.len() as u64, Ordering::Relaxed);
+            }
+            chunk
+        })))
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), StorageError> {
+        self.observe("delete", key, self.inner.delete(key)).await
+    }
+
+    async fn copy(&self, from: &str, to: &str) -> Result<(), StorageError> {
+        self.observe("copy", from, self.inner.copy(from, to)).await
+    }
+
+    async fn size(&self, key: &str) -> Result<i64, StorageError> {
+        self.observe("size", key, self.inner.size(key)).await
+    }
+
+    async fn presign_download(
+        &self,
+        key: &str,
+        content_type: Option<String>,
+        expires_in: Duration,
+    ) -> Result<String, StorageError> {
+        self.observe("presign", key, self.inner.presign_download(key, content_type, expires_in)).await
+    }
+
+    async fn presign_upload(
+        &self,
+        key: &str,
+        size: i64,
+        content_type: &str,
+        sha256: Option<&str>,
+        expires_in: Duration,
+    ) -> Result<String, StorageError> {
+        self.observe("presign", key, self.inner.presign_upload(key, size, content_type, sha256, expires_in)).await
+    }
+
+    fn upload_headers(&self, sha256: Option<&str>) -> Vec<(&'static str, String)> {
+        self.inner.upload_headers(sha256)
+    }
+
+    async fn sha256(&self, key: &str) -> Result<Option<String>, StorageError> {
+        self.observe("sha256", key, self.inner.sha256(key)).await
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<ObjectInfo>, StorageError> {
+        self.observe("list", prefix, self.inner.list(prefix)).await
+    }
+
+    async fn create_multipart(&self, key: &str, content_type: &str) -> Result<String, StorageError> {
+        self.observe("create_multipart", key, self.inner.create_multipart(key, content_type)).await
+    }
+
+    async fn upload_part(
+        &self,
+        key: &str,
+        upload_id: &str,
+        part_number: i32,
+        data: Vec<u8>,
+    ) -> Result<String, StorageError> {
+        let len = data.len();
+        let result = self.observe("upload_part", key, self.inner.upload_part(key, upload_id, part_number, data)).await;
+        if result.is_ok() {
+            self.uploaded(len);
+        }
+        result
+    }
+
+    async fn complete_multipart(
+        &self,
+        key: &str,
+        upload_id: &str,
+        parts: &[(i32, String)],
+    ) -> Result<Option<String>, StorageError> {
+        self.observe("complete_multipart", key, self.inner.complete_multipart(key, upload_id, parts)).await
+    }
+
+    async fn abort_multipart(&self, key: &str, upload_id: &str) -> Result<(), StorageError> {
+        self.observe("abort_multipart", key, self.inner.abort_multipart(key, upload_id)).await
+    }
+
+    async fn abort_stale_multipart(&self, started_before: i64, keep: &HashSet<String>) -> Result<usize, StorageError> {
+        self.observe("abort_stale_multipart", "", self.inner.abort_stale_multipart(started_before, keep)).await
+    }
+
+    async fn set_storage_class(&self, key: &str, class: StorageClass) -> Result<(), StorageError> {
+        self.observe("set_storage_class", key, self.inner.set_storage_class(key, class)).await
+    }
+
+    async fn restore(&self, key: &str, days: i32) -> Result<RestoreState, StorageError> {
+        self.observe("restore", key, self.inner.restore(key, days)).await
+    }
+
+    fn verify_stream(&self, method: &str, params: &StreamParams) -> bool {
+        self.inner.verify_stream(method, params)
+    }
+
+    async fn check(&self) -> Result<(), StorageError> {
+        self.observe("check", "", self.inner.check()).await
+    }
+
+    async fn reload(&self, config: &AppConfig) -> Result<(), StorageError> {
+        self.inner.reload(config).await
+    }
+}
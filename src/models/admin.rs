@@ -0,0 +1,164 @@
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use utoipa::IntoParams;
+
+use crate::models::Role;
+
+/// A user as listed by `/admin/users`, with what they store.
+#[derive(Serialize, FromRow)]
+pub(crate) struct UserSummary {
+    pub(crate) id: i32,
+    pub(crate) username: String,
+    pub(crate) role: Role,
+    /// `None` means the server's default quota applies.
+    pub(crate) quota_bytes: Option<i64>,
+    pub(crate) bytes: i64,
+    pub(crate) files: i64,
+    pub(crate) created_at: Option<chrono::NaiveDateTime>,
+}
+
+/// One user's storage and activity, for `/admin/users/{id}`.
+#[derive(Serialize, FromRow)]
+pub(crate) struct UserStats {
+    pub(crate) id: i32,
+    pub(crate) username: String,
+    pub(crate) role: Role,
+    pub(crate) quota_bytes: Option<i64>,
+    pub(crate) created_at: Option<chrono::NaiveDateTime>,
+    pub(crate) bytes: i64,
+    pub(crate) files: i64,
+    pub(crate) trash_bytes: i64,
+    pub(crate) trash_files: i64,
+    /// Earlier revisions kept by versioning.
+    pub(crate) version_bytes: i64,
+    pub(crate) versions: i64,
+    pub(crate) devices: i64,
+    /// When any of the user's devices last contacted the server.
+    pub(crate) last_seen_at: Option<chrono::NaiveDateTime>,
+    pub(crate) active_shares: i64,
+    pub(crate) active_tokens: i64,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub(crate) struct AdminListParams {
+    /// Only this user's entries.
+    pub(crate) user_id: Option<i32>,
+    /// For background jobs: `pending`, `running`, `completed` or `failed`.
+    pub(crate) status: Option<String>,
+    pub(crate) limit: Option<i64>,
+    pub(crate) offset: Option<i64>,
+}
+
+/// An active share link of any user.
+#[derive(Serialize, FromRow)]
+pub(crate) struct AdminShare {
+    pub(crate) id: i32,
+    pub(crate) user_id: i32,
+    pub(crate) username: String,
+    pub(crate) file_path: String,
+    pub(crate) password_protected: bool,
+    pub(crate) folder: bool,
+    pub(crate) created_at: Option<chrono::NaiveDateTime>,
+    pub(crate) expires_at: Option<chrono::NaiveDateTime>,
+}
+
+/// A background job of any user, or of the server itself.
+#[derive(Serialize, FromRow)]
+pub(crate) struct AdminJob {
+    pub(crate) id: i32,
+    pub(crate) user_id: Option<i32>,
+    pub(crate) username: Option<String>,
+    pub(crate) kind: String,
+    pub(crate) status: String,
+    pub(crate) progress: i32,
+    pub(crate) total: i32,
+    pub(crate) error: Option<String>,
+    pub(crate) created_at: Option<chrono::NaiveDateTime>,
+    pub(crate) updated_at: Option<chrono::NaiveDateTime>,
+}
+
+/// A stored object the integrity check flagged, for `/admin/integrity`.
+#[derive(Serialize, FromRow)]
+pub(crate) struct IntegrityIssue {
+    pub(crate) id: i32,
+    pub(crate) user_id: i32,
+    pub(crate) username: String,
+    pub(crate) file_path: String,
+    pub(crate) system_path: String,
+    /// `missing` or `corrupted`.
+    pub(crate) kind: String,
+    pub(crate) detail: String,
+    pub(crate) detected_at: chrono::NaiveDateTime,
+    /// When the object was last checked and still found wrong.
+    pub(crate) checked_at: chrono::NaiveDateTime,
+}
+
+/// A file of any user's held in the quarantine, for `/admin/quarantine`.
+#[derive(Serialize, FromRow)]
+pub(crate) struct QuarantinedFile {
+    pub(crate) id: i32,
+    pub(crate) user_id: i32,
+    pub(crate) username: String,
+    pub(crate) file_path: String,
+    pub(crate) file_hash: Option<String>,
+    pub(crate) file_size: i64,
+    pub(crate) content_type: Option<String>,
+    /// What the scanner reported finding.
+    pub(crate) signature: String,
+    pub(crate) quarantined_at: chrono::NaiveDateTime,
+}
+
+/// The maintenance mode the server is in, while writes are refused.
+#[derive(Serialize, FromRow)]
+pub(crate) struct Maintenance {
+    pub(crate) reason: Option<String>,
+    pub(crate) started_by: Option<String>,
+    pub(crate) started_at: chrono::NaiveDateTime,
+}
+
+/// The body of `POST /admin/maintenance`.
+#[derive(Deserialize)]
+pub(crate) struct MaintenanceRequest {
+    pub(crate) enabled: bool,
+    pub(crate) reason: Option<String>,
+}
+
+/// A row of `settings`: an admin's override of one tunable.
+#[derive(FromRow)]
+pub(crate) struct SettingOverride {
+    pub(crate) key: String,
+    /// JSON.
+    pub(crate) value: String,
+    pub(crate) updated_by: Option<String>,
+    pub(crate) updated_at: chrono::NaiveDateTime,
+}
+
+/// One tunable as `/admin/settings` shows it.
+#[derive(Serialize)]
+pub(crate) struct SettingEntry {
+    pub(crate) key: String,
+    /// The value in force.
+    pub(crate) value: serde_json::Value,
+    /// What the environment or config file sets.
+    pub(crate) default: serde_json::Value,
+    pub(crate) overridden: bool,
+    pub(crate) updated_by: Option<String>,
+    pub(crate) updated_at: Option<chrono::NaiveDateTime>,
+}
+
+/// A background job, for `/admin/schedule`.
+#[derive(Serialize)]
+pub(crate) struct ScheduledJob {
+    pub(crate) name: &'static str,
+    /// The cron expression the job runs on; it runs on its interval when unset.
+    pub(crate) schedule: Option<String>,
+    pub(crate) running: bool,
+    /// When the schedule next runs the job.
+    pub(crate) next_run_at: Option<chrono::NaiveDateTime>,
+    pub(crate) last_started_at: Option<chrono::NaiveDateTime>,
+    pub(crate) last_finished_at: Option<chrono::NaiveDateTime>,
+    /// `succeeded` or `failed`.
+    pub(crate) last_status: Option<String>,
+    pub(crate) last_error: Option<String>,
+}
@@ -0,0 +1,59 @@
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use utoipa::{IntoParams, ToSchema};
+
+use crate::{models::FileFailure, storage::StorageClass};
+
+/// A file or folder kept in an archive storage class.
+#[derive(Serialize, FromRow, ToSchema)]
+pub(crate) struct ArchiveRule {
+    /// The file, or the folder whose files the rule covers.
+    pub(crate) path: String,
+    #[sqlx(try_from = "String")]
+    pub(crate) storage_class: StorageClass,
+    pub(crate) created_at: chrono::NaiveDateTime,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub(crate) struct ArchiveRuleRequest {
+    pub(crate) path: String,
+    /// `STANDARD_IA` or `GLACIER`.
+    pub(crate) storage_class: StorageClass,
+}
+
+#[derive(Deserialize, IntoParams)]
+pub(crate) struct ArchivePathParams {
+    /// A file, or a folder for everything under it.
+    pub(crate) path: Option<String>,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub(crate) struct RestoreArchiveRequest {
+    /// A file, or a folder to restore every glacial file under.
+    pub(crate) path: String,
+    /// How long restored copies stay readable, `ARCHIVE_RESTORE_DAYS` by default.
+    #[serde(default)]
+    pub(crate) days: Option<i32>,
+}
+
+/// A file that was moved to an archive storage class.
+#[derive(Serialize, FromRow, ToSchema)]
+pub(crate) struct ArchivedFile {
+    pub(crate) file_path: String,
+    #[sqlx(try_from = "String")]
+    pub(crate) storage_class: StorageClass,
+    /// `in_progress` or `restored`, once a glacial file's restore was asked for.
+    pub(crate) restore_status: Option<String>,
+    /// When the restored copy stops being readable.
+    pub(crate) restore_expires_at: Option<chrono::NaiveDateTime>,
+    /// Whether the file can be downloaded now: it isn't glacial, or was restored.
+    pub(crate) readable: bool,
+}
+
+/// What asking for restores did.
+#[derive(Serialize, ToSchema)]
+pub(crate) struct ArchiveRestore {
+    pub(crate) files: Vec<ArchivedFile>,
+    /// Files whose restore storage refused, left as they were.
+    pub(crate) failed: Vec<FileFailure>,
+}
@@ -0,0 +1,62 @@
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use utoipa::{IntoParams, ToSchema};
+
+/// One operation on a file, as recorded in the audit log.
+#[derive(Serialize, FromRow, ToSchema)]
+pub(crate) struct AuditEntry {
+    pub(crate) id: i64,
+    /// The owner of the file.
+    pub(crate) user_id: i32,
+    /// Who acted: the owner, an admin, or `None` for a download through a share link
+    /// and for a file the server quarantined.
+    pub(crate) username: Option<String>,
+    pub(crate) device_id: Option<String>,
+    pub(crate) ip: Option<String>,
+    /// `insert`, `update`, `move`, `delete`, `restore`, `share` or `download`.
+    pub(crate) operation: String,
+    pub(crate) file_path: String,
+    pub(crate) created_at: chrono::NaiveDateTime,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub(crate) struct AuditParams {
+    /// Only this file's history; every file's when unset.
+    pub(crate) path: Option<String>,
+    /// Whose files to look at; the caller's when unset. Operators and admins only.
+    pub(crate) user_id: Option<i32>,
+    pub(crate) limit: Option<i64>,
+    pub(crate) offset: Option<i64>,
+}
+
+/// One thing done to the user's files, for a "recent activity" view.
+#[derive(Serialize, FromRow, ToSchema)]
+pub(crate) struct ActivityEntry {
+    pub(crate) id: i64,
+    /// `insert`, `update`, `move`, `delete`, `restore` or `share`.
+    pub(crate) operation: String,
+    pub(crate) file_path: String,
+    /// Who acted: the user, a collaborator, an admin, or `None` for the server.
+    pub(crate) username: Option<String>,
+    pub(crate) device_id: Option<String>,
+    /// The name the device registered with, when it is one of the user's own.
+    pub(crate) device_name: Option<String>,
+    pub(crate) created_at: chrono::NaiveDateTime,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct ActivityPage {
+    /// Newest first.
+    pub(crate) data: Vec<ActivityEntry>,
+    /// Pass back as `before` for the next, older page; `None` on the last one.
+    pub(crate) next_before: Option<i64>,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub(crate) struct ActivityParams {
+    pub(crate) limit: Option<i64>,
+    /// Only entries older than this id, the `next_before` of the previous page.
+    pub(crate) before: Option<i64>,
+}
@@ -0,0 +1,154 @@
+use std::net::IpAddr;
+
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use utoipa::ToSchema;
+
+/// What a user may do. Each role may do everything the ones before it can.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, sqlx::Type, ToSchema)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "text", rename_all = "lowercase")]
+pub(crate) enum Role {
+    #[default]
+    User,
+    /// Can view every user, their storage and the job queues under `/admin`.
+    Operator,
+    /// Can also manage users and tokens, delete anyone's files and revoke any share.
+    Admin,
+}
+
+impl Role {
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            Role::User => "user",
+            Role::Operator => "operator",
+            Role::Admin => "admin",
+        }
+    }
+}
+
+/// What an API token may be used for. Session tokens always have the full scope.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize, sqlx::Type, ToSchema)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "text", rename_all = "lowercase")]
+pub(crate) enum TokenScope {
+    /// Everything the token's user may do.
+    #[default]
+    Full,
+    /// Listing, searching and downloading, but no changes.
+    Read,
+    /// Adding and updating files, for backup agents that should never read or delete.
+    Upload,
+}
+
+impl TokenScope {
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            TokenScope::Full => "full",
+            TokenScope::Read => "read",
+            TokenScope::Upload => "upload",
+        }
+    }
+}
+
+/// The user behind a request's bearer token.
+#[derive(Clone, Debug, FromRow)]
+pub(crate) struct AuthUser {
+    pub(crate) user_id: i32,
+    pub(crate) username: String,
+    pub(crate) role: Role,
+    /// What the credential the request came with allows.
+    #[sqlx(default)]
+    pub(crate) scope: TokenScope,
+    /// Client-chosen `X-Device-Id`, used to tell a user's devices apart in change events.
+    #[sqlx(default)]
+    pub(crate) device_id: Option<String>,
+    /// The address the request came from, for the audit log.
+    #[sqlx(skip)]
+    pub(crate) ip: Option<IpAddr>,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub(crate) struct CreateTokenRequest {
+    pub(crate) username: String,
+    /// The role of the user if this creates them; an existing user's is kept.
+    #[serde(default)]
+    pub(crate) role: Role,
+    #[serde(default)]
+    pub(crate) scope: TokenScope,
+    pub(crate) name: Option<String>,
+}
+
+/// A token the caller mints for themselves, such as for a backup script.
+#[derive(Deserialize, ToSchema)]
+pub(crate) struct CreateApiTokenRequest {
+    /// A label to tell the caller's tokens apart by.
+    pub(crate) name: Option<String>,
+    #[serde(default)]
+    pub(crate) scope: TokenScope,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub(crate) struct CreateUserRequest {
+    pub(crate) username: String,
+    pub(crate) password: String,
+    #[serde(default)]
+    pub(crate) role: Role,
+    /// Overrides the server's default quota for this user.
+    pub(crate) quota_bytes: Option<i64>,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub(crate) struct LoginRequest {
+    pub(crate) username: String,
+    pub(crate) password: String,
+    /// The current code of the user's authenticator app, once they have enabled
+    /// two-factor authentication.
+    pub(crate) totp_code: Option<String>,
+}
+
+/// A code from the caller's authenticator app.
+#[derive(Deserialize, ToSchema)]
+pub(crate) struct TotpCodeRequest {
+    pub(crate) code: String,
+}
+
+/// A new TOTP secret, to be added to an authenticator app and confirmed with
+/// `/auth/2fa/verify` before logins ask for codes.
+#[derive(Serialize, ToSchema)]
+pub(crate) struct TotpEnrollment {
+    /// The base32 secret, for apps that can't scan `otpauth_url`.
+    pub(crate) secret: String,
+    /// The `otpauth://` URI to show as a QR code.
+    pub(crate) otpauth_url: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Claims {
+    pub(crate) sub: i32,
+    pub(crate) username: String,
+    pub(crate) role: Role,
+    pub(crate) iat: i64,
+    pub(crate) exp: i64,
+}
+
+/// What a `/download/token` token grants: a download of one file, until `exp`.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct DownloadClaims {
+    pub(crate) sub: i32,
+    pub(crate) username: String,
+    pub(crate) role: Role,
+    pub(crate) path: String,
+    pub(crate) exp: i64,
+}
+
+#[derive(Serialize, FromRow, ToSchema)]
+pub(crate) struct TokenInfo {
+    pub(crate) id: i32,
+    pub(crate) username: String,
+    pub(crate) name: Option<String>,
+    pub(crate) scope: TokenScope,
+    pub(crate) created_at: Option<chrono::NaiveDateTime>,
+    pub(crate) last_used_at: Option<chrono::NaiveDateTime>,
+    pub(crate) revoked_at: Option<chrono::NaiveDateTime>,
+}
@@ -0,0 +1,117 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+use sqlx::{types::Json, FromRow};
+
+use crate::{db::TextList, models::Role};
+
+/// The format `/export` writes and `/import` reads.
+pub(crate) const CATALOG_VERSION: u32 = 1;
+
+#[derive(Deserialize)]
+pub(crate) struct ExportParams {
+    /// `json` for one document, the default, or `ndjson` for one record a line.
+    pub(crate) format: Option<String>,
+}
+
+/// A user in a catalog. Users are matched by name, since ids differ between
+/// deployments.
+#[derive(Serialize, Deserialize, FromRow)]
+pub(crate) struct CatalogUser {
+    pub(crate) username: String,
+    pub(crate) role: Role,
+    #[serde(default)]
+    pub(crate) quota_bytes: Option<i64>,
+    /// Carried so users can log in with their passwords after a migration.
+    #[serde(default)]
+    pub(crate) password_hash: Option<String>,
+}
+
+/// A live file in a catalog: its row, tags and metadata, and the key its content
+/// is stored under.
+#[derive(Serialize, Deserialize, FromRow)]
+pub(crate) struct CatalogFile {
+    pub(crate) username: String,
+    pub(crate) file_path: String,
+    #[serde(default)]
+    pub(crate) file_name: Option<String>,
+    pub(crate) file_hash: String,
+    pub(crate) file_size: i64,
+    pub(crate) modified_time: i64,
+    #[serde(default)]
+    pub(crate) content_type: Option<String>,
+    #[serde(default)]
+    pub(crate) etag: Option<String>,
+    pub(crate) system_path: String,
+    #[serde(default)]
+    pub(crate) created_at: Option<chrono::NaiveDateTime>,
+    #[serde(default)]
+    #[sqlx(try_from = "TextList")]
+    pub(crate) tags: Vec<String>,
+    #[serde(default)]
+    pub(crate) metadata: Option<Json<BTreeMap<String, String>>>,
+}
+
+/// An active share link in a catalog. Only the hash of its token is stored, which
+/// is enough for the link to keep working.
+#[derive(Serialize, Deserialize, FromRow)]
+pub(crate) struct CatalogShare {
+    pub(crate) username: String,
+    pub(crate) file_path: String,
+    #[serde(default)]
+    pub(crate) folder: bool,
+    pub(crate) token_hash: String,
+    #[serde(default)]
+    pub(crate) password_hash: Option<String>,
+    #[serde(default)]
+    pub(crate) expires_at: Option<chrono::NaiveDateTime>,
+    #[serde(default)]
+    pub(crate) created_at: Option<chrono::NaiveDateTime>,
+}
+
+/// A whole catalog, as `format=json` writes it.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Catalog {
+    pub(crate) version: u32,
+    #[serde(default)]
+    pub(crate) exported_at: Option<chrono::NaiveDateTime>,
+    #[serde(default)]
+    pub(crate) users: Vec<CatalogUser>,
+    #[serde(default)]
+    pub(crate) files: Vec<CatalogFile>,
+    #[serde(default)]
+    pub(crate) shares: Vec<CatalogShare>,
+}
+
+/// One line of a catalog as `format=ndjson` writes it. The header comes first.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(crate) enum CatalogRecord {
+    Header {
+        version: u32,
+        #[serde(default)]
+        exported_at: Option<chrono::NaiveDateTime>,
+    },
+    User(CatalogUser),
+    File(CatalogFile),
+    Share(CatalogShare),
+}
+
+/// A catalog entry `/import` left out, and why.
+#[derive(Serialize)]
+pub(crate) struct ImportSkip {
+    pub(crate) username: String,
+    pub(crate) file_path: String,
+    pub(crate) reason: String,
+}
+
+#[derive(Serialize, Default)]
+pub(crate) struct ImportReport {
+    pub(crate) users_created: usize,
+    /// Users that already existed by name, whose files were added to theirs.
+    pub(crate) users_existing: usize,
+    pub(crate) files_imported: usize,
+    pub(crate) files_skipped: Vec<ImportSkip>,
+    pub(crate) shares_imported: usize,
+    pub(crate) shares_skipped: Vec<ImportSkip>,
+}
@@ -0,0 +1,70 @@
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use utoipa::{IntoParams, ToSchema};
+
+use crate::db::TextList;
+
+#[derive(Deserialize, ToSchema)]
+pub(crate) struct RegisterDeviceRequest {
+    /// The `X-Device-Id` the client already uses; one is generated when omitted.
+    pub(crate) device_id: Option<String>,
+    pub(crate) name: String,
+    pub(crate) platform: Option<String>,
+    /// Sync profile limiting what `/changes` and `/get` show this device.
+    pub(crate) profile: Option<String>,
+}
+
+#[derive(Serialize, FromRow, ToSchema)]
+pub(crate) struct DeviceInfo {
+    pub(crate) device_id: String,
+    pub(crate) name: String,
+    pub(crate) platform: Option<String>,
+    pub(crate) profile: Option<String>,
+    /// Last `/changes` cursor the device acknowledged by passing it as `since`.
+    pub(crate) cursor: i64,
+    /// Changes made since `cursor`; the device is up to date at zero.
+    pub(crate) behind: i64,
+    pub(crate) created_at: Option<chrono::NaiveDateTime>,
+    pub(crate) last_seen_at: Option<chrono::NaiveDateTime>,
+    pub(crate) last_sync_at: Option<chrono::NaiveDateTime>,
+}
+
+/// Include and exclude globs matched against `file_path`. A pattern without a `/`
+/// matches a file or folder name at any depth, one with a `/` is anchored at the
+/// root, and matching a folder matches everything inside it. With no include
+/// patterns everything is included; excludes win over includes.
+#[derive(Serialize, Deserialize, FromRow, ToSchema)]
+pub(crate) struct SyncProfile {
+    pub(crate) name: String,
+    #[serde(default)]
+    #[sqlx(rename = "include_patterns", try_from = "TextList")]
+    pub(crate) include: Vec<String>,
+    #[serde(default)]
+    #[sqlx(rename = "exclude_patterns", try_from = "TextList")]
+    pub(crate) exclude: Vec<String>,
+    #[serde(skip_deserializing)]
+    pub(crate) updated_at: Option<chrono::NaiveDateTime>,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub(crate) struct LockRequest {
+    pub(crate) file_path: String,
+    /// Length of the lease; `DEFAULT_LOCK_TTL_SECS` when unset.
+    pub(crate) expires_in_secs: Option<i64>,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub(crate) struct UnlockParams {
+    pub(crate) file_path: String,
+}
+
+/// A lock on one of the user's files, held by the device that took it.
+#[derive(Serialize, FromRow, ToSchema)]
+pub(crate) struct FileLock {
+    pub(crate) file_path: String,
+    pub(crate) device_id: String,
+    /// When the holder first took the lock; renewing it doesn't move this.
+    pub(crate) acquired_at: Option<chrono::NaiveDateTime>,
+    pub(crate) expires_at: chrono::NaiveDateTime,
+}
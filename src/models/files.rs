@@ -0,0 +1,669 @@
+use std::collections::{BTreeMap, HashMap};
+
+use serde::{Deserialize, Serialize};
+use sqlx::{types::Json, FromRow};
+use utoipa::{IntoParams, ToSchema};
+
+use crate::{db::TextList, models::FileFailure};
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default, FromRow)]
+pub(crate) struct FileEntry {
+    pub(crate) file_name: String,
+    pub(crate) file_path: String,
+    pub(crate) file_hash: Option<String>,
+    pub(crate) file_size: i64,
+    pub(crate) modified_time: i64,
+    #[serde(default)]
+    #[sqlx(default)]
+    pub(crate) content_type: Option<String>,
+    #[serde(default)]
+    #[sqlx(default)]
+    pub(crate) etag: Option<String>,
+    /// Server-side timestamps, set by the database rather than the client.
+    #[serde(default, skip_deserializing)]
+    #[sqlx(default)]
+    pub(crate) created_at: Option<chrono::NaiveDateTime>,
+    #[serde(default, skip_deserializing)]
+    #[sqlx(default)]
+    pub(crate) updated_at: Option<chrono::NaiveDateTime>,
+    /// Counts the changes to the file's content and path, starting at 1.
+    #[serde(default, skip_deserializing)]
+    #[sqlx(default)]
+    pub(crate) version: Option<i64>,
+    /// Set on insert results that matched an existing row and were not re-uploaded.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    #[sqlx(default)]
+    pub(crate) skipped: bool,
+    /// For updates: the `modified_time` and hash the client last saw on the server.
+    /// When given and the server copy has moved on, the update is reported as a conflict.
+    #[serde(default, skip_serializing)]
+    #[sqlx(default)]
+    pub(crate) base_modified_time: Option<i64>,
+    #[serde(default, skip_serializing)]
+    #[sqlx(default)]
+    pub(crate) base_hash: Option<String>,
+    /// For updates and deletes: the `version` the client last saw on the server.
+    /// When given and the server copy has moved on, the file is reported as a
+    /// conflict instead.
+    #[serde(default, skip_serializing)]
+    #[sqlx(default)]
+    pub(crate) base_version: Option<i64>,
+    /// Set on insert results that stored a conflicting update as a conflicted copy:
+    /// the path the update was meant for.
+    #[serde(default, skip_deserializing, skip_serializing_if = "Option::is_none")]
+    #[sqlx(default)]
+    pub(crate) conflict_copy_of: Option<String>,
+    /// For moves of a `/sync`: the path the file is moved from.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[sqlx(default)]
+    pub(crate) from_path: Option<String>,
+    /// Labels attached to the file, e.g. `favorite`. On an insert or update a list
+    /// replaces the file's tags, and leaving it out keeps them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[sqlx(default, try_from = "TextList")]
+    pub(crate) tags: Option<Vec<String>>,
+    /// Key-value metadata attached to the file, replaced the same way as `tags`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[sqlx(default)]
+    pub(crate) metadata: Option<Json<BTreeMap<String, String>>>,
+    /// For inserts and updates of a `/sync`: the client-assigned id of the part,
+    /// named `files:<upload_id>`, holding the file's bytes.
+    #[serde(default, skip_serializing)]
+    #[sqlx(skip)]
+    pub(crate) upload_id: Option<String>,
+    /// For inserts of a `/sync`: the file is recorded without bytes on purpose.
+    #[serde(default, skip_serializing)]
+    #[sqlx(skip)]
+    pub(crate) metadata_only: bool,
+    /// For inserts and updates of a `/sync`: the bytes are copied from a file of
+    /// the caller's with the same `file_hash` and `file_size`, which
+    /// `/sync/precheck` tells of, instead of being uploaded.
+    #[serde(default, skip_serializing)]
+    #[sqlx(skip)]
+    pub(crate) link: bool,
+    /// For inserts and updates of a `/sync`: where the file's bytes are written.
+    #[serde(skip)]
+    #[sqlx(skip)]
+    pub(crate) storage_key: Option<String>,
+    /// For entries of a `/sync`: why the file is refused before anything is done
+    /// with it, by the upload policy or for a path colliding with another.
+    #[serde(skip)]
+    #[sqlx(skip)]
+    pub(crate) refusal: Option<String>,
+}
+
+/// One file as `/file` reports it.
+#[derive(Serialize, FromRow, ToSchema)]
+pub(crate) struct FileInfo {
+    #[serde(flatten)]
+    #[sqlx(flatten)]
+    pub(crate) file: FileEntry,
+    /// The file's place in the change feed, which moves on with every change to it,
+    /// so a client that kept it can tell whether its copy is still current.
+    pub(crate) version_id: i64,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub(crate) struct BatchDeleteRequest {
+    pub(crate) paths: Vec<String>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct BatchDeleteResponse {
+    pub(crate) success: Vec<String>,
+    pub(crate) failure: Vec<FileFailure>,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub(crate) struct DownloadUrlsRequest {
+    pub(crate) paths: Vec<String>,
+}
+
+/// The files of a `/download/archive`: the listed `paths`, or everything under `prefix`.
+#[derive(Deserialize, ToSchema)]
+pub(crate) struct ArchiveRequest {
+    pub(crate) paths: Option<Vec<String>>,
+    pub(crate) prefix: Option<String>,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub(crate) struct DownloadTokenRequest {
+    pub(crate) file_path: String,
+    /// How long the token works for, up to `DOWNLOAD_TOKEN_EXPIRY_SECS`, which is
+    /// also the default.
+    pub(crate) expires_in_secs: Option<i64>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct DownloadUrl {
+    pub(crate) url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) expires_in_seconds: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) error: Option<String>,
+}
+
+/// One file of a `/download/batch`: its URL and what to check the download
+/// against, or why it has none.
+#[derive(Serialize, ToSchema)]
+pub(crate) struct BatchDownload {
+    pub(crate) file_path: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) file_size: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) file_hash: Option<String>,
+    /// The `ETag` `/download` would answer with, for later conditional requests.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) etag: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) content_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) error: Option<String>,
+}
+
+impl BatchDownload {
+    pub(crate) fn failed(file_path: String, error: String) -> Self {
+        Self {
+            file_path,
+            url: None,
+            file_size: None,
+            file_hash: None,
+            etag: None,
+            content_type: None,
+            error: Some(error),
+        }
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct BatchDownloadResponse {
+    /// One entry per requested path, in request order.
+    pub(crate) data: Vec<BatchDownload>,
+    pub(crate) expires_in_seconds: u64,
+}
+
+#[derive(Serialize, Default, ToSchema)]
+pub(crate) struct GetAllResponse {
+    pub(crate) data: Option<Vec<FileEntry>>,
+    /// Files deleted since the requested `since`, so clients can drop local copies.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) deleted: Option<Vec<Tombstone>>,
+    /// Unix time the listing was taken; pass it back as `since` on the next call.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) server_time: Option<i64>,
+    /// Number of files matching a full listing's filter, across all pages.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) total: Option<i64>,
+    /// Folders other users shared with the caller, whose files are listed by
+    /// passing their owner as `owner`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) shared: Option<Vec<FolderGrant>>,
+    pub(crate) error: Option<String>,
+}
+
+/// What a client holds, as the digests of its folders, for `/get/diff` to compare
+/// against the server's. A folder's digest is the lowercase hex SHA-256 of one line
+/// per child, sorted as bytes: `d <name>\t<digest>\n` for each subfolder and
+/// `f <name>\t<file_hash>\n` for each file, the hash empty when it has none.
+#[derive(Deserialize, ToSchema)]
+pub(crate) struct DiffRequest {
+    /// Digests by folder path, the root being `""`. Folders left out are taken to
+    /// differ, so a client can stop at whatever depth it likes.
+    pub(crate) digests: HashMap<String, String>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct DiffResponse {
+    /// The digest of the server's root folder; a client is in sync once its own
+    /// matches.
+    pub(crate) digest: String,
+    /// Folders whose direct files are all in `data`: the client's files directly in
+    /// them that aren't listed are gone from the server.
+    pub(crate) folders: Vec<String>,
+    /// The files directly in `folders`.
+    pub(crate) data: Vec<FileEntry>,
+    /// Folders the client gave a digest of that hold no files on the server.
+    pub(crate) removed: Vec<String>,
+    /// Unix time the comparison was made.
+    pub(crate) server_time: i64,
+}
+
+#[derive(Serialize, FromRow, ToSchema)]
+pub(crate) struct Tombstone {
+    pub(crate) file_path: String,
+    pub(crate) deleted_at: chrono::NaiveDateTime,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub(crate) struct GetAllParams {
+    pub(crate) since: Option<i64>,
+    /// Only list files whose path starts with this.
+    pub(crate) prefix: Option<String>,
+    #[serde(default)]
+    pub(crate) sort: ListSort,
+    #[serde(default)]
+    pub(crate) order: SortOrder,
+    /// Page size for a full listing; everything is returned when unset.
+    pub(crate) limit: Option<i64>,
+    pub(crate) offset: Option<i64>,
+    /// Lists this user's files in the folders they share with the caller instead.
+    pub(crate) owner: Option<String>,
+    #[serde(default)]
+    pub(crate) format: ListFormat,
+}
+
+/// How `/get` sends a full listing: one JSON document, or with `ndjson` one file per
+/// line, streamed from the database as it's read.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum ListFormat {
+    #[default]
+    Json,
+    Ndjson,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ListSort {
+    #[default]
+    Path,
+    Size,
+    ModifiedTime,
+}
+
+impl ListSort {
+    pub(crate) fn column(self) -> &'static str {
+        match self {
+            ListSort::Path => "file_path",
+            ListSort::Size => "file_size",
+            ListSort::ModifiedTime => "modified_time",
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+impl SortOrder {
+    pub(crate) fn keyword(self) -> &'static str {
+        match self {
+            SortOrder::Asc => "ASC",
+            SortOrder::Desc => "DESC",
+        }
+    }
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub(crate) struct ChangesParams {
+    /// `cursor` from the previous `/changes` response; omit to start from the beginning.
+    pub(crate) since: Option<i64>,
+    pub(crate) limit: Option<i64>,
+}
+
+/// One entry of the change feed: the file's current row, or a deletion.
+#[derive(Serialize, ToSchema)]
+pub(crate) struct Change {
+    pub(crate) change_seq: i64,
+    pub(crate) file_path: String,
+    pub(crate) deleted: bool,
+    /// When a deletion happened.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) deleted_at: Option<chrono::NaiveDateTime>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) file: Option<FileEntry>,
+}
+
+#[derive(FromRow)]
+pub(crate) struct ChangedFile {
+    pub(crate) change_seq: i64,
+    #[sqlx(flatten)]
+    pub(crate) file: FileEntry,
+}
+
+#[derive(Serialize, Default, ToSchema)]
+pub(crate) struct ChangesResponse {
+    pub(crate) data: Option<Vec<Change>>,
+    /// Pass back as `since` to get only what changed after this page.
+    pub(crate) cursor: Option<i64>,
+    /// More changes are already waiting past `cursor`.
+    pub(crate) has_more: bool,
+    pub(crate) error: Option<String>,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub(crate) struct SearchParams {
+    /// Matched anywhere in the file's path or original name.
+    pub(crate) q: Option<String>,
+    /// Comma-separated extensions, without the dot, e.g. `jpg,png`.
+    pub(crate) ext: Option<String>,
+    pub(crate) min_size: Option<i64>,
+    pub(crate) max_size: Option<i64>,
+    /// Unix timestamps bounding `modified_time`, inclusive.
+    pub(crate) modified_after: Option<i64>,
+    pub(crate) modified_before: Option<i64>,
+    pub(crate) limit: Option<i64>,
+    pub(crate) offset: Option<i64>,
+}
+
+impl SearchParams {
+    /// The lowercased extensions of `ext`, or `None` when it names none.
+    pub(crate) fn extensions(&self) -> Option<Vec<String>> {
+        let extensions: Vec<String> = self
+            .ext
+            .as_deref()?
+            .split(',')
+            .map(|ext| ext.trim().trim_start_matches('.').to_lowercase())
+            .filter(|ext| !ext.is_empty())
+            .collect();
+        (!extensions.is_empty()).then_some(extensions)
+    }
+
+    /// Whether anything besides `q` narrows the search.
+    pub(crate) fn has_filters(&self) -> bool {
+        self.extensions().is_some()
+            || self.min_size.is_some()
+            || self.max_size.is_some()
+            || self.modified_after.is_some()
+            || self.modified_before.is_some()
+    }
+}
+
+#[derive(Serialize, FromRow, ToSchema)]
+pub(crate) struct FileVersion {
+    pub(crate) version: i32,
+    pub(crate) file_path: String,
+    pub(crate) file_hash: Option<String>,
+    pub(crate) file_size: i64,
+    pub(crate) modified_time: i64,
+    pub(crate) content_type: Option<String>,
+    pub(crate) etag: Option<String>,
+    pub(crate) s3_key: String,
+    pub(crate) created_at: Option<chrono::NaiveDateTime>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct VersionsResponse {
+    pub(crate) data: Option<Vec<FileVersion>>,
+    pub(crate) error: Option<String>,
+}
+
+/// Picks the revision to restore, by version number or by content hash. With a
+/// hash, the newest revision holding that content wins.
+#[derive(Deserialize, ToSchema)]
+pub(crate) struct RevertRequest {
+    pub(crate) file_path: String,
+    pub(crate) version: Option<i32>,
+    pub(crate) file_hash: Option<String>,
+}
+
+#[derive(Serialize, FromRow, ToSchema)]
+pub(crate) struct TrashEntry {
+    pub(crate) id: i32,
+    pub(crate) file_path: String,
+    pub(crate) file_hash: Option<String>,
+    pub(crate) file_size: i64,
+    pub(crate) modified_time: i64,
+    pub(crate) content_type: Option<String>,
+    pub(crate) deleted_at: chrono::NaiveDateTime,
+    /// When the purge task will delete the entry for good.
+    pub(crate) expires_at: chrono::NaiveDateTime,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub(crate) struct TrashRestoreRequest {
+    pub(crate) id: i32,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub(crate) struct TaggedParams {
+    pub(crate) tag: Option<String>,
+    pub(crate) limit: Option<i64>,
+    pub(crate) offset: Option<i64>,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub(crate) struct ListDirParams {
+    /// Folder to list; the root when unset.
+    pub(crate) dir: Option<String>,
+}
+
+/// A subfolder in a `/list` response, summarised over everything beneath it.
+#[derive(Serialize, FromRow, ToSchema)]
+pub(crate) struct FolderEntry {
+    pub(crate) name: String,
+    pub(crate) path: String,
+    pub(crate) file_count: i64,
+    pub(crate) total_size: i64,
+    pub(crate) modified_time: Option<i64>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct DirListing {
+    pub(crate) dir: String,
+    pub(crate) folders: Vec<FolderEntry>,
+    pub(crate) files: Vec<FileEntry>,
+}
+
+/// Moves a file, or a folder with everything under it, to another path.
+#[derive(Deserialize, ToSchema)]
+pub(crate) struct MoveRequest {
+    pub(crate) from: String,
+    pub(crate) to: String,
+}
+
+/// Renames the last segment of a file or folder path, keeping it in place.
+#[derive(Deserialize, ToSchema)]
+pub(crate) struct RenameRequest {
+    pub(crate) file_path: String,
+    pub(crate) new_name: String,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub(crate) struct CreateShareRequest {
+    pub(crate) file_path: String,
+    /// The link stops working this many seconds after creation; never when unset.
+    pub(crate) expires_in_secs: Option<i64>,
+    /// Required from anyone opening the link, when set.
+    pub(crate) password: Option<String>,
+    /// Shares the folder at `file_path`, with everything under it, as a gallery
+    /// page instead of a single file.
+    #[serde(default)]
+    pub(crate) folder: bool,
+}
+
+/// What a collaborator may do in a folder shared with them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, sqlx::Type, ToSchema)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "text", rename_all = "lowercase")]
+pub(crate) enum FolderPermission {
+    /// Listing and downloading.
+    Read,
+    /// Syncing changes into the folder as well.
+    Write,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub(crate) struct GrantFolderRequest {
+    pub(crate) folder: String,
+    /// The registered user to share it with.
+    pub(crate) username: String,
+    pub(crate) permission: FolderPermission,
+}
+
+/// A folder shared between two users.
+#[derive(Serialize, FromRow, ToSchema)]
+pub(crate) struct FolderGrant {
+    pub(crate) id: i32,
+    pub(crate) folder: String,
+    /// The other user: the collaborator in `/collaborators`, the owner in `/shared`.
+    pub(crate) username: String,
+    pub(crate) permission: FolderPermission,
+    pub(crate) created_at: chrono::NaiveDateTime,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub(crate) struct ShareParams {
+    pub(crate) password: Option<String>,
+    /// Serves the image's JPEG thumbnail of this size (128, 256 or 512) instead.
+    pub(crate) size: Option<String>,
+}
+
+#[derive(FromRow)]
+pub(crate) struct ShareLink {
+    pub(crate) id: i32,
+    /// The owner of the shared file or folder.
+    pub(crate) user_id: i32,
+    pub(crate) file_path: String,
+    pub(crate) folder: bool,
+    pub(crate) password_hash: Option<String>,
+    /// Revoked, or past its expiry.
+    pub(crate) expired: bool,
+}
+
+/// How one of the caller's share links has been used.
+#[derive(Serialize, FromRow, ToSchema)]
+pub(crate) struct ShareStats {
+    pub(crate) id: i32,
+    pub(crate) file_path: String,
+    pub(crate) folder: bool,
+    pub(crate) password_protected: bool,
+    pub(crate) created_at: Option<chrono::NaiveDateTime>,
+    pub(crate) expires_at: Option<chrono::NaiveDateTime>,
+    pub(crate) revoked_at: Option<chrono::NaiveDateTime>,
+    /// Pages of a folder link opened, and previews of a file link.
+    pub(crate) views: i64,
+    /// Files served through the link in full, not counting range requests past the
+    /// start of a file.
+    pub(crate) downloads: i64,
+    /// The last view or download, if there was one.
+    pub(crate) last_accessed_at: Option<chrono::NaiveDateTime>,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub(crate) struct CreateFileDropRequest {
+    /// The folder dropped files land in, created as they arrive.
+    pub(crate) folder: String,
+    /// The link stops taking files this many seconds after creation.
+    pub(crate) expires_in_secs: i64,
+    /// Largest file the link takes; the server's upload limit applies either way.
+    pub(crate) max_file_bytes: Option<i64>,
+    /// How many files the link takes in all; 1 for a one-shot link.
+    pub(crate) max_files: Option<i32>,
+}
+
+/// One of the caller's file request links.
+#[derive(Serialize, FromRow, ToSchema)]
+pub(crate) struct FileDrop {
+    pub(crate) id: i32,
+    pub(crate) folder: String,
+    pub(crate) created_at: Option<chrono::NaiveDateTime>,
+    pub(crate) expires_at: chrono::NaiveDateTime,
+    pub(crate) revoked_at: Option<chrono::NaiveDateTime>,
+    pub(crate) max_file_bytes: Option<i64>,
+    pub(crate) max_files: Option<i32>,
+    /// Files dropped through the link so far.
+    pub(crate) file_count: i32,
+}
+
+#[derive(FromRow)]
+pub(crate) struct FileDropLink {
+    pub(crate) id: i32,
+    /// The owner of the folder files are dropped into.
+    pub(crate) user_id: i32,
+    pub(crate) folder: String,
+    pub(crate) max_file_bytes: Option<i64>,
+    pub(crate) max_files: Option<i32>,
+    pub(crate) file_count: i32,
+    /// Revoked, or past its expiry.
+    pub(crate) expired: bool,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub(crate) struct DuplicatesParams {
+    /// Also cluster files without a hash that share a name and size. Such matches
+    /// are only likely copies, so they can be deleted but not linked.
+    #[serde(default)]
+    pub(crate) match_names: bool,
+    pub(crate) limit: Option<i64>,
+    pub(crate) offset: Option<i64>,
+}
+
+/// What the files of a duplicate cluster have in common.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum DuplicateMatch {
+    /// The same hash and size.
+    Hash,
+    /// No hash, but the same name and size.
+    NameSize,
+}
+
+#[derive(Serialize, FromRow, ToSchema)]
+pub(crate) struct DuplicateFile {
+    pub(crate) file_path: String,
+    pub(crate) modified_time: i64,
+    pub(crate) content_type: Option<String>,
+}
+
+/// Files that look like copies of each other, oldest first.
+#[derive(Serialize, ToSchema)]
+pub(crate) struct DuplicateCluster {
+    pub(crate) matched_by: DuplicateMatch,
+    pub(crate) file_hash: Option<String>,
+    pub(crate) file_name: Option<String>,
+    pub(crate) file_size: i64,
+    /// What keeping one copy and removing the others would free.
+    pub(crate) reclaimable_bytes: i64,
+    pub(crate) files: Vec<DuplicateFile>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct DuplicatesResponse {
+    pub(crate) data: Vec<DuplicateCluster>,
+    /// Clusters found, across every page.
+    pub(crate) total: usize,
+    /// What resolving every cluster would free.
+    pub(crate) reclaimable_bytes: i64,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum DuplicateAction {
+    /// Delete the copies, as `/delete` would.
+    Delete,
+    /// Keep the copies' paths, but have them share the kept file's stored content.
+    /// Needs deduplicated storage (`STORAGE_DEDUP`).
+    Link,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub(crate) struct ResolveDuplicatesRequest {
+    /// The copy to keep.
+    pub(crate) keep: String,
+    /// Copies of it to delete or link.
+    pub(crate) remove: Vec<String>,
+    pub(crate) action: DuplicateAction,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct ResolveDuplicatesResponse {
+    pub(crate) success: Vec<String>,
+    pub(crate) failure: Vec<FileFailure>,
+    /// Bytes of copies deleted, or linked where they didn't share content already.
+    /// Deleted copies only free their storage once they leave the trash.
+    pub(crate) reclaimed_bytes: i64,
+}
@@ -0,0 +1,96 @@
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use utoipa::{IntoParams, ToSchema};
+
+#[derive(Serialize, FromRow, ToSchema)]
+pub(crate) struct Job {
+    pub(crate) id: i32,
+    pub(crate) kind: String,
+    pub(crate) status: String,
+    pub(crate) progress: i32,
+    pub(crate) total: i32,
+    pub(crate) result: Option<serde_json::Value>,
+    pub(crate) error: Option<String>,
+    pub(crate) created_at: Option<chrono::NaiveDateTime>,
+    pub(crate) updated_at: Option<chrono::NaiveDateTime>,
+}
+
+#[derive(Serialize, Default, ToSchema)]
+pub(crate) struct ReconcileReport {
+    pub(crate) scanned_objects: usize,
+    pub(crate) scanned_rows: usize,
+    /// Stored objects no file, upload, trash entry or version refers to.
+    pub(crate) orphaned_objects: Vec<String>,
+    /// `filehash` rows whose object is missing from storage.
+    pub(crate) dangling_rows: Vec<String>,
+    pub(crate) deleted_objects: usize,
+    pub(crate) deleted_rows: usize,
+}
+
+#[derive(Serialize, Default)]
+pub(crate) struct IntegrityReport {
+    pub(crate) sampled: usize,
+    /// Objects found intact.
+    pub(crate) verified: usize,
+    /// Objects that couldn't be read this time, such as while storage was down.
+    pub(crate) skipped: usize,
+    /// Paths of files whose object is gone.
+    pub(crate) missing: Vec<String>,
+    /// Paths of files whose object has the wrong size or checksum.
+    pub(crate) corrupted: Vec<String>,
+}
+
+#[derive(Serialize, Default)]
+pub(crate) struct MigrationReport {
+    pub(crate) target_bucket: String,
+    pub(crate) scanned_objects: usize,
+    /// Objects copied within S3.
+    pub(crate) copied: usize,
+    /// Objects too large to copy within S3, read out and uploaded again.
+    pub(crate) streamed: usize,
+    /// Objects an earlier run already copied.
+    pub(crate) already_present: usize,
+    pub(crate) failed: Vec<MigrationFailure>,
+}
+
+#[derive(Serialize, Default)]
+pub(crate) struct RerouteReport {
+    /// Files and vault items looked at.
+    pub(crate) scanned: usize,
+    /// Those moved to the bucket their route now names.
+    pub(crate) moved: usize,
+    /// Those changed while being moved, left where they were for a later run.
+    pub(crate) skipped: usize,
+    pub(crate) failed: Vec<MigrationFailure>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct MigrationFailure {
+    pub(crate) key: String,
+    pub(crate) error: String,
+}
+
+/// A failed storage operation waiting in the retry queue.
+#[derive(Serialize, FromRow, ToSchema)]
+pub(crate) struct QueuedRetry {
+    pub(crate) id: i32,
+    /// `delete` or `copy`.
+    pub(crate) kind: String,
+    pub(crate) object_key: String,
+    pub(crate) source_key: Option<String>,
+    /// `pending`, or `dead` once it ran out of attempts.
+    pub(crate) status: String,
+    pub(crate) attempts: i32,
+    pub(crate) last_error: Option<String>,
+    /// When the next attempt is due.
+    pub(crate) run_at: chrono::NaiveDateTime,
+    pub(crate) created_at: Option<chrono::NaiveDateTime>,
+    pub(crate) updated_at: Option<chrono::NaiveDateTime>,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub(crate) struct RetryQueueParams {
+    pub(crate) status: Option<String>,
+    pub(crate) limit: Option<i64>,
+}
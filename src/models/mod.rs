@@ -0,0 +1,33 @@
+mod admin;
+mod archive;
+mod audit;
+mod auth;
+mod catalog;
+mod devices;
+mod files;
+mod jobs;
+mod notifications;
+mod photos;
+mod server;
+mod snapshots;
+mod sync;
+mod uploads;
+mod vault;
+mod webhooks;
+
+pub(crate) use admin::*;
+pub(crate) use archive::*;
+pub(crate) use audit::*;
+pub(crate) use auth::*;
+pub(crate) use catalog::*;
+pub(crate) use devices::*;
+pub(crate) use files::*;
+pub(crate) use jobs::*;
+pub(crate) use notifications::*;
+pub(crate) use photos::*;
+pub(crate) use server::*;
+pub(crate) use snapshots::*;
+pub(crate) use sync::*;
+pub(crate) use uploads::*;
+pub(crate) use vault::*;
+pub(crate) use webhooks::*;
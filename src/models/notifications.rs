@@ -0,0 +1,21 @@
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use utoipa::ToSchema;
+
+use crate::db::TextList;
+
+/// Where a user is notified, and of what. A channel without an address, topic or
+/// token isn't used.
+#[derive(Serialize, Deserialize, FromRow, ToSchema, Default)]
+pub(crate) struct NotificationPreferences {
+    pub(crate) email: Option<String>,
+    /// The ntfy topic published to, which whoever subscribes to it hears.
+    pub(crate) ntfy_topic: Option<String>,
+    /// The device token of the user's phone, as the push service knows it.
+    pub(crate) push_token: Option<String>,
+    /// Any of `quota.near_limit`, `share.accessed`, `integrity.issue` and
+    /// `sync.failing`; every event when empty.
+    #[serde(default)]
+    #[sqlx(try_from = "TextList")]
+    pub(crate) events: Vec<String>,
+}
@@ -0,0 +1,37 @@
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use utoipa::{IntoParams, ToSchema};
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub(crate) struct TimelineParams {
+    /// First day to include, as `YYYY-MM-DD`.
+    pub(crate) from: Option<chrono::NaiveDate>,
+    /// Last day to include, as `YYYY-MM-DD`.
+    pub(crate) to: Option<chrono::NaiveDate>,
+    pub(crate) limit: Option<i64>,
+    pub(crate) offset: Option<i64>,
+}
+
+#[derive(Serialize, FromRow, ToSchema)]
+pub(crate) struct TimelinePhoto {
+    pub(crate) file_path: String,
+    pub(crate) file_size: i64,
+    pub(crate) content_type: Option<String>,
+    pub(crate) etag: Option<String>,
+    /// When the EXIF data says the picture was taken, in the camera's local time, or
+    /// the file's modified time in UTC when it doesn't say.
+    pub(crate) taken_at: chrono::NaiveDateTime,
+    pub(crate) camera_make: Option<String>,
+    pub(crate) camera_model: Option<String>,
+    /// Where the picture was taken, in degrees, north and east positive.
+    pub(crate) latitude: Option<f64>,
+    pub(crate) longitude: Option<f64>,
+}
+
+/// The photos taken on one day, newest first.
+#[derive(Serialize, ToSchema)]
+pub(crate) struct TimelineDay {
+    pub(crate) date: chrono::NaiveDate,
+    pub(crate) photos: Vec<TimelinePhoto>,
+}
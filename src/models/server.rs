@@ -0,0 +1,44 @@
+use serde::Serialize;
+use utoipa::ToSchema;
+
+/// What this server supports, for clients to adapt to before they log in.
+#[derive(Serialize, ToSchema)]
+pub(crate) struct Capabilities {
+    /// The server's release, as in `Cargo.toml`.
+    pub(crate) version: &'static str,
+    /// `/sync` payload versions the server reads.
+    pub(crate) sync_payload_versions: Vec<u32>,
+    /// Largest file accepted, if there's a cap.
+    pub(crate) max_upload_bytes: Option<u64>,
+    /// Largest `/sync` request accepted, if there's a cap.
+    pub(crate) max_request_bytes: Option<u64>,
+    /// Smallest chunk of a resumable upload, other than its last.
+    pub(crate) min_upload_chunk_bytes: usize,
+    /// Largest chunk of a resumable upload.
+    pub(crate) max_upload_chunk_bytes: usize,
+    /// Largest block `/sync/blocks` cuts files into.
+    pub(crate) max_block_bytes: usize,
+    pub(crate) features: Features,
+    /// How clients can authenticate: `password` logins for a session token,
+    /// `totp` codes alongside them, `api_token` bearer tokens, and HTTP `basic`
+    /// auth, for WebDAV only.
+    pub(crate) auth_methods: Vec<&'static str>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct Features {
+    /// Identical content is stored once.
+    pub(crate) dedup: bool,
+    /// Content is encrypted at rest with per-user keys.
+    pub(crate) encryption: bool,
+    pub(crate) compression: bool,
+    /// Uploads are scanned for malware, and infected ones quarantined.
+    pub(crate) scanning: bool,
+    /// Changes can be followed over `/ws` as well as `/events`.
+    pub(crate) websocket: bool,
+    pub(crate) grpc: bool,
+    pub(crate) webdav: bool,
+    /// `/sync/precheck` tells which contents the server has, and `/sync` entries
+    /// can `link` to them instead of sending their bytes.
+    pub(crate) upload_precheck: bool,
+}
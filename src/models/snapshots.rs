@@ -0,0 +1,41 @@
+use serde::Serialize;
+use sqlx::FromRow;
+use utoipa::ToSchema;
+
+use crate::models::FileFailure;
+
+/// A point in time the user's tree can be restored to.
+#[derive(Serialize, FromRow, ToSchema)]
+pub(crate) struct Snapshot {
+    pub(crate) id: i32,
+    pub(crate) created_at: chrono::NaiveDateTime,
+    /// How many files the tree held.
+    pub(crate) files: i64,
+}
+
+/// One file as a snapshot recorded it.
+#[derive(FromRow)]
+pub(crate) struct SnapshotFile {
+    pub(crate) file_path: String,
+    pub(crate) file_name: Option<String>,
+    pub(crate) file_hash: String,
+    pub(crate) file_size: i64,
+    pub(crate) modified_time: i64,
+    pub(crate) content_type: Option<String>,
+    pub(crate) etag: Option<String>,
+}
+
+/// What putting the tree back to a snapshot did.
+#[derive(Serialize, ToSchema)]
+pub(crate) struct SnapshotRestore {
+    /// Files given back the content they had, or brought back where they were gone.
+    pub(crate) restored: Vec<String>,
+    /// Files the snapshot didn't have, moved to the trash.
+    pub(crate) deleted: Vec<String>,
+    /// Files whose content is no longer kept anywhere, left as they are.
+    pub(crate) missing: Vec<String>,
+    /// Files that couldn't be restored or deleted, left as they are.
+    pub(crate) failed: Vec<FileFailure>,
+    /// A snapshot of the tree as it was just before, to undo the restore with.
+    pub(crate) undo_snapshot: i32,
+}
@@ -0,0 +1,591 @@
+use std::collections::HashMap;
+
+use axum::http::StatusCode;
+use serde::{Deserialize, Serialize};
+use sqlx::error::ErrorKind;
+use utoipa::{IntoParams, ToSchema};
+
+use crate::{
+    db::describe_error,
+    models::{FileEntry, FileLock},
+    DB_UNAVAILABLE_ERROR, DUPLICATE_PATH_MESSAGE, FILE_NOT_FOUND_MESSAGE, INSERT_CONFLICT_MESSAGE,
+    INTEGRITY_FAILURE_MESSAGE, LOCKED_MESSAGE, MISSING_UPLOAD_MESSAGE, PATH_COLLISION_MESSAGE, POLICY_VIOLATION_MESSAGE, ROLLED_BACK_MESSAGE, SIZE_MISMATCH_MESSAGE,
+    STORAGE_TIMEOUT_ERROR, STORAGE_UNAVAILABLE_ERROR, UPDATE_CONFLICT_MESSAGE, VERSION_MISMATCH_MESSAGE,
+};
+
+#[derive(Deserialize, Serialize, Hash, Eq, PartialEq, Debug, Clone, Copy, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Operation {
+    Insert,
+    Update,
+    Delete,
+    /// Gives a file a new path without sending its content again. The entry's
+    /// `from_path` is where the file is, and `file_path` where it goes.
+    Move,
+}
+
+pub(crate) type FileSyncPayload = HashMap<Operation, Vec<FileEntry>>;
+
+/// The multipart `payload` field: the files of each operation, plus flags that
+/// apply to all of them.
+#[derive(Deserialize)]
+pub(crate) struct SyncPayload {
+    /// Format of the payload; `None` for clients that predate versioning, which
+    /// send version 1. Clients before version 3 call it `version`.
+    #[serde(default, alias = "version")]
+    pub(crate) schema_version: Option<u32>,
+    /// Store an update that conflicts with the server copy beside it, as
+    /// `name (conflicted copy from <device> <date>).ext`, instead of rejecting it.
+    #[serde(default)]
+    pub(crate) conflict_copies: bool,
+    /// From version 3: every file in one list, each naming its operation.
+    #[serde(default)]
+    pub(crate) operations: Option<Vec<OperationEntry>>,
+    /// Before version 3: the files of each operation, under its name.
+    #[serde(flatten)]
+    pub(crate) by_operation: FileSyncPayload,
+}
+
+/// A file of a version 3 payload, and what to do with it.
+#[derive(Deserialize)]
+pub(crate) struct OperationEntry {
+    pub(crate) op: Operation,
+    #[serde(flatten)]
+    pub(crate) file: FileEntry,
+}
+
+/// What went wrong with a file, for clients to act on. Unlike `error`, which is
+/// worded for people and may change, codes are stable.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum FailureCode {
+    /// The file, or the file a move starts from, doesn't exist.
+    NotFound,
+    /// A file already exists at the path.
+    AlreadyExists,
+    /// The path is listed twice in the payload.
+    DuplicatePath,
+    /// The server copy changed since the update's base.
+    Conflict,
+    /// The server copy is at another version than the `base_version` given.
+    VersionMismatch,
+    /// Another device holds a lock on the file.
+    Locked,
+    /// The upload doesn't match the size or hash the payload gives it.
+    IntegrityFailure,
+    /// No part was sent for the entry's `upload_id`.
+    MissingUpload,
+    /// Storing the files would take the user over their storage quota.
+    QuotaExceeded,
+    /// The entry is malformed, such as a move without `from_path`.
+    InvalidRequest,
+    /// The file was fine, but another file of an atomic sync failed.
+    RolledBack,
+    /// Storage failed transiently; retrying may work.
+    StorageUnavailable,
+    /// The database failed transiently; retrying may work.
+    DatabaseUnavailable,
+    /// The upload policy refuses the file, by its extension, path or size.
+    PolicyViolation,
+    /// The path is spelled differently from another, existing or in the payload,
+    /// that it stands for once normalized.
+    PathCollision,
+    /// Anything else.
+    Internal,
+}
+
+impl FailureCode {
+    /// The code of a failure the server worded as `error`.
+    pub(crate) fn of(error: &str) -> Self {
+        let prefixes = [
+            (ROLLED_BACK_MESSAGE, Self::RolledBack),
+            (POLICY_VIOLATION_MESSAGE, Self::PolicyViolation),
+            (PATH_COLLISION_MESSAGE, Self::PathCollision),
+            (FILE_NOT_FOUND_MESSAGE, Self::NotFound),
+            (INSERT_CONFLICT_MESSAGE, Self::AlreadyExists),
+            (DUPLICATE_PATH_MESSAGE, Self::DuplicatePath),
+            (SIZE_MISMATCH_MESSAGE, Self::IntegrityFailure),
+            (INTEGRITY_FAILURE_MESSAGE, Self::IntegrityFailure),
+            (MISSING_UPLOAD_MESSAGE, Self::MissingUpload),
+            (DB_UNAVAILABLE_ERROR, Self::DatabaseUnavailable),
+            (STORAGE_TIMEOUT_ERROR, Self::StorageUnavailable),
+            (STORAGE_UNAVAILABLE_ERROR, Self::StorageUnavailable),
+        ];
+        prefixes
+            .into_iter()
+            .find(|(prefix, _)| error.starts_with(prefix))
+            .map_or(Self::Internal, |(_, code)| code)
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct FileFailure {
+    pub(crate) file_path: String,
+    pub(crate) code: FailureCode,
+    pub(crate) error: String
+}
+
+impl FileFailure {
+    /// A failure with the code its message stands for.
+    pub(crate) fn new(file_path: String, error: String) -> Self {
+        Self { file_path, code: FailureCode::of(&error), error }
+    }
+
+    /// A failure caused by a database error, coded by the constraint it broke, if any.
+    pub(crate) fn from_db(file_path: String, err: &sqlx::Error) -> Self {
+        let error = describe_error(err);
+        let code = match err {
+            sqlx::Error::Database(e) => match e.kind() {
+                ErrorKind::UniqueViolation => FailureCode::AlreadyExists,
+                ErrorKind::NotNullViolation | ErrorKind::CheckViolation => FailureCode::InvalidRequest,
+                _ => FailureCode::of(&error),
+            },
+            _ => FailureCode::of(&error),
+        };
+        Self { file_path, code, error }
+    }
+}
+
+/// An update or delete refused because the server copy changed since the client's base.
+#[derive(Serialize, ToSchema)]
+pub(crate) struct FileConflict {
+    pub(crate) file_path: String,
+    /// `version_mismatch` when the `base_version` given is out of date, and
+    /// `conflict` when another part of the base is.
+    pub(crate) code: FailureCode,
+    pub(crate) error: String,
+    /// The `base_version` the client gave, if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) base_version: Option<i64>,
+    /// The server's current version of the file.
+    pub(crate) server: Box<FileEntry>,
+}
+
+impl FileConflict {
+    /// The conflict between the client's `file` and the `server` copy.
+    pub(crate) fn new(file: &FileEntry, server: FileEntry) -> Self {
+        let (code, error) = if file.base_version.is_some_and(|v| server.version != Some(v)) {
+            (FailureCode::VersionMismatch, VERSION_MISMATCH_MESSAGE)
+        } else {
+            (FailureCode::Conflict, UPDATE_CONFLICT_MESSAGE)
+        };
+        Self {
+            file_path: file.file_path.clone(),
+            code,
+            error: error.to_string(),
+            base_version: file.base_version,
+            server: Box::new(server),
+        }
+    }
+}
+
+/// An update refused because another device holds a lock on the file.
+#[derive(Serialize, ToSchema)]
+pub(crate) struct FileLocked {
+    pub(crate) file_path: String,
+    /// Always `locked`.
+    pub(crate) code: FailureCode,
+    pub(crate) error: String,
+    pub(crate) lock: FileLock,
+}
+
+impl FileLocked {
+    pub(crate) fn new(file_path: String, lock: FileLock) -> Self {
+        Self { file_path, code: FailureCode::Locked, error: LOCKED_MESSAGE.to_string(), lock }
+    }
+}
+
+/// Why one file of a sync operation didn't apply.
+pub(crate) enum OperationError {
+    Failure(FileFailure),
+    Conflict(FileConflict),
+    Locked(FileLocked),
+}
+
+impl From<FileFailure> for OperationError {
+    fn from(failure: FileFailure) -> Self {
+        Self::Failure(failure)
+    }
+}
+
+#[derive(Serialize, Default, ToSchema)]
+pub(crate) struct OperationResult {
+    pub(crate) success: Vec<FileEntry>,
+    pub(crate) failure: Vec<FileFailure>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub(crate) conflict: Vec<FileConflict>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub(crate) locked: Vec<FileLocked>,
+}
+
+pub(crate) type SyncResponse = HashMap<Operation, OperationResult>;
+
+/// What the storage backend reported for a file uploaded in this request.
+pub(crate) struct StoredObject {
+    pub(crate) content_type: String,
+    pub(crate) etag: Option<String>,
+    /// Hex SHA-256 of the bytes as they were received.
+    pub(crate) sha256: String,
+    /// Number of bytes received.
+    pub(crate) size: i64,
+}
+
+/// What an `Insert` does when a row already exists at its `file_path`.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum OnConflict {
+    #[default]
+    Fail,
+    Update,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub(crate) struct SyncParams {
+    #[serde(default, rename = "async")]
+    pub(crate) run_async: bool,
+    /// Check the payload and its uploads, quota included, and report what would
+    /// happen without storing or recording anything.
+    #[serde(default)]
+    pub(crate) dry_run: bool,
+    #[serde(default)]
+    pub(crate) on_conflict: OnConflict,
+    /// Apply the whole payload in one transaction, all or nothing.
+    #[serde(default)]
+    pub(crate) atomic: bool,
+    /// Apply the payload to this user's files, in folders they share with the
+    /// caller for writing.
+    pub(crate) owner: Option<String>,
+}
+
+/// How `process_sync` applies a payload, from the `/sync` query.
+#[derive(Clone, Copy)]
+pub(crate) struct SyncOptions {
+    pub(crate) on_conflict: OnConflict,
+    pub(crate) atomic: bool,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct SyncSummary {
+    pub(crate) total: usize,
+    pub(crate) succeeded: usize,
+    pub(crate) failed: usize,
+    pub(crate) conflicted: usize,
+    pub(crate) locked: usize,
+}
+
+/// Body returned by `/sync`: per-operation results plus overall counts.
+#[derive(Serialize, ToSchema)]
+pub(crate) struct SyncReport {
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) dry_run: bool,
+    pub(crate) summary: SyncSummary,
+    #[schema(value_type = HashMap<Operation, OperationResult>)]
+    pub(crate) results: SyncResponse,
+}
+
+impl SyncReport {
+    pub(crate) fn new(results: SyncResponse, dry_run: bool) -> Self {
+        let succeeded = results.values().map(|r| r.success.len()).sum();
+        let failed = results.values().map(|r| r.failure.len()).sum();
+        let conflicted = results.values().map(|r| r.conflict.len()).sum();
+        let locked = results.values().map(|r| r.locked.len()).sum();
+        Self {
+            dry_run,
+            summary: SyncSummary { total: succeeded + failed + conflicted + locked, succeeded, failed, conflicted, locked },
+            results,
+        }
+    }
+
+    /// `200` when everything succeeded, `207` on partial failure, `422` when nothing did.
+    /// Conflicts and locked files count as failures here.
+    pub(crate) fn status(&self) -> StatusCode {
+        match (self.summary.succeeded, self.summary.failed + self.summary.conflicted + self.summary.locked) {
+            (_, 0) => StatusCode::OK,
+            (0, _) => StatusCode::UNPROCESSABLE_ENTITY,
+            _ => StatusCode::MULTI_STATUS,
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Debug, ToSchema)]
+pub(crate) struct FileChange {
+    pub(crate) operation: Operation,
+    pub(crate) file_path: String,
+}
+
+#[derive(Serialize, Clone, Debug, ToSchema)]
+pub(crate) struct SyncEvent {
+    /// Owner of the changed files; only that user's `/events` subscribers see the event.
+    #[serde(skip)]
+    pub(crate) user_id: i32,
+    /// The `X-Device-Id` of the request that made the changes, if it sent one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) device_id: Option<String>,
+    pub(crate) changes: Vec<FileChange>,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub(crate) struct WsParams {
+    /// This connection's device; its own changes aren't echoed back to it.
+    pub(crate) device_id: Option<String>,
+}
+
+/// One chunk of a file, as `/sync/blocks` names it.
+#[derive(Deserialize, Serialize, Clone, Debug, ToSchema)]
+pub(crate) struct BlockRef {
+    /// Hex SHA-256 of the chunk's bytes.
+    pub(crate) hash: String,
+    pub(crate) size: i64,
+}
+
+/// The `payload` part of a `/sync/blocks`, which has to come before the content.
+#[derive(Deserialize, ToSchema)]
+pub(crate) struct BlockSyncPayload {
+    pub(crate) file_path: String,
+    pub(crate) modified_time: i64,
+    /// The new content's chunks, in order. Each one missing from the file's
+    /// current manifest follows as a `chunk` part, in the same order; the rest are
+    /// copied from the stored copy. Leave out to send the whole content as one
+    /// `content` part and have the server chunk it.
+    pub(crate) chunks: Option<Vec<BlockRef>>,
+    /// The `file_hash` of the manifest the chunks were diffed against. When the
+    /// server copy has moved on since, the request is refused as a conflict.
+    pub(crate) base_hash: Option<String>,
+    /// With a `content` part: its length, checked against the quota up front.
+    #[serde(default)]
+    pub(crate) file_size: i64,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct ManifestBlock {
+    pub(crate) hash: String,
+    pub(crate) size: i64,
+    /// Where the chunk starts in the file.
+    pub(crate) offset: i64,
+}
+
+/// The chunks of a file's current content, and the parameters they were cut with.
+#[derive(Serialize, ToSchema)]
+pub(crate) struct BlockManifest {
+    pub(crate) file_path: String,
+    pub(crate) file_hash: Option<String>,
+    pub(crate) file_size: i64,
+    pub(crate) chunks: Vec<ManifestBlock>,
+    pub(crate) min_chunk_bytes: usize,
+    pub(crate) max_chunk_bytes: usize,
+    /// Low bits of the rolling hash that are zero where a chunk ends.
+    pub(crate) mask_bits: u32,
+}
+
+/// Body returned by `/sync/blocks`: the stored file, and how much of it was sent.
+#[derive(Serialize, ToSchema)]
+pub(crate) struct BlockSyncResult {
+    pub(crate) data: FileEntry,
+    pub(crate) chunks_reused: usize,
+    pub(crate) chunks_uploaded: usize,
+    pub(crate) bytes_uploaded: i64,
+}
+
+/// A file's content, by the SHA-256 and size `/sync/precheck` asks about.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq, Hash, ToSchema)]
+pub(crate) struct ContentRef {
+    pub(crate) file_hash: String,
+    pub(crate) file_size: i64,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub(crate) struct PrecheckRequest {
+    pub(crate) files: Vec<ContentRef>,
+}
+
+/// Body returned by `/sync/precheck`, each list in request order.
+#[derive(Serialize, ToSchema)]
+pub(crate) struct PrecheckResponse {
+    /// Contents a file of the caller's already has, which `/sync` copies for an
+    /// insert or update that sets `link` instead of sending a part.
+    pub(crate) available: Vec<ContentRef>,
+    /// Contents that have to be uploaded.
+    pub(crate) missing: Vec<ContentRef>,
+}
+
+#[cfg(test)]
+mod tests {
+    //! `pocket-protocol` spells the wire types out again for clients, and `openapi`
+    //! spells out `FileEntry`'s schema. These keep both in step with the server's:
+    //! the entries below list every field, so adding one anywhere fails to compile
+    //! until it is added here, and then fails here until it is added everywhere.
+
+    use serde::{de::DeserializeOwned, Serialize};
+    use serde_json::Value;
+    use utoipa::PartialSchema;
+
+    use super::*;
+    use crate::models::FileLock;
+
+    /// `value` as a client reads it and writes it back.
+    fn through_protocol<T: DeserializeOwned + Serialize>(value: &Value) -> Value {
+        serde_json::to_value(serde_json::from_value::<T>(value.clone()).unwrap()).unwrap()
+    }
+
+    fn timestamp() -> chrono::NaiveDateTime {
+        chrono::DateTime::from_timestamp(1_700_000_000, 0).unwrap().naive_utc()
+    }
+
+    /// A file as the server reports it, with every field set.
+    fn served_file() -> FileEntry {
+        FileEntry {
+            file_name: "data/1/0f/a.txt".into(),
+            file_path: "docs/a.txt".into(),
+            file_hash: Some("ab".repeat(32)),
+            file_size: 3,
+            modified_time: 1,
+            content_type: Some("text/plain".into()),
+            etag: Some("\"e\"".into()),
+            created_at: Some(timestamp()),
+            updated_at: Some(timestamp()),
+            version: Some(2),
+            skipped: true,
+            base_modified_time: None,
+            base_hash: None,
+            base_version: None,
+            conflict_copy_of: Some("docs/b.txt".into()),
+            from_path: Some("docs/c.txt".into()),
+            tags: Some(vec!["favorite".into()]),
+            metadata: Some(sqlx::types::Json([("k".to_string(), "v".to_string())].into())),
+            upload_id: None,
+            metadata_only: false,
+            link: false,
+            storage_key: None,
+            refusal: None,
+        }
+    }
+
+    /// A file as a client sends it, with every field set.
+    fn sent_file() -> pocket_protocol::FileEntry {
+        pocket_protocol::FileEntry {
+            file_name: "a.txt".into(),
+            file_path: "docs/a.txt".into(),
+            file_hash: Some("ab".repeat(32)),
+            file_size: 3,
+            modified_time: 1,
+            content_type: Some("text/plain".into()),
+            etag: Some("\"e\"".into()),
+            created_at: Some("2023-11-14T22:13:20".into()),
+            updated_at: Some("2023-11-14T22:13:20".into()),
+            version: Some(2),
+            skipped: true,
+            base_modified_time: Some(1),
+            base_hash: Some("cd".repeat(32)),
+            base_version: Some(1),
+            conflict_copy_of: Some("docs/b.txt".into()),
+            from_path: Some("docs/c.txt".into()),
+            tags: Some(vec!["favorite".into()]),
+            metadata: Some([("k".to_string(), "v".to_string())].into()),
+            upload_id: Some("0".into()),
+            metadata_only: true,
+            link: true,
+        }
+    }
+
+    #[test]
+    fn served_files_read_back_the_same() {
+        let served = serde_json::to_value(served_file()).unwrap();
+        assert_eq!(through_protocol::<pocket_protocol::FileEntry>(&served), served);
+    }
+
+    #[test]
+    fn sent_files_reach_the_server_whole() {
+        let sent = sent_file();
+        let read: FileEntry = serde_json::from_value(serde_json::to_value(&sent).unwrap()).unwrap();
+        assert_eq!((read.file_name, read.file_path), (sent.file_name, sent.file_path));
+        assert_eq!((read.file_hash, read.file_size, read.modified_time), (sent.file_hash, sent.file_size, sent.modified_time));
+        assert_eq!((read.content_type, read.etag), (sent.content_type, sent.etag));
+        assert_eq!((read.base_modified_time, read.base_hash, read.base_version), (sent.base_modified_time, sent.base_hash, sent.base_version));
+        assert_eq!((read.from_path, read.tags), (sent.from_path, sent.tags));
+        assert_eq!(read.metadata.map(|m| m.0), sent.metadata);
+        assert_eq!((read.upload_id, read.metadata_only, read.link), (sent.upload_id, sent.metadata_only, sent.link));
+    }
+
+    #[test]
+    fn the_file_entry_schema_documents_every_field() {
+        let schema = serde_json::to_value(FileEntry::schema()).unwrap();
+        let mut documented: Vec<&String> = schema["properties"].as_object().unwrap().keys().collect();
+        documented.sort();
+        let sent = serde_json::to_value(sent_file()).unwrap();
+        let mut fields: Vec<&String> = sent.as_object().unwrap().keys().collect();
+        fields.sort();
+        assert_eq!(documented, fields);
+    }
+
+    #[test]
+    fn failure_codes_read_back_the_same() {
+        // Codes are stable, so each one's wire name is pinned too.
+        let wire = |code| match code {
+            FailureCode::NotFound => "not_found",
+            FailureCode::AlreadyExists => "already_exists",
+            FailureCode::DuplicatePath => "duplicate_path",
+            FailureCode::Conflict => "conflict",
+            FailureCode::VersionMismatch => "version_mismatch",
+            FailureCode::Locked => "locked",
+            FailureCode::IntegrityFailure => "integrity_failure",
+            FailureCode::MissingUpload => "missing_upload",
+            FailureCode::QuotaExceeded => "quota_exceeded",
+            FailureCode::InvalidRequest => "invalid_request",
+            FailureCode::RolledBack => "rolled_back",
+            FailureCode::StorageUnavailable => "storage_unavailable",
+            FailureCode::DatabaseUnavailable => "database_unavailable",
+            FailureCode::PolicyViolation => "policy_violation",
+            FailureCode::PathCollision => "path_collision",
+            FailureCode::Internal => "internal",
+        };
+        let codes = [
+            FailureCode::NotFound,
+            FailureCode::AlreadyExists,
+            FailureCode::DuplicatePath,
+            FailureCode::Conflict,
+            FailureCode::VersionMismatch,
+            FailureCode::Locked,
+            FailureCode::IntegrityFailure,
+            FailureCode::MissingUpload,
+            FailureCode::QuotaExceeded,
+            FailureCode::InvalidRequest,
+            FailureCode::RolledBack,
+            FailureCode::StorageUnavailable,
+            FailureCode::DatabaseUnavailable,
+            FailureCode::PolicyViolation,
+            FailureCode::PathCollision,
+            FailureCode::Internal,
+        ];
+        for code in codes {
+            let served = serde_json::to_value(code).unwrap();
+            assert_eq!(served, wire(code));
+            assert_eq!(through_protocol::<pocket_protocol::FailureCode>(&served), served);
+        }
+    }
+
+    #[test]
+    fn sync_reports_read_back_the_same() {
+        let lock = FileLock {
+            file_path: "docs/d.txt".into(),
+            device_id: "laptop".into(),
+            acquired_at: Some(timestamp()),
+            expires_at: timestamp(),
+        };
+        let results = SyncResponse::from([
+            (Operation::Insert, OperationResult { success: vec![served_file()], ..OperationResult::default() }),
+            (
+                Operation::Update,
+                OperationResult {
+                    success: Vec::new(),
+                    failure: vec![FileFailure::new("docs/e.txt".into(), FILE_NOT_FOUND_MESSAGE.into())],
+                    conflict: vec![FileConflict::new(&FileEntry { base_version: Some(1), ..served_file() }, served_file())],
+                    locked: vec![FileLocked::new("docs/d.txt".into(), lock)],
+                },
+            ),
+        ]);
+        let served = serde_json::to_value(SyncReport::new(results, true)).unwrap();
+        assert_eq!(through_protocol::<pocket_protocol::SyncReport>(&served), served);
+    }
+}
@@ -0,0 +1,131 @@
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use utoipa::ToSchema;
+
+#[derive(Deserialize, ToSchema)]
+pub(crate) struct UploadUrlRequest {
+    pub(crate) file_name: String,
+    pub(crate) file_size: i64,
+    pub(crate) content_type: Option<String>,
+    /// Hex SHA-256 of the file; when given, `/upload-confirm` rejects any other content.
+    pub(crate) sha256: Option<String>,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub(crate) struct RemoteUploadRequest {
+    /// An `http` or `https` URL for the server to download.
+    pub(crate) url: String,
+    /// Where to store the file, replacing any file there. A path ending in `/` is a
+    /// folder, and the file is named after the URL, or the name the remote server
+    /// gives it; the root when unset.
+    pub(crate) file_path: Option<String>,
+    /// The remote server's, or one guessed from the name, when unset.
+    pub(crate) content_type: Option<String>,
+    /// Now when unset.
+    pub(crate) modified_time: Option<i64>,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub(crate) struct UploadInitRequest {
+    pub(crate) file_name: String,
+    pub(crate) file_path: String,
+    pub(crate) file_hash: Option<String>,
+    pub(crate) file_size: i64,
+    pub(crate) modified_time: i64,
+    pub(crate) content_type: Option<String>,
+}
+
+#[derive(FromRow)]
+pub(crate) struct UploadSession {
+    pub(crate) user_id: i32,
+    pub(crate) file_path: String,
+    pub(crate) file_hash: Option<String>,
+    pub(crate) file_size: i64,
+    pub(crate) modified_time: i64,
+    pub(crate) content_type: String,
+    pub(crate) system_path: String,
+    pub(crate) storage_upload_id: String,
+    pub(crate) upload_offset: i64,
+    pub(crate) parts: sqlx::types::Json<Vec<UploadedPart>>,
+    pub(crate) file_name: Option<String>,
+    pub(crate) expires_at: chrono::NaiveDateTime,
+    pub(crate) updated_at: Option<chrono::NaiveDateTime>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct UploadedPart {
+    pub(crate) part_number: i32,
+    pub(crate) etag: String,
+}
+
+/// Where a resumable upload stands, for a client resuming it.
+#[derive(Serialize, ToSchema)]
+pub(crate) struct UploadStatus {
+    pub(crate) upload_id: String,
+    pub(crate) file_path: String,
+    pub(crate) file_size: i64,
+    /// Where the next chunk starts.
+    pub(crate) bytes_received: i64,
+    pub(crate) parts: usize,
+    /// The byte ranges still to send; empty once the upload can be completed.
+    pub(crate) missing_ranges: Vec<ByteRange>,
+    pub(crate) complete: bool,
+    /// When the session is abandoned unless another chunk arrives first.
+    pub(crate) expires_at: chrono::NaiveDateTime,
+    /// When the last chunk arrived, or the session was opened.
+    pub(crate) updated_at: Option<chrono::NaiveDateTime>,
+}
+
+/// Bytes `start` up to but excluding `end`.
+#[derive(Serialize, ToSchema)]
+pub(crate) struct ByteRange {
+    pub(crate) start: i64,
+    pub(crate) end: i64,
+}
+
+/// What a user is storing, from the counters `filehash` triggers keep up to date.
+#[derive(Serialize, ToSchema)]
+pub(crate) struct Usage {
+    pub(crate) bytes: i64,
+    pub(crate) files: i64,
+    /// `None` means unlimited.
+    pub(crate) quota_bytes: Option<i64>,
+    pub(crate) remaining_bytes: Option<i64>,
+}
+
+/// A user's storage at a glance, for dashboards. Every figure comes from rollups
+/// triggers keep up to date, apart from the largest files, which the
+/// `(user_id, file_size)` index serves.
+#[derive(Serialize, ToSchema)]
+pub(crate) struct StorageStats {
+    /// Everything the user's files take up, trash included.
+    pub(crate) total_bytes: i64,
+    /// Their live files, which are what counts against the quota.
+    pub(crate) used_bytes: i64,
+    pub(crate) files: i64,
+    pub(crate) trash_bytes: i64,
+    pub(crate) trash_files: i64,
+    /// `None` means unlimited.
+    pub(crate) quota_bytes: Option<i64>,
+    pub(crate) remaining_bytes: Option<i64>,
+    /// Live files by top-level folder, largest first; `""` holds those at the root.
+    pub(crate) folders: Vec<UsageRollup>,
+    /// Live files by lowercased extension, largest first; `""` holds those without one.
+    pub(crate) extensions: Vec<UsageRollup>,
+    pub(crate) largest_files: Vec<LargestFile>,
+}
+
+#[derive(Serialize, FromRow, ToSchema)]
+pub(crate) struct UsageRollup {
+    pub(crate) name: String,
+    pub(crate) bytes: i64,
+    pub(crate) files: i64,
+}
+
+#[derive(Serialize, FromRow, ToSchema)]
+pub(crate) struct LargestFile {
+    pub(crate) file_path: String,
+    pub(crate) file_size: i64,
+    pub(crate) modified_time: i64,
+    pub(crate) content_type: Option<String>,
+}
@@ -0,0 +1,25 @@
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+use sqlx::{types::Json, FromRow};
+use utoipa::ToSchema;
+
+/// How many changes each device has made to a vault item, by device. One
+/// version descends from another when it has seen every change the other has.
+pub(crate) type VersionVector = BTreeMap<String, u64>;
+
+/// A file encrypted by its client. Only `item_id`, `file_size` and
+/// `version_vector` mean anything to the server.
+#[derive(Serialize, FromRow, ToSchema)]
+pub(crate) struct VaultItem {
+    pub(crate) item_id: String,
+    pub(crate) encrypted_name: String,
+    pub(crate) encrypted_metadata: Option<String>,
+    pub(crate) file_size: i64,
+    #[schema(value_type = BTreeMap<String, u64>)]
+    pub(crate) version_vector: Json<VersionVector>,
+    pub(crate) created_at: chrono::NaiveDateTime,
+    pub(crate) updated_at: chrono::NaiveDateTime,
+    #[serde(skip)]
+    pub(crate) system_path: String,
+}
@@ -0,0 +1,50 @@
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use utoipa::ToSchema;
+
+use crate::db::TextList;
+
+#[derive(Deserialize, ToSchema)]
+pub(crate) struct CreateWebhookRequest {
+    /// Where events are POSTed, over `http` or `https`.
+    pub(crate) url: String,
+    /// Any of `file.created`, `file.updated`, `file.deleted`, `share.accessed` and
+    /// `share.viewed`; every event when empty.
+    #[serde(default)]
+    pub(crate) events: Vec<String>,
+}
+
+#[derive(Serialize, FromRow, ToSchema)]
+pub(crate) struct Webhook {
+    pub(crate) id: i32,
+    pub(crate) url: String,
+    #[sqlx(try_from = "TextList")]
+    pub(crate) events: Vec<String>,
+    pub(crate) created_at: Option<chrono::NaiveDateTime>,
+}
+
+/// One event sent, or still being sent, to a webhook.
+#[derive(Serialize, FromRow, ToSchema)]
+pub(crate) struct WebhookDelivery {
+    pub(crate) id: i32,
+    pub(crate) event: String,
+    /// `pending` while attempts remain, then `delivered` or `failed`.
+    pub(crate) status: String,
+    pub(crate) attempts: i32,
+    /// The HTTP status of the last attempt, when the receiver answered.
+    pub(crate) response_status: Option<i32>,
+    pub(crate) last_error: Option<String>,
+    pub(crate) created_at: Option<chrono::NaiveDateTime>,
+    pub(crate) delivered_at: Option<chrono::NaiveDateTime>,
+}
+
+/// A delivery claimed for an attempt, with where to send it.
+#[derive(FromRow)]
+pub(crate) struct DueDelivery {
+    pub(crate) id: i32,
+    pub(crate) event: String,
+    pub(crate) payload: String,
+    pub(crate) attempts: i32,
+    pub(crate) url: String,
+    pub(crate) secret: String,
+}
@@ -0,0 +1,323 @@
+//! Notifications users set up through `/notifications`: an email, an ntfy topic
+//! or a phone's push token each, and the events they want to hear of. Events are
+//! sent from where they happen, except quotas nearly used and syncs that keep
+//! failing, which a sweep looks for every `sweep_interval_secs`. A delivery that
+//! fails is logged and not attempted again.
+
+mod smtp;
+
+use std::time::Duration;
+
+use axum::http::header;
+use serde::Serialize;
+use tracing::{info, warn, Instrument};
+
+use crate::{
+    config::NotificationConfig,
+    db::on_db,
+    handlers::maintenance::in_maintenance,
+    models::NotificationPreferences,
+    AppState,
+};
+
+use self::smtp::Mailer;
+
+pub(crate) const QUOTA_NEAR_LIMIT: &str = "quota.near_limit";
+pub(crate) const SHARE_ACCESSED: &str = "share.accessed";
+pub(crate) const INTEGRITY_ISSUE: &str = "integrity.issue";
+pub(crate) const SYNC_FAILING: &str = "sync.failing";
+
+/// Every event a user can be notified of.
+pub(crate) const NOTIFICATION_EVENTS: [&str; 4] = [QUOTA_NEAR_LIMIT, SHARE_ACCESSED, INTEGRITY_ISSUE, SYNC_FAILING];
+
+/// A server a channel's notifications are sent through, and its token if it needs one.
+struct Endpoint {
+    url: String,
+    token: Option<String>,
+}
+
+/// The channels set up on this server.
+pub(crate) struct Notifier {
+    http: reqwest::Client,
+    mailer: Option<Mailer>,
+    ntfy: Option<Endpoint>,
+    push: Option<Endpoint>,
+    quota_warning_percent: i64,
+    sync_failure_hours: i32,
+}
+
+/// What ntfy is sent, as a JSON publish to its root URL.
+#[derive(Serialize)]
+struct NtfyMessage<'a> {
+    topic: &'a str,
+    title: &'a str,
+    message: &'a str,
+    tags: [&'a str; 1],
+}
+
+/// What the push service is sent, in the shape Expo's accepts.
+#[derive(Serialize)]
+struct PushMessage<'a> {
+    to: &'a str,
+    title: &'a str,
+    body: &'a str,
+    data: PushData<'a>,
+}
+
+#[derive(Serialize)]
+struct PushData<'a> {
+    event: &'a str,
+}
+
+impl Notifier {
+    /// The channels `config` sets up, or `None` when it sets up none.
+    pub(crate) fn from_config(config: &NotificationConfig) -> Option<Self> {
+        let endpoint = |url: &Option<String>, token: &Option<String>| {
+            url.as_ref().map(|url| Endpoint { url: url.trim_end_matches('/').to_string(), token: token.clone() })
+        };
+        let notifier = Self {
+            http: reqwest::Client::builder()
+                .timeout(Duration::from_secs(config.timeout_secs))
+                .build()
+                .expect("Failed to build notification client"),
+            mailer: Mailer::from_config(config),
+            ntfy: endpoint(&config.ntfy_url, &config.ntfy_token),
+            push: endpoint(&config.push_url, &config.push_token),
+            quota_warning_percent: config.quota_warning_percent.into(),
+            sync_failure_hours: config.sync_failure_hours.min(i32::MAX as u64) as i32,
+        };
+        (!notifier.channels().is_empty()).then_some(notifier)
+    }
+
+    /// Names of the channels set up: `email`, `ntfy` and `push`.
+    pub(crate) fn channels(&self) -> Vec<&'static str> {
+        [(self.mailer.is_some(), "email"), (self.ntfy.is_some(), "ntfy"), (self.push.is_some(), "push")]
+            .into_iter()
+            .filter_map(|(set_up, name)| set_up.then_some(name))
+            .collect()
+    }
+
+    /// Sends `title` and `body` to every channel `preferences` gives an address for,
+    /// logging those that fail.
+    async fn deliver(&self, preferences: &NotificationPreferences, event: &str, title: &str, body: &str) {
+        if let (Some(mailer), Some(email)) = (&self.mailer, &preferences.email)
+            && let Err(e) = mailer.send(email, title, body).await
+        {
+            warn!("Failed to mail {} notification to {}: {}", event, email, e);
+        }
+        if let (Some(ntfy), Some(topic)) = (&self.ntfy, &preferences.ntfy_topic) {
+            let message = NtfyMessage { topic, title, message: body, tags: [event] };
+            if let Err(e) = self.post(ntfy, &message).await {
+                warn!("Failed to publish {} notification to ntfy topic {}: {}", event, topic, e);
+            }
+        }
+        if let (Some(push), Some(token)) = (&self.push, &preferences.push_token) {
+            let message = PushMessage { to: token, title, body, data: PushData { event } };
+            if let Err(e) = self.post(push, &message).await {
+                warn!("Failed to push {} notification: {}", event, e);
+            }
+        }
+    }
+
+    async fn post(&self, endpoint: &Endpoint, body: &impl Serialize) -> Result<(), String> {
+        let body = serde_json::to_vec(body).map_err(|e| e.to_string())?;
+        let mut request = self.http.post(&endpoint.url).header(header::CONTENT_TYPE, "application/json").body(body);
+        if let Some(token) = &endpoint.token {
+            request = request.bearer_auth(token);
+        }
+        let response = request.send().await.map_err(|e| e.to_string())?;
+        if !response.status().is_success() {
+            return Err(format!("answered {}", response.status()));
+        }
+        Ok(())
+    }
+}
+
+/// Tells `user_id` of `event` in the background, on each channel they set up,
+/// unless they left the event out.
+pub(crate) fn notify(state: &AppState, user_id: i32, event: &'static str, title: String, body: String) {
+    if state.notifier.is_none() {
+        return;
+    }
+
+    let state = state.clone();
+    tokio::spawn(async move {
+        let Some(notifier) = &state.notifier else { return };
+        let preferences = match load_preferences(&state, user_id).await {
+            Ok(Some(preferences)) => preferences,
+            Ok(None) => return,
+            Err(e) => {
+                warn!("Failed to load the notification preferences of user {}: {}", user_id, e);
+                return;
+            }
+        };
+        if !preferences.events.is_empty() && !preferences.events.iter().any(|e| e == event) {
+            return;
+        }
+        notifier.deliver(&preferences, event, &title, &body).await;
+    }.in_current_span());
+}
+
+/// The user's preferences, or `None` when they never set any.
+pub(crate) async fn load_preferences(state: &AppState, user_id: i32) -> Result<Option<NotificationPreferences>, sqlx::Error> {
+    on_db!(&state.pool, pool => sqlx::query_as::<_, NotificationPreferences>(
+        "SELECT email, ntfy_topic, push_token, events FROM notification_preferences WHERE user_id = $1"
+    )
+    .bind(user_id)
+    .fetch_optional(pool)
+    .await)
+}
+
+/// Tells the owner of a share link that `file_path` was downloaded through it.
+pub(crate) fn notify_share_access(state: &AppState, owner: i32, file_path: &str) {
+    notify(
+        state,
+        owner,
+        SHARE_ACCESSED,
+        "Shared file downloaded".to_string(),
+        format!("{} was downloaded through one of your share links.", file_path),
+    );
+}
+
+/// Tells `user_id` of files the integrity check found missing or damaged.
+pub(crate) fn notify_integrity_issues(state: &AppState, user_id: i32, file_paths: &[String]) {
+    notify(
+        state,
+        user_id,
+        INTEGRITY_ISSUE,
+        format!("{} stored files need attention", file_paths.len()),
+        format!(
+            "The integrity check found these files missing or damaged in storage:\n{}",
+            file_paths.join("\n")
+        ),
+    );
+}
+
+/// Notes whether a sync from `device_id` failed, so the sweep can tell the user
+/// once its syncs have kept failing for `sync_failure_hours`. One that doesn't
+/// fail starts the count over.
+pub(crate) fn record_sync_outcome(state: &AppState, user_id: i32, device_id: String, failed: bool) {
+    if state.notifier.is_none() {
+        return;
+    }
+
+    let pool = state.pool.clone();
+    tokio::spawn(async move {
+        let result = on_db!(&pool, pool => sqlx::query(
+            r#"
+            UPDATE devices
+            SET sync_failing_since = CASE WHEN $3 THEN COALESCE(sync_failing_since, CURRENT_TIMESTAMP) END,
+                sync_failure_notified = $3 AND sync_failure_notified
+            WHERE user_id = $1 AND id = $2
+            "#
+        )
+        .bind(user_id)
+        .bind(&device_id)
+        .bind(failed)
+        .execute(pool)
+        .await
+        .map(|_| ()));
+
+        if let Err(e) = result {
+            warn!("Failed to record the sync outcome of device {}: {}", device_id, e);
+        }
+    }.in_current_span());
+}
+
+/// Looks for quotas nearly used and syncs that keep failing every `every`,
+/// outside maintenance.
+pub(crate) async fn notify_periodically(state: AppState, every: Duration) {
+    let mut interval = tokio::time::interval(every);
+    loop {
+        interval.tick().await;
+        if in_maintenance(&state).await {
+            continue;
+        }
+        if let Err(e) = warn_quotas(&state).await {
+            warn!("Failed to check quotas for notifications: {}", e);
+        }
+        if let Err(e) = report_failing_syncs(&state).await {
+            warn!("Failed to check for failing syncs: {}", e);
+        }
+    }
+}
+
+/// Warns the users who set up notifications and have used `quota_warning_percent`
+/// of their quota, once until their usage falls back below it.
+async fn warn_quotas(state: &AppState) -> Result<(), sqlx::Error> {
+    let Some(notifier) = &state.notifier else { return Ok(()) };
+    let default_quota = state.settings.current().default_quota_bytes.map(|quota| quota as i64);
+    let users = on_db!(&state.pool, pool => sqlx::query_as::<_, (i32, i64, i64, bool)>(
+        r#"
+        SELECT p.user_id, COALESCE(us.bytes, 0), COALESCE(u.quota_bytes, $1), p.quota_warned
+        FROM notification_preferences p
+        JOIN users u ON u.id = p.user_id
+        LEFT JOIN user_usage us ON us.user_id = p.user_id
+        WHERE COALESCE(u.quota_bytes, $1) > 0
+        "#
+    )
+    .bind(default_quota)
+    .fetch_all(pool)
+    .await)?;
+
+    for (user_id, used, quota, warned) in users {
+        let percent = used.saturating_mul(100) / quota;
+        let near = percent >= notifier.quota_warning_percent;
+        if near == warned {
+            continue;
+        }
+        on_db!(&state.pool, pool => sqlx::query("UPDATE notification_preferences SET quota_warned = $2 WHERE user_id = $1")
+            .bind(user_id)
+            .bind(near)
+            .execute(pool)
+            .await
+            .map(|_| ()))?;
+        if near {
+            info!(user_id, "Warning of quota {}% used", percent);
+            notify(
+                state,
+                user_id,
+                QUOTA_NEAR_LIMIT,
+                format!("Storage {}% full", percent.min(100)),
+                format!("You are using {} of your {} bytes of storage.", used, quota),
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Tells users of the devices whose syncs have failed for `sync_failure_hours`,
+/// once per run of failures.
+async fn report_failing_syncs(state: &AppState) -> Result<(), sqlx::Error> {
+    let Some(notifier) = &state.notifier else { return Ok(()) };
+    let sql = state.pool.sql(
+        r#"
+        UPDATE devices SET sync_failure_notified = TRUE
+        WHERE sync_failing_since <= CURRENT_TIMESTAMP - make_interval(hours => $1) AND NOT sync_failure_notified
+        RETURNING user_id, name
+        "#,
+        r#"
+        UPDATE devices SET sync_failure_notified = TRUE
+        WHERE sync_failing_since <= datetime('now', '-' || $1 || ' hours') AND NOT sync_failure_notified
+        RETURNING user_id, name
+        "#,
+    );
+    let devices = on_db!(&state.pool, pool => sqlx::query_as::<_, (i32, String)>(sql)
+        .bind(notifier.sync_failure_hours)
+        .fetch_all(pool)
+        .await)?;
+
+    for (user_id, name) in devices {
+        notify(
+            state,
+            user_id,
+            SYNC_FAILING,
+            format!("{} can't sync", name),
+            format!(
+                "Syncs from {} have been failing for more than {} hours.",
+                name, notifier.sync_failure_hours
+            ),
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,213 @@
+//! Just enough SMTP to hand a plain-text mail to a relay: `EHLO`, `STARTTLS` or
+//! TLS from the start, `AUTH PLAIN`, and one recipient per mail.
+
+use std::sync::Arc;
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+    net::TcpStream,
+};
+use tokio_rustls::{
+    rustls::{crypto::ring::default_provider, pki_types::ServerName, ClientConfig, RootCertStore},
+    TlsConnector,
+};
+
+use crate::config::{NotificationConfig, SmtpSecurity};
+
+/// Longest reply line read from the relay.
+const MAX_REPLY_LINE: usize = 4096;
+
+pub(crate) struct Mailer {
+    host: String,
+    port: u16,
+    security: SmtpSecurity,
+    credentials: Option<(String, String)>,
+    from: String,
+    tls: TlsConnector,
+}
+
+impl Mailer {
+    /// The relay `config` names, or `None` when mail is off.
+    pub(crate) fn from_config(config: &NotificationConfig) -> Option<Self> {
+        let host = config.smtp_host.clone()?;
+        let roots = RootCertStore { roots: webpki_roots::TLS_SERVER_ROOTS.to_vec() };
+        let tls = ClientConfig::builder_with_provider(Arc::new(default_provider()))
+            .with_safe_default_protocol_versions()
+            .expect("the ring provider supports the default TLS versions")
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        Some(Self {
+            host,
+            port: config.smtp_port,
+            security: config.smtp_security,
+            credentials: config.smtp_username.clone().zip(config.smtp_password.clone()),
+            from: config.smtp_from.clone(),
+            tls: TlsConnector::from(Arc::new(tls)),
+        })
+    }
+
+    pub(crate) async fn send(&self, to: &str, subject: &str, body: &str) -> Result<(), String> {
+        let message = compose(&self.from, to, subject, body);
+        let tcp = TcpStream::connect((self.host.as_str(), self.port))
+            .await
+            .map_err(|e| format!("Failed to connect to {}:{}: {}", self.host, self.port, e))?;
+
+        match self.security {
+            SmtpSecurity::Tls => {
+                let mut session = Session::new(self.upgrade(tcp).await?);
+                session.reply(220).await?;
+                session.command(&format!("EHLO {}", hello_name()), 250).await?;
+                self.transfer(session, to, &message).await
+            }
+            SmtpSecurity::StartTls => {
+                let mut session = Session::new(tcp);
+                session.reply(220).await?;
+                session.command(&format!("EHLO {}", hello_name()), 250).await?;
+                session.command("STARTTLS", 220).await?;
+                let mut session = Session::new(self.upgrade(session.into_inner()).await?);
+                session.command(&format!("EHLO {}", hello_name()), 250).await?;
+                self.transfer(session, to, &message).await
+            }
+            SmtpSecurity::None => {
+                let mut session = Session::new(tcp);
+                session.reply(220).await?;
+                session.command(&format!("EHLO {}", hello_name()), 250).await?;
+                self.transfer(session, to, &message).await
+            }
+        }
+    }
+
+    async fn upgrade(&self, tcp: TcpStream) -> Result<tokio_rustls::client::TlsStream<TcpStream>, String> {
+        let name = ServerName::try_from(self.host.clone()).map_err(|e| format!("Invalid SMTP host: {}", e))?;
+        self.tls
+            .connect(name, tcp)
+            .await
+            .map_err(|e| format!("TLS handshake with {} failed: {}", self.host, e))
+    }
+
+    /// Authenticates when there are credentials, then sends `message` to `to`.
+    async fn transfer<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        mut session: Session<S>,
+        to: &str,
+        message: &str,
+    ) -> Result<(), String> {
+        if let Some((username, password)) = &self.credentials {
+            let token = BASE64.encode(format!("\0{}\0{}", username, password));
+            session.command(&format!("AUTH PLAIN {}", token), 235).await?;
+        }
+        session.command(&format!("MAIL FROM:<{}>", address(&self.from)), 250).await?;
+        session.command(&format!("RCPT TO:<{}>", to), 250).await?;
+        session.command("DATA", 354).await?;
+        session.data(message).await?;
+        // The mail was accepted; how the goodbye goes doesn't matter.
+        let _ = session.command("QUIT", 221).await;
+        Ok(())
+    }
+}
+
+struct Session<S> {
+    stream: BufReader<S>,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Session<S> {
+    fn new(stream: S) -> Self {
+        Self { stream: BufReader::new(stream) }
+    }
+
+    fn into_inner(self) -> S {
+        self.stream.into_inner()
+    }
+
+    async fn command(&mut self, line: &str, expected: u16) -> Result<(), String> {
+        let stream = self.stream.get_mut();
+        stream.write_all(line.as_bytes()).await.map_err(io)?;
+        stream.write_all(b"\r\n").await.map_err(io)?;
+        stream.flush().await.map_err(io)?;
+        let verb = line.split(' ').next().unwrap_or(line);
+        self.reply(expected).await.map_err(|e| format!("{}: {}", verb, e))
+    }
+
+    /// Sends the mail after `DATA`, ended by a line holding a dot.
+    async fn data(&mut self, message: &str) -> Result<(), String> {
+        let stream = self.stream.get_mut();
+        stream.write_all(message.as_bytes()).await.map_err(io)?;
+        stream.write_all(b"\r\n.\r\n").await.map_err(io)?;
+        stream.flush().await.map_err(io)?;
+        self.reply(250).await.map_err(|e| format!("DATA: {}", e))
+    }
+
+    /// Reads a reply, every line of one that spans several, and fails unless its
+    /// code is `expected`.
+    async fn reply(&mut self, expected: u16) -> Result<(), String> {
+        loop {
+            let mut line = String::new();
+            let read = (&mut self.stream)
+                .take(MAX_REPLY_LINE as u64)
+                .read_line(&mut line)
+                .await
+                .map_err(io)?;
+            if read == 0 {
+                return Err("the relay closed the connection".into());
+            }
+            let line = line.trim_end();
+            let code = line.get(..3).and_then(|code| code.parse::<u16>().ok());
+            if line.as_bytes().get(3) == Some(&b'-') {
+                continue;
+            }
+            return match code {
+                Some(code) if code == expected => Ok(()),
+                _ => Err(format!("the relay answered {:?}", line)),
+            };
+        }
+    }
+}
+
+fn io(e: std::io::Error) -> String {
+    format!("SMTP connection failed: {}", e)
+}
+
+/// The name the server introduces itself by.
+fn hello_name() -> String {
+    std::env::var("HOSTNAME").ok().filter(|name| !name.is_empty()).unwrap_or_else(|| "localhost".into())
+}
+
+/// The bare address of `Name <address>`, or all of `from` when there's no name.
+fn address(from: &str) -> &str {
+    match (from.rfind('<'), from.rfind('>')) {
+        (Some(start), Some(end)) if start < end => &from[start + 1..end],
+        _ => from.trim(),
+    }
+}
+
+/// The headers and body of a mail, its lines ending in CRLF and dot-stuffed for `DATA`.
+fn compose(from: &str, to: &str, subject: &str, body: &str) -> String {
+    let subject = if subject.is_ascii() {
+        subject.to_string()
+    } else {
+        format!("=?UTF-8?B?{}?=", BASE64.encode(subject))
+    };
+    let domain = address(from).rsplit('@').next().unwrap_or("localhost");
+    let mut message = format!(
+        "From: {}\r\nTo: {}\r\nSubject: {}\r\nDate: {}\r\nMessage-ID: <{}@{}>\r\nMIME-Version: 1.0\r\n\
+         Content-Type: text/plain; charset=utf-8\r\nContent-Transfer-Encoding: 8bit\r\n\r\n",
+        from,
+        to,
+        subject,
+        chrono::Utc::now().to_rfc2822(),
+        hex::encode(rand::random::<[u8; 16]>()),
+        domain,
+    );
+    for line in body.lines() {
+        if line.starts_with('.') {
+            message.push('.');
+        }
+        message.push_str(line);
+        message.push_str("\r\n");
+    }
+    // `Session::data` ends the last line along with the mail.
+    message.truncate(message.len() - 2);
+    message
+}
+
@@ -0,0 +1,427 @@
+//! The OpenAPI description of the HTTP API, served at `/openapi.json`. Request and
+//! response bodies come from the model types themselves; the few bodies handlers
+//! build inline are described by the schemas below.
+
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashMap},
+};
+
+use utoipa::{
+    openapi::{
+        security::{HttpAuthScheme, HttpBuilder, SecurityScheme},
+        RefOr, Schema,
+    },
+    Modify, OpenApi, PartialSchema, ToSchema,
+};
+
+use crate::{handlers, models};
+
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "Pocket Drive",
+        description = "File sync server. Every endpoint except login, share links, signed `/stream` and \
+            `/download/signed` URLs and the probes takes an API token or login JWT as a bearer token."
+    ),
+    modifiers(&BearerAuth),
+    security(("bearer" = [])),
+    paths(
+        handlers::auth::handle_login,
+        handlers::auth::handle_totp_enroll,
+        handlers::auth::handle_totp_verify,
+        handlers::auth::handle_totp_disable,
+        handlers::auth::handle_create_api_token,
+        handlers::auth::handle_list_api_tokens,
+        handlers::auth::handle_revoke_api_token,
+        handlers::health::handle_capabilities,
+        handlers::sync::handle_sync,
+        handlers::sync::handle_sync_precheck,
+        handlers::blocks::handle_block_manifest,
+        handlers::blocks::handle_block_sync,
+        handlers::listing::handle_get_all,
+        handlers::diff::handle_get_diff,
+        handlers::listing::handle_changes,
+        handlers::listing::handle_search,
+        handlers::listing::handle_list_dir,
+        handlers::listing::handle_list_tagged,
+        handlers::downloads::handle_file_download,
+        handlers::downloads::handle_download_urls,
+        handlers::downloads::handle_download_batch,
+        handlers::downloads::handle_download_archive,
+        handlers::downloads::handle_direct_download,
+        handlers::downloads::handle_create_download_token,
+        handlers::downloads::handle_signed_download,
+        handlers::downloads::handle_metadata,
+        handlers::downloads::handle_get_file,
+        handlers::thumbnails::handle_thumbnail,
+        handlers::photos::handle_timeline,
+        handlers::uploads::handle_upload_url,
+        handlers::uploads::handle_upload_confirm,
+        handlers::uploads::handle_upload_init,
+        handlers::uploads::handle_upload_status,
+        handlers::uploads::handle_upload_session_status,
+        handlers::uploads::handle_upload_chunk,
+        handlers::uploads::handle_upload_complete,
+        handlers::remote::handle_remote_upload,
+        handlers::policies::handle_get_policy,
+        handlers::archive::handle_list_archive_rules,
+        handlers::archive::handle_put_archive_rule,
+        handlers::archive::handle_delete_archive_rule,
+        handlers::archive::handle_list_archived_files,
+        handlers::archive::handle_restore_archive,
+        handlers::files::handle_batch_delete,
+        handlers::files::handle_move,
+        handlers::files::handle_rename,
+        handlers::duplicates::handle_list_duplicates,
+        handlers::duplicates::handle_resolve_duplicates,
+        handlers::versions::handle_list_versions,
+        handlers::versions::handle_revert,
+        handlers::audit::handle_audit,
+        handlers::audit::handle_activity,
+        handlers::trash::handle_list_trash,
+        handlers::trash::handle_trash_restore,
+        handlers::snapshots::handle_list_snapshots,
+        handlers::snapshots::handle_create_snapshot,
+        handlers::snapshots::handle_restore_snapshot,
+        handlers::shares::handle_create_share,
+        handlers::shares::handle_revoke_share,
+        handlers::shares::handle_share_stats,
+        handlers::filedrops::handle_create_filedrop,
+        handlers::filedrops::handle_list_filedrops,
+        handlers::filedrops::handle_revoke_filedrop,
+        handlers::shares::handle_share_download,
+        handlers::collaborators::handle_grant_folder,
+        handlers::collaborators::handle_list_collaborators,
+        handlers::collaborators::handle_revoke_collaborator,
+        handlers::collaborators::handle_shared_with_me,
+        handlers::vault::handle_list_vault,
+        handlers::vault::handle_put_vault_item,
+        handlers::vault::handle_get_vault_item,
+        handlers::vault::handle_delete_vault_item,
+        handlers::devices::handle_register_device,
+        handlers::devices::handle_list_devices,
+        handlers::locks::handle_lock,
+        handlers::locks::handle_unlock,
+        handlers::profiles::handle_put_profile,
+        handlers::profiles::handle_list_profiles,
+        handlers::webhooks::handle_create_webhook,
+        handlers::webhooks::handle_list_webhooks,
+        handlers::webhooks::handle_delete_webhook,
+        handlers::webhooks::handle_list_deliveries,
+        handlers::notifications::handle_get_notifications,
+        handlers::notifications::handle_update_notifications,
+        handlers::jobs::handle_get_job,
+        handlers::usage::handle_usage,
+        handlers::usage::handle_stats,
+        handlers::events::handle_events,
+        handlers::events::handle_ws,
+    ),
+    components(schemas(SyncPayloadSchema, models::OnConflict, models::ListSort, models::SortOrder, models::ListFormat)),
+    tags(
+        (name = "sync", description = "Uploading changes and following other devices' changes"),
+        (name = "files", description = "Listing, reading and organising stored files"),
+        (name = "uploads", description = "Presigned and resumable uploads, for files too large for `/sync`"),
+        (name = "devices", description = "Registered devices and their sync profiles"),
+        (name = "webhooks", description = "Sending file events to other services"),
+        (name = "notifications", description = "Telling users by email, ntfy or push of what needs their attention"),
+        (name = "vault", description = "Files encrypted end to end by their clients, kept by opaque id"),
+        (name = "auth", description = "Logging in, two-factor authentication and API tokens"),
+    )
+)]
+pub(crate) struct ApiDoc;
+
+/// Declares the `bearer` scheme the spec's `security` entries refer to.
+struct BearerAuth;
+
+impl Modify for BearerAuth {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "bearer",
+            SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Bearer).build()),
+        );
+    }
+}
+
+// `FileEntry`'s schema, written out here because the fields serde only reads or
+// only writes would otherwise be left out of it.
+/// A file as listed, and as sent in a `/sync` payload.
+#[derive(ToSchema)]
+#[allow(dead_code)]
+struct FileEntrySchema {
+    /// On an insert or update without an `upload_id`, the filename of the `files`
+    /// part holding the content.
+    file_name: String,
+    file_path: String,
+    file_hash: Option<String>,
+    file_size: i64,
+    modified_time: i64,
+    content_type: Option<String>,
+    etag: Option<String>,
+    #[schema(read_only)]
+    created_at: Option<chrono::NaiveDateTime>,
+    #[schema(read_only)]
+    updated_at: Option<chrono::NaiveDateTime>,
+    /// Counts the changes to the file's content and path, starting at 1.
+    #[schema(read_only)]
+    version: Option<i64>,
+    /// Set on insert results that matched an existing row and were not re-uploaded.
+    #[schema(read_only)]
+    skipped: Option<bool>,
+    /// For updates: the `modified_time` and hash the client last saw on the server.
+    /// When given and the server copy has moved on, the update is reported as a conflict.
+    #[schema(write_only)]
+    base_modified_time: Option<i64>,
+    #[schema(write_only)]
+    base_hash: Option<String>,
+    /// For updates and deletes: the `version` the client last saw on the server.
+    /// When given and the server copy has moved on, the file is reported as a
+    /// `version_mismatch` conflict. Required from payload version 4.
+    #[schema(write_only)]
+    base_version: Option<i64>,
+    /// Set on insert results that stored a conflicting update as a conflicted copy:
+    /// the path the update was meant for.
+    #[schema(read_only)]
+    conflict_copy_of: Option<String>,
+    /// For moves: the path the file is moved from.
+    from_path: Option<String>,
+    /// Labels attached to the file, e.g. `favorite`. On an insert or update a list
+    /// replaces the file's tags, and leaving it out keeps them.
+    tags: Option<Vec<String>>,
+    /// Key-value metadata attached to the file, replaced the same way as `tags`.
+    metadata: Option<BTreeMap<String, String>>,
+    /// On an insert or update, an id unique within the payload naming the
+    /// `files:<upload_id>` part that holds the content. Files that share a
+    /// `file_name` need one to tell their parts apart.
+    #[schema(write_only)]
+    upload_id: Option<String>,
+    /// On an insert, that it is recorded without content on purpose. From payload
+    /// version 2, every insert has either this, an `upload_id` or `link`.
+    #[schema(write_only)]
+    metadata_only: Option<bool>,
+    /// On an insert or update, that its content is copied from a file of the
+    /// caller's with the same `file_hash`, a SHA-256, and `file_size`, as
+    /// `/sync/precheck` reports, instead of sent in a part.
+    #[schema(write_only)]
+    link: Option<bool>,
+}
+
+impl PartialSchema for models::FileEntry {
+    fn schema() -> RefOr<Schema> {
+        FileEntrySchema::schema()
+    }
+}
+
+impl ToSchema for models::FileEntry {
+    fn name() -> Cow<'static, str> {
+        Cow::Borrowed("FileEntry")
+    }
+}
+
+/// The `{"error": "..."}` body every failure carries.
+#[derive(ToSchema)]
+#[allow(dead_code)]
+pub(crate) struct ErrorBody {
+    error: String,
+    /// A stable code for failures clients are expected to handle, such as
+    /// `quota_exceeded` or `duplicate_path`.
+    code: Option<String>,
+    /// Attempts made at the storage or database call that failed, when it was retried.
+    attempts: Option<u32>,
+}
+
+/// The `{"data": ...}` envelope most endpoints wrap their result in.
+#[derive(ToSchema)]
+#[allow(dead_code)]
+pub(crate) struct Data<T> {
+    data: T,
+}
+
+/// A `{"data": [...]}` list of files. Spelled out because generic schemas can't be
+/// composed from the hand-written `FileEntry` one.
+#[derive(ToSchema)]
+#[allow(dead_code)]
+pub(crate) struct FileList {
+    data: Vec<models::FileEntry>,
+}
+
+/// The multipart form of a `/sync`. Each uploaded file is either a
+/// `files:<upload_id>` part, for the entry with that `upload_id`, or a `files`
+/// part whose filename is the `file_name` of its insert or update entry.
+#[derive(ToSchema)]
+#[allow(dead_code)]
+pub(crate) struct SyncForm {
+    payload: SyncPayloadSchema,
+    #[schema(value_type = Vec<String>, format = Binary)]
+    files: Vec<Vec<u8>>,
+}
+
+/// The `payload` part of a `/sync`, as JSON. Deletes are applied first, then
+/// moves, then updates, then inserts, each operation finishing before the next
+/// starts and taking its files in the order they were sent; a path can only
+/// appear under one of them. Up to version 2 files are listed under their
+/// operation's name, and from version 3 in `operations`.
+#[derive(ToSchema)]
+#[schema(as = SyncPayload)]
+#[allow(dead_code)]
+pub(crate) struct SyncPayloadSchema {
+    /// Format of the payload, up to 4; newer ones are refused, and
+    /// `/capabilities` lists those the server reads. Version 2 requires every
+    /// insert to have an `upload_id`, or be `metadata_only` or `link`, and version
+    /// 4 every update and delete to have a `base_version`. Sent as `version` by
+    /// older clients.
+    #[schema(default = 1, minimum = 1)]
+    schema_version: Option<u32>,
+    /// Store an update that conflicts with the server copy beside it, as
+    /// `name (conflicted copy from <device> <date>).ext`, instead of rejecting it.
+    #[schema(default = false)]
+    conflict_copies: bool,
+    /// Files to create, each with a matching `files` part.
+    insert: Option<Vec<models::FileEntry>>,
+    /// Files to replace, each with a matching `files` part.
+    update: Option<Vec<models::FileEntry>>,
+    /// Files to move to the trash; only `file_path` and `base_version` are used.
+    delete: Option<Vec<models::FileEntry>>,
+    /// Files to give a new path without uploading them again; only `from_path` and
+    /// `file_path` are used, besides `tags` and `metadata`.
+    #[schema(rename = "move")]
+    moves: Option<Vec<models::FileEntry>>,
+    /// From version 3, instead of the lists above: every file, with its operation.
+    operations: Option<Vec<OperationEntrySchema>>,
+}
+
+/// A file of a version 3 `/sync` payload, and the operation to apply to it.
+#[derive(ToSchema)]
+#[schema(as = OperationEntry)]
+#[allow(dead_code)]
+pub(crate) struct OperationEntrySchema {
+    op: models::Operation,
+    #[serde(flatten)]
+    file: models::FileEntry,
+}
+
+/// The multipart form of a `/sync/blocks`: the payload, then either the `chunk`
+/// parts its chunk list calls for or one `content` part.
+#[derive(ToSchema)]
+#[allow(dead_code)]
+pub(crate) struct BlockSyncForm {
+    payload: models::BlockSyncPayload,
+    #[schema(value_type = Vec<String>, format = Binary)]
+    chunk: Vec<Vec<u8>>,
+    #[schema(value_type = Option<String>, format = Binary)]
+    content: Option<Vec<u8>>,
+}
+
+/// Returned for work that runs in the background; poll `status_url` for the job.
+#[derive(ToSchema)]
+#[allow(dead_code)]
+pub(crate) struct JobAccepted {
+    job_id: i32,
+    status_url: String,
+}
+
+#[derive(ToSchema)]
+#[allow(dead_code)]
+pub(crate) struct PresignedDownload {
+    url: String,
+    expires_in_seconds: u64,
+}
+
+#[derive(ToSchema)]
+#[allow(dead_code)]
+pub(crate) struct DownloadToken {
+    token: String,
+    /// Downloads the file with no other credentials, until the token expires.
+    url: String,
+    expires_in_seconds: i64,
+}
+
+/// Where and how to `PUT` the file's bytes before calling `/upload-confirm`.
+#[derive(ToSchema)]
+#[allow(dead_code)]
+pub(crate) struct PresignedUpload {
+    url: String,
+    method: String,
+    /// Pass back as the `file_name` of the `/upload-confirm` entry.
+    system_path: String,
+    /// Headers the `PUT` must carry.
+    headers: HashMap<String, serde_json::Value>,
+    expires_in_seconds: u64,
+}
+
+#[derive(ToSchema)]
+#[allow(dead_code)]
+pub(crate) struct UploadSessionCreated {
+    upload_id: String,
+    upload_url: String,
+    offset: i64,
+    min_chunk_bytes: usize,
+    max_chunk_bytes: usize,
+}
+
+#[derive(ToSchema)]
+#[allow(dead_code)]
+pub(crate) struct LoginResponse {
+    token: String,
+    token_type: String,
+    expires_in_seconds: i64,
+}
+
+#[derive(ToSchema)]
+#[allow(dead_code)]
+pub(crate) struct TokenCreated {
+    id: i32,
+    username: String,
+    name: Option<String>,
+    scope: models::TokenScope,
+    /// The token itself, which is only ever shown here.
+    token: String,
+    created_at: Option<chrono::NaiveDateTime>,
+}
+
+#[derive(ToSchema)]
+#[allow(dead_code)]
+pub(crate) struct ShareCreated {
+    id: i32,
+    /// The link to hand out.
+    url: String,
+    token: String,
+    expires_at: Option<chrono::NaiveDateTime>,
+    password_protected: bool,
+    /// A folder link, opening the folder's gallery page.
+    folder: bool,
+}
+
+#[derive(ToSchema)]
+#[allow(dead_code)]
+pub(crate) struct FileDropCreated {
+    id: i32,
+    /// The link to hand out, opening the upload form.
+    url: String,
+    token: String,
+    folder: String,
+    expires_at: chrono::NaiveDateTime,
+    max_file_bytes: Option<i64>,
+    max_files: Option<i32>,
+}
+
+#[derive(ToSchema)]
+#[allow(dead_code)]
+pub(crate) struct WebhookCreated {
+    id: i32,
+    url: String,
+    events: Vec<String>,
+    created_at: Option<chrono::NaiveDateTime>,
+    /// Key of the HMAC-SHA256 each delivery is signed with, sent as
+    /// `X-Pocket-Signature: sha256=<hex>`.
+    secret: String,
+}
+
+#[derive(ToSchema)]
+#[allow(dead_code)]
+pub(crate) struct DeviceList {
+    data: Vec<models::DeviceInfo>,
+    /// IDs of the devices with changes they haven't fetched yet.
+    out_of_date: Vec<String>,
+}
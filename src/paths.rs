@@ -0,0 +1,144 @@
+//! The one spelling file paths are kept in. Clients on different systems send
+//! the same path as `docs\a.txt`, `/docs//a.txt ` or with decomposed accents, so
+//! paths are normalized as they come in. A path that only differs from another
+//! in spelling, such as one in the same payload or, with `case_insensitive_paths`,
+//! an existing file of other case, is refused as colliding with it rather than
+//! stored as a near-duplicate.
+
+use std::collections::HashMap;
+
+use unicode_normalization::UnicodeNormalization;
+
+use crate::{
+    db::{on_db, Array},
+    error::AppError,
+    AppState, PATH_COLLISION_MESSAGE,
+};
+
+/// `path` with `/` separators, in Unicode NFC, and without empty segments or the
+/// whitespace ending a segment.
+pub(crate) fn normalize_path(path: &str) -> String {
+    let path: String = path.replace('\\', "/").nfc().collect();
+    path.split('/')
+        .map(str::trim_end)
+        .filter(|segment| !segment.is_empty())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// What two spellings of one path have in common: the normalized path, with ASCII
+/// letters lowercased when case doesn't count.
+pub(crate) fn path_key(path: &str, case_insensitive: bool) -> String {
+    if case_insensitive { path.to_ascii_lowercase() } else { path.to_string() }
+}
+
+/// The folders `path` is in, outermost first.
+pub(crate) fn ancestors(path: &str) -> impl Iterator<Item = &str> {
+    path.match_indices('/').map(|(at, _)| &path[..at])
+}
+
+/// The failure of a path colliding with `other`.
+pub(crate) fn collision(other: &str) -> String {
+    format!("{}: spelled differently from {}", PATH_COLLISION_MESSAGE, other)
+}
+
+/// How `user_id`'s files and the folders holding them are spelled, for `paths`
+/// and every folder above them, by `path_key`. Only used when case doesn't count:
+/// otherwise a normalized path has a single spelling.
+pub(crate) async fn existing_spellings(
+    state: &AppState,
+    user_id: i32,
+    paths: &[&str],
+) -> Result<HashMap<String, Vec<String>>, sqlx::Error> {
+    let mut prefixes: Vec<String> =
+        paths.iter().flat_map(|path| ancestors(path).chain(std::iter::once(*path))).map(|p| path_key(p, true)).collect();
+    prefixes.sort();
+    prefixes.dedup();
+    if prefixes.is_empty() {
+        return Ok(HashMap::new());
+    }
+
+    // `LOWER` folds at least the ASCII letters `path_key` does; the spellings are
+    // checked against it again below.
+    let sql = state.pool.sql(
+        r#"
+        SELECT DISTINCT SUBSTR(f.file_path, 1, LENGTH(p)) FROM filehash f, UNNEST($2::text[]) p
+        WHERE f.user_id = $1 AND LOWER(SUBSTR(f.file_path, 1, LENGTH(p))) = p
+            AND SUBSTR(f.file_path, LENGTH(p) + 1, 1) IN ('/', '')
+        "#,
+        r#"
+        SELECT DISTINCT SUBSTR(f.file_path, 1, LENGTH(p.value)) FROM filehash f, json_each($2) p
+        WHERE f.user_id = $1 AND LOWER(SUBSTR(f.file_path, 1, LENGTH(p.value))) = p.value
+            AND SUBSTR(f.file_path, LENGTH(p.value) + 1, 1) IN ('/', '')
+        "#,
+    );
+    let spellings = on_db!(&state.pool, pool => sqlx::query_scalar::<_, String>(sql)
+        .bind(user_id)
+        .bind(Array(&prefixes))
+        .fetch_all(pool)
+        .await)?;
+
+    let mut by_key: HashMap<String, Vec<String>> = HashMap::new();
+    for spelling in spellings {
+        let key = path_key(&spelling, true);
+        if prefixes.binary_search(&key).is_ok() {
+            by_key.entry(key).or_default().push(spelling);
+        }
+    }
+    Ok(by_key)
+}
+
+/// The spelling in `spellings` of the file `path` names in other case, if it
+/// isn't spelled as `path` already.
+pub(crate) fn stored_spelling<'a>(spellings: &'a HashMap<String, Vec<String>>, path: &str) -> Option<&'a str> {
+    let stored = spellings.get(&path_key(path, true))?;
+    if stored.iter().any(|spelling| spelling == path) {
+        return None;
+    }
+    stored.first().map(String::as_str)
+}
+
+/// A spelling in `spellings` that `path`, or a folder it is in, would collide
+/// with, other than `moving`, the path it is being moved from.
+pub(crate) fn colliding_spelling<'a>(
+    spellings: &'a HashMap<String, Vec<String>>,
+    path: &str,
+    moving: Option<&str>,
+) -> Option<&'a str> {
+    ancestors(path).chain(std::iter::once(path)).find_map(|prefix| {
+        spellings
+            .get(&path_key(prefix, true))?
+            .iter()
+            .find(|spelling| *spelling != prefix && Some(spelling.as_str()) != moving)
+            .map(String::as_str)
+    })
+}
+
+/// `from` and `to` of a move or rename, moving the file or folder `from` names in
+/// other case when case doesn't count. Fails with a 409 when `to`, or a folder it
+/// is in, exists in other case.
+pub(crate) async fn spell_move(state: &AppState, user_id: i32, from: &str, to: &str) -> Result<(String, String), AppError> {
+    let (from, to) = (normalize_path(from), normalize_path(to));
+    if !state.config.case_insensitive_paths {
+        return Ok((from, to));
+    }
+    let spellings = existing_spellings(state, user_id, &[&from, &to]).await?;
+    let from = stored_spelling(&spellings, &from).map(str::to_string).unwrap_or(from);
+    if let Some(other) = colliding_spelling(&spellings, &to, Some(&from)) {
+        return Err(AppError::Conflict(collision(other)));
+    }
+    Ok((from, to))
+}
+
+/// Fails with a 409 when `path`, or a folder it is in, exists in other case and
+/// case doesn't count.
+pub(crate) async fn check_spelling(state: &AppState, user_id: i32, path: &str) -> Result<(), AppError> {
+    if !state.config.case_insensitive_paths {
+        return Ok(());
+    }
+    let spellings = existing_spellings(state, user_id, &[path]).await?;
+    match colliding_spelling(&spellings, path, None) {
+        Some(other) => Err(AppError::Conflict(collision(other))),
+        None => Ok(()),
+    }
+}
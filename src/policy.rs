@@ -0,0 +1,160 @@
+//! What may be stored through `/sync`. A server-wide policy is one of the
+//! `Settings`, and admins can give a user a policy of their own on top of it;
+//! files either one refuses fail with `policy_violation` rather than reaching
+//! storage. The endpoints are in `handlers::policies`.
+
+use std::collections::BTreeMap;
+
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::{
+    db::on_db,
+    handlers::{profiles::glob_to_regex, uploads::resolve_content_type},
+    models::{FileEntry, Operation},
+    AppState, POLICY_VIOLATION_MESSAGE,
+};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, ToSchema)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct UploadPolicy {
+    /// Extensions no file may have, e.g. `exe` or `tar.gz`, compared without case.
+    pub(crate) blocked_extensions: Vec<String>,
+    /// The largest file of a content type, by MIME type or `type/*`, e.g.
+    /// `{"video/*": 1073741824}`. Where several apply, the smallest does.
+    #[schema(value_type = BTreeMap<String, u64>)]
+    pub(crate) max_bytes_by_type: BTreeMap<String, u64>,
+    /// Paths no file may be stored at, as globs in the form sync profiles use,
+    /// e.g. `**/node_modules` or `*.tmp`.
+    pub(crate) forbidden_paths: Vec<String>,
+}
+
+impl UploadPolicy {
+    pub(crate) fn validate(&self) -> Result<(), String> {
+        if self.blocked_extensions.iter().any(|ext| normalize_extension(ext).is_empty()) {
+            return Err("blocked_extensions must not be empty".into());
+        }
+        if self.forbidden_paths.iter().any(|p| matches!(p.trim(), "" | "/")) {
+            return Err("forbidden_paths must not be empty".into());
+        }
+        if let Some(content_type) = self.max_bytes_by_type.keys().find(|t| !t.contains('/')) {
+            return Err(format!("{} in max_bytes_by_type isn't a MIME type or type/*", content_type));
+        }
+        Ok(())
+    }
+
+    /// Both policies at once: whatever either refuses, and the smaller of their
+    /// limits.
+    pub(crate) fn combined(&self, other: &UploadPolicy) -> UploadPolicy {
+        let mut combined = self.clone();
+        combined.blocked_extensions.extend(other.blocked_extensions.iter().cloned());
+        combined.forbidden_paths.extend(other.forbidden_paths.iter().cloned());
+        for (content_type, max) in &other.max_bytes_by_type {
+            combined
+                .max_bytes_by_type
+                .entry(content_type.clone())
+                .and_modify(|limit| *limit = (*limit).min(*max))
+                .or_insert(*max);
+        }
+        combined
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.blocked_extensions.is_empty() && self.max_bytes_by_type.is_empty() && self.forbidden_paths.is_empty()
+    }
+}
+
+/// An `UploadPolicy` ready to check files against.
+pub(crate) struct PolicyCheck {
+    extensions: Vec<String>,
+    max_bytes_by_type: BTreeMap<String, u64>,
+    forbidden: Vec<(String, Regex)>,
+}
+
+impl PolicyCheck {
+    pub(crate) fn new(policy: &UploadPolicy) -> Self {
+        Self {
+            extensions: policy.blocked_extensions.iter().map(|ext| normalize_extension(ext)).collect(),
+            max_bytes_by_type: policy.max_bytes_by_type.clone(),
+            // Patterns are escaped as they're translated, so they always compile.
+            forbidden: policy
+                .forbidden_paths
+                .iter()
+                .filter_map(|p| Regex::new(&glob_to_regex(p.trim())).ok().map(|regex| (p.trim().to_string(), regex)))
+                .collect(),
+        }
+    }
+
+    /// Why the policy refuses `cmd` of `file`, if it does. Deletions are never
+    /// refused, and moves only by where they go.
+    pub(crate) fn violation(&self, cmd: Operation, file: &FileEntry) -> Option<String> {
+        if cmd == Operation::Delete {
+            return None;
+        }
+        let path = file.file_path.trim_matches('/');
+        let name = path.rsplit('/').next().unwrap_or_default().to_lowercase();
+        if let Some(ext) = self.extensions.iter().find(|ext| name.ends_with(&format!(".{}", ext))) {
+            return Some(format!("{}: .{} files aren't allowed", POLICY_VIOLATION_MESSAGE, ext));
+        }
+        if let Some((pattern, _)) = self.forbidden.iter().find(|(_, regex)| regex.is_match(path)) {
+            return Some(format!("{}: the path matches the forbidden pattern {}", POLICY_VIOLATION_MESSAGE, pattern));
+        }
+        if cmd == Operation::Move {
+            return None;
+        }
+
+        // Both what the client says the file is and what its name says, so neither
+        // gets a file past a limit.
+        let guessed = resolve_content_type(None, path);
+        let declared = file.content_type.as_deref().filter(|ct| !ct.is_empty());
+        let limit = self
+            .max_bytes_by_type
+            .iter()
+            .filter(|(pattern, _)| {
+                std::iter::once(guessed.as_str()).chain(declared).any(|content_type| type_matches(pattern, content_type))
+            })
+            .min_by_key(|(_, max)| **max);
+        match limit {
+            Some((pattern, max)) if file.file_size as u64 > *max => Some(format!(
+                "{}: {} files may be at most {} bytes",
+                POLICY_VIOLATION_MESSAGE, pattern, max
+            )),
+            _ => None,
+        }
+    }
+}
+
+fn normalize_extension(ext: &str) -> String {
+    ext.trim().trim_start_matches('.').to_lowercase()
+}
+
+/// Whether `pattern`, a MIME type or `type/*`, covers `content_type`.
+fn type_matches(pattern: &str, content_type: &str) -> bool {
+    let content_type = content_type.split(';').next().unwrap_or_default().trim();
+    match pattern.strip_suffix("/*") {
+        Some("*") => true,
+        Some(kind) => content_type.split('/').next().is_some_and(|t| t.eq_ignore_ascii_case(kind)),
+        None => content_type.eq_ignore_ascii_case(pattern),
+    }
+}
+
+/// The policy an admin gave `user_id`, if any.
+pub(crate) async fn user_policy(state: &AppState, user_id: i32) -> Result<Option<UploadPolicy>, sqlx::Error> {
+    let row = on_db!(&state.pool, pool => sqlx::query_scalar::<_, String>(
+        "SELECT policy FROM upload_policies WHERE user_id = $1"
+    )
+    .bind(user_id)
+    .fetch_optional(pool)
+    .await)?;
+    Ok(row.and_then(|policy| serde_json::from_str(&policy).ok()))
+}
+
+/// The policy files of `user_id` are held to: the server's and their own together.
+pub(crate) async fn effective_policy(state: &AppState, user_id: i32) -> Result<UploadPolicy, sqlx::Error> {
+    let server = state.settings.current().upload_policy.clone();
+    Ok(match user_policy(state, user_id).await? {
+        Some(own) => server.combined(&own),
+        None => server,
+    })
+}
@@ -0,0 +1,100 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::http::Method;
+
+use crate::{RateLimitConfig, RateLimitSettings, RATE_LIMIT_MAX_TRACKED_KEYS};
+
+#[derive(Clone, Copy)]
+pub(crate) struct RateLimit {
+    capacity: f64,
+    refill_per_sec: f64,
+}
+
+impl From<RateLimitSettings> for RateLimit {
+    fn from(settings: RateLimitSettings) -> Self {
+        Self {
+            capacity: settings.burst.max(1.0),
+            refill_per_sec: settings.per_min.max(0.001) / 60.0,
+        }
+    }
+}
+
+struct TokenBucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+/// Backing store for rate-limit buckets, so the in-memory one can be swapped for
+/// a shared store (e.g. Redis) when running more than one instance.
+pub(crate) trait RateLimitStore: Send + Sync {
+    /// Takes one token from the bucket for `key`, or returns how long to wait.
+    fn acquire(&self, key: &str, limit: RateLimit) -> Result<(), Duration>;
+}
+
+#[derive(Default)]
+pub(crate) struct InMemoryRateLimitStore {
+    buckets: Mutex<HashMap<String, TokenBucket>>,
+}
+
+impl RateLimitStore for InMemoryRateLimitStore {
+    fn acquire(&self, key: &str, limit: RateLimit) -> Result<(), Duration> {
+        let mut buckets = self.buckets.lock().unwrap();
+        let now = Instant::now();
+
+        if buckets.len() > RATE_LIMIT_MAX_TRACKED_KEYS {
+            buckets.retain(|_, b| now.duration_since(b.last_refill) < Duration::from_secs(3600));
+        }
+
+        let bucket = buckets.entry(key.to_string()).or_insert(TokenBucket {
+            tokens: limit.capacity,
+            last_refill: now,
+        });
+
+        let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * limit.refill_per_sec).min(limit.capacity);
+        bucket.last_refill = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / limit.refill_per_sec))
+        }
+    }
+}
+
+/// Token-bucket limits per client and endpoint. Writes (uploads, deletes) get a
+/// tighter budget than reads, routes can have budgets of their own, and each user
+/// can be held to one budget across all their clients.
+pub(crate) struct RateLimiter {
+    pub(crate) store: Arc<dyn RateLimitStore>,
+    pub(crate) read: RateLimit,
+    pub(crate) write: RateLimit,
+    pub(crate) routes: HashMap<String, RateLimit>,
+    pub(crate) user: Option<RateLimit>,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(store: Arc<dyn RateLimitStore>, config: &RateLimitConfig) -> Self {
+        Self {
+            store,
+            read: config.read.into(),
+            write: config.write.into(),
+            routes: config.routes.iter().map(|(route, limit)| (route.clone(), (*limit).into())).collect(),
+            user: config.user.map(RateLimit::from),
+        }
+    }
+
+    /// The per-caller budget for `method` requests to `endpoint`.
+    pub(crate) fn limit_for(&self, method: &Method, endpoint: &str) -> RateLimit {
+        match self.routes.get(endpoint) {
+            Some(limit) => *limit,
+            None if matches!(*method, Method::GET | Method::HEAD) => self.read,
+            None => self.write,
+        }
+    }
+}
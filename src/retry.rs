@@ -0,0 +1,91 @@
+use std::{future::Future, time::Duration};
+
+use rand::Rng;
+use tokio::time::{sleep, timeout};
+use tracing::warn;
+
+use crate::RetryConfig;
+
+/// How many times, how far apart and how patiently an operation is run before
+/// its failure is given up on.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct RetryPolicy {
+    attempts: u32,
+    base_delay: Duration,
+    max_delay: Duration,
+    /// Limit on each attempt.
+    timeout: Duration,
+}
+
+impl RetryPolicy {
+    pub(crate) fn storage(config: &RetryConfig) -> Self {
+        Self::new(config, config.storage_attempts, config.storage_timeout_secs)
+    }
+
+    pub(crate) fn database(config: &RetryConfig) -> Self {
+        Self::new(config, config.db_attempts, config.db_timeout_secs)
+    }
+
+    fn new(config: &RetryConfig, attempts: u32, timeout_secs: u64) -> Self {
+        Self {
+            attempts: attempts.max(1),
+            base_delay: Duration::from_millis(config.base_delay_ms),
+            max_delay: Duration::from_millis(config.max_delay_ms),
+            timeout: Duration::from_secs(timeout_secs.max(1)),
+        }
+    }
+
+    pub(crate) fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    /// Runs `call` until it succeeds, fails with an error `retry` turns down, or
+    /// the attempts run out, and returns its last result with the number of
+    /// attempts made. An attempt outlasting the timeout fails with `timed_out()`.
+    pub(crate) async fn run<T, E, Fut>(
+        &self,
+        operation: &str,
+        mut call: impl FnMut() -> Fut,
+        retry: impl Fn(&E) -> bool,
+        timed_out: impl Fn() -> E,
+    ) -> (Result<T, E>, u32)
+    where
+        Fut: Future<Output = Result<T, E>>,
+    {
+        let mut attempt = 1;
+        loop {
+            let result = timeout(self.timeout, call()).await.unwrap_or_else(|_| Err(timed_out()));
+            match result {
+                Err(e) if attempt < self.attempts && retry(&e) => {
+                    let delay = self.delay(attempt);
+                    warn!(operation, attempt, delay_ms = delay.as_millis() as u64, "retrying after a transient failure");
+                    sleep(delay).await;
+                    attempt += 1;
+                }
+                result => return (result, attempt),
+            }
+        }
+    }
+
+    /// Wait before retry number `attempt`: anywhere up to the base delay doubled
+    /// per earlier retry, so clients failing together don't retry together.
+    fn delay(&self, attempt: u32) -> Duration {
+        let cap = self.base_delay.saturating_mul(1 << (attempt - 1).min(16)).min(self.max_delay);
+        Duration::from_millis(rand::thread_rng().gen_range(0..=cap.as_millis() as u64))
+    }
+}
+
+/// `error`, noting how many attempts it took when there was more than one.
+pub(crate) fn after_attempts(error: String, attempts: u32) -> String {
+    if attempts > 1 {
+        format!("{} (after {} attempts)", error, attempts)
+    } else {
+        error
+    }
+}
+
+/// The attempt count `after_attempts` noted in `message`, if any.
+pub(crate) fn attempts_made(message: &str) -> Option<u32> {
+    let (_, note) = message.rsplit_once(" (after ")?;
+    note.strip_suffix(" attempts)")?.parse().ok()
+}
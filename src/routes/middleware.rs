@@ -0,0 +1,212 @@
+use std::{net::SocketAddr, time::Duration};
+
+use axum::{
+    extract::{ConnectInfo, MatchedPath, Request, State},
+    http::{header, HeaderMap, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use tracing::info;
+
+use crate::{
+    auth::{authenticate, authenticate_basic, bearer_token, dav_scope_allows, scope_allows},
+    bandwidth::Throttle,
+    grpc::status_of,
+    handlers::maintenance::{active_maintenance, maintenance_response},
+    models::{AuthUser, Role, TokenScope},
+    AppState,
+};
+
+/// Rejects requests with `429` once the caller's bucket for this endpoint is empty.
+/// Callers are identified by their bearer token, or by IP when they send none.
+pub(crate) async fn rate_limit(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let client = bearer_token(req.headers())
+        .map(|token| format!("token:{}", token))
+        .unwrap_or_else(|| format!("ip:{}", addr.ip()));
+    let endpoint = matched_path(&req);
+
+    let limits = &state.rate_limiter;
+    let limit = limits.limit_for(req.method(), endpoint);
+
+    let key = format!("{}:{} {}", client, req.method(), endpoint);
+    if let Err(wait) = limits.store.acquire(&key, limit) {
+        return too_many_requests(wait);
+    }
+
+    next.run(req).await
+}
+
+/// Rejects requests with `429` once the user's shared budget is spent, however many
+/// tokens and endpoints their requests are spread over. Runs inside `require_auth`.
+pub(crate) async fn throttle_user(State(state): State<AppState>, req: Request, next: Next) -> Response {
+    let limits = &state.rate_limiter;
+    if let Some(limit) = limits.user
+        && let Some(user) = req.extensions().get::<AuthUser>()
+        && let Err(wait) = limits.store.acquire(&format!("user:{}", user.user_id), limit)
+    {
+        return too_many_requests(wait);
+    }
+
+    next.run(req).await
+}
+
+/// Slows the request and response bodies to the user's and device's byte rates,
+/// from `settings`. Runs inside `require_auth`.
+pub(crate) async fn limit_bandwidth(State(state): State<AppState>, req: Request, next: Next) -> Response {
+    let Some(user) = req.extensions().get::<AuthUser>().cloned() else {
+        return next.run(req).await;
+    };
+    let settings = state.settings.current();
+    let device = user.device_id.as_deref();
+    let throttle = |direction: &str, user_rate: Option<u64>, device_rate: Option<u64>| {
+        Throttle::new(state.bandwidth.clone())
+            .limit(format!("{}:user:{}", direction, user.user_id), user_rate)
+            .limit(
+                format!("{}:device:{}:{}", direction, user.user_id, device.unwrap_or_default()),
+                device.and(device_rate),
+            )
+    };
+    let upload = throttle("up", settings.user_upload_bytes_per_sec, settings.device_upload_bytes_per_sec);
+    let download = throttle("down", settings.user_download_bytes_per_sec, settings.device_download_bytes_per_sec);
+
+    let req = req.map(|body| upload.wrap(body));
+    next.run(req).await.map(|body| download.wrap(body))
+}
+
+fn too_many_requests(wait: Duration) -> Response {
+    let retry_after = wait.as_secs_f64().ceil().max(1.0) as u64;
+    (
+        StatusCode::TOO_MANY_REQUESTS,
+        [(header::RETRY_AFTER, retry_after.to_string())],
+        Json(serde_json::json!({
+            "error": "Rate limit exceeded",
+            "retry_after_seconds": retry_after
+        })),
+    ).into_response()
+}
+
+/// Rejects requests without a valid bearer token and exposes the caller as an `AuthUser`
+/// request extension.
+pub(crate) async fn require_auth(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    mut req: Request,
+    next: Next,
+) -> Response {
+    match authenticate(&state, req.headers()).await {
+        Ok(Some(user)) if !scope_allows(user.scope, req.method(), matched_path(&req)) => out_of_scope(user.scope),
+        Ok(Some(mut user)) => {
+            user.device_id = req
+                .headers()
+                .get("x-device-id")
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_string);
+            user.ip = Some(addr.ip());
+            req.extensions_mut().insert(user);
+            next.run(req).await
+        }
+        Ok(None) => (StatusCode::UNAUTHORIZED, Json(serde_json::json!({
+            "error": "Missing or invalid token"
+        }))).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, Json(serde_json::json!({
+            "error": e.to_string()
+        }))).into_response(),
+    }
+}
+
+/// `require_auth` for WebDAV, which also takes HTTP Basic credentials since that is
+/// all most WebDAV clients can send. Failures carry the challenge that makes them
+/// prompt for a login.
+pub(crate) async fn require_dav_auth(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    mut req: Request,
+    next: Next,
+) -> Response {
+    let user = match authenticate(&state, req.headers()).await {
+        Ok(None) => authenticate_basic(&state, req.headers()).await,
+        found => found,
+    };
+    match user {
+        Ok(Some(user)) if !dav_scope_allows(user.scope, req.method()) => out_of_scope(user.scope),
+        Ok(Some(mut user)) => {
+            user.ip = Some(addr.ip());
+            req.extensions_mut().insert(user);
+            next.run(req).await
+        }
+        Ok(None) => (
+            StatusCode::UNAUTHORIZED,
+            [(header::WWW_AUTHENTICATE, "Basic realm=\"Pocket Drive\", charset=\"UTF-8\"")],
+        ).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, Json(serde_json::json!({
+            "error": e.to_string()
+        }))).into_response(),
+    }
+}
+
+/// The route `req` matched, or its path when it matched none.
+fn matched_path(req: &Request) -> &str {
+    req.extensions()
+        .get::<MatchedPath>()
+        .map(|p| p.as_str())
+        .unwrap_or_else(|| req.uri().path())
+}
+
+/// The `403` for a token used beyond its scope.
+fn out_of_scope(scope: TokenScope) -> Response {
+    (StatusCode::FORBIDDEN, Json(serde_json::json!({
+        "error": format!("This token is limited to the {} scope", scope.as_str())
+    }))).into_response()
+}
+
+/// Turns the plain HTTP rejections of gRPC calls, from auth or rate limiting, into
+/// the gRPC statuses their clients look for.
+pub(crate) async fn grpc_status(req: Request, next: Next) -> Response {
+    let is_grpc = |headers: &HeaderMap| {
+        headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|v| v.starts_with("application/grpc"))
+    };
+    if !is_grpc(req.headers()) {
+        return next.run(req).await;
+    }
+
+    let response = next.run(req).await;
+    if is_grpc(response.headers()) {
+        return response;
+    }
+    status_of(response).await.into_http()
+}
+
+/// Lets the request through only for users with at least `role`. Runs inside
+/// `require_auth`.
+pub(crate) async fn require_role(State(role): State<Role>, req: Request, next: Next) -> Response {
+    match req.extensions().get::<AuthUser>() {
+        Some(user) if user.role >= role => {
+            info!(user_id = user.user_id, username = %user.username, "ADMIN {} {}", req.method(), req.uri().path());
+            next.run(req).await
+        }
+        Some(_) => (StatusCode::FORBIDDEN, Json(serde_json::json!({
+            "error": format!("Requires the {} role", role.as_str())
+        }))).into_response(),
+        None => (StatusCode::UNAUTHORIZED, Json(serde_json::json!({
+            "error": "Missing or invalid token"
+        }))).into_response(),
+    }
+}
+
+/// Refuses writes with a `503` while the server is in maintenance mode.
+pub(crate) async fn freeze_writes(State(state): State<AppState>, req: Request, next: Next) -> Response {
+    if let Some(maintenance) = active_maintenance(&state).await {
+        return maintenance_response(&maintenance);
+    }
+
+    next.run(req).await
+}
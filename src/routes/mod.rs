@@ -0,0 +1,408 @@
+use std::time::Duration;
+
+use axum::{
+    extract::{DefaultBodyLimit, Request},
+    http::{header, HeaderName, HeaderValue, Method},
+    routing::{any, delete, get, head, patch, post, put},
+    Router,
+};
+use tower_http::{
+    cors::{AllowHeaders, AllowMethods, AllowOrigin, CorsLayer},
+    compression::{
+        predicate::{NotForContentType, Predicate, SizeAbove},
+        CompressionLayer,
+    },
+    decompression::RequestDecompressionLayer,
+    request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer},
+    set_header::SetResponseHeaderLayer,
+    trace::{DefaultOnRequest, DefaultOnResponse, TraceLayer},
+    LatencyUnit,
+};
+use tonic::server::NamedService;
+use tower::{service_fn, ServiceExt};
+use tracing::{info_span, Level};
+
+use crate::{
+    env_or,
+    grpc::PocketSync,
+    handlers::{
+        admin::{
+            handle_admin_revoke_share, handle_list_jobs, handle_list_shares, handle_list_users, handle_purge_files,
+            handle_user_stats,
+        },
+        archive::{
+            handle_delete_archive_rule, handle_list_archive_rules, handle_list_archived_files, handle_put_archive_rule,
+            handle_restore_archive,
+        },
+        audit::{handle_activity, handle_audit},
+        auth::{
+            handle_create_api_token, handle_create_token, handle_create_user, handle_list_api_tokens,
+            handle_list_tokens, handle_login, handle_reset_totp, handle_revoke_api_token, handle_revoke_token,
+            handle_totp_disable, handle_totp_enroll, handle_totp_verify,
+        },
+        blocks::{handle_block_manifest, handle_block_sync},
+        catalog::{handle_export, handle_import},
+        collaborators::{
+            handle_grant_folder, handle_list_collaborators, handle_revoke_collaborator, handle_shared_with_me,
+        },
+        dav::handle_dav,
+        devices::{handle_list_devices, handle_register_device},
+        diff::handle_get_diff,
+        docs::{handle_docs, handle_openapi},
+        downloads::{
+            handle_create_download_token, handle_direct_download, handle_download_archive, handle_download_batch,
+            handle_download_urls, handle_file_download, handle_get_file, handle_metadata, handle_signed_download, handle_stream_get,
+            handle_stream_put,
+        },
+        duplicates::{handle_list_duplicates, handle_resolve_duplicates},
+        events::{handle_events, handle_ws},
+        filedrops::{
+            handle_create_filedrop, handle_filedrop_page, handle_filedrop_upload, handle_list_filedrops,
+            handle_revoke_filedrop,
+        },
+        files::{handle_batch_delete, handle_move, handle_rename},
+        gallery::{handle_share_folder_path, handle_share_folder_root},
+        health::{handle_capabilities, handle_healthz, handle_metrics, handle_readyz, root},
+        integrity::{handle_list_integrity_issues, handle_verify_integrity},
+        jobs::{handle_get_job, handle_latest_reconcile, handle_list_retries, handle_reconcile},
+        listing::{handle_changes, handle_get_all, handle_list_dir, handle_list_tagged, handle_search},
+        locks::{handle_lock, handle_unlock},
+        maintenance::{handle_get_maintenance, handle_set_maintenance},
+        migration::{handle_migrate, handle_reroute},
+        notifications::{handle_get_notifications, handle_update_notifications},
+        photos::handle_timeline,
+        policies::{handle_delete_user_policy, handle_get_policy, handle_get_user_policy, handle_put_user_policy},
+        profiles::{handle_list_profiles, handle_put_profile},
+        quarantine::{handle_delete_quarantined, handle_list_quarantine, handle_release_quarantined},
+        remote::handle_remote_upload,
+        schedule::{handle_list_schedule, handle_run_job},
+        settings::{handle_list_settings, handle_reset_setting, handle_update_settings},
+        shares::{handle_create_share, handle_revoke_share, handle_share_download, handle_share_stats},
+        snapshots::{handle_create_snapshot, handle_list_snapshots, handle_restore_snapshot},
+        storage::handle_reload_storage,
+        sync::{handle_sync, handle_sync_precheck},
+        thumbnails::handle_thumbnail,
+        trash::{handle_list_trash, handle_trash_restore},
+        uploads::{
+            handle_upload_chunk, handle_upload_complete, handle_upload_confirm, handle_upload_init,
+            handle_upload_session_status, handle_upload_status, handle_upload_url,
+        },
+        vault::{handle_delete_vault_item, handle_get_vault_item, handle_list_vault, handle_put_vault_item},
+        usage::{handle_stats, handle_usage},
+        versions::{handle_list_versions, handle_revert},
+        webhooks::{handle_create_webhook, handle_delete_webhook, handle_list_deliveries, handle_list_webhooks},
+    },
+    metrics::track_requests,
+    routes::middleware::{
+        freeze_writes, grpc_status, limit_bandwidth, rate_limit, require_auth, require_role, require_dav_auth,
+        throttle_user,
+    },
+    models::Role,
+    AppState, CorsConfig, DAV_PREFIX, DEFAULT_COMPRESSION_MIN_BYTES, MAX_UPLOAD_CHUNK_BYTES,
+};
+
+pub(crate) mod middleware;
+
+pub fn build_router(appstate: AppState) -> Router {
+    // Only the JSON listing endpoints and the reports of writes are compressed;
+    // streaming responses stay untouched.
+    let compression = CompressionLayer::new().compress_when(
+        SizeAbove::new(env_or("COMPRESSION_MIN_BYTES", DEFAULT_COMPRESSION_MIN_BYTES))
+            .and(NotForContentType::GRPC)
+            .and(NotForContentType::IMAGES)
+            .and(NotForContentType::SSE),
+    );
+    let listings = Router::new()
+        .route("/get", get(handle_get_all))
+        .route("/get/diff", post(handle_get_diff))
+        .route("/search", get(handle_search))
+        .route("/changes", get(handle_changes))
+        .route("/versions", get(handle_list_versions))
+        .route("/trash", get(handle_list_trash))
+        .route("/usage", get(handle_usage))
+        .route("/stats", get(handle_stats))
+        .route("/list", get(handle_list_dir))
+        .route("/files", get(handle_list_tagged))
+        .route("/duplicates", get(handle_list_duplicates))
+        .route("/photos/timeline", get(handle_timeline))
+        .route("/audit", get(handle_audit))
+        .route("/activity", get(handle_activity))
+        .layer(compression.clone());
+
+    // Uploads are streamed to storage, so the in-memory body cap doesn't apply to
+    // them; `/sync` checks each file against `max_upload_bytes` as it arrives, and
+    // `/stream` against the size its URL was signed for.
+    let body_limit = |max: Option<u64>| match max {
+        Some(max) => DefaultBodyLimit::max(usize::try_from(max).unwrap_or(usize::MAX)),
+        None => DefaultBodyLimit::disable(),
+    };
+    let sync_limit = body_limit(appstate.config.max_request_bytes);
+
+    // Operators can look at every user's data; only admins can change it.
+    let operator = Router::new()
+        .route("/admin/reconcile", get(handle_latest_reconcile))
+        .route("/admin/jobs", get(handle_list_retries))
+        .route("/admin/background-jobs", get(handle_list_jobs))
+        .route("/admin/users", get(handle_list_users))
+        .route("/admin/users/{id}", get(handle_user_stats))
+        .route("/admin/users/{id}/policy", get(handle_get_user_policy))
+        .route("/admin/shares", get(handle_list_shares))
+        .route("/admin/quarantine", get(handle_list_quarantine))
+        .route("/admin/integrity", get(handle_list_integrity_issues))
+        .route("/admin/maintenance", get(handle_get_maintenance))
+        .route("/admin/settings", get(handle_list_settings))
+        .route("/admin/schedule", get(handle_list_schedule))
+        .route_layer(axum::middleware::from_fn_with_state(Role::Operator, require_role));
+    let admin = Router::new()
+        .route("/admin/reconcile", post(handle_reconcile))
+        .route("/admin/integrity", post(handle_verify_integrity))
+        .route("/admin/tokens", post(handle_create_token).get(handle_list_tokens))
+        .route("/admin/tokens/{id}", delete(handle_revoke_token))
+        .route("/admin/users", post(handle_create_user))
+        .route("/admin/users/{id}/delete", post(handle_purge_files))
+        .route("/admin/users/{id}/2fa", delete(handle_reset_totp))
+        .route("/admin/users/{id}/policy", put(handle_put_user_policy).delete(handle_delete_user_policy))
+        .route("/admin/shares/{id}", delete(handle_admin_revoke_share))
+        .route("/admin/quarantine/{id}/release", post(handle_release_quarantined))
+        .route("/admin/quarantine/{id}", delete(handle_delete_quarantined))
+        .route("/admin/maintenance", post(handle_set_maintenance))
+        .route("/admin/migrate", post(handle_migrate))
+        .route("/admin/reroute", post(handle_reroute))
+        .route("/admin/storage/reload", post(handle_reload_storage))
+        .route("/admin/settings", patch(handle_update_settings))
+        .route("/admin/settings/{key}", delete(handle_reset_setting))
+        .route(
+            "/admin/schedule/{name}/run",
+            post(handle_run_job).route_layer(axum::middleware::from_fn_with_state(appstate.clone(), freeze_writes)),
+        )
+        .route("/export", get(handle_export))
+        .route(
+            "/import",
+            post(handle_import)
+                .layer(sync_limit)
+                .route_layer(axum::middleware::from_fn_with_state(appstate.clone(), freeze_writes)),
+        )
+        .route_layer(axum::middleware::from_fn_with_state(Role::Admin, require_role));
+
+    // Everything that changes files, which maintenance mode turns away.
+    let writes = Router::new()
+        .route("/sync", post(handle_sync).layer(sync_limit))
+        .route("/sync/blocks", post(handle_block_sync).layer(sync_limit))
+        .route("/upload-url", post(handle_upload_url))
+        .route("/upload-confirm", post(handle_upload_confirm))
+        .route("/upload/init", post(handle_upload_init))
+        .route("/upload/{id}", patch(handle_upload_chunk).layer(DefaultBodyLimit::max(MAX_UPLOAD_CHUNK_BYTES)))
+        .route("/upload/{id}/complete", post(handle_upload_complete))
+        .route("/remote-upload", post(handle_remote_upload))
+        .route("/restore", post(handle_revert))
+        .route("/revert", post(handle_revert))
+        .route("/delete", post(handle_batch_delete))
+        .route("/duplicates/resolve", post(handle_resolve_duplicates))
+        .route("/trash/restore", post(handle_trash_restore))
+        .route("/restore-snapshot/{id}", post(handle_restore_snapshot))
+        .route("/archive", put(handle_put_archive_rule).delete(handle_delete_archive_rule))
+        .route("/restore-archive", post(handle_restore_archive))
+        .route("/move", post(handle_move))
+        .route("/rename", post(handle_rename))
+        .route("/vault/{item_id}", put(handle_put_vault_item).delete(handle_delete_vault_item))
+        .route_layer(axum::middleware::from_fn_with_state(appstate.clone(), freeze_writes))
+        .layer(compression);
+
+    let authenticated = Router::new()
+        .route("/sync/blocks", get(handle_block_manifest))
+        .route("/sync/precheck", post(handle_sync_precheck))
+        .route("/download", get(handle_file_download))
+        .route("/download/urls", post(handle_download_urls))
+        .route("/download/batch", post(handle_download_batch))
+        .route("/download/archive", post(handle_download_archive))
+        .route("/download/direct", get(handle_direct_download))
+        .route("/download/token", post(handle_create_download_token))
+        .route("/metadata", get(handle_metadata))
+        .route("/file", get(handle_get_file))
+        .route("/thumbnail", get(handle_thumbnail))
+        .route("/upload/{id}", head(handle_upload_status))
+        .route("/upload/{id}/status", get(handle_upload_session_status))
+        .route("/auth/2fa/enroll", post(handle_totp_enroll))
+        .route("/auth/2fa/verify", post(handle_totp_verify))
+        .route("/auth/2fa/disable", post(handle_totp_disable))
+        .route("/tokens", post(handle_create_api_token).get(handle_list_api_tokens))
+        .route("/tokens/{id}", delete(handle_revoke_api_token))
+        .route("/share", post(handle_create_share))
+        .route("/share/{id}", delete(handle_revoke_share))
+        .route("/share/{id}/stats", get(handle_share_stats))
+        .route("/filedrop", post(handle_create_filedrop).get(handle_list_filedrops))
+        .route("/filedrop/{id}", delete(handle_revoke_filedrop))
+        .route("/collaborators", post(handle_grant_folder).get(handle_list_collaborators))
+        .route("/collaborators/{id}", delete(handle_revoke_collaborator))
+        .route("/shared", get(handle_shared_with_me))
+        .route("/snapshots", get(handle_list_snapshots).post(handle_create_snapshot))
+        .route("/vault", get(handle_list_vault))
+        .route("/vault/{item_id}", get(handle_get_vault_item))
+        .route("/jobs/{id}", get(handle_get_job))
+        .route("/devices", get(handle_list_devices))
+        .route("/devices/register", post(handle_register_device))
+        .route("/lock", post(handle_lock).delete(handle_unlock))
+        .route("/webhooks", post(handle_create_webhook).get(handle_list_webhooks))
+        .route("/webhooks/{id}", delete(handle_delete_webhook))
+        .route("/webhooks/{id}/deliveries", get(handle_list_deliveries))
+        .route("/notifications", get(handle_get_notifications).put(handle_update_notifications))
+        .route("/profiles", put(handle_put_profile).get(handle_list_profiles))
+        .route("/policy", get(handle_get_policy))
+        .route("/archive", get(handle_list_archive_rules))
+        .route("/archive/files", get(handle_list_archived_files))
+        .route("/events", get(handle_events))
+        .route("/ws", get(handle_ws))
+        .route_service(&format!("/{}/{{*method}}", PocketSync::NAME), PocketSync::new(appstate.clone()))
+        .merge(writes)
+        .merge(listings)
+        .merge(operator)
+        .merge(admin)
+        .route_layer(axum::middleware::from_fn_with_state(appstate.clone(), limit_bandwidth))
+        .route_layer(axum::middleware::from_fn_with_state(appstate.clone(), throttle_user))
+        .route_layer(axum::middleware::from_fn_with_state(appstate.clone(), require_auth))
+        // Bodies sent with `Content-Encoding` are inflated before they reach a
+        // handler, so body limits and upload caps count the inflated bytes.
+        .layer(RequestDecompressionLayer::new());
+
+    // WebDAV clients mostly log in with HTTP Basic, so `/dav` has its own auth.
+    let dav = Router::new()
+        .route("/dav", any(handle_dav))
+        .route("/dav/", any(handle_dav))
+        .route("/dav/{*path}", any(handle_dav))
+        .route_layer(axum::middleware::from_fn_with_state(appstate.clone(), limit_bandwidth))
+        .route_layer(axum::middleware::from_fn_with_state(appstate.clone(), throttle_user))
+        .route_layer(axum::middleware::from_fn_with_state(appstate.clone(), require_dav_auth));
+
+    let cors = cors_layer(&appstate.config.cors);
+    // Only worth sending when the server is reached over HTTPS.
+    let hsts = appstate
+        .config
+        .public_base_url
+        .starts_with("https://")
+        .then(|| HeaderValue::from_static("max-age=31536000; includeSubDomains"));
+
+    // `/stream` URLs carry their own signature, and share links, file drop links
+    // and download tokens are credentials of their own, so they stay outside bearer auth. `/metrics` is scraped with `METRICS_TOKEN`.
+    // Clients read `/capabilities` before they have a token.
+    let app = Router::new()
+        .route("/", get(root))
+        .route("/openapi.json", get(handle_openapi))
+        .route("/docs", get(handle_docs))
+        .route("/capabilities", get(handle_capabilities))
+        .route("/auth/login", post(handle_login))
+        .route("/stream", get(handle_stream_get))
+        .route(
+            "/stream",
+            put(handle_stream_put)
+                .layer(DefaultBodyLimit::disable())
+                .route_layer(axum::middleware::from_fn_with_state(appstate.clone(), freeze_writes)),
+        )
+        .route("/s/{token}", get(handle_share_download))
+        .route("/download/signed/{token}", get(handle_signed_download))
+        .route("/s/{token}/", get(handle_share_folder_root))
+        .route("/s/{token}/{*path}", get(handle_share_folder_path))
+        .route("/drop/{token}", get(handle_filedrop_page))
+        .route(
+            "/drop/{token}",
+            post(handle_filedrop_upload)
+                .layer(DefaultBodyLimit::disable())
+                .route_layer(axum::middleware::from_fn_with_state(appstate.clone(), freeze_writes)),
+        )
+        .route("/metrics", get(handle_metrics))
+        .merge(authenticated)
+        .merge(dav)
+        .route_layer(axum::middleware::from_fn_with_state(appstate.clone(), rate_limit))
+        .route_layer(axum::middleware::from_fn(grpc_status))
+        // Probes are polled constantly, so they skip the rate limit.
+        .route("/healthz", get(handle_healthz))
+        .route("/readyz", get(handle_readyz))
+        .route_layer(axum::middleware::from_fn_with_state(appstate.clone(), track_requests))
+        .layer(DefaultBodyLimit::max(appstate.config.max_body_bytes))
+        .with_state(appstate)
+        .layer(PropagateRequestIdLayer::x_request_id())
+        .layer(
+            TraceLayer::new_for_http()
+                .make_span_with(request_span)
+                .on_request(DefaultOnRequest::new().level(Level::DEBUG))
+                .on_response(DefaultOnResponse::new().level(Level::INFO).latency_unit(LatencyUnit::Millis)),
+        )
+        .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid))
+        .layer(SetResponseHeaderLayer::if_not_present(
+            header::X_CONTENT_TYPE_OPTIONS,
+            HeaderValue::from_static("nosniff"),
+        ))
+        .layer(SetResponseHeaderLayer::if_not_present(header::X_FRAME_OPTIONS, HeaderValue::from_static("DENY")))
+        .layer(SetResponseHeaderLayer::if_not_present(
+            header::REFERRER_POLICY,
+            HeaderValue::from_static("no-referrer"),
+        ))
+        .layer(SetResponseHeaderLayer::if_not_present(header::STRICT_TRANSPORT_SECURITY, hsts));
+
+    let Some(cors) = cors else { return app };
+    // WebDAV clients send `OPTIONS` to discover the server, which the CORS layer
+    // would answer as a preflight, so `/dav` requests go around it.
+    let with_cors = app.clone().layer(cors);
+    Router::new().fallback_service(service_fn(move |req: Request| {
+        let path = req.uri().path();
+        let is_dav = path.strip_prefix(DAV_PREFIX).is_some_and(|rest| rest.is_empty() || rest.starts_with('/'));
+        let router = if is_dav { app.clone() } else { with_cors.clone() };
+        router.oneshot(req)
+    }))
+}
+
+/// The CORS policy of `config`, or `None` when no origin is allowed. Panics on
+/// entries that aren't valid origins, methods or header names.
+fn cors_layer(config: &CorsConfig) -> Option<CorsLayer> {
+    if config.allowed_origins.is_empty() {
+        return None;
+    }
+    let any = |items: &[String]| items.iter().any(|item| item == "*");
+
+    let origins = if any(&config.allowed_origins) {
+        AllowOrigin::any()
+    } else {
+        AllowOrigin::list(config.allowed_origins.iter().map(|origin| {
+            origin.parse::<HeaderValue>().unwrap_or_else(|_| panic!("Invalid CORS origin {}", origin))
+        }))
+    };
+    let methods = if any(&config.allowed_methods) {
+        AllowMethods::any()
+    } else {
+        AllowMethods::list(config.allowed_methods.iter().map(|method| {
+            method.parse::<Method>().unwrap_or_else(|_| panic!("Invalid CORS method {}", method))
+        }))
+    };
+    let headers = if any(&config.allowed_headers) {
+        AllowHeaders::any()
+    } else {
+        AllowHeaders::list(config.allowed_headers.iter().map(|name| {
+            name.parse::<HeaderName>().unwrap_or_else(|_| panic!("Invalid CORS header {}", name))
+        }))
+    };
+
+    Some(
+        CorsLayer::new()
+            .allow_origin(origins)
+            .allow_methods(methods)
+            .allow_headers(headers)
+            .expose_headers([
+                header::CONTENT_DISPOSITION,
+                header::CONTENT_RANGE,
+                header::ETAG,
+                header::RETRY_AFTER,
+                HeaderName::from_static("x-request-id"),
+            ])
+            .max_age(Duration::from_secs(config.max_age_secs)),
+    )
+}
+
+/// The span every request's logs, storage calls and queries are recorded under.
+/// Only the path is logged: query strings can carry signatures and share passwords.
+fn request_span(req: &Request) -> tracing::Span {
+    let request_id = req
+        .headers()
+        .get("x-request-id")
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+    info_span!("request", request_id, method = %req.method(), path = %req.uri().path())
+}
@@ -0,0 +1,166 @@
+//! Malware scanning of stored content, by whichever scanner `ScanConfig` names.
+//! The quarantine that acts on the verdicts is in `handlers::quarantine`.
+
+use std::{process::Stdio, time::Duration};
+
+use serde::Deserialize;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    process::Command,
+};
+
+use crate::config::ScanConfig;
+
+/// Bytes sent to clamd per `INSTREAM` chunk.
+const CLAMAV_CHUNK_BYTES: usize = 64 * 1024;
+
+/// What a scanner made of some content.
+pub(crate) enum Verdict {
+    Clean,
+    /// Infected with what the scanner names, e.g. `Win.Test.EICAR_HDB-1`.
+    Infected(String),
+}
+
+enum Engine {
+    ClamAv(String),
+    Command(String),
+    Http { client: reqwest::Client, url: String },
+}
+
+/// A configured scanner. Each scan is bounded by `timeout_secs`, and one that
+/// can't reach a verdict fails with the reason.
+pub(crate) struct Scanner {
+    engine: Engine,
+    timeout: Duration,
+    /// Files larger than this are left unscanned.
+    pub(crate) max_bytes: i64,
+}
+
+/// The answer expected from an HTTP scanner.
+#[derive(Deserialize)]
+struct HttpVerdict {
+    infected: bool,
+    signature: Option<String>,
+}
+
+impl Scanner {
+    /// The scanner `config` names, or `None` when scanning is off. Panics when more
+    /// than one is named, rather than picking one.
+    pub(crate) fn from_config(config: &ScanConfig) -> Option<Self> {
+        let engine = match (&config.clamav_addr, &config.command, &config.http_url) {
+            (None, None, None) => return None,
+            (Some(addr), None, None) => Engine::ClamAv(addr.clone()),
+            (None, Some(command), None) => Engine::Command(command.clone()),
+            (None, None, Some(url)) => Engine::Http { client: reqwest::Client::new(), url: url.clone() },
+            _ => panic!("Set only one of SCAN_CLAMAV_ADDR, SCAN_COMMAND and SCAN_HTTP_URL"),
+        };
+        Some(Self { engine, timeout: Duration::from_secs(config.timeout_secs), max_bytes: config.max_bytes })
+    }
+
+    /// A short name of the scanner for logs.
+    pub(crate) fn name(&self) -> &'static str {
+        match self.engine {
+            Engine::ClamAv(_) => "clamav",
+            Engine::Command(_) => "command",
+            Engine::Http { .. } => "http",
+        }
+    }
+
+    pub(crate) async fn scan(&self, data: &[u8]) -> Result<Verdict, String> {
+        let scan = async {
+            match &self.engine {
+                Engine::ClamAv(addr) => scan_clamav(addr, data).await,
+                Engine::Command(command) => scan_command(command, data).await,
+                Engine::Http { client, url } => scan_http(client, url, data).await,
+            }
+        };
+        tokio::time::timeout(self.timeout, scan)
+            .await
+            .map_err(|_| format!("no verdict within {}s", self.timeout.as_secs()))?
+    }
+}
+
+/// Streams `data` to clamd with `INSTREAM`, which answers `stream: OK` or
+/// `stream: <signature> FOUND`.
+async fn scan_clamav(addr: &str, data: &[u8]) -> Result<Verdict, String> {
+    let mut stream = TcpStream::connect(addr)
+        .await
+        .map_err(|e| format!("Failed to connect to clamd at {}: {}", addr, e))?;
+    let io = |e: std::io::Error| format!("clamd connection failed: {}", e);
+
+    stream.write_all(b"zINSTREAM\0").await.map_err(io)?;
+    for chunk in data.chunks(CLAMAV_CHUNK_BYTES) {
+        stream.write_all(&(chunk.len() as u32).to_be_bytes()).await.map_err(io)?;
+        stream.write_all(chunk).await.map_err(io)?;
+    }
+    stream.write_all(&0u32.to_be_bytes()).await.map_err(io)?;
+
+    let mut reply = Vec::new();
+    stream.read_to_end(&mut reply).await.map_err(io)?;
+    let reply = String::from_utf8_lossy(&reply);
+    let reply = reply.trim_end_matches(['\0', '\n']);
+    match reply.strip_prefix("stream: ") {
+        Some("OK") => Ok(Verdict::Clean),
+        Some(found) if found.ends_with(" FOUND") => {
+            Ok(Verdict::Infected(found.trim_end_matches(" FOUND").to_string()))
+        }
+        _ => Err(format!("clamd answered {:?}", reply)),
+    }
+}
+
+/// Pipes `data` into `command`, whose exit status is the verdict.
+async fn scan_command(command: &str, data: &[u8]) -> Result<Verdict, String> {
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| format!("Failed to run scan command: {}", e))?;
+
+    // Written while the output is read, so a command that answers before reading
+    // all of its input can't block on a full pipe. It may stop reading early, so a
+    // broken pipe isn't an error.
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    let write = async move {
+        let _ = stdin.write_all(data).await;
+    };
+    let (output, ()) = tokio::join!(child.wait_with_output(), write);
+    let output = output.map_err(|e| format!("Scan command failed: {}", e))?;
+
+    let stdout = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    match output.status.code() {
+        Some(0) => Ok(Verdict::Clean),
+        Some(1) if stdout.is_empty() => Ok(Verdict::Infected("unnamed".to_string())),
+        Some(1) => Ok(Verdict::Infected(stdout)),
+        _ => Err(format!(
+            "Scan command exited with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )),
+    }
+}
+
+/// Posts `data` to an HTTP scanner and reads its JSON verdict.
+async fn scan_http(client: &reqwest::Client, url: &str, data: &[u8]) -> Result<Verdict, String> {
+    let res = client
+        .post(url)
+        .header(reqwest::header::CONTENT_TYPE, "application/octet-stream")
+        .body(data.to_vec())
+        .send()
+        .await
+        .map_err(|e| format!("Scanner request failed: {}", e))?;
+    if !res.status().is_success() {
+        return Err(format!("Scanner answered {}", res.status()));
+    }
+    let body = res.bytes().await.map_err(|e| format!("Scanner request failed: {}", e))?;
+    let verdict: HttpVerdict = serde_json::from_slice(&body)
+        .map_err(|e| format!("Scanner sent an invalid verdict: {}", e))?;
+    Ok(match verdict {
+        HttpVerdict { infected: false, .. } => Verdict::Clean,
+        HttpVerdict { signature, .. } => Verdict::Infected(signature.unwrap_or_else(|| "unnamed".to_string())),
+    })
+}
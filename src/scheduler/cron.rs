@@ -0,0 +1,144 @@
+//! Cron expressions: `minute hour day-of-month month day-of-week`, in UTC. Each
+//! field takes `*`, numbers, ranges `a-b`, steps `*/n` and `a-b/n`, and lists of
+//! those; months and weekdays also take their English three-letter names, and
+//! Sunday is both 0 and 7. `@hourly`, `@daily`, `@weekly`, `@monthly` and
+//! `@yearly` stand for the usual expressions. As in cron, a day matches when
+//! either its day of the month or its weekday does, if both are restricted.
+
+use chrono::{DateTime, Datelike, Duration, NaiveDate, Timelike, Utc};
+
+const MONTHS: [&str; 12] = ["jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec"];
+const WEEKDAYS: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+/// Years searched for a matching minute before a schedule is taken to never run,
+/// as `0 0 30 2 *` never does.
+const SEARCH_YEARS: i32 = 5;
+
+/// A parsed cron expression. Each field is a bitmask of the values it matches.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Schedule {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    /// Whether day-of-month and day-of-week were both restricted.
+    either_day: bool,
+}
+
+impl Schedule {
+    pub(crate) fn parse(expression: &str) -> Result<Self, String> {
+        let expression = match expression.trim() {
+            "@hourly" => "0 * * * *",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@weekly" => "0 0 * * 0",
+            "@monthly" => "0 0 1 * *",
+            "@yearly" | "@annually" => "0 0 1 1 *",
+            other => other,
+        };
+        let fields: Vec<&str> = expression.split_whitespace().collect();
+        let [minutes, hours, days, months, weekdays] = fields[..] else {
+            return Err(format!("{:?} must have 5 fields, not {}", expression, fields.len()));
+        };
+        let mut weekday_mask = field(weekdays, 0, 7, &WEEKDAYS, 0)?;
+        // 7 is Sunday too.
+        if weekday_mask & (1 << 7) != 0 {
+            weekday_mask = (weekday_mask | 1) & !(1 << 7);
+        }
+        Ok(Self {
+            minutes: field(minutes, 0, 59, &[], 0)?,
+            hours: field(hours, 0, 23, &[], 0)?,
+            days: field(days, 1, 31, &[], 0)?,
+            months: field(months, 1, 12, &MONTHS, 1)?,
+            weekdays: weekday_mask,
+            either_day: !days.starts_with('*') && !weekdays.starts_with('*'),
+        })
+    }
+
+    /// The first minute after `after` the schedule matches, or `None` when it
+    /// matches none in the next `SEARCH_YEARS` years.
+    pub(crate) fn next_after(&self, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let mut at = after.with_second(0)?.with_nanosecond(0)? + Duration::minutes(1);
+        let end = after + Duration::days(366 * SEARCH_YEARS as i64);
+        while at <= end {
+            if !matches(self.months, at.month()) {
+                let (year, month) = if at.month() == 12 { (at.year() + 1, 1) } else { (at.year(), at.month() + 1) };
+                at = NaiveDate::from_ymd_opt(year, month, 1)?.and_hms_opt(0, 0, 0)?.and_utc();
+                continue;
+            }
+            if !self.matches_day(at) {
+                at = (at.date_naive() + Duration::days(1)).and_hms_opt(0, 0, 0)?.and_utc();
+                continue;
+            }
+            if !matches(self.hours, at.hour()) {
+                at = at.with_minute(0)? + Duration::hours(1);
+                continue;
+            }
+            if !matches(self.minutes, at.minute()) {
+                at += Duration::minutes(1);
+                continue;
+            }
+            return Some(at);
+        }
+        None
+    }
+
+    fn matches_day(&self, at: DateTime<Utc>) -> bool {
+        let day = matches(self.days, at.day());
+        let weekday = matches(self.weekdays, at.weekday().num_days_from_sunday());
+        if self.either_day { day || weekday } else { day && weekday }
+    }
+}
+
+fn matches(mask: u64, value: u32) -> bool {
+    mask & (1 << value) != 0
+}
+
+/// The mask of one field, whose values run from `min` to `max`. `names` spell
+/// the values from `first_named` on.
+fn field(spec: &str, min: u32, max: u32, names: &[&str], first_named: u32) -> Result<u64, String> {
+    let value = |text: &str| -> Result<u32, String> {
+        let lower = text.to_ascii_lowercase();
+        let value = match names.iter().position(|name| *name == lower) {
+            Some(i) => i as u32 + first_named,
+            None => text.parse().map_err(|_| format!("{:?} isn't a number", text))?,
+        };
+        if !(min..=max).contains(&value) {
+            return Err(format!("{} is outside {}-{}", value, min, max));
+        }
+        Ok(value)
+    };
+
+    let mut mask = 0u64;
+    for part in spec.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => {
+                let step: u32 = step.parse().map_err(|_| format!("{:?} isn't a step", step))?;
+                if step == 0 {
+                    return Err("a step must be at least 1".into());
+                }
+                (range, step)
+            }
+            None => (part, 1),
+        };
+        let (start, end) = match range {
+            "*" => (min, max),
+            range => match range.split_once('-') {
+                Some((start, end)) => (value(start)?, value(end)?),
+                // `a/n` runs from `a` to the end.
+                None if step > 1 => (value(range)?, max),
+                None => {
+                    let value = value(range)?;
+                    (value, value)
+                }
+            },
+        };
+        if start > end {
+            return Err(format!("{:?} runs backwards", range));
+        }
+        for value in (start..=end).step_by(step as usize) {
+            mask |= 1 << value;
+        }
+    }
+    Ok(mask)
+}
@@ -0,0 +1,283 @@
+//! Background jobs run by the clock. Every job is registered here, and can be
+//! run on demand through `POST /admin/schedule/{name}/run`; those `ScheduleConfig`
+//! gives a cron expression also run whenever it matches, instead of on their
+//! `*_INTERVAL_SECS`. A run is never started while another of the same job is
+//! going. When each job is next due and how its last run went are kept in
+//! `scheduled_jobs`, so a run missed while the server was down happens once it's
+//! back up.
+
+mod cron;
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use chrono::{DateTime, NaiveDateTime, Utc};
+use futures::future::BoxFuture;
+use tracing::{info, warn};
+
+use crate::{
+    config::ScheduleConfig,
+    db::on_db,
+    handlers::{
+        archive,
+        integrity::verify_sample,
+        jobs::reconcile_on_schedule,
+        listing::prune_tombstones,
+        maintenance::in_maintenance,
+        snapshots::snapshot_trees,
+        trash::purge_trash,
+        uploads::{abort_orphaned_multipart, expire_upload_sessions},
+    },
+    AppState,
+};
+
+use self::cron::Schedule;
+
+/// One run of a job.
+type JobFn = for<'a> fn(&'a AppState) -> BoxFuture<'a, Result<(), String>>;
+
+pub(crate) struct Job {
+    pub(crate) name: &'static str,
+    /// The cron expression the job runs on, as configured, and what it parsed to.
+    schedule: Option<(String, Schedule)>,
+    run: JobFn,
+    running: AtomicBool,
+}
+
+impl Job {
+    pub(crate) fn schedule(&self) -> Option<&str> {
+        self.schedule.as_ref().map(|(expression, _)| expression.as_str())
+    }
+
+    pub(crate) fn is_running(&self) -> bool {
+        self.running.load(Ordering::Acquire)
+    }
+}
+
+/// The jobs there are, in the order they were registered.
+pub(crate) struct Scheduler {
+    jobs: Vec<Job>,
+}
+
+impl Scheduler {
+    /// Every job, with the schedules `config` gives them. Panics on a schedule of
+    /// a job there isn't, or one that doesn't parse, rather than start without it.
+    pub(crate) fn from_config(config: &ScheduleConfig) -> Self {
+        let mut scheduler = Self { jobs: Vec::new() };
+        scheduler.register("trash_purge", trash_purge);
+        scheduler.register("tombstone_prune", tombstone_prune);
+        scheduler.register("snapshots", snapshots);
+        scheduler.register("archive", archive_sweep);
+        scheduler.register("upload_sweep", upload_sweep);
+        scheduler.register("reconcile", reconcile);
+        scheduler.register("integrity_check", integrity_check);
+
+        for (name, expression) in &config.jobs {
+            let names: Vec<&str> = scheduler.jobs.iter().map(|job| job.name).collect();
+            let job = scheduler
+                .jobs
+                .iter_mut()
+                .find(|job| job.name == name)
+                .unwrap_or_else(|| panic!("Unknown scheduled job {}; expected one of {}", name, names.join(", ")));
+            let schedule = Schedule::parse(expression)
+                .unwrap_or_else(|e| panic!("Invalid schedule of {}: {}", name, e));
+            job.schedule = Some((expression.trim().to_string(), schedule));
+        }
+        scheduler
+    }
+
+    fn register(&mut self, name: &'static str, run: JobFn) {
+        self.jobs.push(Job { name, schedule: None, run, running: AtomicBool::new(false) });
+    }
+
+    pub(crate) fn jobs(&self) -> &[Job] {
+        &self.jobs
+    }
+
+    pub(crate) fn job(&self, name: &str) -> Option<&Job> {
+        self.jobs.iter().find(|job| job.name == name)
+    }
+
+    /// Whether `name` runs on a cron schedule rather than its interval.
+    pub(crate) fn schedules(&self, name: &str) -> bool {
+        self.job(name).is_some_and(|job| job.schedule.is_some())
+    }
+}
+
+/// Clears a job's running flag however its run ends.
+struct Running<'a>(&'a AtomicBool);
+
+impl Drop for Running<'_> {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::Release);
+    }
+}
+
+/// Runs `job` now and records how it went, or returns `None` when a run of it is
+/// still going.
+pub(crate) async fn run_job(state: &AppState, job: &Job) -> Option<Result<(), String>> {
+    if job.running.swap(true, Ordering::AcqRel) {
+        return None;
+    }
+    let _running = Running(&job.running);
+
+    info!("Running job {}", job.name);
+    record_start(state, job.name).await;
+    let result = (job.run)(state).await;
+    match &result {
+        Ok(()) => info!("Job {} finished", job.name),
+        Err(e) => warn!("Job {} failed: {}", job.name, e),
+    }
+    record_finish(state, job.name, &result).await;
+    Some(result)
+}
+
+/// Runs the job at `index` whenever its schedule matches, outside maintenance,
+/// until the server shuts down.
+pub(crate) async fn run_on_schedule(state: AppState, index: usize) {
+    let scheduler = state.scheduler.clone();
+    let job = &scheduler.jobs[index];
+    let Some((expression, schedule)) = &job.schedule else { return };
+
+    // Picks up where the last server left off, unless the schedule changed since.
+    let mut next = match stored_next_run(&state, job.name, expression).await {
+        Some(at) => Some(at),
+        None => schedule.next_after(Utc::now()),
+    };
+    loop {
+        let Some(at) = next else {
+            warn!("The schedule {:?} of job {} never matches", expression, job.name);
+            return;
+        };
+        record_next_run(&state, job.name, expression, at).await;
+        let wait = (at - Utc::now()).to_std().unwrap_or_default();
+        tokio::select! {
+            _ = tokio::time::sleep(wait) => {}
+            _ = state.shutdown.cancelled() => return,
+        }
+
+        if in_maintenance(&state).await {
+            info!("Skipped job {} during maintenance", job.name);
+        } else if run_job(&state, job).await.is_none() {
+            info!("Skipped job {}: its previous run is still going", job.name);
+        }
+        next = schedule.next_after(Utc::now());
+    }
+}
+
+/// When the job is next due, as stored for the same `expression`.
+async fn stored_next_run(state: &AppState, name: &str, expression: &str) -> Option<DateTime<Utc>> {
+    let stored = on_db!(&state.pool, pool => sqlx::query_scalar::<_, Option<NaiveDateTime>>(
+        "SELECT next_run_at FROM scheduled_jobs WHERE name = $1 AND schedule = $2"
+    )
+    .bind(name)
+    .bind(expression)
+    .fetch_optional(pool)
+    .await);
+    match stored {
+        Ok(at) => at.flatten().map(|at| at.and_utc()),
+        Err(e) => {
+            warn!("Failed to load when job {} is due: {}", name, e);
+            None
+        }
+    }
+}
+
+async fn record_next_run(state: &AppState, name: &str, expression: &str, at: DateTime<Utc>) {
+    let recorded = on_db!(&state.pool, pool => sqlx::query(
+        r#"
+        INSERT INTO scheduled_jobs (name, schedule, next_run_at) VALUES ($1, $2, $3)
+        ON CONFLICT (name) DO UPDATE SET schedule = excluded.schedule, next_run_at = excluded.next_run_at
+        "#
+    )
+    .bind(name)
+    .bind(expression)
+    .bind(at.naive_utc())
+    .execute(pool)
+    .await
+    .map(|_| ()));
+    if let Err(e) = recorded {
+        warn!("Failed to record when job {} is due: {}", name, e);
+    }
+}
+
+async fn record_start(state: &AppState, name: &str) {
+    let recorded = on_db!(&state.pool, pool => sqlx::query(
+        r#"
+        INSERT INTO scheduled_jobs (name, last_started_at) VALUES ($1, CURRENT_TIMESTAMP)
+        ON CONFLICT (name) DO UPDATE SET last_started_at = excluded.last_started_at
+        "#
+    )
+    .bind(name)
+    .execute(pool)
+    .await
+    .map(|_| ()));
+    if let Err(e) = recorded {
+        warn!("Failed to record the start of job {}: {}", name, e);
+    }
+}
+
+async fn record_finish(state: &AppState, name: &str, result: &Result<(), String>) {
+    let (status, error) = match result {
+        Ok(()) => ("succeeded", None),
+        Err(e) => ("failed", Some(e.as_str())),
+    };
+    let recorded = on_db!(&state.pool, pool => sqlx::query(
+        r#"
+        INSERT INTO scheduled_jobs (name, last_finished_at, last_status, last_error)
+        VALUES ($1, CURRENT_TIMESTAMP, $2, $3)
+        ON CONFLICT (name) DO UPDATE
+        SET last_finished_at = excluded.last_finished_at, last_status = excluded.last_status,
+            last_error = excluded.last_error
+        "#
+    )
+    .bind(name)
+    .bind(status)
+    .bind(error)
+    .execute(pool)
+    .await
+    .map(|_| ()));
+    if let Err(e) = recorded {
+        warn!("Failed to record how job {} went: {}", name, e);
+    }
+}
+
+fn trash_purge(state: &AppState) -> BoxFuture<'_, Result<(), String>> {
+    Box::pin(async move {
+        purge_trash(state).await;
+        Ok(())
+    })
+}
+
+fn tombstone_prune(state: &AppState) -> BoxFuture<'_, Result<(), String>> {
+    Box::pin(async move { prune_tombstones(state).await.map_err(|e| e.to_string()) })
+}
+
+fn snapshots(state: &AppState) -> BoxFuture<'_, Result<(), String>> {
+    Box::pin(async move {
+        snapshot_trees(state).await;
+        Ok(())
+    })
+}
+
+fn archive_sweep(state: &AppState) -> BoxFuture<'_, Result<(), String>> {
+    Box::pin(async move {
+        archive::sweep(state).await;
+        Ok(())
+    })
+}
+
+fn upload_sweep(state: &AppState) -> BoxFuture<'_, Result<(), String>> {
+    Box::pin(async move {
+        expire_upload_sessions(state).await;
+        abort_orphaned_multipart(state).await;
+        Ok(())
+    })
+}
+
+fn reconcile(state: &AppState) -> BoxFuture<'_, Result<(), String>> {
+    Box::pin(reconcile_on_schedule(state))
+}
+
+fn integrity_check(state: &AppState) -> BoxFuture<'_, Result<(), String>> {
+    Box::pin(async move { verify_sample(state, state.integrity_sample_size).await.map(|_| ()) })
+}
@@ -0,0 +1,138 @@
+//! Tunables admins can change while the server runs. Each starts out as the
+//! environment or config file sets it, and a row in `settings` overrides it. The
+//! rows are re-read every `SETTINGS_REFRESH_SECS`, so a change made through one
+//! instance reaches every other without a restart.
+
+use std::sync::{Arc, RwLock};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::policy::UploadPolicy;
+
+/// The most a presigned S3 URL can live for.
+const MAX_PRESIGN_EXPIRY_SECS: u64 = 7 * 24 * 3600;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Settings {
+    /// Lifetime of every presigned download and upload URL handed out.
+    pub(crate) presign_expiry_secs: u64,
+    /// Largest file accepted by any upload path; unlimited when `None`.
+    pub(crate) max_upload_bytes: Option<u64>,
+    /// Bytes of live files each user may store, unless their own quota says
+    /// otherwise; unlimited when `None`.
+    pub(crate) default_quota_bytes: Option<u64>,
+    /// Days deleted files stay in the trash. Zero deletes them at once.
+    pub(crate) trash_retention_days: i64,
+    /// Days deletions are remembered for devices that haven't caught up. Zero
+    /// remembers them forever.
+    pub(crate) tombstone_retention_days: i64,
+    /// Previous revisions kept per file.
+    pub(crate) max_file_versions: i64,
+    /// Most paths one `/delete` accepts.
+    pub(crate) max_delete_batch: usize,
+    /// Whether reconciliation deletes stored objects no row refers to, rather than
+    /// only reporting them.
+    pub(crate) reconcile_delete_orphans: bool,
+    /// Bytes a second each user may upload, across all their devices and requests;
+    /// unlimited when `None`. Limits are kept per instance.
+    pub(crate) user_upload_bytes_per_sec: Option<u64>,
+    /// Bytes a second each user may download, likewise.
+    pub(crate) user_download_bytes_per_sec: Option<u64>,
+    /// Bytes a second each of a user's devices may upload, by `X-Device-Id`.
+    pub(crate) device_upload_bytes_per_sec: Option<u64>,
+    /// Bytes a second each of a user's devices may download.
+    pub(crate) device_download_bytes_per_sec: Option<u64>,
+    /// What `/sync` refuses to store for anyone; users can be held to more.
+    pub(crate) upload_policy: UploadPolicy,
+}
+
+impl Settings {
+    fn validate(&self) -> Result<(), String> {
+        if !(1..=MAX_PRESIGN_EXPIRY_SECS).contains(&self.presign_expiry_secs) {
+            return Err(format!("presign_expiry_secs must be between 1 and {}", MAX_PRESIGN_EXPIRY_SECS));
+        }
+        if self.trash_retention_days < 0 {
+            return Err("trash_retention_days can't be negative".into());
+        }
+        if self.tombstone_retention_days < 0 {
+            return Err("tombstone_retention_days can't be negative".into());
+        }
+        if self.max_file_versions < 0 {
+            return Err("max_file_versions can't be negative".into());
+        }
+        if self.max_delete_batch == 0 {
+            return Err("max_delete_batch must be at least 1".into());
+        }
+        let rates = [
+            ("user_upload_bytes_per_sec", self.user_upload_bytes_per_sec),
+            ("user_download_bytes_per_sec", self.user_download_bytes_per_sec),
+            ("device_upload_bytes_per_sec", self.device_upload_bytes_per_sec),
+            ("device_download_bytes_per_sec", self.device_download_bytes_per_sec),
+        ];
+        if let Some((name, _)) = rates.iter().find(|(_, rate)| *rate == Some(0)) {
+            return Err(format!("{} must be at least 1, or null for no limit", name));
+        }
+        self.upload_policy.validate().map_err(|e| format!("upload_policy: {}", e))?;
+        Ok(())
+    }
+
+    /// A copy of these settings with `overrides` applied over them, or why they
+    /// don't make valid settings.
+    pub(crate) fn with_overrides<'a>(
+        &self,
+        overrides: impl IntoIterator<Item = (&'a str, &'a Value)>,
+    ) -> Result<Self, String> {
+        let mut fields = self.fields();
+        for (key, value) in overrides {
+            match fields.get_mut(key) {
+                Some(field) => *field = value.clone(),
+                None => return Err(format!("Unknown setting {}", key)),
+            }
+        }
+        let settings: Self = serde_json::from_value(Value::Object(fields)).map_err(|e| e.to_string())?;
+        settings.validate()?;
+        Ok(settings)
+    }
+
+    /// Each setting by name.
+    pub(crate) fn fields(&self) -> Map<String, Value> {
+        match serde_json::to_value(self) {
+            Ok(Value::Object(fields)) => fields,
+            _ => unreachable!("settings serialize to an object"),
+        }
+    }
+}
+
+/// The settings in force, swapped whole whenever the overrides change so readers
+/// never see half of an update.
+pub(crate) struct LiveSettings {
+    /// What the environment and config file set, which overrides apply over.
+    defaults: Settings,
+    current: RwLock<Arc<Settings>>,
+}
+
+impl LiveSettings {
+    pub(crate) fn new(defaults: Settings) -> Self {
+        Self { current: RwLock::new(Arc::new(defaults.clone())), defaults }
+    }
+
+    pub(crate) fn defaults(&self) -> &Settings {
+        &self.defaults
+    }
+
+    pub(crate) fn current(&self) -> Arc<Settings> {
+        self.current.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Puts `settings` in force, returning whether anything changed.
+    pub(crate) fn replace(&self, settings: Settings) -> bool {
+        let mut current = self.current.write().unwrap_or_else(|e| e.into_inner());
+        if **current == settings {
+            return false;
+        }
+        *current = Arc::new(settings);
+        true
+    }
+}
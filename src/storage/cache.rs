@@ -0,0 +1,544 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use axum::body::Bytes;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio_stream::StreamExt;
+use tracing::{debug, info, warn};
+
+use crate::{
+    config::AppConfig,
+    storage::{
+        read_part, stream_signer, ByteChunks, ObjectBody, ObjectInfo, RestoreState, StorageBackend, StorageClass, StorageError,
+        StreamParams, StreamSigner,
+    },
+    WRITE_BEHIND_RETRY_SECS,
+};
+
+/// What a pending write-behind needs to be resumed after a restart, kept in
+/// `pending/` beside the cached content.
+#[derive(Serialize, Deserialize)]
+struct PendingWrite {
+    key: String,
+    content_type: String,
+}
+
+struct Entry {
+    size: u64,
+    content_type: String,
+    /// Position in `Index::by_use`.
+    last_used: u64,
+    /// Tells a rewrite of the key apart from the write it replaced.
+    generation: u64,
+    /// Not yet written to the wrapped backend, so it can't be evicted.
+    pending: bool,
+}
+
+#[derive(Default)]
+struct Index {
+    entries: HashMap<String, Entry>,
+    /// Keys that may be evicted, least recently used first.
+    by_use: BTreeMap<u64, String>,
+    clock: u64,
+    bytes: u64,
+}
+
+impl Index {
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+
+    fn insert(&mut self, key: &str, size: u64, content_type: &str, pending: bool) -> u64 {
+        self.remove(key);
+        let tick = self.tick();
+        if !pending {
+            self.by_use.insert(tick, key.to_string());
+        }
+        self.bytes += size;
+        let entry = Entry { size, content_type: content_type.to_string(), last_used: tick, generation: tick, pending };
+        self.entries.insert(key.to_string(), entry);
+        tick
+    }
+
+    fn remove(&mut self, key: &str) -> Option<Entry> {
+        let entry = self.entries.remove(key)?;
+        self.by_use.remove(&entry.last_used);
+        self.bytes -= entry.size;
+        Some(entry)
+    }
+
+    /// Marks `key` as just used, returning its size.
+    fn touch(&mut self, key: &str) -> Option<u64> {
+        let tick = self.tick();
+        let entry = self.entries.get_mut(key)?;
+        if !entry.pending {
+            self.by_use.remove(&entry.last_used);
+            self.by_use.insert(tick, key.to_string());
+        }
+        entry.last_used = tick;
+        Some(entry.size)
+    }
+
+    /// Drops the least recently used keys until at most `max_bytes` are held,
+    /// returning them.
+    fn evict(&mut self, max_bytes: u64) -> Vec<String> {
+        let mut evicted = Vec::new();
+        while self.bytes > max_bytes {
+            let Some((_, key)) = self.by_use.pop_first() else { break };
+            if let Some(entry) = self.entries.remove(&key) {
+                self.bytes -= entry.size;
+            }
+            evicted.push(key);
+        }
+        evicted
+    }
+}
+
+/// What became of a key while its write-behind was in flight.
+enum Written {
+    /// The write is still the latest; it is no longer pending.
+    Current,
+    /// The key was written again since, so the newer write stands.
+    Superseded,
+    /// The key was deleted since, so the write undid the delete.
+    Deleted,
+}
+
+struct Cache {
+    dir: PathBuf,
+    index: Mutex<Index>,
+    max_bytes: u64,
+}
+
+impl Cache {
+    fn file_name(key: &str) -> String {
+        hex::encode(Sha256::digest(key.as_bytes()))
+    }
+
+    fn object_path(&self, key: &str) -> PathBuf {
+        self.dir.join("objects").join(Self::file_name(key))
+    }
+
+    fn pending_path(&self, key: &str) -> PathBuf {
+        self.dir.join("pending").join(Self::file_name(key))
+    }
+
+    /// Opens the cache under `dir`, dropping what a previous run cached but
+    /// keeping its pending writes, which it returns.
+    fn open(dir: &Path, max_bytes: u64) -> std::io::Result<(Self, Vec<PendingWrite>)> {
+        std::fs::create_dir_all(dir.join("objects"))?;
+        std::fs::create_dir_all(dir.join("pending"))?;
+        let cache = Self { dir: dir.to_path_buf(), index: Mutex::new(Index::default()), max_bytes };
+
+        let mut pending = Vec::new();
+        let mut kept = HashSet::new();
+        for marker in std::fs::read_dir(dir.join("pending"))? {
+            let marker = marker?.path();
+            let write = std::fs::read(&marker)
+                .ok()
+                .and_then(|data| serde_json::from_slice::<PendingWrite>(&data).ok());
+            let size = write.as_ref().and_then(|w| std::fs::metadata(cache.object_path(&w.key)).ok()).map(|m| m.len());
+            match (write, size) {
+                (Some(write), Some(size)) => {
+                    cache.index.lock().unwrap().insert(&write.key, size, &write.content_type, true);
+                    kept.insert(Self::file_name(&write.key));
+                    pending.push(write);
+                }
+                _ => {
+                    warn!("Dropping unreadable write-behind marker {}", marker.display());
+                    std::fs::remove_file(&marker)?;
+                }
+            }
+        }
+        for object in std::fs::read_dir(dir.join("objects"))? {
+            let object = object?;
+            if !kept.contains(&*object.file_name().to_string_lossy()) {
+                std::fs::remove_file(object.path())?;
+            }
+        }
+        Ok((cache, pending))
+    }
+
+    /// Caches `data` under `key`, pending until written behind when `pending`,
+    /// and returns the generation of the entry.
+    async fn store(&self, key: &str, data: &[u8], content_type: &str, pending: bool) -> Result<u64, String> {
+        let path = self.object_path(key);
+        let partial = path.with_extension(hex::encode(rand::random::<[u8; 8]>()));
+        tokio::fs::write(&partial, data).await.map_err(|e| e.to_string())?;
+        if let Err(e) = tokio::fs::rename(&partial, &path).await {
+            let _ = tokio::fs::remove_file(&partial).await;
+            return Err(e.to_string());
+        }
+        if pending {
+            let marker = PendingWrite { key: key.to_string(), content_type: content_type.to_string() };
+            let marker = serde_json::to_vec(&marker).map_err(|e| e.to_string())?;
+            tokio::fs::write(self.pending_path(key), marker).await.map_err(|e| e.to_string())?;
+        }
+
+        let (generation, evicted) = {
+            let mut index = self.index.lock().unwrap();
+            let generation = index.insert(key, data.len() as u64, content_type, pending);
+            (generation, index.evict(self.max_bytes))
+        };
+        for key in evicted {
+            debug!("Evicting {} from the storage cache", key);
+            let _ = tokio::fs::remove_file(self.object_path(&key)).await;
+        }
+        Ok(generation)
+    }
+
+    /// The cached content of `key`, if any.
+    async fn read(&self, key: &str) -> Option<Vec<u8>> {
+        self.index.lock().unwrap().touch(key)?;
+        // Gone if evicted since the lookup.
+        tokio::fs::read(self.object_path(key)).await.ok()
+    }
+
+    fn size(&self, key: &str) -> Option<u64> {
+        self.index.lock().unwrap().touch(key)
+    }
+
+    fn is_pending(&self, key: &str) -> bool {
+        self.index.lock().unwrap().entries.get(key).is_some_and(|entry| entry.pending)
+    }
+
+    fn content_type(&self, key: &str) -> Option<String> {
+        self.index.lock().unwrap().entries.get(key).map(|entry| entry.content_type.clone())
+    }
+
+    fn pending_keys(&self, prefix: &str) -> Vec<String> {
+        let index = self.index.lock().unwrap();
+        index
+            .entries
+            .iter()
+            .filter(|(key, entry)| entry.pending && key.starts_with(prefix))
+            .map(|(key, _)| key.clone())
+            .collect()
+    }
+
+    /// Whether the write-behind of `generation` of `key` still needs doing.
+    fn still_pending(&self, key: &str, generation: u64) -> bool {
+        let index = self.index.lock().unwrap();
+        index.entries.get(key).is_some_and(|entry| entry.pending && entry.generation == generation)
+    }
+
+    async fn forget(&self, key: &str) {
+        if self.index.lock().unwrap().remove(key).is_some() {
+            let _ = tokio::fs::remove_file(self.object_path(key)).await;
+            let _ = tokio::fs::remove_file(self.pending_path(key)).await;
+        }
+    }
+
+    /// Records that `generation` of `key` reached the wrapped backend.
+    async fn written(&self, key: &str, generation: u64) -> Written {
+        let written = {
+            let mut index = self.index.lock().unwrap();
+            let tick = index.tick();
+            match index.entries.get_mut(key) {
+                Some(entry) if entry.generation == generation => {
+                    entry.pending = false;
+                    entry.last_used = tick;
+                    index.by_use.insert(tick, key.to_string());
+                    Written::Current
+                }
+                Some(_) => Written::Superseded,
+                None => Written::Deleted,
+            }
+        };
+        if let Written::Current = written {
+            let _ = tokio::fs::remove_file(self.pending_path(key)).await;
+            let evicted = self.index.lock().unwrap().evict(self.max_bytes);
+            for key in evicted {
+                let _ = tokio::fs::remove_file(self.object_path(&key)).await;
+            }
+        }
+        written
+    }
+}
+
+/// Keeps objects up to `max_object_bytes` on local disk in front of the wrapped
+/// backend, evicting the least recently used once the cache holds `max_bytes`.
+/// Writes are cached on the way in and misses on the way out; downloads of cached
+/// objects get a `/stream` URL served from disk instead of one to the backend.
+///
+/// With write-behind, small writes are acknowledged once they are on disk and
+/// sent on in the background, retried until they land. Each is recorded in
+/// `pending/` until then, so a restart resumes it, and it is served from disk
+/// and listed meanwhile.
+pub(crate) struct CachedBackend {
+    inner: Arc<dyn StorageBackend>,
+    cache: Arc<Cache>,
+    max_object_bytes: u64,
+    write_behind: bool,
+    signer: StreamSigner,
+}
+
+impl CachedBackend {
+    pub(crate) fn new(inner: Arc<dyn StorageBackend>, config: &AppConfig) -> std::io::Result<Self> {
+        let (cache, pending) = Cache::open(&config.cache.dir, config.cache.max_bytes)?;
+        let backend = Self {
+            inner,
+            cache: Arc::new(cache),
+            max_object_bytes: config.cache.max_object_bytes,
+            write_behind: config.cache.write_behind,
+            signer: stream_signer(config),
+        };
+        if !pending.is_empty() {
+            info!("Resuming {} writes left pending by the last run", pending.len());
+        }
+        for write in pending {
+            let generation = backend.cache.index.lock().unwrap().entries.get(&write.key).map(|e| e.generation);
+            let path = backend.cache.object_path(&write.key);
+            if let (Some(generation), Ok(data)) = (generation, std::fs::read(path)) {
+                backend.write_behind(write.key, data, write.content_type, generation);
+            }
+        }
+        Ok(backend)
+    }
+
+    /// Sends `data` on to the wrapped backend in the background until it lands,
+    /// or until the key is written again or deleted.
+    fn write_behind(&self, key: String, data: Vec<u8>, content_type: String, generation: u64) {
+        let (inner, cache) = (self.inner.clone(), self.cache.clone());
+        tokio::spawn(async move {
+            while cache.still_pending(&key, generation) {
+                match inner.put(&key, data.clone(), &content_type).await {
+                    Ok(_) => {
+                        if let Written::Deleted = cache.written(&key, generation).await
+                            && let Err(e) = inner.delete(&key).await
+                        {
+                            warn!("Failed to delete {}, deleted while it was written behind: {}", key, e);
+                        }
+                        return;
+                    }
+                    Err(e) => {
+                        warn!("Failed to write {} behind, will retry: {}", key, e);
+                        tokio::time::sleep(Duration::from_secs(WRITE_BEHIND_RETRY_SECS)).await;
+                    }
+                }
+            }
+        });
+    }
+
+    fn cacheable(&self, size: u64) -> bool {
+        size <= self.max_object_bytes
+    }
+}
+
+fn slice(data: Vec<u8>, key: &str, start: u64, len: u64) -> Result<ObjectBody, StorageError> {
+    let end = start.checked_add(len).filter(|&end| end <= data.len() as u64);
+    let Some(end) = end else {
+        return Err(format!("range {}+{} is past the end of {}", start, len, key).into());
+    };
+    let body = Bytes::from(data).slice(start as usize..end as usize);
+    Ok(Box::pin(futures::stream::once(async move { Ok(body) })))
+}
+
+#[async_trait]
+impl StorageBackend for CachedBackend {
+    async fn put(&self, key: &str, data: Vec<u8>, content_type: &str) -> Result<Option<String>, StorageError> {
+        if !self.cacheable(data.len() as u64) {
+            self.cache.forget(key).await;
+            return self.inner.put(key, data, content_type).await;
+        }
+
+        if self.write_behind {
+            match self.cache.store(key, &data, content_type, true).await {
+                Ok(generation) => {
+                    self.write_behind(key.to_string(), data, content_type.to_string(), generation);
+                    return Ok(None);
+                }
+                Err(e) => {
+                    warn!("Failed to cache {}, writing it through: {}", key, e);
+                    self.cache.forget(key).await;
+                }
+            }
+        } else if let Err(e) = self.cache.store(key, &data, content_type, false).await {
+            warn!("Failed to cache {}: {}", key, e);
+            self.cache.forget(key).await;
+        }
+
+        let result = self.inner.put(key, data, content_type).await;
+        if result.is_err() {
+            self.cache.forget(key).await;
+        }
+        result
+    }
+
+    async fn put_stream(
+        &self,
+        key: &str,
+        content_type: &str,
+        chunks: &mut ByteChunks<'_>,
+    ) -> Result<Option<String>, StorageError> {
+        let (head, done) = read_part(chunks, self.max_object_bytes as usize + 1).await?;
+        if done && self.cacheable(head.len() as u64) {
+            return self.put(key, head, content_type).await;
+        }
+
+        self.cache.forget(key).await;
+        let mut rest = futures::stream::iter([Ok(Bytes::from(head))]).chain(chunks);
+        self.inner.put_stream(key, content_type, &mut rest).await
+    }
+
+    async fn get(&self, key: &str) -> Result<Vec<u8>, StorageError> {
+        if let Some(data) = self.cache.read(key).await {
+            return Ok(data);
+        }
+
+        let data = self.inner.get(key).await?;
+        if self.cacheable(data.len() as u64)
+            && let Err(e) = self.cache.store(key, &data, "application/octet-stream", false).await
+        {
+            warn!("Failed to cache {}: {}", key, e);
+        }
+        Ok(data)
+    }
+
+    async fn get_range(&self, key: &str, start: u64, len: u64) -> Result<ObjectBody, StorageError> {
+        if let Some(data) = self.cache.read(key).await {
+            return slice(data, key, start, len);
+        }
+
+        // A small object is fetched whole, so the next read of it is a hit.
+        if self.cacheable(self.inner.size(key).await? as u64) {
+            return slice(self.get(key).await?, key, start, len);
+        }
+        self.inner.get_range(key, start, len).await
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), StorageError> {
+        self.cache.forget(key).await;
+        self.inner.delete(key).await
+    }
+
+    async fn copy(&self, from: &str, to: &str) -> Result<(), StorageError> {
+        // The wrapped backend doesn't have `from` yet.
+        if self.cache.is_pending(from)
+            && let (Some(data), Some(content_type)) = (self.cache.read(from).await, self.cache.content_type(from))
+        {
+            return self.put(to, data, &content_type).await.map(|_| ());
+        }
+
+        self.cache.forget(to).await;
+        self.inner.copy(from, to).await
+    }
+
+    async fn size(&self, key: &str) -> Result<i64, StorageError> {
+        match self.cache.size(key) {
+            Some(size) => Ok(size as i64),
+            None => self.inner.size(key).await,
+        }
+    }
+
+    async fn presign_download(
+        &self,
+        key: &str,
+        content_type: Option<String>,
+        expires_in: Duration,
+    ) -> Result<String, StorageError> {
+        match self.cache.size(key) {
+            Some(_) => Ok(self.signer.stream_url("GET", key, None, expires_in)),
+            None => self.inner.presign_download(key, content_type, expires_in).await,
+        }
+    }
+
+    async fn presign_upload(
+        &self,
+        key: &str,
+        size: i64,
+        content_type: &str,
+        sha256: Option<&str>,
+        expires_in: Duration,
+    ) -> Result<String, StorageError> {
+        self.cache.forget(key).await;
+        self.inner.presign_upload(key, size, content_type, sha256, expires_in).await
+    }
+
+    fn upload_headers(&self, sha256: Option<&str>) -> Vec<(&'static str, String)> {
+        self.inner.upload_headers(sha256)
+    }
+
+    async fn sha256(&self, key: &str) -> Result<Option<String>, StorageError> {
+        match self.cache.read(key).await {
+            Some(data) => Ok(Some(hex::encode(Sha256::digest(&data)))),
+            None => self.inner.sha256(key).await,
+        }
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<ObjectInfo>, StorageError> {
+        let mut objects = self.inner.list(prefix).await?;
+        let listed: HashSet<String> = objects.iter().map(|object| object.key.clone()).collect();
+        for key in self.cache.pending_keys(prefix) {
+            if !listed.contains(&key) {
+                objects.push(ObjectInfo { key, modified: None });
+            }
+        }
+        Ok(objects)
+    }
+
+    async fn create_multipart(&self, key: &str, content_type: &str) -> Result<String, StorageError> {
+        self.inner.create_multipart(key, content_type).await
+    }
+
+    async fn upload_part(
+        &self,
+        key: &str,
+        upload_id: &str,
+        part_number: i32,
+        data: Vec<u8>,
+    ) -> Result<String, StorageError> {
+        self.inner.upload_part(key, upload_id, part_number, data).await
+    }
+
+    async fn complete_multipart(
+        &self,
+        key: &str,
+        upload_id: &str,
+        parts: &[(i32, String)],
+    ) -> Result<Option<String>, StorageError> {
+        self.cache.forget(key).await;
+        self.inner.complete_multipart(key, upload_id, parts).await
+    }
+
+    async fn abort_multipart(&self, key: &str, upload_id: &str) -> Result<(), StorageError> {
+        self.inner.abort_multipart(key, upload_id).await
+    }
+
+    async fn abort_stale_multipart(&self, started_before: i64, keep: &HashSet<String>) -> Result<usize, StorageError> {
+        self.inner.abort_stale_multipart(started_before, keep).await
+    }
+
+    /// Accepts its own `/stream` URLs as well as the wrapped backend's.
+    /// An upload still waiting to be written behind fails here, and is moved on a
+    /// later attempt.
+    async fn set_storage_class(&self, key: &str, class: StorageClass) -> Result<(), StorageError> {
+        self.inner.set_storage_class(key, class).await
+    }
+
+    async fn restore(&self, key: &str, days: i32) -> Result<RestoreState, StorageError> {
+        self.inner.restore(key, days).await
+    }
+
+    fn verify_stream(&self, method: &str, params: &StreamParams) -> bool {
+        self.signer.verify(method, params) || self.inner.verify_stream(method, params)
+    }
+
+    async fn check(&self) -> Result<(), StorageError> {
+        tokio::fs::metadata(&self.cache.dir)
+            .await
+            .map_err(|e| format!("{}: {}", self.cache.dir.display(), e))?;
+        self.inner.check().await
+    }
+
+    async fn reload(&self, config: &AppConfig) -> Result<(), StorageError> {
+        self.inner.reload(config).await
+    }
+}
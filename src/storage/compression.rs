@@ -0,0 +1,412 @@
+use std::{collections::HashSet, io::Write, sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use axum::body::Bytes;
+use sha2::{Digest, Sha256};
+use tokio_stream::StreamExt;
+use tracing::debug;
+use zstd::stream::write::{Decoder, Encoder};
+
+use crate::{
+    config::AppConfig,
+    db::{on_db, DbPool},
+    storage::{
+        read_part, stream_signer, ByteChunks, ObjectBody, ObjectInfo, RestoreState, StorageBackend, StorageClass, StorageError,
+        StreamParams, StreamSigner,
+    },
+    MIN_COMPRESSED_OBJECT_BYTES,
+};
+
+/// The codec recorded for objects this backend compresses.
+const CODEC: &str = "zstd";
+
+/// Compresses content with zstd before it reaches the wrapped backend, and
+/// decompresses it on the way out. Each compressed key has a row in
+/// `compressed_objects` with its codec and its size before compression; keys
+/// without one (skipped extensions, presigned and multipart uploads, and anything
+/// written before compression was turned on) pass straight through.
+///
+/// The backend's own presigned URLs would hand out compressed bytes, so downloads
+/// of compressed objects get a `/stream` URL served through the server instead.
+/// Ranged reads decompress from the start of the object and skip to the range.
+pub(crate) struct CompressedBackend {
+    inner: Arc<dyn StorageBackend>,
+    pool: DbPool,
+    level: i32,
+    skip_extensions: Vec<String>,
+    signer: StreamSigner,
+}
+
+impl CompressedBackend {
+    pub(crate) fn new(inner: Arc<dyn StorageBackend>, pool: DbPool, config: &AppConfig) -> Self {
+        Self {
+            inner,
+            pool,
+            level: config.compression.level,
+            skip_extensions: config.compression.skip_extensions.iter().map(|ext| ext.to_ascii_lowercase()).collect(),
+            signer: stream_signer(config),
+        }
+    }
+
+    /// Whether content stored under `key` is worth compressing: not when its
+    /// extension is skipped, nor, for keys without one such as dedup blobs, when
+    /// an extension of its content type is.
+    fn compressible(&self, key: &str, content_type: &str) -> bool {
+        let skipped = |ext: &str| self.skip_extensions.iter().any(|skip| skip.eq_ignore_ascii_case(ext));
+        let name = key.rsplit('/').next().unwrap_or(key);
+        if let Some((_, ext)) = name.rsplit_once('.') {
+            return !skipped(ext);
+        }
+        match mime_guess::get_mime_extensions_str(content_type) {
+            Some(extensions) => !extensions.iter().any(|ext| skipped(ext)),
+            None => true,
+        }
+    }
+
+    /// The size before compression of `key`'s content, if it is stored compressed.
+    async fn original_size(&self, key: &str) -> Result<Option<i64>, String> {
+        let row = on_db!(&self.pool, pool => sqlx::query_as::<_, (String, i64)>(
+            "SELECT codec, original_size FROM compressed_objects WHERE key = $1"
+        )
+        .bind(key)
+        .fetch_optional(pool)
+        .await)
+        .map_err(|e| e.to_string())?;
+
+        match row {
+            Some((codec, size)) if codec == CODEC => Ok(Some(size)),
+            Some((codec, _)) => Err(format!("{} is compressed with unknown codec {}", key, codec)),
+            None => Ok(None),
+        }
+    }
+
+    async fn record(&self, key: &str, original_size: i64, stored_size: i64) -> Result<(), String> {
+        on_db!(&self.pool, pool => sqlx::query(
+            r#"
+            INSERT INTO compressed_objects (key, codec, original_size, stored_size)
+            VALUES ($1, $2, $3, $4)
+            ON CONFLICT (key) DO UPDATE
+            SET codec = EXCLUDED.codec,
+                original_size = EXCLUDED.original_size,
+                stored_size = EXCLUDED.stored_size,
+                updated_at = CURRENT_TIMESTAMP
+            "#
+        )
+        .bind(key)
+        .bind(CODEC)
+        .bind(original_size)
+        .bind(stored_size)
+        .execute(pool)
+        .await
+        .map(|_| ()))
+        .map_err(|e| e.to_string())
+    }
+
+    /// Forgets that `key` was compressed, once it holds content as uploaded.
+    async fn forget(&self, key: &str) -> Result<(), String> {
+        on_db!(&self.pool, pool => sqlx::query("DELETE FROM compressed_objects WHERE key = $1")
+            .bind(key)
+            .execute(pool)
+            .await
+            .map(|_| ()))
+            .map_err(|e| e.to_string())
+    }
+}
+
+/// Streams `len` bytes of a compressed object's content from `start`, decompressing
+/// `body`, the whole stored object.
+fn decompress_range(body: ObjectBody, start: u64, len: u64) -> Result<ObjectBody, String> {
+    struct Inflating {
+        body: ObjectBody,
+        decoder: Decoder<'static, Vec<u8>>,
+        skip: u64,
+        remaining: u64,
+    }
+
+    let state = Inflating {
+        body,
+        decoder: Decoder::new(Vec::new()).map_err(|e| e.to_string())?,
+        skip: start,
+        remaining: len,
+    };
+
+    Ok(Box::pin(futures::stream::unfold(state, |mut s| async move {
+        while s.remaining > 0 {
+            let inflated = match s.body.next().await {
+                Some(Ok(chunk)) => s.decoder.write_all(&chunk).and_then(|_| s.decoder.flush()),
+                Some(Err(e)) => {
+                    s.remaining = 0;
+                    return Some((Err(e), s));
+                }
+                None => {
+                    s.remaining = 0;
+                    return Some((Err("compressed object ended early".to_string()), s));
+                }
+            };
+            if let Err(e) = inflated {
+                s.remaining = 0;
+                return Some((Err(format!("failed to decompress: {}", e)), s));
+            }
+
+            let mut output = std::mem::take(s.decoder.get_mut());
+            let skipped = (s.skip as usize).min(output.len());
+            output.drain(..skipped);
+            s.skip -= skipped as u64;
+            output.truncate(output.len().min(s.remaining as usize));
+            if !output.is_empty() {
+                s.remaining -= output.len() as u64;
+                return Some((Ok(Bytes::from(output)), s));
+            }
+        }
+        None
+    })))
+}
+
+#[async_trait]
+impl StorageBackend for CompressedBackend {
+    async fn put(&self, key: &str, data: Vec<u8>, content_type: &str) -> Result<Option<String>, StorageError> {
+        if data.len() >= MIN_COMPRESSED_OBJECT_BYTES && self.compressible(key, content_type) {
+            let compressed = zstd::bulk::compress(&data, self.level).map_err(|e| e.to_string())?;
+            // Content that doesn't shrink is kept as it is.
+            if compressed.len() < data.len() {
+                let (original_size, stored_size) = (data.len() as i64, compressed.len() as i64);
+                let etag = self.inner.put(key, compressed, content_type).await?;
+                self.record(key, original_size, stored_size).await?;
+                return Ok(etag);
+            }
+        }
+        let etag = self.inner.put(key, data, content_type).await?;
+        self.forget(key).await?;
+        Ok(etag)
+    }
+
+    async fn put_stream(
+        &self,
+        key: &str,
+        content_type: &str,
+        chunks: &mut ByteChunks<'_>,
+    ) -> Result<Option<String>, StorageError> {
+        if !self.compressible(key, content_type) {
+            let etag = self.inner.put_stream(key, content_type, chunks).await?;
+            self.forget(key).await?;
+            return Ok(etag);
+        }
+
+        // Content that turns out to be short is stored as it is.
+        let (head, done) = read_part(chunks, MIN_COMPRESSED_OBJECT_BYTES).await?;
+        if done && head.len() < MIN_COMPRESSED_OBJECT_BYTES {
+            let etag = self.inner.put(key, head, content_type).await?;
+            self.forget(key).await?;
+            return Ok(etag);
+        }
+
+        struct Deflating<'c, 'a> {
+            head: Option<Vec<u8>>,
+            /// Whether `read_part` already saw the end of `chunks`.
+            done: bool,
+            chunks: &'c mut ByteChunks<'a>,
+            encoder: Option<Encoder<'static, Vec<u8>>>,
+            original_size: &'c mut i64,
+            stored_size: &'c mut i64,
+        }
+
+        let mut original_size = 0;
+        let mut stored_size = 0;
+        let etag = {
+            let state = Deflating {
+                head: Some(head),
+                done,
+                chunks,
+                encoder: Some(Encoder::new(Vec::new(), self.level).map_err(|e| e.to_string())?),
+                original_size: &mut original_size,
+                stored_size: &mut stored_size,
+            };
+            let mut compressed = Box::pin(futures::stream::unfold(state, |mut s| async move {
+                loop {
+                    s.encoder.as_ref()?;
+                    let next = match s.head.take() {
+                        Some(head) => Some(Ok(Bytes::from(head))),
+                        None if s.done => None,
+                        None => s.chunks.next().await,
+                    };
+                    let chunk = match next {
+                        Some(Ok(chunk)) => chunk,
+                        Some(Err(e)) => {
+                            s.encoder = None;
+                            return Some((Err(e), s));
+                        }
+                        None => {
+                            let finished = s.encoder.take()?.finish().map_err(|e| e.to_string());
+                            if let Ok(output) = &finished {
+                                *s.stored_size += output.len() as i64;
+                            }
+                            return Some((finished.map(Bytes::from), s));
+                        }
+                    };
+                    *s.original_size += chunk.len() as i64;
+                    let encoder = s.encoder.as_mut()?;
+                    if let Err(e) = encoder.write_all(&chunk) {
+                        s.encoder = None;
+                        return Some((Err(format!("failed to compress: {}", e)), s));
+                    }
+                    let output = std::mem::take(encoder.get_mut());
+                    if !output.is_empty() {
+                        *s.stored_size += output.len() as i64;
+                        return Some((Ok(Bytes::from(output)), s));
+                    }
+                }
+            }));
+            self.inner.put_stream(key, content_type, &mut compressed).await?
+        };
+        debug!("Stored {} compressed from {} to {} bytes", key, original_size, stored_size);
+        self.record(key, original_size, stored_size).await?;
+        Ok(etag)
+    }
+
+    async fn get(&self, key: &str) -> Result<Vec<u8>, StorageError> {
+        let data = self.inner.get(key).await?;
+        match self.original_size(key).await? {
+            Some(_) => zstd::stream::decode_all(&data[..]).map_err(|e| format!("failed to decompress {}: {}", key, e).into()),
+            None => Ok(data),
+        }
+    }
+
+    async fn get_range(&self, key: &str, start: u64, len: u64) -> Result<ObjectBody, StorageError> {
+        let Some(original_size) = self.original_size(key).await? else {
+            return self.inner.get_range(key, start, len).await;
+        };
+        if start + len > original_size as u64 {
+            return Err(format!("range {}+{} is past the end of {}", start, len, key).into());
+        }
+
+        let stored = self.inner.size(key).await? as u64;
+        Ok(decompress_range(self.inner.get_range(key, 0, stored).await?, start, len)?)
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), StorageError> {
+        self.inner.delete(key).await?;
+        Ok(self.forget(key).await?)
+    }
+
+    async fn copy(&self, from: &str, to: &str) -> Result<(), StorageError> {
+        self.inner.copy(from, to).await?;
+        self.forget(to).await?;
+        on_db!(&self.pool, pool => sqlx::query(
+            r#"
+            INSERT INTO compressed_objects (key, codec, original_size, stored_size)
+            SELECT $2, codec, original_size, stored_size FROM compressed_objects WHERE key = $1
+            "#
+        )
+        .bind(from)
+        .bind(to)
+        .execute(pool)
+        .await
+        .map(|_| ()))
+        .map_err(|e| e.to_string().into())
+    }
+
+    async fn size(&self, key: &str) -> Result<i64, StorageError> {
+        match self.original_size(key).await? {
+            Some(size) => Ok(size),
+            None => self.inner.size(key).await,
+        }
+    }
+
+    async fn presign_download(
+        &self,
+        key: &str,
+        content_type: Option<String>,
+        expires_in: Duration,
+    ) -> Result<String, StorageError> {
+        match self.original_size(key).await? {
+            Some(_) => Ok(self.signer.stream_url("GET", key, None, expires_in)),
+            None => self.inner.presign_download(key, content_type, expires_in).await,
+        }
+    }
+
+    async fn presign_upload(
+        &self,
+        key: &str,
+        size: i64,
+        content_type: &str,
+        sha256: Option<&str>,
+        expires_in: Duration,
+    ) -> Result<String, StorageError> {
+        self.inner.presign_upload(key, size, content_type, sha256, expires_in).await
+    }
+
+    fn upload_headers(&self, sha256: Option<&str>) -> Vec<(&'static str, String)> {
+        self.inner.upload_headers(sha256)
+    }
+
+    async fn sha256(&self, key: &str) -> Result<Option<String>, StorageError> {
+        let Some(original_size) = self.original_size(key).await? else {
+            return self.inner.sha256(key).await;
+        };
+
+        let mut body = self.get_range(key, 0, original_size as u64).await?;
+        let mut hasher = Sha256::new();
+        while let Some(chunk) = body.next().await {
+            hasher.update(&chunk?);
+        }
+        Ok(Some(hex::encode(hasher.finalize())))
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<ObjectInfo>, StorageError> {
+        self.inner.list(prefix).await
+    }
+
+    async fn create_multipart(&self, key: &str, content_type: &str) -> Result<String, StorageError> {
+        self.inner.create_multipart(key, content_type).await
+    }
+
+    async fn upload_part(
+        &self,
+        key: &str,
+        upload_id: &str,
+        part_number: i32,
+        data: Vec<u8>,
+    ) -> Result<String, StorageError> {
+        self.inner.upload_part(key, upload_id, part_number, data).await
+    }
+
+    async fn complete_multipart(
+        &self,
+        key: &str,
+        upload_id: &str,
+        parts: &[(i32, String)],
+    ) -> Result<Option<String>, StorageError> {
+        let etag = self.inner.complete_multipart(key, upload_id, parts).await?;
+        self.forget(key).await?;
+        Ok(etag)
+    }
+
+    async fn abort_multipart(&self, key: &str, upload_id: &str) -> Result<(), StorageError> {
+        self.inner.abort_multipart(key, upload_id).await
+    }
+
+    async fn abort_stale_multipart(&self, started_before: i64, keep: &HashSet<String>) -> Result<usize, StorageError> {
+        self.inner.abort_stale_multipart(started_before, keep).await
+    }
+
+    /// Accepts its own `/stream` URLs as well as the wrapped backend's.
+    async fn set_storage_class(&self, key: &str, class: StorageClass) -> Result<(), StorageError> {
+        self.inner.set_storage_class(key, class).await
+    }
+
+    async fn restore(&self, key: &str, days: i32) -> Result<RestoreState, StorageError> {
+        self.inner.restore(key, days).await
+    }
+
+    fn verify_stream(&self, method: &str, params: &StreamParams) -> bool {
+        self.signer.verify(method, params) || self.inner.verify_stream(method, params)
+    }
+
+    async fn check(&self) -> Result<(), StorageError> {
+        self.inner.check().await
+    }
+
+    async fn reload(&self, config: &AppConfig) -> Result<(), StorageError> {
+        self.inner.reload(config).await
+    }
+}
@@ -0,0 +1,374 @@
+use std::{collections::HashSet, sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use sha2::{Digest, Sha256};
+use tokio_stream::StreamExt;
+use tracing::{debug, warn};
+
+use crate::{
+    config::AppConfig,
+    db::{on_db, Db, DbPool},
+    storage::{ByteChunks, ObjectBody, ObjectInfo, RestoreState, StorageBackend, StorageClass, StorageError, StreamParams},
+};
+
+/// Where the wrapped backend keeps content-addressed blobs.
+const BLOB_PREFIX: &str = "blobs";
+
+/// Where streamed uploads land while their hash is being computed.
+const STAGING_PREFIX: &str = "staging";
+
+/// Stores each distinct content once. Keys written through `put`, `put_stream` and
+/// `copy` become references, in `blob_refs`, to a blob named by the SHA-256 the
+/// server computed, and `blobs.ref_count` tracks how many keys share it. A write
+/// whose content is already stored skips the storage write; a blob is only deleted
+/// with its last reference.
+///
+/// Keys without a reference (presigned and multipart uploads, and anything written
+/// before dedup was turned on) pass straight through to the wrapped backend.
+pub(crate) struct DedupBackend {
+    inner: Arc<dyn StorageBackend>,
+    pool: DbPool,
+}
+
+impl DedupBackend {
+    pub(crate) fn new(inner: Arc<dyn StorageBackend>, pool: DbPool) -> Self {
+        Self { inner, pool }
+    }
+
+    fn blob_key(sha256: &str) -> String {
+        format!("{}/{}/{}", BLOB_PREFIX, &sha256[..2], sha256)
+    }
+
+    /// The blob behind `key`, if it is a reference.
+    async fn resolve(&self, key: &str) -> Result<Option<(String, i64)>, String> {
+        on_db!(&self.pool, pool => sqlx::query_as::<_, (String, i64)>(
+            r#"
+            SELECT r.sha256, b.size
+            FROM blob_refs r
+            JOIN blobs b ON b.sha256 = r.sha256
+            WHERE r.key = $1
+            "#
+        )
+        .bind(key)
+        .fetch_optional(pool)
+        .await)
+        .map_err(|e| e.to_string())
+    }
+
+    /// The key to read `key`'s content from.
+    async fn storage_key(&self, key: &str) -> Result<String, String> {
+        Ok(match self.resolve(key).await? {
+            Some((sha256, _)) => Self::blob_key(&sha256),
+            None => key.to_string(),
+        })
+    }
+
+    /// Adds a reference to the blob, returning whether the blob is new and so still
+    /// has to be written. Blocks while `release` is deleting the same blob.
+    async fn take_ref(&self, sha256: &str, size: i64) -> Result<bool, String> {
+        // A row left at zero references is deleted by `release` before its lock is
+        // released, so on SQLite, where writers queue, a count of one means a new blob.
+        let sql = self.pool.sql(
+            r#"
+            INSERT INTO blobs (sha256, size, ref_count)
+            VALUES ($1, $2, 1)
+            ON CONFLICT (sha256) DO UPDATE SET ref_count = blobs.ref_count + 1
+            RETURNING xmax = 0
+            "#,
+            r#"
+            INSERT INTO blobs (sha256, size, ref_count)
+            VALUES ($1, $2, 1)
+            ON CONFLICT (sha256) DO UPDATE SET ref_count = blobs.ref_count + 1
+            RETURNING ref_count = 1
+            "#,
+        );
+        on_db!(&self.pool, pool => sqlx::query_scalar::<_, bool>(sql)
+            .bind(sha256)
+            .bind(size)
+            .fetch_one(pool)
+            .await)
+            .map_err(|e| e.to_string())
+    }
+
+    /// Drops a reference, deleting the blob once nothing refers to it. The row stays
+    /// locked until the object is gone, so a concurrent `take_ref` sees either the
+    /// live blob or no row at all, never a row whose object is being deleted.
+    async fn release(&self, sha256: &str) -> Result<(), String> {
+        let mut tx = self.pool.begin().await.map_err(|e| e.to_string())?;
+        let remaining = on_db!(tx.as_conn(), conn => sqlx::query_scalar::<_, i32>(
+            "UPDATE blobs SET ref_count = ref_count - 1 WHERE sha256 = $1 RETURNING ref_count"
+        )
+        .bind(sha256)
+        .fetch_optional(conn)
+        .await)
+        .map_err(|e| e.to_string())?;
+
+        if remaining.is_some_and(|count| count <= 0) {
+            self.inner.delete(&Self::blob_key(sha256)).await?;
+            on_db!(tx.as_conn(), conn => sqlx::query("DELETE FROM blobs WHERE sha256 = $1")
+                .bind(sha256)
+                .execute(conn)
+                .await
+                .map(|_| ()))
+                .map_err(|e| e.to_string())?;
+        }
+        tx.commit().await.map_err(|e| e.to_string())
+    }
+
+    /// Removes `key`'s reference, if it has one, returning whether it did.
+    async fn unreference(&self, key: &str) -> Result<bool, String> {
+        let released = on_db!(&self.pool, pool => sqlx::query_scalar::<_, String>("DELETE FROM blob_refs WHERE key = $1 RETURNING sha256")
+            .bind(key)
+            .fetch_optional(pool)
+            .await)
+            .map_err(|e| e.to_string())?;
+
+        match released {
+            Some(sha256) => self.release(&sha256).await.map(|_| true),
+            None => Ok(false),
+        }
+    }
+
+    /// Points `key` at the blob, releasing whatever it referred to before.
+    async fn point(&self, key: &str, sha256: &str) -> Result<(), StorageError> {
+        let previous = match &self.pool {
+            Db::Postgres(pool) => sqlx::query_scalar::<_, Option<String>>(
+                r#"
+                WITH old AS (SELECT sha256 FROM blob_refs WHERE key = $1)
+                INSERT INTO blob_refs (key, sha256)
+                VALUES ($1, $2)
+                ON CONFLICT (key) DO UPDATE SET sha256 = EXCLUDED.sha256, updated_at = CURRENT_TIMESTAMP
+                RETURNING (SELECT sha256 FROM old)
+                "#
+            )
+            .bind(key)
+            .bind(sha256)
+            .fetch_one(pool)
+            .await,
+            // A SQLite `RETURNING` subquery already sees the new row, so the old one
+            // is read first, under the same write lock.
+            Db::Sqlite(pool) => async {
+                let mut tx = pool.begin_with("BEGIN IMMEDIATE").await?;
+                let previous = sqlx::query_scalar::<_, String>("SELECT sha256 FROM blob_refs WHERE key = $1")
+                    .bind(key)
+                    .fetch_optional(&mut *tx)
+                    .await?;
+                sqlx::query(
+                    r#"
+                    INSERT INTO blob_refs (key, sha256)
+                    VALUES ($1, $2)
+                    ON CONFLICT (key) DO UPDATE SET sha256 = EXCLUDED.sha256, updated_at = CURRENT_TIMESTAMP
+                    "#
+                )
+                .bind(key)
+                .bind(sha256)
+                .execute(&mut *tx)
+                .await?;
+                tx.commit().await.map(|_| previous)
+            }
+            .await,
+        }
+        .map_err(|e| e.to_string())?;
+
+        match previous {
+            Some(old) => Ok(self.release(&old).await?),
+            // A passthrough object the key used to name is superseded by the reference.
+            None => self.inner.delete(key).await,
+        }
+    }
+}
+
+#[async_trait]
+impl StorageBackend for DedupBackend {
+    async fn put(&self, key: &str, data: Vec<u8>, content_type: &str) -> Result<Option<String>, StorageError> {
+        let sha256 = hex::encode(Sha256::digest(&data));
+        if self.take_ref(&sha256, data.len() as i64).await? {
+            if let Err(e) = self.inner.put(&Self::blob_key(&sha256), data, content_type).await {
+                let _ = self.release(&sha256).await;
+                return Err(e);
+            }
+        } else {
+            debug!("Deduplicated write of {} ({})", key, sha256);
+        }
+        self.point(key, &sha256).await?;
+        Ok(Some(sha256))
+    }
+
+    async fn put_stream(
+        &self,
+        key: &str,
+        content_type: &str,
+        chunks: &mut ByteChunks<'_>,
+    ) -> Result<Option<String>, StorageError> {
+        // The hash is only known once the body has been read, so it is staged first.
+        let staging = format!("{}/{}", STAGING_PREFIX, hex::encode(rand::random::<[u8; 16]>()));
+        let mut hasher = Sha256::new();
+        let mut size = 0i64;
+        {
+            let mut hashing = chunks.map(|chunk| {
+                if let Ok(bytes) = &chunk {
+                    hasher.update(bytes);
+                    size += bytes.len() as i64;
+                }
+                chunk
+            });
+            self.inner.put_stream(&staging, content_type, &mut hashing).await?;
+        }
+        let sha256 = hex::encode(hasher.finalize());
+
+        let stored: Result<(), StorageError> = async {
+            if self.take_ref(&sha256, size).await? {
+                if let Err(e) = self.inner.copy(&staging, &Self::blob_key(&sha256)).await {
+                    let _ = self.release(&sha256).await;
+                    return Err(e);
+                }
+            } else {
+                debug!("Deduplicated upload of {} ({})", key, sha256);
+            }
+            self.point(key, &sha256).await
+        }
+        .await;
+
+        if let Err(e) = self.inner.delete(&staging).await {
+            warn!("Failed to delete staged upload {}: {}", staging, e);
+        }
+        stored.map(|_| Some(sha256))
+    }
+
+    async fn get(&self, key: &str) -> Result<Vec<u8>, StorageError> {
+        self.inner.get(&self.storage_key(key).await?).await
+    }
+
+    async fn get_range(&self, key: &str, start: u64, len: u64) -> Result<ObjectBody, StorageError> {
+        self.inner.get_range(&self.storage_key(key).await?, start, len).await
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), StorageError> {
+        if !self.unreference(key).await? {
+            self.inner.delete(key).await?;
+        }
+        Ok(())
+    }
+
+    async fn copy(&self, from: &str, to: &str) -> Result<(), StorageError> {
+        match self.resolve(from).await? {
+            Some((sha256, size)) => {
+                self.take_ref(&sha256, size).await?;
+                self.point(to, &sha256).await
+            }
+            None => self.inner.copy(from, to).await,
+        }
+    }
+
+    async fn size(&self, key: &str) -> Result<i64, StorageError> {
+        match self.resolve(key).await? {
+            Some((_, size)) => Ok(size),
+            None => self.inner.size(key).await,
+        }
+    }
+
+    async fn presign_download(
+        &self,
+        key: &str,
+        content_type: Option<String>,
+        expires_in: Duration,
+    ) -> Result<String, StorageError> {
+        self.inner.presign_download(&self.storage_key(key).await?, content_type, expires_in).await
+    }
+
+    async fn presign_upload(
+        &self,
+        key: &str,
+        size: i64,
+        content_type: &str,
+        sha256: Option<&str>,
+        expires_in: Duration,
+    ) -> Result<String, StorageError> {
+        // The client writes the raw key, which a leftover reference would shadow.
+        self.unreference(key).await?;
+        self.inner.presign_upload(key, size, content_type, sha256, expires_in).await
+    }
+
+    fn upload_headers(&self, sha256: Option<&str>) -> Vec<(&'static str, String)> {
+        self.inner.upload_headers(sha256)
+    }
+
+    async fn sha256(&self, key: &str) -> Result<Option<String>, StorageError> {
+        match self.resolve(key).await? {
+            Some((sha256, _)) => Ok(Some(sha256)),
+            None => self.inner.sha256(key).await,
+        }
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<ObjectInfo>, StorageError> {
+        let pattern = format!("{}%", prefix.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_"));
+        let sql = self.pool.sql(
+            r"SELECT key, EXTRACT(EPOCH FROM updated_at)::BIGINT FROM blob_refs WHERE key LIKE $1 ESCAPE '\'",
+            r"SELECT key, CAST(strftime('%s', updated_at) AS INTEGER) FROM blob_refs WHERE key LIKE $1 ESCAPE '\'",
+        );
+        let refs = on_db!(&self.pool, pool => sqlx::query_as::<_, (String, Option<i64>)>(sql)
+        .bind(pattern)
+        .fetch_all(pool)
+        .await)
+        .map_err(|e| e.to_string())?;
+
+        let mut objects = self.inner.list(prefix).await?;
+        objects.extend(refs.into_iter().map(|(key, modified)| ObjectInfo { key, modified }));
+        Ok(objects)
+    }
+
+    async fn create_multipart(&self, key: &str, content_type: &str) -> Result<String, StorageError> {
+        self.inner.create_multipart(key, content_type).await
+    }
+
+    async fn upload_part(
+        &self,
+        key: &str,
+        upload_id: &str,
+        part_number: i32,
+        data: Vec<u8>,
+    ) -> Result<String, StorageError> {
+        self.inner.upload_part(key, upload_id, part_number, data).await
+    }
+
+    async fn complete_multipart(
+        &self,
+        key: &str,
+        upload_id: &str,
+        parts: &[(i32, String)],
+    ) -> Result<Option<String>, StorageError> {
+        let etag = self.inner.complete_multipart(key, upload_id, parts).await?;
+        self.unreference(key).await?;
+        Ok(etag)
+    }
+
+    async fn abort_multipart(&self, key: &str, upload_id: &str) -> Result<(), StorageError> {
+        self.inner.abort_multipart(key, upload_id).await
+    }
+
+    async fn abort_stale_multipart(&self, started_before: i64, keep: &HashSet<String>) -> Result<usize, StorageError> {
+        self.inner.abort_stale_multipart(started_before, keep).await
+    }
+
+    /// Deduplicated files share their blob, so moving one moves every file with the
+    /// same content.
+    async fn set_storage_class(&self, key: &str, class: StorageClass) -> Result<(), StorageError> {
+        self.inner.set_storage_class(&self.storage_key(key).await?, class).await
+    }
+
+    async fn restore(&self, key: &str, days: i32) -> Result<RestoreState, StorageError> {
+        self.inner.restore(&self.storage_key(key).await?, days).await
+    }
+
+    fn verify_stream(&self, method: &str, params: &StreamParams) -> bool {
+        self.inner.verify_stream(method, params)
+    }
+
+    async fn check(&self) -> Result<(), StorageError> {
+        self.inner.check().await
+    }
+
+    async fn reload(&self, config: &AppConfig) -> Result<(), StorageError> {
+        self.inner.reload(config).await
+    }
+}
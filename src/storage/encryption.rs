@@ -0,0 +1,559 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use aes_gcm::{
+    aead::{Aead, KeyInit, Payload},
+    Aes256Gcm, Nonce,
+};
+use async_trait::async_trait;
+use axum::body::Bytes;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use sha2::{Digest, Sha256};
+use tokio_stream::StreamExt;
+use tracing::warn;
+
+use crate::{
+    config::AppConfig,
+    db::{on_db, DbPool},
+    storage::{
+        stream_signer, ByteChunks, ObjectBody, ObjectInfo, RestoreState, StorageBackend, StorageClass, StorageError,
+        StreamParams, StreamSigner,
+    },
+};
+
+/// Starts every encrypted object; objects without it are read back as stored.
+const MAGIC: &[u8; 4] = b"PKE1";
+
+/// The magic, the id of the object's data key and its nonce prefix.
+const HEADER_LEN: usize = 16;
+
+/// Plaintext bytes sealed per segment, so ranges can be decrypted on their own.
+const SEGMENT_LEN: usize = 64 * 1024;
+
+const TAG_LEN: usize = 16;
+
+/// Where multipart uploads are assembled before being encrypted into place.
+const MULTIPART_PREFIX: &str = "multipart";
+
+#[derive(Clone, Copy)]
+struct Header {
+    key_id: i32,
+    nonce_prefix: [u8; 8],
+}
+
+impl Header {
+    fn encode(&self) -> [u8; HEADER_LEN] {
+        let mut bytes = [0; HEADER_LEN];
+        bytes[..4].copy_from_slice(MAGIC);
+        bytes[4..8].copy_from_slice(&self.key_id.to_be_bytes());
+        bytes[8..].copy_from_slice(&self.nonce_prefix);
+        bytes
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < HEADER_LEN || &bytes[..4] != MAGIC {
+            return None;
+        }
+        Some(Self {
+            key_id: i32::from_be_bytes(bytes[4..8].try_into().ok()?),
+            nonce_prefix: bytes[8..HEADER_LEN].try_into().ok()?,
+        })
+    }
+}
+
+/// Seals and opens the segments of one object. Segment `i` is sealed under the
+/// object's nonce prefix and `i`, and authenticates the header and whether it is
+/// the last segment, so segments can't be reordered, moved between objects or cut off.
+struct Sealer {
+    cipher: Aes256Gcm,
+    header: Header,
+}
+
+impl Sealer {
+    fn nonce(&self, index: u64) -> [u8; 12] {
+        let mut nonce = [0; 12];
+        nonce[..8].copy_from_slice(&self.header.nonce_prefix);
+        nonce[8..].copy_from_slice(&(index as u32).to_be_bytes());
+        nonce
+    }
+
+    fn aad(&self, last: bool) -> [u8; HEADER_LEN + 1] {
+        let mut aad = [0; HEADER_LEN + 1];
+        aad[..HEADER_LEN].copy_from_slice(&self.header.encode());
+        aad[HEADER_LEN] = last as u8;
+        aad
+    }
+
+    fn seal(&self, index: u64, last: bool, plaintext: &[u8]) -> Result<Bytes, String> {
+        let nonce = self.nonce(index);
+        self.cipher
+            .encrypt(Nonce::from_slice(&nonce), Payload { msg: plaintext, aad: &self.aad(last) })
+            .map(Bytes::from)
+            .map_err(|_| "failed to encrypt segment".to_string())
+    }
+
+    fn open(&self, index: u64, last: bool, ciphertext: &[u8]) -> Result<Bytes, String> {
+        let nonce = self.nonce(index);
+        self.cipher
+            .decrypt(Nonce::from_slice(&nonce), Payload { msg: ciphertext, aad: &self.aad(last) })
+            .map(Bytes::from)
+            .map_err(|_| format!("segment {} failed to decrypt", index))
+    }
+}
+
+/// Plaintext length of an encrypted object `stored` bytes long.
+fn plaintext_len(stored: u64) -> u64 {
+    let body = stored.saturating_sub(HEADER_LEN as u64);
+    let segments = body.div_ceil((SEGMENT_LEN + TAG_LEN) as u64).max(1);
+    body.saturating_sub(segments * TAG_LEN as u64)
+}
+
+/// Number of segments `plaintext` bytes are sealed in; empty content still has one.
+fn segment_count(plaintext: u64) -> u64 {
+    plaintext.div_ceil(SEGMENT_LEN as u64).max(1)
+}
+
+/// The user whose data key encrypts `key`: the owner of the `data/<user_id>/`
+/// namespace it falls under, in the trash and among versions too. Keys outside
+/// every namespace, such as dedup blobs, use the shared key.
+fn owner(key: &str) -> Option<i32> {
+    let mut segments = key.split('/');
+    while let Some(segment) = segments.next() {
+        if segment == "data" {
+            return segments.next().and_then(|id| id.parse().ok());
+        }
+    }
+    None
+}
+
+fn multipart_key(key: &str) -> String {
+    format!("{}/{}", MULTIPART_PREFIX, key)
+}
+
+/// Encrypts content with AES-256-GCM before it reaches the wrapped backend, with a
+/// data key per user that is itself stored wrapped by the master key, so the bucket
+/// alone never yields plaintext. Objects are sealed in segments, so ranged reads
+/// only decrypt what they return.
+///
+/// The backend's own presigned URLs would hand out or accept ciphertext, so every
+/// URL is a `/stream` URL served through the server instead. Multipart uploads are
+/// assembled under `multipart/` and encrypted into place once complete. Objects
+/// stored before encryption was turned on are read back as they are.
+pub(crate) struct EncryptedBackend {
+    inner: Arc<dyn StorageBackend>,
+    pool: DbPool,
+    master: Aes256Gcm,
+    signer: StreamSigner,
+    /// Unwrapped data keys by id.
+    keys: Mutex<HashMap<i32, Aes256Gcm>>,
+    /// The id of each owner's data key; `None` is the shared key.
+    owners: Mutex<HashMap<Option<i32>, i32>>,
+}
+
+impl EncryptedBackend {
+    /// Panics unless `config.encryption_master_key` is 32 bytes of base64.
+    pub(crate) fn new(inner: Arc<dyn StorageBackend>, pool: DbPool, config: &AppConfig) -> Self {
+        let master = config
+            .encryption_master_key
+            .as_deref()
+            .and_then(|key| BASE64.decode(key).ok())
+            .and_then(|key| Aes256Gcm::new_from_slice(&key).ok())
+            .expect("ENCRYPTION_MASTER_KEY must be 32 bytes, base64-encoded");
+
+        Self {
+            inner,
+            pool,
+            master,
+            signer: stream_signer(config),
+            keys: Mutex::new(HashMap::new()),
+            owners: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn unwrap_key(&self, wrapped: &[u8]) -> Result<Aes256Gcm, String> {
+        if wrapped.len() < 12 {
+            return Err("wrapped data key is truncated".into());
+        }
+        let (nonce, ciphertext) = wrapped.split_at(12);
+        let key = self
+            .master
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| "data key can't be unwrapped with this master key".to_string())?;
+        Aes256Gcm::new_from_slice(&key).map_err(|e| e.to_string())
+    }
+
+    /// The data key with `id`, as read back from an object's header.
+    async fn data_key(&self, id: i32) -> Result<Aes256Gcm, String> {
+        if let Some(cipher) = self.keys.lock().unwrap().get(&id) {
+            return Ok(cipher.clone());
+        }
+
+        let wrapped = on_db!(&self.pool, pool => sqlx::query_scalar::<_, Vec<u8>>("SELECT wrapped_key FROM encryption_keys WHERE id = $1")
+            .bind(id)
+            .fetch_optional(pool)
+            .await)
+            .map_err(|e| e.to_string())?
+            .ok_or_else(|| format!("unknown data key {}", id))?;
+        let cipher = self.unwrap_key(&wrapped)?;
+        self.keys.lock().unwrap().insert(id, cipher.clone());
+        Ok(cipher)
+    }
+
+    /// The id and key new objects of `owner` are sealed with, created on first use.
+    async fn owner_key(&self, owner: Option<i32>) -> Result<(i32, Aes256Gcm), String> {
+        let cached = self.owners.lock().unwrap().get(&owner).copied();
+        if let Some(id) = cached {
+            return Ok((id, self.data_key(id).await?));
+        }
+
+        let nonce: [u8; 12] = rand::random();
+        let mut wrapped = nonce.to_vec();
+        wrapped.extend(
+            self.master
+                .encrypt(Nonce::from_slice(&nonce), rand::random::<[u8; 32]>().as_slice())
+                .map_err(|_| "failed to wrap data key".to_string())?,
+        );
+        on_db!(&self.pool, pool => sqlx::query(
+            r#"
+            INSERT INTO encryption_keys (user_id, wrapped_key)
+            VALUES ($1, $2)
+            ON CONFLICT ((COALESCE(user_id, 0))) DO NOTHING
+            "#
+        )
+        .bind(owner)
+        .bind(&wrapped)
+        .execute(pool)
+        .await
+        .map(|_| ()))
+        .map_err(|e| e.to_string())?;
+
+        // Another request may have created the key first; whichever row won is the key.
+        let (id, wrapped) = on_db!(&self.pool, pool => sqlx::query_as::<_, (i32, Vec<u8>)>(
+            "SELECT id, wrapped_key FROM encryption_keys WHERE COALESCE(user_id, 0) = COALESCE($1, 0)"
+        )
+        .bind(owner)
+        .fetch_one(pool)
+        .await)
+        .map_err(|e| e.to_string())?;
+        let cipher = self.unwrap_key(&wrapped)?;
+        self.keys.lock().unwrap().insert(id, cipher.clone());
+        self.owners.lock().unwrap().insert(owner, id);
+        Ok((id, cipher))
+    }
+
+    /// A sealer for a new object at `key`, under a fresh nonce prefix.
+    async fn new_sealer(&self, key: &str) -> Result<Sealer, String> {
+        let (key_id, cipher) = self.owner_key(owner(key)).await?;
+        Ok(Sealer { cipher, header: Header { key_id, nonce_prefix: rand::random() } })
+    }
+
+    /// The stored size of `key`, and its header when it is encrypted.
+    async fn inspect(&self, key: &str) -> Result<(u64, Option<Header>), StorageError> {
+        let stored = self.inner.size(key).await? as u64;
+        if stored < (HEADER_LEN + TAG_LEN) as u64 {
+            return Ok((stored, None));
+        }
+        let mut body = self.inner.get_range(key, 0, HEADER_LEN as u64).await?;
+        let mut bytes = Vec::with_capacity(HEADER_LEN);
+        while let Some(chunk) = body.next().await {
+            bytes.extend_from_slice(&chunk?);
+        }
+        Ok((stored, Header::decode(&bytes)))
+    }
+
+    async fn sealer(&self, header: Header) -> Result<Sealer, String> {
+        Ok(Sealer { cipher: self.data_key(header.key_id).await?, header })
+    }
+}
+
+/// Reads `len` plaintext bytes from `start` out of the sealed segments streamed in
+/// `body`, which starts with the segment holding `start` in an object `stored`
+/// bytes long.
+fn open_range(sealer: Sealer, body: ObjectBody, stored: u64, start: u64, len: u64) -> ObjectBody {
+    struct Opening {
+        sealer: Sealer,
+        body: ObjectBody,
+        buffer: Vec<u8>,
+        index: u64,
+        last: u64,
+        stored: u64,
+        skip: usize,
+        remaining: u64,
+    }
+
+    let segments = segment_count(plaintext_len(stored));
+    let state = Opening {
+        sealer,
+        body,
+        buffer: Vec::new(),
+        index: start / SEGMENT_LEN as u64,
+        last: segments - 1,
+        stored,
+        skip: (start % SEGMENT_LEN as u64) as usize,
+        remaining: len,
+    };
+
+    Box::pin(futures::stream::unfold(state, |mut s| async move {
+        if s.remaining == 0 {
+            return None;
+        }
+
+        let sealed_len = (SEGMENT_LEN + TAG_LEN) as u64;
+        let segment_len = if s.index == s.last {
+            s.stored - HEADER_LEN as u64 - s.index * sealed_len
+        } else {
+            sealed_len
+        } as usize;
+        while s.buffer.len() < segment_len {
+            match s.body.next().await {
+                Some(Ok(chunk)) => s.buffer.extend_from_slice(&chunk),
+                Some(Err(e)) => {
+                    s.remaining = 0;
+                    return Some((Err(e), s));
+                }
+                None => {
+                    s.remaining = 0;
+                    return Some((Err("encrypted object ended early".to_string()), s));
+                }
+            }
+        }
+
+        let rest = s.buffer.split_off(segment_len);
+        let sealed = std::mem::replace(&mut s.buffer, rest);
+        let opened = match s.sealer.open(s.index, s.index == s.last, &sealed) {
+            Ok(opened) => opened,
+            Err(e) => {
+                s.remaining = 0;
+                return Some((Err(e), s));
+            }
+        };
+
+        let end = opened.len().min(s.skip + s.remaining as usize);
+        let part = opened.slice(s.skip.min(end)..end);
+        s.remaining -= part.len() as u64;
+        s.skip = 0;
+        s.index += 1;
+        Some((Ok(part), s))
+    }))
+}
+
+#[async_trait]
+impl StorageBackend for EncryptedBackend {
+    async fn put(&self, key: &str, data: Vec<u8>, content_type: &str) -> Result<Option<String>, StorageError> {
+        let sealer = self.new_sealer(key).await?;
+        let segments = segment_count(data.len() as u64);
+        let mut sealed = Vec::with_capacity(HEADER_LEN + data.len() + segments as usize * TAG_LEN);
+        sealed.extend_from_slice(&sealer.header.encode());
+        for index in 0..segments {
+            let from = (index as usize * SEGMENT_LEN).min(data.len());
+            let to = (from + SEGMENT_LEN).min(data.len());
+            sealed.extend_from_slice(&sealer.seal(index, index == segments - 1, &data[from..to])?);
+        }
+        self.inner.put(key, sealed, content_type).await
+    }
+
+    async fn put_stream(
+        &self,
+        key: &str,
+        content_type: &str,
+        chunks: &mut ByteChunks<'_>,
+    ) -> Result<Option<String>, StorageError> {
+        struct Sealing<'c, 'a> {
+            chunks: &'c mut ByteChunks<'a>,
+            sealer: Sealer,
+            buffer: Vec<u8>,
+            index: u64,
+            header_sent: bool,
+            done: bool,
+        }
+
+        let state = Sealing {
+            chunks,
+            sealer: self.new_sealer(key).await?,
+            buffer: Vec::new(),
+            index: 0,
+            header_sent: false,
+            done: false,
+        };
+
+        // A segment is only sealed once more bytes are known to follow it, so the
+        // last one can be marked as such.
+        let mut sealed = Box::pin(futures::stream::unfold(state, |mut s| async move {
+            if !s.header_sent {
+                s.header_sent = true;
+                return Some((Ok(Bytes::copy_from_slice(&s.sealer.header.encode())), s));
+            }
+            if s.done {
+                return None;
+            }
+            while s.buffer.len() <= SEGMENT_LEN {
+                match s.chunks.next().await {
+                    Some(Ok(chunk)) => s.buffer.extend_from_slice(&chunk),
+                    Some(Err(e)) => {
+                        s.done = true;
+                        return Some((Err(e), s));
+                    }
+                    None => {
+                        s.done = true;
+                        let sealed = s.sealer.seal(s.index, true, &s.buffer);
+                        return Some((sealed, s));
+                    }
+                }
+            }
+            let rest = s.buffer.split_off(SEGMENT_LEN);
+            let segment = std::mem::replace(&mut s.buffer, rest);
+            let sealed = s.sealer.seal(s.index, false, &segment);
+            s.index += 1;
+            Some((sealed, s))
+        }));
+        self.inner.put_stream(key, content_type, &mut sealed).await
+    }
+
+    async fn get(&self, key: &str) -> Result<Vec<u8>, StorageError> {
+        let data = self.inner.get(key).await?;
+        let Some(header) = Header::decode(&data) else { return Ok(data) };
+
+        let sealer = self.sealer(header).await?;
+        let segments = segment_count(plaintext_len(data.len() as u64));
+        let mut opened = Vec::with_capacity(data.len());
+        for (index, sealed) in data[HEADER_LEN..].chunks(SEGMENT_LEN + TAG_LEN).enumerate() {
+            let index = index as u64;
+            opened.extend_from_slice(&sealer.open(index, index == segments - 1, sealed)?);
+        }
+        Ok(opened)
+    }
+
+    async fn get_range(&self, key: &str, start: u64, len: u64) -> Result<ObjectBody, StorageError> {
+        let (stored, header) = self.inspect(key).await?;
+        let Some(header) = header else { return self.inner.get_range(key, start, len).await };
+        if start + len > plaintext_len(stored) {
+            return Err(format!("range {}+{} is past the end of {}", start, len, key).into());
+        }
+
+        let sealed_len = (SEGMENT_LEN + TAG_LEN) as u64;
+        let first = start / SEGMENT_LEN as u64;
+        let last = (start + len).saturating_sub(1) / SEGMENT_LEN as u64;
+        let from = HEADER_LEN as u64 + first * sealed_len;
+        let to = stored.min(HEADER_LEN as u64 + (last + 1) * sealed_len);
+        let body = self.inner.get_range(key, from, to - from).await?;
+        Ok(open_range(self.sealer(header).await?, body, stored, start, len))
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), StorageError> {
+        self.inner.delete(key).await
+    }
+
+    /// Copies the sealed bytes as they are; the header still names the data key.
+    async fn copy(&self, from: &str, to: &str) -> Result<(), StorageError> {
+        self.inner.copy(from, to).await
+    }
+
+    async fn size(&self, key: &str) -> Result<i64, StorageError> {
+        let (stored, header) = self.inspect(key).await?;
+        Ok(match header {
+            Some(_) => plaintext_len(stored),
+            None => stored,
+        } as i64)
+    }
+
+    async fn presign_download(
+        &self,
+        key: &str,
+        _content_type: Option<String>,
+        expires_in: Duration,
+    ) -> Result<String, StorageError> {
+        Ok(self.signer.stream_url("GET", key, None, expires_in))
+    }
+
+    async fn presign_upload(
+        &self,
+        key: &str,
+        size: i64,
+        _content_type: &str,
+        _sha256: Option<&str>,
+        expires_in: Duration,
+    ) -> Result<String, StorageError> {
+        Ok(self.signer.stream_url("PUT", key, Some(size), expires_in))
+    }
+
+    async fn sha256(&self, key: &str) -> Result<Option<String>, StorageError> {
+        let (stored, header) = self.inspect(key).await?;
+        if header.is_none() {
+            return self.inner.sha256(key).await;
+        }
+
+        let mut body = self.get_range(key, 0, plaintext_len(stored)).await?;
+        let mut hasher = Sha256::new();
+        while let Some(chunk) = body.next().await {
+            hasher.update(&chunk?);
+        }
+        Ok(Some(hex::encode(hasher.finalize())))
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<ObjectInfo>, StorageError> {
+        self.inner.list(prefix).await
+    }
+
+    async fn create_multipart(&self, key: &str, content_type: &str) -> Result<String, StorageError> {
+        self.inner.create_multipart(&multipart_key(key), content_type).await
+    }
+
+    async fn upload_part(
+        &self,
+        key: &str,
+        upload_id: &str,
+        part_number: i32,
+        data: Vec<u8>,
+    ) -> Result<String, StorageError> {
+        self.inner.upload_part(&multipart_key(key), upload_id, part_number, data).await
+    }
+
+    async fn complete_multipart(
+        &self,
+        key: &str,
+        upload_id: &str,
+        parts: &[(i32, String)],
+    ) -> Result<Option<String>, StorageError> {
+        let assembled = multipart_key(key);
+        self.inner.complete_multipart(&assembled, upload_id, parts).await?;
+
+        let size = self.inner.size(&assembled).await? as u64;
+        let mut body = self.inner.get_range(&assembled, 0, size).await?;
+        let etag = self.put_stream(key, "application/octet-stream", &mut body).await?;
+        if let Err(e) = self.inner.delete(&assembled).await {
+            warn!("Failed to delete assembled upload {}: {}", assembled, e);
+        }
+        Ok(etag)
+    }
+
+    async fn abort_multipart(&self, key: &str, upload_id: &str) -> Result<(), StorageError> {
+        self.inner.abort_multipart(&multipart_key(key), upload_id).await
+    }
+
+    async fn abort_stale_multipart(&self, started_before: i64, keep: &HashSet<String>) -> Result<usize, StorageError> {
+        self.inner.abort_stale_multipart(started_before, keep).await
+    }
+
+    async fn set_storage_class(&self, key: &str, class: StorageClass) -> Result<(), StorageError> {
+        self.inner.set_storage_class(key, class).await
+    }
+
+    async fn restore(&self, key: &str, days: i32) -> Result<RestoreState, StorageError> {
+        self.inner.restore(key, days).await
+    }
+
+    fn verify_stream(&self, method: &str, params: &StreamParams) -> bool {
+        self.signer.verify(method, params)
+    }
+
+    async fn check(&self) -> Result<(), StorageError> {
+        self.inner.check().await
+    }
+
+    async fn reload(&self, config: &AppConfig) -> Result<(), StorageError> {
+        self.inner.reload(config).await
+    }
+}
@@ -0,0 +1,284 @@
+use std::{
+    collections::HashSet,
+    path::{Component, PathBuf},
+    time::{Duration, UNIX_EPOCH},
+};
+
+use async_trait::async_trait;
+use axum::body::Bytes;
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio_stream::StreamExt;
+
+use crate::{
+    storage::{ByteChunks, ObjectBody, ObjectInfo, StorageBackend, StorageError, StreamParams, StreamSigner},
+    LOCAL_MULTIPART_PREFIX,
+};
+
+/// Stores objects as files under `root`, serving presigned URLs through `/stream`.
+pub(crate) struct LocalFsBackend {
+    root: PathBuf,
+    signer: StreamSigner,
+}
+
+impl LocalFsBackend {
+    pub(crate) fn new(root: PathBuf, signer: StreamSigner) -> std::io::Result<Self> {
+        std::fs::create_dir_all(&root)?;
+        Ok(Self { root, signer })
+    }
+
+    /// Maps a key to a path under `root`, refusing anything that could escape it.
+    fn path_for(&self, key: &str) -> Result<PathBuf, String> {
+        let relative = std::path::Path::new(key);
+        if relative.components().any(|c| !matches!(c, Component::Normal(_))) {
+            return Err(format!("invalid storage key: {}", key));
+        }
+        Ok(self.root.join(relative))
+    }
+}
+
+#[async_trait]
+impl StorageBackend for LocalFsBackend {
+    async fn put(&self, key: &str, data: Vec<u8>, _content_type: &str) -> Result<Option<String>, StorageError> {
+        let path = self.path_for(key)?;
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await.map_err(|e| e.to_string())?;
+        }
+        let etag = hex::encode(Sha256::digest(&data));
+        tokio::fs::write(path, data).await.map_err(|e| e.to_string())?;
+        Ok(Some(etag))
+    }
+
+    async fn put_stream(
+        &self,
+        key: &str,
+        _content_type: &str,
+        chunks: &mut ByteChunks<'_>,
+    ) -> Result<Option<String>, StorageError> {
+        let path = self.path_for(key)?;
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await.map_err(|e| e.to_string())?;
+        }
+
+        // Written beside the target and renamed into place, so a failed upload
+        // never leaves a truncated file under the real key.
+        let partial = path.with_extension("partial");
+        let mut file = tokio::fs::File::create(&partial).await.map_err(|e| e.to_string())?;
+        let mut hasher = Sha256::new();
+        let written: Result<(), StorageError> = async {
+            while let Some(chunk) = chunks.next().await {
+                let chunk = chunk?;
+                hasher.update(&chunk);
+                file.write_all(&chunk).await.map_err(|e| e.to_string())?;
+            }
+            file.flush().await.map_err(|e| e.to_string().into())
+        }
+        .await;
+
+        if let Err(e) = written {
+            let _ = tokio::fs::remove_file(&partial).await;
+            return Err(e);
+        }
+        tokio::fs::rename(&partial, &path).await.map_err(|e| e.to_string())?;
+        Ok(Some(hex::encode(hasher.finalize())))
+    }
+
+    async fn get(&self, key: &str) -> Result<Vec<u8>, StorageError> {
+        tokio::fs::read(self.path_for(key)?).await.map_err(|e| e.to_string().into())
+    }
+
+    async fn get_range(&self, key: &str, start: u64, len: u64) -> Result<ObjectBody, StorageError> {
+        let mut file = tokio::fs::File::open(self.path_for(key)?).await.map_err(|e| e.to_string())?;
+        file.seek(std::io::SeekFrom::Start(start)).await.map_err(|e| e.to_string())?;
+
+        let body = futures::stream::unfold((file.take(len), vec![0; 64 * 1024]), |(mut file, mut buffer)| async move {
+            match file.read(&mut buffer).await {
+                Ok(0) => None,
+                Ok(read) => Some((Ok(Bytes::copy_from_slice(&buffer[..read])), (file, buffer))),
+                Err(e) => Some((Err(e.to_string()), (file, buffer))),
+            }
+        });
+        Ok(Box::pin(body))
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), StorageError> {
+        match tokio::fs::remove_file(self.path_for(key)?).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.to_string().into()),
+            _ => Ok(()),
+        }
+    }
+
+    async fn copy(&self, from: &str, to: &str) -> Result<(), StorageError> {
+        let to = self.path_for(to)?;
+        if let Some(parent) = to.parent() {
+            tokio::fs::create_dir_all(parent).await.map_err(|e| e.to_string())?;
+        }
+        tokio::fs::copy(self.path_for(from)?, to)
+            .await
+            .map(|_| ())
+            .map_err(|e| e.to_string().into())
+    }
+
+    async fn size(&self, key: &str) -> Result<i64, StorageError> {
+        tokio::fs::metadata(self.path_for(key)?)
+            .await
+            .map(|m| m.len() as i64)
+            .map_err(|e| e.to_string().into())
+    }
+
+    async fn presign_download(
+        &self,
+        key: &str,
+        _content_type: Option<String>,
+        expires_in: Duration,
+    ) -> Result<String, StorageError> {
+        self.path_for(key)?;
+        Ok(self.signer.stream_url("GET", key, None, expires_in))
+    }
+
+    async fn presign_upload(
+        &self,
+        key: &str,
+        size: i64,
+        _content_type: &str,
+        _sha256: Option<&str>,
+        expires_in: Duration,
+    ) -> Result<String, StorageError> {
+        self.path_for(key)?;
+        Ok(self.signer.stream_url("PUT", key, Some(size), expires_in))
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<ObjectInfo>, StorageError> {
+        let mut objects = Vec::new();
+        let mut pending = vec![self.root.clone()];
+
+        while let Some(dir) = pending.pop() {
+            let mut entries = match tokio::fs::read_dir(&dir).await {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.to_string().into()),
+            };
+
+            while let Some(entry) = entries.next_entry().await.map_err(|e| e.to_string())? {
+                let path = entry.path();
+                let metadata = entry.metadata().await.map_err(|e| e.to_string())?;
+                if metadata.is_dir() {
+                    pending.push(path);
+                    continue;
+                }
+
+                let Ok(relative) = path.strip_prefix(&self.root) else { continue };
+                let key = relative.to_string_lossy().replace('\\', "/");
+                if !key.starts_with(prefix) {
+                    continue;
+                }
+
+                let modified = metadata
+                    .modified()
+                    .ok()
+                    .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+                    .map(|d| d.as_secs() as i64);
+                objects.push(ObjectInfo { key, modified });
+            }
+        }
+
+        Ok(objects)
+    }
+
+    async fn sha256(&self, key: &str) -> Result<Option<String>, StorageError> {
+        let mut file = tokio::fs::File::open(self.path_for(key)?).await.map_err(|e| e.to_string())?;
+        let mut hasher = Sha256::new();
+        let mut buffer = vec![0; 64 * 1024];
+        loop {
+            let read = file.read(&mut buffer).await.map_err(|e| e.to_string())?;
+            if read == 0 {
+                break;
+            }
+            hasher.update(&buffer[..read]);
+        }
+        Ok(Some(hex::encode(hasher.finalize())))
+    }
+
+    async fn check(&self) -> Result<(), StorageError> {
+        match tokio::fs::metadata(&self.root).await {
+            Ok(meta) if meta.is_dir() => Ok(()),
+            Ok(_) => Err(format!("{} is not a directory", self.root.display()).into()),
+            Err(e) => Err(format!("{}: {}", self.root.display(), e).into()),
+        }
+    }
+
+    async fn create_multipart(&self, _key: &str, _content_type: &str) -> Result<String, StorageError> {
+        Ok(hex::encode(rand::random::<[u8; 16]>()))
+    }
+
+    async fn upload_part(
+        &self,
+        _key: &str,
+        upload_id: &str,
+        part_number: i32,
+        data: Vec<u8>,
+    ) -> Result<String, StorageError> {
+        let part_key = format!("{}/{}/{}", LOCAL_MULTIPART_PREFIX, upload_id, part_number);
+        self.put(&part_key, data, "application/octet-stream")
+            .await?
+            .ok_or_else(|| format!("part {} was stored without an ETag", part_number).into())
+    }
+
+    async fn complete_multipart(
+        &self,
+        key: &str,
+        upload_id: &str,
+        parts: &[(i32, String)],
+    ) -> Result<Option<String>, StorageError> {
+        let path = self.path_for(key)?;
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await.map_err(|e| e.to_string())?;
+        }
+
+        let partial = path.with_extension("partial");
+        let mut file = tokio::fs::File::create(&partial).await.map_err(|e| e.to_string())?;
+        let mut hasher = Sha256::new();
+        for (part_number, _) in parts {
+            let part_key = format!("{}/{}/{}", LOCAL_MULTIPART_PREFIX, upload_id, part_number);
+            let data = self.get(&part_key).await?;
+            hasher.update(&data);
+            file.write_all(&data).await.map_err(|e| e.to_string())?;
+        }
+        file.flush().await.map_err(|e| e.to_string())?;
+        tokio::fs::rename(&partial, &path).await.map_err(|e| e.to_string())?;
+
+        self.abort_multipart(key, upload_id).await?;
+        Ok(Some(hex::encode(hasher.finalize())))
+    }
+
+    async fn abort_multipart(&self, _key: &str, upload_id: &str) -> Result<(), StorageError> {
+        let dir = self.path_for(&format!("{}/{}", LOCAL_MULTIPART_PREFIX, upload_id))?;
+        match tokio::fs::remove_dir_all(dir).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.to_string().into()),
+            _ => Ok(()),
+        }
+    }
+
+    async fn abort_stale_multipart(&self, started_before: i64, keep: &HashSet<String>) -> Result<usize, StorageError> {
+        let mut dirs = match tokio::fs::read_dir(self.root.join(LOCAL_MULTIPART_PREFIX)).await {
+            Ok(dirs) => dirs,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e.to_string().into()),
+        };
+        let cutoff = UNIX_EPOCH + Duration::from_secs(started_before.max(0) as u64);
+        let mut aborted = 0;
+        while let Some(dir) = dirs.next_entry().await.map_err(|e| e.to_string())? {
+            let upload_id = dir.file_name().to_string_lossy().into_owned();
+            let modified = dir.metadata().await.and_then(|meta| meta.modified()).map_err(|e| e.to_string())?;
+            if modified < cutoff && !keep.contains(&upload_id) {
+                self.abort_multipart("", &upload_id).await?;
+                aborted += 1;
+            }
+        }
+        Ok(aborted)
+    }
+
+    fn verify_stream(&self, method: &str, params: &StreamParams) -> bool {
+        self.signer.verify(method, params)
+    }
+}
@@ -0,0 +1,186 @@
+use std::{collections::{HashMap, HashSet}, sync::Mutex, time::Duration};
+
+use async_trait::async_trait;
+use axum::body::Bytes;
+use sha2::{Digest, Sha256};
+use tokio_stream::StreamExt;
+
+use crate::storage::{
+    ByteChunks, ObjectBody, ObjectInfo, StorageBackend, StorageError, StreamParams, StreamSigner,
+};
+
+struct MemoryObject {
+    data: Bytes,
+    modified: i64,
+}
+
+/// Keeps every object in process memory, so nothing survives a restart. Meant for
+/// tests and throwaway instances; presigned URLs go through `/stream` like the
+/// local backend's.
+pub(crate) struct MemoryBackend {
+    objects: Mutex<HashMap<String, MemoryObject>>,
+    /// Parts of unfinished multipart uploads, keyed by upload id and part number.
+    parts: Mutex<HashMap<(String, i32), Bytes>>,
+    signer: StreamSigner,
+}
+
+impl MemoryBackend {
+    pub(crate) fn new(signer: StreamSigner) -> Self {
+        Self {
+            objects: Mutex::new(HashMap::new()),
+            parts: Mutex::new(HashMap::new()),
+            signer,
+        }
+    }
+
+    fn object(&self, key: &str) -> Result<Bytes, StorageError> {
+        self.objects
+            .lock()
+            .unwrap()
+            .get(key)
+            .map(|object| object.data.clone())
+            .ok_or_else(|| format!("no such key: {}", key).into())
+    }
+
+    fn store(&self, key: &str, data: Bytes) -> Option<String> {
+        let etag = hex::encode(Sha256::digest(&data));
+        let modified = chrono::Utc::now().timestamp();
+        self.objects.lock().unwrap().insert(key.to_string(), MemoryObject { data, modified });
+        Some(etag)
+    }
+}
+
+#[async_trait]
+impl StorageBackend for MemoryBackend {
+    async fn put(&self, key: &str, data: Vec<u8>, _content_type: &str) -> Result<Option<String>, StorageError> {
+        Ok(self.store(key, Bytes::from(data)))
+    }
+
+    async fn put_stream(
+        &self,
+        key: &str,
+        _content_type: &str,
+        chunks: &mut ByteChunks<'_>,
+    ) -> Result<Option<String>, StorageError> {
+        let mut data = Vec::new();
+        while let Some(chunk) = chunks.next().await {
+            data.extend_from_slice(&chunk?);
+        }
+        Ok(self.store(key, Bytes::from(data)))
+    }
+
+    async fn get(&self, key: &str) -> Result<Vec<u8>, StorageError> {
+        self.object(key).map(|data| data.to_vec())
+    }
+
+    async fn get_range(&self, key: &str, start: u64, len: u64) -> Result<ObjectBody, StorageError> {
+        let data = self.object(key)?;
+        let start = (start as usize).min(data.len());
+        let end = start.saturating_add(len as usize).min(data.len());
+        Ok(Box::pin(futures::stream::once(async move { Ok(data.slice(start..end)) })))
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), StorageError> {
+        self.objects.lock().unwrap().remove(key);
+        Ok(())
+    }
+
+    async fn copy(&self, from: &str, to: &str) -> Result<(), StorageError> {
+        let data = self.object(from)?;
+        self.store(to, data);
+        Ok(())
+    }
+
+    async fn size(&self, key: &str) -> Result<i64, StorageError> {
+        self.object(key).map(|data| data.len() as i64)
+    }
+
+    async fn presign_download(
+        &self,
+        key: &str,
+        _content_type: Option<String>,
+        expires_in: Duration,
+    ) -> Result<String, StorageError> {
+        Ok(self.signer.stream_url("GET", key, None, expires_in))
+    }
+
+    async fn presign_upload(
+        &self,
+        key: &str,
+        size: i64,
+        _content_type: &str,
+        _sha256: Option<&str>,
+        expires_in: Duration,
+    ) -> Result<String, StorageError> {
+        Ok(self.signer.stream_url("PUT", key, Some(size), expires_in))
+    }
+
+    async fn sha256(&self, key: &str) -> Result<Option<String>, StorageError> {
+        self.object(key).map(|data| Some(hex::encode(Sha256::digest(&data))))
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<ObjectInfo>, StorageError> {
+        Ok(self
+            .objects
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(key, _)| key.starts_with(prefix))
+            .map(|(key, object)| ObjectInfo { key: key.clone(), modified: Some(object.modified) })
+            .collect())
+    }
+
+    async fn create_multipart(&self, _key: &str, _content_type: &str) -> Result<String, StorageError> {
+        Ok(hex::encode(rand::random::<[u8; 16]>()))
+    }
+
+    async fn upload_part(
+        &self,
+        _key: &str,
+        upload_id: &str,
+        part_number: i32,
+        data: Vec<u8>,
+    ) -> Result<String, StorageError> {
+        let etag = hex::encode(Sha256::digest(&data));
+        self.parts
+            .lock()
+            .unwrap()
+            .insert((upload_id.to_string(), part_number), Bytes::from(data));
+        Ok(etag)
+    }
+
+    async fn complete_multipart(
+        &self,
+        key: &str,
+        upload_id: &str,
+        parts: &[(i32, String)],
+    ) -> Result<Option<String>, StorageError> {
+        let mut data = Vec::new();
+        {
+            let stored = self.parts.lock().unwrap();
+            for (part_number, _) in parts {
+                let part = stored
+                    .get(&(upload_id.to_string(), *part_number))
+                    .ok_or_else(|| format!("missing part {} of upload {}", part_number, upload_id))?;
+                data.extend_from_slice(part);
+            }
+        }
+
+        self.abort_multipart(key, upload_id).await?;
+        Ok(self.store(key, Bytes::from(data)))
+    }
+
+    async fn abort_multipart(&self, _key: &str, upload_id: &str) -> Result<(), StorageError> {
+        self.parts.lock().unwrap().retain(|(id, _), _| id != upload_id);
+        Ok(())
+    }
+
+    async fn abort_stale_multipart(&self, _started_before: i64, _keep: &HashSet<String>) -> Result<usize, StorageError> {
+        // Parts die with the process, so none outlive the uploads they belong to.
+        Ok(0)
+    }
+
+    fn verify_stream(&self, method: &str, params: &StreamParams) -> bool {
+        self.signer.verify(method, params)
+    }
+}
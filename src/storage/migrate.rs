@@ -0,0 +1,66 @@
+//! Copying every object in the bucket in use to another bucket of the same S3
+//! service, for moving a deployment. Keys stay the same, so once the copy is done
+//! the server only needs `S3_BUCKET` pointed at the new bucket.
+
+use tracing::info;
+
+use crate::{
+    config::AppConfig,
+    storage::{build_s3, retry::with_retries, s3::S3Backend, ObjectInfo, StorageBackend},
+};
+
+/// The largest object a single `CopyObject` call can copy.
+const MAX_COPY_OBJECT_BYTES: i64 = 5 * 1024 * 1024 * 1024;
+
+/// How an object reached the target bucket.
+pub(crate) enum Copied {
+    /// Copied within S3 by `CopyObject`.
+    ServerSide,
+    /// Too large for `CopyObject`, so read out and uploaded again.
+    Streamed,
+    /// Already there at the same size, from an earlier run.
+    Present,
+}
+
+pub(crate) struct BucketMigration {
+    source: S3Backend,
+    target: S3Backend,
+}
+
+impl BucketMigration {
+    /// Sets up copying from the bucket in use to `bucket`, creating it if needed.
+    pub(crate) async fn new(config: &AppConfig, bucket: &str) -> Result<Self, String> {
+        let source = build_s3(config, &config.bucket, config.region.as_deref()).await;
+        let target = build_s3(config, bucket, config.region.as_deref()).await;
+        with_retries(&target.retry, "ensure_bucket", || target.ensure_bucket()).await?;
+        info!("Migrating objects from bucket {} to {}", source.bucket, target.bucket);
+        Ok(Self { source, target })
+    }
+
+    pub(crate) fn target_bucket(&self) -> &str {
+        &self.target.bucket
+    }
+
+    /// Every object in the bucket in use.
+    pub(crate) async fn objects(&self) -> Result<Vec<ObjectInfo>, String> {
+        with_retries(&self.source.retry, "list", || self.source.list("")).await.map_err(String::from)
+    }
+
+    /// Copies `key` to the target bucket, unless an earlier run already did.
+    pub(crate) async fn copy(&self, key: &str) -> Result<Copied, String> {
+        let (size, content_type) = with_retries(&self.source.retry, "head", || self.source.head(key)).await?;
+        if self.target.head(key).await.is_ok_and(|(copied, _)| copied == size) {
+            return Ok(Copied::Present);
+        }
+
+        if size <= MAX_COPY_OBJECT_BYTES {
+            with_retries(&self.target.retry, "copy", || self.target.copy_from_bucket(&self.source.bucket, key)).await?;
+            return Ok(Copied::ServerSide);
+        }
+        // Streamed uploads retry their own parts, so the body isn't read twice.
+        let mut body = self.source.get_range(key, 0, size as u64).await?;
+        let content_type = content_type.unwrap_or_else(|| "application/octet-stream".into());
+        self.target.put_stream(key, &content_type, &mut body).await?;
+        Ok(Copied::Streamed)
+    }
+}
@@ -0,0 +1,486 @@
+use std::{
+    collections::HashSet,
+    env, fmt,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use aws_config::{
+    provider_config::ProviderConfig,
+    sts::AssumeRoleProvider,
+    web_identity_token::{StaticConfiguration, WebIdentityTokenCredentialsProvider},
+};
+use aws_sdk_s3::config::{retry::RetryConfig, timeout::TimeoutConfig, Credentials, Region, SharedCredentialsProvider};
+use axum::body::Bytes;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use tokio_stream::{Stream, StreamExt};
+use tracing::info;
+use utoipa::ToSchema;
+
+use crate::{config::AppConfig, env_or, retry::RetryPolicy, STORAGE_TIMEOUT_ERROR, STORAGE_UNAVAILABLE_ERROR};
+use local::LocalFsBackend;
+use memory::MemoryBackend;
+use routing::RoutedBackend;
+use s3::S3Backend;
+
+pub(crate) mod cache;
+pub(crate) mod compression;
+pub(crate) mod dedup;
+pub(crate) mod encryption;
+mod local;
+mod memory;
+pub(crate) mod migrate;
+pub(crate) mod retry;
+pub(crate) mod routing;
+mod s3;
+pub(crate) mod spool;
+
+#[derive(Deserialize)]
+pub(crate) struct StreamParams {
+    pub(crate) key: String,
+    expires: i64,
+    sig: String,
+    pub(crate) size: Option<i64>,
+}
+
+pub(crate) struct ObjectInfo {
+    pub(crate) key: String,
+    /// Last modification time as a unix timestamp, when the backend reports one.
+    pub(crate) modified: Option<i64>,
+}
+
+/// How an object is kept, in S3's terms. The archive classes are cheaper to keep
+/// and dearer to read.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub(crate) enum StorageClass {
+    Standard,
+    /// Infrequent access: readable at once, billed per read.
+    StandardIa,
+    /// Readable only once restored, which takes hours.
+    Glacier,
+}
+
+impl StorageClass {
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            StorageClass::Standard => "STANDARD",
+            StorageClass::StandardIa => "STANDARD_IA",
+            StorageClass::Glacier => "GLACIER",
+        }
+    }
+
+    /// Whether objects of the class have to be restored before they can be read.
+    pub(crate) fn is_glacial(self) -> bool {
+        self == StorageClass::Glacier
+    }
+}
+
+impl TryFrom<String> for StorageClass {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, String> {
+        match value.as_str() {
+            "STANDARD" => Ok(StorageClass::Standard),
+            "STANDARD_IA" => Ok(StorageClass::StandardIa),
+            "GLACIER" => Ok(StorageClass::Glacier),
+            _ => Err(format!("Unknown storage class {}", value)),
+        }
+    }
+}
+
+/// Where reading an archived object stands.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum RestoreState {
+    /// The object can be read as it is.
+    Readable,
+    /// A restore was asked for and hasn't finished.
+    InProgress,
+    /// A readable copy is available until `expires_at`, a unix timestamp.
+    Restored { expires_at: Option<i64> },
+}
+
+/// Why a storage call failed. Shown with the prefix each kind has always been
+/// reported under, which failure codes and clients still read.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum StorageError {
+    /// No answer in time, by the S3 client's own timeouts or the retry policy's.
+    TimedOut(String),
+    /// Failed in a way the service reported as passing, such as a 5xx or
+    /// throttling, or the request never reached it.
+    Unavailable(String),
+    /// Anything else, which the same call won't get past.
+    Failed(String),
+}
+
+impl StorageError {
+    /// Whether the same call may pass when made again.
+    pub(crate) fn is_transient(&self) -> bool {
+        matches!(self, StorageError::TimedOut(_) | StorageError::Unavailable(_))
+    }
+
+    /// The same error, its description rewritten by `f`.
+    pub(crate) fn map(self, f: impl FnOnce(String) -> String) -> Self {
+        match self {
+            StorageError::TimedOut(detail) => StorageError::TimedOut(f(detail)),
+            StorageError::Unavailable(detail) => StorageError::Unavailable(f(detail)),
+            StorageError::Failed(detail) => StorageError::Failed(f(detail)),
+        }
+    }
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StorageError::TimedOut(detail) => write!(f, "{}: {}", STORAGE_TIMEOUT_ERROR, detail),
+            StorageError::Unavailable(detail) => write!(f, "{}: {}", STORAGE_UNAVAILABLE_ERROR, detail),
+            StorageError::Failed(detail) => f.write_str(detail),
+        }
+    }
+}
+
+impl From<String> for StorageError {
+    fn from(detail: String) -> Self {
+        StorageError::Failed(detail)
+    }
+}
+
+impl From<&str> for StorageError {
+    fn from(detail: &str) -> Self {
+        StorageError::Failed(detail.to_string())
+    }
+}
+
+impl From<StorageError> for String {
+    fn from(error: StorageError) -> Self {
+        error.to_string()
+    }
+}
+
+/// Where file bytes live. Handlers only talk to this, so the server can run
+/// against S3, a local directory or plain memory depending on `STORAGE_BACKEND`.
+#[async_trait]
+pub(crate) trait StorageBackend: Send + Sync {
+    /// Stores `data` under `key` and returns the object's ETag, unquoted.
+    async fn put(&self, key: &str, data: Vec<u8>, content_type: &str) -> Result<Option<String>, StorageError>;
+    /// Like `put`, but consumes the body chunk by chunk so it is never held in memory whole.
+    async fn put_stream(
+        &self,
+        key: &str,
+        content_type: &str,
+        chunks: &mut ByteChunks<'_>,
+    ) -> Result<Option<String>, StorageError>;
+    async fn get(&self, key: &str) -> Result<Vec<u8>, StorageError>;
+    /// Streams `len` bytes of the object starting at byte `start`.
+    async fn get_range(&self, key: &str, start: u64, len: u64) -> Result<ObjectBody, StorageError>;
+    async fn delete(&self, key: &str) -> Result<(), StorageError>;
+    async fn copy(&self, from: &str, to: &str) -> Result<(), StorageError>;
+    /// Size in bytes of the stored object.
+    async fn size(&self, key: &str) -> Result<i64, StorageError>;
+    async fn presign_download(
+        &self,
+        key: &str,
+        content_type: Option<String>,
+        expires_in: Duration,
+    ) -> Result<String, StorageError>;
+    /// A URL the client can PUT exactly `size` bytes of `content_type` to. Backends
+    /// that can enforce it also pin the body to the hex `sha256`.
+    async fn presign_upload(
+        &self,
+        key: &str,
+        size: i64,
+        content_type: &str,
+        sha256: Option<&str>,
+        expires_in: Duration,
+    ) -> Result<String, StorageError>;
+
+    /// Extra headers a client must send with a `presign_upload` PUT.
+    fn upload_headers(&self, _sha256: Option<&str>) -> Vec<(&'static str, String)> {
+        Vec::new()
+    }
+
+    /// Hex SHA-256 of the stored object, or `None` when the backend doesn't know it.
+    async fn sha256(&self, key: &str) -> Result<Option<String>, StorageError>;
+
+    /// Every object whose key starts with `prefix`.
+    async fn list(&self, prefix: &str) -> Result<Vec<ObjectInfo>, StorageError>;
+
+    /// Starts a multipart upload to `key` and returns its id.
+    async fn create_multipart(&self, key: &str, content_type: &str) -> Result<String, StorageError>;
+    /// Stores one part of a multipart upload and returns its ETag.
+    async fn upload_part(
+        &self,
+        key: &str,
+        upload_id: &str,
+        part_number: i32,
+        data: Vec<u8>,
+    ) -> Result<String, StorageError>;
+    /// Joins the `(part_number, etag)` parts, in order, into the object at `key`.
+    async fn complete_multipart(
+        &self,
+        key: &str,
+        upload_id: &str,
+        parts: &[(i32, String)],
+    ) -> Result<Option<String>, StorageError>;
+    async fn abort_multipart(&self, key: &str, upload_id: &str) -> Result<(), StorageError>;
+    /// Aborts the multipart uploads started before `started_before`, a unix
+    /// timestamp, other than those in `keep`, and returns how many it aborted.
+    /// Uploads cut off by a crash are never completed or aborted otherwise, and
+    /// their parts are billed until they are.
+    async fn abort_stale_multipart(&self, started_before: i64, keep: &HashSet<String>) -> Result<usize, StorageError>;
+
+    /// Moves the object at `key` to `class` where it is. Backends without storage
+    /// classes keep every object alike, and do nothing.
+    async fn set_storage_class(&self, _key: &str, _class: StorageClass) -> Result<(), StorageError> {
+        Ok(())
+    }
+
+    /// Where reading the object at `key` stands, asking for a copy readable for
+    /// `days` when its class needs one and none was asked for yet.
+    async fn restore(&self, _key: &str, _days: i32) -> Result<RestoreState, StorageError> {
+        Ok(RestoreState::Readable)
+    }
+
+    /// Checks a `/stream` signature. Only backends that hand out `/stream` URLs accept any.
+    fn verify_stream(&self, _method: &str, _params: &StreamParams) -> bool {
+        false
+    }
+
+    /// Confirms the backend is reachable and usable, for `/readyz`.
+    async fn check(&self) -> Result<(), StorageError> {
+        Ok(())
+    }
+
+    /// Rebuilds the backend's clients with the credentials `config` names, once
+    /// they've been checked to work. Calls already under way finish on the old
+    /// ones. Backends without clients have nothing to rebuild.
+    async fn reload(&self, _config: &AppConfig) -> Result<(), StorageError> {
+        Ok(())
+    }
+}
+
+/// Converts a hex SHA-256 into the base64 form S3 checksum headers use.
+pub(crate) fn sha256_base64(hex_digest: &str) -> Option<String> {
+    hex::decode(hex_digest).ok().map(|bytes| BASE64.encode(bytes))
+}
+
+/// An object body being streamed out of storage.
+pub(crate) type ObjectBody = futures::stream::BoxStream<'static, Result<Bytes, String>>;
+
+/// A request body being streamed into storage.
+pub(crate) type ByteChunks<'a> = dyn Stream<Item = Result<Bytes, String>> + Send + Unpin + 'a;
+
+/// Reads from `chunks` until at least `limit` bytes are buffered or the body ends.
+/// The flag is `true` once the body has been read to the end.
+pub(crate) async fn read_part(chunks: &mut ByteChunks<'_>, limit: usize) -> Result<(Vec<u8>, bool), String> {
+    let mut buffer = Vec::new();
+    while buffer.len() < limit {
+        match chunks.next().await {
+            Some(chunk) => buffer.extend_from_slice(&chunk?),
+            None => return Ok((buffer, true)),
+        }
+    }
+    Ok((buffer, false))
+}
+
+/// Issues and checks the `/stream` URLs that stand in for presigned URLs on
+/// backends without their own. Each URL carries an HMAC so it can't be forged
+/// or reused after it expires.
+pub(crate) struct StreamSigner {
+    base_url: String,
+    secret: Vec<u8>,
+}
+
+impl StreamSigner {
+    fn sign(&self, method: &str, key: &str, expires: i64, size: Option<i64>) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.secret)
+            .expect("HMAC accepts keys of any length");
+        let size = size.map(|s| s.to_string()).unwrap_or_default();
+        mac.update(format!("{}\n{}\n{}\n{}", method, key, expires, size).as_bytes());
+        mac
+    }
+
+    fn stream_url(&self, method: &str, key: &str, size: Option<i64>, expires_in: Duration) -> String {
+        let expires = chrono::Utc::now().timestamp() + expires_in.as_secs() as i64;
+        let sig = hex::encode(self.sign(method, key, expires, size).finalize().into_bytes());
+        let mut url = format!(
+            "{}/stream?key={}&expires={}&sig={}",
+            self.base_url,
+            urlencoding::encode(key),
+            expires,
+            sig
+        );
+        if let Some(size) = size {
+            url.push_str(&format!("&size={}", size));
+        }
+        url
+    }
+
+    fn verify(&self, method: &str, params: &StreamParams) -> bool {
+        if params.expires < chrono::Utc::now().timestamp() {
+            return false;
+        }
+        let Ok(sig) = hex::decode(&params.sig) else { return false };
+        self.sign(method, &params.key, params.expires, params.size)
+            .verify_slice(&sig)
+            .is_ok()
+    }
+}
+
+/// Builds the storage backend named by `config.storage_backend` (`s3`, `local` or `memory`).
+pub(crate) async fn build_storage(config: &AppConfig) -> Arc<dyn StorageBackend> {
+    config.routing.validate();
+    if !config.routing.targets.is_empty() && config.storage_backend != "s3" {
+        panic!("Storage routing targets need STORAGE_BACKEND=s3");
+    }
+    match config.storage_backend.as_str() {
+        "local" => {
+            let root = &config.local_storage_dir;
+            info!("Using local storage under {}", root.display());
+            let backend = LocalFsBackend::new(root.clone(), stream_signer(config))
+                .expect("Failed to create local storage directory");
+            Arc::new(backend)
+        }
+        "memory" => {
+            info!("Using in-memory storage; files are lost on restart");
+            Arc::new(MemoryBackend::new(stream_signer(config)))
+        }
+        "s3" => {
+            let backend = build_s3(config, &config.bucket, config.region.as_deref()).await;
+            if let Err(e) = retry::with_retries(&backend.retry, "ensure_bucket", || backend.ensure_bucket()).await {
+                panic!("S3 bucket {} is unavailable: {}", config.bucket, e);
+            }
+            if config.routing.targets.is_empty() {
+                return Arc::new(backend);
+            }
+            // Deduplicated content is stored once for all the keys holding it, in
+            // whichever bucket its first upload went to.
+            if config.dedup {
+                panic!("STORAGE_DEDUP can't be combined with storage routing targets");
+            }
+            match RoutedBackend::new(config, backend).await {
+                Ok(routed) => Arc::new(routed),
+                Err(e) => panic!("Storage routing is unavailable: {}", e),
+            }
+        }
+        other => panic!("Unknown STORAGE_BACKEND: {}", other),
+    }
+}
+
+/// A client for `bucket`, in `region`, on the S3 service `config` describes.
+async fn build_s3(config: &AppConfig, bucket: &str, region: Option<&str>) -> S3Backend {
+    let client = s3_client(config, region).await.unwrap_or_else(|e| panic!("{}", e));
+    S3Backend {
+        client: RwLock::new(client),
+        bucket: bucket.to_string(),
+        region: region.map(str::to_string),
+        sse_kms_key_id: env::var("S3_SSE_KMS_KEY_ID").ok(),
+        retry: RetryPolicy::storage(&config.retry),
+    }
+}
+
+/// A client for `region` on the S3 service `config` describes, with the
+/// credentials it names. The SDK caches them, and renews those that expire.
+pub(crate) async fn s3_client(config: &AppConfig, region: Option<&str>) -> Result<aws_sdk_s3::Client, String> {
+    let credentials = &config.credentials;
+    let static_keys = match (&config.access_key_id, &config.secret_access_key) {
+        (Some(id), Some(secret)) => Some(Credentials::new(id, secret, None, None, "pocket-config")),
+        (None, None) => None,
+        _ => return Err("S3_ACCESS_KEY_ID and S3_SECRET_ACCESS_KEY must be set together".into()),
+    };
+    if static_keys.is_some() && credentials.profile.is_some() {
+        return Err("S3_PROFILE can't be combined with S3_ACCESS_KEY_ID".into());
+    }
+    if credentials.web_identity_token_file.is_some() {
+        if credentials.role_arn.is_none() {
+            return Err("S3_WEB_IDENTITY_TOKEN_FILE needs S3_ROLE_ARN".into());
+        }
+        if static_keys.is_some() || credentials.profile.is_some() {
+            return Err("S3_WEB_IDENTITY_TOKEN_FILE can't be combined with static keys or S3_PROFILE".into());
+        }
+    }
+
+    let mut loader = aws_config::from_env();
+    if let Some(region) = region {
+        loader = loader.region(Region::new(region.to_string()));
+    }
+    if let Some(profile) = &credentials.profile {
+        info!("Using S3 credentials of profile {}", profile);
+        loader = loader.profile_name(profile);
+    }
+    if let Some(keys) = static_keys {
+        loader = loader.credentials_provider(keys);
+    }
+    let mut sdk_config = loader.load().await;
+
+    if let Some(role_arn) = &credentials.role_arn {
+        let provider = match &credentials.web_identity_token_file {
+            Some(token_file) => {
+                info!("Assuming role {} with the web identity token in {}", role_arn, token_file.display());
+                let provider_config = ProviderConfig::with_default_region().await.with_region(sdk_config.region().cloned());
+                let provider = WebIdentityTokenCredentialsProvider::builder()
+                    .configure(&provider_config)
+                    .static_configuration(StaticConfiguration {
+                        web_identity_token_file: token_file.clone(),
+                        role_arn: role_arn.clone(),
+                        session_name: credentials.role_session_name.clone(),
+                    })
+                    .build();
+                SharedCredentialsProvider::new(provider)
+            }
+            None => {
+                info!("Assuming role {}", role_arn);
+                let mut builder = AssumeRoleProvider::builder(role_arn).session_name(&credentials.role_session_name);
+                if let Some(external_id) = &credentials.external_id {
+                    builder = builder.external_id(external_id);
+                }
+                SharedCredentialsProvider::new(builder.configure(&sdk_config).build().await)
+            }
+        };
+        sdk_config = sdk_config.into_builder().credentials_provider(provider).build();
+    }
+
+    let mut s3_config = aws_sdk_s3::config::Builder::from(&sdk_config);
+    if let Some(endpoint) = &config.endpoint_url {
+        info!("Using S3 endpoint: {}", endpoint);
+        s3_config = s3_config.endpoint_url(endpoint);
+    }
+    if config.force_path_style {
+        s3_config = s3_config.force_path_style(true);
+    }
+    // The SDK's own retries are off, so `RetryPolicy` alone decides how often a
+    // call is made and what the attempts in an error count. Each timeout here
+    // then bounds a single attempt, streamed upload parts included.
+    //
+    // The two layers give a budget of their own: an attempt ends at the first of
+    // `storage_timeout_secs` (60s) and these limits (5s to connect, 30s between
+    // reads, 120s in all), so by default the policy's timeout is the one that
+    // cuts a slow call. A call then takes at most `storage_attempts` times that,
+    // plus a backoff of up to `max_delay_ms` before each retry: about three
+    // minutes with the defaults. Streamed uploads skip the policy's timeout, so
+    // each of their parts gets the 120s here and the same number of attempts,
+    // and the upload as a whole has no limit beyond its part count.
+    s3_config = s3_config.retry_config(RetryConfig::disabled());
+    s3_config = s3_config.timeout_config(
+        TimeoutConfig::builder()
+            .connect_timeout(Duration::from_secs(env_or("S3_CONNECT_TIMEOUT_SECS", 5)))
+            .read_timeout(Duration::from_secs(env_or("S3_READ_TIMEOUT_SECS", 30)))
+            .operation_timeout(Duration::from_secs(env_or("S3_OPERATION_TIMEOUT_SECS", 120)))
+            .build(),
+    );
+    Ok(aws_sdk_s3::Client::from_conf(s3_config.build()))
+}
+
+/// Signer for `/stream` URLs, from the public base URL and `STORAGE_SIGNING_SECRET`.
+fn stream_signer(config: &AppConfig) -> StreamSigner {
+    let base_url = config.public_base_url.clone();
+    // Without a configured secret, stream URLs simply stop working after a restart.
+    let secret = env::var("STORAGE_SIGNING_SECRET")
+        .map(String::into_bytes)
+        .unwrap_or_else(|_| rand::random::<[u8; 32]>().to_vec());
+    StreamSigner { base_url, secret }
+}
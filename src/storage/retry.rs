@@ -0,0 +1,168 @@
+use std::{collections::HashSet, future::Future, sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use tokio::time::timeout;
+
+use crate::{
+    config::AppConfig,
+    retry::{after_attempts, RetryPolicy},
+    storage::{ByteChunks, ObjectBody, ObjectInfo, RestoreState, StorageBackend, StorageClass, StorageError, StreamParams},
+};
+
+/// The error of an attempt the policy's timeout cut short.
+fn timed_out(policy: &RetryPolicy) -> StorageError {
+    StorageError::TimedOut(format!("no response within {}s", policy.timeout().as_secs()))
+}
+
+/// Runs a storage call under `policy`, retrying transient failures. The error
+/// of a call that took more than one attempt says how many.
+pub(crate) async fn with_retries<T, Fut>(
+    policy: &RetryPolicy,
+    operation: &str,
+    call: impl FnMut() -> Fut,
+) -> Result<T, StorageError>
+where
+    Fut: Future<Output = Result<T, StorageError>>,
+{
+    let (result, attempts) = policy.run(operation, call, StorageError::is_transient, || timed_out(policy)).await;
+    result.map_err(|e| e.map(|detail| after_attempts(detail, attempts)))
+}
+
+/// Retries the wrapped backend's transient failures and bounds each attempt by
+/// the policy's timeout. Streamed uploads pass straight through, since their body
+/// is consumed by the first attempt; backends retry their parts themselves.
+pub(crate) struct RetryingBackend {
+    inner: Arc<dyn StorageBackend>,
+    policy: RetryPolicy,
+}
+
+impl RetryingBackend {
+    pub(crate) fn new(inner: Arc<dyn StorageBackend>, policy: RetryPolicy) -> Self {
+        Self { inner, policy }
+    }
+}
+
+#[async_trait]
+impl StorageBackend for RetryingBackend {
+    async fn put(&self, key: &str, data: Vec<u8>, content_type: &str) -> Result<Option<String>, StorageError> {
+        with_retries(&self.policy, "put", || self.inner.put(key, data.clone(), content_type)).await
+    }
+
+    async fn put_stream(
+        &self,
+        key: &str,
+        content_type: &str,
+        chunks: &mut ByteChunks<'_>,
+    ) -> Result<Option<String>, StorageError> {
+        self.inner.put_stream(key, content_type, chunks).await
+    }
+
+    async fn get(&self, key: &str) -> Result<Vec<u8>, StorageError> {
+        with_retries(&self.policy, "get", || self.inner.get(key)).await
+    }
+
+    async fn get_range(&self, key: &str, start: u64, len: u64) -> Result<ObjectBody, StorageError> {
+        with_retries(&self.policy, "get", || self.inner.get_range(key, start, len)).await
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), StorageError> {
+        with_retries(&self.policy, "delete", || self.inner.delete(key)).await
+    }
+
+    async fn copy(&self, from: &str, to: &str) -> Result<(), StorageError> {
+        with_retries(&self.policy, "copy", || self.inner.copy(from, to)).await
+    }
+
+    async fn size(&self, key: &str) -> Result<i64, StorageError> {
+        with_retries(&self.policy, "size", || self.inner.size(key)).await
+    }
+
+    async fn presign_download(
+        &self,
+        key: &str,
+        content_type: Option<String>,
+        expires_in: Duration,
+    ) -> Result<String, StorageError> {
+        self.inner.presign_download(key, content_type, expires_in).await
+    }
+
+    async fn presign_upload(
+        &self,
+        key: &str,
+        size: i64,
+        content_type: &str,
+        sha256: Option<&str>,
+        expires_in: Duration,
+    ) -> Result<String, StorageError> {
+        self.inner.presign_upload(key, size, content_type, sha256, expires_in).await
+    }
+
+    fn upload_headers(&self, sha256: Option<&str>) -> Vec<(&'static str, String)> {
+        self.inner.upload_headers(sha256)
+    }
+
+    async fn sha256(&self, key: &str) -> Result<Option<String>, StorageError> {
+        with_retries(&self.policy, "sha256", || self.inner.sha256(key)).await
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<ObjectInfo>, StorageError> {
+        with_retries(&self.policy, "list", || self.inner.list(prefix)).await
+    }
+
+    async fn create_multipart(&self, key: &str, content_type: &str) -> Result<String, StorageError> {
+        with_retries(&self.policy, "create_multipart", || self.inner.create_multipart(key, content_type)).await
+    }
+
+    async fn upload_part(
+        &self,
+        key: &str,
+        upload_id: &str,
+        part_number: i32,
+        data: Vec<u8>,
+    ) -> Result<String, StorageError> {
+        with_retries(&self.policy, "upload_part", || {
+            self.inner.upload_part(key, upload_id, part_number, data.clone())
+        })
+        .await
+    }
+
+    async fn complete_multipart(
+        &self,
+        key: &str,
+        upload_id: &str,
+        parts: &[(i32, String)],
+    ) -> Result<Option<String>, StorageError> {
+        with_retries(&self.policy, "complete_multipart", || self.inner.complete_multipart(key, upload_id, parts)).await
+    }
+
+    async fn abort_multipart(&self, key: &str, upload_id: &str) -> Result<(), StorageError> {
+        with_retries(&self.policy, "abort_multipart", || self.inner.abort_multipart(key, upload_id)).await
+    }
+
+    async fn abort_stale_multipart(&self, started_before: i64, keep: &HashSet<String>) -> Result<usize, StorageError> {
+        with_retries(&self.policy, "abort_stale_multipart", || self.inner.abort_stale_multipart(started_before, keep)).await
+    }
+
+    async fn set_storage_class(&self, key: &str, class: StorageClass) -> Result<(), StorageError> {
+        with_retries(&self.policy, "set_storage_class", || self.inner.set_storage_class(key, class)).await
+    }
+
+    async fn restore(&self, key: &str, days: i32) -> Result<RestoreState, StorageError> {
+        with_retries(&self.policy, "restore", || self.inner.restore(key, days)).await
+    }
+
+    fn verify_stream(&self, method: &str, params: &StreamParams) -> bool {
+        self.inner.verify_stream(method, params)
+    }
+
+    /// Not retried, so `/readyz` reports trouble as soon as it starts.
+    async fn check(&self) -> Result<(), StorageError> {
+        timeout(self.policy.timeout(), self.inner.check())
+            .await
+            .unwrap_or_else(|_| Err(timed_out(&self.policy)))
+    }
+
+    async fn reload(&self, config: &AppConfig) -> Result<(), StorageError> {
+        self.inner.reload(config).await
+    }
+}
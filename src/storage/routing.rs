@@ -0,0 +1,222 @@
+//! Keeping objects in the bucket the routing table sends them to. Keys of files and
+//! vault items routed to a target carry its name after the owner's id, as in
+//! `data/3/@eu/<random>/<name>`, and keys made from theirs, such as trash and
+//! revisions, embed it too, so every call resolves its bucket from the key alone.
+
+use std::{collections::{HashMap, HashSet}, time::Duration};
+
+use async_trait::async_trait;
+use tracing::info;
+
+use crate::{
+    config::AppConfig,
+    storage::{
+        build_s3, retry::with_retries, s3::S3Backend, ByteChunks, ObjectBody, ObjectInfo, RestoreState, StorageBackend,
+        StorageClass, StorageError, StreamParams,
+    },
+    VAULT_PREFIX,
+};
+
+/// The target named in `key`, or `None` for the default bucket.
+pub(crate) fn target_of(key: &str) -> Option<&str> {
+    let segments: Vec<&str> = key.split('/').collect();
+    segments
+        .windows(3)
+        .find(|w| matches!(w[0], "data" | VAULT_PREFIX) && !w[1].is_empty() && w[1].bytes().all(|b| b.is_ascii_digit()))
+        .and_then(|w| w[2].strip_prefix('@'))
+}
+
+/// `key`, a file's or vault item's own key, naming `target` instead of the target
+/// it names now.
+pub(crate) fn with_target(key: &str, target: Option<&str>) -> String {
+    let mut segments: Vec<String> = key.split('/').map(String::from).collect();
+    if segments.len() > 3 && segments[2].starts_with('@') {
+        segments.remove(2);
+    }
+    if let Some(target) = target {
+        segments.insert(2, format!("@{}", target));
+    }
+    segments.join("/")
+}
+
+/// S3 storage spread over the configured bucket and the routing table's targets.
+pub(crate) struct RoutedBackend {
+    default: S3Backend,
+    targets: HashMap<String, S3Backend>,
+}
+
+impl RoutedBackend {
+    /// Connects to every target, creating its bucket if needed.
+    pub(crate) async fn new(config: &AppConfig, default: S3Backend) -> Result<Self, String> {
+        let mut targets = HashMap::new();
+        for (name, target) in &config.routing.targets {
+            let backend = build_s3(config, &target.bucket, target.region.as_deref().or(config.region.as_deref())).await;
+            with_retries(&backend.retry, "ensure_bucket", || backend.ensure_bucket())
+                .await
+                .map_err(|e| format!("bucket {} of storage target {}: {}", target.bucket, name, e))?;
+            info!("Routing storage target {} to bucket {}", name, target.bucket);
+            targets.insert(name.clone(), backend);
+        }
+        Ok(Self { default, targets })
+    }
+
+    fn backend(&self, key: &str) -> Result<&S3Backend, String> {
+        match target_of(key) {
+            Some(name) => self.targets.get(name).ok_or_else(|| format!("Storage target {} isn't configured", name)),
+            None => Ok(&self.default),
+        }
+    }
+
+    fn all(&self) -> impl Iterator<Item = &S3Backend> {
+        std::iter::once(&self.default).chain(self.targets.values())
+    }
+}
+
+#[async_trait]
+impl StorageBackend for RoutedBackend {
+    async fn put(&self, key: &str, data: Vec<u8>, content_type: &str) -> Result<Option<String>, StorageError> {
+        self.backend(key)?.put(key, data, content_type).await
+    }
+
+    async fn put_stream(
+        &self,
+        key: &str,
+        content_type: &str,
+        chunks: &mut ByteChunks<'_>,
+    ) -> Result<Option<String>, StorageError> {
+        self.backend(key)?.put_stream(key, content_type, chunks).await
+    }
+
+    async fn get(&self, key: &str) -> Result<Vec<u8>, StorageError> {
+        self.backend(key)?.get(key).await
+    }
+
+    async fn get_range(&self, key: &str, start: u64, len: u64) -> Result<ObjectBody, StorageError> {
+        self.backend(key)?.get_range(key, start, len).await
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), StorageError> {
+        self.backend(key)?.delete(key).await
+    }
+
+    /// Copies within S3 inside one bucket, and streams the object across otherwise.
+    async fn copy(&self, from: &str, to: &str) -> Result<(), StorageError> {
+        let (source, target) = (self.backend(from)?, self.backend(to)?);
+        if source.bucket == target.bucket {
+            return source.copy(from, to).await;
+        }
+        let (size, content_type) = source.head(from).await?;
+        let mut body = source.get_range(from, 0, size as u64).await?;
+        let content_type = content_type.unwrap_or_else(|| "application/octet-stream".into());
+        target.put_stream(to, &content_type, &mut body).await.map(|_| ())
+    }
+
+    async fn size(&self, key: &str) -> Result<i64, StorageError> {
+        self.backend(key)?.size(key).await
+    }
+
+    async fn presign_download(
+        &self,
+        key: &str,
+        content_type: Option<String>,
+        expires_in: Duration,
+    ) -> Result<String, StorageError> {
+        self.backend(key)?.presign_download(key, content_type, expires_in).await
+    }
+
+    async fn presign_upload(
+        &self,
+        key: &str,
+        size: i64,
+        content_type: &str,
+        sha256: Option<&str>,
+        expires_in: Duration,
+    ) -> Result<String, StorageError> {
+        self.backend(key)?.presign_upload(key, size, content_type, sha256, expires_in).await
+    }
+
+    fn upload_headers(&self, sha256: Option<&str>) -> Vec<(&'static str, String)> {
+        self.default.upload_headers(sha256)
+    }
+
+    async fn sha256(&self, key: &str) -> Result<Option<String>, StorageError> {
+        self.backend(key)?.sha256(key).await
+    }
+
+    /// Lists every bucket, since keys anywhere under `prefix` may be in any of them.
+    async fn list(&self, prefix: &str) -> Result<Vec<ObjectInfo>, StorageError> {
+        let mut objects = Vec::new();
+        for backend in self.all() {
+            objects.extend(backend.list(prefix).await?);
+        }
+        Ok(objects)
+    }
+
+    async fn create_multipart(&self, key: &str, content_type: &str) -> Result<String, StorageError> {
+        self.backend(key)?.create_multipart(key, content_type).await
+    }
+
+    async fn upload_part(
+        &self,
+        key: &str,
+        upload_id: &str,
+        part_number: i32,
+        data: Vec<u8>,
+    ) -> Result<String, StorageError> {
+        self.backend(key)?.upload_part(key, upload_id, part_number, data).await
+    }
+
+    async fn complete_multipart(
+        &self,
+        key: &str,
+        upload_id: &str,
+        parts: &[(i32, String)],
+    ) -> Result<Option<String>, StorageError> {
+        self.backend(key)?.complete_multipart(key, upload_id, parts).await
+    }
+
+    async fn abort_multipart(&self, key: &str, upload_id: &str) -> Result<(), StorageError> {
+        self.backend(key)?.abort_multipart(key, upload_id).await
+    }
+
+    async fn abort_stale_multipart(&self, started_before: i64, keep: &HashSet<String>) -> Result<usize, StorageError> {
+        let mut aborted = 0;
+        for backend in self.all() {
+            aborted += backend.abort_stale_multipart(started_before, keep).await?;
+        }
+        Ok(aborted)
+    }
+
+    async fn set_storage_class(&self, key: &str, class: StorageClass) -> Result<(), StorageError> {
+        self.backend(key)?.set_storage_class(key, class).await
+    }
+
+    async fn restore(&self, key: &str, days: i32) -> Result<RestoreState, StorageError> {
+        self.backend(key)?.restore(key, days).await
+    }
+
+    fn verify_stream(&self, method: &str, params: &StreamParams) -> bool {
+        self.default.verify_stream(method, params)
+    }
+
+    async fn check(&self) -> Result<(), StorageError> {
+        for backend in self.all() {
+            backend.check().await?;
+        }
+        Ok(())
+    }
+
+    /// Checks every bucket against its new client before swapping any in, so
+    /// credentials one target refuses leave all of them on the old ones rather
+    /// than some buckets reloaded and the rest not.
+    async fn reload(&self, config: &AppConfig) -> Result<(), StorageError> {
+        let mut clients = Vec::new();
+        for backend in self.all() {
+            clients.push(backend.prepare_reload(config).await?);
+        }
+        for (backend, client) in self.all().zip(clients) {
+            backend.swap(client);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,655 @@
+use std::{collections::HashSet, sync::RwLock, time::Duration};
+
+use async_trait::async_trait;
+use aws_sdk_s3::{
+    self as s3,
+    error::ProvideErrorMetadata,
+    presigning::PresigningConfig,
+    primitives::ByteStream,
+    types::{
+        BucketLocationConstraint, ChecksumMode, CompletedMultipartUpload, CompletedPart, CreateBucketConfiguration,
+        GlacierJobParameters, RestoreRequest, ServerSideEncryption, StorageClass as S3StorageClass, Tier,
+    },
+    config::http::HttpResponse,
+    Client,
+};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use tracing::{info, warn};
+
+use crate::{
+    config::AppConfig,
+    retry::RetryPolicy,
+    storage::{
+        read_part, retry::with_retries, s3_client, sha256_base64, ByteChunks, ObjectBody, ObjectInfo, RestoreState, StorageBackend,
+        StorageClass, StorageError,
+    },
+    MULTIPART_PART_SIZE,
+};
+
+pub(crate) struct S3Backend {
+    /// Swapped for a new one by `reload`.
+    pub(crate) client: RwLock<Client>,
+    pub(crate) bucket: String,
+    /// The bucket's region, when it isn't the one the SDK resolves.
+    pub(crate) region: Option<String>,
+    /// When set, every object is written with SSE-KMS under this key.
+    pub(crate) sse_kms_key_id: Option<String>,
+    /// Applied to the parts of streamed uploads, which the retrying wrapper can't replay.
+    pub(crate) retry: RetryPolicy,
+}
+
+/// Renders an S3 error as `Code: message` when the service returned one, so
+/// failures such as `KMS.KeyDisabled` or `AccessDenied` are readable, and tells
+/// timeouts and passing failures apart from the rest.
+fn describe_s3_error<E>(err: &s3::error::SdkError<E, HttpResponse>) -> StorageError
+where
+    E: s3::error::ProvideErrorMetadata + std::error::Error + 'static,
+{
+    let timed_out = match err {
+        s3::error::SdkError::TimeoutError(_) => true,
+        s3::error::SdkError::DispatchFailure(e) => e.is_timeout(),
+        _ => false,
+    };
+    if timed_out {
+        return StorageError::TimedOut(s3::error::DisplayErrorContext(err).to_string());
+    }
+
+    let description = match (err.code(), err.message()) {
+        (Some(code), Some(message)) => format!("{}: {}", code, message),
+        (Some(code), None) => code.to_string(),
+        // Answers to HEAD requests have no body to carry a code in.
+        _ => match err {
+            s3::error::SdkError::ServiceError(e) => format!("HTTP {}", e.raw().status().as_u16()),
+            _ => s3::error::DisplayErrorContext(err).to_string(),
+        },
+    };
+    if is_unavailable(err) {
+        StorageError::Unavailable(description)
+    } else {
+        StorageError::Failed(description)
+    }
+}
+
+/// Whether S3 failed in a way that may pass: it was unreachable, answered with a
+/// 5xx or asked to slow down.
+fn is_unavailable<E: ProvideErrorMetadata>(err: &s3::error::SdkError<E, HttpResponse>) -> bool {
+    match err {
+        s3::error::SdkError::DispatchFailure(e) => e.is_io() || e.is_other(),
+        s3::error::SdkError::ResponseError(_) => true,
+        s3::error::SdkError::ServiceError(e) => {
+            e.raw().status().is_server_error()
+                || e.raw().status().as_u16() == 429
+                || matches!(err.code(), Some("SlowDown" | "Throttling" | "ThrottlingException" | "RequestTimeout"))
+        }
+        _ => false,
+    }
+}
+
+impl S3Backend {
+    /// Uploads `first` and the rest of `chunks` as parts of `upload_id`, then completes it.
+    async fn upload_parts(
+        &self,
+        key: &str,
+        upload_id: &str,
+        first: Vec<u8>,
+        chunks: &mut ByteChunks<'_>,
+    ) -> Result<Option<String>, StorageError> {
+        let mut parts = Vec::new();
+        let mut part = first;
+        let mut last = false;
+
+        for part_number in 1.. {
+            let etag = with_retries(&self.retry, "upload_part", || {
+                self.upload_part(key, upload_id, part_number, part.clone())
+            })
+            .await?;
+            parts.push((part_number, etag));
+
+            if last {
+                break;
+            }
+            let (next, eof) = read_part(chunks, MULTIPART_PART_SIZE).await?;
+            if next.is_empty() {
+                break;
+            }
+            part = next;
+            last = eof;
+        }
+
+        with_retries(&self.retry, "complete_multipart", || self.complete_multipart(key, upload_id, &parts)).await
+    }
+}
+
+impl S3Backend {
+    /// The client calls are made with. Clones share their connections.
+    fn client(&self) -> Client {
+        self.client.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Builds a client with the credentials `config` names and checks the bucket
+    /// answers to it, leaving the one in use alone until `swap`.
+    pub(crate) async fn prepare_reload(&self, config: &AppConfig) -> Result<Client, String> {
+        let client = s3_client(config, self.region.as_deref()).await?;
+        check_bucket(&client, &self.bucket)
+            .await
+            .map_err(|e| format!("bucket {} refused the new credentials: {}", self.bucket, e))?;
+        Ok(client)
+    }
+
+    /// Puts a client from `prepare_reload` in place of the one in use.
+    pub(crate) fn swap(&self, client: Client) {
+        *self.client.write().unwrap_or_else(|e| e.into_inner()) = client;
+        info!("Reloaded the S3 client of bucket {}", self.bucket);
+    }
+
+    /// Creates the bucket unless it already exists, in the client's region. Only
+    /// regions other than `us-east-1` take a location constraint.
+    pub(crate) async fn ensure_bucket(&self) -> Result<(), StorageError> {
+        let client = self.client();
+        match client.head_bucket().bucket(&self.bucket).send().await {
+            Ok(_) => return Ok(()),
+            Err(e) if e.as_service_error().is_some_and(|e| e.is_not_found()) => {}
+            Err(e) => return Err(describe_s3_error(&e)),
+        }
+
+        let location = client
+            .config()
+            .region()
+            .map(|region| region.as_ref())
+            .filter(|region| *region != "us-east-1")
+            .map(|region| CreateBucketConfiguration::builder().location_constraint(BucketLocationConstraint::from(region)).build());
+        match client
+            .create_bucket()
+            .bucket(&self.bucket)
+            .set_create_bucket_configuration(location)
+            .send()
+            .await
+        {
+            Ok(_) => {
+                info!("Created S3 bucket {}", self.bucket);
+                Ok(())
+            }
+            // Another server starting at the same time got there first.
+            Err(e) if e.as_service_error().is_some_and(|e| e.is_bucket_already_owned_by_you()) => Ok(()),
+            Err(e) => Err(describe_s3_error(&e)),
+        }
+    }
+
+    /// Size and content type of the object at `key`.
+    pub(crate) async fn head(&self, key: &str) -> Result<(i64, Option<String>), StorageError> {
+        let head = self.client()
+            .head_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .send()
+            .await
+            .map_err(|e| describe_s3_error(&e))?;
+
+        Ok((head.content_length().unwrap_or_default(), head.content_type().map(str::to_string)))
+    }
+
+    /// Copies `key` from `bucket`, another bucket of the same service, to the same
+    /// key here without the bytes leaving S3. Objects over 5 GiB can't be copied
+    /// this way.
+    pub(crate) async fn copy_from_bucket(&self, bucket: &str, key: &str) -> Result<(), StorageError> {
+        self.client()
+            .copy_object()
+            .bucket(&self.bucket)
+            .copy_source(format!("{}/{}", bucket, urlencoding::encode(key)))
+            .key(key)
+            .set_server_side_encryption(self.sse_kms_key_id.as_ref().map(|_| ServerSideEncryption::AwsKms))
+            .set_ssekms_key_id(self.sse_kms_key_id.clone())
+            .send()
+            .await
+            .map(|_| ())
+            .map_err(|e| describe_s3_error(&e))
+    }
+}
+
+/// Whether `bucket` can be reached with `client`.
+async fn check_bucket(client: &Client, bucket: &str) -> Result<(), StorageError> {
+    client
+        .head_bucket()
+        .bucket(bucket)
+        .send()
+        .await
+        .map(|_| ())
+        .map_err(|e| describe_s3_error(&e))
+}
+
+/// Aborts a multipart upload on drop unless disarmed first.
+struct AbortOnDrop {
+    client: Client,
+    bucket: String,
+    key: String,
+    upload_id: Option<String>,
+}
+
+impl AbortOnDrop {
+    fn disarm(mut self) {
+        self.upload_id = None;
+    }
+}
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        let (Some(upload_id), Ok(runtime)) = (self.upload_id.take(), tokio::runtime::Handle::try_current()) else { return };
+        let (client, bucket, key) = (self.client.clone(), std::mem::take(&mut self.bucket), std::mem::take(&mut self.key));
+        runtime.spawn(async move {
+            if let Err(e) = client.abort_multipart_upload().bucket(bucket).key(&key).upload_id(upload_id).send().await {
+                warn!("Failed to abort dropped multipart upload for {}: {}", key, describe_s3_error(&e));
+            }
+        });
+    }
+}
+
+#[async_trait]
+impl StorageBackend for S3Backend {
+    async fn put(&self, key: &str, data: Vec<u8>, content_type: &str) -> Result<Option<String>, StorageError> {
+        self.client()
+            .put_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .body(ByteStream::from(data))
+            .content_type(content_type)
+            .set_server_side_encryption(self.sse_kms_key_id.as_ref().map(|_| ServerSideEncryption::AwsKms))
+            .set_ssekms_key_id(self.sse_kms_key_id.clone())
+            .send()
+            .await
+            .map(|out| out.e_tag().map(|tag| tag.trim_matches('"').to_string()))
+            .map_err(|e| describe_s3_error(&e))
+    }
+
+    async fn put_stream(
+        &self,
+        key: &str,
+        content_type: &str,
+        chunks: &mut ByteChunks<'_>,
+    ) -> Result<Option<String>, StorageError> {
+        // Bodies that fit in a single part skip the multipart round trips entirely.
+        let (first, eof) = read_part(chunks, MULTIPART_PART_SIZE).await?;
+        if eof {
+            return with_retries(&self.retry, "put", || self.put(key, first.clone(), content_type)).await;
+        }
+
+        let upload_id = with_retries(&self.retry, "create_multipart", || self.create_multipart(key, content_type)).await?;
+
+        // Requests whose future is dropped mid-upload never reach the abort below.
+        let guard = AbortOnDrop { client: self.client(), bucket: self.bucket.clone(), key: key.to_string(), upload_id: Some(upload_id.clone()) };
+        let result = self.upload_parts(key, &upload_id, first, chunks).await;
+        guard.disarm();
+        if result.is_err() {
+            // Abandoned parts are billed until aborted.
+            if let Err(e) = with_retries(&self.retry, "abort_multipart", || self.abort_multipart(key, &upload_id)).await {
+                warn!("Failed to abort multipart upload for {}: {}", key, e);
+            }
+        }
+        result
+    }
+
+    async fn get(&self, key: &str) -> Result<Vec<u8>, StorageError> {
+        let object = self.client()
+            .get_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .send()
+            .await
+            .map_err(|e| describe_s3_error(&e))?;
+
+        object.body
+            .collect()
+            .await
+            .map(|data| data.into_bytes().to_vec())
+            .map_err(|e| e.to_string().into())
+    }
+
+    async fn get_range(&self, key: &str, start: u64, len: u64) -> Result<ObjectBody, StorageError> {
+        let object = self.client()
+            .get_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .range(format!("bytes={}-{}", start, start + len - 1))
+            .send()
+            .await
+            .map_err(|e| describe_s3_error(&e))?;
+
+        let body = futures::stream::unfold(object.body, |mut body| async move {
+            body.next()
+                .await
+                .map(|chunk| (chunk.map_err(|e| e.to_string()), body))
+        });
+        Ok(Box::pin(body))
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), StorageError> {
+        self.client()
+            .delete_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .send()
+            .await
+            .map(|_| ())
+            .map_err(|e| describe_s3_error(&e))
+    }
+
+    async fn copy(&self, from: &str, to: &str) -> Result<(), StorageError> {
+        self.client()
+            .copy_object()
+            .bucket(&self.bucket)
+            .copy_source(format!("{}/{}", self.bucket, urlencoding::encode(from)))
+            .key(to)
+            .set_server_side_encryption(self.sse_kms_key_id.as_ref().map(|_| ServerSideEncryption::AwsKms))
+            .set_ssekms_key_id(self.sse_kms_key_id.clone())
+            .send()
+            .await
+            .map(|_| ())
+            .map_err(|e| describe_s3_error(&e))
+    }
+
+    async fn size(&self, key: &str) -> Result<i64, StorageError> {
+        self.head(key).await.map(|(size, _)| size)
+    }
+
+    async fn presign_download(
+        &self,
+        key: &str,
+        content_type: Option<String>,
+        expires_in: Duration,
+    ) -> Result<String, StorageError> {
+        let config = PresigningConfig::expires_in(expires_in).map_err(|e| e.to_string())?;
+        self.client()
+            .get_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .set_response_content_type(content_type)
+            .presigned(config)
+            .await
+            .map(|req| req.uri().to_string())
+            .map_err(|e| e.to_string().into())
+    }
+
+    async fn presign_upload(
+        &self,
+        key: &str,
+        size: i64,
+        content_type: &str,
+        sha256: Option<&str>,
+        expires_in: Duration,
+    ) -> Result<String, StorageError> {
+        let config = PresigningConfig::expires_in(expires_in).map_err(|e| e.to_string())?;
+        self.client()
+            .put_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .content_length(size)
+            .content_type(content_type)
+            .set_server_side_encryption(self.sse_kms_key_id.as_ref().map(|_| ServerSideEncryption::AwsKms))
+            .set_ssekms_key_id(self.sse_kms_key_id.clone())
+            .set_checksum_sha256(sha256.and_then(sha256_base64))
+            .presigned(config)
+            .await
+            .map(|req| req.uri().to_string())
+            .map_err(|e| e.to_string().into())
+    }
+
+    fn upload_headers(&self, sha256: Option<&str>) -> Vec<(&'static str, String)> {
+        let mut headers = Vec::new();
+        if let Some(key_id) = &self.sse_kms_key_id {
+            headers.push(("x-amz-server-side-encryption", "aws:kms".to_string()));
+            headers.push(("x-amz-server-side-encryption-aws-kms-key-id", key_id.clone()));
+        }
+        if let Some(checksum) = sha256.and_then(sha256_base64) {
+            headers.push(("x-amz-checksum-sha256", checksum));
+        }
+        headers
+    }
+
+    async fn sha256(&self, key: &str) -> Result<Option<String>, StorageError> {
+        let head = self.client()
+            .head_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .checksum_mode(ChecksumMode::Enabled)
+            .send()
+            .await
+            .map_err(|e| describe_s3_error(&e))?;
+
+        // Multipart objects carry a checksum of their part checksums ("...-N"), which
+        // isn't the object's SHA-256, so only a plain checksum is reported.
+        Ok(head
+            .checksum_sha256()
+            .filter(|checksum| !checksum.contains('-'))
+            .and_then(|checksum| BASE64.decode(checksum).ok())
+            .map(hex::encode))
+    }
+
+    /// Copies the object onto itself in the new class. S3 refuses to copy a glacial
+    /// object until it's restored, and objects over 5 GiB altogether.
+    async fn set_storage_class(&self, key: &str, class: StorageClass) -> Result<(), StorageError> {
+        let head = self.client()
+            .head_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .send()
+            .await
+            .map_err(|e| describe_s3_error(&e))?;
+        // Only objects outside STANDARD report a class.
+        let current = head.storage_class().map_or("STANDARD", |class| class.as_str());
+        if current == class.as_str() {
+            return Ok(());
+        }
+
+        self.client()
+            .copy_object()
+            .bucket(&self.bucket)
+            .copy_source(format!("{}/{}", self.bucket, urlencoding::encode(key)))
+            .key(key)
+            .storage_class(S3StorageClass::from(class.as_str()))
+            .set_server_side_encryption(self.sse_kms_key_id.as_ref().map(|_| ServerSideEncryption::AwsKms))
+            .set_ssekms_key_id(self.sse_kms_key_id.clone())
+            .send()
+            .await
+            .map(|_| ())
+            .map_err(|e| describe_s3_error(&e))
+    }
+
+    async fn restore(&self, key: &str, days: i32) -> Result<RestoreState, StorageError> {
+        let head = self.client()
+            .head_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .send()
+            .await
+            .map_err(|e| describe_s3_error(&e))?;
+        if !matches!(head.storage_class(), Some(S3StorageClass::Glacier | S3StorageClass::DeepArchive)) {
+            return Ok(RestoreState::Readable);
+        }
+        // `ongoing-request="false", expiry-date="Fri, 21 Dec 2012 00:00:00 GMT"`
+        // once a restore finished.
+        if let Some(restore) = head.restore() {
+            if restore.contains(r#"ongoing-request="true""#) {
+                return Ok(RestoreState::InProgress);
+            }
+            let expires_at = restore
+                .split_once(r#"expiry-date=""#)
+                .and_then(|(_, rest)| rest.split('"').next())
+                .and_then(|date| chrono::DateTime::parse_from_rfc2822(date).ok())
+                .map(|date| date.timestamp());
+            return Ok(RestoreState::Restored { expires_at });
+        }
+
+        let job = GlacierJobParameters::builder().tier(Tier::Standard).build().map_err(|e| e.to_string())?;
+        match self
+            .client()
+            .restore_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .restore_request(RestoreRequest::builder().days(days).glacier_job_parameters(job).build())
+            .send()
+            .await
+        {
+            Ok(_) => Ok(RestoreState::InProgress),
+            // Someone else asked for one since the HEAD above.
+            Err(e) if e.code() == Some("RestoreAlreadyInProgress") => Ok(RestoreState::InProgress),
+            Err(e) => Err(describe_s3_error(&e)),
+        }
+    }
+
+    async fn check(&self) -> Result<(), StorageError> {
+        check_bucket(&self.client(), &self.bucket).await
+    }
+
+    /// Only swaps the client in once the bucket answers to the new one, so a
+    /// mistyped role or key leaves the server working as it was.
+    async fn reload(&self, config: &AppConfig) -> Result<(), StorageError> {
+        let client = self.prepare_reload(config).await?;
+        self.swap(client);
+        Ok(())
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<ObjectInfo>, StorageError> {
+        let mut objects = Vec::new();
+        let mut continuation: Option<String> = None;
+
+        loop {
+            let page = self.client()
+                .list_objects_v2()
+                .bucket(&self.bucket)
+                .prefix(prefix)
+                .set_continuation_token(continuation.take())
+                .send()
+                .await
+                .map_err(|e| describe_s3_error(&e))?;
+
+            objects.extend(page.contents().iter().filter_map(|object| {
+                Some(ObjectInfo {
+                    key: object.key()?.to_string(),
+                    modified: object.last_modified().map(|t| t.secs()),
+                })
+            }));
+
+            match page.next_continuation_token() {
+                Some(token) => continuation = Some(token.to_string()),
+                None => break,
+            }
+        }
+
+        Ok(objects)
+    }
+
+    async fn create_multipart(&self, key: &str, content_type: &str) -> Result<String, StorageError> {
+        let created = self.client()
+            .create_multipart_upload()
+            .bucket(&self.bucket)
+            .key(key)
+            .content_type(content_type)
+            .set_server_side_encryption(self.sse_kms_key_id.as_ref().map(|_| ServerSideEncryption::AwsKms))
+            .set_ssekms_key_id(self.sse_kms_key_id.clone())
+            .send()
+            .await
+            .map_err(|e| describe_s3_error(&e))?;
+
+        created
+            .upload_id()
+            .map(String::from)
+            .ok_or_else(|| "multipart upload was created without an id".into())
+    }
+
+    async fn upload_part(
+        &self,
+        key: &str,
+        upload_id: &str,
+        part_number: i32,
+        data: Vec<u8>,
+    ) -> Result<String, StorageError> {
+        let uploaded = self.client()
+            .upload_part()
+            .bucket(&self.bucket)
+            .key(key)
+            .upload_id(upload_id)
+            .part_number(part_number)
+            .body(ByteStream::from(data))
+            .send()
+            .await
+            .map_err(|e| describe_s3_error(&e))?;
+
+        uploaded
+            .e_tag()
+            .map(String::from)
+            .ok_or_else(|| format!("part {} was stored without an ETag", part_number).into())
+    }
+
+    async fn complete_multipart(
+        &self,
+        key: &str,
+        upload_id: &str,
+        parts: &[(i32, String)],
+    ) -> Result<Option<String>, StorageError> {
+        let parts = parts
+            .iter()
+            .map(|(part_number, etag)| {
+                CompletedPart::builder()
+                    .part_number(*part_number)
+                    .e_tag(etag)
+                    .build()
+            })
+            .collect();
+
+        self.client()
+            .complete_multipart_upload()
+            .bucket(&self.bucket)
+            .key(key)
+            .upload_id(upload_id)
+            .multipart_upload(CompletedMultipartUpload::builder().set_parts(Some(parts)).build())
+            .send()
+            .await
+            .map(|out| out.e_tag().map(|tag| tag.trim_matches('"').to_string()))
+            .map_err(|e| describe_s3_error(&e))
+    }
+
+    async fn abort_multipart(&self, key: &str, upload_id: &str) -> Result<(), StorageError> {
+        self.client()
+            .abort_multipart_upload()
+            .bucket(&self.bucket)
+            .key(key)
+            .upload_id(upload_id)
+            .send()
+            .await
+            .map(|_| ())
+            .map_err(|e| describe_s3_error(&e))
+    }
+
+    async fn abort_stale_multipart(&self, started_before: i64, keep: &HashSet<String>) -> Result<usize, StorageError> {
+        let mut aborted = 0;
+        let mut key_marker = None;
+        let mut upload_id_marker = None;
+        loop {
+            let page = self.client()
+                .list_multipart_uploads()
+                .bucket(&self.bucket)
+                .set_key_marker(key_marker)
+                .set_upload_id_marker(upload_id_marker)
+                .send()
+                .await
+                .map_err(|e| describe_s3_error(&e))?;
+
+            for upload in page.uploads() {
+                let (Some(key), Some(upload_id)) = (upload.key(), upload.upload_id()) else { continue };
+                let stale = upload.initiated().is_some_and(|at| at.secs() < started_before);
+                if !stale || keep.contains(upload_id) {
+                    continue;
+                }
+                match self.abort_multipart(key, upload_id).await {
+                    Ok(()) => aborted += 1,
+                    Err(e) => warn!("Failed to abort stale multipart upload for {}: {}", key, e),
+                }
+            }
+
+            if !page.is_truncated().unwrap_or(false) {
+                return Ok(aborted);
+            }
+            key_marker = page.next_key_marker().map(str::to_string);
+            upload_id_marker = page.next_upload_id_marker().map(str::to_string);
+        }
+    }
+}
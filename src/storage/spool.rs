@@ -0,0 +1,103 @@
+//! Request bodies buffered before they're stored: in memory while small, and in a
+//! temp file once they pass a threshold, so a large upload never sits in memory
+//! whole. The temp file is deleted once the spooled body is dropped.
+
+use std::path::{Path, PathBuf};
+
+use axum::body::Bytes;
+use futures::StreamExt;
+use tokio::io::AsyncWriteExt;
+use tokio_util::io::ReaderStream;
+use tracing::warn;
+
+use crate::storage::{ByteChunks, ObjectBody};
+
+/// Bytes read from the temp file per chunk when streaming it back out.
+const SPOOL_READ_CHUNK_BYTES: usize = 64 * 1024;
+
+pub(crate) enum Spooled {
+    Memory(Vec<u8>),
+    File(SpoolFile),
+}
+
+/// A temp file holding a spooled body, removed when dropped.
+pub(crate) struct SpoolFile {
+    path: PathBuf,
+    len: u64,
+}
+
+impl Drop for SpoolFile {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.path)
+            && e.kind() != std::io::ErrorKind::NotFound
+        {
+            warn!("Failed to remove spool file {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+impl Spooled {
+    pub(crate) fn len(&self) -> u64 {
+        match self {
+            Spooled::Memory(data) => data.len() as u64,
+            Spooled::File(file) => file.len,
+        }
+    }
+}
+
+impl SpoolFile {
+    /// Streams the spooled bytes back from disk.
+    pub(crate) async fn chunks(&self) -> Result<ObjectBody, String> {
+        let reader = tokio::fs::File::open(&self.path).await.map_err(|e| e.to_string())?;
+        Ok(ReaderStream::with_capacity(reader, SPOOL_READ_CHUNK_BYTES)
+            .map(|chunk| chunk.map_err(|e| e.to_string()))
+            .boxed())
+    }
+}
+
+/// Reads `chunks` to the end, moving to a temp file in `dir` once more than
+/// `threshold` bytes have arrived. Stops with an error once more than `limit`
+/// bytes have, without reading the rest.
+pub(crate) async fn spool(
+    chunks: &mut ByteChunks<'_>,
+    threshold: usize,
+    limit: u64,
+    dir: &Path,
+) -> Result<Spooled, String> {
+    let mut buffer = Vec::new();
+    let mut received: u64 = 0;
+    let mut spilled: Option<(tokio::fs::File, SpoolFile)> = None;
+
+    while let Some(chunk) = chunks.next().await {
+        let chunk: Bytes = chunk?;
+        received += chunk.len() as u64;
+        if received > limit {
+            return Err(format!("body is larger than {} bytes", limit));
+        }
+
+        match &mut spilled {
+            Some((file, _)) => file.write_all(&chunk).await.map_err(|e| e.to_string())?,
+            None if buffer.len() + chunk.len() > threshold => {
+                tokio::fs::create_dir_all(dir).await.map_err(|e| e.to_string())?;
+                let path = dir.join(format!("pocket-spool-{}", hex::encode(rand::random::<[u8; 16]>())));
+                let mut file = tokio::fs::File::create(&path).await.map_err(|e| e.to_string())?;
+                // Owned from here, so the file goes however reading ends.
+                let spool = SpoolFile { path, len: 0 };
+                file.write_all(&buffer).await.map_err(|e| e.to_string())?;
+                file.write_all(&chunk).await.map_err(|e| e.to_string())?;
+                buffer = Vec::new();
+                spilled = Some((file, spool));
+            }
+            None => buffer.extend_from_slice(&chunk),
+        }
+    }
+
+    match spilled {
+        Some((mut file, mut spool)) => {
+            file.flush().await.map_err(|e| e.to_string())?;
+            spool.len = received;
+            Ok(Spooled::File(spool))
+        }
+        None => Ok(Spooled::Memory(buffer)),
+    }
+}
@@ -0,0 +1,520 @@
+//! Certificates from an ACME CA (RFC 8555) such as Let's Encrypt, validated with
+//! HTTP-01 challenges answered on `acme_http_port`. The account key and the issued
+//! certificate are kept in `acme_cache_dir`, so restarts don't order new ones.
+
+use std::{
+    collections::HashMap,
+    path::Path,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use axum::{
+    extract::{Path as UrlPath, State},
+    http::{header, HeaderMap, StatusCode, Uri},
+    response::{IntoResponse, Redirect, Response},
+    routing::get,
+    Router,
+};
+use base64::{engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD}, Engine};
+use ring::{
+    digest::{digest, SHA256},
+    rand::SystemRandom,
+    signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_ASN1_SIGNING, ECDSA_P256_SHA256_FIXED_SIGNING},
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio_rustls::rustls::pki_types::{pem::PemObject, CertificateDer};
+use tracing::{info, warn, Instrument};
+
+use super::{certified_key, Certificates};
+use crate::{config::TlsConfig, ACME_CHECK_INTERVAL, ACME_RENEW_BEFORE_DAYS};
+
+const ACCOUNT_KEY_FILE: &str = "account.pk8";
+const CERT_FILE: &str = "cert.pem";
+const KEY_FILE: &str = "key.pem";
+
+/// Times an authorization or order is polled, two seconds apart, before giving up.
+const POLL_ATTEMPTS: u32 = 30;
+
+/// Shortest wait before retrying a failed issuance; doubles up to `ACME_CHECK_INTERVAL`.
+const RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// Key authorizations of the pending HTTP-01 challenges, by token.
+type Challenges = Arc<RwLock<HashMap<String, String>>>;
+
+/// Answers challenges and serves the cached certificate, then keeps it renewed in
+/// the background. Connections are refused a certificate until the first one is issued.
+pub(super) async fn start(config: TlsConfig, https_port: u16, certificates: Arc<Certificates>) {
+    tokio::fs::create_dir_all(&config.acme_cache_dir)
+        .await
+        .unwrap_or_else(|e| panic!("Failed to create ACME cache dir {}: {}", config.acme_cache_dir.display(), e));
+
+    let challenges = Challenges::default();
+    let http = tokio::net::TcpListener::bind(("0.0.0.0", config.acme_http_port))
+        .await
+        .unwrap_or_else(|e| panic!("Failed to bind the ACME challenge port {}: {}", config.acme_http_port, e));
+    let routes = Router::new()
+        .route("/.well-known/acme-challenge/{token}", get(answer_challenge))
+        .with_state(challenges.clone())
+        .fallback(move |headers: HeaderMap, uri: Uri| async move { redirect_to_https(&headers, &uri, https_port) });
+    tokio::spawn(async move {
+        if let Err(e) = axum::serve(http, routes).await {
+            warn!("ACME challenge server stopped: {}", e);
+        }
+    });
+
+    let expires = match load_cached(&config.acme_cache_dir).await {
+        Some((key, expires)) => {
+            certificates.set(key);
+            info!("Loaded cached ACME certificate for {}", config.acme_domains.join(", "));
+            Some(expires)
+        }
+        None => None,
+    };
+    tokio::spawn(keep_renewed(config, certificates, challenges, expires).in_current_span());
+}
+
+async fn answer_challenge(State(challenges): State<Challenges>, UrlPath(token): UrlPath<String>) -> Response {
+    match challenges.read().unwrap_or_else(|e| e.into_inner()).get(&token) {
+        Some(key_authorization) => key_authorization.clone().into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+fn redirect_to_https(headers: &HeaderMap, uri: &Uri, https_port: u16) -> Response {
+    let Some(host) = headers.get(header::HOST).and_then(|v| v.to_str().ok()) else {
+        return StatusCode::BAD_REQUEST.into_response();
+    };
+    let host = host.split(':').next().unwrap_or(host);
+    let port = if https_port == 443 { String::new() } else { format!(":{}", https_port) };
+    let path = uri.path_and_query().map_or("/", |p| p.as_str());
+    Redirect::permanent(&format!("https://{}{}{}", host, port, path)).into_response()
+}
+
+/// Renews the certificate once it has fewer than `ACME_RENEW_BEFORE_DAYS` left,
+/// checking every `ACME_CHECK_INTERVAL`.
+async fn keep_renewed(
+    config: TlsConfig,
+    certificates: Arc<Certificates>,
+    challenges: Challenges,
+    mut expires: Option<i64>,
+) {
+    let mut retry = RETRY_DELAY;
+    loop {
+        let renew_at = expires.map_or(0, |expires| expires - ACME_RENEW_BEFORE_DAYS * 86_400);
+        if chrono::Utc::now().timestamp() < renew_at {
+            tokio::time::sleep(ACME_CHECK_INTERVAL).await;
+            continue;
+        }
+
+        info!("Ordering an ACME certificate for {}", config.acme_domains.join(", "));
+        match issue(&config, &challenges).await {
+            Ok((chain, key)) => match certified_key(chain.as_bytes(), key.as_bytes()) {
+                Ok(certified) => {
+                    certificates.set(certified);
+                    expires = first_not_after(chain.as_bytes());
+                    retry = RETRY_DELAY;
+                    info!("Installed a new ACME certificate for {}", config.acme_domains.join(", "));
+                    continue;
+                }
+                Err(e) => warn!("The CA issued an unusable certificate: {}", e),
+            },
+            Err(e) => warn!("Failed to obtain an ACME certificate: {}", e),
+        }
+        tokio::time::sleep(retry).await;
+        retry = (retry * 2).min(ACME_CHECK_INTERVAL);
+    }
+}
+
+/// The cached certificate and its expiry, if one was issued before.
+async fn load_cached(dir: &Path) -> Option<(tokio_rustls::rustls::sign::CertifiedKey, i64)> {
+    let chain = tokio::fs::read(dir.join(CERT_FILE)).await.ok()?;
+    let key = tokio::fs::read(dir.join(KEY_FILE)).await.ok()?;
+    let expires = first_not_after(&chain)?;
+    match certified_key(&chain, &key) {
+        Ok(certified) => Some((certified, expires)),
+        Err(e) => {
+            warn!("Ignoring the cached ACME certificate: {}", e);
+            None
+        }
+    }
+}
+
+/// Orders, validates and downloads a certificate for every configured domain,
+/// returning its PEM chain and key, both also written to the cache.
+async fn issue(config: &TlsConfig, challenges: &Challenges) -> Result<(String, String), String> {
+    let mut client = Client::new(config).await?;
+    client.register(config.acme_email.as_deref()).await?;
+
+    let identifiers: Vec<Value> = config
+        .acme_domains
+        .iter()
+        .map(|domain| json!({ "type": "dns", "value": domain }))
+        .collect();
+    let res = client.post(&client.directory.new_order.clone(), Some(json!({ "identifiers": identifiers })))
+        .await?;
+    let order_url = location(&res)?;
+    let order: Order = res.json().await.map_err(|e| e.to_string())?;
+
+    let mut tokens = Vec::new();
+    let validated = client.authorize(&order.authorizations, challenges, &mut tokens).await;
+    {
+        let mut pending = challenges.write().unwrap_or_else(|e| e.into_inner());
+        for token in &tokens {
+            pending.remove(token);
+        }
+    }
+    validated?;
+    client.poll(&order_url, "ready").await?;
+
+    let rng = SystemRandom::new();
+    let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &rng)
+        .map_err(|_| "failed to generate a certificate key")?;
+    let key = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, pkcs8.as_ref(), &rng)
+        .map_err(|_| "failed to load the certificate key")?;
+    let csr = certificate_request(&key, &config.acme_domains)?;
+    client
+        .post(&order.finalize, Some(json!({ "csr": URL_SAFE_NO_PAD.encode(csr) })))
+        .await?;
+    let order: Order = serde_json::from_value(client.poll(&order_url, "valid").await?).map_err(|e| e.to_string())?;
+    let certificate_url = order.certificate.ok_or("the valid order has no certificate")?;
+    let chain = client.post(&certificate_url, None).await?.text().await.map_err(|e| e.to_string())?;
+
+    let key = pem("PRIVATE KEY", pkcs8.as_ref());
+    write_private(&config.acme_cache_dir.join(KEY_FILE), key.as_bytes()).await?;
+    tokio::fs::write(config.acme_cache_dir.join(CERT_FILE), &chain).await.map_err(|e| e.to_string())?;
+    Ok((chain, key))
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Directory {
+    new_nonce: String,
+    new_account: String,
+    new_order: String,
+}
+
+#[derive(Deserialize)]
+struct Order {
+    #[serde(default)]
+    authorizations: Vec<String>,
+    finalize: String,
+    certificate: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Authorization {
+    status: String,
+    challenges: Vec<Challenge>,
+}
+
+#[derive(Deserialize)]
+struct Challenge {
+    #[serde(rename = "type")]
+    kind: String,
+    url: String,
+    token: Option<String>,
+}
+
+/// One session with the CA: the account key, the account URL once registered, and
+/// the nonce for the next request.
+struct Client {
+    http: reqwest::Client,
+    directory: Directory,
+    key: EcdsaKeyPair,
+    jwk: Value,
+    account: Option<String>,
+    nonce: Option<String>,
+}
+
+impl Client {
+    async fn new(config: &TlsConfig) -> Result<Self, String> {
+        let http = reqwest::Client::builder()
+            .timeout(Duration::from_secs(30))
+            .build()
+            .map_err(|e| e.to_string())?;
+        let directory = http
+            .get(&config.acme_directory_url)
+            .send()
+            .await
+            .and_then(|res| res.error_for_status())
+            .map_err(|e| format!("failed to fetch the ACME directory: {}", e))?
+            .json()
+            .await
+            .map_err(|e| format!("invalid ACME directory: {}", e))?;
+
+        let rng = SystemRandom::new();
+        let path = config.acme_cache_dir.join(ACCOUNT_KEY_FILE);
+        let pkcs8 = match tokio::fs::read(&path).await {
+            Ok(pkcs8) => pkcs8,
+            Err(_) => {
+                let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng)
+                    .map_err(|_| "failed to generate an account key")?;
+                write_private(&path, pkcs8.as_ref()).await?;
+                pkcs8.as_ref().to_vec()
+            }
+        };
+        let key = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &pkcs8, &rng)
+            .map_err(|_| format!("invalid account key {}", path.display()))?;
+
+        // The public key is the uncompressed point: 0x04, then x and y.
+        let point = key.public_key().as_ref();
+        let jwk = json!({
+            "crv": "P-256",
+            "kty": "EC",
+            "x": URL_SAFE_NO_PAD.encode(&point[1..33]),
+            "y": URL_SAFE_NO_PAD.encode(&point[33..65]),
+        });
+        Ok(Self { http, directory, key, jwk, account: None, nonce: None })
+    }
+
+    /// Finds or creates the account of the key, agreeing to the CA's terms.
+    async fn register(&mut self, email: Option<&str>) -> Result<(), String> {
+        let mut account = json!({ "termsOfServiceAgreed": true });
+        if let Some(email) = email {
+            account["contact"] = json!([format!("mailto:{}", email)]);
+        }
+        let res = self.post(&self.directory.new_account.clone(), Some(account)).await?;
+        self.account = Some(location(&res)?);
+        Ok(())
+    }
+
+    /// Answers the HTTP-01 challenge of every pending authorization and waits for
+    /// them to be valid. The tokens put up are pushed onto `tokens`.
+    async fn authorize(
+        &mut self,
+        authorizations: &[String],
+        challenges: &Challenges,
+        tokens: &mut Vec<String>,
+    ) -> Result<(), String> {
+        for url in authorizations {
+            let authorization: Authorization = self.post(url, None).await?.json().await.map_err(|e| e.to_string())?;
+            if authorization.status == "valid" {
+                continue;
+            }
+            let challenge = authorization
+                .challenges
+                .iter()
+                .find(|challenge| challenge.kind == "http-01")
+                .ok_or("the CA offered no HTTP-01 challenge")?;
+            let token = challenge.token.clone().ok_or("the HTTP-01 challenge has no token")?;
+
+            let key_authorization = format!("{}.{}", token, self.thumbprint());
+            challenges
+                .write()
+                .unwrap_or_else(|e| e.into_inner())
+                .insert(token.clone(), key_authorization);
+            tokens.push(token);
+
+            self.post(&challenge.url, Some(json!({}))).await?;
+            self.poll(url, "valid").await?;
+        }
+        Ok(())
+    }
+
+    /// Polls an authorization or order until it reaches `status`, returning it then.
+    async fn poll(&mut self, url: &str, status: &str) -> Result<Value, String> {
+        for _ in 0..POLL_ATTEMPTS {
+            let object: Value = self.post(url, None).await?.json().await.map_err(|e| e.to_string())?;
+            match object["status"].as_str() {
+                Some(s) if s == status => return Ok(object),
+                Some("invalid") => return Err(format!("{} became invalid: {}", url, object)),
+                _ => tokio::time::sleep(Duration::from_secs(2)).await,
+            }
+        }
+        Err(format!("{} didn't become {} in time", url, status))
+    }
+
+    /// The base64url SHA-256 of the account key's canonical JWK (RFC 7638).
+    fn thumbprint(&self) -> String {
+        let canonical = format!(
+            r#"{{"crv":"P-256","kty":"EC","x":"{}","y":"{}"}}"#,
+            self.jwk["x"].as_str().unwrap_or_default(),
+            self.jwk["y"].as_str().unwrap_or_default(),
+        );
+        URL_SAFE_NO_PAD.encode(digest(&SHA256, canonical.as_bytes()))
+    }
+
+    /// Sends a JWS-signed request; a POST-as-GET when `payload` is `None`. A
+    /// rejected nonce is retried with the fresh one the CA sends back.
+    async fn post(&mut self, url: &str, payload: Option<Value>) -> Result<reqwest::Response, String> {
+        let payload = payload.map_or_else(String::new, |p| URL_SAFE_NO_PAD.encode(p.to_string()));
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            let nonce = match self.nonce.take() {
+                Some(nonce) => nonce,
+                None => self.new_nonce().await?,
+            };
+            let mut protected = json!({ "alg": "ES256", "nonce": nonce, "url": url });
+            match &self.account {
+                Some(account) => protected["kid"] = json!(account),
+                None => protected["jwk"] = self.jwk.clone(),
+            }
+            let protected = URL_SAFE_NO_PAD.encode(protected.to_string());
+            let signature = self
+                .key
+                .sign(&SystemRandom::new(), format!("{}.{}", protected, payload).as_bytes())
+                .map_err(|_| "failed to sign an ACME request")?;
+            let body = json!({
+                "protected": protected,
+                "payload": payload,
+                "signature": URL_SAFE_NO_PAD.encode(signature.as_ref()),
+            });
+
+            let res = self
+                .http
+                .post(url)
+                .header(header::CONTENT_TYPE, "application/jose+json")
+                .body(body.to_string())
+                .send()
+                .await
+                .map_err(|e| format!("{}: {}", url, e))?;
+            self.nonce = res
+                .headers()
+                .get("replay-nonce")
+                .and_then(|v| v.to_str().ok())
+                .map(String::from);
+            if res.status().is_success() {
+                return Ok(res);
+            }
+
+            let status = res.status();
+            let problem: Value = res.json().await.unwrap_or_default();
+            if problem["type"] == "urn:ietf:params:acme:error:badNonce" && attempts < 3 {
+                continue;
+            }
+            return Err(format!("{} answered {}: {}", url, status, problem));
+        }
+    }
+
+    async fn new_nonce(&self) -> Result<String, String> {
+        let res = self
+            .http
+            .head(&self.directory.new_nonce)
+            .send()
+            .await
+            .map_err(|e| format!("failed to get an ACME nonce: {}", e))?;
+        res.headers()
+            .get("replay-nonce")
+            .and_then(|v| v.to_str().ok())
+            .map(String::from)
+            .ok_or_else(|| "the CA sent no nonce".to_string())
+    }
+}
+
+fn location(res: &reqwest::Response) -> Result<String, String> {
+    res.headers()
+        .get(header::LOCATION)
+        .and_then(|v| v.to_str().ok())
+        .map(String::from)
+        .ok_or_else(|| format!("{} sent no Location", res.url()))
+}
+
+async fn write_private(path: &Path, contents: &[u8]) -> Result<(), String> {
+    let mut options = tokio::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let mut file = options.open(path).await.map_err(|e| format!("{}: {}", path.display(), e))?;
+    tokio::io::AsyncWriteExt::write_all(&mut file, contents)
+        .await
+        .map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+fn pem(label: &str, der: &[u8]) -> String {
+    let encoded = STANDARD.encode(der);
+    let mut pem = format!("-----BEGIN {}-----\n", label);
+    for line in encoded.as_bytes().chunks(64) {
+        pem.push_str(std::str::from_utf8(line).unwrap_or_default());
+        pem.push('\n');
+    }
+    pem.push_str(&format!("-----END {}-----\n", label));
+    pem
+}
+
+// DER encodings of the object identifiers a CSR for a P-256 key needs.
+const OID_COMMON_NAME: &[u8] = &[0x06, 0x03, 0x55, 0x04, 0x03];
+const OID_EC_PUBLIC_KEY: &[u8] = &[0x06, 0x07, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01];
+const OID_PRIME256V1: &[u8] = &[0x06, 0x08, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07];
+const OID_EXTENSION_REQUEST: &[u8] = &[0x06, 0x09, 0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x09, 0x0e];
+const OID_SUBJECT_ALT_NAME: &[u8] = &[0x06, 0x03, 0x55, 0x1d, 0x11];
+const OID_ECDSA_WITH_SHA256: &[u8] = &[0x06, 0x08, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x02];
+
+/// A DER value of `tag` around `content`.
+fn der(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut out = vec![tag];
+    let len = content.len();
+    if len < 0x80 {
+        out.push(len as u8);
+    } else {
+        let bytes: Vec<u8> = len.to_be_bytes().into_iter().skip_while(|&b| b == 0).collect();
+        out.push(0x80 | bytes.len() as u8);
+        out.extend(bytes);
+    }
+    out.extend_from_slice(content);
+    out
+}
+
+/// A PKCS #10 request naming the first domain and listing all of them as
+/// subject alternative names, signed with `key`.
+fn certificate_request(key: &EcdsaKeyPair, domains: &[String]) -> Result<Vec<u8>, String> {
+    let first = domains.first().ok_or("no domains to certify")?;
+    let subject = der(0x30, &der(0x31, &der(0x30, &[OID_COMMON_NAME, &der(0x0c, first.as_bytes())].concat())));
+
+    let mut point = vec![0];
+    point.extend_from_slice(key.public_key().as_ref());
+    let public_key = der(0x30, &[der(0x30, &[OID_EC_PUBLIC_KEY, OID_PRIME256V1].concat()), der(0x03, &point)].concat());
+
+    let names: Vec<u8> = domains.iter().flat_map(|domain| der(0x82, domain.as_bytes())).collect();
+    let alt_names = der(0x30, &[OID_SUBJECT_ALT_NAME, &der(0x04, &der(0x30, &names))].concat());
+    let extensions = der(0x30, &[OID_EXTENSION_REQUEST, &der(0x31, &der(0x30, &alt_names))].concat());
+
+    let info = der(0x30, &[&[0x02, 0x01, 0x00][..], &subject, &public_key, &der(0xa0, &extensions)].concat());
+    let signature = key
+        .sign(&SystemRandom::new(), &info)
+        .map_err(|_| "failed to sign the certificate request")?;
+    let mut bits = vec![0];
+    bits.extend_from_slice(signature.as_ref());
+    Ok(der(0x30, &[info, der(0x30, OID_ECDSA_WITH_SHA256), der(0x03, &bits)].concat()))
+}
+
+/// Splits the first DER value off `input`: its tag, its content and what follows.
+fn read_der(input: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, rest) = input.split_first()?;
+    let (&first, rest) = rest.split_first()?;
+    let (len, rest) = if first < 0x80 {
+        (first as usize, rest)
+    } else {
+        let count = (first & 0x7f) as usize;
+        if count > 4 || rest.len() < count {
+            return None;
+        }
+        let len = rest[..count].iter().fold(0usize, |len, &b| len << 8 | b as usize);
+        (len, &rest[count..])
+    };
+    (rest.len() >= len).then(|| (tag, &rest[..len], &rest[len..]))
+}
+
+/// The `notAfter` of the first certificate of a PEM chain, in Unix seconds.
+fn first_not_after(chain_pem: &[u8]) -> Option<i64> {
+    let cert = CertificateDer::pem_slice_iter(chain_pem).next()?.ok()?;
+    let (_, cert, _) = read_der(&cert)?;
+    let (_, mut tbs, _) = read_der(cert)?;
+    if tbs.first() == Some(&0xa0) {
+        tbs = read_der(tbs)?.2;
+    }
+    // The serial number, signature algorithm and issuer come before the validity.
+    for _ in 0..3 {
+        tbs = read_der(tbs)?.2;
+    }
+    let (_, validity, _) = read_der(tbs)?;
+    let (_, _, validity) = read_der(validity)?;
+    let (_, not_after, _) = read_der(validity)?;
+    let not_after = std::str::from_utf8(not_after).ok()?;
+    let format = if not_after.len() == 13 { "%y%m%d%H%M%SZ" } else { "%Y%m%d%H%M%SZ" };
+    chrono::NaiveDateTime::parse_from_str(not_after, format)
+        .ok()
+        .map(|time| time.and_utc().timestamp())
+}
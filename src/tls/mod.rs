@@ -0,0 +1,211 @@
+//! HTTPS without a reverse proxy: a listener that completes TLS handshakes before
+//! handing connections to `axum::serve`, with certificates that can be swapped
+//! while the server runs, from renewed files or, with the `acme` feature, from the
+//! CA directly.
+
+use std::{
+    fmt, io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::SystemTime,
+};
+
+use axum::serve::Listener;
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+};
+use tokio_rustls::{
+    rustls::{
+        crypto::ring::{default_provider, sign::any_supported_type},
+        pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+        server::{ClientHello, ResolvesServerCert},
+        sign::CertifiedKey,
+        ServerConfig,
+    },
+    server::TlsStream,
+    TlsAcceptor,
+};
+use tracing::{info, warn, Instrument};
+
+use crate::{config::TlsConfig, TLS_HANDSHAKE_TIMEOUT, TLS_RELOAD_INTERVAL};
+
+#[cfg(feature = "acme")]
+mod acme;
+
+/// Connections whose handshake finished but that `axum::serve` hasn't taken yet.
+const HANDSHAKE_BACKLOG: usize = 64;
+
+/// The certificate currently served, replaced whenever a renewed one is loaded.
+#[derive(Default)]
+pub(crate) struct Certificates(RwLock<Option<Arc<CertifiedKey>>>);
+
+impl Certificates {
+    pub(crate) fn set(&self, key: CertifiedKey) {
+        *self.0.write().unwrap_or_else(|e| e.into_inner()) = Some(Arc::new(key));
+    }
+
+    fn is_loaded(&self) -> bool {
+        self.0.read().unwrap_or_else(|e| e.into_inner()).is_some()
+    }
+}
+
+impl fmt::Debug for Certificates {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Certificates").field("loaded", &self.is_loaded()).finish()
+    }
+}
+
+impl ResolvesServerCert for Certificates {
+    fn resolve(&self, _: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        self.0.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+}
+
+/// Parses a PEM certificate chain and private key into what rustls serves.
+pub(crate) fn certified_key(chain_pem: &[u8], key_pem: &[u8]) -> Result<CertifiedKey, String> {
+    let chain = CertificateDer::pem_slice_iter(chain_pem)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("invalid certificate: {}", e))?;
+    if chain.is_empty() {
+        return Err("no certificate found".to_string());
+    }
+    let key = PrivateKeyDer::from_pem_slice(key_pem).map_err(|e| format!("invalid private key: {}", e))?;
+    let key = any_supported_type(&key).map_err(|e| format!("unsupported private key: {}", e))?;
+    Ok(CertifiedKey::new(chain, key))
+}
+
+/// Accepts TCP connections and yields them once their TLS handshake is done.
+/// Handshakes run on their own tasks, so a slow client never holds up the rest.
+pub struct TlsListener {
+    handshaken: mpsc::Receiver<(TlsStream<TcpStream>, SocketAddr)>,
+    local_addr: SocketAddr,
+}
+
+impl TlsListener {
+    /// Serves HTTPS on `tcp` as `config` says, or hands `tcp` back when TLS is off.
+    /// Panics if the certificate files can't be loaded, rather than serve without them.
+    pub async fn bind(tcp: TcpListener, config: &TlsConfig) -> Result<Self, TcpListener> {
+        if !config.enabled() {
+            return Err(tcp);
+        }
+        let certificates = Arc::new(Certificates::default());
+
+        if config.acme_domains.is_empty() {
+            let cert_path = config.cert_path.clone().expect("enabled() checked the certificate path");
+            let key_path = config.key_path.clone().expect("TLS_KEY_PATH must be set with TLS_CERT_PATH");
+            let modified = load_files(&certificates, &cert_path, &key_path)
+                .await
+                .unwrap_or_else(|e| panic!("Failed to load TLS certificate {}: {}", cert_path.display(), e));
+            tokio::spawn(reload_files(certificates.clone(), cert_path, key_path, modified).in_current_span());
+        } else {
+            let port = tcp.local_addr().map_or(443, |addr| addr.port());
+            start_acme(config, port, certificates.clone()).await;
+        }
+
+        let provider = Arc::new(default_provider());
+        let mut server = ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .expect("the ring provider supports the default TLS versions")
+            .with_no_client_auth()
+            .with_cert_resolver(certificates);
+        // gRPC needs HTTP/2, which clients only speak over TLS when ALPN offers it.
+        server.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+        let local_addr = tcp.local_addr().expect("a bound listener has an address");
+        let (tx, handshaken) = mpsc::channel(HANDSHAKE_BACKLOG);
+        tokio::spawn(accept(tcp, TlsAcceptor::from(Arc::new(server)), tx).in_current_span());
+        Ok(Self { handshaken, local_addr })
+    }
+}
+
+impl Listener for TlsListener {
+    type Io = TlsStream<TcpStream>;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        match self.handshaken.recv().await {
+            Some(conn) => conn,
+            // The accept loop never ends on its own; stop offering connections if it did.
+            None => std::future::pending().await,
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        Ok(self.local_addr)
+    }
+}
+
+async fn accept(tcp: TcpListener, acceptor: TlsAcceptor, tx: mpsc::Sender<(TlsStream<TcpStream>, SocketAddr)>) {
+    loop {
+        let (stream, addr) = match tcp.accept().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                // Usually running out of file descriptors; give connections time to close.
+                warn!("Failed to accept a connection: {}", e);
+                tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+
+        let acceptor = acceptor.clone();
+        let tx = tx.clone();
+        tokio::spawn(async move {
+            match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                Ok(Ok(stream)) => {
+                    let _ = tx.send((stream, addr)).await;
+                }
+                Ok(Err(e)) => tracing::debug!("TLS handshake with {} failed: {}", addr, e),
+                Err(_) => tracing::debug!("TLS handshake with {} timed out", addr),
+            }
+        });
+    }
+}
+
+/// Loads the certificate files into `certificates`, returning the certificate's
+/// modification time.
+async fn load_files(certificates: &Certificates, cert_path: &Path, key_path: &Path) -> Result<SystemTime, String> {
+    let chain = tokio::fs::read(cert_path).await.map_err(|e| e.to_string())?;
+    let key = tokio::fs::read(key_path)
+        .await
+        .map_err(|e| format!("{}: {}", key_path.display(), e))?;
+    let modified = modified(cert_path).await.map_err(|e| e.to_string())?;
+    certificates.set(certified_key(&chain, &key)?);
+    Ok(modified)
+}
+
+async fn modified(path: &Path) -> io::Result<SystemTime> {
+    tokio::fs::metadata(path).await?.modified()
+}
+
+/// Reloads the certificate files whenever the certificate changes. A renewal that
+/// fails to load leaves the previous certificate in place.
+async fn reload_files(certificates: Arc<Certificates>, cert_path: PathBuf, key_path: PathBuf, mut loaded: SystemTime) {
+    let mut interval = tokio::time::interval(TLS_RELOAD_INTERVAL);
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        match modified(&cert_path).await {
+            Ok(time) if time != loaded => match load_files(&certificates, &cert_path, &key_path).await {
+                Ok(time) => {
+                    info!("Reloaded TLS certificate {}", cert_path.display());
+                    loaded = time;
+                }
+                Err(e) => warn!("Failed to reload TLS certificate {}: {}", cert_path.display(), e),
+            },
+            Ok(_) => {}
+            Err(e) => warn!("Failed to check TLS certificate {}: {}", cert_path.display(), e),
+        }
+    }
+}
+
+#[cfg(feature = "acme")]
+async fn start_acme(config: &TlsConfig, https_port: u16, certificates: Arc<Certificates>) {
+    acme::start(config.clone(), https_port, certificates).await;
+}
+
+#[cfg(not(feature = "acme"))]
+async fn start_acme(_: &TlsConfig, _: u16, _: Arc<Certificates>) {
+    panic!("ACME_DOMAINS is set, but this server was built without the `acme` feature");
+}
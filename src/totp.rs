@@ -0,0 +1,65 @@
+//! Time-based one-time passwords (RFC 6238), the six-digit codes authenticator
+//! apps show for two-factor authentication: an HMAC-SHA1 of the number of
+//! 30-second steps since the epoch, keyed with a secret shared on enrollment.
+
+use data_encoding::BASE32_NOPAD;
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+
+/// Seconds each code is shown for.
+const STEP_SECS: i64 = 30;
+
+const DIGITS: u32 = 6;
+
+/// Steps either side of the current one whose codes are still accepted, for
+/// clocks that are a little off and codes typed just as they change.
+const SKEW_STEPS: i64 = 1;
+
+/// Bytes of a generated secret, the 160 bits RFC 4226 recommends.
+const SECRET_BYTES: usize = 20;
+
+/// A new random secret, base32 encoded as authenticator apps expect.
+pub(crate) fn generate_secret() -> String {
+    BASE32_NOPAD.encode(&rand::random::<[u8; SECRET_BYTES]>())
+}
+
+/// The `otpauth://` URI that authenticator apps import `secret` from, usually as a QR code.
+pub(crate) fn otpauth_url(issuer: &str, username: &str, secret: &str) -> String {
+    let issuer = urlencoding::encode(issuer);
+    format!(
+        "otpauth://totp/{}:{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+        issuer,
+        urlencoding::encode(username),
+        secret,
+        issuer,
+        DIGITS,
+        STEP_SECS,
+    )
+}
+
+/// The step at unix time `now` that `code` is the code of, if any is within the
+/// accepted skew. Steps up to `last_step` don't count, so a code can't be replayed.
+pub(crate) fn verify(secret: &str, code: &str, now: i64, last_step: Option<i64>) -> Option<i64> {
+    let code = code.trim();
+    if code.len() != DIGITS as usize || !code.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let code: u32 = code.parse().ok()?;
+    let key = BASE32_NOPAD.decode(secret.as_bytes()).ok()?;
+
+    let current = now.div_euclid(STEP_SECS);
+    (current - SKEW_STEPS..=current + SKEW_STEPS)
+        .filter(|step| last_step.is_none_or(|last| *step > last))
+        .find(|step| code_at(&key, *step) == Some(code))
+}
+
+/// The code of `step`, by the dynamic truncation of RFC 4226.
+fn code_at(key: &[u8], step: i64) -> Option<u32> {
+    let mut mac = Hmac::<Sha1>::new_from_slice(key).ok()?;
+    mac.update(&step.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+
+    let offset = usize::from(hash[hash.len() - 1] & 0x0f);
+    let value = u32::from_be_bytes(hash[offset..offset + 4].try_into().ok()?) & 0x7fff_ffff;
+    Some(value % 10u32.pow(DIGITS))
+}
@@ -0,0 +1,308 @@
+//! Webhooks users register through `/webhooks`. Each of a user's file events is
+//! queued as a delivery to every webhook subscribed to it and sent right away from
+//! a background task; failed attempts are made again with backoff by the retry
+//! worker, up to `WEBHOOK_MAX_ATTEMPTS`. The deliveries double as each webhook's log.
+
+use std::net::IpAddr;
+
+use axum::http::header;
+use serde::Serialize;
+use tracing::{info, warn, Instrument};
+
+use crate::{
+    db::{on_db, retry_delay, DbPool},
+    events::sign,
+    models::{DueDelivery, FileChange, Operation, Webhook},
+    AppState, RETRY_BATCH_SIZE, RETRY_LEASE_SECS, WEBHOOK_DELIVERY_HISTORY,
+};
+
+pub(crate) const FILE_CREATED: &str = "file.created";
+pub(crate) const FILE_UPDATED: &str = "file.updated";
+pub(crate) const FILE_DELETED: &str = "file.deleted";
+pub(crate) const SHARE_ACCESSED: &str = "share.accessed";
+pub(crate) const SHARE_VIEWED: &str = "share.viewed";
+
+/// Every event a webhook can subscribe to.
+pub(crate) const WEBHOOK_EVENTS: [&str; 5] = [FILE_CREATED, FILE_UPDATED, FILE_DELETED, SHARE_ACCESSED, SHARE_VIEWED];
+
+/// The body of a delivery, signed as `X-Pocket-Signature: sha256=<hex>` with the
+/// webhook's secret.
+#[derive(Serialize)]
+struct EventPayload<'a> {
+    event: &'a str,
+    timestamp: i64,
+    file_paths: &'a [String],
+    /// For `share.accessed` and `share.viewed`: the link used, and the address it
+    /// was opened from.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    share_id: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ip: Option<String>,
+}
+
+/// Queues the user's applied changes for their webhooks, one event per kind of change.
+pub(crate) fn queue_changes(state: &AppState, user_id: i32, changes: &[FileChange]) {
+    let events = [
+        (Operation::Insert, FILE_CREATED),
+        (Operation::Update, FILE_UPDATED),
+        (Operation::Delete, FILE_DELETED),
+    ]
+    .into_iter()
+    .map(|(operation, event)| {
+        let paths: Vec<String> = changes
+            .iter()
+            .filter(|change| change.operation == operation)
+            .map(|change| change.file_path.clone())
+            .collect();
+        (event, paths)
+    })
+    .filter(|(_, paths)| !paths.is_empty())
+    .collect();
+    queue(state, user_id, events, None);
+}
+
+/// Queues a `share.accessed` event for a file of `owner`'s downloaded through
+/// their share link `share_id`, from `ip`.
+pub(crate) fn queue_share_access(state: &AppState, owner: i32, share_id: i32, file_path: String, ip: IpAddr) {
+    queue(state, owner, vec![(SHARE_ACCESSED, vec![file_path])], Some((share_id, ip)));
+}
+
+/// Queues a `share.viewed` event for a folder page or preview of `owner`'s opened
+/// through their share link `share_id`, from `ip`.
+pub(crate) fn queue_share_view(state: &AppState, owner: i32, share_id: i32, file_path: String, ip: IpAddr) {
+    queue(state, owner, vec![(SHARE_VIEWED, vec![file_path])], Some((share_id, ip)));
+}
+
+/// `share` is the link and address of a share event.
+fn queue(state: &AppState, user_id: i32, events: Vec<(&'static str, Vec<String>)>, share: Option<(i32, IpAddr)>) {
+    if events.is_empty() {
+        return;
+    }
+
+    let state = state.clone();
+    tokio::spawn(async move {
+        let webhooks = on_db!(&state.pool, pool => sqlx::query_as::<_, Webhook>(
+            "SELECT id, url, events, created_at FROM webhooks WHERE user_id = $1"
+        )
+        .bind(user_id)
+        .fetch_all(pool)
+        .await);
+        let webhooks = match webhooks {
+            Ok(webhooks) => webhooks,
+            Err(e) => {
+                warn!("Failed to load the webhooks of user {}: {}", user_id, e);
+                return;
+            }
+        };
+
+        for (event, file_paths) in &events {
+            let subscribed: Vec<&Webhook> = webhooks
+                .iter()
+                .filter(|webhook| webhook.events.is_empty() || webhook.events.iter().any(|e| e == event))
+                .collect();
+            if subscribed.is_empty() {
+                continue;
+            }
+            let payload = serde_json::to_string(&EventPayload {
+                event,
+                timestamp: chrono::Utc::now().timestamp(),
+                file_paths,
+                share_id: share.map(|(id, _)| id),
+                ip: share.map(|(_, ip)| ip.to_string()),
+            });
+            let payload = match payload {
+                Ok(payload) => payload,
+                Err(e) => {
+                    warn!("Failed to encode {} webhook payload: {}", event, e);
+                    continue;
+                }
+            };
+
+            for webhook in subscribed {
+                match enqueue_delivery(&state.pool, webhook.id, event, &payload).await {
+                    Ok(delivery) => {
+                        tokio::spawn(attempt(state.clone(), delivery).in_current_span());
+                    }
+                    Err(e) => warn!("Failed to queue {} for webhook {}: {}", event, webhook.id, e),
+                }
+                prune_deliveries(&state.pool, webhook.id).await;
+            }
+        }
+    }.in_current_span());
+}
+
+/// Records a delivery for its first attempt, which the caller makes. It's leased
+/// like a claimed one, so the retry worker only picks it up if that attempt never
+/// gets recorded.
+async fn enqueue_delivery(pool: &DbPool, webhook_id: i32, event: &str, payload: &str) -> Result<DueDelivery, sqlx::Error> {
+    let sql = pool.sql(
+        r#"
+        INSERT INTO webhook_deliveries (webhook_id, event, payload, run_at)
+        VALUES ($1, $2, $3, CURRENT_TIMESTAMP + make_interval(secs => $4))
+        RETURNING id, event, payload, attempts,
+            (SELECT url FROM webhooks WHERE id = $1) AS url,
+            (SELECT secret FROM webhooks WHERE id = $1) AS secret
+        "#,
+        r#"
+        INSERT INTO webhook_deliveries (webhook_id, event, payload, run_at)
+        VALUES ($1, $2, $3, datetime('now', $4 || ' seconds'))
+        RETURNING id, event, payload, attempts,
+            (SELECT url FROM webhooks WHERE id = $1) AS url,
+            (SELECT secret FROM webhooks WHERE id = $1) AS secret
+        "#,
+    );
+    on_db!(pool, pool => sqlx::query_as::<_, DueDelivery>(sql)
+        .bind(webhook_id)
+        .bind(event)
+        .bind(payload)
+        .bind(RETRY_LEASE_SECS as f64)
+        .fetch_one(pool)
+        .await)
+}
+
+/// Keeps the latest `WEBHOOK_DELIVERY_HISTORY` deliveries of a webhook, and any
+/// still pending.
+async fn prune_deliveries(pool: &DbPool, webhook_id: i32) {
+    let pruned = on_db!(pool, pool => sqlx::query(
+        r#"
+        DELETE FROM webhook_deliveries
+        WHERE webhook_id = $1 AND status <> 'pending' AND id NOT IN (
+            SELECT id FROM webhook_deliveries WHERE webhook_id = $1 ORDER BY id DESC LIMIT $2
+        )
+        "#
+    )
+    .bind(webhook_id)
+    .bind(WEBHOOK_DELIVERY_HISTORY)
+    .execute(pool)
+    .await
+    .map(|_| ()));
+    if let Err(e) = pruned {
+        warn!("Failed to prune the deliveries of webhook {}: {}", webhook_id, e);
+    }
+}
+
+/// Makes one attempt at every due delivery, claiming them by pushing their
+/// `run_at` out by a lease as `claim_due_retries` does.
+pub(crate) async fn run_due_deliveries(state: &AppState) {
+    let sql = state.pool.sql(
+        r#"
+        UPDATE webhook_deliveries
+        SET run_at = CURRENT_TIMESTAMP + make_interval(secs => $2)
+        WHERE id IN (
+            SELECT id FROM webhook_deliveries
+            WHERE status = 'pending' AND run_at <= CURRENT_TIMESTAMP
+            ORDER BY run_at
+            LIMIT $1
+            FOR UPDATE SKIP LOCKED
+        )
+        RETURNING id, event, payload, attempts,
+            (SELECT url FROM webhooks w WHERE w.id = webhook_deliveries.webhook_id) AS url,
+            (SELECT secret FROM webhooks w WHERE w.id = webhook_deliveries.webhook_id) AS secret
+        "#,
+        r#"
+        UPDATE webhook_deliveries
+        SET run_at = datetime('now', $2 || ' seconds')
+        WHERE id IN (
+            SELECT id FROM webhook_deliveries
+            WHERE status = 'pending' AND run_at <= CURRENT_TIMESTAMP
+            ORDER BY run_at
+            LIMIT $1
+        )
+        RETURNING id, event, payload, attempts,
+            (SELECT url FROM webhooks w WHERE w.id = webhook_deliveries.webhook_id) AS url,
+            (SELECT secret FROM webhooks w WHERE w.id = webhook_deliveries.webhook_id) AS secret
+        "#,
+    );
+    let due = on_db!(&state.pool, pool => sqlx::query_as::<_, DueDelivery>(sql)
+        .bind(RETRY_BATCH_SIZE)
+        .bind(RETRY_LEASE_SECS as f64)
+        .fetch_all(pool)
+        .await);
+    let due = match due {
+        Ok(due) => due,
+        Err(e) => {
+            warn!("Failed to claim due webhook deliveries: {}", e);
+            return;
+        }
+    };
+    if due.is_empty() {
+        return;
+    }
+
+    info!("RETRYING {} WEBHOOK DELIVERIES", due.len());
+    for delivery in due {
+        attempt(state.clone(), delivery).await;
+    }
+}
+
+/// Sends a delivery once and records how that went: `delivered` on a 2xx, otherwise
+/// another failed attempt, retried with backoff until the attempts run out.
+async fn attempt(state: AppState, delivery: DueDelivery) {
+    let signature = format!("sha256={}", sign(delivery.secret.as_bytes(), delivery.payload.as_bytes()));
+    let sent = state
+        .webhooks
+        .client
+        .post(&delivery.url)
+        .header(header::CONTENT_TYPE, "application/json")
+        .header("X-Pocket-Signature", signature)
+        .header("X-Pocket-Event", &delivery.event)
+        .header("X-Pocket-Delivery", delivery.id.to_string())
+        .body(delivery.payload)
+        .send()
+        .await;
+    let (response_status, error) = match sent {
+        Ok(res) if res.status().is_success() => (Some(res.status().as_u16() as i32), None),
+        Ok(res) => (Some(res.status().as_u16() as i32), Some(format!("the receiver answered {}", res.status()))),
+        Err(e) => (None, Some(e.to_string())),
+    };
+
+    let attempts = delivery.attempts + 1;
+    let recorded = match &error {
+        None => on_db!(&state.pool, pool => sqlx::query(
+            r#"
+            UPDATE webhook_deliveries
+            SET status = 'delivered', attempts = $2, response_status = $3, last_error = NULL,
+                delivered_at = CURRENT_TIMESTAMP
+            WHERE id = $1
+            "#
+        )
+        .bind(delivery.id)
+        .bind(attempts)
+        .bind(response_status)
+        .execute(pool)
+        .await
+        .map(|_| ())),
+        Some(error) => {
+            warn!("Webhook delivery {} to {} failed on attempt {}: {}", delivery.id, delivery.url, attempts, error);
+            let sql = state.pool.sql(
+                r#"
+                UPDATE webhook_deliveries
+                SET attempts = $2, response_status = $3, last_error = $4,
+                    status = CASE WHEN $2 >= $5 THEN 'failed' ELSE 'pending' END,
+                    run_at = CURRENT_TIMESTAMP + make_interval(secs => $6)
+                WHERE id = $1
+                "#,
+                r#"
+                UPDATE webhook_deliveries
+                SET attempts = $2, response_status = $3, last_error = $4,
+                    status = CASE WHEN $2 >= $5 THEN 'failed' ELSE 'pending' END,
+                    run_at = datetime('now', $6 || ' seconds')
+                WHERE id = $1
+                "#,
+            );
+            on_db!(&state.pool, pool => sqlx::query(sql)
+                .bind(delivery.id)
+                .bind(attempts)
+                .bind(response_status)
+                .bind(error)
+                .bind(state.webhooks.max_attempts as i32)
+                .bind(retry_delay(attempts))
+                .execute(pool)
+                .await
+                .map(|_| ()))
+        }
+    };
+    if let Err(e) = recorded {
+        warn!("Failed to record the outcome of webhook delivery {}: {}", delivery.id, e);
+    }
+}
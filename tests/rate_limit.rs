@@ -0,0 +1,44 @@
+mod common;
+
+use std::env;
+
+use serde_json::json;
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn callers_over_budget_get_429_with_retry_after() {
+    // SAFETY: each test binary runs a single test, and nothing else reads these.
+    unsafe {
+        env::set_var("RATE_LIMIT_READ_BURST", "2");
+        env::set_var("RATE_LIMIT_READ_PER_MIN", "1");
+    }
+    let server = common::start().await;
+
+    for _ in 0..2 {
+        assert_eq!(server.get("/").await.status(), 200);
+    }
+    let res = server.get("/").await;
+    assert_eq!(res.status(), 429);
+    assert_eq!(res.headers()["retry-after"], "60");
+
+    // Writes draw on a budget of their own.
+    let payload = json!({
+        "insert": [{
+            "file_name": "a.txt",
+            "file_path": "a.txt",
+            "file_hash": "h1",
+            "file_size": 5,
+            "modified_time": 1
+        }]
+    });
+    let res = common::sync(&server, payload, &[("a.txt", b"hello")]).await;
+    assert!(res.status().is_success(), "sync answered {}", res.status());
+
+    let rows: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM filehash WHERE file_path = 'a.txt'")
+        .fetch_one(&server.pool)
+        .await
+        .unwrap();
+    assert_eq!(rows, 1);
+    let objects = server.s3.list_objects_v2().bucket(common::BUCKET).send().await.unwrap();
+    assert_eq!(objects.key_count(), Some(1));
+}
@@ -0,0 +1,206 @@
+//! Drives the router in-process with `oneshot`, against in-memory storage and a
+//! database pool that never connects, so these need neither Docker nor Postgres.
+
+use std::{net::SocketAddr, time::Duration};
+
+use axum::{
+    body::{to_bytes, Body},
+    extract::ConnectInfo,
+    http::{Request, StatusCode},
+    response::Response,
+    Router,
+};
+use pocket_server::{build_router, state_with_pool, AppConfig, CorsConfig, RateLimitConfig, RateLimitSettings};
+use sqlx::postgres::PgPoolOptions;
+use tower::ServiceExt;
+
+async fn router() -> Router {
+    router_with(AppConfig::default()).await
+}
+
+async fn router_with(config: AppConfig) -> Router {
+    let pool = PgPoolOptions::new()
+        .acquire_timeout(Duration::from_millis(500))
+        .connect_lazy("postgres://pocket@127.0.0.1:1/pocket")
+        .unwrap();
+    let config = AppConfig {
+        storage_backend: "memory".to_string(),
+        ..config
+    };
+    build_router(state_with_pool(pool.into(), config).await)
+}
+
+/// Sends `req` as if from a client at 127.0.0.1; rate limiting keys on the peer address.
+async fn send(app: Router, mut req: Request<Body>) -> Response {
+    req.extensions_mut().insert(ConnectInfo(SocketAddr::from(([127, 0, 0, 1], 40000))));
+    app.oneshot(req).await.unwrap()
+}
+
+fn get(path: &str) -> Request<Body> {
+    Request::get(path).body(Body::empty()).unwrap()
+}
+
+async fn body_text(res: Response) -> String {
+    let bytes = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+    String::from_utf8(bytes.to_vec()).unwrap()
+}
+
+#[tokio::test]
+async fn root_answers() {
+    let res = send(router().await, get("/")).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(body_text(res).await, "Pocket Drive is running!");
+}
+
+#[tokio::test]
+async fn liveness_ignores_the_database() {
+    let res = send(router().await, get("/healthz")).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert!(res.headers().contains_key("x-request-id"));
+}
+
+#[tokio::test]
+async fn openapi_spec_is_public() {
+    let res = send(router().await, get("/openapi.json")).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let spec: serde_json::Value = serde_json::from_str(&body_text(res).await).unwrap();
+    assert!(spec["paths"]["/sync"]["post"].is_object());
+    assert!(spec["components"]["schemas"]["FileEntry"].is_object());
+}
+
+#[tokio::test]
+async fn capabilities_are_public_and_follow_the_config() {
+    let config = AppConfig { dedup: true, max_upload_bytes: Some(1024), ..AppConfig::default() };
+    let res = send(router_with(config).await, get("/capabilities")).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let capabilities: serde_json::Value = serde_json::from_str(&body_text(res).await).unwrap();
+    assert_eq!(capabilities["version"], env!("CARGO_PKG_VERSION"));
+    assert_eq!(capabilities["sync_payload_versions"], serde_json::json!([1, 2, 3, 4]));
+    assert_eq!(capabilities["max_upload_bytes"], 1024);
+    assert_eq!(capabilities["features"]["dedup"], true);
+    assert_eq!(capabilities["features"]["encryption"], false);
+}
+
+#[tokio::test]
+async fn readiness_fails_without_the_database() {
+    let res = send(router().await, get("/readyz")).await;
+    assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+}
+
+#[tokio::test]
+async fn protected_routes_require_a_token() {
+    let res = send(router().await, get("/get")).await;
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn signed_downloads_reject_forged_tokens() {
+    let res = send(router().await, get("/download/signed/not-a-token")).await;
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn webdav_challenges_for_basic_credentials() {
+    let req = Request::builder().method("PROPFIND").uri("/dav/").body(Body::empty()).unwrap();
+    let res = send(router().await, req).await;
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    assert!(res.headers()["www-authenticate"].to_str().unwrap().starts_with("Basic "));
+}
+
+#[tokio::test]
+async fn grpc_calls_without_a_token_get_a_grpc_status() {
+    let req = Request::post("/pocket.v1.PocketSync/Download")
+        .header("content-type", "application/grpc")
+        .body(Body::empty())
+        .unwrap();
+    let res = send(router().await, req).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    // 16 is UNAUTHENTICATED.
+    assert_eq!(res.headers()["grpc-status"], "16");
+}
+
+#[tokio::test]
+async fn unknown_routes_are_not_found() {
+    let res = send(router().await, get("/no-such-route")).await;
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn metrics_count_served_requests() {
+    let app = router().await;
+    send(app.clone(), get("/")).await;
+
+    let res = send(app, get("/metrics")).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert!(body_text(res).await.contains("route=\"/\""));
+}
+
+#[tokio::test]
+async fn oversized_bodies_are_rejected() {
+    let body = vec![b' '; 3 * 1024 * 1024];
+    let req = Request::post("/auth/login")
+        .header("content-type", "application/json")
+        .body(Body::from(body))
+        .unwrap();
+    let res = send(router().await, req).await;
+    assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+}
+
+#[tokio::test]
+async fn bodies_in_unknown_encodings_are_refused() {
+    let req = Request::post("/delete")
+        .header("content-type", "application/json")
+        .header("content-encoding", "zstd")
+        .body(Body::from("{}"))
+        .unwrap();
+    let res = send(router().await, req).await;
+    assert_eq!(res.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+}
+
+#[tokio::test]
+async fn route_budgets_answer_429_with_retry_after() {
+    let mut rate_limits = RateLimitConfig::default();
+    rate_limits.routes.insert("/".to_string(), RateLimitSettings { per_min: 1.0, burst: 1.0 });
+    let app = router_with(AppConfig { rate_limits, ..AppConfig::default() }).await;
+
+    assert_eq!(send(app.clone(), get("/")).await.status(), StatusCode::OK);
+    let res = send(app.clone(), get("/")).await;
+    assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert!(res.headers().contains_key("retry-after"));
+
+    // Other routes keep the default budget.
+    assert_eq!(send(app, get("/metrics")).await.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn responses_carry_security_headers() {
+    let res = send(router().await, get("/")).await;
+    assert_eq!(res.headers()["x-content-type-options"], "nosniff");
+    assert_eq!(res.headers()["x-frame-options"], "DENY");
+    assert!(!res.headers().contains_key("strict-transport-security"));
+}
+
+#[tokio::test]
+async fn cors_preflights_skip_auth_but_not_webdav() {
+    let cors = CorsConfig {
+        allowed_origins: vec!["https://ui.example".to_string()],
+        ..CorsConfig::default()
+    };
+    let app = router_with(AppConfig { cors, ..AppConfig::default() }).await;
+
+    let preflight = |path: &str| {
+        Request::options(path)
+            .header("origin", "https://ui.example")
+            .header("access-control-request-method", "GET")
+            .body(Body::empty())
+            .unwrap()
+    };
+    let res = send(app.clone(), preflight("/get")).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(res.headers()["access-control-allow-origin"], "https://ui.example");
+
+    // WebDAV's own OPTIONS still needs credentials, and isn't taken for a preflight.
+    let res = send(app, preflight("/dav/")).await;
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    assert!(!res.headers().contains_key("access-control-allow-origin"));
+}
@@ -0,0 +1,72 @@
+mod common;
+
+use std::time::Duration;
+
+use serde_json::{json, Value};
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn the_activity_feed_pages_through_changes_and_shares_but_not_downloads() {
+    let server = common::start().await;
+    let client = reqwest::Client::new();
+    let entry = |path: &str| {
+        json!({ "file_name": path, "file_path": path, "file_hash": "h1", "file_size": 1, "modified_time": 1 })
+    };
+
+    let res = common::sync(&server, json!({ "insert": [entry("a.txt"), entry("b.txt")] }), &[("a.txt", b"a"), ("b.txt", b"b")]).await;
+    assert_eq!(res.status(), 200);
+    let res = common::sync(&server, json!({ "delete": [entry("a.txt")] }), &[]).await;
+    assert_eq!(res.status(), 200);
+
+    let trash: Value = server.get("/trash").await.json().await.unwrap();
+    let res = client
+        .post(server.url("/trash/restore"))
+        .bearer_auth(common::ADMIN_TOKEN)
+        .json(&json!({ "id": trash["data"][0]["id"] }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 200);
+    let res = client
+        .post(server.url("/share"))
+        .bearer_auth(common::ADMIN_TOKEN)
+        .json(&json!({ "file_path": "b.txt" }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 201);
+    assert_eq!(server.get("/download?file_path=b.txt").await.status(), 200);
+
+    // Audit rows are written in the background.
+    for _ in 0..50 {
+        let logged: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM audit_log").fetch_one(&server.pool).await.unwrap();
+        if logged == 6 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+
+    let page: Value = server.get("/activity?limit=3").await.json().await.unwrap();
+    let seen = |page: &Value| -> Vec<(String, String)> {
+        page["data"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|e| (e["operation"].as_str().unwrap().into(), e["file_path"].as_str().unwrap().into()))
+            .collect()
+    };
+    let first = seen(&page);
+    assert_eq!(first, [("share".into(), "b.txt".into()), ("restore".into(), "a.txt".into()), ("delete".into(), "a.txt".into())]);
+    assert_eq!(page["data"][2]["device_id"], common::DEVICE_ID);
+
+    let before = page["next_before"].as_i64().unwrap();
+    let page: Value = server.get(&format!("/activity?limit=3&before={}", before)).await.json().await.unwrap();
+    assert_eq!(seen(&page).len(), 2, "downloads are left out");
+    assert!(page["next_before"].is_null());
+
+    let system_path: String = sqlx::query_scalar("SELECT system_path FROM filehash WHERE file_path = 'a.txt'")
+        .fetch_one(&server.pool)
+        .await
+        .unwrap();
+    server.s3.head_object().bucket(common::BUCKET).key(&system_path).send().await.unwrap();
+}
@@ -0,0 +1,70 @@
+mod common;
+
+use std::time::Duration;
+
+use serde_json::{json, Value};
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn archive_marks_are_kept_and_restores_report_readable_objects() {
+    let server = common::start().await;
+    let client = reqwest::Client::new();
+    let entry = json!({ "file_name": "a.txt", "file_path": "cold/a.txt", "file_hash": "h1", "file_size": 1, "modified_time": 1 });
+    let res = common::sync(&server, json!({ "insert": [entry] }), &[("cold/a.txt", b"a")]).await;
+    assert_eq!(res.status(), 200);
+
+    let mark = |class: &str| {
+        client
+            .put(server.url("/archive"))
+            .bearer_auth(common::ADMIN_TOKEN)
+            .json(&json!({ "path": "/cold/", "storage_class": class }))
+            .send()
+    };
+    assert_eq!(mark("STANDARD").await.unwrap().status(), 400);
+    assert_eq!(mark("GLACIER").await.unwrap().status(), 200);
+    let rules: Value = server.get("/archive").await.json().await.unwrap();
+    assert_eq!(rules["data"][0]["path"], "cold");
+    assert_eq!(rules["data"][0]["storage_class"], "GLACIER");
+
+    // MinIO keeps every object in STANDARD, so the file is recorded as moved here.
+    sqlx::query(
+        "INSERT INTO archived_files (file_id, storage_class) SELECT id, 'GLACIER' FROM filehash WHERE file_path = 'cold/a.txt' \
+         ON CONFLICT (file_id) DO UPDATE SET storage_class = 'GLACIER'",
+    )
+    .execute(&server.pool)
+    .await
+    .unwrap();
+    let restore = |path: &str| {
+        client
+            .post(server.url("/restore-archive"))
+            .bearer_auth(common::ADMIN_TOKEN)
+            .json(&json!({ "path": path }))
+            .send()
+    };
+    assert_eq!(restore("warm").await.unwrap().status(), 404);
+    let res = restore("cold").await.unwrap();
+    assert_eq!(res.status(), 200);
+    let report: Value = res.json().await.unwrap();
+    assert_eq!(report["data"]["files"][0]["readable"], true, "{}", report);
+    assert_eq!(report["data"]["failed"], json!([]));
+
+    let unmark = || client.delete(server.url("/archive?path=cold")).bearer_auth(common::ADMIN_TOKEN).send();
+    assert_eq!(unmark().await.unwrap().status(), 204);
+    assert_eq!(unmark().await.unwrap().status(), 404);
+    let mut archived = Value::Null;
+    for _ in 0..50 {
+        archived = server.get("/archive/files").await.json().await.unwrap();
+        if archived["data"] == json!([]) {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    assert_eq!(archived["data"], json!([]), "unmarked files go back to STANDARD");
+
+    let system_path: String = sqlx::query_scalar("SELECT system_path FROM filehash WHERE file_path = 'cold/a.txt'")
+        .fetch_one(&server.pool)
+        .await
+        .unwrap();
+    let object = server.s3.get_object().bucket(common::BUCKET).key(&system_path).send().await.unwrap();
+    assert_eq!(object.body.collect().await.unwrap().into_bytes().as_ref(), b"a");
+}
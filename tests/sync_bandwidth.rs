@@ -0,0 +1,51 @@
+mod common;
+
+use std::{env, time::Instant};
+
+use serde_json::json;
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn downloads_are_slowed_to_the_device_rate() {
+    // SAFETY: each test binary runs a single test, and the server isn't started yet.
+    unsafe {
+        env::set_var("BANDWIDTH_DEVICE_DOWNLOAD_BYTES_PER_SEC", "100000");
+    }
+    let server = common::start().await;
+    let data = vec![7u8; 300_000];
+    let entry = json!({
+        "file_name": "big.bin",
+        "file_path": "big.bin",
+        "file_hash": "ccc333",
+        "file_size": data.len(),
+        "modified_time": 1
+    });
+    let res = common::sync(&server, json!({ "insert": [entry] }), &[("big.bin", &data)]).await;
+    assert_eq!(res.status(), 200);
+
+    // A second's worth passes at once, and the rest at 100 kB a second.
+    let started = Instant::now();
+    let res = reqwest::Client::new()
+        .get(server.url("/download/direct?file_path=big.bin"))
+        .bearer_auth(common::ADMIN_TOKEN)
+        .header("X-Device-Id", "laptop")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 200);
+    assert_eq!(res.bytes().await.unwrap().len(), data.len());
+    assert!(started.elapsed().as_secs_f64() >= 1.5, "took {:?}", started.elapsed());
+
+    // Requests without a device only answer to the user-wide limit, which is off.
+    let started = Instant::now();
+    let res = server.get("/download/direct?file_path=big.bin").await;
+    assert_eq!(res.bytes().await.unwrap().len(), data.len());
+    assert!(started.elapsed().as_secs_f64() < 1.5, "took {:?}", started.elapsed());
+
+    let system_path: String = sqlx::query_scalar("SELECT system_path FROM filehash")
+        .fetch_one(&server.pool)
+        .await
+        .unwrap();
+    let head = server.s3.head_object().bucket(common::BUCKET).key(&system_path).send().await.unwrap();
+    assert_eq!(head.content_length(), Some(data.len() as i64));
+}
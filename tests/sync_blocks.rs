@@ -0,0 +1,88 @@
+mod common;
+
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+async fn block_sync(server: &common::TestServer, form: reqwest::multipart::Form) -> reqwest::Response {
+    reqwest::Client::new()
+        .post(server.url("/sync/blocks"))
+        .bearer_auth(common::ADMIN_TOKEN)
+        .header("X-Device-Id", common::DEVICE_ID)
+        .multipart(form)
+        .send()
+        .await
+        .expect("Block sync request failed")
+}
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn block_sync_uploads_only_the_chunks_the_server_lacks() {
+    let server = common::start().await;
+    let original: &[u8] = b"the first version of the notes";
+    let appended: &[u8] = b", and a second paragraph";
+
+    // A file stored through `/sync` has no manifest until its content goes
+    // through `/sync/blocks` once.
+    let res = common::sync(
+        &server,
+        json!({
+            "insert": [{
+                "file_name": "notes.txt",
+                "file_path": "docs/notes.txt",
+                "file_hash": hex::encode(Sha256::digest(original)),
+                "file_size": original.len(),
+                "modified_time": 1
+            }]
+        }),
+        &[("notes.txt", original)],
+    )
+    .await;
+    assert_eq!(res.status(), 200);
+    assert_eq!(server.get("/sync/blocks?file_path=docs/notes.txt").await.status(), 404);
+
+    let form = reqwest::multipart::Form::new()
+        .text("payload", json!({ "file_path": "docs/notes.txt", "modified_time": 1 }).to_string())
+        .part("content", reqwest::multipart::Part::bytes(original.to_vec()));
+    let res = block_sync(&server, form).await;
+    assert_eq!(res.status(), 200);
+
+    let manifest: Value = server.get("/sync/blocks?file_path=docs/notes.txt").await.json().await.unwrap();
+    let original_hash = hex::encode(Sha256::digest(original));
+    assert_eq!(manifest["chunks"], json!([{ "hash": original_hash, "size": original.len(), "offset": 0 }]));
+
+    // The first chunk is already stored, so only the second one is sent.
+    let chunks = json!([
+        { "hash": original_hash, "size": original.len() },
+        { "hash": hex::encode(Sha256::digest(appended)), "size": appended.len() },
+    ]);
+    let payload = json!({
+        "file_path": "docs/notes.txt",
+        "modified_time": 2,
+        "chunks": chunks,
+        "base_hash": manifest["file_hash"],
+    });
+    let form = reqwest::multipart::Form::new()
+        .text("payload", payload.to_string())
+        .part("chunk", reqwest::multipart::Part::bytes(appended.to_vec()));
+    let res = block_sync(&server, form).await;
+    assert_eq!(res.status(), 200);
+    let result: Value = res.json().await.unwrap();
+    assert_eq!(result["chunks_reused"], 1);
+    assert_eq!(result["chunks_uploaded"], 1);
+
+    let content = [original, appended].concat();
+    assert_eq!(result["data"]["file_hash"], hex::encode(Sha256::digest(&content)));
+    let system_path: String = sqlx::query_scalar("SELECT system_path FROM filehash WHERE file_path = $1")
+        .bind("docs/notes.txt")
+        .fetch_one(&server.pool)
+        .await
+        .unwrap();
+    let object = server.s3
+        .get_object()
+        .bucket(common::BUCKET)
+        .key(&system_path)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(object.body.collect().await.unwrap().into_bytes().as_ref(), content);
+}
@@ -0,0 +1,69 @@
+mod common;
+
+use serde_json::{json, Value};
+
+const FILES: usize = 300;
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn large_update_batches_apply_per_file_outcomes() {
+    let server = common::start().await;
+
+    let entry = |i: usize, hash: &str, size: usize, mtime: i64| {
+        json!({
+            "file_name": format!("f{}.txt", i),
+            "file_path": format!("dir/f{}.txt", i),
+            "file_hash": format!("{}{}", hash, i),
+            "file_size": size,
+            "modified_time": mtime
+        })
+    };
+    let names: Vec<String> = (0..FILES).map(|i| format!("f{}.txt", i)).collect();
+
+    let inserts: Vec<Value> = (0..FILES).map(|i| entry(i, "old", 1, 1)).collect();
+    let uploads: Vec<(&str, &[u8])> = names.iter().map(|name| (name.as_str(), &b"a"[..])).collect();
+    let res = common::sync(&server, json!({ "insert": inserts }), &uploads).await;
+    assert_eq!(res.status(), 200);
+
+    let mut updates: Vec<Value> = (0..FILES).map(|i| entry(i, "new", 2, 2)).collect();
+    updates[0]["base_hash"] = json!("stale");
+    updates[1]["base_hash"] = json!("old1");
+    updates.push(json!({
+        "file_name": "gone.txt",
+        "file_path": "gone.txt",
+        "file_hash": "x",
+        "file_size": 2,
+        "modified_time": 2
+    }));
+    let mut uploads: Vec<(&str, &[u8])> = names.iter().map(|name| (name.as_str(), &b"bb"[..])).collect();
+    uploads.push(("gone.txt", b"bb"));
+    let res = common::sync(&server, json!({ "update": updates }), &uploads).await;
+    assert_eq!(res.status(), 200);
+    let report: Value = res.json().await.unwrap();
+    let update = &report["results"]["update"];
+    assert_eq!(update["success"].as_array().unwrap().len(), FILES - 1);
+    assert_eq!(update["conflict"][0]["file_path"], "dir/f0.txt");
+    assert_eq!(update["failure"][0]["file_path"], "gone.txt");
+
+    let hashes: Vec<(String, i64)> =
+        sqlx::query_as("SELECT file_hash, file_size FROM filehash WHERE file_path IN ('dir/f0.txt', 'dir/f1.txt') ORDER BY file_path")
+            .fetch_all(&server.pool)
+            .await
+            .unwrap();
+    assert_eq!(hashes, [("old0".to_string(), 1), ("new1".to_string(), 2)]);
+    let versions: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM file_versions")
+        .fetch_one(&server.pool)
+        .await
+        .unwrap();
+    assert_eq!(versions, FILES as i64 - 1, "every updated file keeps its previous version");
+
+    let system_path: String = sqlx::query_scalar("SELECT system_path FROM filehash WHERE file_path = 'dir/f1.txt'")
+        .fetch_one(&server.pool)
+        .await
+        .unwrap();
+    let head = server.s3.head_object().bucket(common::BUCKET).key(&system_path).send().await.unwrap();
+    assert_eq!(head.content_length(), Some(2));
+
+    let listing: Value = server.get("/get").await.json().await.unwrap();
+    assert_eq!(listing["data"].as_array().unwrap().len(), FILES);
+}
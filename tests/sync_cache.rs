@@ -0,0 +1,45 @@
+mod common;
+
+use std::{env, time::Duration};
+
+use serde_json::{json, Value};
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn small_uploads_are_served_from_disk_and_written_behind() {
+    let dir = env::temp_dir().join(format!("pocket-cache-{}", std::process::id()));
+    // SAFETY: each test binary runs a single test, and the server isn't started yet.
+    unsafe {
+        env::set_var("STORAGE_CACHE", "true");
+        env::set_var("CACHE_DIR", &dir);
+        env::set_var("CACHE_WRITE_BEHIND", "true");
+    }
+    let server = common::start().await;
+    let entry = json!({ "file_name": "a.txt", "file_path": "a.txt", "file_hash": "a1", "file_size": 5, "modified_time": 1 });
+    let res = common::sync(&server, json!({ "insert": [entry] }), &[("a.txt", b"hello")]).await;
+    assert_eq!(res.status(), 200);
+
+    let system_path: String = sqlx::query_scalar("SELECT system_path FROM filehash WHERE file_path = 'a.txt'")
+        .fetch_one(&server.pool)
+        .await
+        .unwrap();
+    let mut landed = false;
+    for _ in 0..50 {
+        if server.s3.head_object().bucket(common::BUCKET).key(&system_path).send().await.is_ok() {
+            landed = true;
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    assert!(landed, "the upload reaches the bucket in the background");
+    assert_eq!(std::fs::read_dir(dir.join("pending")).unwrap().count(), 0);
+
+    let download: Value = server.get("/download?file_path=a.txt").await.json().await.unwrap();
+    let url = download["url"].as_str().unwrap();
+    assert!(url.starts_with("/stream?"), "cached objects are streamed by the server, not {}", url);
+    let res = reqwest::get(server.url(url)).await.unwrap();
+    assert_eq!(res.status(), 200);
+    assert_eq!(res.bytes().await.unwrap().as_ref(), b"hello");
+
+    let _ = std::fs::remove_dir_all(dir);
+}
@@ -0,0 +1,60 @@
+mod common;
+
+use serde_json::{json, Value};
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn exported_catalog_imports_files_whose_objects_exist() {
+    let server = common::start().await;
+    let client = reqwest::Client::new();
+    let entry = json!({
+        "file_name": "notes.txt",
+        "file_path": "docs/notes.txt",
+        "file_hash": "aaa111",
+        "file_size": 5,
+        "modified_time": 1,
+        "tags": ["work"]
+    });
+    let res = common::sync(&server, json!({ "insert": [entry] }), &[("notes.txt", b"hello")]).await;
+    assert_eq!(res.status(), 200);
+
+    let res = server.get("/export").await;
+    assert_eq!(res.status(), 200);
+    let mut catalog: Value = res.json().await.unwrap();
+    assert_eq!(catalog["version"], 1);
+    assert_eq!(catalog["files"].as_array().unwrap().len(), 1);
+    let system_path = catalog["files"][0]["system_path"].as_str().unwrap().to_string();
+
+    // An entry whose object was never stored is skipped rather than imported.
+    let mut missing = catalog["files"][0].clone();
+    missing["file_path"] = json!("docs/missing.txt");
+    missing["system_path"] = json!("data/missing.txt");
+    catalog["files"].as_array_mut().unwrap().push(missing);
+
+    sqlx::query("DELETE FROM filehash").execute(&server.pool).await.unwrap();
+    let listing: Value = server.get("/get").await.json().await.unwrap();
+    assert_eq!(listing["total"], 0);
+
+    let res = client
+        .post(server.url("/import"))
+        .bearer_auth(common::ADMIN_TOKEN)
+        .json(&catalog)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 200);
+    let report: Value = res.json().await.unwrap();
+    assert_eq!(report["data"]["files_imported"], 1);
+    assert_eq!(report["data"]["files_skipped"][0]["file_path"], "docs/missing.txt");
+
+    let tagged: Value = server.get("/files?tag=work").await.json().await.unwrap();
+    assert_eq!(tagged["data"][0]["file_path"], "docs/notes.txt");
+
+    let head = server.s3
+        .head_object()
+        .bucket(common::BUCKET)
+        .key(&system_path)
+        .send()
+        .await;
+    assert!(head.is_ok(), "object should be in storage");
+}
@@ -0,0 +1,55 @@
+mod common;
+
+use pocket_protocol::{FailureCode, FileEntry, Operation, PocketClient, SyncRequest};
+use serde_json::{json, Value};
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn the_protocol_client_syncs_follows_changes_and_downloads() {
+    let server = common::start().await;
+    let client = PocketClient::new(server.url(""), common::ADMIN_TOKEN).with_device(common::DEVICE_ID);
+
+    let request = SyncRequest::new()
+        .insert(FileEntry::new("docs/a.txt", 1), b"alpha".to_vec())
+        .insert(FileEntry::new("docs/b.txt", 1), b"beta".to_vec());
+    let report = client.sync(request).await.unwrap();
+    assert_eq!(report.summary.succeeded, 2, "{:?}", report);
+    let inserted = report.results(Operation::Insert).success;
+    assert_eq!(inserted.len(), 2);
+    let b = inserted.iter().find(|f| f.file_path == "docs/b.txt").unwrap();
+    assert_eq!(b.version, Some(1));
+
+    let page = client.changes(None).await.unwrap();
+    assert_eq!(page.data.unwrap().len(), 2);
+    assert_eq!(client.download("docs/a.txt").await.unwrap(), b"alpha");
+
+    // A delete made against a version the file has moved on from is refused.
+    let report = client.sync(SyncRequest::new().delete("docs/b.txt", 7)).await.unwrap();
+    let conflict = &report.results(Operation::Delete).conflict[0];
+    assert_eq!(conflict.code, FailureCode::VersionMismatch);
+    assert_eq!(conflict.server.version, Some(1));
+
+    let report = client.sync(SyncRequest::new().rename("docs/a.txt", "docs/c.txt").delete("docs/b.txt", 1)).await.unwrap();
+    assert_eq!(report.summary.failed, 0, "{:?}", report);
+    let changes = client.changes(page.cursor).await.unwrap();
+    let mut changed: Vec<(String, bool)> = changes.data.unwrap().into_iter().map(|c| (c.file_path, c.deleted)).collect();
+    changed.sort();
+    assert_eq!(changed, [("docs/a.txt".into(), true), ("docs/b.txt".into(), true), ("docs/c.txt".into(), false)]);
+
+    let missing = client.download("docs/a.txt").await.unwrap_err();
+    assert!(matches!(missing, pocket_protocol::Error::Api { status, .. } if status == 404), "{}", missing);
+
+    // The client's payloads are the same as hand-rolled ones.
+    let entry = json!({ "file_name": "d.txt", "file_path": "d.txt", "file_hash": "d1", "file_size": 1, "modified_time": 1 });
+    let res = common::sync(&server, json!({ "insert": [entry] }), &[("d.txt", b"d")]).await;
+    assert_eq!(res.status(), 200);
+    let listing: Value = server.get("/get").await.json().await.unwrap();
+    assert_eq!(listing["data"].as_array().unwrap().len(), 2);
+
+    let system_path: String = sqlx::query_scalar("SELECT system_path FROM filehash WHERE file_path = 'docs/c.txt'")
+        .fetch_one(&server.pool)
+        .await
+        .unwrap();
+    let object = server.s3.get_object().bucket(common::BUCKET).key(&system_path).send().await.unwrap();
+    assert_eq!(object.body.collect().await.unwrap().into_bytes().as_ref(), b"alpha");
+}
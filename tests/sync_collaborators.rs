@@ -0,0 +1,98 @@
+mod common;
+
+use serde_json::{json, Value};
+
+fn entry(path: &str, hash: &str) -> Value {
+    json!({ "file_name": path, "file_path": path, "file_hash": hash, "file_size": 1, "modified_time": 1 })
+}
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn collaborators_reach_only_the_folders_shared_with_them() {
+    let server = common::start().await;
+    let client = reqwest::Client::new();
+    let admin = |req: reqwest::RequestBuilder| req.bearer_auth(common::ADMIN_TOKEN);
+
+    let res = admin(client.post(server.url("/admin/users")))
+        .json(&json!({ "username": "dave", "password": "hunter2" }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 201);
+    let session: Value = client
+        .post(server.url("/auth/login"))
+        .json(&json!({ "username": "dave", "password": "hunter2" }))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    let jwt = session["token"].as_str().unwrap().to_string();
+    let res = client
+        .post(server.url("/devices/register"))
+        .bearer_auth(&jwt)
+        .json(&json!({ "device_id": "dave-laptop", "name": "laptop" }))
+        .send()
+        .await
+        .unwrap();
+    assert!(res.status().is_success());
+
+    let payload = json!({ "insert": [entry("docs/a.txt", "a1"), entry("wiki/w.txt", "w1"), entry("private/p.txt", "p1")] });
+    let res = common::sync(&server, payload, &[("docs/a.txt", b"a"), ("wiki/w.txt", b"w"), ("private/p.txt", b"p")]).await;
+    assert_eq!(res.status(), 200);
+
+    for (folder, permission) in [("docs", "read"), ("wiki", "write")] {
+        let res = admin(client.post(server.url("/collaborators")))
+            .json(&json!({ "folder": folder, "username": "dave", "permission": permission }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), 201);
+    }
+
+    let as_dave = |path: &str| client.get(server.url(path)).bearer_auth(&jwt).send();
+    let listing: Value = as_dave("/get?owner=admin").await.unwrap().json().await.unwrap();
+    let mut paths: Vec<&str> = listing["data"].as_array().unwrap().iter().filter_map(|f| f["file_path"].as_str()).collect();
+    paths.sort();
+    assert_eq!(paths, ["docs/a.txt", "wiki/w.txt"]);
+
+    let own: Value = as_dave("/get").await.unwrap().json().await.unwrap();
+    assert!(own["data"].as_array().unwrap().is_empty());
+    assert_eq!(own["shared"].as_array().unwrap().len(), 2, "the owner's folders are listed as shared");
+
+    assert_eq!(as_dave("/download?owner=admin&file_path=docs/a.txt").await.unwrap().status(), 200);
+    assert_eq!(as_dave("/download?owner=admin&file_path=private/p.txt").await.unwrap().status(), 403);
+
+    let sync_as_dave = |payload: Value, path: &str, data: &'static [u8]| {
+        let form = reqwest::multipart::Form::new()
+            .text("payload", payload.to_string())
+            .part("files", reqwest::multipart::Part::bytes(data).file_name(path.to_string()));
+        client
+            .post(server.url("/sync?owner=admin"))
+            .bearer_auth(&jwt)
+            .header("X-Device-Id", "dave-laptop")
+            .multipart(form)
+            .send()
+    };
+    let res = sync_as_dave(json!({ "update": [entry("docs/a.txt", "a2")] }), "docs/a.txt", b"A").await.unwrap();
+    assert_eq!(res.status(), 403, "docs is read-only");
+    let res = sync_as_dave(json!({ "update": [entry("wiki/w.txt", "w2")] }), "wiki/w.txt", b"W").await.unwrap();
+    assert_eq!(res.status(), 200);
+
+    let (hash, system_path): (String, String) =
+        sqlx::query_as("SELECT f.file_hash, f.system_path FROM filehash f JOIN users u ON u.id = f.user_id WHERE u.username = 'admin' AND f.file_path = 'wiki/w.txt'")
+            .fetch_one(&server.pool)
+            .await
+            .unwrap();
+    assert_eq!(hash, "w2", "the change lands in the owner's tree");
+    let object = server.s3.get_object().bucket(common::BUCKET).key(&system_path).send().await.unwrap();
+    assert_eq!(object.body.collect().await.unwrap().into_bytes().as_ref(), b"W");
+
+    let grants: Value = server.get("/collaborators").await.json().await.unwrap();
+    for grant in grants["data"].as_array().unwrap() {
+        let res = admin(client.delete(server.url(&format!("/collaborators/{}", grant["id"])))).send().await.unwrap();
+        assert_eq!(res.status(), 204);
+    }
+    assert_eq!(as_dave("/get?owner=admin").await.unwrap().status(), 404);
+}
@@ -0,0 +1,96 @@
+mod common;
+
+use serde_json::{json, Value};
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn concurrent_syncs_apply_every_file_once() {
+    let server = common::start().await;
+    let entry = |path: &str, hash: &str| {
+        json!({ "file_name": path, "file_path": path, "file_hash": hash, "file_size": 5, "modified_time": 1 })
+    };
+
+    // One sync of many independent files: every one is processed and stored.
+    let names: Vec<String> = (0..12).map(|i| format!("f{}.txt", i)).collect();
+    let payload = json!({ "insert": names.iter().map(|n| entry(n, n)).collect::<Vec<_>>() });
+    let files: Vec<(&str, &[u8])> = names.iter().map(|n| (n.as_str(), &b"batch"[..])).collect();
+    let res = common::sync(&server, payload, &files).await;
+    assert_eq!(res.status(), 200);
+    let report: Value = res.json().await.unwrap();
+    assert_eq!(report["summary"]["succeeded"], names.len());
+    let rows: Vec<String> = sqlx::query_scalar("SELECT system_path FROM filehash WHERE file_path LIKE 'f%.txt'")
+        .fetch_all(&server.pool)
+        .await
+        .unwrap();
+    assert_eq!(rows.len(), names.len());
+    let listing: Value = server.get("/get").await.json().await.unwrap();
+    assert_eq!(listing["data"].as_array().unwrap().len(), names.len());
+    for key in rows {
+        let object = server.s3.get_object().bucket(common::BUCKET).key(key).send().await.unwrap();
+        assert_eq!(object.body.collect().await.unwrap().into_bytes().as_ref(), b"batch");
+    }
+
+    // Several devices insert the same path at once: one wins, the rest are told it exists.
+    let requests = (0..4).map(|i| {
+        let server = &server;
+        let payload = json!({ "insert": [entry("same.txt", &format!("h{}", i))] });
+        async move {
+            let res = common::sync(server, payload, &[("same.txt", b"racer")]).await;
+            (res.status().as_u16(), res.json::<Value>().await.unwrap())
+        }
+    });
+    let outcomes = futures::future::join_all(requests).await;
+
+    let winners: Vec<usize> = outcomes.iter().enumerate().filter(|(_, (status, _))| *status == 200).map(|(i, _)| i).collect();
+    assert_eq!(winners.len(), 1, "{:?}", outcomes);
+    let winner = winners[0];
+    for (i, (status, report)) in outcomes.iter().enumerate().filter(|(i, _)| *i != winner) {
+        assert_eq!(*status, 422, "request {}: {}", i, report);
+        assert_eq!(report["results"]["insert"]["failure"][0]["error"], "conflict: a file already exists at this path");
+    }
+
+    let hashes: Vec<String> = sqlx::query_scalar("SELECT file_hash FROM filehash WHERE file_path = 'same.txt'")
+        .fetch_all(&server.pool)
+        .await
+        .unwrap();
+    assert_eq!(hashes, [format!("h{}", winner)]);
+
+    // Every device then updates it from version 1 at once: one wins, the rest find
+    // it moved on, and only the content the winner replaced is kept as a version.
+    let contents: [&[u8]; 4] = [b"one!!", b"two!!", b"three", b"four!"];
+    let requests = contents.iter().enumerate().map(|(i, data)| {
+        let server = &server;
+        let mut file = entry("same.txt", &format!("u{}", i));
+        file["op"] = json!("update");
+        file["base_version"] = json!(1);
+        let payload = json!({ "schema_version": 4, "operations": [file] });
+        async move {
+            let res = common::sync(server, payload, &[("same.txt", data)]).await;
+            (res.status().as_u16(), res.json::<Value>().await.unwrap())
+        }
+    });
+    let outcomes = futures::future::join_all(requests).await;
+
+    let updaters: Vec<usize> = outcomes.iter().enumerate().filter(|(_, (status, _))| *status == 200).map(|(i, _)| i).collect();
+    assert_eq!(updaters.len(), 1, "{:?}", outcomes);
+    let updater = updaters[0];
+    assert_eq!(outcomes[updater].1["results"]["update"]["success"][0]["version"], 2);
+    for (i, (status, report)) in outcomes.iter().enumerate().filter(|(i, _)| *i != updater) {
+        assert_eq!(*status, 422, "request {}: {}", i, report);
+        let conflict = &report["results"]["update"]["conflict"][0];
+        assert_eq!(conflict["code"], "version_mismatch");
+        assert_eq!(conflict["server"]["version"], 2);
+    }
+
+    let (version, hash, system_path): (i64, String, String) =
+        sqlx::query_as("SELECT version, file_hash, system_path FROM filehash WHERE file_path = 'same.txt'")
+            .fetch_one(&server.pool)
+            .await
+            .unwrap();
+    assert_eq!((version, hash), (2, format!("u{}", updater)));
+    let object = server.s3.get_object().bucket(common::BUCKET).key(&system_path).send().await.unwrap();
+    assert_eq!(object.body.collect().await.unwrap().into_bytes().as_ref(), contents[updater]);
+    let listing: Value = server.get("/versions?path=same.txt").await.json().await.unwrap();
+    assert_eq!(listing["data"].as_array().unwrap().len(), 1);
+    assert_eq!(listing["data"][0]["file_hash"], format!("h{}", winner));
+}
@@ -0,0 +1,45 @@
+mod common;
+
+use serde_json::json;
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn untyped_upload_is_stored_with_its_sniffed_content_type() {
+    let server = common::start().await;
+    let data: &[u8] = b"%PDF-1.7\n%fake but recognisable\n";
+
+    let res = common::sync(
+        &server,
+        json!({
+            "insert": [{
+                "file_name": "scan",
+                "file_path": "docs/scan",
+                "file_hash": "def456",
+                "file_size": data.len(),
+                "modified_time": 1_700_000_000
+            }]
+        }),
+        &[("scan", data)],
+    )
+    .await;
+    assert_eq!(res.status(), 200);
+
+    let (system_path, content_type): (String, Option<String>) = sqlx::query_as(
+        "SELECT system_path, content_type FROM filehash WHERE file_path = $1"
+    )
+    .bind("docs/scan")
+    .fetch_one(&server.pool)
+    .await
+    .unwrap();
+    assert_eq!(content_type.as_deref(), Some("application/pdf"));
+
+    let object = server.s3
+        .head_object()
+        .bucket(common::BUCKET)
+        .key(&system_path)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(object.content_type(), Some("application/pdf"));
+    assert_eq!(server.get("/metadata?path=docs/scan").await.status(), 200);
+}
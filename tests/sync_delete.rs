@@ -0,0 +1,46 @@
+mod common;
+
+use serde_json::json;
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn delete_removes_row_and_object() {
+    let server = common::start().await;
+    let entry = json!({
+        "file_name": "photo.jpg",
+        "file_path": "pics/photo.jpg",
+        "file_hash": "f00d",
+        "file_size": 4,
+        "modified_time": 1_700_000_000
+    });
+
+    let res = common::sync(&server, json!({ "insert": [entry] }), &[("photo.jpg", b"\xff\xd8\xff\xe0")]).await;
+    assert_eq!(res.status(), 200);
+
+    let system_path: String = sqlx::query_scalar("SELECT system_path FROM filehash WHERE file_path = $1")
+        .bind("pics/photo.jpg")
+        .fetch_one(&server.pool)
+        .await
+        .unwrap();
+
+    let res = common::sync(&server, json!({ "delete": [entry] }), &[]).await;
+    assert_eq!(res.status(), 200);
+
+    let rows: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM filehash WHERE file_path = $1")
+        .bind("pics/photo.jpg")
+        .fetch_one(&server.pool)
+        .await
+        .unwrap();
+    assert_eq!(rows, 0);
+
+    let head = server.s3
+        .head_object()
+        .bucket(common::BUCKET)
+        .key(&system_path)
+        .send()
+        .await;
+    assert!(head.is_err(), "object should be gone from storage");
+
+    let res = server.get("/download?file_path=pics/photo.jpg").await;
+    assert_eq!(res.status(), 404);
+}
@@ -0,0 +1,26 @@
+mod common;
+
+use serde_json::json;
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn download_does_not_resolve_storage_keys() {
+    let server = common::start().await;
+    let payload = json!({
+        "insert": [{ "file_name": "notes.txt", "file_path": "docs/notes.txt", "file_hash": "abc123", "file_size": 5, "modified_time": 1 }]
+    });
+    let res = common::sync(&server, payload, &[("notes.txt", b"hello")]).await;
+    assert_eq!(res.status(), 200);
+
+    let res = server.get("/download?file_path=docs/notes.txt").await;
+    assert_eq!(res.status(), 200);
+
+    // The object exists under this key, but it is not a path any file was synced to.
+    let key: String = sqlx::query_scalar("SELECT system_path FROM filehash WHERE file_path = 'docs/notes.txt'")
+        .fetch_one(&server.pool)
+        .await
+        .unwrap();
+    server.s3.head_object().bucket(common::BUCKET).key(&key).send().await.unwrap();
+    let res = server.get(&format!("/download?file_path={}", key)).await;
+    assert_eq!(res.status(), 404);
+}
@@ -0,0 +1,56 @@
+mod common;
+
+use serde_json::{json, Value};
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn dry_runs_check_uploads_and_quota_without_storing_anything() {
+    let server = common::start().await;
+    let dry_run = |payload: Value, files: &[(&str, &'static [u8])]| {
+        let mut form = reqwest::multipart::Form::new().text("payload", payload.to_string());
+        for (name, data) in files {
+            form = form.part("files", reqwest::multipart::Part::bytes(*data).file_name(name.to_string()));
+        }
+        reqwest::Client::new()
+            .post(server.url("/sync?dry_run=true"))
+            .bearer_auth(common::ADMIN_TOKEN)
+            .header("X-Device-Id", common::DEVICE_ID)
+            .multipart(form)
+            .send()
+    };
+    // SHA-256 of "abc".
+    let abc = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+    let entry = |path: &str| json!({ "file_name": path, "file_path": path, "file_hash": abc, "file_size": 3, "modified_time": 1 });
+
+    let res = common::sync(&server, json!({ "insert": [entry("kept.txt")] }), &[("kept.txt", b"abc")]).await;
+    assert_eq!(res.status(), 200);
+
+    let payload = json!({ "insert": [entry("good.txt"), entry("bad.txt"), entry("kept.txt")] });
+    let res = dry_run(payload, &[("good.txt", b"abc"), ("bad.txt", b"xyz"), ("kept.txt", b"abc")]).await.unwrap();
+    assert_eq!(res.status(), 200);
+    assert_eq!(res.headers()["Dry-Run"], "true");
+    let report: Value = res.json().await.unwrap();
+    assert_eq!(report["dry_run"], true);
+    let failure = &report["results"]["insert"]["failure"][0];
+    assert_eq!(failure["file_path"], "bad.txt");
+    assert_eq!(failure["code"], "integrity_failure");
+    let skipped: Vec<&str> = report["results"]["insert"]["success"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .filter(|f| f["skipped"] == true)
+        .filter_map(|f| f["file_path"].as_str())
+        .collect();
+    assert_eq!(skipped, ["kept.txt"]);
+
+    let rows: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM filehash").fetch_one(&server.pool).await.unwrap();
+    assert_eq!(rows, 1, "a dry run records nothing");
+    let objects = server.s3.list_objects_v2().bucket(common::BUCKET).send().await.unwrap();
+    assert_eq!(objects.key_count(), Some(1), "a dry run stores nothing");
+
+    sqlx::query("UPDATE users SET quota_bytes = 4").execute(&server.pool).await.unwrap();
+    let res = dry_run(json!({ "insert": [entry("good.txt")] }), &[("good.txt", b"abc")]).await.unwrap();
+    assert_eq!(res.status(), 413, "the quota is checked too");
+    let usage: Value = server.get("/usage").await.json().await.unwrap();
+    assert_eq!(usage["bytes"], 3);
+}
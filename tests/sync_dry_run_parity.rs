@@ -0,0 +1,94 @@
+mod common;
+
+use serde_json::{json, Value};
+
+/// Each operation's outcome, as (list, file path, failure code) sorted.
+fn outcomes(report: &Value) -> Vec<(String, String, String)> {
+    let mut outcomes = Vec::new();
+    for (op, result) in report["results"].as_object().unwrap() {
+        for list in ["success", "failure"] {
+            for file in result[list].as_array().into_iter().flatten() {
+                let path = file["file_path"].as_str().unwrap_or_default().to_string();
+                let code = file["code"].as_str().unwrap_or_default().to_string();
+                outcomes.push((format!("{}.{}", op, list), path, code));
+            }
+        }
+    }
+    outcomes.sort();
+    outcomes
+}
+
+async fn dry_run(server: &common::TestServer, payload: &Value, files: &[(&str, &[u8])]) -> reqwest::Response {
+    let mut form = reqwest::multipart::Form::new().text("payload", payload.to_string());
+    for (name, data) in files {
+        form = form.part("files", reqwest::multipart::Part::bytes(data.to_vec()).file_name(name.to_string()));
+    }
+    reqwest::Client::new()
+        .post(server.url("/sync?dry_run=true"))
+        .bearer_auth(common::ADMIN_TOKEN)
+        .header("X-Device-Id", common::DEVICE_ID)
+        .multipart(form)
+        .send()
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn dry_run_predicts_what_the_sync_does() {
+    let server = common::start().await;
+    let entry = |path: &str, hash: &str, modified_time: i64| {
+        json!({ "file_name": path, "file_path": path, "file_hash": hash, "file_size": 3, "modified_time": modified_time })
+    };
+
+    let payload = json!({ "insert": [entry("a.txt", "h1", 5), entry("b.txt", "h1", 5)] });
+    let res = common::sync(&server, payload, &[("a.txt", b"old"), ("b.txt", b"old")]).await;
+    assert_eq!(res.status(), 200);
+
+    // An insert without a hash could never be stored, so both refuse the whole payload.
+    let unhashed = json!({ "insert": [{ "file_name": "d.txt", "file_path": "d.txt", "file_size": 3, "modified_time": 1 }] });
+    let res = dry_run(&server, &unhashed, &[("d.txt", b"new")]).await;
+    assert_eq!(res.status(), 400);
+    let predicted: Value = res.json().await.unwrap();
+    let res = common::sync(&server, unhashed, &[("d.txt", b"new")]).await;
+    assert_eq!(res.status(), 400);
+    let report: Value = res.json().await.unwrap();
+    assert_eq!(predicted, report);
+    assert_eq!(report["code"], "invalid_request");
+    assert_eq!(report["unhashed_paths"], json!(["d.txt"]));
+
+    // An update older than the server copy, an insert over an existing file, a new
+    // file, and a delete of one that isn't there.
+    let payload = json!({
+        "update": [entry("a.txt", "h2", 1)],
+        "insert": [entry("b.txt", "h2", 9), entry("c.txt", "h3", 9)],
+        "delete": [entry("missing.txt", "h4", 1)],
+    });
+    let files: [(&str, &[u8]); 3] = [("a.txt", b"new"), ("b.txt", b"new"), ("c.txt", b"new")];
+
+    let res = dry_run(&server, &payload, &files).await;
+    assert_eq!(res.status(), 200);
+    let predicted: Value = res.json().await.unwrap();
+
+    let res = common::sync(&server, payload, &files).await;
+    let applied: Value = res.json().await.unwrap();
+    assert_eq!(outcomes(&predicted), outcomes(&applied), "{} vs {}", predicted, applied);
+    assert_eq!(
+        outcomes(&applied),
+        [
+            ("delete.failure".into(), "missing.txt".into(), "not_found".into()),
+            ("insert.failure".into(), "b.txt".into(), "already_exists".into()),
+            ("insert.success".into(), "c.txt".into(), String::new()),
+            ("update.success".into(), "a.txt".into(), String::new()),
+        ]
+    );
+
+    let hash: String = sqlx::query_scalar("SELECT file_hash FROM filehash WHERE file_path = 'a.txt'")
+        .fetch_one(&server.pool)
+        .await
+        .unwrap();
+    assert_eq!(hash, "h2");
+    let objects = server.s3.list_objects_v2().bucket(common::BUCKET).send().await.unwrap();
+    assert!(objects.key_count().unwrap_or_default() >= 3);
+    assert_eq!(server.get("/metadata?path=c.txt").await.status(), 200);
+}
@@ -0,0 +1,46 @@
+mod common;
+
+use serde_json::{json, Value};
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn a_path_inserted_twice_fails_the_second_time() {
+    let server = common::start().await;
+    let entry = |hash: &str| {
+        json!({ "file_name": "a.txt", "file_path": "docs/a.txt", "file_hash": hash, "file_size": 5, "modified_time": 1 })
+    };
+    let payload = json!({ "insert": [entry("first"), entry("second")] });
+
+    let form = reqwest::multipart::Form::new().text("payload", payload.to_string());
+    let res = reqwest::Client::new()
+        .post(server.url("/sync?dry_run=true"))
+        .bearer_auth(common::ADMIN_TOKEN)
+        .header("X-Device-Id", common::DEVICE_ID)
+        .multipart(form.part("files", reqwest::multipart::Part::bytes(&b"hello"[..]).file_name("a.txt")))
+        .send()
+        .await
+        .unwrap();
+    let predicted: Value = res.json().await.unwrap();
+    assert_eq!(predicted["results"]["insert"]["failure"][0]["code"], "duplicate_path", "{}", predicted);
+
+    let res = common::sync(&server, payload, &[("a.txt", b"hello")]).await;
+    assert_eq!(res.status(), 207);
+    let report: Value = res.json().await.unwrap();
+    let insert = &report["results"]["insert"];
+    assert_eq!(insert["success"][0]["file_hash"], "first", "{}", report);
+    assert_eq!(insert["success"].as_array().unwrap().len(), 1);
+    let failure = &insert["failure"][0];
+    assert_eq!(failure["file_path"], "docs/a.txt");
+    assert_eq!(failure["code"], "duplicate_path");
+    assert_eq!(failure["error"], "file appears more than once in payload");
+
+    let (count, system_path): (i64, String) =
+        sqlx::query_as("SELECT COUNT(*) OVER (), system_path FROM filehash WHERE file_path = 'docs/a.txt'")
+            .fetch_one(&server.pool)
+            .await
+            .unwrap();
+    assert_eq!(count, 1);
+    let object = server.s3.get_object().bucket(common::BUCKET).key(&system_path).send().await.unwrap();
+    assert_eq!(object.body.collect().await.unwrap().into_bytes().as_ref(), b"hello");
+    assert_eq!(server.get("/metadata?path=docs/a.txt").await.status(), 200);
+}
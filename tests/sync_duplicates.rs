@@ -0,0 +1,61 @@
+mod common;
+
+use serde_json::{json, Value};
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn duplicate_copies_are_clustered_and_deleted() {
+    let server = common::start().await;
+    let entries: Vec<Value> = ["a.txt", "b.txt", "c.txt"]
+        .iter()
+        .enumerate()
+        .map(|(i, name)| json!({
+            "file_name": name,
+            "file_path": format!("copies/{}", name),
+            "file_hash": "aaa111",
+            "file_size": 5,
+            "modified_time": i + 1
+        }))
+        .collect();
+    let files: [(&str, &[u8]); 3] = [("a.txt", b"hello"), ("b.txt", b"hello"), ("c.txt", b"hello")];
+    let res = common::sync(&server, json!({ "insert": entries }), &files).await;
+    assert_eq!(res.status(), 200);
+
+    let found: Value = server.get("/duplicates").await.json().await.unwrap();
+    assert_eq!(found["total"], 1);
+    assert_eq!(found["reclaimable_bytes"], 10);
+    assert_eq!(found["data"][0]["files"][0]["file_path"], "copies/a.txt");
+
+    let res = reqwest::Client::new()
+        .post(server.url("/duplicates/resolve"))
+        .bearer_auth(common::ADMIN_TOKEN)
+        .json(&json!({
+            "keep": "copies/a.txt",
+            "remove": ["copies/b.txt", "copies/c.txt", "copies/a.txt"],
+            "action": "delete"
+        }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 200);
+    let resolved: Value = res.json().await.unwrap();
+    assert_eq!(resolved["success"], json!(["copies/b.txt", "copies/c.txt"]));
+    assert_eq!(resolved["failure"][0]["file_path"], "copies/a.txt");
+    assert_eq!(resolved["reclaimed_bytes"], 10);
+
+    let left: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM filehash")
+        .fetch_one(&server.pool)
+        .await
+        .unwrap();
+    assert_eq!(left, 1);
+    let found: Value = server.get("/duplicates").await.json().await.unwrap();
+    assert_eq!(found["total"], 0);
+
+    // Deleted copies go to the trash, and the kept one's object is untouched.
+    let system_path: String = sqlx::query_scalar("SELECT system_path FROM filehash")
+        .fetch_one(&server.pool)
+        .await
+        .unwrap();
+    let object = server.s3.get_object().bucket(common::BUCKET).key(&system_path).send().await.unwrap();
+    assert_eq!(&object.body.collect().await.unwrap().into_bytes()[..], b"hello");
+}
@@ -0,0 +1,47 @@
+mod common;
+
+use serde_json::{json, Value};
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn failures_carry_stable_codes() {
+    let server = common::start().await;
+    let entry = |path: &str, hash: &str| {
+        json!({ "file_name": "a.txt", "file_path": path, "file_hash": hash, "file_size": 1, "modified_time": 1 })
+    };
+
+    let res = common::sync(&server, json!({ "insert": [entry("a.txt", "a1")] }), &[("a.txt", b"a")]).await;
+    assert_eq!(res.status(), 200);
+
+    let payload = json!({
+        "insert": [entry("a.txt", "a2")],
+        "update": [entry("missing.txt", "m1")],
+    });
+    let res = common::sync(&server, payload, &[("a.txt", b"b")]).await;
+    assert_eq!(res.status(), 422, "nothing applied");
+    let report: Value = res.json().await.unwrap();
+    assert_eq!(report["results"]["insert"]["failure"][0]["code"], "already_exists");
+    assert_eq!(report["results"]["update"]["failure"][0]["code"], "not_found");
+
+    let mut stale = entry("a.txt", "a3");
+    stale["base_hash"] = json!("old");
+    let res = common::sync(&server, json!({ "update": [stale] }), &[("a.txt", b"c")]).await;
+    let report: Value = res.json().await.unwrap();
+    assert_eq!(report["results"]["update"]["conflict"][0]["code"], "conflict");
+
+    let payload = json!({ "insert": [entry("b.txt", "b1")], "delete": [entry("b.txt", "b1")] });
+    let res = common::sync(&server, payload, &[("a.txt", b"b")]).await;
+    assert_eq!(res.status(), 400);
+    let body: Value = res.json().await.unwrap();
+    assert_eq!(body["code"], "duplicate_path");
+
+    let system_path: String = sqlx::query_scalar("SELECT system_path FROM filehash WHERE file_path = 'a.txt'")
+        .fetch_one(&server.pool)
+        .await
+        .unwrap();
+    let object = server.s3.get_object().bucket(common::BUCKET).key(&system_path).send().await.unwrap();
+    assert_eq!(object.body.collect().await.unwrap().into_bytes().as_ref(), b"a", "failed files leave the stored copy");
+
+    let listing: Value = server.get("/get").await.json().await.unwrap();
+    assert_eq!(listing["data"].as_array().unwrap().len(), 1);
+}
@@ -0,0 +1,44 @@
+mod common;
+
+use serde_json::{json, Value};
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn one_files_state_is_reported_with_a_version_that_moves_on_with_it() {
+    let server = common::start().await;
+    let entry = |hash: &str, size: i64, modified: i64| {
+        json!({ "file_name": "a.txt", "file_path": "docs/a.txt", "file_hash": hash, "file_size": size, "modified_time": modified })
+    };
+
+    let res = common::sync(&server, json!({ "insert": [entry("h1", 1, 1)] }), &[("docs/a.txt", b"a")]).await;
+    assert_eq!(res.status(), 200);
+    let res = server.get("/file?path=docs/a.txt").await;
+    assert_eq!(res.status(), 200);
+    let etag = res.headers()["etag"].to_str().unwrap().to_string();
+    let first: Value = res.json().await.unwrap();
+    assert_eq!(first["file_hash"], "h1");
+    assert_eq!(first["file_size"], 1);
+
+    let res = reqwest::Client::new()
+        .get(server.url("/file?path=docs/a.txt"))
+        .bearer_auth(common::ADMIN_TOKEN)
+        .header("If-None-Match", &etag)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 304);
+
+    let res = common::sync(&server, json!({ "update": [entry("h2", 2, 2)] }), &[("docs/a.txt", b"ab")]).await;
+    assert_eq!(res.status(), 200);
+    let second: Value = server.get("/file?path=docs/a.txt").await.json().await.unwrap();
+    assert_eq!(second["file_hash"], "h2");
+    assert!(second["version_id"].as_i64() > first["version_id"].as_i64());
+    assert_eq!(server.get("/file?path=docs/missing.txt").await.status(), 404);
+
+    let system_path: String = sqlx::query_scalar("SELECT system_path FROM filehash WHERE file_path = 'docs/a.txt'")
+        .fetch_one(&server.pool)
+        .await
+        .unwrap();
+    let object = server.s3.get_object().bucket(common::BUCKET).key(&system_path).send().await.unwrap();
+    assert_eq!(object.body.collect().await.unwrap().into_bytes().as_ref(), b"ab");
+}
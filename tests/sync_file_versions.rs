@@ -0,0 +1,92 @@
+mod common;
+
+use serde_json::{json, Value};
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn updates_and_deletes_apply_only_at_the_version_they_name() {
+    let server = common::start().await;
+
+    let entry = |path: &str, hash: &str, mtime: i64| {
+        json!({
+            "file_name": path,
+            "file_path": path,
+            "file_hash": hash,
+            "file_size": 5,
+            "modified_time": mtime
+        })
+    };
+    let res = common::sync(
+        &server,
+        json!({ "insert": [entry("a.txt", "aaa1", 1), entry("b.txt", "bbb1", 1)] }),
+        &[("a.txt", b"one!!"), ("b.txt", b"two!!")],
+    )
+    .await;
+    assert_eq!(res.status(), 200);
+    let report: Value = res.json().await.unwrap();
+    assert_eq!(report["results"]["insert"]["success"][0]["version"], 1);
+
+    // From payload version 4 every update and delete names its base.
+    let res = common::sync(
+        &server,
+        json!({ "schema_version": 4, "operations": [{ "op": "delete", "file_path": "b.txt" }] }),
+        &[],
+    )
+    .await;
+    assert_eq!(res.status(), 400);
+
+    let update = |base_version: i64| {
+        let mut file = entry("a.txt", "aaa2", 2);
+        file["op"] = json!("update");
+        file["base_version"] = json!(base_version);
+        json!({ "schema_version": 4, "operations": [file] })
+    };
+    let res = common::sync(&server, update(1), &[("a.txt", b"new!!")]).await;
+    assert_eq!(res.status(), 200);
+    let report: Value = res.json().await.unwrap();
+    assert_eq!(report["results"]["update"]["success"][0]["version"], 2);
+
+    // A second device still on version 1 is refused, and told where the file is.
+    let res = common::sync(&server, update(1), &[("a.txt", b"old!!")]).await;
+    assert_eq!(res.status(), 422);
+    let report: Value = res.json().await.unwrap();
+    let conflict = &report["results"]["update"]["conflict"][0];
+    assert_eq!(conflict["code"], "version_mismatch");
+    assert_eq!(conflict["base_version"], 1);
+    assert_eq!(conflict["server"]["version"], 2);
+
+    let delete = |base_version: i64| {
+        json!({ "schema_version": 4, "operations": [{ "op": "delete", "file_path": "b.txt", "base_version": base_version }] })
+    };
+    let res = common::sync(&server, delete(3), &[]).await;
+    assert_eq!(res.status(), 422);
+    let report: Value = res.json().await.unwrap();
+    assert_eq!(report["results"]["delete"]["conflict"][0]["code"], "version_mismatch");
+    assert_eq!(server.get("/metadata?path=b.txt").await.status(), 200);
+
+    assert_eq!(common::sync(&server, delete(1), &[]).await.status(), 200);
+    assert_eq!(server.get("/metadata?path=b.txt").await.status(), 404);
+
+    // Moves count as a change too.
+    let res = common::sync(
+        &server,
+        json!({ "schema_version": 4, "operations": [{ "op": "move", "from_path": "a.txt", "file_path": "c.txt" }] }),
+        &[],
+    )
+    .await;
+    assert_eq!(res.status(), 200);
+    let version: i64 = sqlx::query_scalar("SELECT version FROM filehash WHERE file_path = 'c.txt'")
+        .fetch_one(&server.pool)
+        .await
+        .unwrap();
+    assert_eq!(version, 3);
+    let metadata: Value = server.get("/metadata?path=c.txt").await.json().await.unwrap();
+    assert_eq!(metadata["version"], 3);
+
+    let system_path: String = sqlx::query_scalar("SELECT system_path FROM filehash WHERE file_path = 'c.txt'")
+        .fetch_one(&server.pool)
+        .await
+        .unwrap();
+    let object = server.s3.get_object().bucket(common::BUCKET).key(&system_path).send().await.unwrap();
+    assert_eq!(object.body.collect().await.unwrap().into_bytes().as_ref(), b"new!!");
+}
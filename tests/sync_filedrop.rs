@@ -0,0 +1,88 @@
+mod common;
+
+use reqwest::multipart::{Form, Part};
+use serde_json::{json, Value};
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn file_drops_take_files_into_the_owners_folder() {
+    let server = common::start().await;
+    let res = common::sync(
+        &server,
+        json!({ "insert": [{
+            "file_name": "report.txt",
+            "file_path": "inbox/report.txt",
+            "file_hash": "aaa111",
+            "file_size": 5,
+            "modified_time": 1
+        }] }),
+        &[("report.txt", b"mine!")],
+    )
+    .await;
+    assert_eq!(res.status(), 200);
+
+    let client = reqwest::Client::new();
+    let res = client
+        .post(server.url("/filedrop"))
+        .bearer_auth(common::ADMIN_TOKEN)
+        .json(&json!({ "folder": "/inbox/", "expires_in_secs": 3600, "max_file_bytes": 10, "max_files": 2 }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 201);
+    let created: Value = res.json().await.unwrap();
+    assert_eq!(created["folder"], "inbox");
+    let url = server.url(&format!("/drop/{}", created["token"].as_str().unwrap()));
+
+    // Anyone with the link gets the form, without a token.
+    let anonymous = reqwest::Client::new();
+    let page = anonymous.get(&url).send().await.unwrap();
+    assert_eq!(page.status(), 200);
+    assert!(page.text().await.unwrap().contains("multipart/form-data"));
+
+    let drop = |file_name: &'static str, data: &'static [u8]| {
+        let form = Form::new()
+            .text("name", "Alice")
+            .part("file", Part::bytes(data).file_name(file_name));
+        anonymous.post(&url).multipart(form).send()
+    };
+
+    // A taken name gets a number rather than replacing the owner's file.
+    let res = drop("report.txt", b"theirs").await.unwrap();
+    assert_eq!(res.status(), 201);
+    let received: Value = res.json().await.unwrap();
+    assert_eq!(received["data"][0]["file_name"], "report (2).txt");
+    let metadata: Value = server.get("/metadata?path=inbox/report (2).txt").await.json().await.unwrap();
+    assert_eq!(metadata["tags"], json!(["filedrop"]));
+    assert_eq!(metadata["metadata"]["dropped_by"], "Alice");
+    let mine: Value = server.get("/metadata?path=inbox/report.txt").await.json().await.unwrap();
+    assert_eq!(mine["file_size"], 5);
+
+    let system_path: String =
+        sqlx::query_scalar("SELECT system_path FROM filehash WHERE file_path = 'inbox/report (2).txt'")
+            .fetch_one(&server.pool)
+            .await
+            .unwrap();
+    let object = server.s3.get_object().bucket(common::BUCKET).key(&system_path).send().await.unwrap();
+    assert_eq!(object.body.collect().await.unwrap().into_bytes().as_ref(), b"theirs");
+
+    // Files over the link's limit are refused, and don't use up a slot.
+    let res = drop("big.bin", b"far too many bytes").await.unwrap();
+    assert_eq!(res.status(), 413);
+    assert_eq!(server.get("/metadata?path=inbox/big.bin").await.status(), 404);
+
+    assert_eq!(drop("notes.txt", b"notes").await.unwrap().status(), 201);
+    assert_eq!(drop("more.txt", b"more").await.unwrap().status(), 410);
+    assert_eq!(anonymous.get(&url).send().await.unwrap().status(), 410);
+
+    let drops: Value = server.get("/filedrop").await.json().await.unwrap();
+    assert_eq!(drops["data"][0]["file_count"], 2);
+
+    let res = client
+        .delete(server.url(&format!("/filedrop/{}", created["id"])))
+        .bearer_auth(common::ADMIN_TOKEN)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 204);
+}
@@ -0,0 +1,60 @@
+mod common;
+
+use serde_json::{json, Value};
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn folder_shares_serve_a_gallery_page() {
+    let server = common::start().await;
+    let entry = |path: &str, hash: &str, size: usize| json!({
+        "file_name": path.rsplit('/').next().unwrap(),
+        "file_path": path,
+        "file_hash": hash,
+        "file_size": size,
+        "modified_time": 1
+    });
+    let res = common::sync(
+        &server,
+        json!({ "insert": [entry("album/notes.txt", "aaa111", 5), entry("album/day 2/more.txt", "bbb222", 4)] }),
+        &[("notes.txt", b"hello"), ("more.txt", b"more")],
+    )
+    .await;
+    assert_eq!(res.status(), 200);
+
+    let share: Value = reqwest::Client::new()
+        .post(server.url("/share"))
+        .bearer_auth(common::ADMIN_TOKEN)
+        .json(&json!({ "file_path": "album", "folder": true }))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    let token = share["token"].as_str().unwrap();
+    assert!(share["url"].as_str().unwrap().ends_with(&format!("/s/{}/", token)));
+
+    // The bare link is sent on to the folder's page.
+    let page = server.get(&format!("/s/{}", token)).await;
+    assert_eq!(page.status(), 200);
+    let html = page.text().await.unwrap();
+    assert!(html.contains(r#"<a href="day%202/">day 2/</a>"#));
+    assert!(html.contains(r#"<a href="notes.txt">notes.txt</a>"#));
+
+    let file = server.get(&format!("/s/{}/day%202/more.txt", token)).await;
+    assert_eq!(file.status(), 200);
+    let system_path: String = sqlx::query_scalar("SELECT system_path FROM filehash WHERE file_path = $1")
+        .bind("album/day 2/more.txt")
+        .fetch_one(&server.pool)
+        .await
+        .unwrap();
+    let object = server.s3
+        .get_object()
+        .bucket(common::BUCKET)
+        .key(&system_path)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(file.bytes().await.unwrap(), object.body.collect().await.unwrap().into_bytes());
+    assert_eq!(server.get(&format!("/s/{}/missing/", token)).await.status(), 404);
+}
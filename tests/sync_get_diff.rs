@@ -0,0 +1,113 @@
+mod common;
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+/// The digests of every folder holding `files`, computed the way a client would.
+fn digests(files: &[(&str, &str)]) -> BTreeMap<String, String> {
+    let mut folders: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+    folders.insert(String::new(), BTreeSet::new());
+    for (path, hash) in files {
+        let (parent, name) = path.rsplit_once('/').unwrap_or(("", path));
+        folders.entry(parent.to_string()).or_default().insert(format!("f {}\t{}\n", name, hash));
+        let mut folder = parent;
+        while !folder.is_empty() {
+            folders.entry(folder.to_string()).or_default();
+            folder = folder.rsplit_once('/').map_or("", |(up, _)| up);
+        }
+    }
+
+    let mut digests: BTreeMap<String, String> = BTreeMap::new();
+    let mut paths: Vec<String> = folders.keys().cloned().collect();
+    paths.sort_by_key(|path| std::cmp::Reverse(if path.is_empty() { 0 } else { path.matches('/').count() + 1 }));
+    for path in paths {
+        let mut lines = folders[&path].clone();
+        for (sub, digest) in &digests {
+            let (parent, name) = sub.rsplit_once('/').unwrap_or(("", sub.as_str()));
+            if !sub.is_empty() && parent == path {
+                lines.insert(format!("d {}\t{}\n", name, digest));
+            }
+        }
+        let digest = hex::encode(Sha256::digest(lines.into_iter().collect::<String>()));
+        digests.insert(path, digest);
+    }
+    digests
+}
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn diffs_list_only_the_folders_that_differ() {
+    let server = common::start().await;
+    let entry = |path: &str, hash: &str| {
+        json!({
+            "file_name": path.rsplit('/').next().unwrap(),
+            "file_path": path,
+            "file_hash": hash,
+            "file_size": 1,
+            "modified_time": 1
+        })
+    };
+    let res = common::sync(
+        &server,
+        json!({ "insert": [
+            entry("docs/a.txt", "aaa"),
+            entry("docs/old/b.txt", "bbb"),
+            entry("photos/2024/c.jpg", "ccc"),
+            entry("top.txt", "ttt")
+        ] }),
+        &[("a.txt", b"a"), ("b.txt", b"b"), ("c.jpg", b"c"), ("top.txt", b"t")],
+    )
+    .await;
+    assert_eq!(res.status(), 200);
+
+    let client = reqwest::Client::new();
+    let diff = |digests: BTreeMap<String, String>| {
+        client
+            .post(server.url("/get/diff"))
+            .bearer_auth(common::ADMIN_TOKEN)
+            .json(&json!({ "digests": digests }))
+            .send()
+    };
+
+    // A client holding the same files is told nothing.
+    let same = digests(&[("docs/a.txt", "aaa"), ("docs/old/b.txt", "bbb"), ("photos/2024/c.jpg", "ccc"), ("top.txt", "ttt")]);
+    let res = diff(same.clone()).await.unwrap();
+    assert_eq!(res.status(), 200);
+    let body: Value = res.json().await.unwrap();
+    assert_eq!(body["digest"], same[""].as_str());
+    assert_eq!(body["folders"], json!([]));
+    assert_eq!(body["data"], json!([]));
+
+    // One that changed a file under docs/old and kept a folder the server never had
+    // hears only of the folders on the way down to it.
+    let changed = digests(&[
+        ("docs/a.txt", "aaa"),
+        ("docs/old/b.txt", "stale"),
+        ("photos/2024/c.jpg", "ccc"),
+        ("top.txt", "ttt"),
+        ("gone/deep/x.txt", "xxx"),
+    ]);
+    let body: Value = diff(changed).await.unwrap().json().await.unwrap();
+    assert_eq!(body["folders"], json!(["", "docs", "docs/old"]));
+    let paths: Vec<&str> = body["data"].as_array().unwrap().iter().map(|f| f["file_path"].as_str().unwrap()).collect();
+    assert_eq!(paths, ["docs/a.txt", "docs/old/b.txt", "top.txt"]);
+    assert_eq!(body["data"][1]["file_hash"], "bbb");
+    assert_eq!(body["removed"], json!(["gone"]));
+
+    // Folders left out are listed in full.
+    let body: Value = diff(BTreeMap::new()).await.unwrap().json().await.unwrap();
+    assert_eq!(body["data"].as_array().unwrap().len(), 4);
+    assert_eq!(body["folders"], json!(["", "docs", "docs/old", "photos", "photos/2024"]));
+
+    let stored: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM filehash").fetch_one(&server.pool).await.unwrap();
+    assert_eq!(stored, 4);
+    let system_path: String = sqlx::query_scalar("SELECT system_path FROM filehash WHERE file_path = 'top.txt'")
+        .fetch_one(&server.pool)
+        .await
+        .unwrap();
+    server.s3.head_object().bucket(common::BUCKET).key(&system_path).send().await.unwrap();
+    let listing: Value = server.get("/get").await.json().await.unwrap();
+    assert_eq!(listing["total"], 4);
+}
@@ -0,0 +1,66 @@
+mod common;
+
+use serde_json::{json, Value};
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn insert_is_stored_listed_and_downloadable() {
+    let server = common::start().await;
+    let data: &[u8] = b"hello pocket";
+
+    let res = common::sync(
+        &server,
+        json!({
+            "insert": [{
+                "file_name": "notes.txt",
+                "file_path": "docs/notes.txt",
+                "file_hash": "abc123",
+                "file_size": data.len(),
+                "modified_time": 1_700_000_000
+            }]
+        }),
+        &[("notes.txt", data)],
+    )
+    .await;
+    assert_eq!(res.status(), 200);
+    let report: Value = res.json().await.unwrap();
+    assert_eq!(report["summary"]["succeeded"], 1);
+
+    let (system_path, file_hash): (String, Option<String>) = sqlx::query_as(
+        "SELECT system_path, file_hash FROM filehash WHERE file_path = $1"
+    )
+    .bind("docs/notes.txt")
+    .fetch_one(&server.pool)
+    .await
+    .unwrap();
+    assert!(system_path.starts_with("data/") && system_path.ends_with("/notes.txt"));
+    assert_eq!(file_hash.as_deref(), Some("abc123"));
+
+    let object = server.s3
+        .get_object()
+        .bucket(common::BUCKET)
+        .key(&system_path)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(object.body.collect().await.unwrap().into_bytes().as_ref(), data);
+
+    let listing: Value = server.get("/get").await.json().await.unwrap();
+    let paths: Vec<&str> = listing["data"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .filter_map(|f| f["file_path"].as_str())
+        .collect();
+    assert_eq!(paths, ["docs/notes.txt"]);
+
+    let download: Value = server
+        .get("/download?file_path=docs/notes.txt")
+        .await
+        .json()
+        .await
+        .unwrap();
+    let url = download["url"].as_str().unwrap();
+    let bytes = reqwest::get(url).await.unwrap().bytes().await.unwrap();
+    assert_eq!(bytes.as_ref(), data);
+}
@@ -0,0 +1,86 @@
+mod common;
+
+use std::time::Duration;
+
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn integrity_checks_flag_missing_and_corrupted_objects() {
+    let server = common::start().await;
+    let entry = |path: &str, data: &[u8]| {
+        json!({
+            "file_name": path,
+            "file_path": path,
+            "file_hash": hex::encode(Sha256::digest(data)),
+            "file_size": data.len(),
+            "modified_time": 1
+        })
+    };
+    let payload = json!({ "insert": [entry("a.txt", b"alpha"), entry("b.txt", b"bravo"), entry("c.txt", b"charlie")] });
+    let res = common::sync(&server, payload, &[("a.txt", b"alpha"), ("b.txt", b"bravo"), ("c.txt", b"charlie")]).await;
+    assert_eq!(res.status(), 200);
+
+    let key = |path: &'static str| {
+        let pool = server.pool.clone();
+        async move {
+            sqlx::query_scalar::<_, String>("SELECT system_path FROM filehash WHERE file_path = $1")
+                .bind(path)
+                .fetch_one(&pool)
+                .await
+                .unwrap()
+        }
+    };
+    let (a, b) = (key("a.txt").await, key("b.txt").await);
+    server.s3.put_object().bucket(common::BUCKET).key(&a).body(b"alphx".to_vec().into()).send().await.unwrap();
+    server.s3.delete_object().bucket(common::BUCKET).key(&b).send().await.unwrap();
+
+    let check = || async {
+        let res = reqwest::Client::new()
+            .post(server.url("/admin/integrity"))
+            .bearer_auth(common::ADMIN_TOKEN)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), 202);
+        let accepted: Value = res.json().await.unwrap();
+        loop {
+            let job: Value = server.get(accepted["status_url"].as_str().unwrap()).await.json().await.unwrap();
+            if job["status"] != "pending" && job["status"] != "running" {
+                assert_eq!(job["status"], "completed");
+                return job["result"].clone();
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    };
+
+    let report = check().await;
+    assert_eq!(report["sampled"], 3);
+    assert_eq!(report["verified"], 1);
+    assert_eq!(report["corrupted"], json!(["a.txt"]));
+    assert_eq!(report["missing"], json!(["b.txt"]));
+
+    let issues: Value = server.get("/admin/integrity").await.json().await.unwrap();
+    let mut kinds: Vec<(&str, &str)> = issues["data"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|i| (i["file_path"].as_str().unwrap(), i["kind"].as_str().unwrap()))
+        .collect();
+    kinds.sort();
+    assert_eq!(kinds, [("a.txt", "corrupted"), ("b.txt", "missing")]);
+
+    // Repaired objects are cleared on the next check.
+    server.s3.put_object().bucket(common::BUCKET).key(&a).body(b"alpha".to_vec().into()).send().await.unwrap();
+    let report = check().await;
+    assert_eq!(report["verified"], 2);
+    let issues: Value = server.get("/admin/integrity").await.json().await.unwrap();
+    assert_eq!(issues["data"].as_array().unwrap().len(), 1);
+
+    let res = common::sync(&server, json!({ "delete": [entry("b.txt", b"bravo")] }), &[]).await;
+    assert_eq!(res.status(), 200);
+    check().await;
+    let issues: Value = server.get("/admin/integrity").await.json().await.unwrap();
+    assert!(issues["data"].as_array().unwrap().is_empty(), "deleted files' issues are dropped");
+}
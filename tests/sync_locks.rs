@@ -0,0 +1,92 @@
+mod common;
+
+use serde_json::{json, Value};
+
+const OTHER_DEVICE: &str = "laptop";
+
+async fn lock(server: &common::TestServer, device_id: &str, file_path: &str) -> reqwest::Response {
+    reqwest::Client::new()
+        .post(server.url("/lock"))
+        .bearer_auth(common::ADMIN_TOKEN)
+        .header("X-Device-Id", device_id)
+        .json(&json!({ "file_path": file_path }))
+        .send()
+        .await
+        .expect("Lock request failed")
+}
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn sync_refuses_updates_of_a_file_another_device_locked() {
+    let server = common::start().await;
+    let res = common::sync(
+        &server,
+        json!({
+            "insert": [{
+                "file_name": "notes.txt",
+                "file_path": "docs/notes.txt",
+                "file_hash": "aaa111",
+                "file_size": 5,
+                "modified_time": 1
+            }]
+        }),
+        &[("notes.txt", b"first")],
+    )
+    .await;
+    assert_eq!(res.status(), 200);
+
+    let res = reqwest::Client::new()
+        .post(server.url("/devices/register"))
+        .bearer_auth(common::ADMIN_TOKEN)
+        .json(&json!({ "device_id": OTHER_DEVICE, "name": "Laptop" }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 201);
+    let res = lock(&server, OTHER_DEVICE, "docs/notes.txt").await;
+    assert_eq!(res.status(), 200);
+    assert_eq!(lock(&server, common::DEVICE_ID, "docs/notes.txt").await.status(), 409);
+
+    let update = json!({
+        "update": [{
+            "file_name": "notes.txt",
+            "file_path": "docs/notes.txt",
+            "file_hash": "bbb222",
+            "file_size": 6,
+            "modified_time": 2
+        }]
+    });
+    let res = common::sync(&server, update.clone(), &[("notes.txt", b"second")]).await;
+    assert_eq!(res.status(), 422);
+    let report: Value = res.json().await.unwrap();
+    assert_eq!(report["summary"]["locked"], 1);
+    assert_eq!(report["results"]["update"]["locked"][0]["lock"]["device_id"], OTHER_DEVICE);
+
+    // The upload was skipped, so the stored copy is untouched.
+    let system_path: String = sqlx::query_scalar("SELECT system_path FROM filehash WHERE file_path = $1")
+        .bind("docs/notes.txt")
+        .fetch_one(&server.pool)
+        .await
+        .unwrap();
+    let object = server.s3
+        .get_object()
+        .bucket(common::BUCKET)
+        .key(&system_path)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(object.body.collect().await.unwrap().into_bytes().as_ref(), b"first");
+    let metadata: Value = server.get("/metadata?path=docs/notes.txt").await.json().await.unwrap();
+    assert_eq!(metadata["file_hash"], "aaa111");
+
+    let res = reqwest::Client::new()
+        .delete(server.url("/lock?file_path=docs/notes.txt"))
+        .bearer_auth(common::ADMIN_TOKEN)
+        .header("X-Device-Id", OTHER_DEVICE)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 204);
+    let res = common::sync(&server, update, &[("notes.txt", b"second")]).await;
+    assert_eq!(res.status(), 200);
+}
@@ -0,0 +1,59 @@
+mod common;
+
+use serde_json::{json, Value};
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn maintenance_mode_freezes_writes_but_not_reads() {
+    let server = common::start().await;
+    let entry = |name: &str, hash: &str| json!({
+        "file_name": name,
+        "file_path": format!("docs/{}", name),
+        "file_hash": hash,
+        "file_size": 5,
+        "modified_time": 1
+    });
+    let res = common::sync(&server, json!({ "insert": [entry("a.txt", "aaa111")] }), &[("a.txt", b"hello")]).await;
+    assert_eq!(res.status(), 200);
+
+    let set_maintenance = |body: Value| {
+        reqwest::Client::new()
+            .post(server.url("/admin/maintenance"))
+            .bearer_auth(common::ADMIN_TOKEN)
+            .json(&body)
+            .send()
+    };
+    let res = set_maintenance(json!({ "enabled": true, "reason": "bucket move" })).await.unwrap();
+    assert_eq!(res.status(), 200);
+
+    let res = common::sync(&server, json!({ "insert": [entry("b.txt", "bbb222")] }), &[("b.txt", b"world")]).await;
+    assert_eq!(res.status(), 503);
+    let body: Value = res.json().await.unwrap();
+    assert_eq!(body["code"], "maintenance");
+    assert_eq!(body["reason"], "bucket move");
+
+    let res = server.get("/download?file_path=docs/a.txt").await;
+    assert_eq!(res.status(), 200);
+    assert_eq!(res.bytes().await.unwrap().as_ref(), b"hello");
+    let listing: Value = server.get("/get").await.json().await.unwrap();
+    assert_eq!(listing["total"], 1);
+
+    let res = set_maintenance(json!({ "enabled": false })).await.unwrap();
+    assert_eq!(res.status(), 200);
+    let res = common::sync(&server, json!({ "insert": [entry("b.txt", "bbb222")] }), &[("b.txt", b"world")]).await;
+    assert_eq!(res.status(), 200);
+
+    let system_path: String = sqlx::query_scalar("SELECT system_path FROM filehash WHERE file_path = $1")
+        .bind("docs/b.txt")
+        .fetch_one(&server.pool)
+        .await
+        .unwrap();
+    let object = server.s3
+        .get_object()
+        .bucket(common::BUCKET)
+        .key(&system_path)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(object.body.collect().await.unwrap().into_bytes().as_ref(), b"world");
+}
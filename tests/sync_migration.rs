@@ -0,0 +1,63 @@
+mod common;
+
+use std::{env, time::Duration};
+
+use serde_json::{json, Value};
+
+const TARGET_BUCKET: &str = "pocket-migrated";
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn migration_job_copies_objects_to_the_new_bucket() {
+    // SAFETY: each test binary runs a single test, and the server isn't started yet.
+    unsafe {
+        env::set_var("MIGRATION_BUCKET", TARGET_BUCKET);
+    }
+    let server = common::start().await;
+    let entry = json!({
+        "file_name": "notes.txt",
+        "file_path": "docs/notes.txt",
+        "file_hash": "aaa111",
+        "file_size": 5,
+        "modified_time": 1
+    });
+    let res = common::sync(&server, json!({ "insert": [entry] }), &[("notes.txt", b"hello")]).await;
+    assert_eq!(res.status(), 200);
+
+    let res = reqwest::Client::new()
+        .post(server.url("/admin/migrate"))
+        .bearer_auth(common::ADMIN_TOKEN)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 202);
+    let started: Value = res.json().await.unwrap();
+    let status_url = started["status_url"].as_str().unwrap().to_string();
+
+    let mut job = Value::Null;
+    for _ in 0..50 {
+        job = server.get(&status_url).await.json().await.unwrap();
+        if job["status"] == "completed" || job["status"] == "failed" {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(200)).await;
+    }
+    assert_eq!(job["status"], "completed", "job: {}", job);
+    assert_eq!(job["result"]["copied"], 1);
+    assert_eq!(job["progress"], job["total"]);
+
+    let system_path: String = sqlx::query_scalar("SELECT system_path FROM filehash WHERE file_path = $1")
+        .bind("docs/notes.txt")
+        .fetch_one(&server.pool)
+        .await
+        .unwrap();
+    let object = server.s3
+        .get_object()
+        .bucket(TARGET_BUCKET)
+        .key(&system_path)
+        .send()
+        .await
+        .expect("object should be in the new bucket");
+    let body = object.body.collect().await.unwrap().into_bytes();
+    assert_eq!(&body[..], b"hello");
+}
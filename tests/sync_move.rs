@@ -0,0 +1,51 @@
+mod common;
+
+use serde_json::{json, Value};
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn moves_keep_the_stored_object() {
+    let server = common::start().await;
+    let data: &[u8] = b"a long video";
+    let entry = json!({
+        "file_name": "clip.mp4",
+        "file_path": "videos/clip.mp4",
+        "file_hash": "v1",
+        "file_size": data.len(),
+        "modified_time": 1_700_000_000
+    });
+    let res = common::sync(&server, json!({ "insert": [entry] }), &[("clip.mp4", data)]).await;
+    assert_eq!(res.status(), 200);
+    let key_before: String = sqlx::query_scalar("SELECT system_path FROM filehash WHERE file_path = 'videos/clip.mp4'")
+        .fetch_one(&server.pool)
+        .await
+        .unwrap();
+
+    let moved = |from: &str, to: &str| {
+        json!({ "file_name": "clip.mp4", "file_path": to, "from_path": from, "file_size": data.len(), "modified_time": 1_700_000_000 })
+    };
+    let payload = json!({ "move": [moved("videos/clip.mp4", "archive/holiday.mp4"), moved("videos/missing.mp4", "archive/other.mp4")] });
+    let res = common::sync(&server, payload, &[]).await;
+    assert_eq!(res.status(), 207);
+    let report: Value = res.json().await.unwrap();
+    let result = &report["results"]["move"];
+    assert_eq!(result["success"][0]["file_path"], "archive/holiday.mp4");
+    assert_eq!(result["success"][0]["from_path"], "videos/clip.mp4");
+    assert_eq!(result["failure"][0]["file_path"], "archive/other.mp4");
+
+    let key_after: String = sqlx::query_scalar("SELECT system_path FROM filehash WHERE file_path = 'archive/holiday.mp4'")
+        .fetch_one(&server.pool)
+        .await
+        .unwrap();
+    assert_eq!(key_after, key_before, "moves don't copy the content");
+    let object = server.s3.get_object().bucket(common::BUCKET).key(&key_after).send().await.unwrap();
+    assert_eq!(object.body.collect().await.unwrap().into_bytes().as_ref(), data);
+
+    // A move's paths can't also be listed under another operation.
+    let payload = json!({ "move": [moved("archive/holiday.mp4", "clip.mp4")], "delete": [moved("", "archive/holiday.mp4")] });
+    assert_eq!(common::sync(&server, payload, &[]).await.status(), 400);
+
+    let listing: Value = server.get("/get").await.json().await.unwrap();
+    let paths: Vec<&str> = listing["data"].as_array().unwrap().iter().filter_map(|f| f["file_path"].as_str()).collect();
+    assert_eq!(paths, ["archive/holiday.mp4"]);
+}
@@ -0,0 +1,52 @@
+mod common;
+
+use serde_json::{json, Value};
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn full_listings_stream_as_ndjson() {
+    let server = common::start().await;
+    let entry = |path: &str, hash: &str| json!({
+        "file_name": path.rsplit('/').next().unwrap(),
+        "file_path": path,
+        "file_hash": hash,
+        "file_size": 1,
+        "modified_time": 1
+    });
+    let res = common::sync(
+        &server,
+        json!({ "insert": [entry("b.txt", "bbb"), entry("a.txt", "aaa"), entry("docs/c.txt", "ccc")] }),
+        &[("b.txt", b"b"), ("a.txt", b"a"), ("c.txt", b"c")],
+    )
+    .await;
+    assert_eq!(res.status(), 200);
+
+    let res = server.get("/get?format=ndjson").await;
+    assert_eq!(res.status(), 200);
+    assert_eq!(res.headers()["content-type"], "application/x-ndjson");
+    assert!(res.headers().contains_key("etag"));
+    let body = res.text().await.unwrap();
+    assert!(body.ends_with('\n'));
+    let paths: Vec<String> = body
+        .lines()
+        .map(|line| serde_json::from_str::<Value>(line).unwrap()["file_path"].as_str().unwrap().to_string())
+        .collect();
+    assert_eq!(paths, ["a.txt", "b.txt", "docs/c.txt"]);
+
+    // The usual filters and pages apply.
+    let body = server.get("/get?format=ndjson&prefix=docs/").await.text().await.unwrap();
+    assert_eq!(body.lines().count(), 1);
+    let body = server.get("/get?format=ndjson&limit=1&offset=1").await.text().await.unwrap();
+    assert_eq!(serde_json::from_str::<Value>(body.trim_end()).unwrap()["file_path"], "b.txt");
+    let res = server.get("/get?format=ndjson&since=0").await;
+    assert_eq!(res.status(), 400);
+
+    // Each line is a file as the JSON listing has it, naming where its bytes are.
+    let line: Value = serde_json::from_str(body.trim_end()).unwrap();
+    let system_path: String = sqlx::query_scalar("SELECT system_path FROM filehash WHERE file_path = 'b.txt'")
+        .fetch_one(&server.pool)
+        .await
+        .unwrap();
+    assert_eq!(line["file_name"], system_path.as_str());
+    server.s3.head_object().bucket(common::BUCKET).key(&system_path).send().await.unwrap();
+}
@@ -0,0 +1,83 @@
+mod common;
+
+use std::{env, sync::Arc, time::Duration};
+
+use axum::{extract::State, routing::post, Json, Router};
+use serde_json::{json, Value};
+use tokio::sync::Mutex;
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn share_downloads_are_published_to_ntfy() {
+    let published = Arc::new(Mutex::new(Vec::<Value>::new()));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let ntfy = Router::new()
+        .route("/", post(|State(published): State<Arc<Mutex<Vec<Value>>>>, Json(message): Json<Value>| async move {
+            published.lock().await.push(message);
+        }))
+        .with_state(published.clone());
+    // SAFETY: each test binary runs a single test, and the server isn't started yet.
+    unsafe { env::set_var("NTFY_URL", format!("http://{}", listener.local_addr().unwrap())) };
+    tokio::spawn(async move { axum::serve(listener, ntfy).await.unwrap() });
+    let server = common::start().await;
+
+    let preferences: Value = server.get("/notifications").await.json().await.unwrap();
+    assert_eq!(preferences["channels"], json!(["ntfy"]));
+    assert!(preferences["data"]["ntfy_topic"].is_null());
+
+    let client = reqwest::Client::new();
+    let put = |body: Value| {
+        client.put(server.url("/notifications")).bearer_auth(common::ADMIN_TOKEN).json(&body).send()
+    };
+    assert_eq!(put(json!({ "email": "me@example.com" })).await.unwrap().status(), 400);
+    assert_eq!(put(json!({ "ntfy_topic": "pocket", "events": ["file.created"] })).await.unwrap().status(), 400);
+    let res = put(json!({ "ntfy_topic": "pocket", "events": ["share.accessed", "share.accessed"] })).await.unwrap();
+    assert_eq!(res.status(), 200);
+    let preferences: Value = server.get("/notifications").await.json().await.unwrap();
+    assert_eq!(preferences["data"]["events"], json!(["share.accessed"]));
+
+    let res = common::sync(
+        &server,
+        json!({ "insert": [{
+            "file_name": "report.txt",
+            "file_path": "report.txt",
+            "file_hash": "aaa111",
+            "file_size": 6,
+            "modified_time": 1
+        }] }),
+        &[("report.txt", b"report")],
+    )
+    .await;
+    assert_eq!(res.status(), 200);
+    let system_path: String = sqlx::query_scalar("SELECT system_path FROM filehash WHERE file_path = 'report.txt'")
+        .fetch_one(&server.pool)
+        .await
+        .unwrap();
+    server.s3.head_object().bucket(common::BUCKET).key(&system_path).send().await.unwrap();
+
+    let share: Value = client
+        .post(server.url("/share"))
+        .bearer_auth(common::ADMIN_TOKEN)
+        .json(&json!({ "file_path": "report.txt" }))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    let res = reqwest::get(server.url(&format!("/s/{}", share["token"].as_str().unwrap()))).await.unwrap();
+    assert_eq!(res.bytes().await.unwrap().as_ref(), b"report");
+
+    // Notifications are sent in the background.
+    for _ in 0..50 {
+        if !published.lock().await.is_empty() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    let published = published.lock().await;
+    assert_eq!(published.len(), 1);
+    assert_eq!(published[0]["topic"], "pocket");
+    assert_eq!(published[0]["tags"], json!(["share.accessed"]));
+    assert!(published[0]["message"].as_str().unwrap().contains("report.txt"));
+}
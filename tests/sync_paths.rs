@@ -0,0 +1,66 @@
+mod common;
+
+use std::env;
+
+use serde_json::{json, Value};
+
+fn entry(path: &str) -> Value {
+    json!({ "file_name": "a.txt", "file_path": path, "file_hash": path, "file_size": 1, "modified_time": 1 })
+}
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn paths_are_normalized_and_collisions_refused() {
+    // SAFETY: each test binary runs a single test, and the server isn't started yet.
+    unsafe {
+        env::set_var("CASE_INSENSITIVE_PATHS", "true");
+    }
+    let server = common::start().await;
+    let res = common::sync(
+        &server,
+        json!({ "insert": [entry("Docs\\a.txt"), entry("x/cafe\u{301}.txt"), entry("/docs//b.txt ")] }),
+        &[("Docs\\a.txt", b"1"), ("x/cafe\u{301}.txt", b"2"), ("/docs//b.txt ", b"3")],
+    )
+    .await;
+    assert_eq!(res.status(), 200);
+    let body: Value = res.json().await.unwrap();
+    let inserted = &body["results"]["insert"];
+    assert_eq!(inserted["success"][0]["file_path"], "Docs/a.txt");
+    assert_eq!(inserted["success"][1]["file_path"], "x/caf\u{e9}.txt");
+    assert_eq!(inserted["failure"][0]["file_path"], "/docs//b.txt ");
+    assert_eq!(inserted["failure"][0]["code"], "path_collision", "{}", body);
+
+    let res = common::sync(
+        &server,
+        json!({ "insert": [entry("DOCS/c.txt")], "update": [entry("docs/A.TXT")] }),
+        &[("DOCS/c.txt", b"4"), ("docs/A.TXT", b"5")],
+    )
+    .await;
+    let body: Value = res.json().await.unwrap();
+    assert_eq!(body["results"]["insert"]["failure"][0]["code"], "path_collision", "{}", body);
+    assert_eq!(body["results"]["update"]["success"][0]["file_path"], "Docs/a.txt", "{}", body);
+
+    let paths: Vec<String> = sqlx::query_scalar("SELECT file_path FROM filehash ORDER BY file_path")
+        .fetch_all(&server.pool)
+        .await
+        .unwrap();
+    assert_eq!(paths, ["Docs/a.txt", "x/caf\u{e9}.txt"]);
+    let system_path: String = sqlx::query_scalar("SELECT system_path FROM filehash WHERE file_path = 'Docs/a.txt'")
+        .fetch_one(&server.pool)
+        .await
+        .unwrap();
+    let object = server.s3.get_object().bucket(common::BUCKET).key(&system_path).send().await.unwrap();
+    assert_eq!(object.body.collect().await.unwrap().into_bytes().as_ref(), b"5");
+
+    let client = reqwest::Client::new();
+    let moved = |from: &str, to: &str| {
+        client
+            .post(server.url("/move"))
+            .bearer_auth(common::ADMIN_TOKEN)
+            .json(&json!({ "from": from, "to": to }))
+            .send()
+    };
+    assert_eq!(moved("docs/a.txt", "DOCS/z.txt").await.unwrap().status(), 409);
+    assert_eq!(moved("DOCS/A.txt", "Docs/A.txt").await.unwrap().status(), 200);
+    assert_eq!(server.get("/file?path=Docs/A.txt").await.status(), 200);
+}
@@ -0,0 +1,66 @@
+mod common;
+
+use chrono::NaiveDateTime;
+use serde_json::{json, Value};
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn payloads_are_versioned_and_apply_deletes_first() {
+    let server = common::start().await;
+    let entry = |path: &str| {
+        json!({ "file_name": "notes.txt", "file_path": path, "file_hash": "n1", "file_size": 5, "modified_time": 1 })
+    };
+
+    let res = common::sync(&server, json!({ "version": 1, "insert": [entry("old/notes.txt")] }), &[("notes.txt", b"hello")]).await;
+    assert_eq!(res.status(), 200);
+
+    let res = common::sync(&server, json!({ "version": 4, "insert": [entry("new/notes.txt")] }), &[("notes.txt", b"hello")]).await;
+    assert_eq!(res.status(), 400);
+    let body: Value = res.json().await.unwrap();
+    assert!(body["error"].as_str().unwrap().contains("Unsupported payload version 4"));
+    let res = common::sync(&server, json!({ "schema_version": 3, "insert": [entry("new/notes.txt")] }), &[]).await;
+    assert_eq!(res.status(), 400);
+
+    // A rename sent as an insert and a delete, listed insert first.
+    let rename = json!({ "insert": [entry("new/notes.txt")], "delete": [entry("old/notes.txt")] });
+    let res = common::sync(&server, rename, &[("notes.txt", b"hello")]).await;
+    assert_eq!(res.status(), 200);
+
+    let deleted_at: NaiveDateTime = sqlx::query_scalar("SELECT deleted_at FROM trash WHERE file_path = 'old/notes.txt'")
+        .fetch_one(&server.pool)
+        .await
+        .unwrap();
+    let (created_at, system_path): (NaiveDateTime, String) =
+        sqlx::query_as("SELECT created_at, system_path FROM filehash WHERE file_path = 'new/notes.txt'")
+            .fetch_one(&server.pool)
+            .await
+            .unwrap();
+    assert!(deleted_at <= created_at, "the delete ran before the insert");
+
+    let object = server.s3.get_object().bucket(common::BUCKET).key(&system_path).send().await.unwrap();
+    assert_eq!(object.body.collect().await.unwrap().into_bytes().as_ref(), b"hello");
+
+    let listing: Value = server.get("/get").await.json().await.unwrap();
+    let paths: Vec<&str> = listing["data"].as_array().unwrap().iter().filter_map(|f| f["file_path"].as_str()).collect();
+    assert_eq!(paths, ["new/notes.txt"]);
+
+    // Version 3 lists every file with its operation.
+    let listed = |op: &str, path: &str| {
+        let mut file = entry(path);
+        file["op"] = op.into();
+        file["metadata_only"] = true.into();
+        file
+    };
+    let payload = json!({
+        "schema_version": 3,
+        "operations": [listed("insert", "v3/b.txt"), listed("delete", "new/notes.txt"), listed("insert", "v3/a.txt")]
+    });
+    let res = common::sync(&server, payload, &[]).await;
+    assert_eq!(res.status(), 200);
+    let body: Value = res.json().await.unwrap();
+    assert_eq!(body["results"]["delete"]["success"][0]["file_path"], "new/notes.txt", "{}", body);
+    assert_eq!(body["results"]["insert"]["success"][0]["file_path"], "v3/b.txt");
+    assert_eq!(body["results"]["insert"]["success"][1]["file_path"], "v3/a.txt");
+    let res = common::sync(&server, json!({ "schema_version": 2, "operations": [listed("delete", "v3/a.txt")] }), &[]).await;
+    assert_eq!(res.status(), 400);
+}
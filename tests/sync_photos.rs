@@ -0,0 +1,108 @@
+mod common;
+
+use std::time::Duration;
+
+use image::{codecs::jpeg::JpegEncoder, ImageEncoder, RgbImage};
+use serde_json::{json, Value};
+
+/// A little-endian EXIF block: Make and Model in the first directory, the capture
+/// time in the Exif one, and 48°30'N 2°15'W in the GPS one.
+fn exif_block() -> Vec<u8> {
+    fn entry(out: &mut Vec<u8>, tag: u16, kind: u16, count: u32, value: u32) {
+        out.extend(tag.to_le_bytes());
+        out.extend(kind.to_le_bytes());
+        out.extend(count.to_le_bytes());
+        out.extend(value.to_le_bytes());
+    }
+    let rationals = |values: [u32; 3]| values.into_iter().flat_map(|v| [v.to_le_bytes(), 1u32.to_le_bytes()]).flatten();
+
+    let mut out = b"II*\0".to_vec();
+    out.extend(8u32.to_le_bytes());
+    // The first directory, at 8, and its strings at 62.
+    out.extend(4u16.to_le_bytes());
+    entry(&mut out, 0x010F, 2, 6, 62);
+    entry(&mut out, 0x0110, 2, 7, 68);
+    entry(&mut out, 0x8769, 4, 1, 76);
+    entry(&mut out, 0x8825, 4, 1, 114);
+    out.extend(0u32.to_le_bytes());
+    out.extend(b"Canon\0EOS R5\0\0");
+    // The Exif directory, at 76, and its capture time at 94.
+    out.extend(1u16.to_le_bytes());
+    entry(&mut out, 0x9003, 2, 20, 94);
+    out.extend(0u32.to_le_bytes());
+    out.extend(b"2024:05:17 08:30:00\0");
+    // The GPS directory, at 114, and its coordinates at 168 and 192.
+    out.extend(4u16.to_le_bytes());
+    entry(&mut out, 1, 2, 2, u32::from(b'N'));
+    entry(&mut out, 2, 5, 3, 168);
+    entry(&mut out, 3, 2, 2, u32::from(b'W'));
+    entry(&mut out, 4, 5, 3, 192);
+    out.extend(0u32.to_le_bytes());
+    out.extend(rationals([48, 30, 0]));
+    out.extend(rationals([2, 15, 0]));
+    out
+}
+
+fn photo() -> Vec<u8> {
+    let image = RgbImage::from_pixel(32, 32, image::Rgb([200, 80, 40]));
+    let mut bytes = Vec::new();
+    let mut encoder = JpegEncoder::new(&mut bytes);
+    encoder.set_exif_metadata(exif_block()).unwrap();
+    encoder.write_image(&image, 32, 32, image::ExtendedColorType::Rgb8).unwrap();
+    bytes
+}
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn timeline_groups_photos_by_exif_capture_day() {
+    let server = common::start().await;
+    let photo = photo();
+    let entry = json!({
+        "file_name": "photo.jpg",
+        "file_path": "camera/photo.jpg",
+        "file_hash": "bbb222",
+        "file_size": photo.len(),
+        "modified_time": 1
+    });
+    let res = common::sync(&server, json!({ "insert": [entry] }), &[("photo.jpg", &photo)]).await;
+    assert_eq!(res.status(), 200);
+
+    // The post-upload job reads the EXIF data in the background.
+    let mut recorded = None;
+    for _ in 0..50 {
+        recorded = sqlx::query_as::<_, (Option<String>, Option<f64>, Option<f64>)>(
+            "SELECT camera_model, latitude, longitude FROM media_metadata",
+        )
+        .fetch_optional(&server.pool)
+        .await
+        .unwrap();
+        if recorded.is_some() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(200)).await;
+    }
+    assert_eq!(recorded, Some((Some("EOS R5".to_string()), Some(48.5), Some(-2.25))));
+
+    let timeline: Value = server.get("/photos/timeline?from=2024-05-17&to=2024-05-17").await.json().await.unwrap();
+    let days = timeline["data"].as_array().unwrap();
+    assert_eq!(days.len(), 1);
+    assert_eq!(days[0]["date"], "2024-05-17");
+    assert_eq!(days[0]["photos"][0]["file_path"], "camera/photo.jpg");
+    assert_eq!(days[0]["photos"][0]["camera_make"], "Canon");
+
+    let timeline: Value = server.get("/photos/timeline?from=2024-05-18").await.json().await.unwrap();
+    assert_eq!(timeline["data"], json!([]));
+
+    let system_path: String = sqlx::query_scalar("SELECT system_path FROM filehash WHERE file_path = $1")
+        .bind("camera/photo.jpg")
+        .fetch_one(&server.pool)
+        .await
+        .unwrap();
+    let thumbnail = server.s3
+        .head_object()
+        .bucket(common::BUCKET)
+        .key(format!("thumbs/{}/256.jpg", system_path))
+        .send()
+        .await;
+    assert!(thumbnail.is_ok(), "thumbnails should still be generated");
+}
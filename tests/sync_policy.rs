@@ -0,0 +1,52 @@
+mod common;
+
+use std::env;
+
+use serde_json::{json, Value};
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn upload_policies_refuse_files_by_extension_path_and_size() {
+    // SAFETY: each test binary runs a single test, and the server isn't started yet.
+    unsafe { env::set_var("BLOCKED_EXTENSIONS", "exe") };
+    let server = common::start().await;
+    let client = reqwest::Client::new();
+    let entry = |path: &str, size: i64| {
+        json!({ "file_name": path, "file_path": path, "file_hash": "h1", "file_size": size, "modified_time": 1 })
+    };
+
+    let user_id: i32 = sqlx::query_scalar("SELECT id FROM users ORDER BY id LIMIT 1").fetch_one(&server.pool).await.unwrap();
+    let res = client
+        .put(server.url(&format!("/admin/users/{}/policy", user_id)))
+        .bearer_auth(common::ADMIN_TOKEN)
+        .json(&json!({ "forbidden_paths": ["**/node_modules"], "max_bytes_by_type": { "video/*": 4 } }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 200);
+    let policy: Value = server.get("/policy").await.json().await.unwrap();
+    assert_eq!(policy["data"]["blocked_extensions"], json!(["exe"]), "the server's policy applies too");
+
+    let res = common::sync(
+        &server,
+        json!({ "insert": [entry("setup.EXE", 1), entry("app/node_modules/x.js", 1), entry("clip.mp4", 5), entry("notes.txt", 1)] }),
+        &[("setup.EXE", b"s"), ("app/node_modules/x.js", b"x"), ("clip.mp4", b"01234"), ("notes.txt", b"n")],
+    )
+    .await;
+    assert_eq!(res.status(), 207);
+    let report: Value = res.json().await.unwrap();
+    let failures = report["results"]["insert"]["failure"].as_array().unwrap();
+    assert_eq!(failures.len(), 3, "{}", report);
+    assert!(failures.iter().all(|f| f["code"] == "policy_violation"), "{}", report);
+
+    let mut rename = entry("notes.exe", 1);
+    rename["from_path"] = json!("notes.txt");
+    let res = common::sync(&server, json!({ "move": [rename] }), &[]).await;
+    let report: Value = res.json().await.unwrap();
+    assert_eq!(report["results"]["move"]["failure"][0]["code"], "policy_violation", "{}", report);
+
+    let stored: Vec<String> = sqlx::query_scalar("SELECT system_path FROM filehash").fetch_all(&server.pool).await.unwrap();
+    assert_eq!(stored.len(), 1);
+    let objects = server.s3.list_objects_v2().bucket(common::BUCKET).send().await.unwrap();
+    assert_eq!(objects.contents().len(), 1, "refused files never reach storage");
+}
@@ -0,0 +1,60 @@
+mod common;
+
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn linked_files_copy_content_the_precheck_reports() {
+    let server = common::start().await;
+    let photo: &[u8] = b"not really a jpeg";
+    let hash = hex::encode(Sha256::digest(photo));
+    let unknown = hex::encode(Sha256::digest(b"elsewhere"));
+
+    let original = json!({ "file_name": "beach.jpg", "file_path": "phone/beach.jpg", "file_hash": hash, "file_size": photo.len(), "modified_time": 1 });
+    let res = common::sync(&server, json!({ "insert": [original] }), &[("phone/beach.jpg", photo)]).await;
+    assert_eq!(res.status(), 200);
+
+    let precheck = |files: Value| {
+        reqwest::Client::new()
+            .post(server.url("/sync/precheck"))
+            .bearer_auth(common::ADMIN_TOKEN)
+            .json(&json!({ "files": files }))
+            .send()
+    };
+    let res = precheck(json!([
+        { "file_hash": hash, "file_size": photo.len() },
+        { "file_hash": hash, "file_size": 1 },
+        { "file_hash": unknown, "file_size": 9 },
+    ]))
+    .await
+    .unwrap();
+    assert_eq!(res.status(), 200);
+    let body: Value = res.json().await.unwrap();
+    assert_eq!(body["available"], json!([{ "file_hash": hash, "file_size": photo.len() }]));
+    assert_eq!(body["missing"].as_array().unwrap().len(), 2, "the size has to match too");
+
+    let res = precheck(json!([{ "file_hash": "abc", "file_size": 3 }])).await.unwrap();
+    assert_eq!(res.status(), 400);
+
+    let linked = json!({ "file_name": "beach.jpg", "file_path": "laptop/beach.jpg", "file_hash": hash, "file_size": photo.len(), "modified_time": 2, "link": true });
+    let missing = json!({ "file_name": "other.jpg", "file_path": "laptop/other.jpg", "file_hash": unknown, "file_size": 9, "modified_time": 2, "link": true });
+    let res = common::sync(&server, json!({ "version": 2, "insert": [linked, missing] }), &[]).await;
+    assert_eq!(res.status(), 207);
+    let report: Value = res.json().await.unwrap();
+    assert_eq!(report["results"]["insert"]["success"][0]["file_path"], "laptop/beach.jpg");
+    assert_eq!(report["results"]["insert"]["failure"][0]["code"], "missing_upload");
+
+    let system_path: String = sqlx::query_scalar("SELECT system_path FROM filehash WHERE file_path = 'laptop/beach.jpg'")
+        .fetch_one(&server.pool)
+        .await
+        .unwrap();
+    let object = server.s3.get_object().bucket(common::BUCKET).key(&system_path).send().await.unwrap();
+    assert_eq!(object.body.collect().await.unwrap().into_bytes().as_ref(), photo);
+
+    let unhashed = json!({ "file_name": "a.txt", "file_path": "a.txt", "file_size": 1, "modified_time": 1, "link": true });
+    let res = common::sync(&server, json!({ "insert": [unhashed] }), &[]).await;
+    assert_eq!(res.status(), 400, "linking needs a SHA-256 to find the content by");
+    let listing: Value = server.get("/get").await.json().await.unwrap();
+    assert_eq!(listing["data"].as_array().unwrap().len(), 2);
+}
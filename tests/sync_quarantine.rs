@@ -0,0 +1,70 @@
+mod common;
+
+use std::{env, time::Duration};
+
+use serde_json::{json, Value};
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn infected_uploads_are_quarantined_until_released() {
+    // SAFETY: each test binary runs a single test, so nothing else reads the environment
+    // while it is being written.
+    unsafe {
+        env::set_var("SCAN_COMMAND", "if grep -q EICAR; then echo Eicar-Test-Signature; exit 1; fi");
+    }
+    let server = common::start().await;
+    let entry = |name: &str, hash: &str, size: usize| json!({
+        "file_name": name,
+        "file_path": format!("docs/{}", name),
+        "file_hash": hash,
+        "file_size": size,
+        "modified_time": 1
+    });
+    let res = common::sync(
+        &server,
+        json!({ "insert": [entry("clean.txt", "aaa111", 5), entry("bad.txt", "bbb222", 10)] }),
+        &[("clean.txt", b"hello"), ("bad.txt", b"EICAR test")],
+    )
+    .await;
+    assert_eq!(res.status(), 200);
+
+    // Scans run in the background once the sync is done.
+    let mut quarantined = Vec::new();
+    for _ in 0..50 {
+        let listing: Value = server.get("/admin/quarantine").await.json().await.unwrap();
+        quarantined = listing["data"].as_array().cloned().unwrap_or_default();
+        if !quarantined.is_empty() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    assert_eq!(quarantined.len(), 1);
+    assert_eq!(quarantined[0]["file_path"], "docs/bad.txt");
+    assert_eq!(quarantined[0]["signature"], "Eicar-Test-Signature");
+    assert_eq!(server.get("/metadata?path=docs/bad.txt").await.status(), 404);
+    assert_eq!(server.get("/metadata?path=docs/clean.txt").await.status(), 200);
+
+    let quarantine_key: String = sqlx::query_scalar("SELECT quarantine_key FROM quarantine WHERE file_path = $1")
+        .bind("docs/bad.txt")
+        .fetch_one(&server.pool)
+        .await
+        .unwrap();
+    let object = server.s3
+        .get_object()
+        .bucket(common::BUCKET)
+        .key(&quarantine_key)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(object.body.collect().await.unwrap().into_bytes().as_ref(), b"EICAR test");
+
+    let res = reqwest::Client::new()
+        .post(server.url(&format!("/admin/quarantine/{}/release", quarantined[0]["id"])))
+        .bearer_auth(common::ADMIN_TOKEN)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 200);
+    let metadata: Value = server.get("/metadata?path=docs/bad.txt").await.json().await.unwrap();
+    assert_eq!(metadata["file_hash"], "bbb222");
+}
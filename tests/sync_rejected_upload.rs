@@ -0,0 +1,49 @@
+mod common;
+
+use std::env;
+
+use serde_json::{json, Value};
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn rejected_sync_leaves_the_stored_copy_alone() {
+    // SAFETY: each test binary runs a single test, and nothing else reads this.
+    unsafe {
+        env::set_var("MAX_UPLOAD_BYTES", "4");
+    }
+    let server = common::start().await;
+    let entry = |path: &str, hash: &str, size: i64| {
+        json!({ "file_name": path, "file_path": path, "file_hash": hash, "file_size": size, "modified_time": 1 })
+    };
+
+    let res = common::sync(&server, json!({ "insert": [entry("a.txt", "h1", 3)] }), &[("a.txt", b"old")]).await;
+    assert_eq!(res.status(), 200);
+    let (before, hash): (String, String) = sqlx::query_as("SELECT system_path, file_hash FROM filehash WHERE file_path = 'a.txt'")
+        .fetch_one(&server.pool)
+        .await
+        .unwrap();
+    assert_eq!(hash, "h1");
+
+    // The update's bytes arrive in full before the insert's outgrow what it declared.
+    let payload = json!({ "update": [entry("a.txt", "h2", 3)], "insert": [entry("b.txt", "hb", 1)] });
+    let res = common::sync(&server, payload, &[("a.txt", b"new"), ("b.txt", b"far too long")]).await;
+    assert_eq!(res.status(), 413);
+
+    let (after, hash): (String, String) = sqlx::query_as("SELECT system_path, file_hash FROM filehash WHERE file_path = 'a.txt'")
+        .fetch_one(&server.pool)
+        .await
+        .unwrap();
+    assert_eq!((after.as_str(), hash.as_str()), (before.as_str(), "h1"));
+    let object = server.s3.get_object().bucket(common::BUCKET).key(&after).send().await.unwrap();
+    assert_eq!(object.body.collect().await.unwrap().into_bytes().as_ref(), b"old");
+
+    // Once applied, the update moves the file onto the object it uploaded.
+    let res = common::sync(&server, json!({ "update": [entry("a.txt", "h2", 3)] }), &[("a.txt", b"new")]).await;
+    assert_eq!(res.status(), 200);
+    let report: Value = res.json().await.unwrap();
+    let system_path = report["results"]["update"]["success"][0]["file_name"].as_str().unwrap().to_string();
+    assert_ne!(system_path, before);
+    let object = server.s3.get_object().bucket(common::BUCKET).key(&system_path).send().await.unwrap();
+    assert_eq!(object.body.collect().await.unwrap().into_bytes().as_ref(), b"new");
+    assert_eq!(server.get("/metadata?path=a.txt").await.status(), 200);
+}
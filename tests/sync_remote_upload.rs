@@ -0,0 +1,55 @@
+mod common;
+
+use std::{env, time::Duration};
+
+use axum::{response::Redirect, routing::get, Router};
+use serde_json::{json, Value};
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn remote_uploads_follow_redirects_into_a_stored_file() {
+    // SAFETY: each test binary runs a single test, and the server isn't started yet.
+    unsafe { env::set_var("REMOTE_UPLOAD_ALLOW_PRIVATE", "true") };
+    let server = common::start().await;
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let origin = format!("http://{}", listener.local_addr().unwrap());
+    let remote = Router::new()
+        .route("/files/report.pdf", get(|| async { "%PDF-1.7 remote" }))
+        .route("/latest", get(|| async { Redirect::temporary("/files/report.pdf") }));
+    tokio::spawn(async move { axum::serve(listener, remote).await.unwrap() });
+
+    let client = reqwest::Client::new();
+    let res = client
+        .post(server.url("/remote-upload"))
+        .bearer_auth(common::ADMIN_TOKEN)
+        .json(&json!({ "url": format!("{}/latest", origin), "file_path": "inbox/" }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 202);
+    let accepted: Value = res.json().await.unwrap();
+
+    let mut job = Value::Null;
+    for _ in 0..50 {
+        job = server.get(accepted["status_url"].as_str().unwrap()).await.json().await.unwrap();
+        if job["status"] != "pending" && job["status"] != "running" {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    assert_eq!(job["status"], "completed", "{}", job);
+    assert_eq!(job["result"]["file_path"], "inbox/report.pdf", "named after where the redirect led");
+    assert_eq!(job["result"]["file_size"], 15);
+
+    let system_path: String = sqlx::query_scalar("SELECT system_path FROM filehash WHERE file_path = 'inbox/report.pdf'")
+        .fetch_one(&server.pool)
+        .await
+        .unwrap();
+    let object = server.s3.get_object().bucket(common::BUCKET).key(&system_path).send().await.unwrap();
+    assert_eq!(object.body.collect().await.unwrap().into_bytes().as_ref(), b"%PDF-1.7 remote");
+
+    let entry = json!({ "file_name": "a.txt", "file_path": "a.txt", "file_hash": "a1", "file_size": 1, "modified_time": 1 });
+    let res = common::sync(&server, json!({ "insert": [entry] }), &[("a.txt", b"a")]).await;
+    assert_eq!(res.status(), 200);
+}
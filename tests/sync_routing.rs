@@ -0,0 +1,95 @@
+mod common;
+
+use std::{env, time::Duration};
+
+use serde_json::{json, Value};
+
+const EU_BUCKET: &str = "pocket-eu";
+
+const CONFIG: &str = r#"
+[routing.targets.eu]
+bucket = "pocket-eu"
+
+[[routing.routes]]
+prefix = "eu"
+target = "eu"
+"#;
+
+async fn system_path(server: &common::TestServer, file_path: &str) -> String {
+    sqlx::query_scalar("SELECT system_path FROM filehash WHERE file_path = $1")
+        .bind(file_path)
+        .fetch_one(&server.pool)
+        .await
+        .unwrap()
+}
+
+async fn stored(server: &common::TestServer, bucket: &str, key: &str) -> Option<Vec<u8>> {
+    let object = server.s3.get_object().bucket(bucket).key(key).send().await.ok()?;
+    Some(object.body.collect().await.unwrap().into_bytes().to_vec())
+}
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn routed_folders_are_stored_in_their_bucket_and_rerouted_by_a_job() {
+    let config = env::temp_dir().join(format!("pocket-routing-{}.toml", std::process::id()));
+    std::fs::write(&config, CONFIG).unwrap();
+    // SAFETY: each test binary runs a single test, and the server isn't started yet.
+    unsafe {
+        env::set_var("POCKET_CONFIG", &config);
+    }
+    let server = common::start().await;
+    let entry = |path: &str| json!({ "file_name": "a.txt", "file_path": path, "file_hash": path, "file_size": 2, "modified_time": 1 });
+    let res = common::sync(
+        &server,
+        json!({ "insert": [entry("eu/a.txt"), entry("home/b.txt")] }),
+        &[("eu/a.txt", b"eu"), ("home/b.txt", b"us")],
+    )
+    .await;
+    assert_eq!(res.status(), 200);
+
+    let routed = system_path(&server, "eu/a.txt").await;
+    assert!(routed.contains("/@eu/"), "{}", routed);
+    assert_eq!(stored(&server, EU_BUCKET, &routed).await.as_deref(), Some(&b"eu"[..]));
+    assert_eq!(stored(&server, common::BUCKET, &routed).await, None);
+    let home = system_path(&server, "home/b.txt").await;
+    assert_eq!(stored(&server, common::BUCKET, &home).await.as_deref(), Some(&b"us"[..]));
+    let download = server.get("/download/direct?file_path=eu/a.txt").await;
+    assert_eq!(download.status(), 200);
+    assert_eq!(download.bytes().await.unwrap().as_ref(), b"eu");
+
+    // Moving keeps the object where it is until a reroute.
+    let client = reqwest::Client::new();
+    let res = client
+        .post(server.url("/move"))
+        .bearer_auth(common::ADMIN_TOKEN)
+        .json(&json!({ "from": "home/b.txt", "to": "eu/b.txt" }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 200);
+    assert_eq!(system_path(&server, "eu/b.txt").await, home);
+
+    let res = client.post(server.url("/admin/reroute")).bearer_auth(common::ADMIN_TOKEN).send().await.unwrap();
+    assert_eq!(res.status(), 202);
+    let started: Value = res.json().await.unwrap();
+    let status_url = started["status_url"].as_str().unwrap().to_string();
+    let mut job = Value::Null;
+    for _ in 0..50 {
+        job = server.get(&status_url).await.json().await.unwrap();
+        if job["status"] == "completed" || job["status"] == "failed" {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(200)).await;
+    }
+    assert_eq!(job["status"], "completed", "job: {}", job);
+    assert_eq!(job["result"]["scanned"], 2);
+    assert_eq!(job["result"]["moved"], 1);
+    assert_eq!(job["result"]["failed"], json!([]));
+
+    let moved = system_path(&server, "eu/b.txt").await;
+    assert!(moved.contains("/@eu/"), "{}", moved);
+    assert_eq!(stored(&server, EU_BUCKET, &moved).await.as_deref(), Some(&b"us"[..]));
+    assert_eq!(stored(&server, common::BUCKET, &home).await, None);
+    assert_eq!(system_path(&server, "eu/a.txt").await, routed);
+    std::fs::remove_file(&config).ok();
+}
@@ -0,0 +1,75 @@
+mod common;
+
+use std::{env, time::Duration};
+
+use serde_json::{json, Value};
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn scheduled_jobs_run_on_demand_and_report_how_they_went() {
+    // SAFETY: each test binary runs a single test, and the server isn't started yet.
+    unsafe { env::set_var("SCHEDULE_TRASH_PURGE", "0 3 * * *") };
+    let server = common::start().await;
+
+    let res = common::sync(
+        &server,
+        json!({ "insert": [{
+            "file_name": "a.txt",
+            "file_path": "a.txt",
+            "file_hash": "aaa111",
+            "file_size": 5,
+            "modified_time": 1
+        }] }),
+        &[("a.txt", b"hello")],
+    )
+    .await;
+    assert_eq!(res.status(), 200);
+
+    let schedule: Value = server.get("/admin/schedule").await.json().await.unwrap();
+    let jobs = schedule["data"].as_array().unwrap();
+    let purge = jobs.iter().find(|job| job["name"] == "trash_purge").unwrap();
+    assert_eq!(purge["schedule"], "0 3 * * *");
+    assert!(purge["next_run_at"].as_str().unwrap().contains("T03:00:00"));
+    let check = jobs.iter().find(|job| job["name"] == "integrity_check").unwrap();
+    assert!(check["schedule"].is_null());
+    assert!(check["last_status"].is_null());
+
+    let client = reqwest::Client::new();
+    let run = |name: &str| {
+        client
+            .post(server.url(&format!("/admin/schedule/{}/run", name)))
+            .bearer_auth(common::ADMIN_TOKEN)
+            .send()
+    };
+    assert_eq!(run("nonsense").await.unwrap().status(), 404);
+    assert_eq!(run("integrity_check").await.unwrap().status(), 202);
+
+    let mut status = None;
+    for _ in 0..50 {
+        status = sqlx::query_scalar::<_, Option<String>>(
+            "SELECT last_status FROM scheduled_jobs WHERE name = 'integrity_check'",
+        )
+        .fetch_optional(&server.pool)
+        .await
+        .unwrap()
+        .flatten();
+        if status.is_some() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    assert_eq!(status.as_deref(), Some("succeeded"));
+
+    let schedule: Value = server.get("/admin/schedule").await.json().await.unwrap();
+    let check = schedule["data"].as_array().unwrap().iter().find(|job| job["name"] == "integrity_check").unwrap();
+    assert_eq!(check["running"], false);
+    assert!(check["last_finished_at"].is_string());
+    assert!(check["next_run_at"].is_null());
+
+    // The check left the synced file alone.
+    let system_path: String = sqlx::query_scalar("SELECT system_path FROM filehash WHERE file_path = 'a.txt'")
+        .fetch_one(&server.pool)
+        .await
+        .unwrap();
+    server.s3.head_object().bucket(common::BUCKET).key(&system_path).send().await.unwrap();
+}
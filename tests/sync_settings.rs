@@ -0,0 +1,72 @@
+mod common;
+
+use serde_json::{json, Value};
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn overridden_settings_apply_without_a_restart() {
+    let server = common::start().await;
+    let client = reqwest::Client::new();
+    let entry = json!({
+        "file_name": "notes.txt",
+        "file_path": "docs/notes.txt",
+        "file_hash": "aaa111",
+        "file_size": 5,
+        "modified_time": 1
+    });
+
+    let res = client
+        .patch(server.url("/admin/settings"))
+        .bearer_auth(common::ADMIN_TOKEN)
+        .json(&json!({ "max_upload_bytes": 3 }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 200);
+    let settings: Value = res.json().await.unwrap();
+    let max_upload = settings["data"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|setting| setting["key"] == "max_upload_bytes")
+        .cloned()
+        .unwrap();
+    assert_eq!(max_upload["value"], 3);
+    assert_eq!(max_upload["overridden"], true);
+
+    let stored: String = sqlx::query_scalar("SELECT value FROM settings WHERE key = $1")
+        .bind("max_upload_bytes")
+        .fetch_one(&server.pool)
+        .await
+        .unwrap();
+    assert_eq!(stored, "3");
+
+    let res = common::sync(&server, json!({ "insert": [entry] }), &[("notes.txt", b"hello")]).await;
+    assert_eq!(res.status(), 413);
+
+    let res = client
+        .delete(server.url("/admin/settings/max_upload_bytes"))
+        .bearer_auth(common::ADMIN_TOKEN)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 200);
+
+    let res = common::sync(&server, json!({ "insert": [entry] }), &[("notes.txt", b"hello")]).await;
+    assert_eq!(res.status(), 200);
+    let listing: Value = server.get("/get").await.json().await.unwrap();
+    assert_eq!(listing["total"], 1);
+
+    let system_path: String = sqlx::query_scalar("SELECT system_path FROM filehash WHERE file_path = $1")
+        .bind("docs/notes.txt")
+        .fetch_one(&server.pool)
+        .await
+        .unwrap();
+    let head = server.s3
+        .head_object()
+        .bucket(common::BUCKET)
+        .key(&system_path)
+        .send()
+        .await;
+    assert!(head.is_ok(), "object should be in storage");
+}
@@ -0,0 +1,87 @@
+mod common;
+
+use std::time::Duration;
+
+use serde_json::{json, Value};
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn share_stats_count_views_and_downloads() {
+    let server = common::start().await;
+    let entry = |path: &str, hash: &str, size: usize| json!({
+        "file_name": path.rsplit('/').next().unwrap(),
+        "file_path": path,
+        "file_hash": hash,
+        "file_size": size,
+        "modified_time": 1
+    });
+    let res = common::sync(
+        &server,
+        json!({ "insert": [entry("album/notes.txt", "aaa111", 5), entry("report.txt", "bbb222", 6)] }),
+        &[("notes.txt", b"hello"), ("report.txt", b"report")],
+    )
+    .await;
+    assert_eq!(res.status(), 200);
+
+    let server = &server;
+    let create = |body: Value| async move {
+        reqwest::Client::new()
+            .post(server.url("/share"))
+            .bearer_auth(common::ADMIN_TOKEN)
+            .json(&body)
+            .send()
+            .await
+            .unwrap()
+            .json::<Value>()
+            .await
+            .unwrap()
+    };
+    let folder = create(json!({ "file_path": "album", "folder": true })).await;
+    let file = create(json!({ "file_path": "report.txt" })).await;
+
+    // Two page views and one download through the folder link.
+    let anonymous = reqwest::Client::new();
+    let folder_url = server.url(&format!("/s/{}/", folder["token"].as_str().unwrap()));
+    assert_eq!(anonymous.get(&folder_url).send().await.unwrap().status(), 200);
+    assert_eq!(anonymous.get(&folder_url).send().await.unwrap().status(), 200);
+    let res = anonymous.get(format!("{}notes.txt", folder_url)).send().await.unwrap();
+    assert_eq!(res.bytes().await.unwrap().as_ref(), b"hello");
+
+    // A download of the file link, and a range past its start, which isn't another.
+    let file_url = server.url(&format!("/s/{}", file["token"].as_str().unwrap()));
+    assert_eq!(anonymous.get(&file_url).send().await.unwrap().status(), 200);
+    let res = anonymous.get(&file_url).header("Range", "bytes=2-").send().await.unwrap();
+    assert_eq!(res.status(), 206);
+    let system_path: String = sqlx::query_scalar("SELECT system_path FROM filehash WHERE file_path = 'report.txt'")
+        .fetch_one(&server.pool)
+        .await
+        .unwrap();
+    let object = server.s3.get_object().bucket(common::BUCKET).key(&system_path).send().await.unwrap();
+    let stored = object.body.collect().await.unwrap().into_bytes();
+    assert_eq!(res.bytes().await.unwrap(), stored.slice(2..));
+
+    // The counts are written in the background.
+    for _ in 0..50 {
+        let counted: i64 = sqlx::query_scalar("SELECT CAST(SUM(view_count + download_count) AS BIGINT) FROM shares")
+            .fetch_one(&server.pool)
+            .await
+            .unwrap();
+        if counted == 4 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+
+    let stats: Value = server.get(&format!("/share/{}/stats", folder["id"])).await.json().await.unwrap();
+    assert_eq!(stats["views"], 2);
+    assert_eq!(stats["downloads"], 1);
+    assert_eq!(stats["folder"], true);
+    assert!(stats["last_accessed_at"].is_string());
+
+    let stats: Value = server.get(&format!("/share/{}/stats", file["id"])).await.json().await.unwrap();
+    assert_eq!(stats["views"], 0);
+    assert_eq!(stats["downloads"], 1);
+
+    let res = server.get("/share/9999/stats").await;
+    assert_eq!(res.status(), 404);
+}
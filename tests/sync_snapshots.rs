@@ -0,0 +1,56 @@
+mod common;
+
+use serde_json::{json, Value};
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn restoring_a_snapshot_undoes_overwrites_deletions_and_additions() {
+    let server = common::start().await;
+    let client = reqwest::Client::new();
+    let entry = |path: &str, hash: &str, size: i64| {
+        json!({ "file_name": path, "file_path": path, "file_hash": hash, "file_size": size, "modified_time": 1 })
+    };
+
+    let res = common::sync(&server, json!({ "insert": [entry("a.txt", "ha", 1), entry("b.txt", "hb", 1)] }), &[("a.txt", b"a"), ("b.txt", b"b")]).await;
+    assert_eq!(res.status(), 200);
+    let res = client.post(server.url("/snapshots")).bearer_auth(common::ADMIN_TOKEN).send().await.unwrap();
+    assert_eq!(res.status(), 201);
+    let snapshot: Value = res.json().await.unwrap();
+    assert_eq!(snapshot["data"]["files"], 2);
+
+    // What a compromised client might do.
+    let res = common::sync(
+        &server,
+        json!({ "update": [entry("a.txt", "hx", 3)], "delete": [entry("b.txt", "hb", 1)], "insert": [entry("note.txt", "hn", 1)] }),
+        &[("a.txt", b"xxx"), ("note.txt", b"n")],
+    )
+    .await;
+    assert_eq!(res.status(), 200);
+
+    let res = client
+        .post(server.url(&format!("/restore-snapshot/{}", snapshot["data"]["id"])))
+        .bearer_auth(common::ADMIN_TOKEN)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 200);
+    let restore: Value = res.json().await.unwrap();
+    assert_eq!(restore["data"]["restored"], json!(["a.txt", "b.txt"]));
+    assert_eq!(restore["data"]["deleted"], json!(["note.txt"]));
+    assert_eq!(restore["data"]["missing"], json!([]));
+
+    let system_path: String = sqlx::query_scalar("SELECT system_path FROM filehash WHERE file_path = 'a.txt'")
+        .fetch_one(&server.pool)
+        .await
+        .unwrap();
+    let object = server.s3.get_object().bucket(common::BUCKET).key(&system_path).send().await.unwrap();
+    assert_eq!(object.body.collect().await.unwrap().into_bytes().as_ref(), b"a");
+
+    let listing: Value = server.get("/get").await.json().await.unwrap();
+    let mut paths: Vec<&str> = listing["data"].as_array().unwrap().iter().filter_map(|f| f["file_path"].as_str()).collect();
+    paths.sort();
+    assert_eq!(paths, ["a.txt", "b.txt"]);
+
+    let snapshots: Value = server.get("/snapshots").await.json().await.unwrap();
+    assert_eq!(snapshots["data"][0]["id"], restore["data"]["undo_snapshot"], "the tree is snapshotted before it is restored");
+}
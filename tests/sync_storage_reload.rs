@@ -0,0 +1,57 @@
+mod common;
+
+use std::env;
+
+use serde_json::{json, Value};
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn storage_clients_reload_only_with_credentials_that_work() {
+    let server = common::start().await;
+    let client = reqwest::Client::new();
+    let reload = || {
+        client
+            .post(server.url("/admin/storage/reload"))
+            .bearer_auth(common::ADMIN_TOKEN)
+            .send()
+    };
+
+    let res = reload().await.unwrap();
+    assert_eq!(res.status(), 200);
+    let body: Value = res.json().await.unwrap();
+    assert_eq!(body["backend"], "s3");
+
+    // Rotated to keys the bucket doesn't know: the old client stays in use.
+    // SAFETY: each test binary runs a single test, and nothing else reads these.
+    unsafe { env::set_var("S3_SECRET_ACCESS_KEY", "not-the-secret") };
+    assert_eq!(reload().await.unwrap().status(), 502);
+
+    let res = common::sync(
+        &server,
+        json!({ "insert": [{
+            "file_name": "a.txt",
+            "file_path": "a.txt",
+            "file_hash": "aaa111",
+            "file_size": 5,
+            "modified_time": 1
+        }] }),
+        &[("a.txt", b"hello")],
+    )
+    .await;
+    assert_eq!(res.status(), 200);
+
+    // A web identity token is no use without a role to assume.
+    unsafe { env::set_var("S3_SECRET_ACCESS_KEY", "minioadmin") };
+    unsafe { env::set_var("S3_WEB_IDENTITY_TOKEN_FILE", "/var/run/token") };
+    assert_eq!(reload().await.unwrap().status(), 502);
+    unsafe { env::set_var("S3_WEB_IDENTITY_TOKEN_FILE", "") };
+    assert_eq!(reload().await.unwrap().status(), 200);
+
+    let system_path: String = sqlx::query_scalar("SELECT system_path FROM filehash WHERE file_path = 'a.txt'")
+        .fetch_one(&server.pool)
+        .await
+        .unwrap();
+    let object = server.s3.get_object().bucket(common::BUCKET).key(&system_path).send().await.unwrap();
+    assert_eq!(object.body.collect().await.unwrap().into_bytes().as_ref(), b"hello");
+    assert_eq!(server.get("/metadata?path=a.txt").await.status(), 200);
+}
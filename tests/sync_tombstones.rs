@@ -0,0 +1,44 @@
+mod common;
+
+use serde_json::{json, Value};
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn the_change_feed_dates_deletions_and_turns_away_cursors_past_the_horizon() {
+    let server = common::start().await;
+    let entry = |path: &str| {
+        json!({ "file_name": path, "file_path": path, "file_hash": "h1", "file_size": 1, "modified_time": 1 })
+    };
+
+    let res = common::sync(&server, json!({ "insert": [entry("a.txt"), entry("b.txt")] }), &[("a.txt", b"a"), ("b.txt", b"b")]).await;
+    assert_eq!(res.status(), 200);
+    let page: Value = server.get("/changes").await.json().await.unwrap();
+    let before = page["cursor"].as_i64().unwrap();
+
+    let res = common::sync(&server, json!({ "delete": [entry("a.txt")] }), &[]).await;
+    assert_eq!(res.status(), 200);
+    let page: Value = server.get(&format!("/changes?since={}", before)).await.json().await.unwrap();
+    let change = &page["data"][0];
+    assert_eq!(change["file_path"], "a.txt");
+    assert_eq!(change["deleted"], true);
+    assert!(change["deleted_at"].is_string());
+    let after = page["cursor"].as_i64().unwrap();
+
+    // What the pruner leaves behind once it forgets the deletion.
+    sqlx::query("DELETE FROM tombstones WHERE file_path = 'a.txt'").execute(&server.pool).await.unwrap();
+    sqlx::query("UPDATE change_counters SET pruned_seq = $1").bind(after).execute(&server.pool).await.unwrap();
+
+    let res = server.get(&format!("/changes?since={}", before)).await;
+    assert_eq!(res.status(), 410);
+    assert_eq!(server.get(&format!("/changes?since={}", after)).await.status(), 200);
+
+    let page: Value = server.get("/changes").await.json().await.unwrap();
+    let paths: Vec<&str> = page["data"].as_array().unwrap().iter().filter_map(|c| c["file_path"].as_str()).collect();
+    assert_eq!(paths, ["b.txt"], "starting over needs no deletions");
+
+    let system_path: String = sqlx::query_scalar("SELECT system_path FROM filehash WHERE file_path = 'b.txt'")
+        .fetch_one(&server.pool)
+        .await
+        .unwrap();
+    server.s3.head_object().bucket(common::BUCKET).key(&system_path).send().await.unwrap();
+}
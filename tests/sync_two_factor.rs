@@ -0,0 +1,164 @@
+mod common;
+
+use data_encoding::BASE32_NOPAD;
+use hmac::{Hmac, Mac};
+use serde_json::{json, Value};
+use sha1::Sha1;
+
+/// The code an authenticator app would show for `secret`, `offset` steps from now.
+fn totp_code(secret: &str, offset: i64) -> String {
+    let key = BASE32_NOPAD.decode(secret.as_bytes()).unwrap();
+    let step = chrono::Utc::now().timestamp() / 30 + offset;
+    let mut mac = Hmac::<Sha1>::new_from_slice(&key).unwrap();
+    mac.update(&step.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+    let at = usize::from(hash[19] & 0x0f);
+    let value = u32::from_be_bytes(hash[at..at + 4].try_into().unwrap()) & 0x7fff_ffff;
+    format!("{:06}", value % 1_000_000)
+}
+
+async fn login(server: &common::TestServer, body: Value) -> reqwest::Response {
+    reqwest::Client::new().post(server.url("/auth/login")).json(&body).send().await.unwrap()
+}
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn two_factor_logins_and_scoped_tokens() {
+    let server = common::start().await;
+    let client = reqwest::Client::new();
+    let res = client
+        .post(server.url("/admin/users"))
+        .bearer_auth(common::ADMIN_TOKEN)
+        .json(&json!({ "username": "carol", "password": "hunter2" }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 201);
+
+    let credentials = json!({ "username": "carol", "password": "hunter2" });
+    let session: Value = login(&server, credentials.clone()).await.json().await.unwrap();
+    let jwt = session["token"].as_str().unwrap().to_string();
+
+    let enrollment: Value = client
+        .post(server.url("/auth/2fa/enroll"))
+        .bearer_auth(&jwt)
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    let secret = enrollment["secret"].as_str().unwrap().to_string();
+    assert!(enrollment["otpauth_url"].as_str().unwrap().starts_with("otpauth://totp/"));
+
+    // Logins don't ask for codes until enrollment is confirmed.
+    assert_eq!(login(&server, credentials.clone()).await.status(), 200);
+    let res = client
+        .post(server.url("/auth/2fa/verify"))
+        .bearer_auth(&jwt)
+        .json(&json!({ "code": totp_code(&secret, 0) }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 200);
+
+    assert_eq!(login(&server, credentials.clone()).await.status(), 401);
+    let replayed = json!({ "username": "carol", "password": "hunter2", "totp_code": totp_code(&secret, 0) });
+    assert_eq!(login(&server, replayed).await.status(), 401, "codes are single-use");
+    let next = json!({ "username": "carol", "password": "hunter2", "totp_code": totp_code(&secret, 1) });
+    assert_eq!(login(&server, next).await.status(), 200);
+
+    let mint = |scope: &'static str| {
+        let client = client.clone();
+        let url = server.url("/tokens");
+        let jwt = jwt.clone();
+        async move {
+            let res = client.post(url).bearer_auth(jwt).json(&json!({ "scope": scope })).send().await.unwrap();
+            assert_eq!(res.status(), 201);
+            let created: Value = res.json().await.unwrap();
+            created["token"].as_str().unwrap().to_string()
+        }
+    };
+    let read = mint("read").await;
+    let upload = mint("upload").await;
+
+    let hash: String = sqlx::query_scalar("SELECT token_hash FROM api_tokens WHERE scope = 'read'")
+        .fetch_one(&server.pool)
+        .await
+        .unwrap();
+    assert_ne!(hash, read, "tokens are stored hashed");
+
+    let admin_file = json!({ "file_name": "b.txt", "file_path": "b.txt", "file_hash": "b2", "file_size": 1, "modified_time": 1 });
+    let res = common::sync(&server, json!({ "insert": [admin_file] }), &[("b.txt", &b"b"[..])]).await;
+    assert_eq!(res.status(), 200);
+
+    let get = |token: &str, path: &str| client.get(server.url(path)).bearer_auth(token).send();
+    assert_eq!(get(&read, "/get").await.unwrap().status(), 200);
+    assert_eq!(get(&read, "/download/direct?file_path=b.txt").await.unwrap().status(), 404, "tokens act as their user");
+    assert_eq!(get(&upload, "/get").await.unwrap().status(), 403);
+    let res = client
+        .post(server.url("/delete"))
+        .bearer_auth(&read)
+        .json(&json!({ "file_paths": ["a.txt"] }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 403);
+    let res = client.post(server.url("/tokens")).bearer_auth(&read).json(&json!({})).send().await.unwrap();
+    assert_eq!(res.status(), 403, "scoped tokens can't mint others");
+
+    let res = client
+        .post(server.url("/devices/register"))
+        .bearer_auth(&upload)
+        .json(&json!({ "device_id": "backup", "name": "Backup agent" }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 201);
+    let entry = json!({ "file_name": "a.txt", "file_path": "a.txt", "file_hash": "a1", "file_size": 1, "modified_time": 1 });
+    let payload = json!({ "insert": [entry] });
+    let form = reqwest::multipart::Form::new()
+        .text("payload", payload.to_string())
+        .part("files", reqwest::multipart::Part::bytes(b"a".to_vec()).file_name("a.txt"));
+    let res = client
+        .post(server.url("/sync"))
+        .bearer_auth(&upload)
+        .header("X-Device-Id", "backup")
+        .multipart(form)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 200);
+    let form = reqwest::multipart::Form::new().text("payload", json!({ "delete": [entry] }).to_string());
+    let res = client
+        .post(server.url("/sync"))
+        .bearer_auth(&upload)
+        .header("X-Device-Id", "backup")
+        .multipart(form)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 403, "upload-only tokens can't delete");
+    let moved = json!({ "file_name": "c.txt", "file_path": "c.txt", "from_path": "a.txt", "file_size": 0, "modified_time": 1 });
+    let form = reqwest::multipart::Form::new().text("payload", json!({ "move": [moved] }).to_string());
+    let res = client
+        .post(server.url("/sync"))
+        .bearer_auth(&upload)
+        .header("X-Device-Id", "backup")
+        .multipart(form)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 403, "nor move files, as /move refuses them");
+
+    let system_path: String = sqlx::query_scalar("SELECT system_path FROM filehash WHERE file_path = 'a.txt'")
+        .fetch_one(&server.pool)
+        .await
+        .unwrap();
+    let head = server.s3.head_object().bucket(common::BUCKET).key(&system_path).send().await.unwrap();
+    assert_eq!(head.content_length(), Some(1));
+
+    let tokens: Value = server.get("/admin/tokens").await.json().await.unwrap();
+    let scopes: Vec<&str> = tokens["data"].as_array().unwrap().iter().map(|t| t["scope"].as_str().unwrap()).collect();
+    assert!(scopes.contains(&"read") && scopes.contains(&"upload"));
+}
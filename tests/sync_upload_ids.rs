@@ -0,0 +1,55 @@
+mod common;
+
+use serde_json::{json, Value};
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn parts_are_matched_to_entries_by_upload_id() {
+    let server = common::start().await;
+    let entry = |path: &str, upload_id: &str| {
+        json!({ "file_name": "notes.txt", "file_path": path, "file_hash": "n1", "file_size": 3, "modified_time": 1, "upload_id": upload_id })
+    };
+    let sync = |payload: Value, parts: &[(&str, &'static [u8])]| {
+        let mut form = reqwest::multipart::Form::new().text("payload", payload.to_string());
+        for (id, data) in parts {
+            form = form.part(format!("files:{}", id), reqwest::multipart::Part::bytes(*data).file_name("notes.txt"));
+        }
+        reqwest::Client::new()
+            .post(server.url("/sync"))
+            .bearer_auth(common::ADMIN_TOKEN)
+            .header("X-Device-Id", common::DEVICE_ID)
+            .multipart(form)
+            .send()
+    };
+
+    // Both files are called notes.txt, and their parts arrive in the other order.
+    let payload = json!({ "version": 2, "insert": [entry("work/notes.txt", "w"), entry("home/notes.txt", "h"), entry("lost/notes.txt", "l")] });
+    let res = sync(payload, &[("h", b"hom"), ("w", b"wrk")]).await.unwrap();
+    assert_eq!(res.status(), 207);
+    let report: Value = res.json().await.unwrap();
+    let failure = &report["results"]["insert"]["failure"][0];
+    assert_eq!(failure["file_path"], "lost/notes.txt");
+    assert_eq!(failure["code"], "missing_upload");
+
+    for (path, content) in [("work/notes.txt", b"wrk"), ("home/notes.txt", b"hom")] {
+        let system_path: String = sqlx::query_scalar("SELECT system_path FROM filehash WHERE file_path = $1")
+            .bind(path)
+            .fetch_one(&server.pool)
+            .await
+            .unwrap();
+        let object = server.s3.get_object().bucket(common::BUCKET).key(&system_path).send().await.unwrap();
+        assert_eq!(object.body.collect().await.unwrap().into_bytes().as_ref(), content);
+    }
+
+    let bare = json!({ "file_name": "idea.txt", "file_path": "idea.txt", "file_size": 0, "modified_time": 1 });
+    let res = common::sync(&server, json!({ "version": 2, "insert": [bare] }), &[]).await;
+    assert_eq!(res.status(), 400, "version 2 inserts must say where their bytes are");
+    let metadata_only = json!({ "file_name": "idea.txt", "file_path": "idea.txt", "file_size": 0, "modified_time": 1, "metadata_only": true });
+    let res = common::sync(&server, json!({ "version": 2, "insert": [metadata_only] }), &[]).await;
+    assert_eq!(res.status(), 200);
+
+    let res = sync(json!({ "version": 2, "insert": [entry("other.txt", "o")] }), &[("x", b"abc")]).await.unwrap();
+    assert_eq!(res.status(), 400, "parts must name an entry of the payload");
+    let listing: Value = server.get("/get").await.json().await.unwrap();
+    assert_eq!(listing["data"].as_array().unwrap().len(), 3);
+}
@@ -0,0 +1,63 @@
+mod common;
+
+use serde_json::{json, Value};
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn vault_items_are_kept_by_id_and_versioned_by_vector() {
+    let server = common::start().await;
+    let client = reqwest::Client::new();
+    let put = |vector: Value, body: &'static [u8]| {
+        client
+            .put(server.url("/vault/note-1"))
+            .bearer_auth(common::ADMIN_TOKEN)
+            .header("X-Encrypted-Name", "c2VjcmV0")
+            .header("X-Version-Vector", vector.to_string())
+            .body(body)
+            .send()
+    };
+
+    let res = put(json!({ "laptop": 1 }), b"ciphertext").await.unwrap();
+    assert_eq!(res.status(), 201);
+
+    // A phone that never saw the laptop's version is told about it.
+    let res = put(json!({ "phone": 1 }), b"other").await.unwrap();
+    assert_eq!(res.status(), 409);
+    let body: Value = res.json().await.unwrap();
+    assert_eq!(body["current"]["version_vector"], json!({ "laptop": 1 }));
+
+    let res = put(json!({ "laptop": 1, "phone": 1 }), b"merged").await.unwrap();
+    assert_eq!(res.status(), 200);
+
+    let res = server.get("/vault/note-1").await;
+    assert_eq!(res.headers()["X-Version-Vector"], r#"{"laptop":1,"phone":1}"#);
+    assert_eq!(res.bytes().await.unwrap().as_ref(), b"merged");
+
+    let system_path: String = sqlx::query_scalar("SELECT system_path FROM vault_items WHERE item_id = 'note-1'")
+        .fetch_one(&server.pool)
+        .await
+        .unwrap();
+    assert!(system_path.starts_with("vault/"));
+    let object = server.s3.get_object().bucket(common::BUCKET).key(&system_path).send().await.unwrap();
+    assert_eq!(object.body.collect().await.unwrap().into_bytes().as_ref(), b"merged");
+
+    let file = json!({ "file_name": "a.txt", "file_path": "a.txt", "file_hash": "a1", "file_size": 1, "modified_time": 1 });
+    let res = common::sync(&server, json!({ "insert": [file] }), &[("a.txt", b"a")]).await;
+    assert_eq!(res.status(), 200);
+    let listing: Value = server.get("/get").await.json().await.unwrap();
+    assert_eq!(listing["data"].as_array().unwrap().len(), 1, "vault items have no paths to list");
+    let usage: Value = server.get("/usage").await.json().await.unwrap();
+    assert_eq!(usage["bytes"], 7, "vault items count against the quota");
+
+    let res = client
+        .delete(server.url("/vault/note-1"))
+        .bearer_auth(common::ADMIN_TOKEN)
+        .header("X-Version-Vector", json!({ "laptop": 1 }).to_string())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 409, "an older version can't be deleted");
+    let res = client.delete(server.url("/vault/note-1")).bearer_auth(common::ADMIN_TOKEN).send().await.unwrap();
+    assert_eq!(res.status(), 204);
+    assert_eq!(server.get("/vault/note-1").await.status(), 404);
+}
@@ -0,0 +1,65 @@
+mod common;
+
+use serde_json::{json, Value};
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn a_path_never_hands_out_the_same_version_twice() {
+    let server = common::start().await;
+
+    let op = |op: &str, path: &str, base_version: Option<i64>| {
+        let mut file = json!({ "op": op, "file_name": path, "file_path": path, "file_hash": "hash", "file_size": 5, "modified_time": 1 });
+        if let Some(base_version) = base_version {
+            file["base_version"] = json!(base_version);
+        }
+        json!({ "schema_version": 4, "operations": [file] })
+    };
+    let version = |path: &'static str| {
+        let pool = server.pool.clone();
+        async move {
+            sqlx::query_scalar::<_, i64>("SELECT version FROM filehash WHERE file_path = $1")
+                .bind(path)
+                .fetch_one(&pool)
+                .await
+                .unwrap()
+        }
+    };
+
+    assert_eq!(common::sync(&server, op("insert", "a.txt", None), &[("a.txt", b"one!!")]).await.status(), 200);
+    assert_eq!(common::sync(&server, op("update", "a.txt", Some(1)), &[("a.txt", b"two!!")]).await.status(), 200);
+    assert_eq!(common::sync(&server, op("delete", "a.txt", Some(2)), &[]).await.status(), 200);
+
+    // Created again, the file carries on from where the deleted one stopped.
+    let res = common::sync(&server, op("insert", "a.txt", None), &[("a.txt", b"new!!")]).await;
+    assert_eq!(res.status(), 200);
+    let report: Value = res.json().await.unwrap();
+    assert_eq!(report["results"]["insert"]["success"][0]["version"], 3);
+
+    // So a device still holding the deleted copy can't delete the new one.
+    for base_version in [1, 2] {
+        let res = common::sync(&server, op("delete", "a.txt", Some(base_version)), &[]).await;
+        assert_eq!(res.status(), 422);
+        let report: Value = res.json().await.unwrap();
+        assert_eq!(report["results"]["delete"]["conflict"][0]["code"], "version_mismatch");
+    }
+    assert_eq!(server.get("/metadata?path=a.txt").await.status(), 200);
+
+    // Moving a file away retires its version at the old path too.
+    let res = common::sync(
+        &server,
+        json!({ "schema_version": 4, "operations": [{ "op": "move", "from_path": "a.txt", "file_path": "b.txt" }] }),
+        &[],
+    )
+    .await;
+    assert_eq!(res.status(), 200);
+    assert_eq!(version("b.txt").await, 4);
+    assert_eq!(common::sync(&server, op("insert", "a.txt", None), &[("a.txt", b"old!!")]).await.status(), 200);
+    assert_eq!(version("a.txt").await, 4);
+
+    let system_path: String = sqlx::query_scalar("SELECT system_path FROM filehash WHERE file_path = 'b.txt'")
+        .fetch_one(&server.pool)
+        .await
+        .unwrap();
+    let object = server.s3.get_object().bucket(common::BUCKET).key(&system_path).send().await.unwrap();
+    assert_eq!(object.body.collect().await.unwrap().into_bytes().as_ref(), b"new!!");
+}
@@ -0,0 +1,56 @@
+mod common;
+
+use serde_json::{json, Value};
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn only_applied_updates_keep_a_version() {
+    let server = common::start().await;
+    let entry = |path: &str, hash: &str| {
+        json!({ "file_name": path, "file_path": path, "file_hash": hash, "file_size": 3, "modified_time": 1 })
+    };
+    let versions = |path: &'static str| {
+        let pool = server.pool.clone();
+        async move {
+            sqlx::query_as::<_, (i32, String, String)>("SELECT version, file_hash, s3_key FROM file_versions WHERE file_path = $1")
+                .bind(path)
+                .fetch_all(&pool)
+                .await
+                .unwrap()
+        }
+    };
+    let read = |key: String| {
+        let s3 = server.s3.clone();
+        async move {
+            let object = s3.get_object().bucket(common::BUCKET).key(key).send().await.unwrap();
+            object.body.collect().await.unwrap().into_bytes()
+        }
+    };
+
+    let res = common::sync(&server, json!({ "insert": [entry("a.txt", "h1")] }), &[("a.txt", b"old")]).await;
+    assert_eq!(res.status(), 200);
+    let system_path: String = sqlx::query_scalar("SELECT system_path FROM filehash WHERE file_path = 'a.txt'")
+        .fetch_one(&server.pool)
+        .await
+        .unwrap();
+
+    // An update of a file that was never synced fails, so there is nothing to keep.
+    let res = common::sync(&server, json!({ "update": [entry("b.txt", "h1")] }), &[("b.txt", b"new")]).await;
+    assert_eq!(res.status(), 422);
+    assert!(versions("b.txt").await.is_empty());
+
+    // Bytes that don't hash to the claimed SHA-256 fail the update, so nothing is replaced.
+    let res = common::sync(&server, json!({ "update": [entry("a.txt", &"0".repeat(64))] }), &[("a.txt", b"bad")]).await;
+    assert_eq!(res.status(), 422);
+    assert!(versions("a.txt").await.is_empty());
+    assert_eq!(read(system_path.clone()).await.as_ref(), b"old");
+
+    let res = common::sync(&server, json!({ "update": [entry("a.txt", "h2")] }), &[("a.txt", b"new")]).await;
+    assert_eq!(res.status(), 200);
+    // The version takes over the replaced object; the update wrote to a key of its own.
+    assert_eq!(versions("a.txt").await, [(1, "h1".to_string(), system_path.clone())]);
+    assert_eq!(read(system_path).await.as_ref(), b"old");
+
+    let listing: Value = server.get("/versions?path=a.txt").await.json().await.unwrap();
+    assert_eq!(listing["data"][0]["file_hash"], "h1");
+}